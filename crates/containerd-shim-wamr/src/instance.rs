@@ -1,7 +1,11 @@
-use anyhow::{Context, Result};
-use containerd_shim_wasm::sandbox::Sandbox;
-use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
-use containerd_shim_wasm::shim::{Shim, Version, version};
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::sandbox::context::{
+    Capabilities, Capability, Entrypoint, RuntimeContext, Source, WasmLayer,
+};
+use containerd_shim_wasm::sandbox::{Sandbox, check_entrypoint};
+use containerd_shim_wasm::shim::{
+    Shim, ShimConfig, StackLimitRange, SupportedStackLimits, Version, version,
+};
 use wamr_rust_sdk::function::Function;
 use wamr_rust_sdk::instance::Instance as WamrInst;
 use wamr_rust_sdk::module::Module;
@@ -10,8 +14,32 @@ use wamr_rust_sdk::wasi_context::WasiCtxBuilder;
 
 pub struct WamrShim;
 
+/// The default and the range of the size of the stack of the guest
+const DEFAULT_STACK_SIZE: u32 = 64 * 1024;
+const MIN_STACK_SIZE: u32 = 16 * 1024;
+const MAX_STACK_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Media type of the layers with a module compiled ahead of time by `wamrc`. They are loaded
+/// instead of the wasm layer of the image, which is the fallback when the target of the AOT
+/// artifact isn't the one of the host.
+pub const AOT_LAYER_TYPE: &str = "application/vnd.wamr.aot.layer.v1+aot";
+
+/// Annotation to run the wasm module of the container with the interpreter rather than its AOT
+/// artifact, e.g., to debug a difference between them: `true` or `false`.
+/// It defaults to the [`INTERPRETER_KEY`] engine setting of the shim.
+pub const INTERPRETER_ANNOTATION: &str = "io.runwasi.wamr.interpreter";
+
+/// Engine setting to run the containers without an [`INTERPRETER_ANNOTATION`] with the
+/// interpreter.
+pub const INTERPRETER_KEY: &str = "interpreter";
+
+// The header of the AOT artifacts, like `\0asm` for wasm binaries
+const AOT_MAGIC: &[u8] = b"\0aot";
+
 pub struct WamrSandbox {
     runtime: Runtime,
+    stack_size: u32,
+    interpreter: bool,
 }
 
 unsafe impl Send for WamrSandbox {}
@@ -20,13 +48,21 @@ unsafe impl Sync for WamrSandbox {}
 impl Default for WamrSandbox {
     fn default() -> Self {
         let runtime = Runtime::new().unwrap();
-        Self { runtime }
+        Self {
+            runtime,
+            stack_size: DEFAULT_STACK_SIZE,
+            interpreter: false,
+        }
     }
 }
 
 impl Shim for WamrShim {
     type Sandbox = WamrSandbox;
 
+    fn engine_config_keys() -> &'static [&'static str] {
+        &[INTERPRETER_KEY]
+    }
+
     fn name() -> &'static str {
         "wamr"
     }
@@ -34,9 +70,71 @@ impl Shim for WamrShim {
     fn version() -> Version {
         version!()
     }
+
+    fn supported_stack_limits() -> SupportedStackLimits {
+        SupportedStackLimits {
+            max_stack_size: Some(StackLimitRange {
+                range: MIN_STACK_SIZE as usize..=MAX_STACK_SIZE as usize,
+                default: DEFAULT_STACK_SIZE as usize,
+            }),
+            max_call_depth: None,
+        }
+    }
+
+    fn supported_capabilities() -> Capabilities {
+        [Capability::FsWrite, Capability::Env].into_iter().collect()
+    }
+
+    fn supported_layers_types() -> &'static [&'static str] {
+        &[
+            "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
+            "application/wasm",
+            "application/vnd.wasm.content.layer.v1+wat",
+            AOT_LAYER_TYPE,
+        ]
+    }
+
+    fn reports_guest_started() -> bool {
+        true
+    }
 }
 
 impl Sandbox for WamrSandbox {
+    fn new(ctx: &impl RuntimeContext) -> Self {
+        // an invalid annotation fails the container in `can_handle`
+        let interpreter = interpreter(ctx).unwrap_or_default();
+        // the runtime of WAMR is global, so it's only initialized once here
+        let runtime = if interpreter {
+            Runtime::builder()
+                .use_system_allocator()
+                .run_as_interpreter()
+                .build()
+        } else {
+            Runtime::new()
+        };
+        // checked against the supported range when the container was created
+        let stack_size = ctx
+            .stack_limits()
+            .max_stack_size
+            .map_or(DEFAULT_STACK_SIZE, |stack_size| stack_size as u32);
+        Self {
+            runtime: runtime.unwrap(),
+            stack_size,
+            interpreter,
+        }
+    }
+
+    async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        async move {
+            interpreter(ctx)?;
+            match ctx.entrypoint().source {
+                // the default checks only recognize wasm and wat files
+                Source::File(path) if is_aot_file(&path) => Ok(()),
+                _ => check_entrypoint(ctx),
+            }
+        }
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx.envs();
@@ -44,9 +142,7 @@ impl Sandbox for WamrSandbox {
             source, func, name, ..
         } = ctx.entrypoint();
 
-        let wasm_bytes = source
-            .as_bytes()
-            .context("Failed to get bytes from source")?;
+        let Binaries { aot, wasm } = binaries(&source)?;
 
         log::info!("Create a WAMR module");
 
@@ -54,13 +150,56 @@ impl Sandbox for WamrSandbox {
 
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
-        let mut module = Module::from_buf(&self.runtime, &wasm_bytes, &mod_name)
-            .context("Failed to create module from bytes")?;
+        let mut module = match (aot, wasm) {
+            (Some(_), Some(wasm)) if self.interpreter => {
+                log::info!(
+                    "running the wasm module with the interpreter, ignoring its AOT artifact"
+                );
+                Module::from_buf(&self.runtime, &wasm, &mod_name)
+                    .context("Failed to create module from bytes")?
+            }
+            (Some(_), None) if self.interpreter => {
+                bail!("the interpreter is forced, but there is no wasm module to run with it")
+            }
+            (Some(aot), wasm) => match Module::from_buf(&self.runtime, &aot, &mod_name) {
+                Ok(module) => {
+                    log::info!("loaded the AOT artifact");
+                    module
+                }
+                Err(err) => {
+                    // e.g., it was compiled by `wamrc` for another target than the host
+                    let err = anyhow::Error::from(err).context(format!(
+                        "failed to load the AOT artifact on {}",
+                        std::env::consts::ARCH
+                    ));
+                    let Some(wasm) = wasm else {
+                        return Err(err);
+                    };
+                    log::warn!("{err:#}, falling back to the wasm module");
+                    Module::from_buf(&self.runtime, &wasm, &mod_name)
+                        .context("Failed to create module from bytes")?
+                }
+            },
+            (None, Some(wasm)) => Module::from_buf(&self.runtime, &wasm, &mod_name)
+                .context("Failed to create module from bytes")?,
+            (None, None) => bail!("the image has no wasm module nor AOT artifact to run"),
+        };
 
         log::info!("Create a WASI context");
 
+        // WAMR can't preopen directories read-only, but the mount itself still is. So the guest
+        // can write to the root and the preopens that aren't mounted read-only even without the
+        // fs-write capability
+        let preopens: Vec<_> = ctx
+            .preopens()
+            .iter()
+            .map(|preopen| preopen.path.to_string_lossy())
+            .collect();
+        let dirs = std::iter::once("/")
+            .chain(preopens.iter().map(|path| path.as_ref()))
+            .collect();
         let wasi_ctx = WasiCtxBuilder::new()
-            .set_pre_open_path(vec!["/"], vec![])
+            .set_pre_open_path(dirs, vec![])
             .set_env_vars(envs.iter().map(String::as_str).collect())
             .set_arguments(args.iter().map(String::as_str).collect())
             .build();
@@ -71,12 +210,13 @@ impl Sandbox for WamrSandbox {
 
         log::info!("Create a WAMR instance");
 
-        let instance = WamrInst::new(&self.runtime, &module, 1024 * 64)
+        let instance = WamrInst::new(&self.runtime, &module, self.stack_size)
             .context("Failed to create instance")?;
 
         log::info!("Running {func:?}");
         let function =
             Function::find_export_func(&instance, &func).context("Failed to find function")?;
+        ctx.guest_started();
         let status = function
             .call(&instance, &vec![])
             .map(|_| 0)
@@ -89,3 +229,82 @@ impl Sandbox for WamrSandbox {
         Ok(status)
     }
 }
+
+// The AOT artifact and the wasm module of the guest, if there are any
+struct Binaries {
+    aot: Option<Vec<u8>>,
+    wasm: Option<Vec<u8>>,
+}
+
+fn binaries(source: &Source) -> Result<Binaries> {
+    match source {
+        Source::File(path) => {
+            let bytes = source
+                .as_bytes()
+                .context("Failed to get bytes from source")?
+                .into_owned();
+            if !bytes.starts_with(AOT_MAGIC) {
+                return Ok(Binaries {
+                    aot: None,
+                    wasm: Some(bytes),
+                });
+            }
+            // the wasm module the artifact was compiled from, if the image has it next to it
+            let wasm = Source::File(path.with_extension("wasm"))
+                .as_bytes()
+                .ok()
+                .filter(|wasm| !wasm.starts_with(AOT_MAGIC))
+                .map(|wasm| wasm.into_owned());
+            Ok(Binaries {
+                aot: Some(bytes),
+                wasm,
+            })
+        }
+        Source::Oci(layers) => {
+            let (aot, wasm): (Vec<_>, Vec<_>) = layers.iter().cloned().partition(is_aot_layer);
+            // each of them is picked like the entrypoint among the layers of its kind
+            let bytes = |layers: &[WasmLayer]| -> Result<Option<Vec<u8>>> {
+                if layers.is_empty() {
+                    return Ok(None);
+                }
+                let bytes = Source::Oci(layers)
+                    .as_bytes()
+                    .context("Failed to get bytes from source")?;
+                Ok(Some(bytes.into_owned()))
+            };
+            Ok(Binaries {
+                aot: bytes(&aot)?,
+                wasm: bytes(&wasm)?,
+            })
+        }
+    }
+}
+
+fn is_aot_layer(layer: &WasmLayer) -> bool {
+    layer.config.media_type().to_string() == AOT_LAYER_TYPE || layer.layer.starts_with(AOT_MAGIC)
+}
+
+fn is_aot_file(path: &std::path::Path) -> bool {
+    Source::File(path.to_path_buf())
+        .as_bytes()
+        .is_ok_and(|bytes| bytes.starts_with(AOT_MAGIC))
+}
+
+// Whether the container of `ctx` runs with the interpreter
+fn interpreter(ctx: &impl RuntimeContext) -> Result<bool> {
+    let (value, what) = match ctx.annotation(INTERPRETER_ANNOTATION) {
+        Some(value) => (
+            value.to_string(),
+            format!("{INTERPRETER_ANNOTATION} annotation"),
+        ),
+        None => match ShimConfig::get().engine_value(INTERPRETER_KEY) {
+            Some(value) => (value, format!("`engine.{INTERPRETER_KEY}` value")),
+            None => return Ok(false),
+        },
+    };
+    match value.as_str() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!("invalid {what} {value:?}, expected `true` or `false`"),
+    }
+}
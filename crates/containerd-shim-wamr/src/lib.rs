@@ -2,7 +2,7 @@
 pub mod instance;
 
 #[cfg(unix)]
-pub use instance::WamrShim;
+pub use instance::{AOT_LAYER_TYPE, INTERPRETER_ANNOTATION, INTERPRETER_KEY, WamrShim};
 
 #[cfg(unix)]
 #[cfg(test)]
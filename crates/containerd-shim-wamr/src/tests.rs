@@ -5,6 +5,7 @@ use containerd_shim_wasm::testing::modules::*;
 use serial_test::serial;
 
 use crate::WamrShim as WasiEngine;
+use crate::instance::INTERPRETER_ANNOTATION;
 
 #[test]
 #[serial]
@@ -28,6 +29,35 @@ fn test_hello_world() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_hello_world_interpreter() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation(INTERPRETER_ANNOTATION, "true")
+        .with_wasm(HELLO_WORLD)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_invalid_interpreter_annotation() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiEngine>::builder()?
+        .with_annotation(INTERPRETER_ANNOTATION, "yes")
+        .with_wasm(HELLO_WORLD)?
+        .build();
+
+    assert!(res.is_err());
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_hello_world_oci() -> anyhow::Result<()> {
@@ -116,3 +146,23 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_deterministic_is_rejected() -> anyhow::Result<()> {
+    let Err(err) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation("io.runwasi.deterministic", "seed:42")
+        .with_wasm(HELLO_WORLD)?
+        .build()
+    else {
+        anyhow::bail!("the container was created");
+    };
+
+    assert!(
+        err.to_string()
+            .contains("doesn't support the io.runwasi.deterministic annotation"),
+        "{err}"
+    );
+
+    Ok(())
+}
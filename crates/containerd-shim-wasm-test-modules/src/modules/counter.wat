@@ -0,0 +1,47 @@
+(module
+    ;; Import the fd_write WASI function, which writes io vectors
+    ;; (File Descriptor, *iovs, iovs_len, *nwritten) -> Returns an errno
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    ;; Import the poll_oneoff WASI function, which waits for the subscriptions
+    ;; (*subscriptions, *events, nsubscriptions, *nevents) -> Returns an errno
+    (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The counter is exported, so that it is saved in the checkpoints of the module
+    (global $count (export "count") (mut i32) (i32.const 0))
+
+    ;; Print the counter on its own line every 100ms, forever
+    ;; The clock subscription is at 0, the event at 48, the number of events at 80,
+    ;; the io vector at 88, the number of bytes written at 96, and the line ends at 128
+    (func $main (export "_start")
+        (local $n i32)
+        (local $pos i32)
+
+        (i32.store8 (i32.const 8) (i32.const 0))  ;; subscription.tag - A clock
+        (i32.store (i32.const 16) (i32.const 1))  ;; subscription.clock.id - The monotonic clock
+        (i64.store (i32.const 24) (i64.const 100000000))  ;; subscription.clock.timeout - In ns
+        (i32.store8 (i32.const 127) (i32.const 10))  ;; The new line
+
+        (loop $count
+            (global.set $count (i32.add (global.get $count) (i32.const 1)))
+
+            ;; write the decimal digits of the counter before the new line
+            (local.set $n (global.get $count))
+            (local.set $pos (i32.const 127))
+            (loop $digits
+                (local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+                (i32.store8 (local.get $pos) (i32.add (i32.const 48) (i32.rem_u (local.get $n) (i32.const 10))))
+                (local.set $n (i32.div_u (local.get $n) (i32.const 10)))
+                (br_if $digits (local.get $n))
+            )
+            (i32.store (i32.const 88) (local.get $pos))  ;; iov.iov_base
+            (i32.store (i32.const 92) (i32.sub (i32.const 128) (local.get $pos)))  ;; iov.iov_len
+            (drop (call $fd_write (i32.const 1) (i32.const 88) (i32.const 1) (i32.const 96)))
+
+            (drop (call $poll_oneoff (i32.const 0) (i32.const 48) (i32.const 1) (i32.const 80)))
+            (br $count)
+        )
+    )
+)
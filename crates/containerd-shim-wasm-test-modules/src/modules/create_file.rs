@@ -0,0 +1,13 @@
+use std::fs::File;
+
+fn main() {
+    // the tests mask this directory
+    let path = "/masked/file";
+    match File::create(path) {
+        Ok(_) => println!("created {path}"),
+        Err(err) => {
+            println!("failed to create {path}: {:?}", err.kind());
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+(module
+    ;; Import the random_get, clock_time_get and fd_write WASI functions
+    ;; random_get: (*buf, buf_len) -> Returns an errno
+    ;; clock_time_get: (Clock ID, precision, *time) -> Returns an errno
+    (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "clock_time_get" (func $clock_time_get (param i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The hex digits, to print the bytes with
+    (data (i32.const 0) "0123456789abcdef")
+
+    ;; Print 16 random bytes followed by the time of the wall clock, in hex
+    ;; The bytes are at 16, the time at 32, and their 48 hex digits at 64
+    (func $main (export "_start")
+        (local $i i32)
+        (drop (call $random_get (i32.const 16) (i32.const 16)))
+        (drop (call $clock_time_get
+            (i32.const 0) ;; clock id - 0 for the realtime clock
+            (i64.const 1) ;; precision
+            (i32.const 32) ;; *time - The time in nanoseconds as a little endian u64
+        ))
+
+        (loop $hex
+            ;; the high nibble of byte i
+            (i32.store8
+                (i32.add (i32.const 64) (i32.shl (local.get $i) (i32.const 1)))
+                (i32.load8_u (i32.shr_u (i32.load8_u (i32.add (i32.const 16) (local.get $i))) (i32.const 4))))
+            ;; the low nibble of byte i
+            (i32.store8
+                (i32.add (i32.const 65) (i32.shl (local.get $i) (i32.const 1)))
+                (i32.load8_u (i32.and (i32.load8_u (i32.add (i32.const 16) (local.get $i))) (i32.const 15))))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br_if $hex (i32.lt_u (local.get $i) (i32.const 24)))
+        )
+        (i32.store8 (i32.const 112) (i32.const 10)) ;; '\n'
+
+        (i32.store (i32.const 128) (i32.const 64)) ;; iov.iov_base - The hex digits
+        (i32.store (i32.const 132) (i32.const 49)) ;; iov.iov_len - The hex digits and the newline
+        (drop (call $fd_write
+            (i32.const 1) ;; file_descriptor - 1 for stdout
+            (i32.const 128) ;; *iovs
+            (i32.const 1) ;; iovs_len
+            (i32.const 136) ;; nwritten
+        ))
+    )
+)
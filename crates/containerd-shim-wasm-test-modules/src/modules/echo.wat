@@ -0,0 +1,27 @@
+(module
+    ;; Import the fd_read and fd_write WASI functions, which read and write io vectors
+    ;; (File Descriptor, *iovs, iovs_len, *nread/*nwritten) -> Returns an errno
+    (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Copy stdin to stdout until the end of stdin
+    ;; The io vector is at 0, the number of bytes read is at 8, and the buffer at 16
+    (func $main (export "_start")
+        (loop $echo
+            (i32.store (i32.const 0) (i32.const 16))  ;; iov.iov_base
+            (i32.store (i32.const 4) (i32.const 1024))  ;; iov.iov_len - The size of the buffer
+
+            ;; stop on errors, and at the end of stdin, where nothing is read
+            (br_if 1 (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (br_if 1 (i32.eqz (i32.load (i32.const 8))))
+
+            ;; write what was read
+            (i32.store (i32.const 4) (i32.load (i32.const 8)))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (br $echo)
+        )
+    )
+)
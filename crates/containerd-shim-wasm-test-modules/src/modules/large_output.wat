@@ -0,0 +1,42 @@
+(module
+    ;; Import the fd_write and proc_exit WASI functions
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+    ;; 1 page for the io vector, and 16 pages for the output
+    (memory 17)
+    (export "memory" (memory 0))
+
+    (func $main (export "_start")
+        (local $base i32)
+        (local $len i32)
+
+        ;; Write 1 MiB of 'x' to stdout, without a trailing newline, and exit right away
+        (memory.fill (i32.const 65536) (i32.const 120) (i32.const 1048576))
+        (local.set $base (i32.const 65536))
+        (local.set $len (i32.const 1048576))
+
+        ;; fd_write may write only part of the io vector, so write until it's all written
+        (block $done
+            (loop $write
+                (br_if $done (i32.eqz (local.get $len)))
+                (i32.store (i32.const 0) (local.get $base)) ;; iov.iov_base
+                (i32.store (i32.const 4) (local.get $len))  ;; iov.iov_len
+                (br_if $done
+                    (call $fd_write
+                        (i32.const 1) ;; file_descriptor - 1 for stdout
+                        (i32.const 0) ;; *iovs
+                        (i32.const 1) ;; iovs_len
+                        (i32.const 8) ;; nwritten
+                    )
+                )
+                (local.set $base (i32.add (local.get $base) (i32.load (i32.const 8))))
+                (local.set $len (i32.sub (local.get $len) (i32.load (i32.const 8))))
+                (br $write)
+            )
+        )
+
+        (call $proc_exit (i32.const 0))
+        unreachable
+    )
+)
@@ -0,0 +1,16 @@
+(module
+    ;; `_start` calls `outer`, which calls `middle`, which calls `inner`, which traps,
+    ;; so that the backtrace of the trap has three named frames under `_start`
+    (func $inner
+        (unreachable)
+    )
+    (func $middle
+        (call $inner)
+    )
+    (func $outer
+        (call $middle)
+    )
+    (func $main (export "_start")
+        (call $outer)
+    )
+)
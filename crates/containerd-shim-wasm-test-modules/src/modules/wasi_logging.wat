@@ -0,0 +1,43 @@
+;; A component that logs a record at the info, warn and error levels with `wasi:logging/logging`.
+(component
+  (import "wasi:logging/logging@0.1.0-draft" (instance $logging
+    (type $level (enum "trace" "debug" "info" "warn" "error" "critical"))
+    ;; the exported type can't be named, it's the type 1 of the instance
+    (export "level" (type (eq $level)))
+    (type $log (func (param "level" 1) (param "context" string) (param "message" string)))
+    (export "log" (func (type $log)))
+  ))
+  (alias export $logging "log" (func $log))
+
+  ;; the memory the strings of the records are lowered from
+  (core module $memory-module
+    (memory (export "memory") 1)
+  )
+  (core instance $memory-instance (instantiate $memory-module))
+  (alias core export $memory-instance "memory" (core memory $mem))
+  (core func $log-lowered (canon lower (func $log) (memory $mem)))
+
+  (core module $main-module
+    (import "env" "memory" (memory 1))
+    (import "env" "log" (func $log (param i32 i32 i32 i32 i32)))
+
+    (data (i32.const 0) "wasi-logging")
+    (data (i32.const 16) "an info record")
+    (data (i32.const 32) "a warn record")
+    (data (i32.const 48) "an error record")
+
+    (func (export "_start")
+      ;; the levels are the indices of the cases of the enum
+      (call $log (i32.const 2) (i32.const 0) (i32.const 12) (i32.const 16) (i32.const 14))
+      (call $log (i32.const 3) (i32.const 0) (i32.const 12) (i32.const 32) (i32.const 13))
+      (call $log (i32.const 4) (i32.const 0) (i32.const 12) (i32.const 48) (i32.const 15))
+    )
+  )
+  (core instance $env
+    (export "memory" (memory $mem))
+    (export "log" (func $log-lowered))
+  )
+  (core instance $main-instance (instantiate $main-module (with "env" (instance $env))))
+
+  (func (export "run") (canon lift (core func $main-instance "_start")))
+)
@@ -0,0 +1,470 @@
+//! Process-wide cache of the wasm layers read from the content store.
+//!
+//! Every instance runs its engine in its own process, so compiled engine modules can't be
+//! shared between instances. What the shim can share is the layer content: replicas of the
+//! same image reuse the bytes read for the first one instead of reading them from containerd
+//! again. Entries are keyed by content digest, so the precompiled artifacts of different
+//! engines (or engine versions) never collide.
+//!
+//! The cache holds at most `layers.cache_size` bytes, evicting the least recently used
+//! layers first, and a layer is dropped as soon as the last instance using it is deleted.
+//!
+//! Layers of at least `layers.mmap_threshold` bytes are not held in memory. They are
+//! written to a file in the layer files directory of the node and memory-mapped instead, and
+//! the mapping is shared by all the instances using the layer, including their zygotes. These
+//! layers don't count towards the size of the cache and are never evicted from it. Their files
+//! are kept for later instances, including the ones of other shims, and evicted separately, see
+//! [`super::layer_files`].
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::layer_files::{
+    DEFAULT_LAYER_FILES_DIR, DEFAULT_LAYER_FILES_EVICTION_INTERVAL, DEFAULT_LAYER_FILES_QUOTA,
+    LayerFiles, LayerFilesSnapshot,
+};
+use crate::sandbox::context::LayerContent;
+use crate::shim::ShimConfig;
+
+// `0` disables the cache of in-memory layers
+const DEFAULT_LAYER_CACHE_SIZE: usize = 512 * 1024 * 1024;
+const DEFAULT_LAYER_MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+pub(crate) static LAYER_CACHE: LazyLock<LayerCache> = LazyLock::new(|| {
+    let config = &ShimConfig::get().layers;
+    let capacity = config.cache_size.unwrap_or(DEFAULT_LAYER_CACHE_SIZE);
+    let cache = LayerCache::new(capacity);
+
+    let dir = PathBuf::from(
+        config
+            .files_dir
+            .as_deref()
+            .unwrap_or(DEFAULT_LAYER_FILES_DIR),
+    );
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        log::warn!("large layers won't be memory-mapped, failed to create {dir:?}: {err}");
+        return cache;
+    }
+    let threshold = config
+        .mmap_threshold
+        .unwrap_or(DEFAULT_LAYER_MMAP_THRESHOLD);
+    let quota = config.files_quota.unwrap_or(DEFAULT_LAYER_FILES_QUOTA);
+    let interval = config
+        .files_eviction_interval
+        .unwrap_or(DEFAULT_LAYER_FILES_EVICTION_INTERVAL);
+    let interval = Duration::from_secs(interval.max(1));
+    let evictions = std::thread::Builder::new()
+        .name("layer-files".into())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                LAYER_CACHE.evict_files(0);
+            }
+        });
+    if let Err(err) = evictions {
+        log::warn!("layer files will only be evicted before writing new ones: {err}");
+    }
+    cache
+        .with_mapped_layers(dir, threshold)
+        .with_files_quota(quota)
+});
+
+pub(crate) struct LayerCache {
+    capacity: usize,
+    // directory of the memory-mapped layers, and the size from which layers are mapped
+    mapped_layers: Option<(PathBuf, u64)>,
+    inner: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Entries {
+    layers: HashMap<String, Entry>,
+    size: usize,
+    // monotonic counter used to find the least recently used entry
+    clock: u64,
+    // the files of the memory-mapped layers
+    files: Option<LayerFiles>,
+}
+
+struct Entry {
+    data: LayerContent,
+    last_used: u64,
+    hits: u64,
+    // ids of the instances using the layer
+    users: HashSet<String>,
+}
+
+impl LayerCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            mapped_layers: None,
+            inner: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Memory-maps the layers of at least `threshold` bytes from files in `dir`, including the
+    /// files of the other shims and the ones left there by a previous shim.
+    pub(crate) fn with_mapped_layers(mut self, dir: impl Into<PathBuf>, threshold: u64) -> Self {
+        let dir = dir.into();
+        self.inner.get_mut().unwrap().files = Some(LayerFiles::load(&dir));
+        self.mapped_layers = Some((dir, threshold));
+        self
+    }
+
+    /// Limits the files of the memory-mapped layers to `quota` bytes.
+    pub(crate) fn with_files_quota(mut self, quota: u64) -> Self {
+        let files = &mut self.inner.get_mut().unwrap().files;
+        *files = files.take().map(|files| files.with_quota(quota));
+        self
+    }
+
+    /// Evicts the files of the memory-mapped layers that no instance uses, until there is room
+    /// for a new one of `size` bytes within the quota.
+    pub(crate) fn evict_files(&self, size: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let Entries { layers, files, .. } = &mut *inner;
+        let Some(files) = files else {
+            return;
+        };
+        files.evict(size, |path| {
+            layers.values().any(|entry| entry.data.path() == Some(path))
+        });
+    }
+
+    /// Returns the path of the file to memory-map the content with `digest` from,
+    /// or `None` if content of `size` bytes is kept in memory.
+    pub(crate) fn mapped_path(&self, digest: &str, size: u64) -> Option<PathBuf> {
+        let (dir, threshold) = self.mapped_layers.as_ref()?;
+        (size >= *threshold).then(|| dir.join(digest.replace(':', "-")))
+    }
+
+    /// Returns the content with `digest` for the instance `id`, if it's cached.
+    pub(crate) fn get(&self, id: &str, digest: &str) -> Option<LayerContent> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let data = inner.layers.get_mut(digest).map(|entry| {
+            entry.last_used = clock;
+            entry.hits += 1;
+            entry.users.insert(id.to_string());
+            entry.data.clone()
+        });
+
+        let (hits, misses) = match data {
+            Some(_) => (self.hits.fetch_add(1, Ordering::Relaxed) + 1, self.misses()),
+            None => (self.hits(), self.misses.fetch_add(1, Ordering::Relaxed) + 1),
+        };
+        let outcome = if data.is_some() { "hit" } else { "miss" };
+        log::info!("layer cache {outcome} for {digest} (hits: {hits}, misses: {misses})");
+
+        data
+    }
+
+    /// Caches the content with `digest` used by the instance `id`,
+    /// evicting the least recently used layers if needed.
+    pub(crate) fn insert(&self, id: &str, digest: &str, data: LayerContent) {
+        let size = size_of(&data);
+        if size > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if let Some(entry) = inner.layers.get_mut(digest) {
+            entry.last_used = clock;
+            entry.users.insert(id.to_string());
+            return;
+        }
+
+        while inner.size + size > self.capacity {
+            let Some(lru) = inner
+                .layers
+                .iter()
+                .filter(|(_, entry)| !entry.data.is_mapped())
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(digest, _)| digest.clone())
+            else {
+                break;
+            };
+            log::debug!("evicting layer {lru} from the layer cache");
+            inner.remove(&lru);
+        }
+
+        inner.size += size;
+        inner.layers.insert(
+            digest.to_string(),
+            Entry {
+                data,
+                last_used: clock,
+                hits: 0,
+                users: HashSet::from([id.to_string()]),
+            },
+        );
+    }
+
+    /// Returns the memory-mapped content with `digest` for the instance `id`, mapping it from
+    /// the file at `path` if it's not cached yet. Returns `None` if the file doesn't exist.
+    /// The file is mapped while holding the lock, so that it can't be removed in the meantime.
+    pub(crate) fn insert_mapped(
+        &self,
+        id: &str,
+        digest: &str,
+        path: &Path,
+    ) -> std::io::Result<Option<LayerContent>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if let Some(entry) = inner.layers.get_mut(digest) {
+            entry.last_used = clock;
+            entry.users.insert(id.to_string());
+            return Ok(Some(entry.data.clone()));
+        }
+
+        let data = match LayerContent::map(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if let Some(files) = &mut inner.files {
+            files.touch(path);
+        }
+        inner.layers.insert(
+            digest.to_string(),
+            Entry {
+                data: data.clone(),
+                last_used: clock,
+                hits: 0,
+                users: HashSet::from([id.to_string()]),
+            },
+        );
+        Ok(Some(data))
+    }
+
+    /// Releases the layers used by the instance `id`, dropping the ones no other instance uses.
+    pub(crate) fn release(&self, id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let unused: Vec<_> = inner
+            .layers
+            .iter_mut()
+            .filter_map(|(digest, entry)| {
+                (entry.users.remove(id) && entry.users.is_empty()).then(|| digest.clone())
+            })
+            .collect();
+        for digest in unused {
+            inner.remove(&digest);
+        }
+    }
+
+    /// Returns the content of the cache, for the debug dumps.
+    pub(crate) fn snapshot(&self) -> LayerCacheSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let mut layers: Vec<_> = inner
+            .layers
+            .iter()
+            .map(|(digest, entry)| {
+                let mut users: Vec<_> = entry.users.iter().cloned().collect();
+                users.sort();
+                LayerSnapshot {
+                    digest: digest.clone(),
+                    size: entry.data.len(),
+                    file: entry.data.path().map(Path::to_path_buf),
+                    hits: entry.hits,
+                    users,
+                }
+            })
+            .collect();
+        layers.sort_by(|a, b| a.digest.cmp(&b.digest));
+        LayerCacheSnapshot {
+            capacity: self.capacity,
+            size: inner.size,
+            hits: self.hits(),
+            misses: self.misses(),
+            layers,
+            files: inner.files.as_ref().map(LayerFiles::snapshot),
+        }
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, digest: &str) {
+        let Some(entry) = self.layers.remove(digest) else {
+            return;
+        };
+        self.size -= size_of(&entry.data);
+        // the file is kept for later instances, which makes it the most recently used
+        if let (Some(path), Some(files)) = (entry.data.path(), &mut self.files) {
+            files.touch(path);
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct LayerCacheSnapshot {
+    capacity: usize,
+    size: usize,
+    hits: u64,
+    misses: u64,
+    layers: Vec<LayerSnapshot>,
+    files: Option<LayerFilesSnapshot>,
+}
+
+#[derive(Serialize)]
+struct LayerSnapshot {
+    digest: String,
+    size: usize,
+    // the file the layer is mapped from, if it's not in memory
+    file: Option<PathBuf>,
+    hits: u64,
+    users: Vec<String>,
+}
+
+// the size the content takes in the cache
+fn size_of(data: &LayerContent) -> usize {
+    if data.is_mapped() { 0 } else { data.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_cache_hits_and_misses() {
+        let cache = LayerCache::new(1024);
+
+        assert_eq!(cache.get("a", "sha256:1"), None);
+        cache.insert("a", "sha256:1", b"layer".to_vec().into());
+        assert_eq!(cache.get("b", "sha256:1").as_deref(), Some(&b"layer"[..]));
+
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        let snapshot = serde_json::to_value(cache.snapshot()).unwrap();
+        assert_eq!(snapshot["hits"], 1);
+        assert_eq!(snapshot["layers"][0]["digest"], "sha256:1");
+        assert_eq!(snapshot["layers"][0]["hits"], 1);
+        assert_eq!(
+            snapshot["layers"][0]["users"],
+            serde_json::json!(["a", "b"])
+        );
+    }
+
+    #[test]
+    fn test_layer_cache_evicts_least_recently_used() {
+        let cache = LayerCache::new(10);
+
+        cache.insert("a", "sha256:1", vec![0; 4].into());
+        cache.insert("a", "sha256:2", vec![0; 4].into());
+        assert!(cache.get("a", "sha256:1").is_some());
+
+        // evicts sha256:2, as sha256:1 was used more recently
+        cache.insert("a", "sha256:3", vec![0; 4].into());
+        assert!(cache.get("a", "sha256:1").is_some());
+        assert!(cache.get("a", "sha256:2").is_none());
+        assert!(cache.get("a", "sha256:3").is_some());
+
+        // layers bigger than the cache are not cached
+        cache.insert("a", "sha256:4", vec![0; 11].into());
+        assert!(cache.get("a", "sha256:4").is_none());
+        assert!(cache.get("a", "sha256:1").is_some());
+    }
+
+    #[test]
+    fn test_layer_cache_drops_unused_layers() {
+        let cache = LayerCache::new(1024);
+
+        cache.insert("a", "sha256:1", b"shared".to_vec().into());
+        cache.insert("a", "sha256:2", b"only a".to_vec().into());
+        assert!(cache.get("b", "sha256:1").is_some());
+
+        cache.release("a");
+        assert!(cache.get("c", "sha256:2").is_none());
+
+        cache.release("b");
+        cache.release("c");
+        assert!(cache.get("d", "sha256:1").is_none());
+    }
+
+    #[test]
+    fn test_layer_cache_maps_large_layers() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = LayerCache::new(4).with_mapped_layers(dir.path(), 8);
+
+        assert_eq!(cache.mapped_path("sha256:1", 7), None);
+        let path = cache.mapped_path("sha256:1", 8).unwrap();
+        assert_eq!(path, dir.path().join("sha256-1"));
+
+        assert!(cache.insert_mapped("a", "sha256:1", &path)?.is_none());
+        std::fs::write(&path, [1u8; 8])?;
+        assert!(cache.insert_mapped("a", "sha256:1", &path)?.is_some());
+        // mapped layers don't count towards the size of the cache
+        cache.insert("a", "sha256:2", vec![0; 4].into());
+
+        let layer = cache.get("b", "sha256:1").unwrap();
+        assert!(layer.is_mapped());
+        assert_eq!(&*layer, &[1; 8]);
+        assert!(cache.get("b", "sha256:2").is_some());
+
+        // the file is kept after the last user for the next ones
+        cache.release("a");
+        cache.release("b");
+        assert!(path.exists());
+        assert!(cache.insert_mapped("c", "sha256:1", &path)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_cache_evicts_unused_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = LayerCache::new(4)
+            .with_mapped_layers(dir.path(), 8)
+            .with_files_quota(16);
+
+        let first = cache.mapped_path("sha256:1", 8).unwrap();
+        std::fs::write(&first, [1u8; 8])?;
+        let layer = cache.insert_mapped("a", "sha256:1", &first)?.unwrap();
+        let second = cache.mapped_path("sha256:2", 8).unwrap();
+        std::fs::write(&second, [2u8; 8])?;
+        cache.insert_mapped("b", "sha256:2", &second)?;
+
+        // the files in use are not evicted to make room for a new one
+        cache.evict_files(8);
+        assert!(first.exists());
+        assert!(second.exists());
+
+        // the file is evicted once it's neither used nor mapped
+        cache.release("a");
+        cache.evict_files(8);
+        assert!(first.exists());
+        assert_eq!(&*layer, &[1; 8]);
+        drop(layer);
+        cache.evict_files(8);
+        assert!(!first.exists());
+        assert!(second.exists());
+
+        Ok(())
+    }
+}
@@ -3,7 +3,10 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::{DefaultHasher, Hash, Hasher as _};
-use std::path::Path;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use containerd_client::services::v1::containers_client::ContainersClient;
 use containerd_client::services::v1::content_client::ContentClient;
@@ -11,23 +14,38 @@ use containerd_client::services::v1::images_client::ImagesClient;
 use containerd_client::services::v1::leases_client::LeasesClient;
 use containerd_client::services::v1::{
     Container, DeleteContentRequest, GetContainerRequest, GetImageRequest, Image, Info,
-    InfoRequest, ReadContentRequest, UpdateRequest, WriteAction, WriteContentRequest,
-    WriteContentResponse,
+    InfoRequest, ListImagesRequest, ReadContentRequest, ReadContentResponse, UpdateRequest,
+    WriteAction, WriteContentRequest, WriteContentResponse,
 };
 use containerd_client::tonic::Streaming;
 use containerd_client::tonic::transport::Channel;
 use containerd_client::{tonic, with_namespace};
 use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
-use futures::TryStreamExt;
-use oci_spec::image::{Arch, Digest, ImageManifest, MediaType, Platform};
+use futures::{StreamExt as _, TryStreamExt};
+use oci_spec::image::{
+    Arch, Config, Descriptor, Digest, ImageIndex, ImageManifest, MediaType, Os, Platform,
+};
 use sha256::digest;
+use tokio::io::AsyncWriteExt as _;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Code, Request};
 
+use super::cache::LAYER_CACHE;
+use super::compilations::COMPILATIONS;
+use super::compose::Composition;
+use super::compression::{self, Compression, uncompressed_media_type};
+use super::digest::{DigestVerifier, verify_digests};
 use super::lease::LeaseGuard;
-use crate::sandbox::context::WasmLayer;
-use crate::shim::Compiler;
+use super::retry::Backoff;
+use super::signature::{self, Mode, Policy, SIMPLE_SIGNING_MEDIA_TYPE, Signature};
+use super::timeout::{Timeouts, with_timeout};
+use crate::sandbox::context::{
+    LayerContent, RUN_CONFIG_MEDIA_TYPE, RunConfig, WAT_LAYER_MEDIA_TYPE, WasmBinaryType,
+    WasmConfig, WasmLayer, WasmLayerKind, is_wat, wat_to_wasm,
+};
+use crate::sandbox::error::Error;
+use crate::shim::{Compiler, PrecompiledLayer, ShimConfig};
 
 // Adds lease info to grpc header
 // https://github.com/containerd/containerd/blob/8459273f806e068e1a6bacfaf1355bbbad738d5e/docs/garbage-collection.md#using-grpc
@@ -43,15 +61,78 @@ macro_rules! with_lease {
 }
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
+// Media types of the config of a wasm OCI artifact
+const WASM_CONFIG_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.wasm.config.v0+json",
+    "application/vnd.wasm.config.v1+json",
+];
+// Media type of the layers of a wasm OCI artifact
+const WASM_ARTIFACT_LAYER_MEDIA_TYPE: &str = "application/wasm";
+// Media type of the wasm layers of images built with `oci-tar-builder`
+const COMPONENT_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm";
+// Label on a compressed layer with the digest of its uncompressed content
+const UNCOMPRESSED_LABEL: &str = "runwasi.io/uncompressed";
 // 16MB is the default maximum gRPC message size for gRPC in containerd:
 // https://github.com/containerd/containerd/blob/main/defaults/defaults.go
 // Conservatively set the max to 15MB to leave room for message overhead
 static MAX_WRITE_CHUNK_SIZE_BYTES: i64 = 1024 * 1024 * 15;
+// Number of bytes requested from the content store per `Read` call
+const DEFAULT_CONTENT_READ_CHUNK_SIZE: i64 = 4 * 1024 * 1024;
+
+/// How the layers of an image with a media type the engine doesn't support are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum LayerPolicy {
+    /// The layers are skipped with a warning.
+    #[default]
+    Lenient,
+    /// Loading the layers fails, listing all the unsupported media types.
+    Strict,
+}
+
+impl LayerPolicy {
+    /// Returns the policy of the shim, which is lenient unless `layers.strict` is `true`.
+    pub(crate) fn from_config() -> Self {
+        match ShimConfig::get().layers.strict {
+            Some(true) => Self::Strict,
+            _ => Self::Lenient,
+        }
+    }
+}
+
+// An image with wasm layers
+struct WasmImage {
+    // digest of the manifest of the image
+    digest: Digest,
+    layers: Vec<Descriptor>,
+    // config of the image if it's a wasm OCI artifact
+    wasm_config: Option<WasmConfig>,
+    // process defaults of the image config blob
+    image_config: Option<Config>,
+    // how to run the image, from its run config layer
+    run_config: Option<RunConfig>,
+}
+
+impl WasmImage {
+    // a wasm layer of the image, with the metadata of the image
+    fn layer(&self, config: Descriptor, layer: LayerContent) -> WasmLayer {
+        WasmLayer {
+            config,
+            kind: WasmLayerKind::from_bytes(&layer),
+            layer,
+            wasm_config: self.wasm_config.clone(),
+            image_config: self.image_config.clone(),
+            run_config: self.run_config.clone(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Client {
     inner: Channel,
     namespace: String,
+    rpc_timeout: Duration,
+    signature_policy: Option<&'static Policy>,
 }
 
 #[derive(Debug)]
@@ -76,33 +157,125 @@ impl Client {
         address: impl AsRef<Path> + std::fmt::Debug,
         namespace: impl Into<String> + std::fmt::Debug,
     ) -> Result<Client> {
-        let inner = containerd_client::connect(address.as_ref())
+        let timeouts = Timeouts::new(&ShimConfig::get().containerd);
+        let inner = with_timeout(
+            "connect to containerd",
+            timeouts.connect,
+            containerd_client::connect(address.as_ref()),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?;
+
+        Ok(Client {
+            inner,
+            namespace: namespace.into(),
+            rpc_timeout: timeouts.rpc,
+            signature_policy: None,
+        })
+    }
+
+    /// Like [`Client::connect`], but retries with `backoff` while containerd doesn't accept
+    /// connections, e.g., because it's restarting.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub(crate) async fn connect_with_retry(
+        address: impl AsRef<Path> + std::fmt::Debug,
+        namespace: impl Into<String> + std::fmt::Debug,
+        backoff: &Backoff,
+    ) -> Result<Client> {
+        let address = address.as_ref();
+        let timeouts = Timeouts::new(&ShimConfig::get().containerd);
+        // a hung containerd is not retried, only one that refuses connections
+        let connect = || async {
+            match tokio::time::timeout(timeouts.connect, containerd_client::connect(address)).await
+            {
+                Ok(res) => res.map_err(ConnectError::Transport),
+                Err(_) => Err(ConnectError::Timeout),
+            }
+        };
+        let inner = backoff
+            .retry("connect to containerd", connect, |err| match err {
+                ConnectError::Transport(err) => is_transient_connect_error(err, address),
+                ConnectError::Timeout => false,
+            })
             .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?;
+            .map_err(|err| match err {
+                ConnectError::Transport(err) => ShimError::Containerd(err.to_string()),
+                ConnectError::Timeout => ShimError::Timeout {
+                    what: "connect to containerd".to_string(),
+                    timeout: timeouts.connect,
+                },
+            })?;
 
         Ok(Client {
             inner,
             namespace: namespace.into(),
+            rpc_timeout: timeouts.rpc,
+            signature_policy: None,
+        })
+    }
+
+    /// Verifies the signatures of the images with `policy` before loading their layers.
+    pub(crate) fn with_signature_policy(self, policy: Option<&'static Policy>) -> Self {
+        Self {
+            signature_policy: policy,
+            ..self
+        }
+    }
+
+    // reads the content with `digest` in chunks of `chunk_size` bytes
+    fn content_reader(&self, digest: impl ToString, chunk_size: i64) -> Result<ContentReader> {
+        let digest: Digest = digest.to_string().parse()?;
+        Ok(ContentReader {
+            client: ContentClient::new(self.inner.clone()),
+            namespace: self.namespace.clone(),
+            rpc_timeout: self.rpc_timeout,
+            verifier: Some(DigestVerifier::new(digest.clone(), verify_digests())),
+            digest,
+            chunk_size,
+            offset: 0,
+            stream: None,
+            chunk_read: 0,
         })
     }
 
     // wrapper around read that will read the entire content file
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn read_content(&self, digest: impl ToString + std::fmt::Debug) -> Result<Vec<u8>> {
-        let req = ReadContentRequest {
-            digest: digest.to_string(),
-            ..Default::default()
+        let mut reader = self.content_reader(digest, content_read_chunk_size())?;
+        let mut data = vec![];
+        while let Some(chunk) = reader.next().await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    // like `read_content`, but streams the content to the file at `path` instead of buffering it.
+    // The file is replaced atomically, so that it's never mapped while partially written.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn read_content_to_file(
+        &self,
+        digest: impl ToString + std::fmt::Debug,
+        path: &Path,
+    ) -> Result<()> {
+        let mut reader = self.content_reader(digest, content_read_chunk_size())?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut tmp = TempFile {
+            path: unique_path(path, "tmp"),
+            keep: false,
         };
-        let req = with_namespace!(req, self.namespace);
-        ContentClient::new(self.inner.clone())
-            .read(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .map_ok(|msg| msg.data)
-            .try_concat()
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))
+
+        let mut file = tokio::fs::File::create(&tmp.path).await?;
+        while let Some(chunk) = reader.next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp.path, path).await?;
+        tmp.keep = true;
+        Ok(())
     }
 
     // used in tests to clean up content
@@ -133,15 +306,18 @@ impl Client {
         };
 
         let mut leases_client = LeasesClient::new(self.inner.clone());
-        let lease = leases_client
-            .create(with_namespace!(lease_request, self.namespace))
-            .await
-            .map_err(|e| ShimError::Containerd(e.to_string()))?
-            .into_inner()
-            .lease
-            .ok_or_else(|| {
-                ShimError::Containerd(format!("unable to create lease for  {}", reference))
-            })?;
+        let lease = with_timeout(
+            "Leases.Create",
+            self.rpc_timeout,
+            leases_client.create(with_namespace!(lease_request, self.namespace)),
+        )
+        .await?
+        .map_err(|e| ShimError::Containerd(e.to_string()))?
+        .into_inner()
+        .lease
+        .ok_or_else(|| {
+            ShimError::Containerd(format!("unable to create lease for  {}", reference))
+        })?;
 
         Ok(LeaseGuard::new(
             leases_client.clone(),
@@ -188,6 +364,10 @@ impl Client {
                 Ok(response_stream) => response_stream.into_inner(),
                 Err(e) if e.code() == Code::AlreadyExists => {
                     log::info!("content already exists {expected}");
+                    // labels are only set when the content is committed, add them to the existing content
+                    let mut info = self.get_info(&expected.parse()?).await?;
+                    info.labels.extend(labels);
+                    self.update_info(info).await?;
                     break 'digest expected;
                 }
                 Err(e) => return Err(ShimError::Containerd(e.to_string())),
@@ -278,15 +458,18 @@ impl Client {
             digest: content_digest.to_string(),
         };
         let req = with_namespace!(req, self.namespace);
-        let info = ContentClient::new(self.inner.clone())
-            .info(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .info
-            .ok_or_else(|| {
-                ShimError::Containerd(format!("failed to get info for content {}", content_digest))
-            })?;
+        let info = with_timeout(
+            "Content.Info",
+            self.rpc_timeout,
+            ContentClient::new(self.inner.clone()).info(req),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?
+        .into_inner()
+        .info
+        .ok_or_else(|| {
+            ShimError::Containerd(format!("failed to get info for content {}", content_digest))
+        })?;
         Ok(info)
     }
 
@@ -301,15 +484,18 @@ impl Client {
         // Depending on it would mean keeping it's version in sync with the version in `containerd-client`.
         req.update_mask.as_mut().unwrap().paths = vec!["labels".to_string()];
         let req = with_namespace!(req, self.namespace);
-        let info = ContentClient::new(self.inner.clone())
-            .update(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .info
-            .ok_or_else(|| {
-                ShimError::Containerd(format!("failed to update info for content {}", info.digest))
-            })?;
+        let info = with_timeout(
+            "Content.Update",
+            self.rpc_timeout,
+            ContentClient::new(self.inner.clone()).update(req),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?
+        .into_inner()
+        .info
+        .ok_or_else(|| {
+            ShimError::Containerd(format!("failed to update info for content {}", info.digest))
+        })?;
         Ok(info)
     }
 
@@ -318,21 +504,40 @@ impl Client {
         let name = image_name.to_string();
         let req = GetImageRequest { name };
         let req = with_namespace!(req, self.namespace);
-        let image = ImagesClient::new(self.inner.clone())
-            .get(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .image
-            .ok_or_else(|| {
-                ShimError::Containerd(format!(
-                    "failed to get image for image {}",
-                    image_name.to_string()
-                ))
-            })?;
+        let image = with_timeout(
+            "Images.Get",
+            self.rpc_timeout,
+            ImagesClient::new(self.inner.clone()).get(req),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?
+        .into_inner()
+        .image
+        .ok_or_else(|| {
+            ShimError::Containerd(format!(
+                "failed to get image for image {}",
+                image_name.to_string()
+            ))
+        })?;
         Ok(image)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn list_images(&self) -> Result<Vec<Image>> {
+        let req = ListImagesRequest { filters: vec![] };
+        let req = with_namespace!(req, self.namespace);
+        let images = with_timeout(
+            "Images.List",
+            self.rpc_timeout,
+            ImagesClient::new(self.inner.clone()).list(req),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?
+        .into_inner()
+        .images;
+        Ok(images)
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     fn extract_image_content_sha(&self, image: &Image) -> Result<String> {
         let digest = image
@@ -355,17 +560,20 @@ impl Client {
         let id = container_name.to_string();
         let req = GetContainerRequest { id };
         let req = with_namespace!(req, self.namespace);
-        let container = ContainersClient::new(self.inner.clone())
-            .get(req)
-            .await
-            .map_err(|err| ShimError::Containerd(err.to_string()))?
-            .into_inner()
-            .container
-            .ok_or_else(|| {
-                ShimError::Containerd(format!(
-                    "failed to get image for container {container_name}",
-                ))
-            })?;
+        let container = with_timeout(
+            "Containers.Get",
+            self.rpc_timeout,
+            ContainersClient::new(self.inner.clone()).get(req),
+        )
+        .await?
+        .map_err(|err| ShimError::Containerd(err.to_string()))?
+        .into_inner()
+        .container
+        .ok_or_else(|| {
+            ShimError::Containerd(format!(
+                "failed to get image for container {container_name}",
+            ))
+        })?;
         Ok(container)
     }
 
@@ -375,28 +583,76 @@ impl Client {
         image_name: &str,
     ) -> Result<(ImageManifest, Digest)> {
         let image = self.get_image(image_name).await?;
-        let image_digest = self.extract_image_content_sha(&image)?.try_into()?;
-        let manifest =
-            ImageManifest::from_reader(self.read_content(&image_digest).await?.as_slice())?;
+        let mut image_digest: Digest = self.extract_image_content_sha(&image)?.try_into()?;
+        let mut content = self.read_content(&image_digest).await?;
+
+        let media_type = image
+            .target
+            .as_ref()
+            .map(|target| target.media_type.as_str());
+        if media_type.is_some_and(is_index) {
+            let index = ImageIndex::from_reader(content.as_slice())?;
+            let manifest = select_manifest(&index).ok_or_else(|| {
+                ShimError::NotFound(format!("no wasm manifest in image index {image_digest}"))
+            })?;
+            log::info!(
+                "using manifest {} of image index {image_digest}",
+                manifest.digest()
+            );
+            image_digest = manifest.digest().clone();
+            content = self.read_content(&image_digest).await?;
+        }
+
+        let manifest = ImageManifest::from_reader(content.as_slice())?;
         Ok((manifest, image_digest))
     }
 
-    // load module will query the containerd store to find an image that has an OS of type 'wasm'
-    // If found it continues to parse the manifest and return the layers that contains the WASM modules
-    // and possibly other configuration layers.
-    #[cfg_attr(
-        feature = "tracing",
-        tracing::instrument(skip(compiler), level = "Debug")
-    )]
-    pub async fn load_modules(
+    // Returns the wasm layers of the image `image_name`, or `None` if the image is neither
+    // a wasm OCI artifact nor in the wasm OCI image format, or if it has no wasm layers.
+    async fn wasm_layer_configs(
         &self,
-        containerd_id: impl AsRef<str> + Debug,
-        engine_name: impl AsRef<str> + Debug,
+        image_name: &str,
         supported_layer_types: &[&str],
-        compiler: Option<&impl Compiler>,
-    ) -> Result<Vec<WasmLayer>> {
-        let container = self.get_container(containerd_id).await?;
-        let (manifest, image_digest) = self.get_image_manifest_and_digest(&container.image).await?;
+        layer_policy: LayerPolicy,
+    ) -> Result<Option<WasmImage>> {
+        let (manifest, image_digest) = self.get_image_manifest_and_digest(image_name).await?;
+
+        if is_wasm_artifact(&manifest) {
+            log::info!("found manifest of a wasm OCI artifact");
+            let config = manifest.config();
+            let config_type = config.media_type().to_string();
+            // artifacts with an `artifactType` may have an empty config, or an image config
+            let is_wasm_config = WASM_CONFIG_MEDIA_TYPES.contains(&config_type.as_str());
+            let (wasm_config, image_config) = if is_wasm_config || is_image_config(&config_type) {
+                let blob = self.read_content(config.digest()).await?;
+                let wasm_config = if is_wasm_config {
+                    serde_json::from_slice(&blob)?
+                } else {
+                    WasmConfig::default()
+                };
+                (wasm_config, image_process_config(&blob))
+            } else {
+                (WasmConfig::default(), None)
+            };
+
+            let layers = select_layers(
+                manifest.layers(),
+                |media_type| uncompressed_media_type(media_type) == WASM_ARTIFACT_LAYER_MEDIA_TYPE,
+                layer_policy,
+            )?;
+            if layers.is_empty() {
+                log::info!("no wasm layers found in OCI artifact");
+                return Ok(None);
+            }
+
+            return Ok(Some(WasmImage {
+                digest: image_digest,
+                layers,
+                wasm_config: Some(wasm_config),
+                image_config,
+                run_config: self.read_run_config(&manifest).await?,
+            }));
+        }
 
         let image_config_descriptor = manifest.config();
         let image_config = self.read_content(image_config_descriptor.digest()).await?;
@@ -406,52 +662,267 @@ impl Client {
         let platform: Platform = serde_json::from_slice(image_config)?;
         let Arch::Wasm = platform.architecture() else {
             log::info!("manifest is not in WASM OCI image format");
-            return Ok(vec![]);
+            return Ok(None);
         };
 
         log::info!("found manifest with WASM OCI image format");
-        // This label is unique across runtimes and version of the shim running
-        // a precompiled component/module will not work across different runtimes or versions
 
-        let configs = manifest
-            .layers()
-            .iter()
-            .filter(|x| is_wasm_layer(x.media_type(), supported_layer_types))
-            .collect::<Vec<_>>();
+        let configs = select_layers(
+            manifest.layers(),
+            |media_type| is_wasm_layer(media_type, supported_layer_types),
+            layer_policy,
+        )?;
 
         if configs.is_empty() {
             log::info!("no WASM layers found in OCI image");
-            return Ok(vec![]);
+            return Ok(None);
+        }
+
+        Ok(Some(WasmImage {
+            digest: image_digest,
+            layers: configs,
+            wasm_config: None,
+            image_config: image_process_config(image_config),
+            run_config: self.read_run_config(&manifest).await?,
+        }))
+    }
+
+    // Verifies the signatures of the image `image_name` with the signature policy of the client,
+    // if it has one. The images that fail the verification, including when their signatures
+    // can't be read, fail the loading of their layers, unless the policy only warns about them.
+    async fn verify_signatures(&self, image_name: &str) -> Result<()> {
+        let Some(policy) = self.signature_policy else {
+            return Ok(());
+        };
+        match (
+            self.check_signatures(policy, image_name).await,
+            policy.mode(),
+        ) {
+            (Ok(()), _) => Ok(()),
+            (Err(err), Mode::Warn) => {
+                log::warn!(
+                    "running image {image_name}, whose signature verification failed: {err}"
+                );
+                Ok(())
+            }
+            (Err(err), Mode::Enforce) => Err(err),
+        }
+    }
+
+    async fn check_signatures(&self, policy: &Policy, image_name: &str) -> Result<()> {
+        let (_, manifest_digest) = self.get_image_manifest_and_digest(image_name).await?;
+        let manifest_digest = manifest_digest.to_string();
+        if signature::is_verified(&manifest_digest) {
+            return Ok(());
+        }
+
+        // the signatures may be for the image index, or for the manifest of the platform
+        let mut digests = vec![self.extract_image_content_sha(&self.get_image(image_name).await?)?];
+        if digests[0] != manifest_digest {
+            digests.push(manifest_digest.clone());
         }
+        let signatures = self.image_signatures(image_name, &digests).await?;
+        let signer = policy.verify(&signatures, &digests).map_err(|reason| {
+            ShimError::SignatureVerification {
+                image: image_name.to_string(),
+                reason,
+            }
+        })?;
+        log::info!("image {image_name} is signed by {signer}");
+        signature::set_verified(&manifest_digest);
+        Ok(())
+    }
+
+    // Returns the signatures of the image `image_name` with `digests` in the content store:
+    // the ones of the image cosign attaches them to, and of the manifests of the repository
+    // with the image as their subject.
+    async fn image_signatures(
+        &self,
+        image_name: &str,
+        digests: &[String],
+    ) -> Result<Vec<Signature>> {
+        let repository = signature::repository(image_name);
+        let tags: Vec<_> = digests
+            .iter()
+            .map(|digest| signature::signature_tag(repository, digest))
+            .collect();
+
+        let mut signatures = vec![];
+        for image in self.list_images().await? {
+            let in_repository = image
+                .name
+                .strip_prefix(repository)
+                .is_some_and(|rest| rest.starts_with([':', '@']));
+            let Some(target) = image.target.filter(|_| in_repository) else {
+                continue;
+            };
+            if is_index(&target.media_type) {
+                continue;
+            }
+            let content = self.read_content(&target.digest).await?;
+            let Ok(manifest) = ImageManifest::from_reader(content.as_slice()) else {
+                continue;
+            };
+            let is_referrer = manifest
+                .subject()
+                .as_ref()
+                .is_some_and(|subject| digests.contains(&subject.digest().to_string()));
+            if !tags.contains(&image.name) && !is_referrer {
+                continue;
+            }
+
+            let layers = manifest
+                .layers()
+                .iter()
+                .filter(|layer| layer.media_type().to_string() == SIMPLE_SIGNING_MEDIA_TYPE);
+            for layer in layers {
+                let payload = self.read_content(layer.digest()).await?;
+                match Signature::from_layer(layer.annotations().as_ref(), payload) {
+                    Ok(signature) => signatures.push(signature),
+                    Err(err) => log::warn!("skipping signature layer {}: {err}", layer.digest()),
+                }
+            }
+        }
+        Ok(signatures)
+    }
+
+    // Reads the run config of the image from its `RUN_CONFIG_MEDIA_TYPE` layer, if it has one.
+    // A malformed config is an error in the image, which fails the creation of the container.
+    async fn read_run_config(&self, manifest: &ImageManifest) -> Result<Option<RunConfig>> {
+        let Some(layer) = manifest
+            .layers()
+            .iter()
+            .find(|layer| layer.media_type().to_string() == RUN_CONFIG_MEDIA_TYPE)
+        else {
+            return Ok(None);
+        };
+        let blob = self.read_content(layer.digest()).await?;
+        let config = RunConfig::from_slice(&blob).map_err(|err| {
+            ShimError::InvalidArgument(format!(
+                "invalid run config layer {}: {err}",
+                layer.digest()
+            ))
+        })?;
+        Ok(Some(config))
+    }
+
+    /// Returns the name of the image of the container `containerd_id`, with the digest
+    /// containerd resolves it to, or `None` if the container has no image.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub(crate) async fn container_image(
+        &self,
+        containerd_id: impl AsRef<str> + Debug,
+    ) -> Result<Option<(String, String)>> {
+        let container = self.get_container(containerd_id).await?;
+        if container.image.is_empty() {
+            return Ok(None);
+        }
+        let image = self.get_image(&container.image).await?;
+        let digest = self.extract_image_content_sha(&image)?;
+        Ok(Some((container.image, digest)))
+    }
+
+    // load module will query the containerd store to find an image that has an OS of type 'wasm'
+    // If found it continues to parse the manifest and return the layers that contains the WASM modules
+    // and possibly other configuration layers.
+    // If `force_precompile` is set, the layers are recompiled even if they were already precompiled.
+    // Layers with a media type that's not supported are skipped or fail the load, per `layer_policy`.
+    // Returns how long precompiling the layers took along with them, if they were precompiled.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(compiler), level = "Debug")
+    )]
+    pub async fn load_modules(
+        &self,
+        containerd_id: impl AsRef<str> + Debug,
+        engine_name: impl AsRef<str> + Debug,
+        supported_layer_types: &[&str],
+        compiler: Option<&impl Compiler>,
+        force_precompile: bool,
+        layer_policy: LayerPolicy,
+    ) -> Result<(Vec<WasmLayer>, Option<Duration>), Error> {
+        self.load_layers(
+            containerd_id.as_ref(),
+            engine_name.as_ref(),
+            supported_layer_types,
+            compiler,
+            force_precompile,
+            layer_policy,
+        )
+        .await
+        .map_err(Error::ImageResolution)
+    }
+
+    // The failures of the compilation of the layers are logged, and the original layers are
+    // returned then
+    async fn load_layers(
+        &self,
+        containerd_id: &str,
+        engine_name: &str,
+        supported_layer_types: &[&str],
+        compiler: Option<&impl Compiler>,
+        force_precompile: bool,
+        layer_policy: LayerPolicy,
+    ) -> Result<(Vec<WasmLayer>, Option<Duration>)> {
+        let container = self.get_container(containerd_id).await?;
+        // images without wasm layers are verified too, as the files of their rootfs run then
+        self.verify_signatures(&container.image).await?;
+        let Some(image) = self
+            .wasm_layer_configs(&container.image, supported_layer_types, layer_policy)
+            .await?
+        else {
+            return Ok((vec![], None));
+        };
+        let image_digest = &image.digest;
+        let composition = compose_layers(&image)?;
+        let roots = composition.roots();
 
         log::info!("using OCI layers");
 
         let Some(compiler) = compiler else {
             let mut layers = vec![];
-            for config in configs {
-                let layer = self.read_original_layer(config).await?;
+            for &i in &roots {
+                let layer = self
+                    .read_composed_layer(containerd_id, &image, &composition, i)
+                    .await?;
                 layers.push(layer);
             }
-            return Ok(layers);
+            return Ok((layers, None));
         };
 
-        let precompile_id = precompile_label(engine_name.as_ref(), compiler.cache_key());
+        // This label is unique across runtimes and version of the shim running
+        // a precompiled component/module will not work across different runtimes or versions
+        let precompile_id = precompile_label(engine_name, compiler.cache_key());
 
-        let image_info = self.get_info(&image_digest).await?;
-        let mut needs_precompile = !image_info.labels.contains_key(&precompile_id);
+        let image_info = self.get_info(image_digest).await?;
+        let mut needs_precompile =
+            force_precompile || !image_info.labels.contains_key(&precompile_id);
 
+        let sources: Vec<_> = roots.iter().map(|i| composition.source(*i)).collect();
         let mut layers = vec![];
-        for original_config in configs {
-            let layer = match self
-                .read_precompiled_layer(original_config, &precompile_id)
-                .await
-            {
-                Ok(layer) => layer,
+        for (&i, source) in roots.iter().zip(&sources) {
+            // when forced, compile the original layers even if there are precompiled ones
+            let precompiled = if force_precompile {
+                Ok(None)
+            } else {
+                let config = &image.layers[i];
+                self.read_precompiled_layer(containerd_id, config, &precompile_id, source, &image)
+                    .await
+            };
+            let layer = match precompiled {
+                Ok(Some(layer)) => layer,
+                // the compiler didn't produce an artifact for this layer, or the image
+                // isn't precompiled yet, in which case `needs_precompile` is already set
+                Ok(None) => {
+                    self.read_composed_layer(containerd_id, &image, &composition, i)
+                        .await?
+                }
                 Err(err) => {
                     log::error!("failed to load precompiled layer: {err}");
                     log::error!("falling back to original layer and marking for recompile");
                     needs_precompile = true;
-                    self.read_original_layer(original_config).await?
+                    self.read_composed_layer(containerd_id, &image, &composition, i)
+                        .await?
                 }
             };
             layers.push(layer);
@@ -459,147 +930,915 @@ impl Client {
 
         if needs_precompile {
             log::info!("precompiling layers for image: {}", container.image);
-            let compiled_layers = match compiler.compile(&layers).await {
-                Ok(compiled_layers) => {
-                    if compiled_layers.len() != layers.len() {
-                        return Err(ShimError::FailedPrecondition(
-                            "precompile returned wrong number of layers".to_string(),
-                        ));
-                    }
-                    compiled_layers
-                }
+            let started = Instant::now();
+            let compiled_layers = precompile(compiler, &precompile_id, &layers).await;
+            let compile_time = started.elapsed();
+            let compiled_layers = match compiled_layers {
+                Ok(compiled_layers) => compiled_layers,
                 Err(e) => {
-                    log::error!("precompilation failed: {}", e);
-                    return Ok(layers);
+                    log::error!("precompilation failed: {e:#}");
+                    return Ok((layers, Some(compile_time)));
                 }
             };
 
-            let mut layers_for_runtime = Vec::with_capacity(compiled_layers.len());
-            for (i, compiled_layer) in compiled_layers.iter().enumerate() {
-                if compiled_layer.is_none() {
-                    log::debug!("no compiled layer using original");
-                    layers_for_runtime.push(layers[i].clone());
-                    continue;
-                }
-
-                let compiled_layer = compiled_layer.as_ref().unwrap();
-                let original_config = &layers[i].config;
-                let labels = HashMap::from([(
-                    format!("{precompile_id}/original"),
-                    original_config.digest().to_string(),
-                )]);
-                let precompiled_content = self
-                    .save_content(compiled_layer.clone(), &precompile_id, labels)
-                    .await?;
-
-                log::debug!(
-                    "updating original layer {} with compiled layer {}",
-                    original_config.digest(),
-                    precompiled_content.digest
-                );
-                // We add two labels here:
-                // - one with cache key per engine instance
-                // - one with a gc ref flag so it doesn't get cleaned up as long as the original layer exists
-                let mut original_layer = self.get_info(original_config.digest()).await?;
-                original_layer
-                    .labels
-                    .insert(precompile_id.clone(), precompiled_content.digest.clone());
-                original_layer.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
-                    precompiled_content.digest.clone(),
-                );
-                self.update_info(original_layer).await?;
-
-                // The original image is considered a root object, by adding a ref to the new compiled content
-                // We tell containerd to not garbage collect the new content until this image is removed from the system
-                // this ensures that we keep the content around after the lease is dropped
-                // We also save the precompiled flag here since the image labels can be mutated containerd, for example if the image is pulled twice
-                log::debug!(
-                    "updating image content with precompile digest to avoid garbage collection"
-                );
-                let mut image_content = self.get_info(&image_digest).await?;
-                image_content.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
-                    precompiled_content.digest,
-                );
-                image_content
-                    .labels
-                    .insert(precompile_id.clone(), "true".to_string());
-                self.update_info(image_content).await?;
-
-                layers_for_runtime.push(WasmLayer {
-                    config: original_config.clone(),
-                    layer: compiled_layer.clone(),
-                });
-
-                let _ = precompiled_content.lease.release().await;
-            }
-            return Ok(layers_for_runtime);
+            let compiled_layers = self
+                .save_precompiled_layers(
+                    image_digest,
+                    &precompile_id,
+                    &layers,
+                    &sources,
+                    compiled_layers,
+                )
+                .await?;
+
+            let layers = layers
+                .into_iter()
+                .zip(compiled_layers)
+                .map(|(layer, compiled)| match compiled {
+                    Some((_, compiled)) => WasmLayer {
+                        layer: compiled.into(),
+                        kind: layer.kind.map(WasmLayerKind::precompiled),
+                        ..layer
+                    },
+                    None => {
+                        log::debug!("no compiled layer using original");
+                        layer
+                    }
+                })
+                .collect();
+            return Ok((layers, Some(compile_time)));
         };
 
         log::info!("using OCI layers");
-        Ok(layers)
+        Ok((layers, None))
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
-    async fn read_precompiled_layer(
+    /// Precompiles the wasm layers of the image `image_name` without creating a container,
+    /// storing the artifacts exactly like [`Client::load_modules`] does.
+    /// Images that are already precompiled with the `compiler` are not compiled again.
+    /// Returns the layers with the digests of their artifacts, if the compiler produced one.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(compiler), level = "Debug")
+    )]
+    pub async fn precompile_image(
         &self,
-        config: &oci_spec::image::Descriptor,
-        precompile_id: &String,
-    ) -> Result<WasmLayer, ShimError> {
-        let digest = config.digest().clone();
-        let info = self.get_info(&digest).await?;
-        let Some(label) = info.labels.get(precompile_id) else {
-            return Err(ShimError::NotFound(String::from(
-                "precompiled layer not found",
-            )));
+        image_name: impl AsRef<str> + Debug,
+        engine_name: impl AsRef<str> + Debug,
+        supported_layer_types: &[&str],
+        compiler: &impl Compiler,
+    ) -> Result<Vec<PrecompiledLayer>, Error> {
+        let image_name = image_name.as_ref();
+        let Some(image) = self
+            .wasm_layer_configs(
+                image_name,
+                supported_layer_types,
+                LayerPolicy::from_config(),
+            )
+            .await
+            .map_err(Error::ImageResolution)?
+        else {
+            return Ok(vec![]);
         };
-        let digest: Digest = label.parse()?;
-        log::info!(
-            "layer {} has pre-compiled content: {} ",
-            info.digest,
-            &digest
-        );
-        self.read_content(digest).await.map(|module| WasmLayer {
-            config: config.clone(),
-            layer: module,
-        })
-    }
-
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
-    async fn read_original_layer(
-        &self,
-        config: &oci_spec::image::Descriptor,
-    ) -> Result<WasmLayer, ShimError> {
-        let digest = config.digest();
-        log::debug!("loading digest: {} ", digest);
-        self.read_content(digest).await.map(|module| WasmLayer {
-            config: config.clone(),
-            layer: module,
-        })
-    }
-}
+        let image_digest = &image.digest;
+        let composition = compose_layers(&image).map_err(Error::ImageResolution)?;
+        let roots = composition.roots();
+        let sources: Vec<_> = roots.iter().map(|i| composition.source(*i)).collect();
 
-fn precompile_label(name: &str, version: impl Hash) -> String {
-    let version = {
-        let mut hasher = DefaultHasher::new();
-        version.hash(&mut hasher);
-        hasher.finish().to_string()
-    };
-    format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
-}
+        let precompile_id = precompile_label(engine_name.as_ref(), compiler.cache_key());
 
-fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
-    let supported = supported_layer_types.contains(&media_type.to_string().as_str());
-    log::debug!(
-        "layer type {} is supported: {}",
-        media_type.to_string().as_str(),
-        supported
-    );
-    supported
-}
+        let image_info = self
+            .get_info(image_digest)
+            .await
+            .map_err(Error::ImageResolution)?;
+        if image_info.labels.contains_key(&precompile_id) {
+            let mut precompiled = vec![];
+            for (&i, source) in roots.iter().zip(&sources) {
+                let config = &image.layers[i];
+                match self
+                    .precompiled_digest(config, &precompile_id, source)
+                    .await
+                {
+                    Ok(digest) => precompiled.push(PrecompiledLayer {
+                        layer: config.digest().clone(),
+                        precompiled: digest.map(|(digest, _)| digest),
+                    }),
+                    Err(err) => {
+                        log::warn!("recompiling image {image_name}: {err}");
+                        break;
+                    }
+                }
+            }
+            if precompiled.len() == roots.len() {
+                log::info!("image {image_name} is already precompiled");
+                return Ok(precompiled);
+            }
+        }
 
-async fn send_message(
+        // no container uses the layers, so they are read without going through the layer cache
+        let mut contents = HashMap::new();
+        for (i, config) in image.layers.iter().enumerate() {
+            let data = self
+                .read_content(config.digest())
+                .await
+                .map_err(Error::ImageResolution)?;
+            let compression = Compression::from_media_type(config.media_type().as_ref());
+            let data = match decompress(config.digest(), compression, data)
+                .await
+                .map_err(Error::ImageResolution)?
+            {
+                (_, Some((_, uncompressed))) => uncompressed,
+                (data, None) => data,
+            };
+            contents.insert(
+                i,
+                wat_layer(config, data.into()).map_err(Error::ImageResolution)?,
+            );
+        }
+        let mut layers = vec![];
+        for &i in &roots {
+            let layer = match composition.is_composed(i) {
+                true => compose(&composition, i, contents.clone())
+                    .await
+                    .map_err(Error::ImageResolution)?,
+                false => contents[&i].clone(),
+            };
+            layers.push(image.layer(uncompressed_config(&image.layers[i]), layer));
+        }
+
+        log::info!("precompiling layers for image: {image_name}");
+        let compiled_layers = precompile(compiler, &precompile_id, &layers).await?;
+        let compiled_layers = self
+            .save_precompiled_layers(
+                image_digest,
+                &precompile_id,
+                &layers,
+                &sources,
+                compiled_layers,
+            )
+            .await
+            .map_err(Error::ImageResolution)?;
+
+        Ok(layers
+            .iter()
+            .zip(compiled_layers)
+            .map(|(layer, compiled)| PrecompiledLayer {
+                layer: layer.config.digest().clone(),
+                precompiled: compiled.map(|(digest, _)| digest),
+            })
+            .collect())
+    }
+
+    // Stores the artifacts in `compiled_layers` of the `layers` of the image with digest
+    // `image_digest`, and marks the image as precompiled with `precompile_id`. The artifacts
+    // record the `sources` of the layers, as returned by `Composition::source`.
+    // Returns the digests and contents of the artifacts, in layer order.
+    async fn save_precompiled_layers(
+        &self,
+        image_digest: &Digest,
+        precompile_id: &str,
+        layers: &[WasmLayer],
+        sources: &[String],
+        compiled_layers: Vec<Option<Vec<u8>>>,
+    ) -> Result<Vec<Option<(Digest, Vec<u8>)>>> {
+        if compiled_layers.len() != layers.len() {
+            return Err(ShimError::FailedPrecondition(
+                "precompile returned wrong number of layers".to_string(),
+            ));
+        }
+
+        let mut saved_layers = Vec::with_capacity(compiled_layers.len());
+        let mut image_refs = HashMap::new();
+        let mut leases = vec![];
+        for (i, compiled_layer) in compiled_layers.into_iter().enumerate() {
+            let Some(compiled_layer) = compiled_layer else {
+                saved_layers.push(None);
+                continue;
+            };
+
+            let original_config = &layers[i].config;
+            let mut labels =
+                HashMap::from([(precompile_source_label(precompile_id), sources[i].clone())]);
+            if let Some(kind) = layers[i].kind {
+                labels.insert(
+                    precompile_kind_label(precompile_id),
+                    binary_type_label(kind.binary_type()).to_string(),
+                );
+            }
+            let precompiled_content = self
+                .save_content(compiled_layer.clone(), precompile_id, labels)
+                .await?;
+
+            log::debug!(
+                "updating original layer {} with compiled layer {}",
+                original_config.digest(),
+                precompiled_content.digest
+            );
+            // We add two labels here:
+            // - one with cache key per engine instance
+            // - one with a gc ref flag so it doesn't get cleaned up as long as the original layer exists
+            let gc_ref_label = precompile_gc_ref_label(precompile_id, i);
+            let mut original_layer = self.get_info(original_config.digest()).await?;
+            original_layer.labels.insert(
+                precompile_id.to_string(),
+                precompiled_content.digest.clone(),
+            );
+            original_layer
+                .labels
+                .insert(gc_ref_label.clone(), precompiled_content.digest.clone());
+            self.update_info(original_layer).await?;
+
+            image_refs.insert(gc_ref_label, precompiled_content.digest.clone());
+            let digest = precompiled_content.digest.parse()?;
+            leases.push(precompiled_content.lease);
+
+            saved_layers.push(Some((digest, compiled_layer)));
+        }
+
+        // The original image is considered a root object, by adding a ref to the new compiled content
+        // We tell containerd to not garbage collect the new content until this image is removed from the system
+        // this ensures that we keep the content around after the leases are dropped
+        // We also save the precompiled flag here since the image labels can be mutated containerd, for example if the image is pulled twice.
+        // The flag is only set once an artifact was written, an image without any is compiled again on its next run.
+        if image_refs.is_empty() {
+            log::debug!(
+                "no precompiled layers for image {image_digest}, not flagging it as precompiled"
+            );
+            return Ok(saved_layers);
+        }
+        log::debug!("updating image content with precompile digests to avoid garbage collection");
+        let mut image_content = self.get_info(image_digest).await?;
+        image_content.labels.extend(image_refs);
+        image_content
+            .labels
+            .insert(precompile_id.to_string(), "true".to_string());
+        self.update_info(image_content).await?;
+
+        for lease in leases {
+            let _ = lease.release().await;
+        }
+        Ok(saved_layers)
+    }
+
+    // Returns the digest and kind of the artifact precompiled from the layer `config` with
+    // `precompile_id`, or `None` if the compiler didn't produce one. The artifact must have been
+    // compiled from `source`, i.e., the layer and the layers it's composed with.
+    // The kind is unknown for artifacts that don't record the kind of the layer they were
+    // compiled from.
+    async fn precompiled_digest(
+        &self,
+        config: &Descriptor,
+        precompile_id: &String,
+        source: &str,
+    ) -> Result<Option<(Digest, Option<WasmLayerKind>)>, ShimError> {
+        let info = self.get_info(config.digest()).await?;
+        let Some(label) = info.labels.get(precompile_id) else {
+            return Ok(None);
+        };
+        let digest: Digest = label.parse()?;
+
+        // The artifact records the layer it was compiled from under the current cache key.
+        // Don't hand the engine an artifact that was compiled with a different key, e.g., by an
+        // older version of the engine, or from other dependencies of the layer, the layer will
+        // be recompiled instead.
+        let artifact = self.get_info(&digest).await?;
+        let compiled_from = artifact.labels.get(&precompile_source_label(precompile_id));
+        if compiled_from.map(String::as_str) != Some(source) {
+            return Err(ShimError::FailedPrecondition(format!(
+                "precompiled layer {digest} was not compiled from {source} with {precompile_id}"
+            )));
+        }
+        let kind = artifact
+            .labels
+            .get(&precompile_kind_label(precompile_id))
+            .and_then(|label| parse_binary_type_label(label))
+            .map(|of| WasmLayerKind::Precompiled { of });
+
+        Ok(Some((digest, kind)))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn read_precompiled_layer(
+        &self,
+        containerd_id: &str,
+        config: &Descriptor,
+        precompile_id: &String,
+        source: &str,
+        image: &WasmImage,
+    ) -> Result<Option<WasmLayer>, ShimError> {
+        let precompiled = self
+            .precompiled_digest(config, precompile_id, source)
+            .await?;
+        let Some((digest, kind)) = precompiled else {
+            return Ok(None);
+        };
+
+        log::info!(
+            "layer {} has pre-compiled content: {} ",
+            config.digest(),
+            &digest
+        );
+        let module = self.read_layer(containerd_id, &digest, None).await?;
+        Ok(Some(WasmLayer {
+            kind,
+            ..image.layer(config.clone(), module)
+        }))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn read_original_layer(
+        &self,
+        containerd_id: &str,
+        config: &Descriptor,
+        image: &WasmImage,
+    ) -> Result<WasmLayer, ShimError> {
+        let digest = config.digest();
+        log::debug!("loading digest: {} ", digest);
+        let media_type = config.media_type().to_string();
+        let module = self
+            .read_layer(containerd_id, digest, Some(&media_type))
+            .await?;
+        Ok(image.layer(uncompressed_config(config), wat_layer(config, module)?))
+    }
+
+    // Reads the original layer `i` of the `image`, composed with the layers it depends on
+    async fn read_composed_layer(
+        &self,
+        containerd_id: &str,
+        image: &WasmImage,
+        composition: &Composition,
+        i: usize,
+    ) -> Result<WasmLayer, ShimError> {
+        let config = &image.layers[i];
+        if !composition.is_composed(i) {
+            return self.read_original_layer(containerd_id, config, image).await;
+        }
+
+        let mut contents = HashMap::new();
+        for j in composition.closure(i) {
+            let layer = self
+                .read_original_layer(containerd_id, &image.layers[j], image)
+                .await?;
+            contents.insert(j, layer.layer);
+        }
+        log::info!("composing layer {} with its dependencies", config.digest());
+        let composed = compose(composition, i, contents).await?;
+        Ok(image.layer(uncompressed_config(config), composed))
+    }
+
+    // Reads the content of a layer used by the container `containerd_id`,
+    // sharing it with the other containers through the layer cache.
+    // The content of original layers, given with their `media_type`, is decompressed if it's
+    // compressed, and cached by the digest of the uncompressed content.
+    async fn read_layer(
+        &self,
+        containerd_id: &str,
+        digest: &Digest,
+        media_type: Option<&str>,
+    ) -> Result<LayerContent> {
+        let info = self.get_info(digest).await?;
+        let key = match info.labels.get(UNCOMPRESSED_LABEL) {
+            Some(uncompressed) if media_type.is_some() => uncompressed.clone(),
+            _ => digest.to_string(),
+        };
+        if let Some(layer) = LAYER_CACHE.get(containerd_id, &key) {
+            return Ok(layer);
+        }
+
+        let compression = media_type.and_then(Compression::from_media_type);
+        let size = u64::try_from(info.size).unwrap_or_default();
+        let Some(path) = LAYER_CACHE.mapped_path(&key, size) else {
+            let data = self.read_content(digest).await?;
+            let (key, data) = match media_type {
+                Some(_) => match decompress(digest, compression, data).await? {
+                    (_, Some((uncompressed, data))) => {
+                        self.record_uncompressed_digest(info, &uncompressed).await;
+                        (uncompressed, data)
+                    }
+                    (data, None) => (key, data),
+                },
+                None => (key, data),
+            };
+            let layer = LayerContent::from(data);
+            LAYER_CACHE.insert(containerd_id, &key, layer.clone());
+            return Ok(layer);
+        };
+
+        // another instance of the image may have written the file in the meantime
+        if let Some(layer) = LAYER_CACHE.insert_mapped(containerd_id, &key, &path)? {
+            return Ok(layer);
+        }
+        LAYER_CACHE.evict_files(size);
+
+        let (key, path) = match media_type {
+            // Compressed content is written next to the final file, so that other instances
+            // never map it. The compression is only known for sure once the content is read.
+            Some(_) => {
+                let raw = unique_path(&path, "raw");
+                self.read_content_to_file(digest, &raw).await?;
+                match decompress_file(digest, compression, raw.clone()).await? {
+                    Some((uncompressed, path)) => {
+                        self.record_uncompressed_digest(info, &uncompressed).await;
+                        (uncompressed, path)
+                    }
+                    None => {
+                        tokio::fs::rename(&raw, &path).await?;
+                        (key, path)
+                    }
+                }
+            }
+            None => {
+                self.read_content_to_file(digest, &path).await?;
+                (key, path)
+            }
+        };
+        LAYER_CACHE
+            .insert_mapped(containerd_id, &key, &path)?
+            .ok_or_else(|| {
+                ShimError::Others(format!("layer file {path:?} was removed while loading it"))
+            })
+    }
+
+    // Records the digest of the uncompressed content of a layer, so that the layer is found in
+    // the layer cache without reading it again. Failing to do so only costs a cache miss.
+    async fn record_uncompressed_digest(&self, mut info: Info, uncompressed: &str) {
+        if info.labels.get(UNCOMPRESSED_LABEL).map(String::as_str) == Some(uncompressed) {
+            return;
+        }
+        let digest = info.digest.clone();
+        info.labels
+            .insert(UNCOMPRESSED_LABEL.to_string(), uncompressed.to_string());
+        if let Err(err) = self.update_info(info).await {
+            log::warn!("failed to record the uncompressed digest of layer {digest}: {err}");
+        }
+    }
+}
+
+// Decompresses the content `data` of the layer `digest` if it's compressed, detecting the
+// compression if it's not given. Returns `data` back, or the digest and uncompressed content.
+async fn decompress(
+    digest: &Digest,
+    compression: Option<Compression>,
+    data: Vec<u8>,
+) -> Result<(Vec<u8>, Option<(String, Vec<u8>)>)> {
+    let res = tokio::task::spawn_blocking(move || {
+        let uncompressed = compression::decompress(compression, &data)?;
+        std::io::Result::Ok((data, uncompressed))
+    })
+    .await
+    .map_err(|err| ShimError::Others(err.to_string()))?;
+    res.map_err(|err| ShimError::Others(format!("failed to decompress layer {digest}: {err}")))
+}
+
+// Like `decompress`, but for the content of the layer `digest` in the file at `path`,
+// which is replaced with a file named after the digest of the uncompressed content.
+async fn decompress_file(
+    digest: &Digest,
+    compression: Option<Compression>,
+    path: PathBuf,
+) -> Result<Option<(String, PathBuf)>> {
+    let res = tokio::task::spawn_blocking(move || {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        compression::decompress_file(compression, &path, dir)
+    })
+    .await
+    .map_err(|err| ShimError::Others(err.to_string()))?;
+    res.map_err(|err| ShimError::Others(format!("failed to decompress layer {digest}: {err}")))
+}
+
+// The descriptor of a layer as it's handed to the engine, with the media type of its
+// uncompressed content
+fn uncompressed_config(config: &Descriptor) -> Descriptor {
+    let media_type = config.media_type().to_string();
+    let uncompressed = uncompressed_media_type(&media_type);
+    if uncompressed == media_type {
+        return config.clone();
+    }
+    let mut config = config.clone();
+    config.set_media_type(MediaType::from(uncompressed));
+    config
+}
+
+// Resolves the dependencies between the layers of the `image`. An invalid dependency graph is
+// an error in the image, and the container fails to be created instead of falling back to the
+// files of its rootfs.
+fn compose_layers(image: &WasmImage) -> Result<Composition> {
+    Composition::new(&image.layers).map_err(|err| ShimError::InvalidArgument(format!("{err:#}")))
+}
+
+// Composes the layer `i` with the layers it depends on, on a blocking thread
+async fn compose(
+    composition: &Composition,
+    i: usize,
+    contents: HashMap<usize, LayerContent>,
+) -> Result<LayerContent> {
+    let composition = composition.clone();
+    let res = tokio::task::spawn_blocking(move || composition.compose(i, &contents))
+        .await
+        .map_err(|err| ShimError::Others(err.to_string()))?;
+    res.map(Into::into)
+        .map_err(|err| ShimError::InvalidArgument(format!("{err:#}")))
+}
+
+// Converts the content of a layer in the WebAssembly text format to a wasm binary, so that
+// engines and compilers are only ever given binaries. Besides layers with the WAT media type,
+// the content of wasm layers is checked too, as some tools push WAT as `application/wasm`.
+fn wat_layer(config: &Descriptor, layer: LayerContent) -> Result<LayerContent> {
+    let media_type = config.media_type().to_string();
+    let is_wat = match uncompressed_media_type(&media_type) {
+        WAT_LAYER_MEDIA_TYPE => true,
+        WASM_ARTIFACT_LAYER_MEDIA_TYPE | COMPONENT_LAYER_MEDIA_TYPE => is_wat(&layer),
+        _ => false,
+    };
+    if !is_wat {
+        return Ok(layer);
+    }
+    let wasm = wat_to_wasm(&layer, config.digest().to_string())
+        .map_err(|err| ShimError::InvalidArgument(format!("{err:#}")))?;
+    Ok(wasm.into())
+}
+
+// Reads content from the content store with one `Read` call per chunk, so that a large blob
+// is never held in memory at once, and verifies its digest as it's read
+struct ContentReader {
+    client: ContentClient<Channel>,
+    namespace: String,
+    rpc_timeout: Duration,
+    digest: Digest,
+    chunk_size: i64,
+    // offset of the current chunk
+    offset: i64,
+    // response stream of the current chunk
+    stream: Option<Streaming<ReadContentResponse>>,
+    // bytes read from the current chunk so far
+    chunk_read: i64,
+    // `None` once all the content was read
+    verifier: Option<DigestVerifier>,
+}
+
+impl ContentReader {
+    // Returns the next piece of the content, or `None` once all of it was read and verified.
+    async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some(verifier) = &mut self.verifier {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => {
+                    let req = ReadContentRequest {
+                        digest: self.digest.to_string(),
+                        offset: self.offset,
+                        size: self.chunk_size,
+                    };
+                    let req = with_namespace!(req, self.namespace);
+                    let stream =
+                        with_timeout("Content.Read", self.rpc_timeout, self.client.read(req))
+                            .await?
+                            .map_err(|err| ShimError::Containerd(err.to_string()))?
+                            .into_inner();
+                    self.chunk_read = 0;
+                    self.stream.insert(stream)
+                }
+            };
+
+            let msg = with_timeout("Content.Read", self.rpc_timeout, stream.message())
+                .await?
+                .map_err(|err| ShimError::Containerd(err.to_string()))?;
+            match msg {
+                Some(msg) if msg.data.is_empty() => {}
+                Some(msg) => {
+                    self.chunk_read += msg.data.len() as i64;
+                    verifier.update(&msg.data);
+                    return Ok(Some(msg.data));
+                }
+                // containerd stops at the end of the content, so a chunk shorter than
+                // requested is the last one
+                None if self.chunk_read < self.chunk_size => {
+                    if let Some(verifier) = self.verifier.take() {
+                        verifier.verify()?;
+                    }
+                }
+                None => {
+                    self.offset += self.chunk_read;
+                    self.stream = None;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Returns a path next to `path` with the `extension`, but different for every call, so that
+// concurrent reads of the same content write to different files, including the ones of other
+// shims. The pid of the shim in the name tells the other shims whether the file is still
+// being written, see `layer_files`.
+fn unique_path(path: &Path, extension: &str) -> PathBuf {
+    static FILES: AtomicU64 = AtomicU64::new(0);
+    path.with_extension(format!(
+        "{}-{}.{extension}",
+        std::process::id(),
+        FILES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// A temporary file that's removed when dropped unless it's kept, so that failed or cancelled
+// reads don't leave partial files behind
+struct TempFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Precompiles `layers` with a single call to `compiler`, or, for the compilers that opt in with
+/// [`Compiler::compiles_per_layer`], with one call per layer, running up to
+/// `precompile.concurrency` calls concurrently (the number of CPUs by default).
+/// The results are returned in layer order, and the first failure cancels the remaining calls.
+/// The calls also count towards the compilations of the whole shim, see [`COMPILATIONS`].
+async fn precompile(
+    compiler: &impl Compiler,
+    precompile_id: &str,
+    layers: &[WasmLayer],
+) -> Result<Vec<Option<Vec<u8>>>, Error> {
+    if !compiler.compiles_per_layer() {
+        return compile_layers(compiler, precompile_id, layers).await;
+    }
+
+    // the futures are created up front, as a closure of the stream would have to be general
+    // over the lifetimes of the layers for the future of the shim to be `Send`
+    let compilations: Vec<_> = layers
+        .iter()
+        .map(|layer| compile_layers(compiler, precompile_id, std::slice::from_ref(layer)))
+        .collect();
+    let compiled: Vec<_> = futures::stream::iter(compilations)
+        .buffered(precompile_concurrency())
+        .try_collect()
+        .await?;
+
+    Ok(compiled.into_iter().flatten().collect())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(digest = %digest_of(layers)), level = "Info")
+)]
+async fn compile_layers(
+    compiler: &impl Compiler,
+    precompile_id: &str,
+    layers: &[WasmLayer],
+) -> Result<Vec<Option<Vec<u8>>>, Error> {
+    let digest = digest_of(layers);
+    log::debug!("precompiling layers {digest}");
+    // the layers are compiled once for all the instances of the shim starting them with this compiler
+    COMPILATIONS
+        .run(&format!("{precompile_id}/{digest}"), || {
+            compiler.compile(layers)
+        })
+        .await
+        .map_err(|err| Error::Compilation {
+            digest: digest.to_string(),
+            message: format!("{err:#}"),
+        })
+}
+
+// The digests of `layers`, which identify the compilation of a batch of layers
+fn digest_of(layers: &[WasmLayer]) -> String {
+    let digests: Vec<_> = layers
+        .iter()
+        .map(|layer| layer.config.digest().to_string())
+        .collect();
+    digests.join(",")
+}
+
+fn precompile_concurrency() -> usize {
+    ShimConfig::get()
+        .precompile
+        .concurrency
+        .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+        .unwrap_or(1)
+        .max(1)
+}
+
+fn content_read_chunk_size() -> i64 {
+    ShimConfig::get()
+        .layers
+        .read_chunk_size
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_CONTENT_READ_CHUNK_SIZE)
+}
+
+fn precompile_label(name: &str, version: impl Hash) -> String {
+    let version = {
+        let mut hasher = DefaultHasher::new();
+        version.hash(&mut hasher);
+        hasher.finish().to_string()
+    };
+    format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
+}
+
+// Label on a precompiled artifact with the digest of the layer it was compiled from
+fn precompile_source_label(precompile_id: &str) -> String {
+    format!("{precompile_id}/original")
+}
+
+// The label on a precompiled artifact with the type of the binary it was compiled from,
+// so that engines know how to load the artifact without inspecting it
+fn precompile_kind_label(precompile_id: &str) -> String {
+    format!("{precompile_id}/kind")
+}
+
+fn binary_type_label(binary_type: WasmBinaryType) -> &'static str {
+    match binary_type {
+        WasmBinaryType::Module => "module",
+        WasmBinaryType::Component => "component",
+    }
+}
+
+fn parse_binary_type_label(label: &str) -> Option<WasmBinaryType> {
+    match label {
+        "module" => Some(WasmBinaryType::Module),
+        "component" => Some(WasmBinaryType::Component),
+        _ => None,
+    }
+}
+
+// The label referencing the artifact precompiled from the `i`th wasm layer with `precompile_id`,
+// which keeps containerd from collecting it while the layer or the image exist.
+// The references of different engines and cache keys don't overwrite each other.
+fn precompile_gc_ref_label(precompile_id: &str, i: usize) -> String {
+    format!("containerd.io/gc.ref.content.{precompile_id}/{i}")
+}
+
+/// Returns whether a call that failed with `err` may succeed if it's retried,
+/// i.e., if it failed because containerd was unavailable.
+pub(crate) fn is_transient(err: &ShimError) -> bool {
+    // the client reports gRPC failures with the `Display` of their status, which includes the code
+    matches!(err, ShimError::Containerd(msg) if msg.contains("Unavailable"))
+}
+
+// The failure of an attempt to connect to containerd
+enum ConnectError {
+    Transport(tonic::transport::Error),
+    Timeout,
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(err) => std::fmt::Display::fmt(err, f),
+            Self::Timeout => f.write_str("timed out"),
+        }
+    }
+}
+
+// Connection errors that go away once containerd accepts connections again
+fn is_transient_connect_error(err: &(dyn std::error::Error + 'static), address: &Path) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return match err.kind() {
+                ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted => true,
+                // containerd removes its socket while restarting, but the directory stays
+                ErrorKind::NotFound => address.parent().is_some_and(Path::is_dir),
+                _ => false,
+            };
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn is_index(media_type: &str) -> bool {
+    media_type == MediaType::ImageIndex.to_string()
+        || media_type == "application/vnd.docker.distribution.manifest.list.v2+json"
+}
+
+fn is_image_config(media_type: &str) -> bool {
+    media_type == MediaType::ImageConfig.to_string()
+        || media_type == "application/vnd.docker.container.image.v1+json"
+}
+
+// Returns the process defaults in the `config` of an image config blob, which some tools
+// also set in the config of wasm OCI artifacts. Blobs without them are ignored.
+fn image_process_config(blob: &[u8]) -> Option<Config> {
+    #[derive(serde::Deserialize)]
+    struct ConfigBlob {
+        config: Option<Config>,
+    }
+
+    match serde_json::from_slice::<ConfigBlob>(blob) {
+        Ok(blob) => blob.config,
+        Err(err) => {
+            log::debug!("image config has no process config: {err}");
+            None
+        }
+    }
+}
+
+fn is_wasm_artifact(manifest: &ImageManifest) -> bool {
+    let config_type = manifest.config().media_type().to_string();
+    let artifact_type = manifest.artifact_type().as_ref().map(ToString::to_string);
+    WASM_CONFIG_MEDIA_TYPES.iter().any(|media_type| {
+        *media_type == config_type || artifact_type.as_deref() == Some(*media_type)
+    })
+}
+
+// Selects the manifest with the wasm layers from an image index, i.e., the one with the best
+// `platform_score`. The manifest of the host platform is only selected if there's no wasm one,
+// and its wasm files are then read from the rootfs.
+fn select_manifest(index: &ImageIndex) -> Option<&Descriptor> {
+    index
+        .manifests()
+        .iter()
+        .map(|manifest| (platform_score(manifest), manifest))
+        .filter(|(score, _)| *score > 0)
+        // the first of the manifests with the best score
+        .rev()
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, manifest)| manifest)
+}
+
+// Scores how well the manifest of an image index fits the shim, from best to worst:
+// wasm OCI artifacts, e.g., pushed next to an image that runs the module in a container,
+// `wasi/wasm32` (or a `wasip1`/`wasip2` OS), `wasm/wasm32`, and the host OS and architecture.
+// Returns 0 for manifests that can't be used.
+fn platform_score(manifest: &Descriptor) -> u8 {
+    let artifact_type = manifest.artifact_type().as_ref().map(ToString::to_string);
+    if WASM_CONFIG_MEDIA_TYPES
+        .iter()
+        .any(|media_type| artifact_type.as_deref() == Some(*media_type))
+    {
+        return 4;
+    }
+
+    let Some(platform) = manifest.platform() else {
+        return 0;
+    };
+    let os = platform.os().to_string();
+    let is_wasm_arch = matches!(
+        platform.architecture().to_string().as_str(),
+        "wasm" | "wasm32"
+    );
+    match os.as_str() {
+        "wasi" | "wasip1" | "wasip2" if is_wasm_arch => 3,
+        _ if is_wasm_arch => 2,
+        _ if *platform.os() == Os::default() && *platform.architecture() == Arch::default() => 1,
+        _ => 0,
+    }
+}
+
+fn is_wasm_layer(media_type: &str, supported_layer_types: &[&str]) -> bool {
+    // compressed layers are supported if their uncompressed content is
+    supported_layer_types.contains(&uncompressed_media_type(media_type))
+}
+
+// Returns the layers with a media type `is_supported` accepts. The other layers are skipped
+// with a warning, or fail with the list of their media types if `layer_policy` is strict.
+// The run config layer is not a wasm layer, but it's not unsupported either.
+fn select_layers(
+    layers: &[Descriptor],
+    is_supported: impl Fn(&str) -> bool,
+    layer_policy: LayerPolicy,
+) -> Result<Vec<Descriptor>> {
+    let mut supported = vec![];
+    let mut unsupported: Vec<String> = vec![];
+    for layer in layers {
+        let media_type = layer.media_type().to_string();
+        if is_supported(&media_type) {
+            supported.push(layer.clone());
+            continue;
+        }
+        if media_type == RUN_CONFIG_MEDIA_TYPE {
+            continue;
+        }
+        if layer_policy == LayerPolicy::Lenient {
+            log::warn!(
+                "skipping layer {} with unsupported media type {media_type}",
+                layer.digest()
+            );
+        }
+        if !unsupported.contains(&media_type) {
+            unsupported.push(media_type);
+        }
+    }
+
+    if layer_policy == LayerPolicy::Strict && !unsupported.is_empty() {
+        return Err(ShimError::InvalidArgument(format!(
+            "the image has layers with unsupported media types: {}",
+            unsupported.join(", ")
+        )));
+    }
+    Ok(supported)
+}
+
+async fn send_message(
     request: WriteContentRequest,
     response_stream: &mut Streaming<WriteContentResponse>,
     tx: &mpsc::Sender<WriteContentRequest>,
@@ -626,7 +1865,9 @@ mod tests {
     use std::path::PathBuf;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicI32, Ordering};
+    use std::time::Duration;
 
+    use oci_spec::image::{DescriptorBuilder, ImageIndexBuilder, PlatformBuilder};
     use oci_tar_builder::WASM_LAYER_MEDIA_TYPE;
 
     use super::*;
@@ -644,66 +1885,559 @@ mod tests {
         let expected = digest(data.clone());
         let expected = format!("sha256:{}", expected);
 
-        let label = HashMap::from([(precompile_label("test", "hasdfh"), "original".to_string())]);
-        let returned = client
-            .save_content(data, "test", label.clone())
+        let label = HashMap::from([(precompile_label("test", "hasdfh"), "original".to_string())]);
+        let returned = client
+            .save_content(data, "test", label.clone())
+            .await
+            .unwrap();
+        assert_eq!(expected, returned.digest.clone());
+
+        let data = client.read_content(returned.digest.clone()).await.unwrap();
+        assert_eq!(data, b"hello world");
+
+        client
+            .save_content(data.clone(), "test", label.clone())
+            .await
+            .expect_err("Should not be able to save when lease is open");
+
+        // need to drop the lease to be able to create a second one
+        // a second call should be successful since it already exists
+        let _ = returned.release().await;
+
+        // a second call should be successful since it already exists
+        let returned = client
+            .save_content(data, "test", label.clone())
+            .await
+            .unwrap();
+        assert_eq!(expected, returned.digest);
+
+        client.delete_content(expected.clone()).await.unwrap();
+
+        client
+            .read_content(expected)
+            .await
+            .expect_err("content should not exist");
+
+        let _ = returned.release().await;
+    }
+
+    #[test]
+    fn test_compressed_layers_are_supported() -> anyhow::Result<()> {
+        let zstd = format!("{WASM_LAYER_MEDIA_TYPE}+zstd");
+        assert!(is_wasm_layer(&zstd, &[WASM_LAYER_MEDIA_TYPE]));
+        assert!(!is_wasm_layer(&zstd, &["application/json"]));
+
+        let config = DescriptorBuilder::default()
+            .media_type(MediaType::from(zstd.as_str()))
+            .size(42u64)
+            .digest(format!("sha256:{}", digest("layer")).parse::<Digest>()?)
+            .build()?;
+        let uncompressed = uncompressed_config(&config);
+        assert_eq!(uncompressed.media_type().to_string(), WASM_LAYER_MEDIA_TYPE);
+        assert_eq!(uncompressed.digest(), config.digest());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_layers() -> anyhow::Result<()> {
+        let layer = |media_type: &str, seed: &str| {
+            DescriptorBuilder::default()
+                .media_type(MediaType::from(media_type))
+                .size(42u64)
+                .digest(
+                    format!("sha256:{}", digest(seed))
+                        .parse::<Digest>()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap()
+        };
+        let layers = [
+            layer(WASM_LAYER_MEDIA_TYPE, "module"),
+            layer("text/plain", "readme"),
+            layer(RUN_CONFIG_MEDIA_TYPE, "run config"),
+            layer("application/x-tar", "rootfs"),
+            layer("text/plain", "license"),
+        ];
+        let is_supported = |media_type: &str| is_wasm_layer(media_type, &[WASM_LAYER_MEDIA_TYPE]);
+
+        let selected = select_layers(&layers, is_supported, LayerPolicy::Lenient)?;
+        assert_eq!(selected, [layers[0].clone()]);
+
+        let err = select_layers(&layers, is_supported, LayerPolicy::Strict).unwrap_err();
+        assert!(matches!(err, ShimError::InvalidArgument(_)));
+        assert_eq!(
+            err.to_string(),
+            "invalid argument: the image has layers with unsupported media types: text/plain, application/x-tar"
+        );
+
+        // the run config layer is not an unsupported layer
+        let layers = [layers[0].clone(), layers[2].clone()];
+        let selected = select_layers(&layers, is_supported, LayerPolicy::Strict)?;
+        assert_eq!(selected, [layers[0].clone()]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_read_content_in_chunks() -> anyhow::Result<()> {
+        let client = Client::connect("/run/containerd/containerd.sock", "test-ns").await?;
+        let data = b"hello world".to_vec();
+        let returned = client
+            .save_content(data.clone(), "test-chunks", HashMap::new())
+            .await?;
+
+        for chunk_size in [1, 4, 11, 1024] {
+            let mut reader = client.content_reader(&returned.digest, chunk_size)?;
+            let mut chunks = vec![];
+            while let Some(chunk) = reader.next().await? {
+                assert!(chunk.len() <= chunk_size as usize);
+                chunks.push(chunk);
+            }
+            assert_eq!(chunks.concat(), data, "chunk size {chunk_size}");
+        }
+
+        // a failed read doesn't leave a partial file behind
+        let dir = tempfile::tempdir()?;
+        let missing = format!("sha256:{}", digest("missing"));
+        let path = dir.path().join("layer");
+        assert!(client.read_content_to_file(missing, &path).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        client.read_content_to_file(&returned.digest, &path).await?;
+        assert_eq!(std::fs::read(&path)?, data);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        client.delete_content(&returned.digest).await?;
+        let _ = returned.release().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_until_containerd_accepts_connections() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("containerd.sock");
+
+        // the socket exists but refuses connections, like while containerd restarts
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+        let containerd = tokio::spawn({
+            let path = path.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                std::fs::remove_file(&path)?;
+                let listener = tokio::net::UnixListener::bind(&path)?;
+                let (stream, _) = listener.accept().await?;
+                anyhow::Ok(stream)
+            }
+        });
+
+        Client::connect_with_retry(&path, "test", &Backoff::default()).await?;
+        containerd.await??;
+
+        // an invalid socket path is not retried
+        let start = std::time::Instant::now();
+        let invalid = dir.path().join("missing").join("containerd.sock");
+        assert!(
+            Client::connect_with_retry(invalid, "test", &Backoff::default())
+                .await
+                .is_err()
+        );
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_when_precompile_not_supported() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let (layers, _) = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                NO_COMPILER.as_ref(),
+                false,
+                LayerPolicy::Lenient,
+            )
+            .await
+            .unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, fake_bytes.bytes);
+        assert_eq!(layers[0].wasm_config, None);
+        let image_config = layers[0].image_config.as_ref().unwrap();
+        assert_eq!(
+            image_config.entrypoint().as_deref(),
+            Some(&["_start".to_string()][..])
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unsigned_images_without_wasm_layers_are_not_loaded() {
+        use p256::pkcs8::{EncodePublicKey as _, LineEnding};
+
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let layer = generate_content("layer", "textfile");
+        let (_, container_name, _cleanup) = generate_test_container(None, &[&layer]);
+
+        let load = async |client: &Client| {
+            client
+                .load_modules(
+                    container_name.clone(),
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE],
+                    NO_COMPILER.as_ref(),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+        };
+
+        // without a policy, the image runs the files of its rootfs
+        let (layers, _) = load(&client).await.unwrap();
+        assert!(layers.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = p256::ecdsa::SigningKey::from_slice(&[1; 32]).unwrap();
+        let key_path = dir.path().join("cosign.pub");
+        let pem = key.verifying_key().to_public_key_pem(LineEnding::LF);
+        std::fs::write(&key_path, pem.unwrap()).unwrap();
+        let policy_path = dir.path().join("policy.json");
+        let policy = serde_json::json!({ "publicKeys": [key_path] });
+        std::fs::write(&policy_path, policy.to_string()).unwrap();
+        let policy = Box::leak(Box::new(Policy::load(&policy_path).unwrap()));
+
+        let client = client.with_signature_policy(Some(policy));
+        let err = load(&client).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ImageResolution(ShimError::SignatureVerification { .. })
+            ),
+            "{err}"
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_of_wasm_artifact() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let module = generate_content("module", oci_helpers::WASM_ARTIFACT_LAYER_MEDIA_TYPE);
+        let image_name = format!("localhost/test-artifact:latest{}", random_number());
+        oci_helpers::import_wasm_artifact(&image_name, &[&module]).unwrap();
+
+        let container_name = format!("test-container-{}", random_number());
+        oci_helpers::create_container(&container_name, &image_name).unwrap();
+        let _cleanup = oci_helpers::OCICleanup {
+            image_name,
+            container_name: container_name.clone(),
+        };
+
+        // the layers of artifacts are loaded whatever the layer types of the engine
+        let (layers, _) = client
+            .load_modules(
+                container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                NO_COMPILER.as_ref(),
+                false,
+                LayerPolicy::Lenient,
+            )
+            .await
+            .unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, module.bytes);
+
+        let wasm_config = layers[0].wasm_config.as_ref().unwrap();
+        assert_eq!(wasm_config.os, "wasip1");
+        assert_eq!(
+            wasm_config.layer_digests,
+            [format!("sha256:{}", digest(module.bytes.clone()))]
+        );
+    }
+
+    fn manifest_descriptor(digest: &str) -> DescriptorBuilder {
+        let digest = format!("sha256:{}", digest.repeat(64));
+        DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .digest(digest.parse::<Digest>().unwrap())
+            .size(1u64)
+    }
+
+    fn platform_manifest(digest: &str, os: Os, arch: Arch) -> Descriptor {
+        let platform = PlatformBuilder::default()
+            .os(os)
+            .architecture(arch)
+            .build()
+            .unwrap();
+        manifest_descriptor(digest)
+            .platform(platform)
+            .build()
+            .unwrap()
+    }
+
+    fn image_index(manifests: &[&Descriptor]) -> ImageIndex {
+        ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .manifests(manifests.iter().copied().cloned().collect::<Vec<_>>())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_select_manifest_scores_platforms() {
+        let host = platform_manifest("a", Os::default(), Arch::default());
+        let wasm = platform_manifest("b", Os::Other("wasm".to_string()), Arch::Wasm);
+        let wasi = platform_manifest(
+            "c",
+            Os::Other("wasi".to_string()),
+            Arch::Other("wasm32".to_string()),
+        );
+
+        let index = image_index(&[&host, &wasm, &wasi]);
+        assert_eq!(select_manifest(&index), Some(&wasi));
+        let index = image_index(&[&host, &wasm]);
+        assert_eq!(select_manifest(&index), Some(&wasm));
+        // the wasm files of host images are read from the rootfs
+        let index = image_index(&[&host]);
+        assert_eq!(select_manifest(&index), Some(&host));
+
+        // the first of the manifests with the same score is selected
+        let wasip1 = platform_manifest("d", Os::Other("wasip1".to_string()), Arch::Wasm);
+        let index = image_index(&[&wasip1, &wasi]);
+        assert_eq!(select_manifest(&index), Some(&wasip1));
+
+        let other = if Arch::default() == Arch::ARM64 {
+            Arch::Amd64
+        } else {
+            Arch::ARM64
+        };
+        let foreign = platform_manifest("e", Os::default(), other);
+        assert_eq!(select_manifest(&image_index(&[&foreign])), None);
+    }
+
+    #[test]
+    fn test_select_manifest_prefers_wasm_artifact() {
+        let image = platform_manifest("a", Os::Other("wasip1".to_string()), Arch::Wasm);
+        let artifact = manifest_descriptor("b")
+            .artifact_type(MediaType::Other(WASM_CONFIG_MEDIA_TYPES[0].to_string()))
+            .build()
+            .unwrap();
+
+        let index = image_index(&[&image, &artifact]);
+        assert_eq!(select_manifest(&index), Some(&artifact));
+        let index = image_index(&[&image]);
+        assert_eq!(select_manifest(&index), Some(&image));
+    }
+
+    #[test]
+    fn test_wat_layer() {
+        let layer = |media_type: &str| {
+            DescriptorBuilder::default()
+                .media_type(MediaType::Other(media_type.to_string()))
+                .digest(
+                    format!("sha256:{}", "a".repeat(64))
+                        .parse::<Digest>()
+                        .unwrap(),
+                )
+                .size(1u64)
+                .build()
+                .unwrap()
+        };
+        let wat = || LayerContent::from(b"(module)".to_vec());
+
+        let wasm = wat_layer(&layer(WAT_LAYER_MEDIA_TYPE), wat()).unwrap();
+        assert!(wasm.starts_with(b"\0asm"));
+        let wasm = wat_layer(&layer("application/wasm"), wat()).unwrap();
+        assert!(wasm.starts_with(b"\0asm"));
+        let wasm = wat_layer(&layer(WASM_LAYER_MEDIA_TYPE), wasm).unwrap();
+        assert!(wasm.starts_with(b"\0asm"));
+
+        // other layers, e.g. runtime configs, are left alone
+        let config = wat_layer(&layer("application/json"), b"{}".to_vec().into()).unwrap();
+        assert_eq!(&*config, b"{}");
+
+        let err = wat_layer(
+            &layer(WAT_LAYER_MEDIA_TYPE),
+            b"(module\n  (func (result i32)\n    (i32.const)))"
+                .to_vec()
+                .into(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ShimError::InvalidArgument(_)));
+        let digest = format!("sha256:{}:3:", "a".repeat(64));
+        assert!(err.to_string().contains(&digest), "{err}");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_are_precompiled_once() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
             .await
             .unwrap();
-        assert_eq!(expected, returned.digest.clone());
 
-        let data = client.read_content(returned.digest.clone()).await.unwrap();
-        assert_eq!(data, b"hello world");
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
 
-        client
-            .save_content(data.clone(), "test", label.clone())
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        let (_, compile_time) = client
+            .load_modules(
+                &container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                Some(&engine),
+                false,
+                LayerPolicy::Lenient,
+            )
             .await
-            .expect_err("Should not be able to save when lease is open");
+            .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert!(compile_time.is_some());
 
-        // need to drop the lease to be able to create a second one
-        // a second call should be successful since it already exists
-        let _ = returned.release().await;
+        // Even on second calls should only pre-compile once
+        let (layers, compile_time) = client
+            .load_modules(
+                &container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                Some(&engine),
+                false,
+                LayerPolicy::Lenient,
+            )
+            .await
+            .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(compile_time, None);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+    }
 
-        // a second call should be successful since it already exists
-        let returned = client
-            .save_content(data, "test", label.clone())
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_precompiled_layers_record_their_kind() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
             .await
             .unwrap();
-        assert_eq!(expected, returned.digest);
 
-        client.delete_content(expected.clone()).await.unwrap();
+        // the header of a component, followed by random bytes
+        let mut component = generate_content("component", WASM_LAYER_MEDIA_TYPE);
+        component.bytes = [b"\0asm\x0d\0\x01\0".as_slice(), &component.bytes].concat();
+        let (_image_name, container_name, _cleanup) = generate_test_container(None, &[&component]);
 
-        client
-            .read_content(expected)
-            .await
-            .expect_err("content should not exist");
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(component.bytes.clone(), &fake_precompiled_bytes);
+
+        let precompiled = Some(WasmLayerKind::Precompiled {
+            of: WasmBinaryType::Component,
+        });
+        for _ in 0..2 {
+            let (layers, _) = client
+                .load_modules(
+                    &container_name,
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE],
+                    Some(&engine),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+            assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+            assert_eq!(layers[0].kind, precompiled);
+        }
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
 
-        let _ = returned.release().await;
+        // the original layer when not precompiling
+        let (layers, _) = client
+            .load_modules(
+                &container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                None::<&FakePrecomipler>,
+                false,
+                LayerPolicy::Lenient,
+            )
+            .await
+            .unwrap();
+        assert_eq!(layers[0].kind, Some(WasmLayerKind::Component));
     }
 
     #[tokio::test(flavor = "current_thread")]
-    async fn test_layers_when_precompile_not_supported() {
+    async fn test_precompile_image() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
         let path = path.to_str().unwrap();
         let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
 
         let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
-        let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+        let (image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        let precompiled = client
+            .precompile_image(&image_name, "fake", &[WASM_LAYER_MEDIA_TYPE], &engine)
+            .await
+            .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(precompiled.len(), 1);
+        assert_eq!(
+            precompiled[0].layer.to_string(),
+            format!("sha256:{}", digest(fake_bytes.bytes.clone()))
+        );
+        assert_eq!(
+            precompiled[0].precompiled.as_ref().map(ToString::to_string),
+            Some(format!(
+                "sha256:{}",
+                digest(fake_precompiled_bytes.bytes.clone())
+            ))
+        );
+
+        // precompiling a warm image doesn't compile it again
+        let again = client
+            .precompile_image(&image_name, "fake", &[WASM_LAYER_MEDIA_TYPE], &engine)
+            .await
+            .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        assert_eq!(again, precompiled);
 
-        let layers = client
+        // and containers of the image use the artifacts
+        let (layers, _) = client
             .load_modules(
-                container_name,
+                &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
-                NO_COMPILER.as_ref(),
+                Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 1);
-        assert_eq!(layers[0].layer, fake_bytes.bytes);
+        assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
     }
 
     #[tokio::test(flavor = "current_thread")]
-    async fn test_layers_are_precompiled_once() {
+    async fn test_layers_are_recompiled_if_version_changes() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
         let path = path.to_str().unwrap();
         let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
@@ -723,28 +2457,80 @@ mod tests {
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
 
-        // Even on second calls should only pre-compile once
-        let layers = client
+        engine.precompile_id = "new_version".to_string();
+        let _ = client
             .load_modules(
                 &container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
-        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
-        assert_eq!(layers.len(), 1);
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+
+        // the new version is only compiled once
+        let (layers, _) = client
+            .load_modules(
+                &container_name,
+                "fake",
+                &[WASM_LAYER_MEDIA_TYPE],
+                Some(&engine),
+                false,
+                LayerPolicy::Lenient,
+            )
+            .await
+            .unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
     }
 
     #[tokio::test(flavor = "current_thread")]
-    async fn test_layers_are_recompiled_if_version_changes() {
+    async fn test_layers_are_recompiled_if_forced() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        for (force, expected_calls) in [(false, 1), (false, 1), (true, 2)] {
+            let (layers, _) = client
+                .load_modules(
+                    &container_name,
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE],
+                    Some(&engine),
+                    force,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                engine.precompile_called.load(Ordering::SeqCst),
+                expected_calls
+            );
+            assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_stale_precompiled_layers_are_recompiled_once() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
         let path = path.to_str().unwrap();
         let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
@@ -757,6 +2543,7 @@ mod tests {
         let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
         let mut engine = FakePrecomipler::new();
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        let precompile_id = precompile_label("fake", engine.cache_key());
 
         let _ = client
             .load_modules(
@@ -764,22 +2551,39 @@ mod tests {
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
 
-        engine.precompile_id = "new_version".to_string();
-        let _ = client
-            .load_modules(
-                &container_name,
-                "fake",
-                &[WASM_LAYER_MEDIA_TYPE],
-                Some(&engine),
-            )
+        // simulate an artifact that was compiled with a different cache key
+        let artifact_digest = format!("sha256:{}", digest(fake_precompiled_bytes.bytes.clone()));
+        let mut artifact = client
+            .get_info(&artifact_digest.parse().unwrap())
             .await
             .unwrap();
-        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+        artifact
+            .labels
+            .remove(&precompile_source_label(&precompile_id));
+        client.update_info(artifact).await.unwrap();
+
+        for _ in 0..2 {
+            let (layers, _) = client
+                .load_modules(
+                    &container_name,
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE],
+                    Some(&engine),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+            assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+            assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        }
     }
 
     #[tokio::test(flavor = "current_thread")]
@@ -796,14 +2600,16 @@ mod tests {
         let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
         let mut engine = FakePrecomipler::new();
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
-        let expected_id = precompile_label("fake", &engine.cache_key());
+        let expected_id = precompile_label("fake", engine.cache_key());
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
@@ -825,6 +2631,69 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_precompiled_layers_are_collected_with_the_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, TEST_NAMESPACE).await.unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_, container_name, cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+        // the artifacts of other engines don't replace the references to this one's
+        let other_precompiled_bytes = generate_content("other", WASM_LAYER_MEDIA_TYPE);
+        let mut other_engine = FakePrecomipler::new();
+        other_engine.add_precompiled_bits(fake_bytes.bytes.clone(), &other_precompiled_bytes);
+
+        for engine in [&engine, &other_engine] {
+            client
+                .load_modules(
+                    &container_name,
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE],
+                    Some(engine),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+        }
+
+        let precompiled_digest: Digest =
+            format!("sha256:{}", digest(fake_precompiled_bytes.bytes.clone()))
+                .parse()
+                .unwrap();
+        collect_garbage(&client).await;
+        assert!(client.get_info(&precompiled_digest).await.is_ok());
+
+        // removing the image lets containerd collect the artifact
+        drop(cleanup);
+        collect_garbage(&client).await;
+        oci_helpers::wait_for_content_removal(precompiled_digest.as_ref()).unwrap();
+    }
+
+    // containerd collects the garbage synchronously when a lease is deleted with `sync`
+    async fn collect_garbage(client: &Client) {
+        let mut leases = LeasesClient::new(client.inner.clone());
+        let id = format!("test-gc-{}", random_number());
+        let req = containerd_client::services::v1::CreateRequest {
+            id: id.clone(),
+            labels: HashMap::new(),
+        };
+        leases
+            .create(with_namespace!(req, client.namespace))
+            .await
+            .unwrap();
+        let req = containerd_client::services::v1::DeleteRequest { id, sync: true };
+        leases
+            .delete(with_namespace!(req, client.namespace))
+            .await
+            .unwrap();
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_are_precompiled_but_not_for_all_layers() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -842,23 +2711,108 @@ mod tests {
         let mut engine = FakePrecomipler::new();
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE, "textfile"],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
 
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
-        assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.layers_compiled.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 2);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
         assert_eq!(layers[1].layer, non_wasm_bytes.bytes);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_images_without_precompiled_layers_are_not_flagged() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let non_wasm_bytes = generate_content("original_dont_compile", "textfile");
+        let (image_name, container_name, _cleanup) =
+            generate_test_container(None, &[&non_wasm_bytes]);
+
+        let engine = FakePrecomipler::new();
+        for _ in 0..2 {
+            let (layers, _) = client
+                .load_modules(
+                    &container_name,
+                    "fake",
+                    &["textfile"],
+                    Some(&engine),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+            assert_eq!(layers.len(), 1);
+            assert_eq!(layers[0].layer, non_wasm_bytes.bytes);
+        }
+
+        // the compiler produced no artifact, so the image is compiled again on the next run
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.layers_compiled.load(Ordering::SeqCst), 0);
+        let (_, image_digest) = client
+            .get_image_manifest_and_digest(&image_name)
+            .await
+            .unwrap();
+        let image_info = client.get_info(&image_digest).await.unwrap();
+        let precompile_id = precompile_label("fake", engine.cache_key());
+        assert!(!image_info.labels.contains_key(&precompile_id));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_are_precompiled_once_for_containers_of_the_same_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let non_wasm_bytes = generate_content("original_dont_compile", "textfile");
+        let (image_name, container_name, _cleanup) =
+            generate_test_container(None, &[&fake_bytes, &non_wasm_bytes]);
+
+        let other_container_name = format!("{container_name}-other");
+        oci_helpers::create_container(&other_container_name, &image_name).unwrap();
+        let _other_cleanup = ContainerCleanup(other_container_name.clone());
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomipler::new();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        for name in [&container_name, &other_container_name] {
+            let (layers, _) = client
+                .load_modules(
+                    name,
+                    "fake",
+                    &[WASM_LAYER_MEDIA_TYPE, "textfile"],
+                    Some(&engine),
+                    false,
+                    LayerPolicy::Lenient,
+                )
+                .await
+                .unwrap();
+
+            // the layer that can't be precompiled doesn't trigger a recompile
+            assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+            assert_eq!(layers.len(), 2);
+            assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+            assert_eq!(layers[1].layer, non_wasm_bytes.bytes);
+        }
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_do_not_need_precompiled_if_new_layers_are_added_to_existing_image() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -874,12 +2828,14 @@ mod tests {
         let mut engine = FakePrecomipler::new();
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
@@ -908,18 +2864,20 @@ mod tests {
         // and then check that the layers don't need to be recompiled
         oci_helpers::wait_for_content_removal(&image_sha).unwrap();
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name2,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
         assert_eq!(layers.len(), 2);
-        assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.layers_compiled.load(Ordering::SeqCst), 2);
     }
 
     #[tokio::test(flavor = "current_thread")]
@@ -937,12 +2895,14 @@ mod tests {
         let mut engine = FakePrecomipler::new();
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
@@ -956,18 +2916,20 @@ mod tests {
         let fake_precompiled_bytes2 = generate_content("precompiled2", WASM_LAYER_MEDIA_TYPE);
         engine.add_precompiled_bits(fake_bytes2.bytes.clone(), &fake_precompiled_bytes2);
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name2,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
         assert_eq!(layers.len(), 2);
-        assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.layers_compiled.load(Ordering::SeqCst), 2);
     }
 
     #[tokio::test(flavor = "current_thread")]
@@ -991,19 +2953,21 @@ mod tests {
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
         engine.add_precompiled_bits(fake_bytes2.bytes.clone(), &fake_precompiled_bytes2);
 
-        let expected_id = precompile_label("fake", &engine.cache_key());
+        let expected_id = precompile_label("fake", engine.cache_key());
 
-        let layers = client
+        let (layers, _) = client
             .load_modules(
                 container_name,
                 "fake",
                 &[WASM_LAYER_MEDIA_TYPE],
                 Some(&engine),
+                false,
+                LayerPolicy::Lenient,
             )
             .await
             .unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
-        assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.layers_compiled.load(Ordering::SeqCst), 2);
 
         assert_eq!(layers.len(), 2);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
@@ -1031,6 +2995,119 @@ mod tests {
         );
     }
 
+    struct SlowCompiler {
+        per_layer: bool,
+        calls: AtomicI32,
+    }
+
+    impl SlowCompiler {
+        fn new(per_layer: bool) -> Self {
+            Self {
+                per_layer,
+                calls: AtomicI32::new(0),
+            }
+        }
+    }
+
+    impl Compiler for SlowCompiler {
+        fn cache_key(&self) -> impl Hash {
+            "slow"
+        }
+
+        async fn compile(&self, layers: &[WasmLayer]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut compiled_layers = vec![];
+            for layer in layers {
+                let Some(&n) = layer.layer.first() else {
+                    anyhow::bail!("empty module");
+                };
+                // make the later layers finish first
+                tokio::time::sleep(Duration::from_millis(10 * (4 - n as u64))).await;
+                compiled_layers.push(Some(vec![n; 2]));
+            }
+            Ok(compiled_layers)
+        }
+
+        fn compiles_per_layer(&self) -> bool {
+            self.per_layer
+        }
+    }
+
+    fn test_layer(bytes: &[u8]) -> WasmLayer {
+        let config = DescriptorBuilder::default()
+            .media_type(WASM_LAYER_MEDIA_TYPE)
+            .size(bytes.len() as u64)
+            .digest(
+                format!("sha256:{}", digest(bytes.to_vec()))
+                    .parse::<Digest>()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        WasmLayer {
+            config,
+            layer: bytes.to_vec().into(),
+            wasm_config: None,
+            image_config: None,
+            run_config: None,
+            kind: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_precompile_preserves_layer_order() {
+        let layers: Vec<_> = (0..4).map(|n| test_layer(&[n])).collect();
+
+        let compiler = SlowCompiler::new(true);
+        let compiled = precompile(&compiler, "test", &layers).await.unwrap();
+
+        assert_eq!(
+            compiled,
+            [
+                Some(vec![0, 0]),
+                Some(vec![1, 1]),
+                Some(vec![2, 2]),
+                Some(vec![3, 3]),
+            ]
+        );
+        assert_eq!(compiler.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_precompile_batches_layers_by_default() {
+        let layers: Vec<_> = (0..4).map(|n| test_layer(&[n])).collect();
+
+        let compiler = SlowCompiler::new(false);
+        let compiled = precompile(&compiler, "batched", &layers).await.unwrap();
+
+        assert_eq!(
+            compiled,
+            [
+                Some(vec![0, 0]),
+                Some(vec![1, 1]),
+                Some(vec![2, 2]),
+                Some(vec![3, 3]),
+            ]
+        );
+        assert_eq!(compiler.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_precompile_reports_failed_layer() {
+        let layers = [test_layer(&[0]), test_layer(&[])];
+
+        let err = precompile(&SlowCompiler::new(true), "test", &layers)
+            .await
+            .unwrap_err();
+
+        let failed = layers[1].config.digest().to_string();
+        assert!(
+            matches!(&err, Error::Compilation { digest, .. } if *digest == failed),
+            "{err:#}"
+        );
+        assert!(err.to_string().contains("empty module"), "{err}");
+    }
+
     fn generate_test_container(
         name: Option<String>,
         original: &[&oci_helpers::ImageContent],
@@ -1062,6 +3139,15 @@ mod tests {
         }
     }
 
+    // removes a container that shares the image of another test container
+    struct ContainerCleanup(String);
+
+    impl Drop for ContainerCleanup {
+        fn drop(&mut self) {
+            oci_helpers::clean_container(self.0.clone()).unwrap();
+        }
+    }
+
     fn random_number() -> u32 {
         let x: u32 = rand::random();
         x
@@ -1072,7 +3158,7 @@ mod tests {
         precompile_id: String,
         precompiled_layers: HashMap<String, Vec<u8>>,
         precompile_called: Arc<AtomicI32>,
-        layers_compiled_per_call: Arc<AtomicI32>,
+        layers_compiled: Arc<AtomicI32>,
     }
 
     impl FakePrecomipler {
@@ -1082,7 +3168,7 @@ mod tests {
                 precompile_id,
                 precompiled_layers: HashMap::new(),
                 precompile_called: Arc::new(AtomicI32::new(0)),
-                layers_compiled_per_call: Arc::new(AtomicI32::new(0)),
+                layers_compiled: Arc::new(AtomicI32::new(0)),
             }
         }
         fn add_precompiled_bits(
@@ -1102,7 +3188,6 @@ mod tests {
         }
 
         async fn compile(&self, layers: &[WasmLayer]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
-            self.layers_compiled_per_call.store(0, Ordering::SeqCst);
             self.precompile_called.fetch_add(1, Ordering::SeqCst);
             let mut compiled_layers = vec![];
             for layer in layers {
@@ -1112,7 +3197,7 @@ mod tests {
                     continue;
                 }
 
-                let key = digest(layer.layer.clone());
+                let key = digest(layer.layer.to_vec());
                 if self.precompiled_layers.values().any(|l| digest(l) == key) {
                     // simulate scenario were one of the layers is already compiled
                     compiled_layers.push(None);
@@ -1126,7 +3211,7 @@ mod tests {
                 });
                 let precompiled = self.precompiled_layers[&key].clone();
                 compiled_layers.push(Some(precompiled));
-                self.layers_compiled_per_call.fetch_add(1, Ordering::SeqCst);
+                self.layers_compiled.fetch_add(1, Ordering::SeqCst);
             }
             Ok(compiled_layers)
         }
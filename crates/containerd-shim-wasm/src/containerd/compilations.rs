@@ -0,0 +1,240 @@
+//! Compilations of the wasm layers across all the instances of the shim.
+//!
+//! Precompiling is CPU-bound, so when the replicas of a deployment land on a node at once, their
+//! compilations would take every core from the workloads already running. At most
+//! `precompile.max_concurrent_compilations` layers are compiled at a time, half of the CPUs by
+//! default, and the instances compiling the same layer share a single compilation of it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+
+use crate::shim::ShimConfig;
+
+// The compiled layers, or the error compiling them, which is shared with the other instances
+type Compiled = Result<Vec<Option<Vec<u8>>>, String>;
+
+pub(crate) static COMPILATIONS: LazyLock<Compilations> = LazyLock::new(|| {
+    let max = ShimConfig::get()
+        .precompile
+        .max_concurrent_compilations
+        .unwrap_or_else(|| {
+            let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+            cpus / 2
+        });
+    Compilations::new(max)
+});
+
+pub(crate) struct Compilations {
+    max: usize,
+    permits: Semaphore,
+    // the compilations in flight, by layer
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<Compiled>>>>,
+    // how long the last compilation of each layer took
+    durations: Mutex<HashMap<String, Duration>>,
+}
+
+impl Compilations {
+    fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            permits: Semaphore::new(max),
+            in_flight: Mutex::default(),
+            durations: Mutex::default(),
+        }
+    }
+
+    /// Compiles the layer `key` with `compile` once no more than the maximum number of layers
+    /// are being compiled, or waits for the compilation of `key` in flight and returns its result.
+    /// When the compilation in flight is cancelled, e.g., because its instance was deleted while it
+    /// was queued, its permit is released and one of the instances waiting for it compiles it.
+    pub(crate) async fn run<Fut>(
+        &self,
+        key: &str,
+        compile: impl FnOnce() -> Fut,
+    ) -> anyhow::Result<Vec<Option<Vec<u8>>>>
+    where
+        Fut: Future<Output = anyhow::Result<Vec<Option<Vec<u8>>>>>,
+    {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let compiled = cell
+            .get_or_init(|| async {
+                let _permit = self.acquire(key).await;
+                let started = Instant::now();
+                let compiled = compile().await.map_err(|err| format!("{err:#}"));
+                let elapsed = started.elapsed();
+                self.durations
+                    .lock()
+                    .unwrap()
+                    .insert(key.to_string(), elapsed);
+                compiled
+            })
+            .await
+            .clone();
+
+        // forget the compilation once it's done, so that a later one of the layer, e.g., after
+        // its precompiled content was removed, compiles it again
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|in_flight| Arc::ptr_eq(in_flight, &cell))
+        {
+            in_flight.remove(key);
+        }
+        drop(in_flight);
+
+        compiled.map_err(anyhow::Error::msg)
+    }
+
+    /// Returns the state of the compilations, for the debug dumps.
+    pub(crate) fn snapshot(&self) -> CompilationsSnapshot {
+        let mut in_flight: Vec<_> = self.in_flight.lock().unwrap().keys().cloned().collect();
+        in_flight.sort();
+        let durations = self.durations.lock().unwrap();
+        CompilationsSnapshot {
+            max: self.max,
+            running: self.max - self.permits.available_permits(),
+            in_flight,
+            seconds: durations
+                .iter()
+                .map(|(key, elapsed)| (key.clone(), elapsed.as_secs_f64()))
+                .collect(),
+        }
+    }
+
+    async fn acquire(&self, key: &str) -> SemaphorePermit<'_> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return permit;
+        }
+        log::info!("queueing the compilation of layer {key} behind the ones running");
+        let queued = Instant::now();
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore of the compilations is never closed");
+        log::info!(
+            "compiling layer {key} after waiting {:?} in the queue",
+            queued.elapsed()
+        );
+        permit
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct CompilationsSnapshot {
+    max: usize,
+    running: usize,
+    // the layers being compiled or waiting for a permit
+    in_flight: Vec<String>,
+    // how long the last compilation of each layer took
+    seconds: BTreeMap<String, f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_same_layer_is_compiled_once() -> anyhow::Result<()> {
+        let compilations = Compilations::new(2);
+        let calls = AtomicUsize::new(0);
+        let compile = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(vec![Some(vec![1, 2, 3])])
+        };
+
+        let (first, second) = tokio::join!(
+            compilations.run("layer", compile),
+            compilations.run("layer", compile)
+        );
+        assert_eq!(first?, [Some(vec![1, 2, 3])]);
+        assert_eq!(second?, [Some(vec![1, 2, 3])]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // the failures are shared too
+        let fail = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            anyhow::bail!("invalid module")
+        };
+        let (first, second) = tokio::join!(
+            compilations.run("invalid", fail),
+            compilations.run("invalid", fail)
+        );
+        assert_eq!(first.unwrap_err().to_string(), "invalid module");
+        assert_eq!(second.unwrap_err().to_string(), "invalid module");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // a compilation that's done isn't shared with the later ones
+        compilations.run("layer", compile).await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compilations_are_bounded() -> anyhow::Result<()> {
+        let compilations = Compilations::new(2);
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let compile = || async {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(vec![None])
+        };
+
+        let compiled = futures::future::try_join_all(
+            ["a", "b", "c", "d", "e"].map(|key| compilations.run(key, compile)),
+        )
+        .await?;
+        assert_eq!(compiled.len(), 5);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_compilations_release_their_permit() -> anyhow::Result<()> {
+        let compilations = Compilations::new(1);
+        let timeout = Duration::from_millis(20);
+
+        // the running compilation is cancelled, and the next one of the layer takes over
+        let running = compilations.run("a", std::future::pending);
+        assert!(tokio::time::timeout(timeout, running).await.is_err());
+        let compiled = compilations.run("a", || async { Ok(vec![None]) });
+        assert_eq!(tokio::time::timeout(timeout, compiled).await??, [None]);
+
+        // so is a queued one, whose instance was deleted while waiting for the running one
+        let long = compilations.run("b", || async {
+            tokio::time::sleep(2 * timeout).await;
+            Ok(vec![None])
+        });
+        let queued = compilations.run("c", || async { Ok(vec![None]) });
+        let queued = tokio::time::timeout(timeout, queued);
+        let (long, queued) = tokio::join!(long, queued);
+        long?;
+        assert!(queued.is_err());
+
+        assert_eq!(compilations.permits.available_permits(), 1);
+        let compiled = compilations.run("c", || async { Ok(vec![None]) });
+        assert_eq!(tokio::time::timeout(timeout, compiled).await??, [None]);
+
+        Ok(())
+    }
+}
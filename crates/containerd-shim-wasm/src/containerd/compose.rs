@@ -0,0 +1,366 @@
+//! Composition of component layers with the components they depend on.
+//!
+//! Images built with tools like `wac` can carry a root component and the components it
+//! depends on as separate layers. A layer lists the layers it depends on in its
+//! [`DEPENDS_ON_ANNOTATION`], and it's composed with them before it's handed to the compiler
+//! and the engine: the exports of each dependency are plugged into the matching imports of the
+//! layer. Dependencies are composed with their own dependencies first, and the layers that are
+//! only dependencies of other layers are not handed to the engine.
+
+use std::collections::HashMap;
+
+use anyhow::{Context as _, bail};
+use oci_spec::image::Descriptor;
+use wac_graph::types::Package;
+use wac_graph::{CompositionGraph, EncodeOptions};
+
+use crate::sandbox::context::{
+    DEPENDS_ON_ANNOTATION, LayerContent, TITLE_ANNOTATION, WasmLayerKind,
+};
+
+/// The dependencies between the layers of an image.
+#[derive(Clone, Debug)]
+pub(crate) struct Composition {
+    // name of each layer in errors: its title annotation, or its digest
+    names: Vec<String>,
+    digests: Vec<String>,
+    // indices of the layers each layer depends on
+    dependencies: Vec<Vec<usize>>,
+}
+
+impl Composition {
+    /// Resolves the dependencies of the `layers` of an image from their annotations.
+    /// Fails if a dependency is not a layer of the image, or if the dependencies form a cycle.
+    pub(crate) fn new(layers: &[Descriptor]) -> anyhow::Result<Self> {
+        let names: Vec<_> = layers.iter().map(layer_name).collect();
+        let mut dependencies = Vec::with_capacity(layers.len());
+        for (i, layer) in layers.iter().enumerate() {
+            let mut deps = vec![];
+            for dependency in depends_on(layer) {
+                let Some(j) = layers.iter().position(|other| is_named(other, dependency)) else {
+                    bail!(
+                        "layer {} depends on {dependency}, which is not a layer of the image",
+                        names[i]
+                    );
+                };
+                if !deps.contains(&j) {
+                    deps.push(j);
+                }
+            }
+            dependencies.push(deps);
+        }
+
+        let composition = Self {
+            names,
+            digests: layers
+                .iter()
+                .map(|layer| layer.digest().to_string())
+                .collect(),
+            dependencies,
+        };
+        let mut acyclic = vec![false; layers.len()];
+        for i in 0..layers.len() {
+            composition.check_cycles(i, &mut vec![], &mut acyclic)?;
+        }
+        Ok(composition)
+    }
+
+    /// Returns whether the layer `i` is composed with other layers.
+    pub(crate) fn is_composed(&self, i: usize) -> bool {
+        !self.dependencies[i].is_empty()
+    }
+
+    /// Returns the indices of the layers to load, in order: the ones no other layer depends on.
+    /// The other layers are only read to be composed with the layers depending on them.
+    pub(crate) fn roots(&self) -> Vec<usize> {
+        (0..self.dependencies.len())
+            .filter(|i| !self.dependencies.iter().any(|deps| deps.contains(i)))
+            .collect()
+    }
+
+    /// Returns the layers the layer `i` is composed with, directly or not, followed by `i`.
+    /// Every layer comes after the layers it depends on.
+    pub(crate) fn closure(&self, i: usize) -> Vec<usize> {
+        let mut order = vec![];
+        self.visit(i, &mut order);
+        order
+    }
+
+    /// Returns what the layer `i` is built from: its digest, followed by the digests of the
+    /// layers it's composed with. The artifacts precompiled from a composed layer record it,
+    /// so that they are not used once a dependency changes.
+    pub(crate) fn source(&self, i: usize) -> String {
+        let digests: Vec<_> = self
+            .closure(i)
+            .into_iter()
+            .rev()
+            .map(|j| self.digests[j].as_str())
+            .collect();
+        digests.join("+")
+    }
+
+    /// Composes the layer `i` with the layers it depends on, given the `contents` of the layers
+    /// in its [`Composition::closure`]. Returns the binary of the composed component.
+    pub(crate) fn compose(
+        &self,
+        i: usize,
+        contents: &HashMap<usize, LayerContent>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut composed = HashMap::<usize, Vec<u8>>::new();
+        for j in self.closure(i) {
+            let name = &self.names[j];
+            let content = contents
+                .get(&j)
+                .with_context(|| format!("layer {name} was not read"))?;
+            if WasmLayerKind::from_bytes(content) != Some(WasmLayerKind::Component) {
+                bail!("layer {name} is not a component, so it can't be composed");
+            }
+            let component = match &self.dependencies[j][..] {
+                [] => content.to_vec(),
+                deps => {
+                    let plugs = deps.iter().map(|dep| composed[dep].clone()).collect();
+                    plug(content, plugs).with_context(|| {
+                        format!("failed to compose layer {name} with its dependencies")
+                    })?
+                }
+            };
+            composed.insert(j, component);
+        }
+        Ok(composed.remove(&i).expect("the layer is composed last"))
+    }
+
+    fn visit(&self, i: usize, order: &mut Vec<usize>) {
+        if order.contains(&i) {
+            return;
+        }
+        for dependency in &self.dependencies[i] {
+            self.visit(*dependency, order);
+        }
+        order.push(i);
+    }
+
+    // Fails if there's a cycle through the layer `i`, given the `path` of layers depending on it
+    fn check_cycles(
+        &self,
+        i: usize,
+        path: &mut Vec<usize>,
+        acyclic: &mut [bool],
+    ) -> anyhow::Result<()> {
+        if acyclic[i] {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|j| *j == i) {
+            let cycle: Vec<_> = path[start..]
+                .iter()
+                .chain([&i])
+                .map(|j| self.names[*j].as_str())
+                .collect();
+            bail!(
+                "the dependencies of the wasm layers form a cycle: {}",
+                cycle.join(" -> ")
+            );
+        }
+        path.push(i);
+        for dependency in &self.dependencies[i] {
+            self.check_cycles(*dependency, path, acyclic)?;
+        }
+        path.pop();
+        acyclic[i] = true;
+        Ok(())
+    }
+}
+
+// Plugs the exports of the `plugs` into the matching imports of the `socket` component
+fn plug(socket: &[u8], plugs: Vec<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    let mut graph = CompositionGraph::new();
+    let socket = Package::from_bytes("runwasi:root", None, socket.to_vec(), graph.types_mut())?;
+    let socket = graph.register_package(socket)?;
+    let mut plug_ids = Vec::with_capacity(plugs.len());
+    for (i, plug) in plugs.into_iter().enumerate() {
+        let name = format!("runwasi:dependency{i}");
+        let plug = Package::from_bytes(&name, None, plug, graph.types_mut())?;
+        plug_ids.push(graph.register_package(plug)?);
+    }
+    wac_graph::plug(&mut graph, plug_ids, socket)?;
+    Ok(graph.encode(EncodeOptions::default())?)
+}
+
+fn annotation<'a>(layer: &'a Descriptor, key: &str) -> Option<&'a str> {
+    layer
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(key))
+        .map(String::as_str)
+}
+
+fn layer_name(layer: &Descriptor) -> String {
+    match annotation(layer, TITLE_ANNOTATION) {
+        Some(title) => title.to_string(),
+        None => layer.digest().to_string(),
+    }
+}
+
+fn is_named(layer: &Descriptor, name: &str) -> bool {
+    annotation(layer, TITLE_ANNOTATION) == Some(name) || layer.digest().to_string() == name
+}
+
+fn depends_on(layer: &Descriptor) -> impl Iterator<Item = &str> {
+    annotation(layer, DEPENDS_ON_ANNOTATION)
+        .into_iter()
+        .flat_map(|dependencies| dependencies.split(','))
+        .map(str::trim)
+        .filter(|dependency| !dependency.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{Digest, MediaType};
+    use wasmparser::{Parser, Payload};
+
+    use super::*;
+
+    fn layer(n: u32, annotations: &[(&str, &str)]) -> Descriptor {
+        let mut layer = Descriptor::new(
+            MediaType::Other("application/wasm".to_string()),
+            1,
+            Digest::try_from(format!("sha256:{n:064}")).unwrap(),
+        );
+        let annotations = annotations
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        layer.set_annotations(Some(annotations));
+        layer
+    }
+
+    fn named(n: u32, title: &str, depends_on: &str) -> Descriptor {
+        layer(
+            n,
+            &[
+                (TITLE_ANNOTATION, title),
+                (DEPENDS_ON_ANNOTATION, depends_on),
+            ],
+        )
+    }
+
+    // names of the imports of the outer component
+    fn imports(component: &[u8]) -> Vec<String> {
+        let mut depth = 0;
+        let mut imports = vec![];
+        for payload in Parser::new(0).parse_all(component) {
+            match payload.unwrap() {
+                Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+                Payload::End(_) => depth -= 1,
+                Payload::ComponentImportSection(reader) if depth == 0 => {
+                    for import in reader {
+                        imports.push(import.unwrap().name.0.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        imports
+    }
+
+    #[test]
+    fn test_composition_of_layers() -> anyhow::Result<()> {
+        let digest = format!("sha256:{:064}", 4);
+        let layers = [
+            named(1, "app.wasm", &format!("lib.wasm, {digest}")),
+            named(2, "lib.wasm", "util.wasm"),
+            named(3, "util.wasm", ""),
+            layer(4, &[]),
+            layer(5, &[]),
+        ];
+        let composition = Composition::new(&layers)?;
+
+        assert_eq!(composition.roots(), [0, 4]);
+        assert!(composition.is_composed(0));
+        assert!(!composition.is_composed(4));
+        assert_eq!(composition.closure(0), [2, 1, 3, 0]);
+        assert_eq!(composition.closure(4), [4]);
+        assert_eq!(composition.source(4), format!("sha256:{:064}", 5));
+        assert_eq!(
+            composition.source(0),
+            format!(
+                "sha256:{:064}+sha256:{:064}+sha256:{:064}+sha256:{:064}",
+                1, 4, 2, 3
+            )
+        );
+
+        // layers without dependencies are loaded as they are
+        let composition = Composition::new(&[layer(1, &[]), layer(2, &[])])?;
+        assert_eq!(composition.roots(), [0, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composition_errors() {
+        let err = Composition::new(&[named(1, "app.wasm", "lib.wasm")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "layer app.wasm depends on lib.wasm, which is not a layer of the image"
+        );
+
+        let err = Composition::new(&[
+            named(1, "app.wasm", "lib.wasm"),
+            named(2, "lib.wasm", "util.wasm"),
+            named(3, "util.wasm", "app.wasm"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the dependencies of the wasm layers form a cycle: \
+             app.wasm -> lib.wasm -> util.wasm -> app.wasm"
+        );
+
+        let err = Composition::new(&[named(1, "app.wasm", "app.wasm")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the dependencies of the wasm layers form a cycle: app.wasm -> app.wasm"
+        );
+    }
+
+    #[test]
+    fn test_compose() -> anyhow::Result<()> {
+        let app = wat::parse_str(
+            r#"(component
+                (import "example:lib/api" (instance $api (export "answer" (func (result u32)))))
+                (alias export $api "answer" (func $answer))
+                (export "run" (func $answer))
+            )"#,
+        )?;
+        let lib = wat::parse_str(
+            r#"(component
+                (core module $m (func (export "answer") (result i32) i32.const 42))
+                (core instance $i (instantiate $m))
+                (func $answer (result u32) (canon lift (core func $i "answer")))
+                (instance $api (export "answer" (func $answer)))
+                (export "example:lib/api" (instance $api))
+            )"#,
+        )?;
+        assert_eq!(imports(&app), ["example:lib/api"]);
+
+        let composition =
+            Composition::new(&[named(1, "app.wasm", "lib.wasm"), named(2, "lib.wasm", "")])?;
+        let contents = HashMap::from([(0, app.into()), (1, lib.clone().into())]);
+        let composed = composition.compose(0, &contents)?;
+        assert_eq!(
+            WasmLayerKind::from_bytes(&composed),
+            Some(WasmLayerKind::Component)
+        );
+        assert!(imports(&composed).is_empty());
+
+        // core modules can't be composed
+        let module = wat::parse_str("(module)")?;
+        let contents = HashMap::from([(0, module.into()), (1, lib.into())]);
+        let err = composition.compose(0, &contents).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "layer app.wasm is not a component, so it can't be composed"
+        );
+
+        Ok(())
+    }
+}
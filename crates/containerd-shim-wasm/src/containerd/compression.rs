@@ -0,0 +1,210 @@
+//! Decompression of gzip and zstd compressed wasm layers.
+//!
+//! The compression of a layer is given by the `+gzip` or `+zstd` suffix of its media type,
+//! e.g., `application/wasm+zstd`. Layers without a suffix are still checked for the magic
+//! bytes of the formats, as some image builders compress layers without changing their media type.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+
+use super::digest::DigestWriter;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression of layers with `media_type` from its suffix.
+    pub(crate) fn from_media_type(media_type: &str) -> Option<Self> {
+        if media_type.ends_with("+gzip") {
+            Some(Self::Gzip)
+        } else if media_type.ends_with("+zstd") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the compression of `data` from its magic bytes.
+    pub(crate) fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Decompresses all of `input` into `output`.
+    pub(crate) fn decompress(self, input: impl Read, mut output: impl Write) -> IoResult<()> {
+        match self {
+            Self::Gzip => {
+                std::io::copy(&mut flate2::read::MultiGzDecoder::new(input), &mut output)?;
+            }
+            Self::Zstd => zstd::stream::copy_decode(input, &mut output)?,
+        }
+        output.flush()
+    }
+}
+
+/// Returns `media_type` without its compression suffix.
+pub(crate) fn uncompressed_media_type(media_type: &str) -> &str {
+    media_type
+        .strip_suffix("+gzip")
+        .or_else(|| media_type.strip_suffix("+zstd"))
+        .unwrap_or(media_type)
+}
+
+/// Decompresses `data`, detecting its compression if `compression` is `None`.
+/// Returns the digest and the uncompressed content, or `None` if `data` is not compressed.
+pub(crate) fn decompress(
+    compression: Option<Compression>,
+    data: &[u8],
+) -> IoResult<Option<(String, Vec<u8>)>> {
+    let Some(compression) = compression.or_else(|| Compression::detect(data)) else {
+        return Ok(None);
+    };
+    let mut output = DigestWriter::new(vec![]);
+    compression.decompress(data, &mut output)?;
+    Ok(Some(output.finish()))
+}
+
+/// Decompresses the file at `path` into a file in `dir` named after the digest of the
+/// uncompressed content, detecting the compression if `compression` is `None`.
+/// Returns the digest and the path of the uncompressed file, or `None` if the file is not
+/// compressed. The compressed file is removed once it's decompressed.
+pub(crate) fn decompress_file(
+    compression: Option<Compression>,
+    path: &Path,
+    dir: &Path,
+) -> IoResult<Option<(String, PathBuf)>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let compression = match compression {
+        Some(compression) => compression,
+        None => {
+            let mut magic = [0; 4];
+            let len = read_prefix(&mut input, &mut magic)?;
+            let Some(compression) = Compression::detect(&magic[..len]) else {
+                return Ok(None);
+            };
+            input = BufReader::new(File::open(path)?);
+            compression
+        }
+    };
+
+    let tmp = path.with_extension("uncompressed");
+    let res = (|| {
+        let mut output = DigestWriter::new(BufWriter::new(File::create(&tmp)?));
+        compression.decompress(input, &mut output)?;
+        let (digest, _) = output.finish();
+        let uncompressed = dir.join(digest.replace(':', "-"));
+        std::fs::rename(&tmp, &uncompressed)?;
+        Ok((digest, uncompressed))
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    let _ = std::fs::remove_file(path);
+    res.map(Some)
+}
+
+// like `read_exact`, but stops at the end of the input
+fn read_prefix(input: &mut impl Read, buf: &mut [u8]) -> IoResult<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{}", sha256::digest(data))
+    }
+
+    #[test]
+    fn test_compression_from_media_type() {
+        assert_eq!(
+            Compression::from_media_type("application/wasm+zstd"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::from_media_type("application/wasm+gzip"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(Compression::from_media_type("application/wasm"), None);
+        assert_eq!(
+            uncompressed_media_type("application/wasm+zstd"),
+            "application/wasm"
+        );
+        assert_eq!(
+            uncompressed_media_type("application/wasm"),
+            "application/wasm"
+        );
+    }
+
+    #[test]
+    fn test_decompress() -> anyhow::Result<()> {
+        let zstd = zstd::encode_all(WASM, 0)?;
+        let expected = Some((sha256(WASM), WASM.to_vec()));
+
+        assert_eq!(decompress(Some(Compression::Zstd), &zstd)?, expected);
+        assert_eq!(decompress(None, &zstd)?, expected);
+        assert_eq!(decompress(None, &gzip(WASM))?, expected);
+        assert_eq!(decompress(None, WASM)?, None);
+
+        assert!(decompress(Some(Compression::Zstd), WASM).is_err());
+        assert!(decompress(None, &zstd[..zstd.len() - 2]).is_err());
+        let gzip = gzip(WASM);
+        assert!(decompress(None, &gzip[..gzip.len() - 2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+
+        std::fs::write(&path, zstd::encode_all(WASM, 0)?)?;
+        let (digest, uncompressed) = decompress_file(None, &path, dir.path())?.unwrap();
+        assert_eq!(digest, sha256(WASM));
+        assert_eq!(uncompressed, dir.path().join(digest.replace(':', "-")));
+        assert_eq!(std::fs::read(&uncompressed)?, WASM);
+        assert!(!path.exists());
+
+        std::fs::write(&path, WASM)?;
+        assert_eq!(decompress_file(None, &path, dir.path())?, None);
+        assert!(path.exists());
+
+        // a corrupted file leaves nothing behind
+        std::fs::write(&path, b"\x28\xb5\x2f\xfd corrupted")?;
+        assert!(decompress_file(None, &path, dir.path()).is_err());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+}
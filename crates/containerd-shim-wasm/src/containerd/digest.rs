@@ -0,0 +1,174 @@
+//! Incremental digests of the content read from the content store.
+//!
+//! Content is checked against the sha256 or sha512 digest it was read by as it's streamed,
+//! so that a corrupted blob fails the read instead of being handed to the engine.
+//! The verification can be disabled with `layers.verify_digests`, e.g., to debug a corrupted
+//! content store in an air-gapped environment.
+
+use std::fmt::Write as _;
+use std::io::{Result as IoResult, Write};
+use std::sync::Once;
+
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use oci_spec::image::{Digest, DigestAlgorithm};
+use sha2::{Digest as _, Sha256, Sha512};
+
+use crate::shim::ShimConfig;
+
+/// Returns whether the digests of the content read from the content store are verified.
+pub(crate) fn verify_digests() -> bool {
+    static DISABLED: Once = Once::new();
+
+    let verify = ShimConfig::get().layers.verify_digests.unwrap_or(true);
+    if !verify {
+        DISABLED.call_once(|| log::warn!("the digests of the content are not verified"));
+    }
+    verify
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+pub(crate) struct DigestVerifier {
+    expected: Digest,
+    // `None` if the digest is not verified
+    hasher: Option<Hasher>,
+}
+
+impl DigestVerifier {
+    /// Verifies the content against `expected` if `verify` is set.
+    pub(crate) fn new(expected: Digest, verify: bool) -> Self {
+        let hasher = match expected.algorithm() {
+            _ if !verify => None,
+            DigestAlgorithm::Sha256 => Some(Hasher::Sha256(Sha256::new())),
+            DigestAlgorithm::Sha512 => Some(Hasher::Sha512(Sha512::new())),
+            algorithm => {
+                log::warn!(
+                    "not verifying the digest of {expected}: unsupported algorithm {algorithm}"
+                );
+                None
+            }
+        };
+        Self { expected, hasher }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Some(Hasher::Sha256(hasher)) => hasher.update(data),
+            Some(Hasher::Sha512(hasher)) => hasher.update(data),
+            None => {}
+        }
+    }
+
+    /// Checks the digest of all the data passed to `update` against the expected digest.
+    pub(crate) fn verify(self) -> Result<()> {
+        let actual = match self.hasher {
+            Some(Hasher::Sha256(hasher)) => hex(&hasher.finalize()),
+            Some(Hasher::Sha512(hasher)) => hex(&hasher.finalize()),
+            None => return Ok(()),
+        };
+        if actual != self.expected.digest() {
+            return Err(ShimError::DigestMismatch {
+                actual: format!("{}:{actual}", self.expected.algorithm()),
+                expected: self.expected.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Computes the sha256 digest of the data written through it to `W`.
+pub(crate) struct DigestWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> DigestWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the digest of the written data, e.g. `sha256:<hex>`, and the inner writer.
+    pub(crate) fn finish(self) -> (String, W) {
+        let digest = format!("sha256:{}", hex(&self.hasher.finalize()));
+        (digest, self.inner)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(expected: &str, chunks: &[&[u8]]) -> Result<()> {
+        let mut verifier = DigestVerifier::new(expected.parse().unwrap(), true);
+        for chunk in chunks {
+            verifier.update(chunk);
+        }
+        verifier.verify()
+    }
+
+    #[test]
+    fn test_digest_verifier() {
+        let expected = format!("sha256:{}", sha256::digest("hello world"));
+        assert!(verify(&expected, &[b"hello world"]).is_ok());
+        assert!(verify(&expected, &[b"hello", b" ", b"world"]).is_ok());
+        assert!(verify(&expected, &[]).is_err());
+
+        let err = verify(&expected, &[b"hello"]).unwrap_err();
+        assert!(matches!(err, ShimError::DigestMismatch { .. }));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "digest mismatch for {expected}: got sha256:{}",
+                sha256::digest("hello")
+            )
+        );
+
+        let sha512 = "sha512:309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f";
+        assert!(verify(sha512, &[b"hello ", b"world"]).is_ok());
+        assert!(matches!(
+            verify(sha512, &[b"hello"]),
+            Err(ShimError::DigestMismatch { .. })
+        ));
+
+        // nothing is verified when verification is disabled
+        let mut verifier = DigestVerifier::new(expected.parse().unwrap(), false);
+        verifier.update(b"hello");
+        assert!(verifier.verify().is_ok());
+    }
+
+    #[test]
+    fn test_digest_writer() -> anyhow::Result<()> {
+        let mut writer = DigestWriter::new(vec![]);
+        writer.write_all(b"hello ")?;
+        writer.write_all(b"world")?;
+        let (digest, data) = writer.finish();
+        assert_eq!(digest, format!("sha256:{}", sha256::digest("hello world")));
+        assert_eq!(data, b"hello world");
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Allow and deny lists of the images the instances can run.
+//!
+//! The policy is a JSON file at the path of `policy.image_policy`, with rules that are either
+//! image digests, e.g., `sha256:<hex>`, or globs of image names, e.g.:
+//!
+//! ```json
+//! {
+//!   "allow": ["ghcr.io/org/**", "docker.io/library/hello-wasm:*"],
+//!   "deny": ["ghcr.io/org/legacy/*", "sha256:<hex>"]
+//! }
+//! ```
+//!
+//! In globs, `*` matches any characters but `/`, `**` any characters, and `?` any character but
+//! `/`. They match the name of the image with or without its tag or digest.
+//! An image matching a deny rule is denied, even if it also matches an allow rule. Otherwise,
+//! it's allowed if it matches an allow rule, or if there are none.
+//!
+//! The file is read again when it changes, so that the rules can be updated without restarting
+//! the shims. Without the file, all the images are allowed.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+use serde::Deserialize;
+
+use super::signature::repository;
+use crate::shim::ShimConfig;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl Rules {
+    // The rule an image matches, either its digest or a glob of its name
+    fn find<'a>(rules: &'a [String], name: &str, digest: &str) -> Option<&'a str> {
+        rules.iter().map(String::as_str).find(|rule| {
+            *rule == digest || glob_match(rule, name) || glob_match(rule, repository(name))
+        })
+    }
+
+    // Why the image `name` with `digest` is denied, if it is
+    fn denied(&self, name: &str, digest: &str) -> Option<String> {
+        if let Some(rule) = Self::find(&self.deny, name, digest) {
+            return Some(format!("it matches the deny rule {rule:?}"));
+        }
+        if !self.allow.is_empty() && Self::find(&self.allow, name, digest).is_none() {
+            return Some("it matches none of the allow rules".into());
+        }
+        None
+    }
+}
+
+// When the policy file was modified, and its size, to tell when it changes
+type FileStamp = Option<(SystemTime, u64)>;
+
+/// The image policy in a file, which is read again when it changes.
+pub(crate) struct ImagePolicy {
+    path: PathBuf,
+    // the rules of the file, with the stamp of the file they were read from
+    loaded: Mutex<Option<(FileStamp, Arc<Rules>)>>,
+}
+
+impl ImagePolicy {
+    /// Returns the image policy of the `policy.image_policy` of the shim, if it's set.
+    pub(crate) fn from_config() -> Option<&'static Self> {
+        static POLICY: LazyLock<Option<ImagePolicy>> = LazyLock::new(|| {
+            let path = ShimConfig::get().policy.image_policy.as_ref()?;
+            log::info!("checking the images of the instances with the policy {path:?}");
+            Some(ImagePolicy::new(path))
+        });
+        POLICY.as_ref()
+    }
+
+    fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            loaded: Mutex::new(None),
+        }
+    }
+
+    /// Checks that the image `name` with `digest` is allowed, or `None` for containers without
+    /// an image, which are only allowed without allow rules.
+    pub(crate) fn check(&self, image: Option<(&str, &str)>) -> Result<()> {
+        let rules = self.rules().map_err(|err| {
+            ShimError::FailedPrecondition(format!("invalid image policy {:?}: {err}", self.path))
+        })?;
+        let denied = match image {
+            Some((name, digest)) => rules.denied(name, digest),
+            None if !rules.allow.is_empty() => Some("the container has no image".into()),
+            None => None,
+        };
+        match denied {
+            Some(reason) => Err(ShimError::ImageDenied {
+                image: image.map_or("", |(name, _)| name).to_string(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // The rules of the file, read again if it changed since they were last read
+    fn rules(&self) -> Result<Arc<Rules>, String> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let stamp = file_stamp(&self.path);
+        match &*loaded {
+            Some((loaded_stamp, rules)) if *loaded_stamp == stamp => return Ok(rules.clone()),
+            _ => {}
+        }
+
+        let rules = match stamp {
+            Some(_) => Arc::new(read_rules(&self.path)?),
+            None => Arc::default(),
+        };
+        log::info!("using the image policy {:?}: {rules:?}", self.path);
+        *loaded = Some((stamp, rules.clone()));
+        Ok(rules)
+    }
+}
+
+fn file_stamp(path: &Path) -> FileStamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn read_rules(path: &Path) -> Result<Rules, String> {
+    let file = std::fs::read(path).map_err(|err| err.to_string())?;
+    serde_json::from_slice(&file).map_err(|err| err.to_string())
+}
+
+// Whether `name` matches the glob `pattern`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("**") {
+        Some(rest) => (0..=name.len())
+            .filter(|&i| name.is_char_boundary(i))
+            .any(|i| glob_match(rest, &name[i..])),
+        None => match pattern.chars().next() {
+            None => name.is_empty(),
+            Some('*') => {
+                let rest = &pattern[1..];
+                let segment = name.find('/').unwrap_or(name.len());
+                (0..=segment)
+                    .filter(|&i| name.is_char_boundary(i))
+                    .any(|i| glob_match(rest, &name[i..]))
+            }
+            Some(c) => {
+                let Some(first) = name.chars().next() else {
+                    return false;
+                };
+                let matches = if c == '?' { first != '/' } else { c == first };
+                matches && glob_match(&pattern[c.len_utf8()..], &name[first.len_utf8()..])
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("ghcr.io/org/*", "ghcr.io/org/app"));
+        assert!(glob_match("ghcr.io/org/*", "ghcr.io/org/app:v1"));
+        assert!(!glob_match("ghcr.io/org/*", "ghcr.io/org/team/app"));
+        assert!(glob_match("ghcr.io/org/**", "ghcr.io/org/team/app"));
+        assert!(glob_match("**/app", "ghcr.io/org/app"));
+        assert!(glob_match("ghcr.io/org/app:v?", "ghcr.io/org/app:v1"));
+        assert!(!glob_match("ghcr.io/org/app:v?", "ghcr.io/org/app:v10"));
+        assert!(!glob_match("ghcr.io/org/app", "ghcr.io/org/app2"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = |allow: &[&str], deny: &[&str]| Rules {
+            allow: allow.iter().map(|rule| rule.to_string()).collect(),
+            deny: deny.iter().map(|rule| rule.to_string()).collect(),
+        };
+        let denied = |rules: &Rules, name| rules.denied(name, DIGEST);
+
+        // an empty policy allows all the images
+        assert_eq!(denied(&rules(&[], &[]), "ghcr.io/org/app:v1"), None);
+
+        // the rules match the name with or without its tag, or the digest
+        let allow = rules(&["ghcr.io/org/app", DIGEST], &[]);
+        assert_eq!(denied(&allow, "ghcr.io/org/app:v1"), None);
+        assert_eq!(denied(&allow, "ghcr.io/other/app:v1"), None);
+        assert_eq!(
+            allow.denied("ghcr.io/other/app:v1", "sha256:00"),
+            Some("it matches none of the allow rules".into())
+        );
+
+        // deny wins over allow
+        let policy = rules(&["ghcr.io/org/**"], &["ghcr.io/org/legacy/*"]);
+        assert_eq!(denied(&policy, "ghcr.io/org/team/app:v1"), None);
+        assert_eq!(
+            denied(&policy, "ghcr.io/org/legacy/app:v1"),
+            Some("it matches the deny rule \"ghcr.io/org/legacy/*\"".into())
+        );
+        let policy = rules(&["ghcr.io/org/**"], &[DIGEST]);
+        assert!(denied(&policy, "ghcr.io/org/app:v1").is_some());
+    }
+
+    #[test]
+    fn test_policy_is_reloaded() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("policy.json");
+        let policy = ImagePolicy::new(&path);
+        let image = Some(("ghcr.io/org/app:v1", DIGEST));
+
+        // all the images are allowed without the file
+        assert!(policy.check(image).is_ok());
+
+        std::fs::write(&path, r#"{"deny": ["ghcr.io/org/*"]}"#)?;
+        let err = policy.check(image).unwrap_err();
+        assert!(matches!(err, ShimError::ImageDenied { .. }));
+        assert!(err.to_string().contains("ghcr.io/org/*"), "{err}");
+        assert!(policy.check(None).is_ok());
+
+        // a changed file is read again, even within the resolution of its modification time
+        std::fs::write(&path, r#"{"allow": ["ghcr.io/org/**"], "deny": []}"#)?;
+        let file = std::fs::File::options().write(true).open(&path)?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))?;
+        assert!(policy.check(image).is_ok());
+        assert!(policy.check(None).is_err());
+
+        // an invalid file denies all the images, rather than allowing them
+        std::fs::write(&path, "{")?;
+        let err = policy.check(image).unwrap_err();
+        assert!(matches!(err, ShimError::FailedPrecondition(_)));
+
+        std::fs::remove_file(&path)?;
+        assert!(policy.check(image).is_ok());
+
+        Ok(())
+    }
+}
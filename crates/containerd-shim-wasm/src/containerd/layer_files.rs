@@ -0,0 +1,429 @@
+//! Index of the layer files in the `layers.files_dir` directory of the node.
+//!
+//! The memory-mapped layers are kept in their files after the last instance using them is
+//! deleted, so that later instances, including the ones of other shims and of a restarted
+//! shim, map them again instead of reading them from containerd. The files take at most
+//! `layers.files_quota` bytes, evicting the least recently used ones that are not mapped
+//! by any instance.
+//!
+//! The quota is the one of the node rather than of a shim: the files of all the shims count
+//! towards it, and so do the partial writes of the running shims. Shims started with different
+//! quotas for the same directory each evict down to their own quota.
+//!
+//! The shims of the node share the files:
+//! * the index is saved next to the files, and the shims update it while holding a lock on
+//!   [`LOCK_FILE`], reloading it first so that they see the files of the others,
+//! * a mapped file is locked shared, and a shim only evicts the files it can lock exclusively,
+//!   see [`crate::sys::mmap`],
+//! * the partial writes are named after the pid of their shim, and only removed once it exited.
+//!
+//! The index is rebuilt from the files in the directory when it's missing or corrupted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, create_dir_all, remove_file};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use crate::sys::mmap::remove_unmapped;
+
+pub(super) const DEFAULT_LAYER_FILES_DIR: &str = "/run/containerd/runwasi/layers";
+pub(super) const DEFAULT_LAYER_FILES_QUOTA: u64 = 4 * 1024 * 1024 * 1024;
+pub(super) const DEFAULT_LAYER_FILES_EVICTION_INTERVAL: u64 = 60;
+
+const INDEX_FILE: &str = "index.json";
+
+// The file the shims lock while they update the index
+const LOCK_FILE: &str = "index.lock";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    files: HashMap<String, FileEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct FileEntry {
+    size: u64,
+    // milliseconds since the epoch
+    last_used: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LayerFilesSnapshot {
+    dir: PathBuf,
+    quota: u64,
+    size: u64,
+    writing: u64,
+    files: BTreeMap<String, FileEntry>,
+}
+
+pub(crate) struct LayerFiles {
+    dir: PathBuf,
+    quota: u64,
+    index: Index,
+    // the size of the partial writes of the running shims
+    writing: u64,
+    // the last time a file was used, which never goes backwards
+    clock: u64,
+}
+
+impl LayerFiles {
+    /// Loads the index of the layer files in `dir`, rebuilding it from the files if needed.
+    /// The files left behind by the interrupted writes of the shims that exited are removed.
+    pub(crate) fn load(dir: impl Into<PathBuf>) -> Self {
+        let mut files = Self {
+            dir: dir.into(),
+            quota: u64::MAX,
+            index: Index::default(),
+            writing: 0,
+            clock: 0,
+        };
+        files.update(|_| {});
+        files
+    }
+
+    pub(crate) fn with_quota(mut self, quota: u64) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    // Runs `f` on the index reloaded from the directory and saves it, holding the lock of the
+    // directory. Failing to lock it only risks losing the updates of other shims.
+    fn update<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let _lock = self.lock();
+        self.reload();
+        let res = f(self);
+        self.save();
+        res
+    }
+
+    fn lock(&self) -> Option<Flock<File>> {
+        let res = create_dir_all(&self.dir)
+            .and_then(|_| File::create(self.dir.join(LOCK_FILE)))
+            .and_then(|file| {
+                Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| errno.into())
+            });
+        res.inspect_err(|err| {
+            log::warn!("failed to lock the layer files in {:?}: {err}", self.dir);
+        })
+        .ok()
+    }
+
+    // Reloads the index saved by the shims, indexing the files of the directory it misses
+    fn reload(&mut self) {
+        let saved = match std::fs::read(self.dir.join(INDEX_FILE)) {
+            Ok(saved) => serde_json::from_slice(&saved)
+                .inspect_err(|err| {
+                    log::warn!(
+                        "rebuilding the corrupted index of the layer files in {:?}: {err}",
+                        self.dir
+                    );
+                })
+                .unwrap_or_default(),
+            Err(err) if err.kind() == ErrorKind::NotFound => Index::default(),
+            Err(err) => {
+                log::warn!(
+                    "rebuilding the index of the layer files in {:?}: {err}",
+                    self.dir
+                );
+                Index::default()
+            }
+        };
+
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                log::warn!("failed to read the layer files in {:?}: {err}", self.dir);
+                return;
+            }
+        };
+        let mut index = Index::default();
+        let mut writing = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == INDEX_FILE || name == LOCK_FILE {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            // the layer files are named after their digest, the others are partial writes
+            if name.contains('.') {
+                if writer_is_running(&name) {
+                    writing += metadata.len();
+                } else {
+                    let _ = remove_file(entry.path());
+                }
+                continue;
+            }
+            let last_used = [saved.files.get(&name), self.index.files.get(&name)]
+                .into_iter()
+                .flatten()
+                .map(|entry| entry.last_used)
+                .max()
+                .unwrap_or_else(|| metadata.modified().map_or(0, millis));
+            self.clock = self.clock.max(last_used);
+            let size = metadata.len();
+            index.files.insert(name, FileEntry { size, last_used });
+        }
+        self.index = index;
+        self.writing = writing;
+    }
+
+    /// Returns the index of the layer files, for the debug dumps.
+    pub(crate) fn snapshot(&self) -> LayerFilesSnapshot {
+        LayerFilesSnapshot {
+            dir: self.dir.clone(),
+            quota: self.quota,
+            size: self.size(),
+            writing: self.writing,
+            files: self.index.files.clone().into_iter().collect(),
+        }
+    }
+
+    /// Returns the size in bytes of the layer files.
+    pub(crate) fn size(&self) -> u64 {
+        self.index.files.values().map(|entry| entry.size).sum()
+    }
+
+    /// Records that the layer file at `path` was used.
+    pub(crate) fn touch(&mut self, path: &Path) {
+        let Some(name) = self.name(path) else {
+            return;
+        };
+        self.update(|files| {
+            if let Some(entry) = files.index.files.get_mut(&name) {
+                files.clock = (files.clock + 1).max(millis(SystemTime::now()));
+                entry.last_used = files.clock;
+            }
+        });
+    }
+
+    /// Evicts the least recently used layer files until there is room for another one of
+    /// `size` bytes next to the partial writes, skipping the files that are `in_use` and the ones other processes map.
+    pub(crate) fn evict(&mut self, size: u64, in_use: impl Fn(&Path) -> bool) {
+        self.update(|files| {
+            let mut lru: Vec<_> = files
+                .index
+                .files
+                .iter()
+                .filter(|(name, _)| !in_use(&files.dir.join(name)))
+                .map(|(name, entry)| (entry.last_used, name.clone(), entry.size))
+                .collect();
+            lru.sort();
+            let mut lru = lru.into_iter();
+
+            while files
+                .size()
+                .saturating_add(files.writing)
+                .saturating_add(size)
+                > files.quota
+            {
+                let Some((_, name, size)) = lru.next() else {
+                    log::warn!(
+                        "the layer files in use take {} bytes and {} bytes are being written, \
+                         more than the quota of {} bytes",
+                        files.size(),
+                        files.writing,
+                        files.quota
+                    );
+                    break;
+                };
+
+                let digest = name.replacen('-', ":", 1);
+                match remove_unmapped(files.dir.join(&name)) {
+                    Ok(true) => {
+                        log::info!("evicting layer {digest} ({size} bytes) from the layer files");
+                        files.index.files.remove(&name);
+                    }
+                    // the instances of another shim use it
+                    Ok(false) => {}
+                    Err(err) => log::warn!("failed to remove the file of layer {digest}: {err}"),
+                }
+            }
+        });
+    }
+
+    // The name of the layer file at `path` in the index, if it's one of the layer files
+    fn name(&self, path: &Path) -> Option<String> {
+        if path.parent() != Some(self.dir.as_path()) {
+            return None;
+        }
+        Some(path.file_name()?.to_string_lossy().into_owned())
+    }
+
+    // Saves the index, atomically so that a crash never leaves it half written. Failing to do
+    // so only costs rebuilding it.
+    fn save(&self) {
+        let res = (|| {
+            std::fs::create_dir_all(&self.dir)?;
+            let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+            std::fs::write(&tmp, serde_json::to_vec(&self.index)?)?;
+            std::fs::rename(&tmp, self.dir.join(INDEX_FILE))
+        })();
+        if let Err(err) = res {
+            log::warn!(
+                "failed to save the index of the layer files in {:?}: {err}",
+                self.dir
+            );
+        }
+    }
+}
+
+// Whether the shim writing the partial file `name`, named `<digest>.<pid>-<n>.<extension>` after
+// the pid of the shim, is still running
+fn writer_is_running(name: &str) -> bool {
+    let pid = name
+        .split('.')
+        .nth(1)
+        .and_then(|writer| writer.split('-').next())
+        .and_then(|pid| pid.parse::<i32>().ok());
+    match pid {
+        Some(pid) if pid > 0 => !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH)),
+        _ => false,
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_files_evict_least_recently_used() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = LayerFiles::load(dir.path()).with_quota(10);
+        for name in ["sha256-1", "sha256-2", "sha256-3"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 4])?;
+            files.touch(&path);
+        }
+        assert_eq!(files.size(), 12);
+
+        // sha256-1 is the least recently used, but it's in use
+        let in_use = |path: &Path| path.ends_with("sha256-1");
+        files.evict(0, in_use);
+        assert!(dir.path().join("sha256-1").exists());
+        assert!(!dir.path().join("sha256-2").exists());
+        assert_eq!(files.size(), 8);
+
+        // there is room for a new file of 4 bytes once sha256-3 is evicted
+        files.evict(4, in_use);
+        assert!(!dir.path().join("sha256-3").exists());
+        assert_eq!(files.size(), 4);
+
+        // the files in use are never evicted, even over the quota
+        files.evict(10, in_use);
+        assert_eq!(files.size(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_files_index_survives_restarts() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = LayerFiles::load(dir.path());
+        for name in ["sha256-1", "sha256-2"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 4])?;
+            files.touch(&path);
+        }
+        files.touch(&dir.path().join("sha256-1"));
+        let index = files.index.files.clone();
+        drop(files);
+
+        // a partial write of the previous shim
+        std::fs::write(dir.path().join("sha256-3.0.tmp"), [0; 4])?;
+
+        let mut files = LayerFiles::load(dir.path()).with_quota(4);
+        assert_eq!(files.index.files, index);
+        assert!(!dir.path().join("sha256-3.0.tmp").exists());
+
+        // sha256-2 is still the least recently used
+        files.evict(0, |_| false);
+        assert!(dir.path().join("sha256-1").exists());
+        assert!(!dir.path().join("sha256-2").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_files_are_shared_by_shims() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut shim1 = LayerFiles::load(dir.path());
+        let mut shim2 = LayerFiles::load(dir.path());
+        for (files, name) in [(&mut shim1, "sha256-1"), (&mut shim2, "sha256-2")] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 4])?;
+            files.touch(&path);
+        }
+
+        // each shim sees the files of the other
+        shim1.touch(&dir.path().join("sha256-1"));
+        assert_eq!(shim1.size(), 8);
+        assert_eq!(LayerFiles::load(dir.path()).index.files, shim1.index.files);
+
+        // the files the other shim maps aren't evicted
+        let mapped = crate::sys::mmap::Mmap::open(dir.path().join("sha256-2"))?;
+        let mut shim1 = shim1.with_quota(0);
+        shim1.evict(0, |_| false);
+        assert!(!dir.path().join("sha256-1").exists());
+        assert!(dir.path().join("sha256-2").exists());
+        drop(mapped);
+        shim1.evict(0, |_| false);
+        assert!(!dir.path().join("sha256-2").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_files_count_the_partial_writes_of_running_shims() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let running = format!("sha256-1.{}-0.tmp", std::process::id());
+        std::fs::write(dir.path().join(&running), [0; 4])?;
+        std::fs::write(dir.path().join("sha256-2.0-0.tmp"), [0; 4])?;
+
+        let mut files = LayerFiles::load(dir.path()).with_quota(8);
+        assert!(dir.path().join(&running).exists());
+        assert!(!dir.path().join("sha256-2.0-0.tmp").exists());
+        assert_eq!(files.size(), 0);
+
+        // the partial writes count towards the quota
+        std::fs::write(dir.path().join("sha256-3"), [0; 4])?;
+        files.evict(0, |_| false);
+        assert!(dir.path().join("sha256-3").exists());
+        files.evict(1, |_| false);
+        assert!(!dir.path().join("sha256-3").exists());
+        assert!(dir.path().join(running).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_files_corrupted_index_is_rebuilt() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("sha256-1"), [0; 4])?;
+        std::fs::write(dir.path().join("sha256-2"), [0; 8])?;
+        std::fs::write(dir.path().join(INDEX_FILE), "{\"files\":")?;
+
+        let files = LayerFiles::load(dir.path());
+        assert_eq!(files.size(), 12);
+
+        // the rebuilt index is saved
+        let saved: Index = serde_json::from_slice(&std::fs::read(dir.path().join(INDEX_FILE))?)?;
+        assert_eq!(saved.files, files.index.files);
+
+        Ok(())
+    }
+}
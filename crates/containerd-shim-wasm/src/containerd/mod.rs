@@ -1,6 +1,22 @@
 #![cfg(unix)]
 
+mod cache;
 mod client;
+mod compilations;
+mod compose;
+mod compression;
+mod digest;
+mod image_policy;
+mod layer_files;
 mod lease;
+mod retry;
+mod signature;
+mod timeout;
 
-pub(crate) use client::Client;
+pub(crate) use cache::{LAYER_CACHE, LayerCacheSnapshot};
+pub(crate) use client::{Client, LayerPolicy, is_transient};
+pub(crate) use compilations::{COMPILATIONS, CompilationsSnapshot};
+pub(crate) use image_policy::ImagePolicy;
+pub(crate) use retry::Backoff;
+pub(crate) use signature::Policy as SignaturePolicy;
+pub(crate) use timeout::{Timeouts, with_timeout};
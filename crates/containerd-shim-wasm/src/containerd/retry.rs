@@ -0,0 +1,174 @@
+//! Bounded retries with exponential backoff for the calls to containerd.
+//!
+//! A task can be created while containerd restarts, e.g., when it's upgraded. The socket of
+//! containerd then refuses connections (or doesn't exist) for a moment, which shouldn't fail
+//! the task. Errors that won't go away by waiting, like an invalid socket path, are not retried.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::hash::{BuildHasher as _, Hasher as _};
+use std::time::{Duration, Instant};
+
+use crate::shim::ContainerdConfig;
+
+const DEFAULT_RETRY_ATTEMPTS: u32 = 5;
+const DEFAULT_RETRY_DEADLINE: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Backoff {
+    attempts: u32,
+    deadline: Duration,
+    initial: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            deadline: DEFAULT_RETRY_DEADLINE,
+            initial: INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the backoff of the `retry_attempts` and `retry_deadline` of the `config`, with
+    /// the defaults for the ones it doesn't set.
+    pub(crate) fn new(config: &ContainerdConfig) -> Self {
+        let mut backoff = Self::default();
+        if let Some(attempts) = config.retry_attempts {
+            backoff.attempts = attempts;
+        }
+        if let Some(deadline) = config.retry_deadline {
+            backoff.deadline = Duration::from_secs(deadline);
+        }
+        backoff
+    }
+
+    /// Calls `op` until it succeeds, it fails with an error that `is_transient` rejects,
+    /// or the attempts or the deadline run out. Returns the last error in that case.
+    pub(crate) async fn retry<T, E: Display, Fut: Future<Output = Result<T, E>>>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Fut,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let err = match op().await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+
+            let delay = self.delay(attempt);
+            if !is_transient(&err)
+                || attempt >= self.attempts
+                || start.elapsed() + delay > self.deadline
+            {
+                return Err(err);
+            }
+
+            log::warn!(
+                "failed to {what} (attempt {attempt}/{}), retrying in {delay:?}: {err}",
+                self.attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    // exponential backoff with up to 50% of jitter, so that the instances
+    // waiting for containerd don't all retry at the same time
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_BACKOFF);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        backoff.mul_f64(1.0 - jitter as f64 / 2000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn backoff(attempts: u32) -> Backoff {
+        Backoff {
+            attempts,
+            deadline: Duration::from_secs(10),
+            initial: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let calls = &AtomicU32::new(0);
+        let res = backoff(5)
+            .retry(
+                "test",
+                || async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 | 1 => Err("unavailable"),
+                        _ => Ok(42),
+                    }
+                },
+                |_| true,
+            )
+            .await;
+        assert_eq!(res, Ok(42));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let calls = &AtomicU32::new(0);
+        let res: Result<(), _> = backoff(3)
+            .retry(
+                "test",
+                || async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("unavailable")
+                },
+                |_| true,
+            )
+            .await;
+        assert_eq!(res, Err("unavailable"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // permanent errors are not retried
+        calls.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = backoff(3)
+            .retry(
+                "test",
+                || async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("invalid")
+                },
+                |_| false,
+            )
+            .await;
+        assert_eq!(res, Err("invalid"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff::default();
+        for attempt in 1..10 {
+            let max = INITIAL_BACKOFF
+                .saturating_mul(2u32.pow(attempt - 1))
+                .min(MAX_BACKOFF);
+            let delay = backoff.delay(attempt);
+            assert!(
+                delay <= max && delay >= max / 2,
+                "{delay:?} for attempt {attempt}"
+            );
+        }
+    }
+}
@@ -0,0 +1,700 @@
+//! Verification of the cosign signatures of the images before their wasm layers are loaded.
+//!
+//! The signatures are looked up in the content store, so they must be pulled with the image:
+//! either as the `<repository>:sha256-<digest>.sig` image cosign attaches to the image, or as the
+//! manifests with the image as their OCI `subject`. A signature is valid if it signs the digest
+//! of the image, or of its platform manifest, and if it's made with one of the public keys of the
+//! policy, or with a Fulcio certificate of one of its identities, which Rekor logged while the
+//! certificate was valid.
+//!
+//! The policy is a JSON file at the path of `policy.signature_policy`, e.g.:
+//!
+//! ```json
+//! {
+//!   "mode": "enforce",
+//!   "publicKeys": ["/etc/runwasi/cosign.pub"],
+//!   "identities": [
+//!     {
+//!       "issuer": "https://token.actions.githubusercontent.com",
+//!       "subject": "https://github.com/org/app/.github/workflows/release.yml@refs/heads/main"
+//!     }
+//!   ],
+//!   "fulcioRoots": ["/etc/runwasi/fulcio.pem"],
+//!   "rekorKeys": ["/etc/runwasi/rekor.pub"]
+//! }
+//! ```
+//!
+//! Images that fail the verification aren't started, unless the mode is `warn`, which only logs
+//! the failures, e.g., while signing the images is rolled out. This includes the images without
+//! wasm layers, which would run the files of their rootfs, and the images whose signatures
+//! can't be read. In `enforce` mode, no instance falls back to the files of its rootfs when its
+//! layers can't be loaded. Only ECDSA P-256 and P-384 keys,
+//! cosign's defaults, are supported.
+//! The images that passed the verification aren't verified again while the shim runs.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use containerd_shimkit::sandbox::error::Result;
+use p256::pkcs8::DecodePublicKey as _;
+use serde::{Deserialize, Serialize};
+use x509_cert::Certificate;
+use x509_cert::der::asn1::{ObjectIdentifier, Utf8StringRef};
+use x509_cert::der::{Decode as _, DecodePem as _, Encode as _};
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::{BasicConstraints, SubjectAltName};
+
+use crate::shim::ShimConfig;
+
+/// Media type of the layers of the signatures, whose content is the signed payload.
+pub(crate) const SIMPLE_SIGNING_MEDIA_TYPE: &str =
+    "application/vnd.dev.cosign.simplesigning.v1+json";
+
+// Annotations of the layers of the signatures
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const CERTIFICATE_ANNOTATION: &str = "dev.sigstore.cosign/certificate";
+const CHAIN_ANNOTATION: &str = "dev.sigstore.cosign/chain";
+const BUNDLE_ANNOTATION: &str = "dev.sigstore.cosign/bundle";
+
+// Extensions of the Fulcio certificates with the OIDC issuer of their identity, as a DER
+// UTF8String, or as raw bytes for the certificates of older Fulcio versions
+const ISSUER_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.8");
+const LEGACY_ISSUER_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.1");
+
+// The digests of the images that passed the verification
+static VERIFIED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// What happens to the images that fail the verification.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mode {
+    /// The images aren't started.
+    #[default]
+    Enforce,
+    /// The images are started, and the failure is logged.
+    Warn,
+}
+
+/// An identity of the Fulcio certificates of the signatures.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Identity {
+    /// The OIDC issuer of the identity.
+    issuer: String,
+    /// The email address or URI of the identity, e.g., of a CI workflow.
+    subject: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    public_keys: Vec<PathBuf>,
+    #[serde(default)]
+    identities: Vec<Identity>,
+    #[serde(default)]
+    fulcio_roots: Vec<PathBuf>,
+    #[serde(default)]
+    rekor_keys: Vec<PathBuf>,
+}
+
+/// The signature policy of the shim.
+#[derive(Debug)]
+pub(crate) struct Policy {
+    mode: Mode,
+    // the public keys, with the paths they were read from
+    keys: Vec<(PathBuf, PublicKey)>,
+    identities: Vec<Identity>,
+    fulcio_roots: Vec<Certificate>,
+    rekor_keys: Vec<PublicKey>,
+}
+
+impl Policy {
+    /// Returns the policy of the `policy.signature_policy` of the shim, if it's set, or why
+    /// it's invalid. The signatures of the images aren't verified without it.
+    pub(crate) fn from_config() -> Result<Option<&'static Self>, String> {
+        static POLICY: LazyLock<Result<Option<Policy>, String>> = LazyLock::new(|| {
+            let Some(path) = &ShimConfig::get().policy.signature_policy else {
+                return Ok(None);
+            };
+            let policy = Policy::load(Path::new(path))
+                .map_err(|err| format!("invalid signature policy {path:?}: {err}"))?;
+            log::info!("verifying the signatures of the images with the policy {path:?}");
+            Ok(Some(policy))
+        });
+        POLICY.as_ref().map(Option::as_ref).map_err(Clone::clone)
+    }
+
+    /// Returns whether the images that fail the verification with the policy of
+    /// the shim don't run. An invalid policy doesn't run any image.
+    pub(crate) fn is_enforced() -> bool {
+        match Self::from_config() {
+            Ok(policy) => policy.is_some_and(|policy| policy.mode() == Mode::Enforce),
+            Err(_) => true,
+        }
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let file = std::fs::read(path).map_err(|err| err.to_string())?;
+        let file: PolicyFile = serde_json::from_slice(&file).map_err(|err| err.to_string())?;
+        if file.public_keys.is_empty() && file.identities.is_empty() {
+            return Err("the policy has neither public keys nor identities".into());
+        }
+        if !file.identities.is_empty()
+            && (file.fulcio_roots.is_empty() || file.rekor_keys.is_empty())
+        {
+            return Err("the identities require Fulcio roots and Rekor keys".into());
+        }
+
+        let read = |path: &Path| {
+            std::fs::read_to_string(path).map_err(|err| format!("failed to read {path:?}: {err}"))
+        };
+        let read_key = |path: &PathBuf| {
+            PublicKey::from_pem(&read(path)?).map_err(|err| format!("invalid key {path:?}: {err}"))
+        };
+        let keys = file
+            .public_keys
+            .iter()
+            .map(|path| read_key(path).map(|key| (path.clone(), key)))
+            .collect::<Result<_, String>>()?;
+        let rekor_keys = file
+            .rekor_keys
+            .iter()
+            .map(&read_key)
+            .collect::<Result<_, String>>()?;
+        let mut fulcio_roots = vec![];
+        for path in &file.fulcio_roots {
+            let roots = Certificate::load_pem_chain(read(path)?.as_bytes())
+                .map_err(|err| format!("invalid certificates {path:?}: {err}"))?;
+            fulcio_roots.extend(roots);
+        }
+
+        Ok(Self {
+            mode: file.mode,
+            keys,
+            identities: file.identities,
+            fulcio_roots,
+            rekor_keys,
+        })
+    }
+
+    pub(crate) fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Checks that one of `signatures` signs one of the `digests` of an image, returning who
+    /// made it, or why none of them is valid.
+    pub(crate) fn verify(
+        &self,
+        signatures: &[Signature],
+        digests: &[String],
+    ) -> Result<String, String> {
+        if signatures.is_empty() {
+            return Err("the image has no signatures in the content store".into());
+        }
+        let mut reasons = vec![];
+        for signature in signatures {
+            match self.verify_signature(signature, digests) {
+                Ok(signer) => return Ok(signer),
+                Err(reason) => reasons.push(reason),
+            }
+        }
+        Err(format!("no valid signature: {}", reasons.join("; ")))
+    }
+
+    fn verify_signature(
+        &self,
+        signature: &Signature,
+        digests: &[String],
+    ) -> Result<String, String> {
+        let payload: Payload = serde_json::from_slice(&signature.payload)
+            .map_err(|err| format!("invalid payload: {err}"))?;
+        let signed = &payload.critical.image.docker_manifest_digest;
+        if !digests.contains(signed) {
+            return Err(format!("the signature is for {signed}"));
+        }
+
+        if let Some((path, _)) = self
+            .keys
+            .iter()
+            .find(|(_, key)| key.verify(&signature.payload, &signature.signature))
+        {
+            return Ok(format!("the key {path:?}"));
+        }
+        let Some(certificate) = &signature.certificate else {
+            return Err("the signature isn't made with a key of the policy".into());
+        };
+        if self.identities.is_empty() {
+            return Err("the policy has no identities for the certificate of the signature".into());
+        }
+        self.verify_certificate(signature, certificate)
+    }
+
+    // Checks the Fulcio certificate of `signature` and its identity, returning the identity
+    fn verify_certificate(
+        &self,
+        signature: &Signature,
+        certificate: &str,
+    ) -> Result<String, String> {
+        let leaf = Certificate::from_pem(certificate.as_bytes())
+            .map_err(|err| format!("invalid certificate: {err}"))?;
+        let chain = match &signature.chain {
+            Some(chain) => Certificate::load_pem_chain(chain.as_bytes())
+                .map_err(|err| format!("invalid certificate chain: {err}"))?,
+            None => vec![],
+        };
+        if !self.is_trusted(&leaf, &chain) {
+            return Err("the certificate isn't issued by a Fulcio root of the policy".into());
+        }
+
+        let key = PublicKey::from_spki(&leaf)?;
+        if !key.verify(&signature.payload, &signature.signature) {
+            return Err("the signature doesn't match its certificate".into());
+        }
+
+        let identity = identity(&leaf)?;
+        if !self.identities.contains(&identity) {
+            return Err(format!(
+                "the identity {} of {} isn't in the policy",
+                identity.subject, identity.issuer
+            ));
+        }
+
+        // Fulcio certificates are only valid for a few minutes, so they're checked at the time
+        // Rekor logged the signature
+        let logged = self.verify_bundle(signature)?;
+        let validity = &leaf.tbs_certificate.validity;
+        if logged < validity.not_before.to_unix_duration()
+            || logged > validity.not_after.to_unix_duration()
+        {
+            return Err("the signature was logged while its certificate wasn't valid".into());
+        }
+
+        Ok(format!("{} of {}", identity.subject, identity.issuer))
+    }
+
+    // Whether `certificate` is issued by one of the Fulcio roots, through the CAs of `chain`
+    fn is_trusted(&self, certificate: &Certificate, chain: &[Certificate]) -> bool {
+        let mut certificate = certificate;
+        // every CA of the chain is used once at most
+        for _ in 0..=chain.len() {
+            if self
+                .fulcio_roots
+                .iter()
+                .any(|root| is_issued_by(certificate, root))
+            {
+                return true;
+            }
+            let Some(issuer) = chain
+                .iter()
+                .find(|issuer| is_ca(issuer) && is_issued_by(certificate, issuer))
+            else {
+                return false;
+            };
+            certificate = issuer;
+        }
+        false
+    }
+
+    // Checks the Rekor bundle of `signature`, returning when the signature was logged
+    fn verify_bundle(&self, signature: &Signature) -> Result<Duration, String> {
+        let bundle = signature
+            .bundle
+            .as_ref()
+            .ok_or("the signature has no Rekor bundle")?;
+        let bundle: Bundle =
+            serde_json::from_str(bundle).map_err(|err| format!("invalid Rekor bundle: {err}"))?;
+
+        // Rekor signs the canonical JSON of the payload, whose fields are sorted
+        let payload = serde_json::to_vec(&bundle.payload).map_err(|err| err.to_string())?;
+        let timestamp = BASE64
+            .decode(&bundle.signed_entry_timestamp)
+            .map_err(|err| format!("invalid signed entry timestamp: {err}"))?;
+        if !self
+            .rekor_keys
+            .iter()
+            .any(|key| key.verify(&payload, &timestamp))
+        {
+            return Err("the Rekor bundle isn't signed with a Rekor key of the policy".into());
+        }
+
+        // the logged entry must be the one of this signature
+        let body = BASE64
+            .decode(&bundle.payload.body)
+            .map_err(|err| format!("invalid Rekor entry: {err}"))?;
+        let entry: HashedRekord =
+            serde_json::from_slice(&body).map_err(|err| format!("invalid Rekor entry: {err}"))?;
+        let logged_signature = BASE64
+            .decode(&entry.spec.signature.content)
+            .map_err(|err| format!("invalid Rekor entry: {err}"))?;
+        if entry.kind != "hashedrekord"
+            || entry.spec.data.hash.algorithm != "sha256"
+            || entry.spec.data.hash.value != sha256::digest(signature.payload.as_slice())
+            || logged_signature != signature.signature
+        {
+            return Err("the Rekor entry isn't the one of the signature".into());
+        }
+
+        Ok(Duration::from_secs(bundle.payload.integrated_time))
+    }
+}
+
+/// Returns whether the image with `digest` already passed the verification.
+pub(crate) fn is_verified(digest: &str) -> bool {
+    VERIFIED.lock().unwrap().contains(digest)
+}
+
+/// Records that the image with `digest` passed the verification.
+pub(crate) fn set_verified(digest: &str) {
+    VERIFIED.lock().unwrap().insert(digest.to_string());
+}
+
+/// A signature of an image, from a layer of a signature manifest.
+#[derive(Debug, Clone)]
+pub(crate) struct Signature {
+    // the signed payload, the content of the layer
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+    // the PEM of the Fulcio certificate of the signature, and of the chain of its CAs
+    certificate: Option<String>,
+    chain: Option<String>,
+    // the JSON of the Rekor bundle that logged the signature
+    bundle: Option<String>,
+}
+
+impl Signature {
+    /// Returns the signature of the layer with `annotations` and content `payload`.
+    pub(crate) fn from_layer(
+        annotations: Option<&HashMap<String, String>>,
+        payload: Vec<u8>,
+    ) -> Result<Self, String> {
+        let annotation = |key: &str| annotations.and_then(|a| a.get(key)).cloned();
+        let signature = annotation(SIGNATURE_ANNOTATION).ok_or("the layer has no signature")?;
+        let signature = BASE64
+            .decode(signature)
+            .map_err(|err| format!("invalid signature: {err}"))?;
+        Ok(Self {
+            payload,
+            signature,
+            certificate: annotation(CERTIFICATE_ANNOTATION).filter(|c| !c.is_empty()),
+            chain: annotation(CHAIN_ANNOTATION).filter(|c| !c.is_empty()),
+            bundle: annotation(BUNDLE_ANNOTATION).filter(|b| !b.is_empty()),
+        })
+    }
+}
+
+/// Returns the repository of the image `name`, without its tag or digest.
+pub(crate) fn repository(name: &str) -> &str {
+    let name = name.split_once('@').map_or(name, |(name, _)| name);
+    match name.rfind(':') {
+        // a colon before the last slash is the one of the port of the registry
+        Some(i) if !name[i..].contains('/') => &name[..i],
+        _ => name,
+    }
+}
+
+/// Returns the name of the image cosign attaches the signatures of the image `digest` of
+/// `repository` to.
+pub(crate) fn signature_tag(repository: &str, digest: &str) -> String {
+    format!("{repository}:{}.sig", digest.replace(':', "-"))
+}
+
+// The simple signing payload cosign signs
+#[derive(Deserialize)]
+struct Payload {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: PayloadImage,
+}
+
+#[derive(Deserialize)]
+struct PayloadImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+#[derive(Deserialize)]
+struct Bundle {
+    #[serde(rename = "SignedEntryTimestamp")]
+    signed_entry_timestamp: String,
+    #[serde(rename = "Payload")]
+    payload: BundlePayload,
+}
+
+// The fields are in the order of the canonical JSON Rekor signs
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BundlePayload {
+    body: String,
+    integrated_time: u64,
+    #[serde(rename = "logID")]
+    log_id: String,
+    log_index: u64,
+}
+
+#[derive(Deserialize)]
+struct HashedRekord {
+    kind: String,
+    spec: HashedRekordSpec,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordSpec {
+    data: HashedRekordData,
+    signature: HashedRekordSignature,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordData {
+    hash: HashedRekordHash,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordHash {
+    algorithm: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct HashedRekordSignature {
+    content: String,
+}
+
+/// An ECDSA public key, which verifies DER signatures with the hash of its curve.
+#[derive(Debug)]
+enum PublicKey {
+    P256(p256::ecdsa::VerifyingKey),
+    P384(p384::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    fn from_pem(pem: &str) -> Result<Self, String> {
+        if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_pem(pem) {
+            return Ok(Self::P256(key));
+        }
+        p384::ecdsa::VerifyingKey::from_public_key_pem(pem)
+            .map(Self::P384)
+            .map_err(|_| "not an ECDSA P-256 or P-384 public key".into())
+    }
+
+    // The public key of `certificate`
+    fn from_spki(certificate: &Certificate) -> Result<Self, String> {
+        let der = certificate
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(|err| err.to_string())?;
+        if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_der(&der) {
+            return Ok(Self::P256(key));
+        }
+        p384::ecdsa::VerifyingKey::from_public_key_der(&der)
+            .map(Self::P384)
+            .map_err(|_| "the certificate doesn't have an ECDSA P-256 or P-384 key".into())
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        use p256::ecdsa::signature::Verifier as _;
+
+        match self {
+            Self::P256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            Self::P384(key) => p384::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+        }
+    }
+}
+
+// Whether `certificate` is signed by the key of `issuer`
+fn is_issued_by(certificate: &Certificate, issuer: &Certificate) -> bool {
+    if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return false;
+    }
+    let (Ok(key), Ok(tbs), Some(signature)) = (
+        PublicKey::from_spki(issuer),
+        certificate.tbs_certificate.to_der(),
+        certificate.signature.as_bytes(),
+    ) else {
+        return false;
+    };
+    key.verify(&tbs, signature)
+}
+
+// Whether `certificate` is the one of a CA, which can issue other certificates
+fn is_ca(certificate: &Certificate) -> bool {
+    matches!(
+        certificate.tbs_certificate.get::<BasicConstraints>(),
+        Ok(Some((_, constraints))) if constraints.ca
+    )
+}
+
+// The identity of the Fulcio `certificate`: the issuer of its extension, and its email address
+// or URI alternative name
+fn identity(certificate: &Certificate) -> Result<Identity, String> {
+    let subject = match certificate.tbs_certificate.get::<SubjectAltName>() {
+        Ok(Some((_, names))) => names.0.into_iter().find_map(|name| match name {
+            GeneralName::Rfc822Name(email) => Some(email.to_string()),
+            GeneralName::UniformResourceIdentifier(uri) => Some(uri.to_string()),
+            _ => None,
+        }),
+        _ => None,
+    };
+    let subject = subject.ok_or("the certificate has no email address or URI")?;
+
+    let extensions = certificate.tbs_certificate.extensions.iter().flatten();
+    let issuer = extensions
+        .filter_map(|extension| {
+            let value = extension.extn_value.as_bytes();
+            if extension.extn_id == ISSUER_OID {
+                let issuer = Utf8StringRef::from_der(value).ok()?;
+                Some(issuer.as_str().to_string())
+            } else if extension.extn_id == LEGACY_ISSUER_OID {
+                std::str::from_utf8(value).ok().map(str::to_string)
+            } else {
+                None
+            }
+        })
+        .next();
+    let issuer = issuer.ok_or("the certificate has no OIDC issuer")?;
+
+    Ok(Identity { issuer, subject })
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::SigningKey;
+    use p256::ecdsa::signature::Signer as _;
+    use p256::pkcs8::{EncodePublicKey as _, LineEnding};
+
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn payload(digest: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "critical": {
+                "identity": { "docker-reference": "example.com/app" },
+                "image": { "docker-manifest-digest": digest },
+                "type": "cosign container image signature"
+            },
+            "optional": null
+        }))
+        .unwrap()
+    }
+
+    fn sign(key: &SigningKey, payload: Vec<u8>) -> Signature {
+        let signature: p256::ecdsa::Signature = key.sign(&payload);
+        let annotations = HashMap::from([(
+            SIGNATURE_ANNOTATION.to_string(),
+            BASE64.encode(signature.to_der()),
+        )]);
+        Signature::from_layer(Some(&annotations), payload).unwrap()
+    }
+
+    // Writes a policy with the public key of `key` and `mode`
+    fn write_policy(dir: &Path, key: &SigningKey, mode: &str) -> PathBuf {
+        let key_path = dir.join("cosign.pub");
+        let pem = key
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        std::fs::write(&key_path, pem).unwrap();
+        let policy = serde_json::json!({ "mode": mode, "publicKeys": [key_path] });
+        let path = dir.join("policy.json");
+        std::fs::write(&path, policy.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_verify_with_keys() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key = signing_key(1);
+        let policy = Policy::load(&write_policy(dir.path(), &key, "warn")).unwrap();
+        assert_eq!(policy.mode(), Mode::Warn);
+        let digests = [DIGEST.to_string()];
+
+        let signed = sign(&key, payload(DIGEST));
+        assert!(
+            policy
+                .verify(std::slice::from_ref(&signed), &digests)
+                .is_ok()
+        );
+
+        // signatures of other images, or with other keys, aren't valid
+        let other_image = sign(&key, payload("sha256:00"));
+        let other_key = sign(&signing_key(2), payload(DIGEST));
+        let err = policy
+            .verify(&[other_image, other_key], &digests)
+            .unwrap_err();
+        assert!(err.contains("the signature is for sha256:00"), "{err}");
+        assert!(err.contains("isn't made with a key of the policy"), "{err}");
+
+        // the payload is what's signed
+        let mut tampered = signed;
+        tampered.payload.push(b' ');
+        assert!(policy.verify(&[tampered], &digests).is_err());
+
+        assert!(policy.verify(&[], &digests).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_policies() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let write = |policy: serde_json::Value| {
+            let path = dir.path().join("policy.json");
+            std::fs::write(&path, policy.to_string()).unwrap();
+            Policy::load(&path).map(|_| ())
+        };
+
+        let path = write_policy(dir.path(), &signing_key(1), "enforce");
+        assert!(Policy::load(&path).is_ok());
+
+        assert!(write(serde_json::json!({})).is_err());
+        let key = dir.path().join("cosign.pub");
+        assert!(write(serde_json::json!({ "mode": "audit", "publicKeys": [key] })).is_err());
+        assert!(
+            write(serde_json::json!({ "publicKeys": [dir.path().join("missing.pub")] })).is_err()
+        );
+        // the identities can't be verified without the Fulcio roots and Rekor keys
+        let identity =
+            serde_json::json!({ "issuer": "https://issuer", "subject": "me@example.com" });
+        assert!(write(serde_json::json!({ "identities": [identity] })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature_tag() {
+        assert_eq!(repository("example.com/app:v1"), "example.com/app");
+        assert_eq!(repository("example.com:5000/app"), "example.com:5000/app");
+        assert_eq!(
+            repository(&format!("example.com:5000/app:v1@{DIGEST}")),
+            "example.com:5000/app"
+        );
+        assert_eq!(
+            signature_tag("example.com/app", DIGEST),
+            format!("example.com/app:{}.sig", DIGEST.replace(':', "-"))
+        );
+    }
+
+    #[test]
+    fn test_signature_from_layer() {
+        assert!(Signature::from_layer(None, vec![]).is_err());
+        let annotations = HashMap::from([(SIGNATURE_ANNOTATION.to_string(), "!".to_string())]);
+        assert!(Signature::from_layer(Some(&annotations), vec![]).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Timeouts of the calls to containerd.
+//!
+//! A hung containerd, e.g., under heavy snapshotter load, would otherwise keep the creation of
+//! a task waiting until kubelet gives up, and leave a stuck task behind. Calls that don't complete
+//! in time fail with [`Error::Timeout`](ShimError::Timeout) instead, naming the call.
+
+use std::time::Duration;
+
+use containerd_shimkit::sandbox::error::{Error as ShimError, Result};
+
+use crate::shim::ContainerdConfig;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+// precompiling large modules can take a while
+const DEFAULT_LOAD_MODULES_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Duration,
+    pub(crate) rpc: Duration,
+    pub(crate) load_modules: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            rpc: DEFAULT_RPC_TIMEOUT,
+            load_modules: DEFAULT_LOAD_MODULES_TIMEOUT,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Returns the timeouts of the `config`, with the defaults for the ones it doesn't set.
+    pub(crate) fn new(config: &ContainerdConfig) -> Self {
+        let mut timeouts = Self::default();
+        for (secs, timeout) in [
+            (config.connect_timeout, &mut timeouts.connect),
+            (config.rpc_timeout, &mut timeouts.rpc),
+            (config.load_modules_timeout, &mut timeouts.load_modules),
+        ] {
+            if let Some(secs) = secs {
+                *timeout = Duration::from_secs(secs);
+            }
+        }
+        timeouts
+    }
+}
+
+/// Returns the output of `fut`, or fails with [`Error::Timeout`](ShimError::Timeout)
+/// if it doesn't complete within `timeout`.
+pub(crate) async fn with_timeout<F: Future>(
+    what: &str,
+    timeout: Duration,
+    fut: F,
+) -> Result<F::Output> {
+    tokio::time::timeout(timeout, fut)
+        .await
+        .map_err(|_| ShimError::Timeout {
+            what: what.to_string(),
+            timeout,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let res = with_timeout("Content.Info", Duration::from_secs(1), async { 42 }).await;
+        assert_eq!(res.unwrap(), 42);
+
+        let res: Result<()> = with_timeout(
+            "Content.Info",
+            Duration::from_millis(10),
+            std::future::pending(),
+        )
+        .await;
+        let err = res.unwrap_err();
+        assert!(matches!(err, ShimError::Timeout { .. }));
+        assert_eq!(err.to_string(), "Content.Info timed out after 10ms");
+    }
+}
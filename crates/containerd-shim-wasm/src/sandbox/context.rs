@@ -1,13 +1,21 @@
 use std::borrow::Cow;
+use std::io::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, bail};
-use oci_spec::image::Descriptor;
+use oci_spec::image::{Config, Descriptor};
 use oci_spec::runtime::Spec;
 use serde::{Deserialize, Serialize};
 use wasmparser::Parser;
 
+use crate::sandbox::dns::Resolver;
 use crate::sandbox::path::PathResolve;
+use crate::sandbox::wasi_config::WasiConfig;
+use crate::shim::ShimConfig;
 
 /// The `RuntimeContext` trait provides access to the runtime context that includes
 /// the arguments, environment variables, and entrypoint for the container.
@@ -17,6 +25,7 @@ pub trait RuntimeContext: Send + Sync {
     fn args(&self) -> &[String];
 
     /// Returns environment variables in the format `ENV_VAR_NAME=VALUE` from the runtime spec process field.
+    /// There are none when the guest is denied [`Capability::Env`].
     fn envs(&self) -> &[String];
 
     /// Returns a `Entrypoint` with the following fields obtained from the first argument in the OCI spec for entrypoint:
@@ -32,6 +41,683 @@ pub trait RuntimeContext: Send + Sync {
     ///   "my_module.wat" -> { source: File("my_module.wat"), func: "_start", name: "Some(my_module)", arg0: "my_module.wat" }
     ///   "#init" -> { source: File(""), func: "init", name: None, arg0: "#init" }
     fn entrypoint(&self) -> Entrypoint;
+
+    /// Returns the directories mounted in the container, e.g., Kubernetes volumes, that should be
+    /// preopened for the guest in addition to the root directory.
+    fn preopens(&self) -> &[Preopen] {
+        &[]
+    }
+
+    /// Returns the working directory of the process, an absolute path in the root directory.
+    /// WASI has no working directory of its own, so engines that can resolve the relative paths
+    /// of the guest against it should do so. Guests can also read it from the `PWD` env var.
+    fn cwd(&self) -> &Path {
+        Path::new("/")
+    }
+
+    /// Returns the value of the annotation `key` of the runtime spec, e.g., to let engines read
+    /// their own `io.runwasi.*` settings of the container.
+    fn annotation(&self, _key: &str) -> Option<&str> {
+        None
+    }
+
+    /// Returns the annotations of the runtime spec whose keys start with `prefix`, with the rest
+    /// of their keys, sorted, e.g., for the settings of engines that take a name in their key.
+    fn annotations_with_prefix(&self, _prefix: &str) -> Vec<(&str, &str)> {
+        vec![]
+    }
+
+    /// Returns the maximum number of bytes of memory the guest may use, or `None` if the container
+    /// has no memory limit. It's below the memory limit of the container by the headroom of the
+    /// [`MEMORY_HEADROOM_ANNOTATION`], so that engines can fail the growth of the memory of the
+    /// guest before the container is OOM-killed.
+    fn memory_limit(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the limits of the stack of the guest set by the annotations of the container.
+    /// They are checked against [`Shim::supported_stack_limits`] when the container is created.
+    ///
+    /// [`Shim::supported_stack_limits`]: crate::shim::Shim::supported_stack_limits
+    fn stack_limits(&self) -> StackLimits {
+        StackLimits::default()
+    }
+
+    /// Returns where to write a coredump of the guest when it traps, if the container asks for
+    /// one with the [`COREDUMP_ANNOTATION`].
+    fn coredump(&self) -> Option<Coredump> {
+        None
+    }
+
+    /// Returns how to run the guest deterministically, if the container asks for it with the
+    /// [`DETERMINISTIC_ANNOTATION`]. Engines that support it declare it with
+    /// [`Shim::supports_deterministic`], and the creation of the container fails otherwise.
+    ///
+    /// [`Shim::supports_deterministic`]: crate::shim::Shim::supports_deterministic
+    fn deterministic(&self) -> Option<Deterministic> {
+        None
+    }
+
+    /// Returns the host capabilities granted to the guest with the [`CAPABILITIES_ANNOTATION`].
+    /// The shim already leaves out the env vars and makes the preopens read-only when they aren't
+    /// granted, and engines configure the rest of the WASI context, e.g., the sockets and the root
+    /// directory, from them.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Returns whether the root directory is read-only for the guest: when it isn't granted
+    /// [`Capability::FsWrite`], or when the rootfs is read-only in the runtime spec. Engines
+    /// preopen the root, and the other directories of the rootfs, e.g., the cwd, read-only then.
+    fn root_read_only(&self) -> bool {
+        !self.capabilities().contains(Capability::FsWrite)
+    }
+
+    /// Returns the destinations the guest may connect its sockets to with the
+    /// [`NET_ALLOW_ANNOTATION`], or `None` if it may connect to any. Engines check the
+    /// connections of a guest granted [`Capability::NetOutbound`] against it.
+    fn net_allowlist(&self) -> Option<NetAllowlist> {
+        None
+    }
+
+    /// Returns the TCP ports of the [`LISTEN_ANNOTATION`] the shim bound for the guest, which
+    /// engines take from it, see [`Listeners`].
+    fn listeners(&self) -> Arc<Listeners> {
+        Arc::default()
+    }
+
+    /// Returns the resolver of the `resolv.conf` and `hosts` of the pod mounted in the
+    /// container, which engines resolve the host names of the guest with, or `None` to keep
+    /// the resolver of the host.
+    fn resolver(&self) -> Option<Arc<Resolver>> {
+        None
+    }
+
+    /// Returns the configuration of the guest, which engines serve to the components importing
+    /// `wasi:config/store`, see [`WasiConfig`].
+    fn wasi_config(&self) -> Arc<WasiConfig> {
+        Arc::default()
+    }
+
+    /// Returns whether the entrypoint is a file of the rootfs the engine precompiled ahead of
+    /// time, from its extension, e.g., `app.cwasm`, rather than a wasm module or component.
+    /// Engines list the extensions of their precompiled files in
+    /// [`Shim::precompiled_extensions`], and deserialize the file without validating it.
+    ///
+    /// [`Shim::precompiled_extensions`]: crate::shim::Shim::precompiled_extensions
+    fn is_precompiled_file(&self) -> bool {
+        false
+    }
+
+    /// Reports to the shim that the guest is instantiated and that its entrypoint is called,
+    /// which ends the start deadline of the container. Engines that call it declare it with
+    /// [`Shim::reports_guest_started`], and the guest counts as started when it's run otherwise.
+    ///
+    /// [`Shim::reports_guest_started`]: crate::shim::Shim::reports_guest_started
+    fn guest_started(&self) {}
+}
+
+/// Whether the shim allows the containers to run the precompiled files of their rootfs, see
+/// [`RuntimeContext::is_precompiled_file`], with its `layers.allow_precompiled_files`. They are
+/// native code that skips the validation of the wasm, so they must come from trusted images only.
+pub(crate) fn precompiled_files_allowed() -> bool {
+    ShimConfig::get()
+        .layers
+        .allow_precompiled_files
+        .unwrap_or(false)
+}
+
+/// Annotation with the percentage of the memory limit of the container that is left to the engine
+/// rather than to the guest, e.g., for the compiled code and the WASI state. Defaults to 10%.
+pub const MEMORY_HEADROOM_ANNOTATION: &str = "io.runwasi.memory-headroom";
+
+const DEFAULT_MEMORY_HEADROOM: u64 = 10;
+
+/// Annotation with the maximum size in bytes of the stack of the guest, e.g., `1048576`.
+pub const MAX_STACK_SIZE_ANNOTATION: &str = "io.runwasi.max-stack-size";
+
+/// Annotation with the maximum depth of the calls of the guest, e.g., `10000`.
+pub const MAX_CALL_DEPTH_ANNOTATION: &str = "io.runwasi.max-call-depth";
+
+/// Annotation with the path to write a coredump of the guest to when it traps, in the wasm
+/// coredump format, e.g., `/var/dumps/core.%id%.%time%.wasm`. The path is in the filesystem of the
+/// container, e.g., in a mounted volume. `%id%` is replaced by the id of the container, and
+/// `%time%` by the unix time of the trap in seconds.
+pub const COREDUMP_ANNOTATION: &str = "io.runwasi.coredump";
+
+/// Annotation with the maximum size in bytes of the linear memories of a guest to write a
+/// coredump of, e.g., `268435456`. Defaults to 64 MiB, so that the coredumps of large guests
+/// don't fill the disk of the node.
+pub const COREDUMP_MAX_SIZE_ANNOTATION: &str = "io.runwasi.coredump-max-size";
+
+const DEFAULT_COREDUMP_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Annotation to run the guest deterministically, e.g., `seed:42` or `seed:42,tick:10ms`, so that
+/// runs of the same guest with the same input produce the same output. The random numbers of the
+/// guest are generated from the seed, and its clocks are virtual: they start at the unix epoch and
+/// advance by the tick, 1ms by default, every time the guest reads them, instead of following
+/// the time of the host.
+pub const DETERMINISTIC_ANNOTATION: &str = "io.runwasi.deterministic";
+
+/// How to run the guest deterministically, see [`RuntimeContext::deterministic`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deterministic {
+    /// The seed of the random numbers of the guest.
+    pub seed: u64,
+    /// How much the clocks of the guest advance every time it reads them.
+    pub tick: Duration,
+}
+
+impl Deterministic {
+    /// The tick of the clocks when the annotation doesn't set one.
+    pub const DEFAULT_TICK: Duration = Duration::from_millis(1);
+
+    /// Returns the mode set by the [`DETERMINISTIC_ANNOTATION`] of `spec`, if any.
+    pub fn from_spec(spec: &Spec) -> anyhow::Result<Option<Self>> {
+        let Some(value) = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(DETERMINISTIC_ANNOTATION))
+        else {
+            return Ok(None);
+        };
+
+        let invalid =
+            || anyhow::anyhow!("invalid {DETERMINISTIC_ANNOTATION} annotation: {value:?}");
+        let mut seed = None;
+        let mut tick = Self::DEFAULT_TICK;
+        for option in value.split(',') {
+            match option.trim().split_once(':') {
+                Some(("seed", n)) => seed = Some(n.parse().map_err(|_| invalid())?),
+                Some(("tick", t)) => tick = parse_tick(t).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            }
+        }
+
+        match seed {
+            Some(seed) => Ok(Some(Self { seed, tick })),
+            None => {
+                bail!("invalid {DETERMINISTIC_ANNOTATION} annotation, it has no seed: {value:?}")
+            }
+        }
+    }
+}
+
+// Parses a tick like `10ms`, which must not be zero, so that the clocks of the guest advance
+fn parse_tick(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    let tick = match unit {
+        "ns" => Duration::from_nanos(amount),
+        "us" => Duration::from_micros(amount),
+        "ms" => Duration::from_millis(amount),
+        "s" => Duration::from_secs(amount),
+        _ => return None,
+    };
+    (!tick.is_zero()).then_some(tick)
+}
+
+/// Annotation to only validate the guest when set to `true`, e.g., to check an image before
+/// rolling it out: the engine loads the entrypoint, resolves its imports and the function to call,
+/// then the container exits with `0` if the guest could run or `1` otherwise, without running any
+/// of its code. The [`Validation`] is written as JSON on the stdout of the container. Engines
+/// that can validate declare it with [`Shim::supports_validation`].
+///
+/// [`Validation`]: crate::sandbox::Validation
+/// [`Shim::supports_validation`]: crate::shim::Shim::supports_validation
+pub const VALIDATE_ONLY_ANNOTATION: &str = "io.runwasi.validate-only";
+
+/// Returns whether the [`VALIDATE_ONLY_ANNOTATION`] of `spec` only validates the guest.
+pub fn validate_only(spec: &Spec) -> anyhow::Result<bool> {
+    let value = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(VALIDATE_ONLY_ANNOTATION));
+    match value.map(String::as_str) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(value) => bail!("invalid {VALIDATE_ONLY_ANNOTATION} annotation: {value:?}"),
+    }
+}
+
+/// Where to write a coredump of the guest, see [`RuntimeContext::coredump`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coredump {
+    path: String,
+    /// The maximum size in bytes of the linear memories of the guest to dump.
+    /// Engines skip the coredumps of larger guests.
+    pub max_size: u64,
+}
+
+impl Coredump {
+    /// Writes coredumps to `path`, where `%time%` is replaced by the time of the trap, as long as
+    /// the linear memories of the guest take at most `max_size` bytes.
+    pub fn new(path: impl Into<String>, max_size: u64) -> Self {
+        Self {
+            path: path.into(),
+            max_size,
+        }
+    }
+
+    /// Returns the path to write the coredump of a trap happening now to.
+    pub fn path(&self) -> PathBuf {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        PathBuf::from(self.path.replace("%time%", &time.to_string()))
+    }
+}
+
+/// The limits of the stack of the guest. Unset limits default to the ones of the engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StackLimits {
+    /// The maximum size in bytes of the stack, from the [`MAX_STACK_SIZE_ANNOTATION`].
+    pub max_stack_size: Option<usize>,
+    /// The maximum depth of the calls, from the [`MAX_CALL_DEPTH_ANNOTATION`].
+    pub max_call_depth: Option<u32>,
+}
+
+impl StackLimits {
+    /// Returns the limits set by the annotations of `spec`.
+    pub fn from_spec(spec: &Spec) -> anyhow::Result<Self> {
+        fn annotation<T: std::str::FromStr>(spec: &Spec, key: &str) -> anyhow::Result<Option<T>> {
+            let Some(value) = spec.annotations().as_ref().and_then(|a| a.get(key)) else {
+                return Ok(None);
+            };
+            match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => bail!("invalid {key} annotation: {value:?}"),
+            }
+        }
+
+        Ok(Self {
+            max_stack_size: annotation(spec, MAX_STACK_SIZE_ANNOTATION)?,
+            max_call_depth: annotation(spec, MAX_CALL_DEPTH_ANNOTATION)?,
+        })
+    }
+}
+
+/// Annotation with the comma-separated host capabilities granted to the guest, e.g.,
+/// `net-outbound,fs-write`, and the ones denied to it with a leading `-`, e.g., `-env`.
+/// The guest is granted [`Capability::Env`] unless it's denied, and none of the others without
+/// the annotation: it can't use sockets, and the root and the preopened directories are
+/// read-only.
+pub const CAPABILITIES_ANNOTATION: &str = "io.runwasi.allow";
+
+/// A host capability the guest can be granted with the [`CAPABILITIES_ANNOTATION`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// `net-outbound`: connecting sockets to other hosts, and resolving their names.
+    NetOutbound,
+    /// `net-inbound`: binding sockets to accept connections or datagrams from other hosts.
+    NetInbound,
+    /// `fs-write`: writing to the root directory, unless the rootfs is read-only, and to the
+    /// preopened directories that aren't mounted read-only.
+    FsWrite,
+    /// `env`: reading the env vars of the process, granted unless it's denied with `-env`.
+    Env,
+}
+
+impl Capability {
+    /// All the capabilities.
+    pub const ALL: [Self; 4] = [
+        Self::NetOutbound,
+        Self::NetInbound,
+        Self::FsWrite,
+        Self::Env,
+    ];
+
+    /// Returns the name of the capability in the [`CAPABILITIES_ANNOTATION`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NetOutbound => "net-outbound",
+            Self::NetInbound => "net-inbound",
+            Self::FsWrite => "fs-write",
+            Self::Env => "env",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A set of [`Capability`]s. The default set has the ones the guest is granted without the
+/// [`CAPABILITIES_ANNOTATION`], i.e., [`Capability::Env`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// Returns the capabilities granted by the [`CAPABILITIES_ANNOTATION`] of `spec`, with the
+    /// default ones it doesn't deny.
+    pub fn from_spec(spec: &Spec) -> anyhow::Result<Self> {
+        let Some(value) = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(CAPABILITIES_ANNOTATION))
+        else {
+            return Ok(Self::default());
+        };
+
+        let (mut granted, mut denied) = (Self::default().0, 0);
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, bits) = match entry.strip_prefix('-') {
+                Some(name) => (name, &mut denied),
+                None => (entry, &mut granted),
+            };
+            let capability = Capability::ALL
+                .into_iter()
+                .find(|capability| capability.name() == name)
+                .with_context(|| {
+                    format!(
+                        "invalid {CAPABILITIES_ANNOTATION} annotation, unknown capability {entry:?}"
+                    )
+                })?;
+            *bits |= capability.bit();
+        }
+        Ok(Self(granted & !denied))
+    }
+
+    /// Returns whether the set has `capability`.
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Returns the capabilities of the set.
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(move |capability| self.contains(*capability))
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        [Capability::Env].into_iter().collect()
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .fold(0, |bits, capability| bits | capability.bit()),
+        )
+    }
+}
+
+/// Annotation with the comma-separated destinations the guest may connect its sockets to when
+/// it's granted [`Capability::NetOutbound`], e.g., `10.0.0.0/8:443,example.com:80`. Each is an
+/// IP address, an IP network in CIDR notation, or a host name, followed by a port, with the IPv6
+/// addresses and networks in brackets, e.g., `[fd00::/8]:53`. Without it, the guest may connect
+/// to any destination. The outgoing requests of `wasi:http` aren't checked against it.
+pub const NET_ALLOW_ANNOTATION: &str = "io.runwasi.net.allow";
+
+/// The destinations of the [`NET_ALLOW_ANNOTATION`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetAllowlist(Vec<NetDestination>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct NetDestination {
+    host: NetHost,
+    port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum NetHost {
+    Network(IpAddr, u8),
+    Name(String),
+}
+
+impl NetAllowlist {
+    /// Returns the allowlist of the [`NET_ALLOW_ANNOTATION`] of `spec`, if it has one.
+    pub fn from_spec(spec: &Spec) -> anyhow::Result<Option<Self>> {
+        let Some(value) = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(NET_ALLOW_ANNOTATION))
+        else {
+            return Ok(None);
+        };
+
+        let destinations = value
+            .split(',')
+            .map(str::trim)
+            .filter(|destination| !destination.is_empty())
+            .map(|destination| {
+                NetDestination::parse(destination).with_context(|| {
+                    format!("invalid {NET_ALLOW_ANNOTATION} annotation, invalid destination {destination:?}")
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(Self(destinations)))
+    }
+
+    /// Returns whether the guest may connect to `addr`. The host names of the allowlist are
+    /// resolved on every call so that they follow the changes of their addresses, with the
+    /// `resolver` of the container, see [`RuntimeContext::resolver`], or with the resolver of
+    /// the host if it has none.
+    pub async fn allows(&self, addr: SocketAddr, resolver: Option<&Resolver>) -> bool {
+        let ip = addr.ip().to_canonical();
+        let destinations = self.0.iter().filter(|d| d.port == addr.port());
+        let mut names = vec![];
+        for destination in destinations {
+            match &destination.host {
+                NetHost::Network(network, prefix) if in_network(ip, *network, *prefix) => {
+                    return true;
+                }
+                NetHost::Network(..) => {}
+                NetHost::Name(name) => names.push(name),
+            }
+        }
+
+        for name in names {
+            let resolved = match resolver {
+                Some(resolver) => resolver.lookup(name).await,
+                None => tokio::net::lookup_host((name.as_str(), addr.port()))
+                    .await
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect()),
+            };
+            match resolved {
+                Ok(addrs) => {
+                    if addrs.iter().any(|resolved| resolved.to_canonical() == ip) {
+                        return true;
+                    }
+                }
+                Err(err) => {
+                    log::debug!("failed to resolve {name:?} of {NET_ALLOW_ANNOTATION}: {err}")
+                }
+            }
+        }
+        log::debug!(
+            "denying the connection of the guest to {addr}, it's not in {NET_ALLOW_ANNOTATION}"
+        );
+        false
+    }
+}
+
+impl NetDestination {
+    fn parse(destination: &str) -> anyhow::Result<Self> {
+        // the port comes after the last colon, outside of the brackets of IPv6
+        let bracketed = destination.strip_prefix('[');
+        let (host, port) = match bracketed {
+            Some(rest) => rest
+                .split_once("]:")
+                .context("expected `[<ipv6>]:<port>`")?,
+            None => destination
+                .rsplit_once(':')
+                .context("expected `<host>:<port>`")?,
+        };
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port {port:?}"))?;
+
+        let (addr, prefix) = match host.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (host, None),
+        };
+        let addr = match addr.parse::<IpAddr>() {
+            Ok(addr) if addr.is_ipv6() == bracketed.is_some() => addr,
+            Ok(_) => bail!("only the IPv6 addresses go in brackets"),
+            Err(_) if bracketed.is_none() && prefix.is_none() && !host.is_empty() => {
+                return Ok(Self {
+                    host: NetHost::Name(host.to_ascii_lowercase()),
+                    port,
+                });
+            }
+            Err(_) => bail!("invalid host {host:?}"),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max_prefix,
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => bail!("invalid prefix length {prefix:?}"),
+            },
+        };
+        Ok(Self {
+            host: NetHost::Network(addr.to_canonical(), prefix),
+            port,
+        })
+    }
+}
+
+// Whether `ip` is in the network of `addr` with a prefix of `prefix` bits
+fn in_network(ip: IpAddr, addr: IpAddr, prefix: u8) -> bool {
+    match (ip, addr) {
+        (IpAddr::V4(ip), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Annotation with the comma-separated TCP ports the guest listens on, e.g., `8080,9090`. They
+/// default to the TCP ports exposed by the image config of the wasm layers. The shim binds them
+/// for a guest granted [`Capability::NetInbound`], see [`Listeners`].
+pub const LISTEN_ANNOTATION: &str = "io.runwasi.listen";
+
+/// The ports of the [`LISTEN_ANNOTATION`], bound on all the addresses of the network namespace
+/// of the container before the guest runs, so that a port another container of the pod already
+/// listens on fails the start of the container with its number, and so that the port is
+/// reserved until the guest binds it.
+///
+/// Engines take the listener of a port either to accept its connections themselves, or to drop
+/// it right before the guest binds the port. The shim closes the ones left as soon as the
+/// container is asked to stop, so that the connections drain during its grace period.
+#[derive(Debug, Default)]
+pub struct Listeners(Mutex<Vec<TcpListener>>);
+
+impl Listeners {
+    /// Binds the TCP `ports` on all the addresses, IPv6 and IPv4 when the host has IPv6.
+    pub(crate) fn bind(ports: &[u16]) -> anyhow::Result<Self> {
+        let listeners = ports
+            .iter()
+            .map(|port| {
+                let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, *port)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::AddrInUse => {
+                        TcpListener::bind((Ipv4Addr::UNSPECIFIED, *port))
+                    }
+                    res => res,
+                };
+                listener.map_err(|err| match err.kind() {
+                    std::io::ErrorKind::AddrInUse => anyhow::anyhow!(
+                        "port {port} is already in use in the network namespace of the container"
+                    ),
+                    _ => anyhow::Error::new(err).context(format!("failed to bind port {port}")),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(Mutex::new(listeners)))
+    }
+
+    /// Returns the ports of the listeners left.
+    pub fn ports(&self) -> Vec<u16> {
+        let listeners = self.0.lock().unwrap();
+        listeners
+            .iter()
+            .filter_map(|listener| Some(listener.local_addr().ok()?.port()))
+            .collect()
+    }
+
+    /// Takes the listener of `port`, if it's left.
+    pub fn take(&self, port: u16) -> Option<TcpListener> {
+        let mut listeners = self.0.lock().unwrap();
+        let index = listeners
+            .iter()
+            .position(|listener| listener.local_addr().is_ok_and(|addr| addr.port() == port))?;
+        Some(listeners.swap_remove(index))
+    }
+
+    /// Closes the listeners left.
+    pub fn close(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Returns the ports of the [`LISTEN_ANNOTATION`] of `spec`, or the TCP ports exposed by the
+/// image config of the `wasm_layers` without it.
+pub(crate) fn listen_ports(spec: &Spec, wasm_layers: &[WasmLayer]) -> anyhow::Result<Vec<u16>> {
+    let value = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(LISTEN_ANNOTATION));
+    if let Some(value) = value {
+        return value
+            .split(',')
+            .map(str::trim)
+            .filter(|port| !port.is_empty())
+            .map(|port| match tcp_port(port) {
+                Some(number) if number > 0 => Ok(number),
+                _ => bail!("invalid {LISTEN_ANNOTATION} annotation, invalid TCP port {port:?}"),
+            })
+            .collect();
+    }
+
+    let exposed = wasm_layers
+        .iter()
+        .find_map(|layer| layer.image_config.as_ref()?.exposed_ports().as_ref());
+    Ok(exposed
+        .into_iter()
+        .flatten()
+        .filter_map(|port| tcp_port(port))
+        .collect())
+}
+
+// The port of `port/tcp`, or of just `port`, as the image configs expose them
+fn tcp_port(port: &str) -> Option<u16> {
+    let (number, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    if protocol != "tcp" {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// A directory mounted in the container to preopen for the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preopen {
+    /// The path of the directory in the container, which is also the path the guest sees.
+    pub path: PathBuf,
+    /// Whether the directory was mounted with the `ro` option, is in the `readonlyPaths` of the
+    /// runtime spec, or the guest isn't granted [`Capability::FsWrite`], so the guest can only
+    /// read it.
+    pub read_only: bool,
 }
 
 /// The source for a WASI module / components.
@@ -46,14 +732,346 @@ pub enum Source<'a> {
     /// Runtimes can additionally provide a list of layer types they support,
     /// and they will be included in this array, e.g., a `toml` file with the
     /// runtime configuration.
+    /// When there are several layers, the one to start is the one for which
+    /// [`WasmLayer::is_entrypoint`] is true, and the others can be linked with it.
     Oci(&'a [WasmLayer]),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WasmLayer {
     pub config: Descriptor,
-    #[serde(with = "serde_bytes")]
-    pub layer: Vec<u8>,
+    pub layer: LayerContent,
+    /// The config of the image, if it's a [wasm OCI artifact](WasmConfig)
+    #[serde(default)]
+    pub wasm_config: Option<WasmConfig>,
+    /// The `Entrypoint`, `Cmd`, `Env` and `WorkingDir` of the image config, if it has them.
+    /// They are used when the runtime spec has no `process.args`, e.g., for wasm OCI artifacts,
+    /// whose process containerd can't derive from the image.
+    #[serde(default)]
+    pub image_config: Option<Config>,
+    /// How to run the module / component, if the image has a [`RUN_CONFIG_MEDIA_TYPE`] layer.
+    #[serde(default)]
+    pub run_config: Option<RunConfig>,
+    /// The kind of binary in the layer, or `None` if it's not a wasm binary, e.g., a runtime
+    /// config, or an artifact precompiled without recording what it was compiled from.
+    #[serde(default)]
+    pub kind: Option<WasmLayerKind>,
+}
+
+/// The kind of binary in a [`WasmLayer`], e.g., to pick between loading a core module and
+/// a component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WasmLayerKind {
+    /// A core wasm module.
+    CoreModule,
+    /// A component of the component model.
+    Component,
+    /// An artifact precompiled by the engine from a binary of type `of`.
+    Precompiled { of: WasmBinaryType },
+}
+
+impl WasmLayerKind {
+    /// Classifies `bytes` from the version and layer fields of the wasm binary header.
+    /// Returns `None` if they are not a wasm binary.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match WasmBinaryType::from_bytes(bytes)? {
+            WasmBinaryType::Module => Some(Self::CoreModule),
+            WasmBinaryType::Component => Some(Self::Component),
+        }
+    }
+
+    /// Returns the type of the binary, or of the binary it was precompiled from.
+    pub fn binary_type(self) -> WasmBinaryType {
+        match self {
+            Self::CoreModule => WasmBinaryType::Module,
+            Self::Component => WasmBinaryType::Component,
+            Self::Precompiled { of } => of,
+        }
+    }
+
+    /// Returns the kind of an artifact precompiled from a binary of this kind.
+    pub fn precompiled(self) -> Self {
+        Self::Precompiled {
+            of: self.binary_type(),
+        }
+    }
+}
+
+/// Annotation of the wasm layer with the module / component to start, set to `true`,
+/// in images with several wasm layers, e.g., a main module and the libraries it's linked with.
+pub const ENTRYPOINT_ANNOTATION: &str = "runwasi.io/entrypoint";
+
+/// Annotation of a component layer with the comma-separated layers it depends on, by their
+/// title annotation or digest. The layer is composed with them before it's started.
+pub const DEPENDS_ON_ANNOTATION: &str = "runwasi.io/depends-on";
+
+// Annotation of a layer with its file name, e.g., as set by `oras push`
+pub(crate) const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+impl WasmLayer {
+    /// Returns whether the layer is annotated with [`ENTRYPOINT_ANNOTATION`].
+    pub fn is_entrypoint(&self) -> bool {
+        self.annotation(ENTRYPOINT_ANNOTATION) == Some("true")
+    }
+
+    fn annotation(&self, key: &str) -> Option<&str> {
+        self.config
+            .annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(key))
+            .map(String::as_str)
+    }
+}
+
+/// Returns the index of the layer to start among several wasm `layers`: the one annotated with
+/// [`ENTRYPOINT_ANNOTATION`], or, if no single layer is, the one whose title annotation is the
+/// file name of the entrypoint `arg0` of the process.
+pub(crate) fn entrypoint_layer(layers: &[WasmLayer], arg0: Option<&str>) -> anyhow::Result<usize> {
+    let claimed: Vec<_> = (0..layers.len())
+        .filter(|i| layers[*i].is_entrypoint())
+        .collect();
+    if let [i] = claimed[..] {
+        return Ok(i);
+    }
+
+    let candidates = if claimed.is_empty() {
+        (0..layers.len()).collect()
+    } else {
+        claimed.clone()
+    };
+    let path = arg0.map(|arg0| arg0.split_once('#').map_or(arg0, |(path, _)| path));
+    let name = path.and_then(|path| Path::new(path).file_name()?.to_str());
+    let matching: Vec<_> = candidates
+        .into_iter()
+        .filter(|i| name.is_some() && layers[*i].annotation(TITLE_ANNOTATION) == name)
+        .collect();
+    match (matching.as_slice(), claimed.len()) {
+        ([i], _) => Ok(*i),
+        (_, 0) => bail!(
+            "none of the {} wasm layers is annotated with {ENTRYPOINT_ANNOTATION}=true, \
+             and the entrypoint {path:?} doesn't match the title of one of them",
+            layers.len()
+        ),
+        (_, claimed) => bail!(
+            "{claimed} wasm layers are annotated with {ENTRYPOINT_ANNOTATION}=true, \
+             and the entrypoint {path:?} doesn't match the title of one of them"
+        ),
+    }
+}
+
+/// The config of an image in the [wasm OCI artifact] layout, e.g., as pushed by `oras` or `wkg`,
+/// whose manifest has a config with the `application/vnd.wasm.config.v0+json` media type
+/// and one `application/wasm` layer per module or component.
+///
+/// [wasm OCI artifact]: https://tag-runtime.cncf.io/wgs/wasm/deliverables/wasm-oci-artifact/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WasmConfig {
+    /// The date and time the artifact was created, in RFC 3339 format
+    pub created: Option<String>,
+    pub author: Option<String>,
+    /// Always `wasm`
+    pub architecture: String,
+    /// The WASI version targeted by the artifact, e.g., `wasip1` or `wasip2`
+    pub os: String,
+    /// The digests of the wasm layers, in order
+    pub layer_digests: Vec<String>,
+    /// The interface of the component, if the artifact is a component
+    pub component: Option<WasmComponent>,
+}
+
+/// The interface of a wasm component artifact
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WasmComponent {
+    /// The interfaces exported by the component, e.g., `wasi:cli/run@0.2.0`
+    pub exports: Vec<String>,
+    /// The interfaces imported by the component
+    pub imports: Vec<String>,
+    /// The world the component targets, if it was declared
+    pub target: Option<String>,
+}
+
+/// The media type of the layer describing how to run the module / component of an image.
+pub const RUN_CONFIG_MEDIA_TYPE: &str = "application/vnd.runwasi.config.v1+json";
+
+/// How to run the module / component of an image, from its [`RUN_CONFIG_MEDIA_TYPE`] layer.
+/// The runtime spec wins over it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunConfig {
+    /// The exported function to call, if the entrypoint of the spec doesn't name one
+    pub export: Option<String>,
+    /// The arguments after the entrypoint, if the spec has none
+    pub args: Vec<String>,
+    /// Environment variables in the `NAME=VALUE` format, for the variables the spec doesn't set
+    pub env: Vec<String>,
+    /// Directories of the container that must exist for the module / component to run
+    pub preopens: Vec<String>,
+}
+
+impl RunConfig {
+    const FIELDS: &[&str] = &["export", "args", "env", "preopens"];
+
+    /// Parses the content of a [`RUN_CONFIG_MEDIA_TYPE`] layer.
+    /// Unknown fields are ignored with a warning, so that configs of newer toolchains still run.
+    pub fn from_slice(bytes: &[u8]) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        if let serde_json::Value::Object(fields) = &value {
+            for field in fields.keys() {
+                if !Self::FIELDS.contains(&field.as_str()) {
+                    log::warn!("ignoring unknown field {field:?} of the run config");
+                }
+            }
+        }
+        serde_json::from_value(value)
+    }
+}
+
+/// The content of a [`WasmLayer`], which dereferences to its bytes.
+///
+/// Small layers are kept in memory, while large ones are memory-mapped from a file, so that
+/// the instances of an image share a single copy of them. Cloning the content doesn't copy it.
+#[derive(Clone)]
+pub struct LayerContent(Content);
+
+#[derive(Clone)]
+enum Content {
+    Bytes(Arc<[u8]>),
+    #[cfg(unix)]
+    Mapped(Arc<MappedFile>),
+}
+
+#[cfg(unix)]
+struct MappedFile {
+    path: PathBuf,
+    map: crate::sys::mmap::Mmap,
+}
+
+impl LayerContent {
+    /// Maps the file at `path`, which must not be modified afterwards.
+    #[cfg(unix)]
+    pub(crate) fn map(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let map = crate::sys::mmap::Mmap::open(&path)?;
+        Ok(Self(Content::Mapped(Arc::new(MappedFile { path, map }))))
+    }
+
+    /// Returns the path of the file the content is memory-mapped from, if it's not held in memory.
+    /// The path is only valid outside of the container.
+    #[cfg(unix)]
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match &self.0 {
+            Content::Bytes(_) => None,
+            Content::Mapped(file) => Some(&file.path),
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn is_mapped(&self) -> bool {
+        self.path().is_some()
+    }
+}
+
+impl Deref for LayerContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Content::Bytes(bytes) => &bytes[..],
+            #[cfg(unix)]
+            Content::Mapped(file) => &file.map[..],
+        }
+    }
+}
+
+impl AsRef<[u8]> for LayerContent {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for LayerContent {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Content::Bytes(bytes.into()))
+    }
+}
+
+impl PartialEq for LayerContent {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<[u8]> for LayerContent {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for LayerContent {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl std::fmt::Debug for LayerContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Content::Bytes(bytes) => write!(f, "LayerContent({} bytes)", bytes.len()),
+            #[cfg(unix)]
+            Content::Mapped(file) => {
+                write!(
+                    f,
+                    "LayerContent({} bytes mapped from {:?})",
+                    file.map.len(),
+                    file.path
+                )
+            }
+        }
+    }
+}
+
+// Mapped contents are sent to other processes as the path of their file, which the receiving
+// process maps again. This is how the zygote of an instance gets the layers without a copy.
+#[derive(Serialize)]
+#[cfg_attr(not(unix), allow(dead_code))]
+enum SerializedContent<'a> {
+    Bytes(&'a serde_bytes::Bytes),
+    File(&'a Path),
+}
+
+#[derive(Deserialize)]
+enum DeserializedContent {
+    Bytes(serde_bytes::ByteBuf),
+    File(PathBuf),
+}
+
+impl Serialize for LayerContent {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Content::Bytes(bytes) => SerializedContent::Bytes(serde_bytes::Bytes::new(bytes)),
+            #[cfg(unix)]
+            Content::Mapped(file) => SerializedContent::File(&file.path),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LayerContent {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match DeserializedContent::deserialize(deserializer)? {
+            DeserializedContent::Bytes(bytes) => Ok(bytes.into_vec().into()),
+            #[cfg(unix)]
+            DeserializedContent::File(path) => Self::map(&path).map_err(|err| {
+                serde::de::Error::custom(format!("failed to map layer file {path:?}: {err}"))
+            }),
+            #[cfg(not(unix))]
+            DeserializedContent::File(path) => Err(serde::de::Error::custom(format!(
+                "mapped layer files are not supported: {path:?}"
+            ))),
+        }
+    }
 }
 
 impl<'a> Source<'a> {
@@ -65,14 +1083,35 @@ impl<'a> Source<'a> {
                     .resolve_in_path_or_cwd()
                     .next()
                     .context("module not found")?;
-                Ok(Cow::Owned(std::fs::read(path)?))
+                let bytes = std::fs::read(&path)?;
+                if !is_wat(&bytes) {
+                    return Ok(Cow::Owned(bytes));
+                }
+                let wasm = wat_to_wasm(&bytes, &path)?;
+                Ok(Cow::Owned(wasm))
             }
-            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer)),
-            Source::Oci(_modules) => {
-                bail!("only a single module is supported when using images with OCI layers")
+            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer[..])),
+            Source::Oci(modules) => {
+                let module = &modules[entrypoint_layer(modules, None)?];
+                Ok(Cow::Borrowed(&module.layer[..]))
             }
         }
     }
+
+    /// Returns the kind of the `bytes` returned by [`Source::as_bytes`]: the
+    /// [`WasmLayer::kind`] of the layer to start, or the kind classified from `bytes` for
+    /// files and layers of unknown kind. Precompiled files are not recognized, as their
+    /// format is specific to the engine.
+    pub fn kind(&self, bytes: &[u8]) -> Option<WasmLayerKind> {
+        let layer = match self {
+            Source::File(_) => None,
+            Source::Oci([module]) => Some(module),
+            Source::Oci(modules) => entrypoint_layer(modules, None).ok().map(|i| &modules[i]),
+        };
+        layer
+            .and_then(|layer| layer.kind)
+            .or_else(|| WasmLayerKind::from_bytes(bytes))
+    }
 }
 
 /// The entrypoint for a WASI module / component.
@@ -90,6 +1129,16 @@ pub struct Entrypoint<'a> {
 pub(crate) struct WasiContext<'a> {
     pub spec: &'a Spec,
     pub wasm_layers: &'a [WasmLayer],
+    pub preopens: &'a [Preopen],
+    pub precompiled_extensions: &'a [&'a str],
+    // the file of the entrypoint in the rootfs, if there are no wasm layers and it was resolved
+    pub entrypoint_file: Option<&'a Path>,
+    // the FIFO the start of the guest is reported to the shim on, if it has a start deadline
+    pub started: Option<&'a std::fs::File>,
+    // the ports the executor bound for the guest, once it entered the network namespace
+    pub listeners: Option<&'a Arc<Listeners>>,
+    // the configuration of the guest, once the executor read its files
+    pub wasi_config: Option<&'a Arc<WasiConfig>>,
 }
 
 impl RuntimeContext for WasiContext<'_> {
@@ -119,10 +1168,10 @@ impl RuntimeContext for WasiContext<'_> {
             .split_once('#')
             .unwrap_or((entry_point, "_start"));
 
-        let source = if self.wasm_layers.is_empty() {
-            Source::File(PathBuf::from(path))
-        } else {
-            Source::Oci(self.wasm_layers)
+        let source = match self.entrypoint_file {
+            _ if !self.wasm_layers.is_empty() => Source::Oci(self.wasm_layers),
+            Some(file) => Source::File(file.to_path_buf()),
+            None => Source::File(PathBuf::from(path)),
         };
 
         let module_name = PathBuf::from(path)
@@ -136,9 +1185,133 @@ impl RuntimeContext for WasiContext<'_> {
             name: module_name,
         }
     }
+
+    fn preopens(&self) -> &[Preopen] {
+        self.preopens
+    }
+
+    fn cwd(&self) -> &Path {
+        self.spec
+            .process()
+            .as_ref()
+            .map(|p| p.cwd().as_path())
+            .unwrap_or(Path::new("/"))
+    }
+
+    fn annotation(&self, key: &str) -> Option<&str> {
+        self.spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(key))
+            .map(String::as_str)
+    }
+
+    fn annotations_with_prefix(&self, prefix: &str) -> Vec<(&str, &str)> {
+        let mut annotations: Vec<_> = self
+            .spec
+            .annotations()
+            .iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key.strip_prefix(prefix)?, value.as_str())))
+            .collect();
+        annotations.sort();
+        annotations
+    }
+
+    fn memory_limit(&self) -> Option<u64> {
+        let resources = self.spec.linux().as_ref()?.resources().as_ref()?;
+        // -1 means unlimited
+        let limit = resources.memory().as_ref()?.limit()?;
+        let limit = u64::try_from(limit).ok().filter(|limit| *limit > 0)?;
+
+        let headroom = match self.annotation(MEMORY_HEADROOM_ANNOTATION) {
+            None => DEFAULT_MEMORY_HEADROOM,
+            Some(value) => match value.trim_end_matches('%').parse() {
+                Ok(headroom) if headroom < 100 => headroom,
+                _ => {
+                    log::warn!(
+                        "invalid {MEMORY_HEADROOM_ANNOTATION} annotation {value:?}, using {DEFAULT_MEMORY_HEADROOM}%"
+                    );
+                    DEFAULT_MEMORY_HEADROOM
+                }
+            },
+        };
+        Some(limit / 100 * (100 - headroom))
+    }
+
+    fn stack_limits(&self) -> StackLimits {
+        // validated when the container was created
+        StackLimits::from_spec(self.spec).unwrap_or_default()
+    }
+
+    fn coredump(&self) -> Option<Coredump> {
+        let path = self.annotation(COREDUMP_ANNOTATION)?;
+        let max_size = match self.annotation(COREDUMP_MAX_SIZE_ANNOTATION) {
+            None => DEFAULT_COREDUMP_MAX_SIZE,
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                log::warn!(
+                    "invalid {COREDUMP_MAX_SIZE_ANNOTATION} annotation {value:?}, using {DEFAULT_COREDUMP_MAX_SIZE}"
+                );
+                DEFAULT_COREDUMP_MAX_SIZE
+            }),
+        };
+        Some(Coredump::new(path, max_size))
+    }
+
+    fn deterministic(&self) -> Option<Deterministic> {
+        // validated when the container was created
+        Deterministic::from_spec(self.spec).ok().flatten()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // validated when the container was created
+        Capabilities::from_spec(self.spec).unwrap_or_default()
+    }
+
+    fn root_read_only(&self) -> bool {
+        let readonly_rootfs =
+            (self.spec.root().as_ref()).is_some_and(|root| matches!(root.readonly(), Some(true)));
+        readonly_rootfs || !self.capabilities().contains(Capability::FsWrite)
+    }
+
+    fn net_allowlist(&self) -> Option<NetAllowlist> {
+        // validated when the container was created
+        NetAllowlist::from_spec(self.spec).ok().flatten()
+    }
+
+    fn listeners(&self) -> Arc<Listeners> {
+        self.listeners.cloned().unwrap_or_default()
+    }
+
+    fn resolver(&self) -> Option<Arc<Resolver>> {
+        Resolver::from_spec(self.spec).map(Arc::new)
+    }
+
+    fn wasi_config(&self) -> Arc<WasiConfig> {
+        self.wasi_config.cloned().unwrap_or_default()
+    }
+
+    fn is_precompiled_file(&self) -> bool {
+        let Source::File(path) = self.entrypoint().source else {
+            return false;
+        };
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.precompiled_extensions.contains(&ext))
+    }
+
+    fn guest_started(&self) {
+        let Some(mut fifo) = self.started else {
+            return;
+        };
+        if let Err(err) = fifo.write_all(b"s") {
+            log::warn!("failed to report the start of the guest: {err}");
+        }
+    }
 }
 
 /// The type of a wasm binary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WasmBinaryType {
     /// A wasm module.
     Module,
@@ -159,13 +1332,39 @@ impl WasmBinaryType {
     }
 }
 
+/// The media type of OCI layers with a module / component in the WebAssembly text format.
+pub const WAT_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wat";
+
+/// Returns whether `bytes` are in the WebAssembly text format rather than a wasm binary.
+/// Text is assumed to be WAT, so that malformed WAT reports where it fails to parse.
+/// Other binaries, like precompiled artifacts, are left to the engine.
+pub(crate) fn is_wat(bytes: &[u8]) -> bool {
+    !bytes.starts_with(b"\0asm") && std::str::from_utf8(bytes).is_ok()
+}
+
+/// Converts the WAT in `bytes` to a wasm binary. Parse errors are reported as
+/// `<source>:<line>:<column>`, where `source` is, e.g., the path of the file or the digest
+/// of the layer.
+pub(crate) fn wat_to_wasm(bytes: &[u8], source: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
+    wat::parse_bytes(bytes)
+        .map(Cow::into_owned)
+        .map_err(|mut err| {
+            err.set_path(source.as_ref());
+            err.into()
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
     use oci_spec::image::{Descriptor, Digest};
-    use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
+    use oci_spec::runtime::{
+        LinuxBuilder, LinuxMemoryBuilder, LinuxResourcesBuilder, ProcessBuilder, RootBuilder,
+        SpecBuilder,
+    };
 
     use super::*;
+    use crate::testing::spec_with_annotations;
 
     #[test]
     fn test_get_args() -> Result<()> {
@@ -182,6 +1381,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let args = ctx.args();
@@ -201,6 +1406,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let args = ctx.args();
@@ -228,6 +1439,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let args = ctx.args();
@@ -249,6 +1466,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let path = ctx.entrypoint().source;
@@ -279,6 +1502,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let expected_path = PathBuf::from("hello.wat");
@@ -318,6 +1547,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -357,6 +1592,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -365,6 +1606,172 @@ mod tests {
             Source::File(p) if p == expected_path
         ));
 
+        // the file resolved in the rootfs is used instead of the first arg
+        let file = Path::new("/app/hello.wat");
+        let ctx = WasiContext {
+            entrypoint_file: Some(file),
+            ..ctx
+        };
+        let entrypoint = ctx.entrypoint();
+        assert!(matches!(entrypoint.source, Source::File(p) if p == file));
+        assert_eq!(entrypoint.name.as_deref(), Some("hello"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_precompiled_file() -> Result<()> {
+        let spec = |arg0: &str| -> Result<Spec> {
+            Ok(SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .process(
+                    ProcessBuilder::default()
+                        .cwd("/")
+                        .args(vec![arg0.to_string()])
+                        .build()?,
+                )
+                .build()?)
+        };
+        let is_precompiled_file = |spec: &Spec, wasm_layers: &[WasmLayer]| {
+            WasiContext {
+                spec,
+                wasm_layers,
+                preopens: &[],
+                precompiled_extensions: &["cwasm"],
+                entrypoint_file: None,
+                started: None,
+                listeners: None,
+                wasi_config: None,
+            }
+            .is_precompiled_file()
+        };
+
+        assert!(is_precompiled_file(&spec("/app.cwasm#run")?, &[]));
+        assert!(!is_precompiled_file(&spec("/app.wasm")?, &[]));
+        assert!(!is_precompiled_file(&spec("/app")?, &[]));
+
+        // the layers are precompiled by the shim rather than read from the rootfs
+        let layers = [WasmLayer {
+            layer: vec![].into(),
+            wasm_config: None,
+            image_config: None,
+            run_config: None,
+            kind: None,
+            config: Descriptor::new(
+                oci_spec::image::MediaType::Other("".to_string()),
+                10,
+                Digest::try_from(format!("sha256:{:064?}", 0))?,
+            ),
+        }];
+        assert!(!is_precompiled_file(&spec("/app.cwasm")?, &layers));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_guest_started() -> Result<()> {
+        let spec = SpecBuilder::default().build()?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("started");
+        let fifo = std::fs::File::create(&path)?;
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
+        };
+
+        // without a start deadline there's nothing to report to
+        ctx.guest_started();
+
+        WasiContext {
+            started: Some(&fifo),
+            ..ctx
+        }
+        .guest_started();
+        assert_eq!(std::fs::read_to_string(&path)?, "s");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasm_layer_kind() {
+        // `\0asm`, followed by the version and layer fields of the header
+        let module = b"\0asm\x01\0\0\0";
+        let component = b"\0asm\x0d\0\x01\0";
+
+        assert_eq!(
+            WasmLayerKind::from_bytes(module),
+            Some(WasmLayerKind::CoreModule)
+        );
+        assert_eq!(
+            WasmLayerKind::from_bytes(component),
+            Some(WasmLayerKind::Component)
+        );
+        assert_eq!(WasmLayerKind::from_bytes(b"\x7fELF\x02\x01\x01\0"), None);
+        assert_eq!(WasmLayerKind::from_bytes(b"\0asm"), None);
+
+        assert_eq!(
+            WasmLayerKind::Component.precompiled(),
+            WasmLayerKind::Precompiled {
+                of: WasmBinaryType::Component
+            }
+        );
+        assert_eq!(
+            WasmLayerKind::CoreModule.precompiled().precompiled(),
+            WasmLayerKind::CoreModule.precompiled()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_source_kind() -> Result<()> {
+        let module = b"\0asm\x01\0\0\0";
+        let mut layer = annotated_layer(b"precompiled", &[])?;
+        layer.kind = Some(WasmLayerKind::CoreModule.precompiled());
+
+        // the kind of layers is recorded, as precompiled artifacts can't be classified
+        let source = Source::Oci(std::slice::from_ref(&layer));
+        assert_eq!(source.kind(b"precompiled"), layer.kind);
+
+        layer.kind = None;
+        let source = Source::Oci(std::slice::from_ref(&layer));
+        assert_eq!(source.kind(module), Some(WasmLayerKind::CoreModule));
+        assert_eq!(source.kind(b"precompiled"), None);
+
+        let source = Source::File(PathBuf::from("app.wasm"));
+        assert_eq!(source.kind(module), Some(WasmLayerKind::CoreModule));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wat_file_is_converted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("hello.wat");
+
+        std::fs::write(&path, "(module)")?;
+        let bytes = Source::File(path.clone()).as_bytes()?;
+        assert!(bytes.starts_with(b"\0asm"));
+        assert!(matches!(
+            WasmBinaryType::from_bytes(&bytes),
+            Some(WasmBinaryType::Module)
+        ));
+
+        // binaries are returned as they are
+        std::fs::write(&path, &*bytes)?;
+        assert_eq!(Source::File(path.clone()).as_bytes()?, bytes);
+
+        // parse errors point at the line and column of the file
+        std::fs::write(&path, "(module\n  (func (result i32)\n    (i32.const)))")?;
+        let err = Source::File(path.clone()).as_bytes().unwrap_err();
+        let location = format!("{}:3:", path.display());
+        assert!(err.to_string().contains(&location), "{err}");
+
         Ok(())
     }
 
@@ -387,13 +1794,23 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[WasmLayer {
-                layer: vec![],
+                layer: vec![].into(),
+                wasm_config: None,
+                image_config: None,
+                run_config: None,
+                kind: None,
                 config: Descriptor::new(
                     oci_spec::image::MediaType::Other("".to_string()),
                     10,
                     Digest::try_from(format!("sha256:{:064?}", 0))?,
                 ),
             }],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         assert!(matches!(ctx.entrypoint().source, Source::Oci(_)));
@@ -401,6 +1818,149 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(unix)]
+    fn annotated_layer(bytes: &[u8], annotations: &[(&str, &str)]) -> Result<WasmLayer> {
+        let annotations = annotations
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let mut config = Descriptor::new(
+            oci_spec::image::MediaType::Other("application/wasm".to_string()),
+            bytes.len() as u64,
+            Digest::try_from(format!("sha256:{:064}", 0))?,
+        );
+        config.set_annotations(Some(annotations));
+        Ok(WasmLayer {
+            config,
+            layer: bytes.to_vec().into(),
+            wasm_config: None,
+            image_config: None,
+            run_config: None,
+            kind: None,
+        })
+    }
+
+    #[test]
+    fn test_entrypoint_layer() -> Result<()> {
+        let main = annotated_layer(
+            b"main",
+            &[
+                (ENTRYPOINT_ANNOTATION, "true"),
+                (TITLE_ANNOTATION, "main.wasm"),
+            ],
+        )?;
+        let lib = annotated_layer(b"lib", &[(TITLE_ANNOTATION, "lib.wasm")])?;
+        let untitled = annotated_layer(b"untitled", &[])?;
+
+        let layers = [lib.clone(), main.clone()];
+        assert_eq!(entrypoint_layer(&layers, None)?, 1);
+        assert_eq!(&*Source::Oci(&layers).as_bytes()?, b"main");
+
+        // the args select the entrypoint if the annotations don't
+        let layers = [lib.clone(), untitled.clone()];
+        assert!(entrypoint_layer(&layers, None).is_err());
+        assert!(entrypoint_layer(&layers, Some("/other.wasm")).is_err());
+        assert_eq!(entrypoint_layer(&layers, Some("/lib.wasm#run"))?, 0);
+        assert!(Source::Oci(&layers).as_bytes().is_err());
+
+        let other_main = annotated_layer(
+            b"other",
+            &[
+                (ENTRYPOINT_ANNOTATION, "true"),
+                (TITLE_ANNOTATION, "other.wasm"),
+            ],
+        )?;
+        let layers = [main.clone(), other_main];
+        let err = entrypoint_layer(&layers, None).unwrap_err();
+        assert!(
+            err.to_string().contains("2 wasm layers are annotated"),
+            "{err}"
+        );
+        assert_eq!(entrypoint_layer(&layers, Some("other.wasm"))?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_layer_content_is_serialized_as_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+        std::fs::write(&path, b"wasm")?;
+
+        let content = LayerContent::map(&path)?;
+        let serialized = serde_json::to_string(&content)?;
+        assert!(serialized.contains(path.to_str().unwrap()));
+
+        let received: LayerContent = serde_json::from_str(&serialized)?;
+        assert!(received.is_mapped());
+        assert_eq!(received, b"wasm".to_vec());
+
+        let content = LayerContent::from(b"wasm".to_vec());
+        let received: LayerContent = serde_json::from_str(&serde_json::to_string(&content)?)?;
+        assert!(!received.is_mapped());
+        assert_eq!(received, content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_wasm_config() -> Result<()> {
+        let config: WasmConfig = serde_json::from_str(
+            r#"{
+                "created": "2024-06-01T00:00:00Z",
+                "architecture": "wasm",
+                "os": "wasip2",
+                "layerDigests": ["sha256:1"],
+                "component": {
+                    "exports": ["wasi:http/incoming-handler@0.2.0"],
+                    "imports": ["wasi:io/streams@0.2.0"],
+                    "target": "wasi:http/proxy@0.2.0"
+                },
+                "unknown": true
+            }"#,
+        )?;
+        assert_eq!(config.os, "wasip2");
+        assert_eq!(config.layer_digests, ["sha256:1"]);
+        let component = config.component.unwrap();
+        assert_eq!(component.exports, ["wasi:http/incoming-handler@0.2.0"]);
+        assert_eq!(component.target.as_deref(), Some("wasi:http/proxy@0.2.0"));
+
+        // modules have no component section
+        let config: WasmConfig =
+            serde_json::from_str(r#"{"architecture": "wasm", "os": "wasip1"}"#)?;
+        assert_eq!(config.component, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_run_config() -> Result<()> {
+        let config = RunConfig::from_slice(
+            br#"{
+                "export": "serve",
+                "args": ["--port", "8080"],
+                "env": ["RUST_LOG=info"],
+                "preopens": ["/data"],
+                "unknown": true
+            }"#,
+        )?;
+        assert_eq!(
+            config,
+            RunConfig {
+                export: Some("serve".to_string()),
+                args: vec!["--port".to_string(), "8080".to_string()],
+                env: vec!["RUST_LOG=info".to_string()],
+                preopens: vec!["/data".to_string()],
+            }
+        );
+
+        assert_eq!(RunConfig::from_slice(b"{}")?, RunConfig::default());
+        assert!(RunConfig::from_slice(br#"{"args": "--port"}"#).is_err());
+        assert!(RunConfig::from_slice(b"{").is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_envs() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -416,6 +1976,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let envs = ctx.envs();
@@ -426,6 +1992,451 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_memory_limit() -> Result<()> {
+        let spec_with = |limit: Option<i64>, headroom: Option<&str>| -> Result<Spec> {
+            let mut memory = LinuxMemoryBuilder::default();
+            if let Some(limit) = limit {
+                memory = memory.limit(limit);
+            }
+            let resources = LinuxResourcesBuilder::default()
+                .memory(memory.build()?)
+                .build()?;
+            let headroom = headroom.map(|headroom| (MEMORY_HEADROOM_ANNOTATION, headroom));
+            let mut spec = spec_with_annotations(headroom.as_slice())?;
+            spec.set_linux(Some(LinuxBuilder::default().resources(resources).build()?));
+            Ok(spec)
+        };
+        let memory_limit = |spec: &Spec| {
+            WasiContext {
+                spec,
+                wasm_layers: &[],
+                preopens: &[],
+                precompiled_extensions: &[],
+                entrypoint_file: None,
+                started: None,
+                listeners: None,
+                wasi_config: None,
+            }
+            .memory_limit()
+        };
+
+        let mib = 1024 * 1024;
+        assert_eq!(
+            memory_limit(&spec_with(Some(100 * mib), None)?),
+            Some(90 * mib as u64)
+        );
+        assert_eq!(
+            memory_limit(&spec_with(Some(100 * mib), Some("25"))?),
+            Some(75 * mib as u64)
+        );
+        assert_eq!(
+            memory_limit(&spec_with(Some(100 * mib), Some("0%"))?),
+            Some(100 * mib as u64)
+        );
+        // invalid headrooms fall back to the default
+        assert_eq!(
+            memory_limit(&spec_with(Some(100 * mib), Some("100"))?),
+            Some(90 * mib as u64)
+        );
+        assert_eq!(memory_limit(&spec_with(Some(-1), None)?), None);
+        assert_eq!(memory_limit(&spec_with(None, None)?), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_limits() -> Result<()> {
+        assert_eq!(
+            StackLimits::from_spec(&spec_with_annotations(&[])?)?,
+            StackLimits::default()
+        );
+        assert_eq!(
+            StackLimits::from_spec(&spec_with_annotations(&[
+                (MAX_STACK_SIZE_ANNOTATION, "1048576"),
+                (MAX_CALL_DEPTH_ANNOTATION, "1000"),
+            ])?)?,
+            StackLimits {
+                max_stack_size: Some(1048576),
+                max_call_depth: Some(1000),
+            }
+        );
+
+        let err = StackLimits::from_spec(&spec_with_annotations(&[(
+            MAX_STACK_SIZE_ANNOTATION,
+            "1MiB",
+        )])?)
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid {MAX_STACK_SIZE_ANNOTATION} annotation: \"1MiB\"")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_annotation() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .annotations(std::collections::HashMap::from([
+                ("io.runwasi.test".to_string(), "value".to_string()),
+                ("io.runwasi.kv.default".to_string(), "dir:/data".to_string()),
+                (
+                    "io.runwasi.kv.cache".to_string(),
+                    "redis://redis".to_string(),
+                ),
+            ]))
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
+        };
+
+        assert_eq!(ctx.annotation("io.runwasi.test"), Some("value"));
+        assert_eq!(ctx.annotation("io.runwasi.other"), None);
+        assert_eq!(
+            ctx.annotations_with_prefix("io.runwasi.kv."),
+            [("cache", "redis://redis"), ("default", "dir:/data")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coredump() -> Result<()> {
+        let coredump = |spec: &Spec| {
+            WasiContext {
+                spec,
+                wasm_layers: &[],
+                preopens: &[],
+                precompiled_extensions: &[],
+                entrypoint_file: None,
+                started: None,
+                listeners: None,
+                wasi_config: None,
+            }
+            .coredump()
+        };
+
+        assert_eq!(coredump(&spec_with_annotations(&[])?), None);
+
+        let spec = spec_with_annotations(&[(COREDUMP_ANNOTATION, "/dumps/core.%time%.wasm")])?;
+        let dump = coredump(&spec).unwrap();
+        assert_eq!(dump.max_size, DEFAULT_COREDUMP_MAX_SIZE);
+        let path = dump.path().to_string_lossy().to_string();
+        let time = path
+            .strip_prefix("/dumps/core.")
+            .and_then(|path| path.strip_suffix(".wasm"))
+            .unwrap();
+        assert!(time.parse::<u64>().is_ok(), "{path}");
+
+        let spec = spec_with_annotations(&[
+            (COREDUMP_ANNOTATION, "/dumps/core.wasm"),
+            (COREDUMP_MAX_SIZE_ANNOTATION, "1048576"),
+        ])?;
+        assert_eq!(coredump(&spec).unwrap().max_size, 1048576);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic() -> Result<()> {
+        let spec_with = |value: Option<&str>| {
+            let annotation = value.map(|value| (DETERMINISTIC_ANNOTATION, value));
+            spec_with_annotations(annotation.as_slice())
+        };
+
+        assert_eq!(Deterministic::from_spec(&spec_with(None)?)?, None);
+
+        let deterministic = Deterministic::from_spec(&spec_with(Some("seed:42"))?)?;
+        assert_eq!(
+            deterministic,
+            Some(Deterministic {
+                seed: 42,
+                tick: Deterministic::DEFAULT_TICK
+            })
+        );
+
+        let deterministic = Deterministic::from_spec(&spec_with(Some("seed:7, tick:10us"))?)?;
+        assert_eq!(
+            deterministic,
+            Some(Deterministic {
+                seed: 7,
+                tick: Duration::from_micros(10)
+            })
+        );
+
+        for value in [
+            "",
+            "true",
+            "tick:1ms",
+            "seed:-1",
+            "seed:1,tick:0ms",
+            "seed:1,tick:1h",
+        ] {
+            assert!(
+                Deterministic::from_spec(&spec_with(Some(value))?).is_err(),
+                "{value:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_only() -> Result<()> {
+        let spec_with = |value: Option<&str>| {
+            let annotation = value.map(|value| (VALIDATE_ONLY_ANNOTATION, value));
+            spec_with_annotations(annotation.as_slice())
+        };
+
+        assert!(!validate_only(&spec_with(None)?)?);
+        assert!(!validate_only(&spec_with(Some("false"))?)?);
+        assert!(validate_only(&spec_with(Some("true"))?)?);
+        for value in ["", "1", "yes", "True"] {
+            assert!(
+                validate_only(&spec_with(Some(value))?).is_err(),
+                "{value:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() -> Result<()> {
+        let spec_with = |value: Option<&str>| {
+            let annotation = value.map(|value| (CAPABILITIES_ANNOTATION, value));
+            spec_with_annotations(annotation.as_slice())
+        };
+
+        // only the env is granted without the annotation
+        let capabilities = Capabilities::from_spec(&spec_with(None)?)?;
+        assert_eq!(capabilities, Capabilities::default());
+        assert_eq!(capabilities.iter().collect::<Vec<_>>(), [Capability::Env]);
+        assert_eq!(
+            Capabilities::from_spec(&spec_with(Some(""))?)?,
+            capabilities
+        );
+
+        let capabilities = Capabilities::from_spec(&spec_with(Some("fs-write, net-outbound"))?)?;
+        assert!(capabilities.contains(Capability::NetOutbound));
+        assert!(capabilities.contains(Capability::FsWrite));
+        assert!(!capabilities.contains(Capability::NetInbound));
+        assert_eq!(
+            capabilities.iter().collect::<Vec<_>>(),
+            [
+                Capability::NetOutbound,
+                Capability::FsWrite,
+                Capability::Env
+            ]
+        );
+
+        // the env is only dropped when it's denied, even if it's also granted
+        let capabilities = Capabilities::from_spec(&spec_with(Some("fs-write,-env"))?)?;
+        assert_eq!(
+            capabilities.iter().collect::<Vec<_>>(),
+            [Capability::FsWrite]
+        );
+        assert_eq!(
+            Capabilities::from_spec(&spec_with(Some("env,-env"))?)?
+                .iter()
+                .count(),
+            0
+        );
+        assert_eq!(
+            Capability::ALL
+                .into_iter()
+                .collect::<Capabilities>()
+                .iter()
+                .count(),
+            Capability::ALL.len()
+        );
+
+        let err = Capabilities::from_spec(&spec_with(Some("env,net"))?).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid {CAPABILITIES_ANNOTATION} annotation, unknown capability \"net\"")
+        );
+        let err = Capabilities::from_spec(&spec_with(Some("-net"))?).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid {CAPABILITIES_ANNOTATION} annotation, unknown capability \"-net\"")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_read_only() -> Result<()> {
+        let spec_with = |readonly: bool, capabilities: &str| -> Result<Spec> {
+            let mut spec = spec_with_annotations(&[(CAPABILITIES_ANNOTATION, capabilities)])?;
+            let root = RootBuilder::default().path("rootfs").readonly(readonly);
+            spec.set_root(Some(root.build()?));
+            Ok(spec)
+        };
+        let root_read_only = |spec: &Spec| {
+            WasiContext {
+                spec,
+                wasm_layers: &[],
+                preopens: &[],
+                precompiled_extensions: &[],
+                entrypoint_file: None,
+                started: None,
+                listeners: None,
+                wasi_config: None,
+            }
+            .root_read_only()
+        };
+
+        assert!(!root_read_only(&spec_with(false, "fs-write")?));
+        // without the fs-write capability, or with a read-only rootfs
+        assert!(root_read_only(&spec_with(false, "env")?));
+        assert!(root_read_only(&spec_with(true, "fs-write")?));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_net_allowlist() -> Result<()> {
+        let spec_with = |value: &str| spec_with_annotations(&[(NET_ALLOW_ANNOTATION, value)]);
+        assert_eq!(
+            NetAllowlist::from_spec(&SpecBuilder::default().build()?)?,
+            None
+        );
+
+        let allowlist = NetAllowlist::from_spec(&spec_with(
+            "10.0.0.0/8:443, [fd00::/8]:53, 192.168.1.1:80, localhost:8080",
+        )?)?
+        .unwrap();
+        for addr in [
+            "10.1.2.3:443",
+            "[fd00::1]:53",
+            "192.168.1.1:80",
+            // IPv4 through an IPv6 socket
+            "[::ffff:10.0.0.1]:443",
+            // from /etc/hosts
+            "127.0.0.1:8080",
+        ] {
+            assert!(allowlist.allows(addr.parse()?, None).await, "{addr}");
+        }
+        for addr in [
+            "10.1.2.3:80",
+            "11.0.0.1:443",
+            "[fe80::1]:53",
+            "192.168.1.2:80",
+        ] {
+            assert!(!allowlist.allows(addr.parse()?, None).await, "{addr}");
+        }
+
+        // with the hosts file of the container instead of the one of the host
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("resolv.conf"), "")?;
+        std::fs::write(dir.path().join("hosts"), "10.244.0.7 localhost\n")?;
+        let resolver = Resolver::new(
+            dir.path().join("resolv.conf"),
+            Some(dir.path().join("hosts")),
+        );
+        assert!(
+            allowlist
+                .allows("10.244.0.7:8080".parse()?, Some(&resolver))
+                .await
+        );
+        assert!(
+            !allowlist
+                .allows("127.0.0.1:8080".parse()?, Some(&resolver))
+                .await
+        );
+
+        for value in [
+            "10.0.0.0/8",
+            "10.0.0.0/33:443",
+            "fd00::1:53",
+            "[10.0.0.1]:80",
+            "example.com:http",
+        ] {
+            let err = NetAllowlist::from_spec(&spec_with(value)?).unwrap_err();
+            assert!(
+                err.to_string().contains(NET_ALLOW_ANNOTATION),
+                "{value}: {err}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_ports() -> Result<()> {
+        let image_config = oci_spec::image::ConfigBuilder::default()
+            .exposed_ports(vec!["8080/tcp".to_string(), "53/udp".to_string()])
+            .build()?;
+        let layers = [WasmLayer {
+            layer: vec![].into(),
+            wasm_config: None,
+            image_config: Some(image_config),
+            run_config: None,
+            kind: None,
+            config: Descriptor::new(
+                oci_spec::image::MediaType::Other("".to_string()),
+                10,
+                Digest::try_from(format!("sha256:{:064?}", 0))?,
+            ),
+        }];
+        let spec_with = |value: &str| spec_with_annotations(&[(LISTEN_ANNOTATION, value)]);
+
+        assert_eq!(
+            listen_ports(&SpecBuilder::default().build()?, &layers)?,
+            [8080]
+        );
+        // the annotation wins over the image
+        assert_eq!(
+            listen_ports(&spec_with("9090, 9091/tcp")?, &layers)?,
+            [9090, 9091]
+        );
+        assert!(listen_ports(&spec_with("")?, &layers)?.is_empty());
+        for value in ["0", "65536", "53/udp", "http"] {
+            let err = listen_ports(&spec_with(value)?, &[]).unwrap_err();
+            assert!(
+                err.to_string().contains(LISTEN_ANNOTATION),
+                "{value}: {err}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listeners() -> Result<()> {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        let listeners = Listeners::bind(&[port])?;
+        assert_eq!(listeners.ports(), [port]);
+
+        let err = Listeners::bind(&[port]).unwrap_err();
+        assert!(err.to_string().contains(&port.to_string()), "{err}");
+
+        assert!(listeners.take(port).is_some());
+        assert!(listeners.take(port).is_none());
+        assert!(listeners.ports().is_empty());
+
+        // closing frees the port
+        let listeners = Listeners::bind(&[port])?;
+        listeners.close();
+        assert!(listeners.ports().is_empty());
+        Listeners::bind(&[port])?;
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_envs_return_empty() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -436,6 +2447,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let envs = ctx.envs();
@@ -454,6 +2471,12 @@ mod tests {
         let ctx = WasiContext {
             spec: &spec,
             wasm_layers: &[],
+            preopens: &[],
+            precompiled_extensions: &[],
+            entrypoint_file: None,
+            started: None,
+            listeners: None,
+            wasi_config: None,
         };
 
         let envs = ctx.envs();
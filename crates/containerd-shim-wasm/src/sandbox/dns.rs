@@ -0,0 +1,520 @@
+//! Name resolution of the guests with the `resolv.conf` and `hosts` of the pod.
+//!
+//! The kubelet writes the resolver configuration of each pod, with the cluster DNS and the
+//! search domains of its namespace, and bind-mounts it at `/etc/resolv.conf` of its
+//! containers, along with its `/etc/hosts`. Engines resolve the names of the guests with a
+//! [`Resolver`] of those mounts, which reads them again when they change, e.g., when the pod is
+//! moved to another DNS server. The nameservers are asked with `hickory-resolver`, configured
+//! with the nameservers, the search domains and the options of the `resolv.conf`.
+
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::{Name, TokioAsyncResolver};
+use oci_spec::runtime::Spec;
+
+/// The path of the resolver configuration in the containers.
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// The path of the static host names in the containers.
+pub const HOSTS_PATH: &str = "/etc/hosts";
+
+const DNS_PORT: u16 = 53;
+
+/// The resolver configuration of a `resolv.conf` file, with the defaults of glibc for what it
+/// doesn't set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvConf {
+    /// The addresses of the `nameserver` lines.
+    pub nameservers: Vec<IpAddr>,
+    /// The domains of the `search` line, which relative names are looked up in.
+    pub search: Vec<String>,
+    /// The `ndots` option: names with fewer dots are looked up in the search domains first.
+    pub ndots: usize,
+    /// The `timeout` option: the time to wait for a nameserver to answer.
+    pub timeout: Duration,
+    /// The `attempts` option: the number of times each nameserver is asked.
+    pub attempts: usize,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        Self {
+            nameservers: vec![],
+            search: vec![],
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+        }
+    }
+}
+
+impl ResolvConf {
+    /// Parses the `content` of a `resolv.conf` file. Like glibc, the lines it doesn't
+    /// understand are ignored, and the last `search` or `domain` line wins.
+    pub fn parse(content: &str) -> Self {
+        let mut conf = Self::default();
+        for line in content.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    // skipping the ones with a scope id, e.g., `fe80::1%eth0`
+                    let addr = words.next().and_then(|addr| addr.parse::<IpAddr>().ok());
+                    conf.nameservers.extend(addr);
+                }
+                Some("search") => {
+                    conf.search = words
+                        .map(|domain| domain.trim_end_matches('.'))
+                        .map(str::to_string)
+                        .collect();
+                }
+                Some("domain") => {
+                    conf.search = words.take(1).map(str::to_string).collect();
+                }
+                Some("options") => {
+                    for option in words {
+                        conf.set_option(option);
+                    }
+                }
+                _ => {}
+            }
+        }
+        conf
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let Some((name, value)) = option.split_once(':') else {
+            return;
+        };
+        let Ok(value) = value.parse::<usize>() else {
+            return;
+        };
+        // the bounds of glibc
+        match name {
+            "ndots" => self.ndots = value.min(15),
+            "timeout" => self.timeout = Duration::from_secs(value.clamp(1, 30) as u64),
+            "attempts" => self.attempts = value.clamp(1, 5),
+            _ => {}
+        }
+    }
+}
+
+/// The addresses of the host names of a `hosts` file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hosts(Vec<(IpAddr, Vec<String>)>);
+
+impl Hosts {
+    /// Parses the `content` of a `hosts` file, ignoring the lines it doesn't understand.
+    pub fn parse(content: &str) -> Self {
+        let entries = content
+            .lines()
+            .filter_map(|line| {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut words = line.split_whitespace();
+                let addr = words.next()?.parse().ok()?;
+                let names: Vec<_> = words.map(str::to_ascii_lowercase).collect();
+                (!names.is_empty()).then_some((addr, names))
+            })
+            .collect();
+        Self(entries)
+    }
+
+    /// Returns the addresses of `name`, in the order of the file.
+    pub fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.0
+            .iter()
+            .filter(|(_, names)| names.contains(&name))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// A resolver of the host names of the guest with the `resolv.conf` and the `hosts` files
+/// mounted in the container, which are only visible to the engine once it runs in the
+/// container. It reads them again when they change.
+#[derive(Debug)]
+pub struct Resolver {
+    resolv_conf: PathBuf,
+    hosts: Option<PathBuf>,
+    port: u16,
+    loaded: Mutex<Option<Loaded>>,
+}
+
+#[derive(Debug, Clone)]
+struct Loaded {
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    resolv_conf: ResolvConf,
+    hosts: Hosts,
+    // the resolver of the nameservers of `resolv_conf`, with its cache
+    resolver: TokioAsyncResolver,
+}
+
+impl Resolver {
+    /// Returns the resolver of the `resolv.conf` and the `hosts` mounted in the container with
+    /// runtime spec `spec`, or `None` if it has no `resolv.conf` mounted, in which case the
+    /// engine keeps the resolver of the host.
+    pub fn from_spec(spec: &Spec) -> Option<Self> {
+        let mounted = |path: &str| {
+            spec.mounts()
+                .iter()
+                .flatten()
+                .any(|mount| mount.destination() == Path::new(path))
+        };
+        if !mounted(RESOLV_CONF_PATH) {
+            log::debug!("no {RESOLV_CONF_PATH} mounted, using the resolver of the host");
+            return None;
+        }
+        let hosts = mounted(HOSTS_PATH).then(|| PathBuf::from(HOSTS_PATH));
+        Some(Self::new(RESOLV_CONF_PATH, hosts))
+    }
+
+    pub(crate) fn new(resolv_conf: impl Into<PathBuf>, hosts: Option<PathBuf>) -> Self {
+        Self {
+            resolv_conf: resolv_conf.into(),
+            hosts,
+            port: DNS_PORT,
+            loaded: Mutex::new(None),
+        }
+    }
+
+    /// Returns the configuration of the files, read again if they changed since the last call.
+    pub fn config(&self) -> (ResolvConf, Hosts) {
+        let loaded = self.load();
+        (loaded.resolv_conf, loaded.hosts)
+    }
+
+    fn load(&self) -> Loaded {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        let modified = (
+            modified(&self.resolv_conf),
+            self.hosts.as_deref().and_then(modified),
+        );
+
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some(loaded) = loaded.as_ref().filter(|loaded| loaded.modified == modified) {
+            return loaded.clone();
+        }
+        let read = |path: &Path| match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                log::debug!("failed to read {path:?}: {err}");
+                String::new()
+            }
+        };
+        let resolv_conf = ResolvConf::parse(&read(&self.resolv_conf));
+        let hosts = self.hosts.as_deref().map(read).unwrap_or_default();
+        let resolver = resolver(&resolv_conf, self.port);
+        loaded
+            .insert(Loaded {
+                modified,
+                resolv_conf,
+                hosts: Hosts::parse(&hosts),
+                resolver,
+            })
+            .clone()
+    }
+
+    /// Returns the addresses of the host `name`, from the `hosts` file, or else from the
+    /// nameservers of the `resolv.conf` file, in the search domains of the `resolv.conf`.
+    pub async fn lookup(&self, name: &str) -> IoResult<Vec<IpAddr>> {
+        if let Ok(addr) = name.parse::<IpAddr>() {
+            return Ok(vec![addr]);
+        }
+        let loaded = self.load();
+        let addrs = loaded.hosts.lookup(name);
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+        if loaded.resolv_conf.nameservers.is_empty() {
+            return Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("failed to resolve {name:?}: no nameserver in resolv.conf"),
+            ));
+        }
+
+        // the lookups of hickory can't be shared between threads, unlike the checks of the
+        // addresses of the guests that await them, so they run in their own task
+        let resolver = loaded.resolver.clone();
+        let fqdn = name.to_string();
+        let lookup = tokio::spawn(async move { resolver.lookup_ip(fqdn).await });
+        match lookup.await.map_err(IoError::other)? {
+            Ok(lookup) => Ok(lookup.iter().collect()),
+            Err(err) => {
+                let kind = match err.kind() {
+                    ResolveErrorKind::NoRecordsFound { .. } => ErrorKind::NotFound,
+                    ResolveErrorKind::Timeout => ErrorKind::TimedOut,
+                    _ => ErrorKind::Other,
+                };
+                Err(IoError::new(
+                    kind,
+                    format!("failed to resolve {name:?}: {err}"),
+                ))
+            }
+        }
+    }
+}
+
+// Returns a resolver asking the nameservers of `conf` on `port`, with its search domains and
+// options. The `hosts` of the container are looked up before, rather than the ones of the host.
+fn resolver(conf: &ResolvConf, port: u16) -> TokioAsyncResolver {
+    let search = conf
+        .search
+        .iter()
+        .filter_map(|domain| match Name::from_str(domain) {
+            Ok(domain) => Some(domain),
+            Err(err) => {
+                log::debug!("skipping the invalid search domain {domain:?}: {err}");
+                None
+            }
+        })
+        .collect();
+    // answers that are truncated over UDP are asked again over TCP
+    let nameservers = NameServerConfigGroup::from_ips_clear(&conf.nameservers, port, true);
+    let config = ResolverConfig::from_parts(None, search, nameservers);
+
+    let mut opts = ResolverOpts::default();
+    opts.ndots = conf.ndots;
+    opts.timeout = conf.timeout;
+    opts.attempts = conf.attempts;
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    opts.use_hosts_file = false;
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use anyhow::Result;
+    use oci_spec::runtime::{MountBuilder, SpecBuilder};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    const KUBERNETES_RESOLV_CONF: &str = "\
+# written by the kubelet
+search default.svc.cluster.local svc.cluster.local cluster.local
+nameserver 10.96.0.10
+options ndots:5 timeout:2
+";
+
+    #[test]
+    fn test_resolv_conf() {
+        let conf = ResolvConf::parse(KUBERNETES_RESOLV_CONF);
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec![IpAddr::from([10, 96, 0, 10])],
+                search: vec![
+                    "default.svc.cluster.local".to_string(),
+                    "svc.cluster.local".to_string(),
+                    "cluster.local".to_string(),
+                ],
+                ndots: 5,
+                timeout: Duration::from_secs(2),
+                attempts: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_hosts() {
+        let hosts = Hosts::parse(
+            "127.0.0.1 localhost\n::1 localhost ip6-localhost\n# kubelet\n10.244.0.7 web-0\n",
+        );
+        assert_eq!(
+            hosts.lookup("localhost"),
+            [
+                IpAddr::from([127, 0, 0, 1]),
+                IpAddr::from(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert_eq!(hosts.lookup("WEB-0."), [IpAddr::from([10, 244, 0, 7])]);
+        assert!(hosts.lookup("web-1").is_empty());
+    }
+
+    #[test]
+    fn test_from_spec() -> Result<()> {
+        let mount = MountBuilder::default()
+            .destination(RESOLV_CONF_PATH)
+            // typos:disable-next-line - false positive "typ"
+            .typ("bind")
+            .source("/var/lib/containerd/sandboxes/resolv.conf")
+            .build()?;
+        let spec = SpecBuilder::default().mounts(vec![mount]).build()?;
+        let resolver = Resolver::from_spec(&spec).unwrap();
+        assert_eq!(resolver.resolv_conf, Path::new(RESOLV_CONF_PATH));
+        assert_eq!(resolver.hosts, None);
+
+        assert!(Resolver::from_spec(&SpecBuilder::default().build()?).is_none());
+
+        Ok(())
+    }
+
+    const TYPE_A: u16 = 1;
+
+    // The answer to `query` of a nameserver knowing the service `web` only by its fully
+    // qualified name, without its address if `truncate`
+    fn answer(query: &[u8], truncate: bool) -> Vec<u8> {
+        let mut name = vec![];
+        let mut at = 12;
+        while query[at] != 0 {
+            let len = usize::from(query[at]);
+            name.push(String::from_utf8_lossy(&query[at + 1..at + 1 + len]).to_string());
+            at += 1 + len;
+        }
+        let record_type = u16::from_be_bytes([query[at + 1], query[at + 2]]);
+        let found = name.join(".") == "web.default.svc.cluster.local";
+
+        // the header and the question of the query, without its additional records
+        let mut answer = query[..at + 5].to_vec();
+        answer[10..12].fill(0);
+        // a response, and NXDOMAIN for the other names
+        answer[2] |= 0x80;
+        answer[3] = if found { 0 } else { 3 };
+        if found && record_type == TYPE_A {
+            if truncate {
+                answer[2] |= 0x02;
+                return answer;
+            }
+            answer[7] = 1;
+            // a pointer to the name of the question
+            answer.extend([0xc0, 12]);
+            answer.extend(TYPE_A.to_be_bytes());
+            answer.extend([0, 1, 0, 0, 0, 30, 0, 4, 10, 96, 12, 34]);
+        }
+        answer
+    }
+
+    async fn serve_udp(socket: UdpSocket, truncate: bool) -> IoResult<()> {
+        let mut buf = [0; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            socket.send_to(&answer(&buf[..len], truncate), peer).await?;
+        }
+    }
+
+    async fn serve_tcp(listener: TcpListener) -> IoResult<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(serve_tcp_stream(stream));
+        }
+    }
+
+    // The messages over TCP are prefixed with their length
+    async fn serve_tcp_stream(mut stream: TcpStream) -> IoResult<()> {
+        loop {
+            let mut query = vec![0; usize::from(stream.read_u16().await?)];
+            stream.read_exact(&mut query).await?;
+            let answer = answer(&query, false);
+            stream.write_u16(answer.len() as u16).await?;
+            stream.write_all(&answer).await?;
+        }
+    }
+
+    // Serves the nameserver on the same UDP and TCP port, which it returns, until the tasks are
+    // dropped
+    async fn nameserver(truncate: bool) -> Result<(u16, JoinSet<IoResult<()>>)> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = socket.local_addr()?.port();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let mut tasks = JoinSet::new();
+        tasks.spawn(serve_udp(socket, truncate));
+        tasks.spawn(serve_tcp(listener));
+        Ok((port, tasks))
+    }
+
+    #[tokio::test]
+    async fn test_lookup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let resolv_conf = dir.path().join("resolv.conf");
+        let hosts = dir.path().join("hosts");
+        std::fs::write(
+            &resolv_conf,
+            KUBERNETES_RESOLV_CONF.replace("10.96.0.10", "127.0.0.1"),
+        )?;
+        std::fs::write(&hosts, "10.244.0.7 web-0\n")?;
+
+        let (port, _nameserver) = nameserver(false).await?;
+        let mut resolver = Resolver::new(&resolv_conf, Some(hosts.clone()));
+        resolver.port = port;
+
+        // expanded with the search domains of the namespace
+        assert_eq!(
+            resolver.lookup("web").await?,
+            [IpAddr::from([10, 96, 12, 34])]
+        );
+        assert_eq!(
+            resolver.lookup("web.default.svc.cluster.local.").await?,
+            [IpAddr::from([10, 96, 12, 34])]
+        );
+        assert_eq!(
+            resolver.lookup("web-0").await?,
+            [IpAddr::from([10, 244, 0, 7])]
+        );
+        let err = resolver.lookup("db").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound, "{err}");
+
+        // the kubelet rewrites the files
+        std::fs::write(&hosts, "10.244.0.8 web-0\n")?;
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        std::fs::File::options()
+            .write(true)
+            .open(&hosts)?
+            .set_modified(modified)?;
+        assert_eq!(
+            resolver.lookup("web-0").await?,
+            [IpAddr::from([10, 244, 0, 8])]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_over_tcp_when_truncated() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let resolv_conf = dir.path().join("resolv.conf");
+        std::fs::write(
+            &resolv_conf,
+            KUBERNETES_RESOLV_CONF.replace("10.96.0.10", "127.0.0.1"),
+        )?;
+
+        let (port, _nameserver) = nameserver(true).await?;
+        let mut resolver = Resolver::new(&resolv_conf, None);
+        resolver.port = port;
+
+        // the answer over UDP has no address
+        assert_eq!(
+            resolver.lookup("web").await?,
+            [IpAddr::from([10, 96, 12, 34])]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_without_nameservers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let resolv_conf = dir.path().join("resolv.conf");
+        std::fs::write(&resolv_conf, "search cluster.local\n")?;
+
+        let resolver = Resolver::new(&resolv_conf, None);
+        let err = resolver.lookup("web").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound, "{err}");
+        assert!(err.to_string().contains("no nameserver"), "{err}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! The errors of the instances, by the phase of their creation that failed.
+//!
+//! The shim reports them to containerd as [`SandboxError`]s, with the [`Error::code`] of the
+//! phase at the start of their message, e.g., `[spec] invalid io.runwasi.allow annotation: ...`,
+//! so that the clients of containerd, which only get the message, can tell them apart with
+//! [`code_of`]. The [`SandboxError`]s they wrap keep their variant, and so their ttrpc code.
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use thiserror::Error;
+
+/// An error of the creation or the run of an instance.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The image of the container couldn't be resolved to its wasm layers, e.g., because
+    /// containerd is unreachable, the image is denied or its layers are invalid.
+    #[error(transparent)]
+    ImageResolution(SandboxError),
+    /// A wasm layer failed to compile, e.g., because the engine doesn't validate it.
+    #[error("failed to precompile layer {digest}: {message}")]
+    Compilation { digest: String, message: String },
+    /// The runtime spec of the container is invalid, e.g., one of its annotations.
+    #[error(transparent)]
+    Spec(SandboxError),
+    /// The container couldn't be set up, e.g., its cgroup, namespaces or hooks.
+    #[error(transparent)]
+    ContainerSetup(SandboxError),
+    /// The guest failed, with the trap it failed with, if it trapped.
+    #[error("{message}")]
+    Runtime {
+        message: String,
+        trap: Option<String>,
+    },
+    /// An I/O error, e.g., on the stdio of the container.
+    #[error("{context}: {error}")]
+    Io {
+        context: String,
+        error: std::io::Error,
+    },
+}
+
+impl Error {
+    /// Returns the machine-readable code of the error, which the shim adds to its message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ImageResolution(_) => "image-resolution",
+            Self::Compilation { .. } => "compilation",
+            Self::Spec(_) => "spec",
+            Self::ContainerSetup(_) => "container-setup",
+            Self::Runtime { .. } => "runtime",
+            Self::Io { .. } => "io",
+        }
+    }
+
+    /// Returns whether the call that failed with the error may succeed if it's retried, i.e.,
+    /// if it failed because containerd was unavailable.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::ImageResolution(err) => crate::containerd::is_transient(err),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the code of the error reported to containerd with `message`, if it's an [`Error`].
+pub fn code_of(message: &str) -> Option<&str> {
+    // e.g., after the `invalid argument: ` of the `Display` of `SandboxError`
+    let start = message.find('[')?;
+    let (code, _) = message[start + 1..].split_once("] ")?;
+    code.bytes()
+        .all(|b| b.is_ascii_lowercase() || b == b'-')
+        .then_some(code)
+}
+
+impl From<Error> for SandboxError {
+    fn from(err: Error) -> Self {
+        let code = err.code();
+        let tag = |message: &dyn std::fmt::Display| format!("[{code}] {message}");
+        match err {
+            Error::ImageResolution(err) | Error::Spec(err) | Error::ContainerSetup(err) => {
+                match err {
+                    Self::InvalidArgument(message) => Self::InvalidArgument(tag(&message)),
+                    Self::NotFound(message) => Self::NotFound(tag(&message)),
+                    Self::AlreadyExists(message) => Self::AlreadyExists(tag(&message)),
+                    Self::FailedPrecondition(message) => Self::FailedPrecondition(tag(&message)),
+                    Self::PermissionDenied(message) => Self::PermissionDenied(tag(&message)),
+                    Self::Containerd(message) => Self::Containerd(tag(&message)),
+                    Self::Others(message) => Self::Others(tag(&message)),
+                    Self::Stdio(err) => Self::Stdio(std::io::Error::new(err.kind(), tag(&err))),
+                    // their message is built from their fields, which say what failed already
+                    err @ (Self::DigestMismatch { .. }
+                    | Self::Timeout { .. }
+                    | Self::SignatureVerification { .. }
+                    | Self::ImageDenied { .. }
+                    | Self::Shim(_)) => err,
+                    err => Self::Others(tag(&err)),
+                }
+            }
+            err @ Error::Compilation { .. } => Self::InvalidArgument(tag(&err)),
+            err @ Error::Runtime { .. } => Self::Others(tag(&err)),
+            Error::Io { context, error } => {
+                let kind = error.kind();
+                Self::Stdio(std::io::Error::new(
+                    kind,
+                    tag(&format!("{context}: {error}")),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_into_sandbox_error() {
+        let err: SandboxError = Error::Spec(SandboxError::InvalidArgument(
+            "invalid io.runwasi.allow annotation: \"gpu\"".to_string(),
+        ))
+        .into();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        assert_eq!(
+            err.to_string(),
+            "invalid argument: [spec] invalid io.runwasi.allow annotation: \"gpu\""
+        );
+        assert_eq!(code_of(&err.to_string()), Some("spec"));
+
+        let err: SandboxError = Error::Compilation {
+            digest: "sha256:1".to_string(),
+            message: "invalid opcode".to_string(),
+        }
+        .into();
+        assert_eq!(
+            err.to_string(),
+            "invalid argument: [compilation] failed to precompile layer sha256:1: invalid opcode"
+        );
+
+        let err: SandboxError = Error::Io {
+            context: "failed to open the stdout of instance test".to_string(),
+            error: ErrorKind::NotFound.into(),
+        }
+        .into();
+        let SandboxError::Stdio(err) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(code_of(&err.to_string()), Some("io"));
+
+        // the timeouts keep their variant, which is already machine-readable
+        let err: SandboxError = Error::ImageResolution(SandboxError::Timeout {
+            what: "load the wasm layers".to_string(),
+            timeout: Duration::from_secs(120),
+        })
+        .into();
+        assert!(matches!(err, SandboxError::Timeout { .. }), "{err}");
+        assert_eq!(code_of(&err.to_string()), None);
+    }
+
+    #[test]
+    fn test_code_of() {
+        assert_eq!(
+            code_of("[image-resolution] no image for container test"),
+            Some("image-resolution")
+        );
+        for message in ["", "invalid argument: [0] x", "[spec]x", "the [spec"] {
+            assert_eq!(code_of(message), None, "{message}");
+        }
+    }
+}
@@ -1,47 +1,148 @@
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use context::{RuntimeContext, Source};
 use path::PathResolve as _;
+use serde::Serialize;
 
 pub mod context;
+pub mod dns;
+pub mod error;
 pub(crate) mod path;
+pub mod wasi_config;
+
+/// Checks that the wasi_entrypoint of the container is either:
+/// * a OCI image with wasm layers
+/// * a file with the `wasm` filetype header
+/// * a parsable `wat` file
+/// * a file precompiled by the engine, see [`RuntimeContext::is_precompiled_file`], which the
+///   engine checks when loading it.
+///
+/// Engines that override [`Sandbox::can_handle`] with checks of their own can call it to keep
+/// the default ones.
+pub fn check_entrypoint(ctx: &impl RuntimeContext) -> Result<()> {
+    let source = ctx.entrypoint().source;
+
+    let path = match source {
+        Source::File(path) => path,
+        Source::Oci(_) => return Ok(()),
+    };
+
+    path.resolve_in_path_or_cwd()
+        .next()
+        .context("module not found")?;
+
+    if ctx.is_precompiled_file() {
+        return Ok(());
+    }
+
+    let mut buffer = [0; 4];
+    File::open(&path)?.read_exact(&mut buffer)?;
+
+    if buffer.as_slice() != b"\0asm" {
+        // Check if this is a `.wat` file
+        wat::parse_file(&path)?;
+    }
+
+    Ok(())
+}
 
 #[trait_variant::make(Send)]
 pub trait Sandbox: Default + 'static {
+    /// Create the sandbox of the container, e.g., to configure the engine with the
+    /// [`RuntimeContext::stack_limits`] of the container.
+    /// The default implementation uses [`Default`].
+    fn new(_ctx: &impl RuntimeContext) -> Self {
+        Self::default()
+    }
+
     /// Run a WebAssembly container
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32>;
 
     /// Check that the runtime can run the container.
     /// This checks runs after the container creation and before the container starts.
-    /// By default it checks that the wasi_entrypoint is either:
-    /// * a OCI image with wasm layers
-    /// * a file with the `wasm` filetype header
-    /// * a parsable `wat` file.
+    /// By default it runs [`check_entrypoint`].
     async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
         // this async block is required to make the rewrite of trait_variant happy
-        async move {
-            let source = ctx.entrypoint().source;
+        async move { check_entrypoint(ctx) }
+    }
 
-            let path = match source {
-                Source::File(path) => path,
-                Source::Oci(_) => return Ok(()),
-            };
+    /// Serialize the state of the running instance (linear memories, globals, tables)
+    /// into the `dir` directory, so that it can later be resumed with [`Sandbox::restore`].
+    /// This is called concurrently with [`Sandbox::run_wasi`], from the same process.
+    /// If some of the WASI state can't be serialized (e.g., open host files or sockets),
+    /// this should fail with a [`CheckpointBlocked`] error.
+    /// The default implementation doesn't support checkpointing.
+    async fn checkpoint(&self, _ctx: &impl RuntimeContext, _dir: &Path) -> Result<()> {
+        async move { bail!("checkpoint is not supported by this engine") }
+    }
 
-            path.resolve_in_path_or_cwd()
-                .next()
-                .context("module not found")?;
+    /// Ask the running instance to stop, e.g., by interrupting the guest or by signalling it
+    /// to shut down. This is called concurrently with [`Sandbox::run_wasi`] when the container
+    /// receives `SIGTERM`, and [`Sandbox::run_wasi`] is expected to return shortly after.
+    /// If it returns an error, the container exits with the exit code of `SIGTERM`.
+    /// The default implementation doesn't support graceful termination, and the container
+    /// exits right away.
+    async fn terminate(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        async move { bail!("graceful termination is not supported by this engine") }
+    }
 
-            let mut buffer = [0; 4];
-            File::open(&path)?.read_exact(&mut buffer)?;
+    /// Rebuild the instance from the state serialized by [`Sandbox::checkpoint`] in the
+    /// `dir` directory and resume it. This is called instead of [`Sandbox::run_wasi`].
+    async fn restore(&self, _ctx: &impl RuntimeContext, _dir: &Path) -> Result<i32> {
+        async move { bail!("restoring from a checkpoint is not supported by this engine") }
+    }
 
-            if buffer.as_slice() != b"\0asm" {
-                // Check if this is a `.wat` file
-                wat::parse_file(&path)?;
-            }
+    /// Check that the container could run without running any of its code, for the
+    /// [`VALIDATE_ONLY_ANNOTATION`](context::VALIDATE_ONLY_ANNOTATION): load the entrypoint,
+    /// resolve its imports against the ones the engine provides, and resolve the function to
+    /// call. This is called instead of [`Sandbox::run_wasi`]. What prevents the guest from
+    /// running is reported in the [`Validation`], and an error means it couldn't be validated.
+    /// Engines that implement it declare it with
+    /// [`Shim::supports_validation`](crate::shim::Shim::supports_validation).
+    /// The default implementation doesn't support validation.
+    async fn validate(&self, _ctx: &impl RuntimeContext) -> Result<Validation> {
+        async move { bail!("validation is not supported by this engine") }
+    }
+}
+
+/// The result of [`Sandbox::validate`], written as JSON on the stdout of the container.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Validation {
+    /// The WASI world the guest targets, e.g., `wasi:cli/command`, once the entrypoint is loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub world: Option<String>,
+    /// Each import of the guest that the engine doesn't provide, e.g., `env::now`.
+    pub missing_imports: Vec<String>,
+    /// The other reasons the guest can't run, e.g., that it doesn't export the function to call.
+    pub errors: Vec<String>,
+}
 
-            Ok(())
-        }
+impl Validation {
+    /// Returns whether the guest can run.
+    pub fn is_valid(&self) -> bool {
+        self.missing_imports.is_empty() && self.errors.is_empty()
     }
 }
+
+/// Error returned by [`Sandbox::checkpoint`] listing the state that prevented the checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointBlocked {
+    /// Description of each piece of state that can't be serialized, e.g., "socket on fd 4".
+    pub blockers: Vec<String>,
+}
+
+impl Display for CheckpointBlocked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checkpoint blocked by state that can't be serialized: {}",
+            self.blockers.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for CheckpointBlocked {}
@@ -1,4 +1,19 @@
-use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::bail;
+
+/// The start of an indirection file, e.g., `#!wasm /app/module.wasm`, with the path of the
+/// module to run instead of it.
+pub(crate) const WASM_SHEBANG: &[u8] = b"#!wasm ";
+
+// The most symlinks followed to resolve a path, like Linux
+const MAX_SYMLINKS: usize = 40;
+
+// The largest indirection file, which has a single line
+const MAX_INDIRECTION_SIZE: u64 = 4096;
 
 /// PathResolve allows to resolve a file path in a set of directories.
 pub(crate) trait PathResolve {
@@ -73,3 +88,221 @@ impl<T: AsRef<Path>> PathResolve for T {
         self.resolve_in_dirs(paths().chain(std::env::current_dir().ok()))
     }
 }
+
+/// Resolves `path` in `root` as if `root` was the root of the filesystem: the symlinks are
+/// followed with their absolute targets in `root` too, and `..` never goes above it.
+/// `path` is absolute, or relative to `root`, and the result may not exist.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    fn components(path: &Path) -> impl DoubleEndedIterator<Item = OsString> + '_ {
+        path.components().map(|c| c.as_os_str().to_owned())
+    }
+
+    // the path under `root`, and the components left to resolve, last first
+    let mut resolved = PathBuf::new();
+    let mut pending: Vec<_> = components(path).rev().collect();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop() {
+        match Path::new(&component).components().next() {
+            Some(Component::RootDir) => resolved.clear(),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                resolved.push(name);
+                let full = root.join(&resolved);
+                if !full
+                    .symlink_metadata()
+                    .is_ok_and(|m| m.file_type().is_symlink())
+                {
+                    continue;
+                }
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(IoError::other(format!(
+                        "more than {MAX_SYMLINKS} symlinks in {path:?}"
+                    )));
+                }
+                let target = std::fs::read_link(&full)?;
+                resolved.pop();
+                pending.extend(components(&target).rev());
+            }
+            Some(Component::CurDir | Component::Prefix(_)) | None => {}
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+/// Resolves the entrypoint `arg0` of a container without wasm layers in its rootfs `root`:
+/// * a path with a separator is relative to `cwd`, while a bare name is searched in the
+///   directories of `search_path`, the `PATH` of the container, and then in `cwd`
+/// * the symlinks are followed inside `root`, see [`resolve_in_root`]
+/// * a `#!wasm /app/module.wasm` indirection file, see [`WASM_SHEBANG`], resolves to the
+///   module in it, which is relative to the directory of the file if it's not absolute.
+///
+/// Each step is logged at debug, and the error has all the paths that were tried.
+pub(crate) fn resolve_entrypoint(
+    root: &Path,
+    arg0: &Path,
+    search_path: Option<&str>,
+    cwd: &Path,
+) -> anyhow::Result<PathBuf> {
+    let cwd = Path::new("/").join(cwd);
+    let candidates: Vec<_> = if arg0.components().count() > 1 {
+        vec![cwd.join(arg0)]
+    } else {
+        search_path
+            .map(std::env::split_paths)
+            .into_iter()
+            .flatten()
+            .chain([PathBuf::new()])
+            .map(|dir| cwd.join(dir).join(arg0))
+            .collect()
+    };
+
+    let mut tried = vec![];
+    for candidate in candidates {
+        let file = match resolve_file(root, &candidate) {
+            Ok(file) => file,
+            Err(err) => {
+                log::debug!("entrypoint {arg0:?}: skipping {candidate:?}: {err}");
+                tried.push(candidate);
+                continue;
+            }
+        };
+        let target = match indirection(&file) {
+            Ok(None) => {
+                log::debug!("entrypoint {arg0:?}: using {candidate:?}, resolved to {file:?}");
+                return Ok(file);
+            }
+            Ok(Some(target)) => target,
+            Err(err) => {
+                log::debug!("entrypoint {arg0:?}: skipping {candidate:?}: {err}");
+                tried.push(candidate);
+                continue;
+            }
+        };
+        // `candidate` has a parent, as it's joined to `cwd`
+        let target = candidate.parent().unwrap_or(&cwd).join(target);
+        log::debug!("entrypoint {arg0:?}: {candidate:?} is an indirection to {target:?}");
+        match resolve_file(root, &target) {
+            Ok(file) => {
+                log::debug!("entrypoint {arg0:?}: using {target:?}, resolved to {file:?}");
+                return Ok(file);
+            }
+            Err(err) => {
+                log::debug!("entrypoint {arg0:?}: skipping {target:?}: {err}");
+                tried.extend([candidate, target]);
+            }
+        }
+    }
+
+    let tried: Vec<_> = tried.iter().map(|path| format!("{path:?}")).collect();
+    bail!("entrypoint {arg0:?} not found, tried {}", tried.join(", "))
+}
+
+// Resolves `path` in `root`, failing if it's not a file
+fn resolve_file(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    let file = resolve_in_root(root, path)?;
+    if !file.is_file() {
+        return Err(IoError::new(
+            ErrorKind::NotFound,
+            format!("{file:?} is not a file"),
+        ));
+    }
+    Ok(file)
+}
+
+// Returns the path in the indirection file `path`, or `None` if it's not one
+fn indirection(path: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut head = vec![];
+    File::open(path)?
+        .take(MAX_INDIRECTION_SIZE)
+        .read_to_end(&mut head)?;
+    let Some(rest) = head.strip_prefix(WASM_SHEBANG) else {
+        return Ok(None);
+    };
+    let target = std::str::from_utf8(rest)
+        .ok()
+        .and_then(|rest| rest.lines().next())
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("{path:?} has no module after #!wasm"),
+            )
+        })?;
+    Ok(Some(PathBuf::from(target)))
+}
+
+#[cfg(unix)]
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_in_root() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        std::fs::create_dir_all(root.join("usr/local/bin"))?;
+        std::fs::create_dir_all(root.join("app"))?;
+        std::fs::write(root.join("app/app.wasm"), "")?;
+        symlink("/app/app.wasm", root.join("usr/local/bin/absolute"))?;
+        symlink("../../../app/app.wasm", root.join("usr/local/bin/relative"))?;
+        symlink(
+            "../../../../../etc/passwd",
+            root.join("usr/local/bin/escape"),
+        )?;
+        symlink("loop", root.join("usr/local/bin/loop"))?;
+
+        let resolve = |path: &str| resolve_in_root(root, Path::new(path));
+        assert_eq!(
+            resolve("/usr/local/bin/absolute")?,
+            root.join("app/app.wasm")
+        );
+        assert_eq!(
+            resolve("/usr/local/bin/relative")?,
+            root.join("app/app.wasm")
+        );
+        assert_eq!(resolve("/../../app/./app.wasm")?, root.join("app/app.wasm"));
+        // the symlinks never escape the root
+        assert_eq!(resolve("/usr/local/bin/escape")?, root.join("etc/passwd"));
+        assert!(resolve("/usr/local/bin/loop").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_entrypoint() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        std::fs::create_dir_all(root.join("usr/bin"))?;
+        std::fs::create_dir_all(root.join("app"))?;
+        std::fs::write(root.join("app/app.wasm"), "\0asm")?;
+        symlink("/app/app.wasm", root.join("usr/bin/app"))?;
+        std::fs::write(root.join("app/indirect"), "#!wasm app.wasm\n")?;
+        std::fs::write(root.join("app/dangling"), "#!wasm /missing.wasm\n")?;
+
+        let path = Some("/bin:/usr/bin");
+        let resolve =
+            |arg0: &str, cwd: &str| resolve_entrypoint(root, Path::new(arg0), path, Path::new(cwd));
+        let module = root.join("app/app.wasm");
+        assert_eq!(resolve("app", "/")?, module);
+        assert_eq!(resolve("/usr/bin/app", "/")?, module);
+        assert_eq!(resolve("./app.wasm", "/app")?, module);
+        assert_eq!(resolve("app.wasm", "/app")?, module);
+        assert_eq!(resolve("/app/indirect", "/")?, module);
+
+        // the error has every path that was tried
+        let err = resolve("missing", "/app").unwrap_err().to_string();
+        for tried in ["/bin/missing", "/usr/bin/missing", "/app/missing"] {
+            assert!(err.contains(tried), "{err}");
+        }
+        let err = resolve("/app/dangling", "/").unwrap_err().to_string();
+        assert!(err.contains("/missing.wasm"), "{err}");
+
+        Ok(())
+    }
+}
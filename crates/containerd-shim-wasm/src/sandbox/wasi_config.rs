@@ -0,0 +1,336 @@
+//! The configuration of the guests for `wasi:config/store`.
+//!
+//! The keys of the configuration are merged from, by increasing precedence:
+//!
+//! 1. the file of the [`CONFIG_FILE_ANNOTATION`], a JSON or TOML file mounted in the container,
+//!    e.g., from a `ConfigMap`, whose nested tables are flattened into dotted keys,
+//! 2. the env vars of the container with the [`CONFIG_ENV_PREFIX`], whose names are lowercased
+//!    without the prefix, e.g., `WASI_CONFIG_LOG_LEVEL=debug` for the key `log_level`,
+//! 3. the annotations with the [`CONFIG_ANNOTATION_PREFIX`], e.g.,
+//!    `io.runwasi.config.log_level=debug`.
+//!
+//! A value of `file://<path>` is the content of the file at the absolute `<path>` of the
+//! container instead, e.g., of a mounted `Secret`, so that secrets are never in the pod spec.
+//! The file is read every time the key is, so that the guest sees the secrets once they're
+//! rotated.
+//!
+//! The env vars are taken from the runtime spec, whether or not the guest is granted the `env`
+//! capability, which only decides whether the guest also sees them in its environment.
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+
+use anyhow::{Context as _, Result, bail};
+use oci_spec::runtime::Spec;
+use serde_json::Value;
+
+/// Prefix of the annotations setting a key of the configuration of the guest, followed by the
+/// key, e.g., `io.runwasi.config.log_level=debug`.
+pub const CONFIG_ANNOTATION_PREFIX: &str = "io.runwasi.config.";
+
+/// Annotation with the absolute path of a `.json` or `.toml` file of the configuration of the
+/// guest in the container, e.g., `io.runwasi.config-file=/etc/app/config.toml`.
+pub const CONFIG_FILE_ANNOTATION: &str = "io.runwasi.config-file";
+
+/// Prefix of the env vars setting a key of the configuration of the guest, followed by the key
+/// in upper case, e.g., `WASI_CONFIG_LOG_LEVEL=debug`.
+pub const CONFIG_ENV_PREFIX: &str = "WASI_CONFIG_";
+
+// The prefix of the values read from a file
+const FILE_PREFIX: &str = "file://";
+
+/// The configuration of a guest, see the [module](self) docs.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct WasiConfig {
+    // the values as set, with the `file://` ones not read yet
+    values: BTreeMap<String, String>,
+}
+
+// Only the keys, the values may be secrets
+impl Debug for WasiConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.values.keys()).finish()
+    }
+}
+
+impl WasiConfig {
+    /// Shim side: checks the annotations and the env vars of the configuration of `spec`,
+    /// without reading the files, which are only mounted in the container.
+    pub fn check(spec: &Spec) -> Result<()> {
+        if let Some(path) = config_file(spec) {
+            file_format(path).with_context(|| {
+                format!("invalid {CONFIG_FILE_ANNOTATION} annotation: {path:?}")
+            })?;
+        }
+        for (key, value) in annotations(spec) {
+            check_value(value).with_context(|| {
+                format!("invalid {CONFIG_ANNOTATION_PREFIX}{key} annotation: {value:?}")
+            })?;
+        }
+        for (key, value) in envs(spec) {
+            check_value(&value)
+                .with_context(|| format!("invalid value of the config key {key:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Executor side: merges the configuration of the guest of `spec`, with the file of the
+    /// [`CONFIG_FILE_ANNOTATION`] read from the root of the container.
+    pub fn from_spec(spec: &Spec) -> Result<Self> {
+        Self::check(spec)?;
+        let mut values = match config_file(spec) {
+            Some(path) => read_file(Path::new(path))
+                .with_context(|| format!("failed to read the config file {path:?}"))?,
+            None => BTreeMap::new(),
+        };
+        values.extend(envs(spec));
+        values.extend(annotations(spec).map(|(key, value)| (key.to_string(), value.to_string())));
+        Ok(Self { values })
+    }
+
+    /// Returns whether the configuration has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the value of `key`, or `None` if the configuration doesn't have it.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.values
+            .get(key)
+            .map(|value| resolve(key, value))
+            .transpose()
+    }
+
+    /// Returns all the keys and values of the configuration, sorted by key.
+    pub fn get_all(&self) -> Result<Vec<(String, String)>> {
+        self.values
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), resolve(key, value)?)))
+            .collect()
+    }
+}
+
+fn config_file(spec: &Spec) -> Option<&str> {
+    spec.annotations()
+        .as_ref()?
+        .get(CONFIG_FILE_ANNOTATION)
+        .map(String::as_str)
+}
+
+fn annotations(spec: &Spec) -> impl Iterator<Item = (&str, &str)> {
+    spec.annotations()
+        .iter()
+        .flatten()
+        .filter_map(|(key, value)| {
+            let key = key.strip_prefix(CONFIG_ANNOTATION_PREFIX)?;
+            Some((key, value.as_str()))
+        })
+        .filter(|(key, _)| !key.is_empty())
+}
+
+fn envs(spec: &Spec) -> impl Iterator<Item = (String, String)> {
+    spec.process()
+        .as_ref()
+        .and_then(|process| process.env().as_ref())
+        .into_iter()
+        .flatten()
+        .filter_map(|var| {
+            let (name, value) = var.split_once('=')?;
+            let key = name.strip_prefix(CONFIG_ENV_PREFIX)?;
+            (!key.is_empty()).then(|| (key.to_lowercase(), value.to_string()))
+        })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Toml,
+}
+
+fn file_format(path: &str) -> Result<Format> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        bail!("the path must be absolute");
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Ok(Format::Json),
+        Some("toml") => Ok(Format::Toml),
+        _ => bail!("expected a `.json` or `.toml` file"),
+    }
+}
+
+fn check_value(value: &str) -> Result<()> {
+    if let Some(path) = value.strip_prefix(FILE_PREFIX) {
+        if !Path::new(path).is_absolute() {
+            bail!("the path of a `{FILE_PREFIX}` value must be absolute");
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, String>> {
+    let content = std::fs::read_to_string(path)?;
+    // validated with the annotation
+    let table = match file_format(&path.to_string_lossy())? {
+        Format::Json => serde_json::from_str(&content)?,
+        Format::Toml => serde_json::to_value(toml::from_str::<toml::Table>(&content)?)?,
+    };
+    let Value::Object(table) = table else {
+        bail!("expected an object of keys");
+    };
+    let mut values = BTreeMap::new();
+    flatten("", table, &mut values)?;
+    for (key, value) in &values {
+        check_value(value).with_context(|| format!("invalid value of the config key {key:?}"))?;
+    }
+    Ok(values)
+}
+
+// Adds the values of `table` to `values`, with the keys of the nested tables joined with dots
+fn flatten(
+    prefix: &str,
+    table: serde_json::Map<String, Value>,
+    values: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        let value = match value {
+            Value::String(value) => value,
+            Value::Bool(value) => value.to_string(),
+            Value::Number(value) => value.to_string(),
+            Value::Object(table) => {
+                flatten(&format!("{key}."), table, values)?;
+                continue;
+            }
+            Value::Array(_) | Value::Null => {
+                bail!("the config key {key:?} isn't a string, a number or a boolean")
+            }
+        };
+        values.insert(key, value);
+    }
+    Ok(())
+}
+
+// Returns the value of `key`, with the content of its file if it's a `file://` value
+fn resolve(key: &str, value: &str) -> Result<String> {
+    let Some(path) = value.strip_prefix(FILE_PREFIX) else {
+        return Ok(value.to_string());
+    };
+    std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the file {path:?} of the config key {key:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{ProcessBuilder, SpecBuilder};
+
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec(annotations: &[(&str, &str)], env: &[&str]) -> Result<Spec> {
+        let mut spec = spec_with_annotations(annotations)?;
+        let env = env.iter().map(|var| var.to_string()).collect::<Vec<_>>();
+        spec.set_process(Some(ProcessBuilder::default().env(env).build()?));
+        Ok(spec)
+    }
+
+    #[test]
+    fn test_from_spec() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("config.toml");
+        std::fs::write(
+            &file,
+            "log_level = \"info\"\nport = 8080\n[db]\nhost = \"db\"\nuser = \"app\"\n",
+        )?;
+        let secret = dir.path().join("password");
+        std::fs::write(&secret, "s3cr3t")?;
+        let secret_value = format!("file://{}", secret.display());
+
+        let file_annotation = file.to_string_lossy();
+        let spec = spec(
+            &[
+                (CONFIG_FILE_ANNOTATION, &file_annotation),
+                ("io.runwasi.config.log_level", "debug"),
+                ("io.runwasi.config.db.password", &secret_value),
+                ("io.runwasi.other", "ignored"),
+            ],
+            &[
+                "WASI_CONFIG_PORT=9090",
+                "WASI_CONFIG_DB.USER=admin",
+                "PORT=1",
+            ],
+        )?;
+        let config = WasiConfig::from_spec(&spec)?;
+
+        // the annotations override the env vars, which override the file
+        assert_eq!(config.get("log_level")?.as_deref(), Some("debug"));
+        assert_eq!(config.get("port")?.as_deref(), Some("9090"));
+        assert_eq!(config.get("db.user")?.as_deref(), Some("admin"));
+        assert_eq!(config.get("db.host")?.as_deref(), Some("db"));
+        assert_eq!(config.get("db.password")?.as_deref(), Some("s3cr3t"));
+        assert_eq!(config.get("missing")?, None);
+        assert_eq!(
+            config.get_all()?,
+            [
+                ("db.host", "db"),
+                ("db.password", "s3cr3t"),
+                ("db.user", "admin"),
+                ("log_level", "debug"),
+                ("port", "9090"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+        assert!(!format!("{config:?}").contains("s3cr3t"));
+
+        // the secrets are read again once they're rotated
+        std::fs::write(&secret, "r0tated")?;
+        assert_eq!(config.get("db.password")?.as_deref(), Some("r0tated"));
+        std::fs::remove_file(&secret)?;
+        assert!(config.get("db.password").is_err());
+
+        assert!(WasiConfig::from_spec(&SpecBuilder::default().build()?)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("config.json");
+        std::fs::write(&file, r#"{"debug": true, "limits": {"rate": 1.5}}"#)?;
+        assert_eq!(
+            read_file(&file)?,
+            BTreeMap::from([
+                ("debug".to_string(), "true".to_string()),
+                ("limits.rate".to_string(), "1.5".to_string()),
+            ])
+        );
+
+        for content in [
+            r#"{"hosts": ["a", "b"]}"#,
+            "[]",
+            r#"{"key": "file://relative"}"#,
+        ] {
+            std::fs::write(&file, content)?;
+            assert!(read_file(&file).is_err(), "{content}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        for (annotation, value) in [
+            (CONFIG_FILE_ANNOTATION, "/etc/app/config.yaml"),
+            (CONFIG_FILE_ANNOTATION, "config.json"),
+            ("io.runwasi.config.password", "file://secret"),
+        ] {
+            let err = WasiConfig::check(&spec(&[(annotation, value)], &[])?).unwrap_err();
+            assert!(err.to_string().contains(annotation), "{err}");
+        }
+        assert!(WasiConfig::check(&spec(&[], &["WASI_CONFIG_KEY=file://key"])?).is_err());
+
+        // the file is only read by the executor
+        WasiConfig::check(&spec(&[(CONFIG_FILE_ANNOTATION, "/missing.toml")], &[])?)?;
+        Ok(())
+    }
+}
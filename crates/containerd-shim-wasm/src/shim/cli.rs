@@ -64,15 +64,47 @@
 //! MyShim::run(config);
 //! ```
 //!
+//! The settings of the shim, e.g., the timeouts of the calls to containerd, the layer cache
+//! and the policies of the images, are read from `/etc/containerd-shim-<engine>/config.toml`,
+//! or the file in `RUNWASI_CONFIG_FILE`, when it starts, see [`ShimConfig`]. Each of them can
+//! be overridden with its environment variable, e.g., `RUNWASI_LAYER_CACHE_SIZE`, or with the
+//! `options` of the runtime in the config of containerd, which have the same sections.
+//!
 //! When the `opentelemetry` feature is enabled, additional runtime config
 //! is available through environment variables:
 //!
 //! - `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`: Enable OpenTelemetry tracing
 //! - `OTEL_EXPORTER_OTLP_ENDPOINT`: Enable OpenTelemetry tracing as above
 //! - `OTEL_SDK_DISABLED`: Disable OpenTelemetry SDK
+//! - `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES`: Override the resource attributes
+//!   of the traces, which default to the name of the shim, its version, the engine name and
+//!   the node name
+//!
+//! The trace context of the requests of containerd is propagated to the spans of the shim,
+//! including the loading of the wasm layers and the compilation of each layer. Spans are
+//! exported in the background, and are dropped when the collector isn't reachable.
+//!
+//! ## Metrics
+//!
+//! Setting `RUNWASI_METRICS_ADDRESS` serves Prometheus metrics of the instances on
+//! `GET /metrics`, on a unix socket (`unix:/run/runwasi/metrics-{pid}.sock`, where `{pid}`
+//! is the pid of the shim) or a loopback address (`127.0.0.1:9100`). The metrics include
+//! the running instances, their startup latency and compile duration, the memory and CPU
+//! usage of their cgroups, the exits by class of exit code, and the hits of the layer cache.
+//! Each instance is labeled with its `container_id` and `pod_id`. `runwasi_build_info` is
+//! labeled with the [`Shim::engine_version`] and the version of the shim.
+//!
+//! ## Precompiling images
+//!
+//! The `precompile` subcommand compiles the wasm layers of images in the content store
+//! ahead of time, see [`precompile_image`](crate::shim::precompile_image):
+//!
+//! ```shell
+//! containerd-shim-wasmtime-v1 precompile --namespace k8s.io ghcr.io/containerd/runwasi/wasi-demo-app:latest
+//! ```
 //!
 
-use crate::shim::{Config, Instance, Shim};
+use crate::shim::{Config, Instance, Shim, ShimConfig};
 
 mod private {
     pub trait Sealed {}
@@ -91,6 +123,23 @@ pub trait Cli: Shim + private::Sealed {
 
 impl<S: Shim> Cli for S {
     fn run(config: impl Into<Option<Config>>) {
+        let shim_config = match ShimConfig::init::<S>() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("invalid {} shim configuration: {err:#}", S::name());
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = S::check_config(shim_config) {
+            eprintln!("invalid {} configuration: {err:#}", S::name());
+            std::process::exit(1);
+        }
+
+        #[cfg(unix)]
+        if std::env::args().nth(1).as_deref() == Some("precompile") {
+            crate::shim::precompile::main::<S>(std::env::args().skip(2));
+        }
+
         let config = config.into().unwrap_or_default();
         let config = containerd_shimkit::Config {
             no_setup_logger: config.no_setup_logger,
@@ -102,6 +151,10 @@ impl<S: Shim> Cli for S {
             S::name(),
             S::version(),
             Some(config),
-        )
+        );
+
+        // the metrics are served from the first instance until the shim exits
+        #[cfg(unix)]
+        crate::sys::metrics::shutdown();
     }
 }
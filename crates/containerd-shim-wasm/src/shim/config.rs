@@ -0,0 +1,831 @@
+//! The config file of the shim.
+//!
+//! The settings of the shim are read from `/etc/containerd-shim-<engine>/config.toml`, or the
+//! file in [`CONFIG_FILE_ENV`], once when the shim starts. Each setting has an environment
+//! variable that overrides it, e.g., `RUNWASI_LAYER_CACHE_SIZE` for `layers.cache_size`, for
+//! the setups where containerd runs in a container and its config files are hard to change.
+//!
+//! ```toml
+//! [containerd]
+//! connect_timeout = 10
+//!
+//! [layers]
+//! cache_size = 32
+//! strict = true
+//!
+//! [engine]
+//! cpu_features = "-has_avx512f"
+//! ```
+//!
+//! The keys of the `[engine]` section are the [`Shim::engine_config_keys`], which the engine
+//! reads with [`ShimConfig::engine_value`], and their variables are `RUNWASI_<ENGINE>_<KEY>`,
+//! e.g., `RUNWASI_WASMTIME_CPU_FEATURES`.
+//! Unknown keys are logged and ignored, and a value that's invalid fails the start of the shim
+//! with its key.
+//!
+//! The runtime options of the shim, i.e., the `options` of its runtime in the config of
+//! containerd, have the same sections, and override the file, so that runtimes can share the
+//! binary of the shim with different settings:
+//!
+//! ```toml
+//! [plugins."io.containerd.cri.v1.runtime".containerd.runtimes.wasmtime-debug]
+//! runtime_type = "io.containerd.wasmtime.v1"
+//!
+//! [plugins."io.containerd.cri.v1.runtime".containerd.runtimes.wasmtime-debug.options.engine]
+//! wasm_features = "+threads"
+//! ```
+//!
+//! The environment variables override both.
+//!
+//! The shim only reads the environment when it loads its config: the subsystems of the shim and
+//! the engines are handed the settings of the [`ShimConfig`] instead.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Once, OnceLock};
+
+use anyhow::{Context, Result, bail};
+use containerd_shimkit::sandbox::shim::runtime_options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::shim::Shim;
+
+/// Shim environment variable with the path of the config file, instead of
+/// `/etc/containerd-shim-<engine>/config.toml`.
+pub const CONFIG_FILE_ENV: &str = "RUNWASI_CONFIG_FILE";
+
+// The options of the `Config` of containerd-shimkit, which are in the runtime options too
+const SHIMKIT_OPTIONS: &[&str] = &["SystemdCgroup", "systemd_cgroup"];
+
+// The config the shim was started with, and the warnings of its file
+static CONFIG: OnceLock<(ShimConfig, Vec<String>)> = OnceLock::new();
+
+/// The settings of the shim, by section of the config file.
+///
+/// The settings that aren't in the file nor in the environment are `None`, and keep the
+/// defaults of the shim.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShimConfig {
+    pub containerd: ContainerdConfig,
+    pub layers: LayersConfig,
+    pub precompile: PrecompileConfig,
+    pub policy: PolicyConfig,
+    pub instances: InstancesConfig,
+    /// The settings of the engine, by [`Shim::engine_config_keys`].
+    #[serde(skip_serializing_if = "toml::Table::is_empty")]
+    pub engine: toml::Table,
+}
+
+/// The calls of the shim to containerd, with durations in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContainerdConfig {
+    /// The maximum time to connect to containerd.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    /// The maximum time of a call to containerd.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpc_timeout: Option<u64>,
+    /// The maximum time to load the wasm layers of an instance, including their precompilation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_modules_timeout: Option<u64>,
+    /// The maximum number of attempts of a call to containerd.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_attempts: Option<u32>,
+    /// The maximum time spent retrying a call to containerd.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_deadline: Option<u64>,
+}
+
+/// The wasm layers read from the content store, with sizes in bytes.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayersConfig {
+    /// The maximum number of layers kept in memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_size: Option<usize>,
+    /// The size from which the layers are memory-mapped from files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mmap_threshold: Option<u64>,
+    /// The directory of the files of the memory-mapped layers, which the shims of the node share.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_dir: Option<String>,
+    /// The maximum size of the files of the memory-mapped layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_quota: Option<u64>,
+    /// The interval in seconds between the evictions of the files of the layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_eviction_interval: Option<u64>,
+    /// The size of the chunks of the content read from containerd.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_chunk_size: Option<i64>,
+    /// Whether images with layers the engine doesn't support fail to start.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+    /// Whether the digests of the content read from containerd are verified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_digests: Option<bool>,
+    /// Whether the guests may run precompiled files of their rootfs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_precompiled_files: Option<bool>,
+}
+
+/// The precompilation of the wasm layers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrecompileConfig {
+    /// The number of layers of an image compiled concurrently.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// The number of layers compiled at a time by all the instances of the shim.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_compilations: Option<usize>,
+}
+
+/// The policies the images are checked against before they run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// The path of the policy of the images that may run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_policy: Option<String>,
+    /// The path of the policy of the signatures of the images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature_policy: Option<String>,
+}
+
+/// The instances of the shim.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstancesConfig {
+    /// The address the metrics of the instances are served on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+    /// The path the state of the shim is dumped to on `SIGUSR1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_dump: Option<String>,
+    /// Whether the orphaned containers are swept: `"true"`, `"false"` or `"dry-run"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orphan_sweep: Option<String>,
+    /// The time the guests are given to start without a start timeout annotation, e.g., `"2m"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_timeout: Option<String>,
+}
+
+// A value of a setting, read from the config file or from its environment variable
+trait Value {
+    fn set_toml(&mut self, value: toml::Value) -> Result<()>;
+    fn set_env(&mut self, value: &str) -> Result<()>;
+    fn to_env(&self) -> Option<String>;
+}
+
+impl<T> Value for Option<T>
+where
+    T: FromStr + Display + DeserializeOwned,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    fn set_toml(&mut self, value: toml::Value) -> Result<()> {
+        *self = Some(value.try_into()?);
+        Ok(())
+    }
+
+    fn set_env(&mut self, value: &str) -> Result<()> {
+        *self = Some(value.parse()?);
+        Ok(())
+    }
+
+    fn to_env(&self) -> Option<String> {
+        self.as_ref().map(ToString::to_string)
+    }
+}
+
+struct Setting<'a> {
+    section: &'static str,
+    key: &'static str,
+    env: &'static str,
+    value: &'a mut dyn Value,
+    // checks the values whose type doesn't say if they are valid
+    check: Option<fn(&str) -> Result<()>>,
+}
+
+impl<'a> Setting<'a> {
+    fn new(
+        section: &'static str,
+        key: &'static str,
+        env: &'static str,
+        value: &'a mut dyn Value,
+    ) -> Self {
+        Self {
+            section,
+            key,
+            env,
+            value,
+            check: None,
+        }
+    }
+
+    fn path(&self) -> String {
+        format!("{}.{}", self.section, self.key)
+    }
+
+    fn check(&self) -> Result<()> {
+        match (self.check, self.value.to_env()) {
+            (Some(check), Some(value)) => check(&value),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ShimConfig {
+    /// Returns the config the shim was started with, or the defaults if it has none, e.g., in
+    /// tests. The unknown keys of its file are logged on the first call, once the logger of the
+    /// shim is set up.
+    pub fn get() -> &'static ShimConfig {
+        static WARNED: Once = Once::new();
+
+        let (config, warnings) = CONFIG.get_or_init(Default::default);
+        WARNED.call_once(|| {
+            for warning in warnings {
+                log::warn!("{warning}");
+            }
+        });
+        config
+    }
+
+    /// Loads the config of the shim `S` from its file, its runtime options and the environment.
+    ///
+    /// The config is loaded once, and this must be called when the shim starts, before the
+    /// first call to [`ShimConfig::get`].
+    pub fn init<S: Shim>() -> Result<&'static ShimConfig> {
+        if let Some((config, _)) = CONFIG.get() {
+            return Ok(config);
+        }
+
+        let (config, warnings) = Self::load::<S>()?;
+        Ok(&CONFIG.get_or_init(|| (config, warnings)).0)
+    }
+
+    /// Returns the value of the key `key` of the `[engine]` section, as a string, e.g., `"true"`
+    /// for a boolean.
+    pub fn engine_value(&self, key: &str) -> Option<String> {
+        self.engine
+            .get(key)
+            .and_then(|value| engine_value_to_env(value).ok())
+    }
+
+    /// Returns the path of the config file of the shim `S`.
+    pub fn path<S: Shim>() -> PathBuf {
+        match std::env::var_os(CONFIG_FILE_ENV) {
+            Some(path) => path.into(),
+            None => format!("/etc/containerd-shim-{}/config.toml", S::name()).into(),
+        }
+    }
+
+    // Loads the config file of `S`, if it has one, with the settings of its runtime options
+    // and of the environment, and returns the warnings of the file and the options
+    fn load<S: Shim>() -> Result<(Self, Vec<String>)> {
+        let path = Self::path::<S>();
+        let (mut config, mut warnings) = match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                let (config, warnings) = Self::parse(&content, S::engine_config_keys())
+                    .with_context(|| format!("invalid config file {path:?}"))?;
+                let warnings = warnings
+                    .into_iter()
+                    .map(|warning| format!("{warning} in {path:?}"))
+                    .collect::<Vec<_>>();
+                (config, warnings)
+            }
+            // the default file is optional, the one of the environment isn't
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && std::env::var_os(CONFIG_FILE_ENV).is_none() =>
+            {
+                Default::default()
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
+        };
+        if let Some(options) = runtime_options() {
+            let (options, options_warnings) =
+                Self::parse_runtime_options(options, S::engine_config_keys())
+                    .context("invalid runtime options")?;
+            config.merge::<S>(&options)?;
+            warnings.extend(
+                options_warnings
+                    .into_iter()
+                    .map(|warning| format!("{warning} in the runtime options")),
+            );
+        }
+        config.apply_env::<S>(|name| std::env::var(name).ok())?;
+        Ok((config, warnings))
+    }
+
+    /// Parses the `content` of a config file, with the keys `engine_keys` in its `[engine]`
+    /// section, and returns the config with a warning for each unknown key.
+    pub fn parse(content: &str, engine_keys: &[&str]) -> Result<(Self, Vec<String>)> {
+        Self::from_table(content.parse()?, engine_keys)
+    }
+
+    /// Parses the TOML `content` of the runtime options of the shim, which have the sections of
+    /// the config file besides the options of containerd-shimkit, e.g., `SystemdCgroup`.
+    pub fn parse_runtime_options(
+        content: &str,
+        engine_keys: &[&str],
+    ) -> Result<(Self, Vec<String>)> {
+        let mut table: toml::Table = content.parse()?;
+        for key in SHIMKIT_OPTIONS {
+            table.remove(*key);
+        }
+        Self::from_table(table, engine_keys)
+    }
+
+    fn from_table(table: toml::Table, engine_keys: &[&str]) -> Result<(Self, Vec<String>)> {
+        let mut config = Self::default();
+        let mut engine = toml::Table::new();
+        let mut warnings = vec![];
+        let mut settings = config.settings();
+        for (section, value) in table {
+            if section == "engine" {
+                let toml::Value::Table(table) = value else {
+                    bail!("invalid value of `engine`: expected a table");
+                };
+                for (key, value) in table {
+                    if !engine_keys.contains(&key.as_str()) {
+                        warnings.push(format!("unknown key `engine.{key}`"));
+                        continue;
+                    }
+                    engine_value_to_env(&value)
+                        .with_context(|| format!("invalid value of `engine.{key}`"))?;
+                    engine.insert(key, value);
+                }
+                continue;
+            }
+            if !settings.iter().any(|setting| setting.section == section) {
+                warnings.push(format!("unknown key `{section}`"));
+                continue;
+            }
+            let toml::Value::Table(table) = value else {
+                bail!("invalid value of `{section}`: expected a table");
+            };
+            for (key, value) in table {
+                let Some(setting) = settings
+                    .iter_mut()
+                    .find(|setting| setting.section == section && setting.key == key)
+                else {
+                    warnings.push(format!("unknown key `{section}.{key}`"));
+                    continue;
+                };
+                setting
+                    .value
+                    .set_toml(value)
+                    .and_then(|_| setting.check())
+                    .with_context(|| format!("invalid value of `{}`", setting.path()))?;
+            }
+        }
+        config.engine = engine;
+        Ok((config, warnings))
+    }
+
+    // Overrides the settings with the ones `other` sets
+    fn merge<S: Shim>(&mut self, other: &Self) -> Result<()> {
+        let env: HashMap<_, _> = other.to_env::<S>()?.into_iter().collect();
+        self.apply_env::<S>(|name| env.get(name).cloned())
+    }
+
+    // Overrides the settings with the environment variables `var` returns
+    fn apply_env<S: Shim>(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        for setting in self.settings() {
+            if let Some(value) = var(setting.env) {
+                setting
+                    .value
+                    .set_env(&value)
+                    .and_then(|_| setting.check())
+                    .with_context(|| format!("invalid {} value {value:?}", setting.env))?;
+            }
+        }
+        for key in S::engine_config_keys() {
+            if let Some(value) = var(&engine_env::<S>(key)) {
+                self.engine
+                    .insert(key.to_string(), toml::Value::String(value));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the environment variables of the settings of the config, with their values
+    fn to_env<S: Shim>(&self) -> Result<Vec<(String, String)>> {
+        let mut config = self.clone();
+        let mut env: Vec<_> = config
+            .settings()
+            .into_iter()
+            .filter_map(|setting| Some((setting.env.to_string(), setting.value.to_env()?)))
+            .collect();
+        for (key, value) in &self.engine {
+            let value = engine_value_to_env(value)
+                .with_context(|| format!("invalid value of `engine.{key}`"))?;
+            env.push((engine_env::<S>(key), value));
+        }
+        Ok(env)
+    }
+
+    fn settings(&mut self) -> Vec<Setting<'_>> {
+        let Self {
+            containerd,
+            layers,
+            precompile,
+            policy,
+            instances,
+            engine: _,
+        } = self;
+        let setting = Setting::new;
+        vec![
+            setting(
+                "containerd",
+                "connect_timeout",
+                "RUNWASI_CONTAINERD_CONNECT_TIMEOUT",
+                &mut containerd.connect_timeout,
+            ),
+            setting(
+                "containerd",
+                "rpc_timeout",
+                "RUNWASI_CONTAINERD_RPC_TIMEOUT",
+                &mut containerd.rpc_timeout,
+            ),
+            setting(
+                "containerd",
+                "load_modules_timeout",
+                "RUNWASI_LOAD_MODULES_TIMEOUT",
+                &mut containerd.load_modules_timeout,
+            ),
+            setting(
+                "containerd",
+                "retry_attempts",
+                "RUNWASI_CONTAINERD_RETRY_ATTEMPTS",
+                &mut containerd.retry_attempts,
+            ),
+            setting(
+                "containerd",
+                "retry_deadline",
+                "RUNWASI_CONTAINERD_RETRY_DEADLINE",
+                &mut containerd.retry_deadline,
+            ),
+            setting(
+                "layers",
+                "cache_size",
+                "RUNWASI_LAYER_CACHE_SIZE",
+                &mut layers.cache_size,
+            ),
+            setting(
+                "layers",
+                "mmap_threshold",
+                "RUNWASI_LAYER_MMAP_THRESHOLD",
+                &mut layers.mmap_threshold,
+            ),
+            setting(
+                "layers",
+                "files_dir",
+                "RUNWASI_LAYER_FILES_DIR",
+                &mut layers.files_dir,
+            ),
+            setting(
+                "layers",
+                "files_quota",
+                "RUNWASI_LAYER_FILES_QUOTA",
+                &mut layers.files_quota,
+            ),
+            setting(
+                "layers",
+                "files_eviction_interval",
+                "RUNWASI_LAYER_FILES_EVICTION_INTERVAL",
+                &mut layers.files_eviction_interval,
+            ),
+            setting(
+                "layers",
+                "read_chunk_size",
+                "RUNWASI_CONTENT_READ_CHUNK_SIZE",
+                &mut layers.read_chunk_size,
+            ),
+            setting(
+                "layers",
+                "strict",
+                "RUNWASI_STRICT_LAYERS",
+                &mut layers.strict,
+            ),
+            setting(
+                "layers",
+                "verify_digests",
+                "RUNWASI_VERIFY_DIGESTS",
+                &mut layers.verify_digests,
+            ),
+            setting(
+                "layers",
+                "allow_precompiled_files",
+                "RUNWASI_ALLOW_PRECOMPILED_FILES",
+                &mut layers.allow_precompiled_files,
+            ),
+            setting(
+                "precompile",
+                "concurrency",
+                "RUNWASI_PRECOMPILE_CONCURRENCY",
+                &mut precompile.concurrency,
+            ),
+            setting(
+                "precompile",
+                "max_concurrent_compilations",
+                "RUNWASI_MAX_CONCURRENT_COMPILATIONS",
+                &mut precompile.max_concurrent_compilations,
+            ),
+            setting(
+                "policy",
+                "image_policy",
+                "RUNWASI_IMAGE_POLICY",
+                &mut policy.image_policy,
+            ),
+            setting(
+                "policy",
+                "signature_policy",
+                "RUNWASI_SIGNATURE_POLICY",
+                &mut policy.signature_policy,
+            ),
+            setting(
+                "instances",
+                "metrics_address",
+                "RUNWASI_METRICS_ADDRESS",
+                &mut instances.metrics_address,
+            ),
+            setting(
+                "instances",
+                "debug_dump",
+                "RUNWASI_DEBUG_DUMP",
+                &mut instances.debug_dump,
+            ),
+            Setting {
+                check: Some(check_orphan_sweep),
+                ..setting(
+                    "instances",
+                    "orphan_sweep",
+                    "RUNWASI_ORPHAN_SWEEP",
+                    &mut instances.orphan_sweep,
+                )
+            },
+            Setting {
+                check: Some(check_duration),
+                ..setting(
+                    "instances",
+                    "start_timeout",
+                    "RUNWASI_START_TIMEOUT",
+                    &mut instances.start_timeout,
+                )
+            },
+        ]
+    }
+}
+
+// Returns the environment variable of the key `key` of the `[engine]` section of `S`
+fn engine_env<S: Shim>(key: &str) -> String {
+    format!("RUNWASI_{}_{}", S::name(), key).to_uppercase()
+}
+
+fn engine_value_to_env(value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => bail!("expected a string, a number or a boolean"),
+    }
+}
+
+fn check_orphan_sweep(value: &str) -> Result<()> {
+    match value {
+        "true" | "false" | "dry-run" => Ok(()),
+        _ => bail!("expected `true`, `false` or `dry-run`"),
+    }
+}
+
+fn check_duration(value: &str) -> Result<()> {
+    #[cfg(unix)]
+    crate::sys::container::parse_duration(value)
+        .context("expected a number of seconds or a duration, e.g., `60s` or `2m`")?;
+    #[cfg(not(unix))]
+    let _ = value;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::Sandbox;
+    use crate::sandbox::context::RuntimeContext;
+
+    const EXAMPLE: &str = include_str!("testdata/config.toml");
+
+    struct TestShim;
+
+    #[derive(Default)]
+    struct TestSandbox;
+
+    impl Sandbox for TestSandbox {
+        async fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+            Ok(0)
+        }
+    }
+
+    impl Shim for TestShim {
+        fn name() -> &'static str {
+            "test"
+        }
+
+        fn engine_config_keys() -> &'static [&'static str] {
+            &["cpu_features"]
+        }
+
+        type Sandbox = TestSandbox;
+    }
+
+    fn parse(content: &str) -> Result<(ShimConfig, Vec<String>)> {
+        ShimConfig::parse(content, TestShim::engine_config_keys())
+    }
+
+    #[test]
+    fn test_parse_example() -> Result<()> {
+        let (config, warnings) = parse(EXAMPLE)?;
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.containerd.connect_timeout, Some(10));
+        assert_eq!(config.containerd.rpc_timeout, None);
+        assert_eq!(config.layers.cache_size, Some(32));
+        assert_eq!(config.layers.strict, Some(true));
+        assert_eq!(config.instances.orphan_sweep.as_deref(), Some("dry-run"));
+        assert_eq!(
+            config
+                .engine
+                .get("cpu_features")
+                .and_then(toml::Value::as_str),
+            Some("-has_avx512f,-has_avx512vl")
+        );
+        assert_eq!(
+            config.engine_value("cpu_features").as_deref(),
+            Some("-has_avx512f,-has_avx512vl")
+        );
+        assert_eq!(config.engine_value("wasm_features"), None);
+
+        let env: HashMap<_, _> = config.to_env::<TestShim>()?.into_iter().collect();
+        assert_eq!(env["RUNWASI_CONTAINERD_CONNECT_TIMEOUT"], "10");
+        assert_eq!(env["RUNWASI_LAYER_FILES_QUOTA"], "1073741824");
+        assert_eq!(env["RUNWASI_STRICT_LAYERS"], "true");
+        assert_eq!(env["RUNWASI_START_TIMEOUT"], "2m");
+        assert_eq!(
+            env["RUNWASI_TEST_CPU_FEATURES"],
+            "-has_avx512f,-has_avx512vl"
+        );
+        assert!(!env.contains_key("RUNWASI_CONTAINERD_RPC_TIMEOUT"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let (config, _) = parse(EXAMPLE)?;
+        let content = toml::to_string(&config)?;
+        assert_eq!(parse(&content)?, (config.clone(), vec![]));
+        assert_eq!(toml::from_str::<ShimConfig>(&content)?, config);
+
+        let content = toml::to_string(&ShimConfig::default())?;
+        assert_eq!(parse(&content)?, (ShimConfig::default(), vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_keys() -> Result<()> {
+        let (config, warnings) = parse(
+            r#"
+            logging = "debug"
+
+            [layers]
+            cache_size = 8
+            cache_sise = 16
+
+            [engine]
+            wasm_features = "+threads"
+            "#,
+        )?;
+        assert_eq!(config.layers.cache_size, Some(8));
+        assert!(config.engine.is_empty());
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `engine.wasm_features`",
+                "unknown key `layers.cache_sise`",
+                "unknown key `logging`",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        for (content, expected) in [
+            (
+                "[layers]\ncache_size = \"32\"",
+                "invalid value of `layers.cache_size`",
+            ),
+            (
+                "[layers]\ncache_size = -1",
+                "invalid value of `layers.cache_size`",
+            ),
+            (
+                "[containerd]\nretry_attempts = true",
+                "invalid value of `containerd.retry_attempts`",
+            ),
+            (
+                "[instances]\norphan_sweep = \"never\"",
+                "invalid value of `instances.orphan_sweep`",
+            ),
+            ("layers = 1", "invalid value of `layers`: expected a table"),
+            (
+                "[engine]\ncpu_features = [\"-has_avx\"]",
+                "invalid value of `engine.cpu_features`",
+            ),
+        ] {
+            let err = parse(content).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{content}");
+        }
+        assert!(parse("[layers\n").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() -> Result<()> {
+        let (mut config, _) = parse(EXAMPLE)?;
+        let env = HashMap::from([
+            ("RUNWASI_LAYER_CACHE_SIZE", "64"),
+            ("RUNWASI_CONTAINERD_RPC_TIMEOUT", "5"),
+            ("RUNWASI_TEST_CPU_FEATURES", "+has_avx2"),
+        ]);
+        config.apply_env::<TestShim>(|name| env.get(name).map(ToString::to_string))?;
+        assert_eq!(config.layers.cache_size, Some(64));
+        assert_eq!(config.containerd.rpc_timeout, Some(5));
+        // the settings the environment doesn't set keep the values of the file
+        assert_eq!(config.containerd.connect_timeout, Some(10));
+        assert_eq!(
+            config
+                .engine
+                .get("cpu_features")
+                .and_then(toml::Value::as_str),
+            Some("+has_avx2")
+        );
+
+        let err = config
+            .apply_env::<TestShim>(|name| {
+                (name == "RUNWASI_LAYER_CACHE_SIZE").then(|| "many".to_string())
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid RUNWASI_LAYER_CACHE_SIZE value \"many\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_options() -> Result<()> {
+        let (mut config, _) = parse(EXAMPLE)?;
+        let (options, warnings) = ShimConfig::parse_runtime_options(
+            r#"
+            SystemdCgroup = true
+
+            [layers]
+            strict = false
+
+            [engine]
+            cpu_features = "+has_avx2"
+
+            [debug]
+            verbose = true
+            "#,
+            TestShim::engine_config_keys(),
+        )?;
+        assert_eq!(warnings, ["unknown key `debug`"]);
+        config.merge::<TestShim>(&options)?;
+
+        // the options override the file, and keep the settings they don't set
+        assert_eq!(config.layers.strict, Some(false));
+        assert_eq!(config.layers.cache_size, Some(32));
+        assert_eq!(
+            config
+                .engine
+                .get("cpu_features")
+                .and_then(toml::Value::as_str),
+            Some("+has_avx2")
+        );
+
+        let err = ShimConfig::parse_runtime_options(
+            "[layers]\nstrict = \"yes\"",
+            TestShim::engine_config_keys(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid value of `layers.strict`");
+        Ok(())
+    }
+}
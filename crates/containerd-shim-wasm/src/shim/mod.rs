@@ -13,6 +13,9 @@
 //! ## Key Components
 //!
 //! - [`Shim`]: The trait for implementing the shim entrypoint
+//! - [`ShimConfig`]: The settings of the shim, from its config file and environment
+//! - [`precompile_image`]: Precompiles the wasm layers of an image without running it
+//! - [`sweep_orphans`]: Sweeps the containers left behind by crashed shims
 //! - [`Sandbox`](crate::sandbox::Sandbox): The core trait for implementing Wasm runtimes
 //! - [`RuntimeContext`](crate::sandbox::context::RuntimeContext): The context for running WASI modules
 //!
@@ -55,11 +58,24 @@
 //! }
 //! ```
 
+mod config;
 #[allow(clippy::module_inception)]
 mod shim;
 
+#[cfg(unix)]
+pub(crate) mod precompile;
+
+pub use config::{
+    CONFIG_FILE_ENV, ContainerdConfig, InstancesConfig, LayersConfig, PolicyConfig,
+    PrecompileConfig, ShimConfig,
+};
 pub(crate) use instance::Instance;
-pub use shim::{Compiler, Shim, Version};
+#[cfg(unix)]
+pub use precompile::{PrecompiledLayer, precompile_image};
+pub use shim::{Compiler, EngineVersion, Shim, StackLimitRange, SupportedStackLimits, Version};
+
+#[cfg(unix)]
+pub use crate::sys::container::cleanup::sweep_orphans;
 
 use crate::sys::container::instance;
 
@@ -0,0 +1,174 @@
+//! Precompiles the wasm layers of an image without creating a container, e.g., to warm the
+//! cache of precompiled modules when an image is pushed instead of on its first start.
+//!
+//! The artifacts are stored in the content store exactly like the shim stores them when it
+//! starts a container, so the containers of the image use them right away.
+//!
+//! The shim binary exposes this through the `precompile` subcommand:
+//!
+//! ```text
+//! containerd-shim-<engine>-v1 precompile [--address <path>] [--namespace <namespace>] <image>...
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result, bail};
+use oci_spec::image::Digest;
+
+use crate::containerd::Client;
+use crate::shim::{Shim, ShimConfig};
+
+const DEFAULT_CONTAINERD_ADDRESS: &str = "/run/containerd/containerd.sock";
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// A wasm layer of a precompiled image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecompiledLayer {
+    /// Digest of the original layer
+    pub layer: Digest,
+    /// Digest of the precompiled artifact, or `None` if the compiler didn't produce one
+    pub precompiled: Option<Digest>,
+}
+
+/// Precompiles the wasm layers of the image `image_name` in the containerd `namespace` with
+/// the compiler of the shim `S`. Images that are already precompiled are not compiled again.
+pub async fn precompile_image<S: Shim>(
+    address: impl AsRef<Path>,
+    namespace: impl Into<String>,
+    image_name: &str,
+) -> Result<Vec<PrecompiledLayer>> {
+    let Some(compiler) = S::compiler(ShimConfig::get()).await else {
+        bail!("the {} shim doesn't precompile wasm layers", S::name());
+    };
+
+    let client = Client::connect(address.as_ref(), namespace.into()).await?;
+    let layers = client
+        .precompile_image(
+            image_name,
+            S::name(),
+            S::supported_layers_types(),
+            &compiler,
+        )
+        .await?;
+    Ok(layers)
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    address: PathBuf,
+    namespace: String,
+    images: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut args = args.into_iter();
+    let mut address = PathBuf::from(DEFAULT_CONTAINERD_ADDRESS);
+    let mut namespace = DEFAULT_NAMESPACE.to_string();
+    let mut images = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" | "-a" => {
+                address = args.next().context("missing value for --address")?.into();
+            }
+            "--namespace" | "-n" => {
+                namespace = args.next().context("missing value for --namespace")?;
+            }
+            flag if flag.starts_with('-') => bail!("unknown flag {flag:?}"),
+            _ => images.push(arg),
+        }
+    }
+
+    if images.is_empty() {
+        bail!("no image to precompile");
+    }
+
+    Ok(Args {
+        address,
+        namespace,
+        images,
+    })
+}
+
+/// Entry point of the `precompile` subcommand, `args` are the arguments after the subcommand.
+/// Prints the digests of the artifacts of every wasm layer of the images.
+pub(crate) fn main<S: Shim>(args: impl IntoIterator<Item = String>) -> ! {
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!(
+                "usage: containerd-shim-{}-v1 precompile [--address <path>] [--namespace <namespace>] <image>...",
+                S::name()
+            );
+            std::process::exit(2);
+        }
+    };
+
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create tokio runtime");
+    let res = runtime.block_on(async {
+        for image in &args.images {
+            let layers = precompile_image::<S>(&args.address, &args.namespace, image)
+                .await
+                .with_context(|| format!("failed to precompile image {image}"))?;
+            if layers.is_empty() {
+                println!("{image}: no wasm layers");
+            }
+            for PrecompiledLayer { layer, precompiled } in layers {
+                match precompiled {
+                    Some(precompiled) => println!("{image}: {layer} -> {precompiled}"),
+                    None => println!("{image}: {layer} -> not precompiled"),
+                }
+            }
+        }
+        anyhow::Ok(())
+    });
+
+    if let Err(err) = res {
+        eprintln!("{err:#}");
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() -> Result<()> {
+        assert_eq!(
+            args(&["localhost/hello:latest"])?,
+            Args {
+                address: DEFAULT_CONTAINERD_ADDRESS.into(),
+                namespace: DEFAULT_NAMESPACE.to_string(),
+                images: vec!["localhost/hello:latest".to_string()],
+            }
+        );
+        assert_eq!(
+            args(&[
+                "-n",
+                "k8s.io",
+                "a",
+                "--address",
+                "/tmp/containerd.sock",
+                "b"
+            ])?,
+            Args {
+                address: "/tmp/containerd.sock".into(),
+                namespace: "k8s.io".to_string(),
+                images: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+
+        assert!(args(&[]).is_err());
+        assert!(args(&["--namespace"]).is_err());
+        assert!(args(&["--force", "a"]).is_err());
+
+        Ok(())
+    }
+}
@@ -1,11 +1,19 @@
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::hash::Hash;
+use std::ops::RangeInclusive;
 
 use anyhow::Result;
 #[doc(inline)]
-pub use containerd_shimkit::sandbox::cli::Version;
+pub use containerd_shimkit::sandbox::cli::{EngineVersion, Version};
+use oci_spec::runtime::LinuxResources;
 
 use crate::sandbox::Sandbox;
-use crate::sandbox::context::WasmLayer;
+use crate::sandbox::context::{
+    Capabilities, Capability, MAX_CALL_DEPTH_ANNOTATION, MAX_STACK_SIZE_ANNOTATION, StackLimits,
+    WasmLayer,
+};
+use crate::shim::ShimConfig;
 
 /// The `Shim` trait provides a simplified API for running WebAssembly containers.
 ///
@@ -21,28 +29,187 @@ pub trait Shim: Sync + 'static {
         Version::default()
     }
 
+    /// Returns the name, the version and the wasm features of the engine, which the shim logs
+    /// when it starts, prints with `--version`, adds to its traces and metrics, and returns in
+    /// the responses to the `Connect` ttrpc calls.
+    /// Engines whose [`Compiler`] depends on them should include them in its
+    /// [`Compiler::cache_key`], so that the artifacts and the reported engine never drift.
+    /// The default implementation reports the name of the shim, without a version.
+    fn engine_version() -> EngineVersion {
+        EngineVersion {
+            name: Self::name().to_string(),
+            ..Default::default()
+        }
+    }
+
     type Sandbox: Sandbox;
 
+    /// Returns the keys of the `[engine]` section of the [`ShimConfig`] of the shim, which the
+    /// engine reads with [`ShimConfig::engine_value`], and which the `RUNWASI_<NAME>_<KEY>`
+    /// environment variables override, e.g., `RUNWASI_WASMTIME_CPU_FEATURES` for `cpu_features`.
+    /// The default implementation has no keys.
+    fn engine_config_keys() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Checks the engine configuration of the [`ShimConfig`] of the shim when it starts, so that
+    /// the shim can refuse to start instead of failing on every container. The default
+    /// implementation accepts any configuration.
+    fn check_config(_config: &ShimConfig) -> Result<()> {
+        Ok(())
+    }
+
     /// When `compiler` returns `Some`, the returned `Compiler` will be used to precompile
     /// the layers before they are run.
     /// Returns the compiler to be used by this engine
-    /// to precompile layers.
-    async fn compiler() -> Option<impl Compiler> {
+    /// to precompile layers, with the [`ShimConfig`] the shim was started with.
+    async fn compiler(_config: &ShimConfig) -> Option<impl Compiler> {
         async move { NO_COMPILER }
     }
 
     /// Return the supported OCI layer types
     /// This is used to filter only layers that are supported by the runtime.
     /// The default implementation returns the OCI layer type 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
-    /// for WASM modules which can be contain with wasip1 or wasip2 components.
+    /// for WASM modules which can be contain with wasip1 or wasip2 components,
+    /// and 'application/vnd.wasm.content.layer.v1+wat' for modules in the WebAssembly text format,
+    /// which are converted to binaries before they reach the engine.
     /// Runtimes can override this to support other layer types
     /// such as lays that contain runtime specific configuration
     fn supported_layers_types() -> &'static [&'static str] {
         &[
             "application/vnd.bytecodealliance.wasm.component.layer.v0+wasm",
             "application/wasm",
+            "application/vnd.wasm.content.layer.v1+wat",
         ]
     }
+
+    /// Called after the cgroup limits of a running instance have been updated
+    /// (e.g., by `ctr task update` or the Kubernetes VPA).
+    /// Engines that enforce their own cap on the wasm linear memory can use this
+    /// to raise or lower it. The default implementation does nothing.
+    async fn update_resources(_id: &str, _resources: &LinuxResources) -> Result<()> {
+        async move { Ok(()) }
+    }
+
+    /// Returns the limits of the stack of the guest that the engine supports, see
+    /// [`RuntimeContext::stack_limits`](crate::sandbox::context::RuntimeContext::stack_limits).
+    /// A container setting a limit the engine doesn't support, or out of its range, fails to be created.
+    /// The default implementation supports none of them.
+    fn supported_stack_limits() -> SupportedStackLimits {
+        SupportedStackLimits::default()
+    }
+
+    /// Returns whether the engine can run the guest with the virtual clocks and seeded random
+    /// numbers of [`RuntimeContext::deterministic`](crate::sandbox::context::RuntimeContext::deterministic).
+    /// A container asking for it with an engine that can't fails to be created.
+    /// The default implementation can't.
+    fn supports_deterministic() -> bool {
+        false
+    }
+
+    /// Returns whether the engine can check that a guest could run without running it, with
+    /// [`Sandbox::validate`](crate::sandbox::Sandbox::validate), for the
+    /// [`VALIDATE_ONLY_ANNOTATION`](crate::sandbox::context::VALIDATE_ONLY_ANNOTATION).
+    /// A container asking for it with an engine that can't fails to be created, rather than
+    /// running its guest.
+    /// The default implementation can't.
+    fn supports_validation() -> bool {
+        false
+    }
+
+    /// Returns whether the layers of a container with the `annotations` of its spec are
+    /// precompiled by [`Shim::compiler`]. Engines whose compiler depends on the annotations of
+    /// the container, e.g., to pick the compiler backend, return `false` for the containers it
+    /// doesn't compile for, which then run their original layers.
+    /// The default implementation precompiles the layers of all the containers.
+    fn precompiles(_annotations: &HashMap<String, String>) -> bool {
+        true
+    }
+
+    /// Returns the extensions of the files the engine precompiled ahead of time, e.g., `cwasm`,
+    /// that it can run when they are the entrypoint of a container without wasm layers, see
+    /// [`RuntimeContext::is_precompiled_file`](crate::sandbox::context::RuntimeContext::is_precompiled_file).
+    /// They are only run when the shim is started with `layers.allow_precompiled_files`, as they
+    /// skip the validation of the wasm.
+    /// The default implementation runs none.
+    fn precompiled_extensions() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Returns the host capabilities of the
+    /// [`CAPABILITIES_ANNOTATION`](crate::sandbox::context::CAPABILITIES_ANNOTATION) that the
+    /// engine can grant the guest, see [`RuntimeContext::capabilities`](crate::sandbox::context::RuntimeContext::capabilities).
+    /// A container asking for one the engine doesn't support fails to be created.
+    /// Engines should support [`Capability::Env`], which guests are granted unless they're
+    /// denied it.
+    /// The default implementation only supports [`Capability::Env`], as the shim passes the
+    /// env vars in [`RuntimeContext::envs`](crate::sandbox::context::RuntimeContext::envs).
+    fn supported_capabilities() -> Capabilities {
+        [Capability::Env].into_iter().collect()
+    }
+
+    /// Returns whether the sandbox reports the start of the guest with
+    /// [`RuntimeContext::guest_started`](crate::sandbox::context::RuntimeContext::guest_started)
+    /// once it's instantiated, so that the start deadline of the container covers the
+    /// instantiation, e.g., of a guest whose imports can't be resolved.
+    /// The default implementation doesn't, and the guest counts as started once the sandbox runs it.
+    fn reports_guest_started() -> bool {
+        false
+    }
+}
+
+/// The range of values of a stack limit that an engine supports, and its default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackLimitRange<T> {
+    pub range: RangeInclusive<T>,
+    pub default: T,
+}
+
+/// The limits of the stack of the guest that an engine supports, see
+/// [`Shim::supported_stack_limits`]. `None` if the engine doesn't support the limit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SupportedStackLimits {
+    pub max_stack_size: Option<StackLimitRange<usize>>,
+    pub max_call_depth: Option<StackLimitRange<u32>>,
+}
+
+impl SupportedStackLimits {
+    /// Checks that the engine `name` supports `limits`, returning the reason if it doesn't.
+    pub(crate) fn check(&self, name: &str, limits: &StackLimits) -> Result<(), String> {
+        check_limit(
+            name,
+            MAX_STACK_SIZE_ANNOTATION,
+            limits.max_stack_size,
+            self.max_stack_size.as_ref(),
+        )?;
+        check_limit(
+            name,
+            MAX_CALL_DEPTH_ANNOTATION,
+            limits.max_call_depth,
+            self.max_call_depth.as_ref(),
+        )
+    }
+}
+
+fn check_limit<T: PartialOrd + Display>(
+    name: &str,
+    annotation: &str,
+    value: Option<T>,
+    supported: Option<&StackLimitRange<T>>,
+) -> Result<(), String> {
+    match (value, supported) {
+        (None, _) => Ok(()),
+        (Some(_), None) => Err(format!(
+            "the {annotation} annotation is not supported by {name}"
+        )),
+        (Some(value), Some(supported)) if !supported.range.contains(&value) => Err(format!(
+            "invalid {annotation} annotation: {value} is out of the range {}..={} supported by {name}, which defaults to {}",
+            supported.range.start(),
+            supported.range.end(),
+            supported.default
+        )),
+        (Some(_), Some(_)) => Ok(()),
+    }
 }
 
 #[trait_variant::make(Send)]
@@ -64,6 +231,14 @@ pub trait Compiler: Sync {
     /// The runtime is expected to return the same number of layers passed in, if the layer cannot be precompiled it should return `None` for that layer.
     /// In some edge cases it is possible that the layers may already be precompiled and None should be returned in this case.
     async fn compile(&self, _layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// `compiles_per_layer` opts in to having the layers of an image compiled concurrently.
+    /// When it returns `true`, the shim calls `compile` once per layer, running several calls concurrently, so
+    /// CPU-bound compilers should offload the work (e.g. with `tokio::task::spawn_blocking`).
+    /// By default `compile` is called once with all the layers of the image.
+    fn compiles_per_layer(&self) -> bool {
+        false
+    }
 }
 
 /// Like the unstable never type, this type can never be constructed.
@@ -1,8 +1,8 @@
 use anyhow::bail;
 
-use super::shim::Shim;
+use super::shim::{Shim, StackLimitRange, SupportedStackLimits};
 use crate::sandbox::Sandbox;
-use crate::sandbox::context::RuntimeContext;
+use crate::sandbox::context::{CAPABILITIES_ANNOTATION, RuntimeContext, StackLimits};
 use crate::testing::WasiTest;
 
 struct EngineFailingValidation;
@@ -42,3 +42,50 @@ fn test_validation_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[cfg(unix)] // not yet implemented on Windows
+fn test_unsupported_capability() -> anyhow::Result<()> {
+    // The engine only supports the default `env` capability
+    let result = WasiTest::<EngineFailingValidation>::builder()?
+        .with_wasm("/hello.wasm")?
+        .with_annotation(CAPABILITIES_ANNOTATION, "env,net-outbound")
+        .build();
+
+    let Err(err) = result else {
+        bail!("the container was created with an unsupported capability");
+    };
+    assert!(
+        err.to_string()
+            .contains("wasi_instance doesn't support the net-outbound capability"),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_supported_stack_limits() {
+    let supported = SupportedStackLimits {
+        max_stack_size: Some(StackLimitRange {
+            range: 1024..=4096,
+            default: 2048,
+        }),
+        max_call_depth: None,
+    };
+    let limits = |max_stack_size, max_call_depth| StackLimits {
+        max_stack_size,
+        max_call_depth,
+    };
+
+    assert_eq!(supported.check("test", &limits(None, None)), Ok(()));
+    assert_eq!(supported.check("test", &limits(Some(4096), None)), Ok(()));
+    assert_eq!(
+        supported.check("test", &limits(Some(512), None)),
+        Err("invalid io.runwasi.max-stack-size annotation: 512 is out of the range 1024..=4096 supported by test, which defaults to 2048".to_string())
+    );
+    assert_eq!(
+        supported.check("test", &limits(None, Some(100))),
+        Err("the io.runwasi.max-call-depth annotation is not supported by test".to_string())
+    );
+}
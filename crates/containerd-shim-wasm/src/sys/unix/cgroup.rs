@@ -0,0 +1,877 @@
+//! Reads resource usage and OOM kills from the cgroup of a container process.
+//! The cgroup is located through `/proc/<pid>/cgroup`, so this works regardless
+//! of whether the container was created with the systemd or the cgroupfs manager.
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use containerd_shim::protos::cgroups::metrics::{
+    BlkIOEntry, BlkIOStat, CPUStat, CPUUsage, MemoryEntry, MemoryStat, Metrics, PidsStat, Throttle,
+};
+use containerd_shimkit::sandbox::Stats;
+use containerd_shimkit::sandbox::stats::v2;
+use serde::Serialize;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Cgroup {
+    /// Path of the cgroup in the unified hierarchy
+    V2(PathBuf),
+    /// Path of the cgroup for each v1 controller
+    V1(HashMap<String, PathBuf>),
+}
+
+impl Cgroup {
+    pub(super) fn for_pid(pid: i32) -> IoResult<Self> {
+        let content = read_to_string(format!("/proc/{pid}/cgroup"))?;
+        Ok(Self::parse(&content, Path::new(CGROUP_ROOT)))
+    }
+
+    /// Parses the content of a `/proc/<pid>/cgroup` file, with the cgroup filesystem mounted at `root`.
+    fn parse(content: &str, root: &Path) -> Self {
+        let unified = root.join("cgroup.controllers").exists();
+        let mut controllers = HashMap::new();
+
+        for line in content.lines() {
+            // hierarchy-ID:controller-list:cgroup-path
+            let mut parts = line.splitn(3, ':');
+            let (Some(_), Some(names), Some(path)) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            let path = path.trim_start_matches('/');
+
+            // on a hybrid host, the unified hierarchy is mounted apart, e.g., at `unified`, and
+            // only tracks the processes for systemd, while the controllers are in the v1 ones
+            if names.is_empty() {
+                if unified {
+                    return Self::V2(root.join(path));
+                }
+                continue;
+            }
+
+            // named hierarchies (like `name=systemd`) don't have any controller
+            if names.starts_with("name=") {
+                continue;
+            }
+
+            for name in names.split(',') {
+                controllers.insert(name.to_string(), root.join(names).join(path));
+            }
+        }
+
+        Self::V1(controllers)
+    }
+
+    /// Collects the metrics of the cgroup, in the message of its hierarchy.
+    /// Returns an error of kind `NotFound` if the cgroup doesn't exist anymore.
+    pub(super) fn metrics(&self) -> IoResult<Stats> {
+        match self {
+            Self::V2(path) => v2_metrics(path).map(Stats::from),
+            Self::V1(controllers) => v1_metrics(controllers).map(Stats::V1),
+        }
+    }
+
+    /// The memory usage of the cgroup, in bytes.
+    /// Returns an error of kind `NotFound` if the cgroup doesn't exist anymore.
+    pub(super) fn memory_usage(&self) -> IoResult<u64> {
+        Ok(match self.metrics()? {
+            Stats::V1(metrics) => metrics.memory.usage.usage,
+            Stats::V2(metrics) => metrics
+                .memory
+                .map(|memory| memory.usage)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// The CPU time used by the processes of the cgroup.
+    /// Returns an error of kind `NotFound` if the cgroup doesn't exist anymore.
+    pub(super) fn cpu_usage(&self) -> IoResult<Duration> {
+        Ok(match self.metrics()? {
+            Stats::V1(metrics) => Duration::from_nanos(metrics.cpu.usage.total),
+            Stats::V2(metrics) => {
+                Duration::from_micros(metrics.cpu.map(|cpu| cpu.usage_usec).unwrap_or_default())
+            }
+        })
+    }
+
+    /// Lists the pids of the processes in the cgroup, from `cgroup.procs`.
+    /// Returns an error of kind `NotFound` if the cgroup doesn't exist anymore.
+    pub(super) fn procs(&self) -> IoResult<Vec<u32>> {
+        let path = match self {
+            Self::V2(path) => path,
+            // Every v1 hierarchy has the same processes in the container's cgroup,
+            // prefer the pids controller, which is the most likely to be mounted.
+            Self::V1(controllers) => ["pids", "memory", "cpu"]
+                .iter()
+                .find_map(|name| controllers.get(*name))
+                .or_else(|| controllers.values().next())
+                .ok_or_else(|| IoError::new(ErrorKind::NotFound, "no cgroup controllers"))?,
+        };
+
+        let content = read_to_string(path.join("cgroup.procs"))?;
+        parse_procs(&content)
+    }
+
+    /// Path of the file that the memory controller updates on OOM,
+    /// or `None` if the memory controller is not available.
+    pub(super) fn oom_control(&self) -> Option<PathBuf> {
+        match self {
+            Self::V2(path) => Some(path.join("memory.events")),
+            Self::V1(controllers) => controllers
+                .get("memory")
+                .map(|path| path.join("memory.oom_control")),
+        }
+    }
+
+    /// Counts the processes of the cgroup killed by the OOM killer,
+    /// or `None` if the kernel doesn't report it.
+    pub(super) fn oom_kills(&self) -> IoResult<Option<u64>> {
+        let Some(path) = self.oom_control() else {
+            return Ok(None);
+        };
+        let content = read_optional(path)?.unwrap_or_default();
+        Ok(parse_flat_keyed(&content).get("oom_kill").copied())
+    }
+}
+
+fn parse_procs(content: &str) -> IoResult<Vec<u32>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse()
+                .map_err(|err| IoError::new(ErrorKind::InvalidData, format!("{line:?}: {err}")))
+        })
+        .collect()
+}
+
+fn v2_metrics(path: &Path) -> IoResult<v2::Metrics> {
+    if !path.exists() {
+        return Err(IoError::new(
+            ErrorKind::NotFound,
+            format!("cgroup {path:?} no longer exists"),
+        ));
+    }
+
+    let mut metrics = v2::Metrics::default();
+
+    if let Some(stat) = read_optional(path.join("cpu.stat"))? {
+        let stat = parse_flat_keyed(&stat);
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        metrics.cpu = Some(v2::CpuStat {
+            usage_usec: get("usage_usec"),
+            user_usec: get("user_usec"),
+            system_usec: get("system_usec"),
+            nr_periods: get("nr_periods"),
+            nr_throttled: get("nr_throttled"),
+            throttled_usec: get("throttled_usec"),
+        });
+    }
+
+    if let Some(usage) = read_value(path.join("memory.current"))? {
+        let stat = read_optional(path.join("memory.stat"))?.unwrap_or_default();
+        let stat = parse_flat_keyed(&stat);
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        // newer kernels only report the workingset events of anon and file pages apart
+        let workingset = |key: &str| match stat.get(key) {
+            Some(value) => *value,
+            None => get(&format!("{key}_anon")) + get(&format!("{key}_file")),
+        };
+        metrics.memory = Some(v2::MemoryStat {
+            anon: get("anon"),
+            file: get("file"),
+            kernel_stack: get("kernel_stack"),
+            slab: get("slab"),
+            sock: get("sock"),
+            shmem: get("shmem"),
+            file_mapped: get("file_mapped"),
+            file_dirty: get("file_dirty"),
+            file_writeback: get("file_writeback"),
+            anon_thp: get("anon_thp"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            slab_reclaimable: get("slab_reclaimable"),
+            slab_unreclaimable: get("slab_unreclaimable"),
+            pgfault: get("pgfault"),
+            pgmajfault: get("pgmajfault"),
+            workingset_refault: workingset("workingset_refault"),
+            workingset_activate: workingset("workingset_activate"),
+            workingset_nodereclaim: get("workingset_nodereclaim"),
+            pgrefill: get("pgrefill"),
+            pgscan: get("pgscan"),
+            pgsteal: get("pgsteal"),
+            pgactivate: get("pgactivate"),
+            pgdeactivate: get("pgdeactivate"),
+            pglazyfree: get("pglazyfree"),
+            pglazyfreed: get("pglazyfreed"),
+            thp_fault_alloc: get("thp_fault_alloc"),
+            thp_collapse_alloc: get("thp_collapse_alloc"),
+            usage,
+            usage_limit: read_value(path.join("memory.max"))?.unwrap_or(u64::MAX),
+            swap_usage: read_value(path.join("memory.swap.current"))?.unwrap_or_default(),
+            swap_limit: read_value(path.join("memory.swap.max"))?.unwrap_or(u64::MAX),
+            max_usage: read_value(path.join("memory.peak"))?.unwrap_or_default(),
+            swap_max_usage: read_value(path.join("memory.swap.peak"))?.unwrap_or_default(),
+        });
+    }
+
+    if let Some(events) = read_optional(path.join("memory.events"))? {
+        let events = parse_flat_keyed(&events);
+        let get = |key: &str| events.get(key).copied().unwrap_or_default();
+        metrics.memory_events = Some(v2::MemoryEvents {
+            low: get("low"),
+            high: get("high"),
+            max: get("max"),
+            oom: get("oom"),
+            oom_kill: get("oom_kill"),
+        });
+    }
+
+    if let Some(current) = read_value(path.join("pids.current"))? {
+        metrics.pids = Some(v2::PidsStat {
+            current,
+            limit: read_value(path.join("pids.max"))?.unwrap_or(u64::MAX),
+        });
+    }
+
+    if let Some(stat) = read_optional(path.join("io.stat"))? {
+        metrics.io = Some(v2::IoStat {
+            usage: parse_io_stat(&stat),
+        });
+    }
+
+    Ok(metrics)
+}
+
+/// Parses the `io.stat` file of cgroup v2, with a line of `key=value` pairs per device.
+fn parse_io_stat(content: &str) -> Vec<v2::IoEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (major, minor) = fields.next()?.split_once(':')?;
+            let mut entry = v2::IoEntry {
+                major: major.parse().ok()?,
+                minor: minor.parse().ok()?,
+                ..Default::default()
+            };
+            for (key, value) in fields.filter_map(|field| field.split_once('=')) {
+                let value = value.parse().unwrap_or_default();
+                match key {
+                    "rbytes" => entry.rbytes = value,
+                    "wbytes" => entry.wbytes = value,
+                    "rios" => entry.rios = value,
+                    "wios" => entry.wios = value,
+                    _ => {}
+                }
+            }
+            Some(entry)
+        })
+        .collect()
+}
+
+fn v1_metrics(controllers: &HashMap<String, PathBuf>) -> IoResult<Metrics> {
+    let existing = |name: &str| controllers.get(name).filter(|path| path.exists());
+
+    if controllers.values().all(|path| !path.exists()) {
+        return Err(IoError::new(ErrorKind::NotFound, "cgroup no longer exists"));
+    }
+
+    let mut metrics = Metrics::new();
+
+    if let Some(path) = existing("cpuacct") {
+        let throttling = match existing("cpu") {
+            Some(cpu) => read_optional(cpu.join("cpu.stat"))?,
+            None => None,
+        };
+        let throttling = parse_flat_keyed(throttling.as_deref().unwrap_or_default());
+        let get = |key: &str| throttling.get(key).copied().unwrap_or_default();
+        metrics.cpu = Some(CPUStat {
+            usage: Some(CPUUsage {
+                total: read_value(path.join("cpuacct.usage"))?.unwrap_or_default(),
+                kernel: read_value(path.join("cpuacct.usage_sys"))?.unwrap_or_default(),
+                user: read_value(path.join("cpuacct.usage_user"))?.unwrap_or_default(),
+                ..Default::default()
+            })
+            .into(),
+            throttling: Some(Throttle {
+                periods: get("nr_periods"),
+                throttled_periods: get("nr_throttled"),
+                throttled_time: get("throttled_time"),
+                ..Default::default()
+            })
+            .into(),
+            ..Default::default()
+        })
+        .into();
+    }
+
+    if let Some(path) = existing("memory") {
+        let stat = read_optional(path.join("memory.stat"))?.unwrap_or_default();
+        let stat = parse_flat_keyed(&stat);
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        let entry = |prefix: &str| -> IoResult<MemoryEntry> {
+            Ok(MemoryEntry {
+                usage: read_value(path.join(format!("{prefix}.usage_in_bytes")))?
+                    .unwrap_or_default(),
+                max: read_value(path.join(format!("{prefix}.max_usage_in_bytes")))?
+                    .unwrap_or_default(),
+                limit: read_value(path.join(format!("{prefix}.limit_in_bytes")))?
+                    .unwrap_or(u64::MAX),
+                failcnt: read_value(path.join(format!("{prefix}.failcnt")))?.unwrap_or_default(),
+                ..Default::default()
+            })
+        };
+        metrics.memory = Some(MemoryStat {
+            cache: get("cache"),
+            rss: get("rss"),
+            rss_huge: get("rss_huge"),
+            mapped_file: get("mapped_file"),
+            dirty: get("dirty"),
+            writeback: get("writeback"),
+            pg_pg_in: get("pgpgin"),
+            pg_pg_out: get("pgpgout"),
+            pg_fault: get("pgfault"),
+            pg_maj_fault: get("pgmajfault"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            hierarchical_memory_limit: get("hierarchical_memory_limit"),
+            usage: Some(entry("memory")?).into(),
+            swap: Some(entry("memory.memsw")?).into(),
+            kernel: Some(entry("memory.kmem")?).into(),
+            ..Default::default()
+        })
+        .into();
+    }
+
+    if let Some(path) = existing("pids") {
+        metrics.pids = Some(PidsStat {
+            current: read_value(path.join("pids.current"))?.unwrap_or_default(),
+            limit: read_value(path.join("pids.max"))?.unwrap_or(u64::MAX),
+            ..Default::default()
+        })
+        .into();
+    }
+
+    if let Some(path) = existing("blkio") {
+        // the stats of the CFQ scheduler are empty with the other schedulers, fall back to the
+        // stats of the throttling policy, which all of them have
+        let entries = |name: &str| -> IoResult<Vec<BlkIOEntry>> {
+            let entries = read_optional(path.join(format!("blkio.{name}")))?.unwrap_or_default();
+            let entries = parse_blkio(&entries);
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+            let entries = read_optional(path.join(format!("blkio.throttle.{name}")))?;
+            Ok(parse_blkio(entries.as_deref().unwrap_or_default()))
+        };
+        metrics.blkio = Some(BlkIOStat {
+            io_service_bytes_recursive: entries("io_service_bytes_recursive")?,
+            io_serviced_recursive: entries("io_serviced_recursive")?,
+            ..Default::default()
+        })
+        .into();
+    }
+
+    Ok(metrics)
+}
+
+/// Parses a blkio file of cgroup v1, with a `major:minor op value` line per device and
+/// operation, and a `Total value` line.
+fn parse_blkio(content: &str) -> Vec<BlkIOEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [device, op, value] = fields[..] else {
+                return None;
+            };
+            let (major, minor) = device.split_once(':')?;
+            Some(BlkIOEntry {
+                op: op.to_string(),
+                major: major.parse().ok()?,
+                minor: minor.parse().ok()?,
+                value: value.parse().ok()?,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Reads a file, returning `None` if the file doesn't exist
+/// (e.g., the controller is not enabled, or the kernel is too old).
+fn read_optional(path: impl AsRef<Path>) -> IoResult<Option<String>> {
+    match read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads a single value file, where `max` means unlimited.
+fn read_value(path: impl AsRef<Path>) -> IoResult<Option<u64>> {
+    read_optional(path)?
+        .map(|content| parse_value(&content))
+        .transpose()
+}
+
+fn parse_value(content: &str) -> IoResult<u64> {
+    match content.trim() {
+        "max" => Ok(u64::MAX),
+        value => value
+            .parse()
+            .map_err(|err| IoError::new(ErrorKind::InvalidData, format!("{value:?}: {err}"))),
+    }
+}
+
+/// Parses a flat keyed file, like `cpu.stat` or `memory.stat`.
+fn parse_flat_keyed(content: &str) -> HashMap<&str, u64> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(key, value)| Some((key, value.trim().parse().ok()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) -> Result<()> {
+        create_dir_all(dir)?;
+        for (name, content) in files {
+            write(dir.join(name), content)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_v2() -> Result<()> {
+        let root = tempdir()?;
+        write(root.path().join("cgroup.controllers"), "cpu memory pids")?;
+
+        let cgroup = Cgroup::parse("0::/kubepods/pod1/test\n", root.path());
+        assert_eq!(cgroup, Cgroup::V2(root.path().join("kubepods/pod1/test")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_v1() -> Result<()> {
+        let root = tempdir()?;
+        let content =
+            "12:pids:/test\n4:cpu,cpuacct:/test\n3:memory:/test\n1:name=systemd:/test\n0::/test\n";
+
+        let Cgroup::V1(controllers) = Cgroup::parse(content, root.path()) else {
+            panic!("expected a v1 cgroup");
+        };
+
+        assert_eq!(controllers.len(), 4);
+        assert_eq!(controllers["cpu"], root.path().join("cpu,cpuacct/test"));
+        assert_eq!(controllers["cpuacct"], root.path().join("cpu,cpuacct/test"));
+        assert_eq!(controllers["memory"], root.path().join("memory/test"));
+        assert_eq!(controllers["pids"], root.path().join("pids/test"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_hybrid() -> Result<()> {
+        let root = tempdir()?;
+        write_files(&root.path().join("unified"), &[("cgroup.controllers", "")])?;
+        let content = "4:cpu,cpuacct:/test\n3:memory:/test\n1:name=systemd:/test\n0::/test\n";
+
+        let Cgroup::V1(controllers) = Cgroup::parse(content, root.path()) else {
+            panic!("expected a v1 cgroup");
+        };
+        assert_eq!(controllers.len(), 3);
+        assert_eq!(controllers["memory"], root.path().join("memory/test"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_metrics() -> Result<()> {
+        let dir = tempdir()?;
+        write_files(
+            dir.path(),
+            &[
+                (
+                    "cpu.stat",
+                    "usage_usec 2000\nuser_usec 1500\nsystem_usec 500\nnr_periods 10\nnr_throttled 2\nthrottled_usec 30\n",
+                ),
+                ("memory.current", "4096\n"),
+                ("memory.peak", "8192\n"),
+                ("memory.max", "max\n"),
+                ("memory.stat", "anon 1024\nfile 2048\nfile_mapped 512\n"),
+                ("pids.current", "3\n"),
+                ("pids.max", "100\n"),
+            ],
+        )?;
+
+        let Stats::V2(metrics) = Cgroup::V2(dir.path().to_path_buf()).metrics()? else {
+            panic!("expected v2 metrics");
+        };
+
+        let cpu = metrics.cpu.unwrap();
+        assert_eq!(cpu.usage_usec, 2000);
+        assert_eq!(cpu.user_usec, 1500);
+        assert_eq!(cpu.system_usec, 500);
+        assert_eq!(cpu.nr_periods, 10);
+        assert_eq!(cpu.nr_throttled, 2);
+        assert_eq!(cpu.throttled_usec, 30);
+        let memory = metrics.memory.unwrap();
+        assert_eq!(memory.usage, 4096);
+        assert_eq!(memory.max_usage, 8192);
+        assert_eq!(memory.usage_limit, u64::MAX);
+        assert_eq!(memory.anon, 1024);
+        assert_eq!(memory.file, 2048);
+        assert_eq!(memory.file_mapped, 512);
+        let pids = metrics.pids.unwrap();
+        assert_eq!(pids.current, 3);
+        assert_eq!(pids.limit, 100);
+
+        Ok(())
+    }
+
+    // The files of the cgroup v2 of a container on a 6.x kernel
+    fn v2_fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/sys/unix/testdata/cgroup/v2")
+    }
+
+    // The files of the cgroup v1 of a container, one directory per hierarchy
+    fn v1_fixture() -> Cgroup {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/sys/unix/testdata/cgroup/v1");
+        Cgroup::V1(HashMap::from([
+            ("cpu".to_string(), root.join("cpu,cpuacct")),
+            ("cpuacct".to_string(), root.join("cpu,cpuacct")),
+            ("memory".to_string(), root.join("memory")),
+            ("pids".to_string(), root.join("pids")),
+            ("blkio".to_string(), root.join("blkio")),
+        ]))
+    }
+
+    #[test]
+    fn test_v2_metrics_fixture() -> Result<()> {
+        let metrics = v2_metrics(&v2_fixture())?;
+
+        assert_eq!(
+            metrics.cpu,
+            Some(v2::CpuStat {
+                usage_usec: 184271,
+                user_usec: 131044,
+                system_usec: 53227,
+                nr_periods: 214,
+                nr_throttled: 12,
+                throttled_usec: 380512,
+            })
+        );
+
+        let memory = metrics.memory.unwrap();
+        assert_eq!(memory.usage, 11862016);
+        assert_eq!(memory.usage_limit, 268435456);
+        assert_eq!(memory.max_usage, 15728640);
+        assert_eq!(memory.swap_usage, 0);
+        assert_eq!(memory.swap_limit, u64::MAX);
+        // the kernel doesn't have `memory.swap.peak` yet
+        assert_eq!(memory.swap_max_usage, 0);
+        assert_eq!(memory.anon, 2945024);
+        assert_eq!(memory.file, 8192000);
+        assert_eq!(memory.slab, 804200);
+        assert_eq!(memory.pgfault, 3245);
+
+        let cgroup = Cgroup::V2(v2_fixture());
+        assert_eq!(cgroup.cpu_usage()?, Duration::from_micros(184271));
+        assert_eq!(cgroup.memory_usage()?, 11862016);
+        assert_eq!(memory.pgmajfault, 17);
+        // reported apart for anon and file pages
+        assert_eq!(memory.workingset_refault, 12);
+        assert_eq!(memory.workingset_activate, 3);
+
+        assert_eq!(
+            metrics.memory_events,
+            Some(v2::MemoryEvents {
+                low: 0,
+                high: 0,
+                max: 4,
+                oom: 1,
+                oom_kill: 1,
+            })
+        );
+        assert_eq!(
+            metrics.pids,
+            Some(v2::PidsStat {
+                current: 4,
+                limit: 1024,
+            })
+        );
+        assert_eq!(
+            metrics.io.unwrap().usage,
+            [
+                v2::IoEntry {
+                    major: 259,
+                    minor: 0,
+                    rbytes: 5734400,
+                    wbytes: 1232896,
+                    rios: 178,
+                    wios: 41,
+                },
+                v2::IoEntry {
+                    major: 8,
+                    minor: 0,
+                    rbytes: 4096,
+                    wbytes: 0,
+                    rios: 1,
+                    wios: 0,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v1_metrics_fixture() -> Result<()> {
+        let Stats::V1(metrics) = v1_fixture().metrics()? else {
+            panic!("expected v1 metrics");
+        };
+
+        assert_eq!(metrics.cpu.usage.total, 184271000);
+        assert_eq!(metrics.cpu.usage.user, 131044000);
+        assert_eq!(metrics.cpu.usage.kernel, 53227000);
+        assert_eq!(metrics.cpu.throttling.periods, 214);
+        assert_eq!(metrics.cpu.throttling.throttled_periods, 12);
+        assert_eq!(metrics.cpu.throttling.throttled_time, 380512000);
+
+        let cgroup = v1_fixture();
+        assert_eq!(cgroup.cpu_usage()?, Duration::from_nanos(184271000));
+        assert_eq!(cgroup.memory_usage()?, metrics.memory.usage.usage);
+
+        assert_eq!(metrics.memory.usage.usage, 11862016);
+        assert_eq!(metrics.memory.usage.max, 15728640);
+        assert_eq!(metrics.memory.usage.limit, 268435456);
+        assert_eq!(metrics.memory.swap.usage, 11862016);
+        assert_eq!(metrics.memory.swap.limit, 9223372036854771712);
+        // the kernel memory accounting files are gone from newer kernels
+        assert_eq!(metrics.memory.kernel.usage, 0);
+        assert_eq!(metrics.memory.cache, 8192000);
+        assert_eq!(metrics.memory.rss, 2945024);
+        assert_eq!(metrics.memory.hierarchical_memory_limit, 268435456);
+
+        assert_eq!(metrics.pids.current, 4);
+        assert_eq!(metrics.pids.limit, u64::MAX);
+
+        // the CFQ stats are empty, so the throttling ones are reported
+        let entry = |op: &str, value| BlkIOEntry {
+            op: op.to_string(),
+            major: 8,
+            minor: 0,
+            value,
+            ..Default::default()
+        };
+        assert_eq!(
+            metrics.blkio.io_service_bytes_recursive,
+            [
+                entry("Read", 5734400),
+                entry("Write", 1232896),
+                entry("Sync", 6967296),
+                entry("Async", 0),
+                entry("Discard", 0),
+                entry("Total", 6967296),
+            ]
+        );
+        assert_eq!(
+            metrics.blkio.io_serviced_recursive,
+            [
+                entry("Read", 178),
+                entry("Write", 41),
+                entry("Sync", 219),
+                entry("Async", 0),
+                entry("Discard", 0),
+                entry("Total", 219),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_metrics_missing_controllers() -> Result<()> {
+        let dir = tempdir()?;
+        write_files(dir.path(), &[("memory.current", "4096\n")])?;
+
+        let metrics = v2_metrics(dir.path())?;
+
+        assert!(metrics.cpu.is_none());
+        assert!(metrics.pids.is_none());
+        assert!(metrics.io.is_none());
+        let memory = metrics.memory.unwrap();
+        assert_eq!(memory.usage, 4096);
+        assert_eq!(memory.max_usage, 0);
+        assert_eq!(memory.swap_usage, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v1_metrics() -> Result<()> {
+        let root = tempdir()?;
+        let cpu = root.path().join("cpu,cpuacct/test");
+        let memory = root.path().join("memory/test");
+        let pids = root.path().join("pids/test");
+        write_files(
+            &cpu,
+            &[
+                ("cpuacct.usage", "3000\n"),
+                ("cpuacct.usage_user", "2000\n"),
+                ("cpuacct.usage_sys", "1000\n"),
+                (
+                    "cpu.stat",
+                    "nr_periods 5\nnr_throttled 1\nthrottled_time 40\n",
+                ),
+            ],
+        )?;
+        write_files(
+            &memory,
+            &[
+                ("memory.usage_in_bytes", "4096\n"),
+                ("memory.max_usage_in_bytes", "8192\n"),
+                ("memory.limit_in_bytes", "16384\n"),
+                ("memory.failcnt", "1\n"),
+                ("memory.stat", "cache 2048\nrss 1024\n"),
+            ],
+        )?;
+        write_files(&pids, &[("pids.current", "2\n"), ("pids.max", "max\n")])?;
+
+        let cgroup = Cgroup::V1(HashMap::from([
+            ("cpu".to_string(), cpu.clone()),
+            ("cpuacct".to_string(), cpu),
+            ("memory".to_string(), memory),
+            ("pids".to_string(), pids),
+        ]));
+        let Stats::V1(metrics) = cgroup.metrics()? else {
+            panic!("expected v1 metrics");
+        };
+
+        assert_eq!(metrics.cpu.usage.total, 3000);
+        assert_eq!(metrics.cpu.usage.user, 2000);
+        assert_eq!(metrics.cpu.usage.kernel, 1000);
+        assert_eq!(metrics.cpu.throttling.periods, 5);
+        assert_eq!(metrics.cpu.throttling.throttled_time, 40);
+        assert_eq!(metrics.memory.usage.usage, 4096);
+        assert_eq!(metrics.memory.usage.max, 8192);
+        assert_eq!(metrics.memory.usage.limit, 16384);
+        assert_eq!(metrics.memory.usage.failcnt, 1);
+        assert_eq!(metrics.memory.cache, 2048);
+        assert_eq!(metrics.memory.rss, 1024);
+        assert_eq!(metrics.pids.current, 2);
+        assert_eq!(metrics.pids.limit, u64::MAX);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_torn_down() -> Result<()> {
+        let root = tempdir()?;
+
+        let err = Cgroup::V2(root.path().join("gone")).metrics().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let cgroup = Cgroup::V1(HashMap::from([(
+            "memory".to_string(),
+            root.path().join("memory/gone"),
+        )]));
+        let err = cgroup.metrics().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_procs() -> Result<()> {
+        let root = tempdir()?;
+        let v2 = root.path().join("unified/test");
+        write_files(&v2, &[("cgroup.procs", "42\n43\n\n")])?;
+        assert_eq!(Cgroup::V2(v2).procs()?, vec![42, 43]);
+
+        let pids = root.path().join("pids/test");
+        let memory = root.path().join("memory/test");
+        write_files(&pids, &[("cgroup.procs", "7\n")])?;
+        write_files(&memory, &[("cgroup.procs", "7\n")])?;
+        let cgroup = Cgroup::V1(HashMap::from([
+            ("memory".to_string(), memory),
+            ("pids".to_string(), pids),
+        ]));
+        assert_eq!(cgroup.procs()?, vec![7]);
+
+        let err = Cgroup::V2(root.path().join("gone")).procs().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_oom_kills() -> Result<()> {
+        let root = tempdir()?;
+        let v2 = root.path().join("unified/test");
+        write_files(
+            &v2,
+            &[("memory.events", "low 0\nhigh 0\nmax 3\noom 2\noom_kill 1\n")],
+        )?;
+        assert_eq!(Cgroup::V2(v2).oom_kills()?, Some(1));
+
+        let memory = root.path().join("memory/test");
+        write_files(
+            &memory,
+            &[("memory.oom_control", "oom_kill_disable 0\nunder_oom 0\n")],
+        )?;
+        let cgroup = Cgroup::V1(HashMap::from([("memory".to_string(), memory)]));
+        assert_eq!(cgroup.oom_kills()?, None);
+
+        let cgroup = Cgroup::V1(HashMap::new());
+        assert_eq!(cgroup.oom_control(), None);
+        assert_eq!(cgroup.oom_kills()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_procs() -> Result<()> {
+        assert_eq!(parse_procs("")?, Vec::<u32>::new());
+        assert_eq!(parse_procs("1\n2\n3\n")?, vec![1, 2, 3]);
+        assert_eq!(
+            parse_procs("1\nfoo\n").unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_value() -> Result<()> {
+        assert_eq!(parse_value("42\n")?, 42);
+        assert_eq!(parse_value("max\n")?, u64::MAX);
+        assert_eq!(
+            parse_value("nan").unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+//! Checkpoint and restore of wasm instances.
+//!
+//! The engine runs inside the container, so the shim and the engine coordinate through
+//! a `checkpoint` directory of the bundle. The zygote opens it for the executor before the
+//! init process enters the rootfs, which keeps it out of the reach of the guest, and works with
+//! read-only rootfs:
+//! * To checkpoint, the shim sends [`CHECKPOINT_SIGNAL`] to the init process. The executor
+//!   asks the engine to serialize its state into the `store` directory and then writes
+//!   the outcome to the `status` file. The shim copies the state to the checkpoint path,
+//!   together with the OCI spec and stdio layout of the instance.
+//! * To restore, the shim copies the `store` directory from the checkpoint path into the
+//!   bundle before starting the container, and the executor restores from it instead of
+//!   calling the entrypoint.
+//!
+//! The executor reaches the directory through `/proc/self/fd`, which the engines are given as
+//! the path of the state, so the container needs its `/proc` mount.
+
+use std::fs::{
+    DirBuilder, File, copy, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file,
+    rename, write,
+};
+use std::io::{ErrorKind, Result as IoResult};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::{DirBuilderExt as _, chown};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use containerd_shimkit::sandbox::{Error as SandboxError, InstanceConfig};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{SignalKind, signal};
+
+use super::user_namespace::to_host_id;
+use crate::sandbox::Sandbox;
+use crate::sandbox::context::RuntimeContext;
+
+/// Signal used by the shim to request a checkpoint from the executor
+pub(super) const CHECKPOINT_SIGNAL: Signal = Signal::SIGUSR2;
+
+/// Location of the checkpoint directory, relative to the bundle of the container
+const CHECKPOINT_DIR: &str = "checkpoint";
+const STORE_DIR: &str = "store";
+const RESTORE_DIR: &str = "restore";
+const STATUS_FILE: &str = "status";
+const STATUS_OK: &str = "ok";
+
+const CHECKPOINT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The stdio layout of a checkpointed instance
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct StdioLayout {
+    stdin: PathBuf,
+    stdout: PathBuf,
+    stderr: PathBuf,
+}
+
+/// Shim side: checkpoint the instance with init process `pid` into `path`.
+pub(super) async fn checkpoint(
+    pid: i32,
+    cfg: &InstanceConfig,
+    path: &Path,
+) -> Result<(), SandboxError> {
+    if !catches_signal(pid, CHECKPOINT_SIGNAL)? {
+        return Err(SandboxError::FailedPrecondition(
+            "the instance doesn't support checkpointing".to_string(),
+        ));
+    }
+
+    // the executor holds the directory open, so only its content is reset
+    let dir = cfg.bundle.join(CHECKPOINT_DIR);
+    clear_dir(&dir)?;
+
+    kill(Pid::from_raw(pid), CHECKPOINT_SIGNAL)?;
+
+    let status = tokio::time::timeout(CHECKPOINT_TIMEOUT, wait_for_status(&dir))
+        .await
+        .map_err(|_| {
+            SandboxError::FailedPrecondition("timeout waiting for the checkpoint".to_string())
+        })??;
+    if status != STATUS_OK {
+        return Err(SandboxError::FailedPrecondition(status));
+    }
+
+    create_dir_all(path)?;
+    copy_dir(&dir.join(STORE_DIR), &path.join(STORE_DIR))?;
+    copy(cfg.bundle.join("config.json"), path.join("config.json"))?;
+
+    let stdio = StdioLayout {
+        stdin: cfg.stdin.clone(),
+        stdout: cfg.stdout.clone(),
+        stderr: cfg.stderr.clone(),
+    };
+    write(path.join("stdio.json"), serde_json::to_vec(&stdio)?)?;
+
+    Ok(())
+}
+
+/// Shim side: stage the checkpoint in `path` in the `bundle` of the container, so that the
+/// executor restores from it when the container starts.
+pub(super) fn prepare_restore(path: &Path, bundle: &Path) -> Result<(), SandboxError> {
+    if !path.join("config.json").exists() || !path.join(STORE_DIR).is_dir() {
+        return Err(SandboxError::InvalidArgument(format!(
+            "{path:?} is not a checkpoint directory"
+        )));
+    }
+
+    let dir = bundle.join(CHECKPOINT_DIR);
+    create_private_dir(&dir)?;
+    clear_dir(&dir)?;
+    copy_dir(&path.join(STORE_DIR), &dir.join(STORE_DIR))?;
+
+    Ok(())
+}
+
+/// Zygote side: opens the checkpoint directory of the `bundle` for the executor of the init
+/// process, owned by the user of the process of `spec` so that the executor can write to it.
+/// As with the start FIFO, the directory stays open in the init process the zygote forks.
+pub(super) fn open(bundle: &Path, spec: &Spec) -> IoResult<File> {
+    let dir = bundle.join(CHECKPOINT_DIR);
+    create_private_dir(&dir)?;
+    if let Some(user) = spec.process().as_ref().map(|process| process.user()) {
+        let linux = spec.linux().as_ref();
+        let uid_mappings = linux.and_then(|l| l.uid_mappings().as_deref());
+        let gid_mappings = linux.and_then(|l| l.gid_mappings().as_deref());
+        let uid = match uid_mappings {
+            Some(mappings) if !mappings.is_empty() => to_host_id(user.uid(), mappings),
+            _ => Some(user.uid()),
+        };
+        let gid = match gid_mappings {
+            Some(mappings) if !mappings.is_empty() => to_host_id(user.gid(), mappings),
+            _ => Some(user.gid()),
+        };
+        chown(&dir, uid, gid)?;
+    }
+    File::open(dir)
+}
+
+/// Executor side: the path the executor reaches the checkpoint directory `dir` at.
+fn dir_path(dir: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+}
+
+/// Executor side: returns the directory to restore the instance from, if the shim staged a
+/// checkpoint in the checkpoint directory `dir`.
+/// The staged checkpoint is consumed, so that a restarted init process doesn't restore from it.
+pub(super) fn take_restore_dir(dir: &File) -> Option<PathBuf> {
+    let dir = dir_path(dir);
+    let store = dir.join(STORE_DIR);
+    if !store.is_dir() {
+        return None;
+    }
+
+    let restore = dir.join(RESTORE_DIR);
+    let _ = remove_dir_all(&restore);
+    match rename(&store, &restore) {
+        Ok(()) => Some(restore),
+        Err(err) => {
+            log::error!("failed to stage checkpoint for restore: {err}");
+            None
+        }
+    }
+}
+
+/// Executor side: serve checkpoint requests from the shim in the checkpoint directory `dir`
+/// until an error occurs.
+pub(super) async fn serve(
+    sandbox: &impl Sandbox,
+    ctx: &impl RuntimeContext,
+    dir: &File,
+) -> Result<()> {
+    let mut signals = signal(SignalKind::from_raw(CHECKPOINT_SIGNAL as i32))?;
+    let dir = dir_path(dir);
+
+    while signals.recv().await.is_some() {
+        log::info!("checkpointing instance");
+        let store = dir.join(STORE_DIR);
+        let checkpoint = async {
+            create_dir_all(&store)?;
+            sandbox.checkpoint(ctx, &store).await
+        };
+        let status = match checkpoint.await {
+            Ok(()) => STATUS_OK.to_string(),
+            Err(err) => format!("{err:#}"),
+        };
+        write(dir.join(STATUS_FILE), status).context("failed to write checkpoint status")?;
+    }
+
+    bail!("checkpoint signal stream closed")
+}
+
+async fn wait_for_status(dir: &Path) -> IoResult<String> {
+    loop {
+        match read_to_string(dir.join(STATUS_FILE)) {
+            Ok(status) => return Ok(status),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Creates `dir` if it doesn't exist, only accessible to its owner
+fn create_private_dir(dir: &Path) -> IoResult<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(err) if err.kind() != ErrorKind::AlreadyExists => Err(err),
+        _ => Ok(()),
+    }
+}
+
+// Removes the content of `dir`
+fn clear_dir(dir: &Path) -> IoResult<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_dir_all(entry.path())?;
+        } else {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> IoResult<()> {
+    create_dir_all(dst)?;
+    for entry in read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks if the process `pid` has a handler installed for `signal`.
+fn catches_signal(pid: i32, signal: Signal) -> IoResult<bool> {
+    let status = read_to_string(format!("/proc/{pid}/status"))?;
+    Ok(parse_caught_signals(&status).is_some_and(|mask| mask & (1 << (signal as i32 - 1)) != 0))
+}
+
+fn parse_caught_signals(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigCgt:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir;
+    use std::os::unix::fs::MetadataExt as _;
+
+    use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder, UserBuilder};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_caught_signals() {
+        let status = "Name:\twasm\nSigIgn:\t0000000000001000\nSigCgt:\t0000000000000800\n";
+        let mask = parse_caught_signals(status).unwrap();
+        assert_ne!(mask & (1 << (Signal::SIGUSR2 as i32 - 1)), 0);
+        assert_eq!(mask & (1 << (Signal::SIGUSR1 as i32 - 1)), 0);
+
+        assert_eq!(parse_caught_signals("Name:\twasm\n"), None);
+    }
+
+    #[test]
+    fn test_copy_dir() -> Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        create_dir_all(src.join("nested"))?;
+        write(src.join("memory"), "memory")?;
+        write(src.join("nested/globals"), "globals")?;
+
+        copy_dir(&src, &dir.path().join("dst"))?;
+
+        assert_eq!(read_to_string(dir.path().join("dst/memory"))?, "memory");
+        assert_eq!(
+            read_to_string(dir.path().join("dst/nested/globals"))?,
+            "globals"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare_restore() -> Result<()> {
+        let bundle = tempdir()?;
+        create_dir(bundle.path().join("rootfs"))?;
+        // the directory is owned by the user of the test
+        let owner = bundle.path().metadata()?;
+        let user = UserBuilder::default()
+            .uid(owner.uid())
+            .gid(owner.gid())
+            .build()?;
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").user(user).build()?)
+            .build()?;
+        spec.save(bundle.path().join("config.json"))?;
+
+        let checkpoint = tempdir()?;
+        let err = prepare_restore(checkpoint.path(), bundle.path()).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        create_dir(checkpoint.path().join(STORE_DIR))?;
+        write(checkpoint.path().join("store/memory"), "memory")?;
+        copy(
+            bundle.path().join("config.json"),
+            checkpoint.path().join("config.json"),
+        )?;
+
+        prepare_restore(checkpoint.path(), bundle.path())?;
+
+        // staged out of the rootfs
+        assert_eq!(read_dir(bundle.path().join("rootfs"))?.count(), 0);
+        let staged = bundle.path().join(CHECKPOINT_DIR);
+        assert_eq!(read_to_string(staged.join("store/memory"))?, "memory");
+
+        let dir = open(bundle.path(), &spec)?;
+        let restore = take_restore_dir(&dir).context("no staged checkpoint")?;
+        assert_eq!(read_to_string(restore.join("memory"))?, "memory");
+        assert_eq!(take_restore_dir(&dir), None);
+
+        // the content is reset for the next checkpoint, while the directory stays open
+        clear_dir(&staged)?;
+        assert_eq!(read_dir(dir_path(&dir))?.count(), 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! Sweeps what's left of a container that youki couldn't delete, e.g., when its creation failed
+//! half-way, or when a delete is retried after a previous one removed part of it.
+//!
+//! The state of a container is its directory in the root directory of the shim, where youki
+//! records the path of the cgroup in `youki_config.json` before creating it, and the cgroup
+//! itself. Whatever is already gone is skipped, so that sweeping a container twice is a no-op.
+//!
+//! The containers orphaned by a crashed shim, or by a node crash when the root directory isn't
+//! on a tmpfs, are swept by [`sweep_orphans`]. The shim sweeps its root directory once, in the
+//! background, when it creates its first instance in it, unless `instances.orphan_sweep` disables
+//! it. Only the containers whose init process is gone and whose bundle containerd removed are
+//! orphans, so the running containers, and the exited ones containerd still has to delete, are
+//! never touched. The module cache has nothing left to sweep: its files are shared by all the
+//! containers, and the interrupted writes are removed whenever a shim loads them.
+
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use libcgroups::common::{CgroupConfig, CgroupManager as _, create_cgroup_manager};
+use libcontainer::config::YoukiConfig;
+use libcontainer::container::State;
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+
+use crate::shim::{InstancesConfig, ShimConfig};
+
+// The start time of a process only has the resolution of a clock tick, after a boot time in
+// seconds, and the container is created right after its init process
+const START_TIME_SLACK: Duration = Duration::from_secs(2);
+
+// The root directories the shim swept, or is sweeping
+static SWEPT_ROOTDIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Removes the cgroup and the state directory of the container `id` in `rootdir`.
+pub(super) fn sweep(rootdir: &Path, id: &str, systemd_cgroup: bool) -> Result<()> {
+    let swept = sweep_with(&rootdir.join(id), |cgroup_path| {
+        remove_cgroup(cgroup_path, id, systemd_cgroup)
+    })?;
+    if swept.is_empty() {
+        log::debug!("nothing was left of container {id} to sweep");
+    }
+    for what in swept {
+        log::debug!("swept the {what} of container {id}");
+    }
+    Ok(())
+}
+
+// Sweeps the state directory `dir`, with `remove_cgroup` removing the cgroup it records, and
+// returns what was removed
+fn sweep_with(
+    dir: &Path,
+    remove_cgroup: impl FnOnce(PathBuf) -> Result<()>,
+) -> Result<Vec<String>> {
+    let mut swept = vec![];
+
+    // the directory is only removed once the cgroup is gone, so that a retry still finds it
+    if let Ok(config) = YoukiConfig::load(dir) {
+        let path = config.cgroup_path;
+        remove_cgroup(path.clone()).with_context(|| format!("failed to remove cgroup {path:?}"))?;
+        swept.push(format!("cgroup {path:?}"));
+    }
+
+    match std::fs::remove_dir_all(dir) {
+        Ok(()) => swept.push(format!("state directory {dir:?}")),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to remove state directory {dir:?}"));
+        }
+    }
+
+    Ok(swept)
+}
+
+/// Sweeps the containers in `rootdir`, the root directory of a shim in a namespace, e.g.,
+/// `/run/containerd/<engine>/<namespace>`, that were orphaned by a crashed shim, and returns
+/// their ids. With `dry_run`, the orphaned containers are only logged.
+///
+/// The containers that can't be swept are logged and skipped.
+pub fn sweep_orphans(rootdir: impl AsRef<Path>, dry_run: bool) -> Result<Vec<String>> {
+    let rootdir = rootdir.as_ref();
+    let entries = match std::fs::read_dir(rootdir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {rootdir:?}")),
+    };
+
+    let mut orphans = vec![];
+    for entry in entries {
+        let dir = entry?.path();
+        let Some(id) = dir.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !dir.is_dir() {
+            continue;
+        }
+        let state = match State::load(&dir) {
+            Ok(state) => state,
+            Err(err) => {
+                log::debug!("not sweeping container {id} without a readable state: {err}");
+                continue;
+            }
+        };
+        if !is_orphaned(&state, process_start_time) {
+            continue;
+        }
+        if dry_run {
+            log::info!("container {id} in {rootdir:?} is orphaned, not sweeping it in a dry run");
+        } else {
+            log::info!("sweeping orphaned container {id} in {rootdir:?}");
+            if let Err(err) = sweep(rootdir, id, state.use_systemd) {
+                log::warn!("failed to sweep orphaned container {id}: {err:#}");
+                continue;
+            }
+        }
+        orphans.push(id.to_string());
+    }
+    Ok(orphans)
+}
+
+/// Shim side: sweeps the orphaned containers in `rootdir` in the background, unless the shim
+/// already swept it, or `instances.orphan_sweep` disables the sweep.
+pub(super) fn sweep_orphans_once(rootdir: &Path) {
+    let Some(dry_run) = orphan_sweep_mode(&ShimConfig::get().instances) else {
+        return;
+    };
+    if !SWEPT_ROOTDIRS.lock().unwrap().insert(rootdir.to_path_buf()) {
+        return;
+    }
+    let rootdir = rootdir.to_path_buf();
+    tokio::task::spawn_blocking(move || match sweep_orphans(&rootdir, dry_run) {
+        Ok(orphans) if orphans.is_empty() => {
+            log::debug!("no orphaned containers in {rootdir:?}");
+        }
+        Ok(_) => {}
+        Err(err) => log::warn!("failed to sweep the orphaned containers in {rootdir:?}: {err:#}"),
+    });
+}
+
+// Returns whether the orphaned containers are only logged with the `orphan_sweep` of `config`:
+// `true`, the default, `false` to disable the sweep, or `dry-run`, or `None` if they aren't swept
+fn orphan_sweep_mode(config: &InstancesConfig) -> Option<bool> {
+    match config.orphan_sweep.as_deref() {
+        None | Some("true") => Some(false),
+        Some("false") => None,
+        Some("dry-run") => Some(true),
+        Some(value) => {
+            log::warn!(
+                "invalid `instances.orphan_sweep` {value:?}, only logging the orphaned containers"
+            );
+            Some(true)
+        }
+    }
+}
+
+// Whether the container with `state` is orphaned: containerd removed its bundle, which it only
+// does once the container is deleted or its shim is gone, and its init process is gone too,
+// when its pid doesn't exist or belongs to a process started after the container was created
+fn is_orphaned(state: &State, start_time: impl FnOnce(i32) -> Option<SystemTime>) -> bool {
+    if state.bundle.exists() {
+        return false;
+    }
+    // the creation crashed before forking the init process
+    let Some(pid) = state.pid else {
+        return true;
+    };
+    match start_time(pid) {
+        None => true,
+        Some(started) => state
+            .created
+            .is_some_and(|created| started > SystemTime::from(created) + START_TIME_SLACK),
+    }
+}
+
+// The start time of the process `pid`, or `None` if there is no such process
+fn process_start_time(pid: i32) -> Option<SystemTime> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the start time, in clock ticks since the boot, is the 22nd field, and the 20th after the
+    // name of the process, which is in parentheses and can contain spaces
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let ticks_per_sec = u64::try_from(unsafe { libc::sysconf(libc::_SC_CLK_TCK) }).ok()?;
+    if ticks_per_sec == 0 {
+        return None;
+    }
+
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let boot_time: u64 = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let since_boot = Duration::from_millis(ticks.saturating_mul(1000) / ticks_per_sec);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(boot_time) + since_boot)
+}
+
+fn remove_cgroup(cgroup_path: PathBuf, id: &str, systemd_cgroup: bool) -> Result<()> {
+    let manager = create_cgroup_manager(CgroupConfig {
+        cgroup_path,
+        systemd_cgroup,
+        container_name: id.to_string(),
+    })?;
+    // the processes of a container that was never started are still in its cgroup, with the
+    // pids of the version of nix of libcgroups
+    for pid in manager.get_all_pids().unwrap_or_default() {
+        let _ = kill(Pid::from_raw(pid.as_raw()), Signal::SIGKILL);
+    }
+    manager.remove()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::process::Command;
+
+    use anyhow::bail;
+    use chrono::{DateTime, Utc};
+    use libcontainer::container::ContainerStatus;
+
+    use super::*;
+
+    fn save_config(dir: &Path, cgroup_path: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        // the config is non-exhaustive
+        let config: YoukiConfig = serde_json::from_value(serde_json::json!({
+            "hooks": null,
+            "cgroup_path": cgroup_path,
+        }))?;
+        config.save(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_partial_state() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let dir = root.path().join("test");
+        let removed = Cell::new(None);
+        let remove = |path: PathBuf| -> Result<()> {
+            removed.set(Some(path));
+            Ok(())
+        };
+
+        // nothing was created
+        assert!(sweep_with(&dir, remove)?.is_empty());
+
+        // the creation failed before the cgroup was recorded
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("state.json"), "{")?;
+        assert_eq!(sweep_with(&dir, remove)?.len(), 1);
+        assert!(!dir.exists());
+        assert_eq!(removed.take(), None);
+
+        // the cgroup was recorded, and possibly created
+        save_config(&dir, "/runwasi/test")?;
+        assert_eq!(sweep_with(&dir, remove)?.len(), 2);
+        assert!(!dir.exists());
+        assert_eq!(removed.take(), Some(PathBuf::from("/runwasi/test")));
+
+        // the second sweep is a no-op
+        assert!(sweep_with(&dir, remove)?.is_empty());
+        assert_eq!(removed.take(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_retries_cgroup() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let dir = root.path().join("test");
+        save_config(&dir, "/runwasi/test")?;
+
+        // the state directory is kept when the cgroup can't be removed
+        let err = sweep_with(&dir, |_| bail!("device or resource busy")).unwrap_err();
+        assert!(format!("{err:#}").contains("/runwasi/test"), "{err:#}");
+        assert!(dir.exists());
+
+        // so that the retry removes the cgroup
+        let swept = sweep_with(&dir, |_| Ok(()))?;
+        assert_eq!(swept.len(), 2, "{swept:?}");
+        assert!(!dir.exists());
+
+        Ok(())
+    }
+
+    fn state(pid: Option<i32>, bundle: &Path, created: DateTime<Utc>) -> State {
+        let mut state = State::new("test", ContainerStatus::Running, pid, bundle.to_path_buf());
+        state.created = Some(created);
+        state
+    }
+
+    #[test]
+    fn test_is_orphaned() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        let removed = bundle.path().join("removed");
+        let created = DateTime::from_timestamp(1_000_000, 0).context("invalid timestamp")?;
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+
+        // containerd still has the bundle, whether the container runs or has to be deleted
+        assert!(!is_orphaned(
+            &state(Some(1), bundle.path(), created),
+            |_| None
+        ));
+        assert!(!is_orphaned(
+            &state(Some(1), bundle.path(), created),
+            |_| at(1)
+        ));
+
+        // the init process is gone, or was never forked
+        assert!(is_orphaned(&state(Some(1), &removed, created), |_| None));
+        assert!(is_orphaned(&state(None, &removed, created), |_| at(1)));
+
+        // the init process still runs, or its pid was reused after the container was created
+        assert!(!is_orphaned(&state(Some(1), &removed, created), |_| at(
+            999_999
+        )));
+        assert!(!is_orphaned(&state(Some(1), &removed, created), |_| at(
+            1_000_001
+        )));
+        assert!(is_orphaned(&state(Some(1), &removed, created), |_| at(
+            1_000_060
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_start_time() -> Result<()> {
+        let started = process_start_time(std::process::id() as i32).context("no start time")?;
+        assert!(started <= SystemTime::now() + START_TIME_SLACK);
+
+        let mut child = Command::new("true").spawn()?;
+        let pid = child.id() as i32;
+        child.wait()?;
+        assert_eq!(process_start_time(pid), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_orphans() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let bundle = tempfile::tempdir()?;
+        let pid = std::process::id() as i32;
+        let running = state(Some(pid), bundle.path(), Utc::now());
+        let orphaned = state(None, &bundle.path().join("removed"), Utc::now());
+        for (id, state) in [("running", running), ("orphaned", orphaned)] {
+            let dir = root.path().join(id);
+            std::fs::create_dir_all(&dir)?;
+            state.save(&dir)?;
+        }
+        // a creation that didn't get to record the state
+        std::fs::create_dir_all(root.path().join("creating"))?;
+
+        // a dry run only reports the orphan
+        assert_eq!(sweep_orphans(root.path(), true)?, ["orphaned"]);
+        assert!(root.path().join("orphaned").exists());
+
+        assert_eq!(sweep_orphans(root.path(), false)?, ["orphaned"]);
+        assert!(!root.path().join("orphaned").exists());
+        assert!(root.path().join("running").exists());
+        assert!(root.path().join("creating").exists());
+
+        assert!(sweep_orphans(root.path().join("missing"), false)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_sweep_mode() {
+        let mode = |orphan_sweep: Option<&str>| {
+            orphan_sweep_mode(&InstancesConfig {
+                orphan_sweep: orphan_sweep.map(Into::into),
+                ..Default::default()
+            })
+        };
+        assert_eq!(mode(None), Some(false));
+        assert_eq!(mode(Some("false")), None);
+        assert_eq!(mode(Some("dry-run")), Some(true));
+        // a typo never sweeps more than asked
+        assert_eq!(mode(Some("dryrun")), Some(true));
+    }
+}
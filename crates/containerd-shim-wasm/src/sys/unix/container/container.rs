@@ -3,11 +3,17 @@ use std::io::Error as IoError;
 use std::mem::transmute;
 
 use anyhow::{Context, anyhow};
+use containerd_shimkit::sandbox::Error as SandboxError;
 use containerd_shimkit::zygote::{WireError, Zygote};
+use libcgroups::common::{CgroupConfig, CgroupManager as _, ControllerOpt, create_cgroup_manager};
 use libcontainer::container::Container as YoukiContainer;
+use libcontainer::error::LibcontainerError;
 use libcontainer::signal::Signal;
-use serde::Serialize;
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::Pid;
+use oci_spec::runtime::LinuxResources;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
 thread_local! {
     // The youki's Container will live in a static inside the zygote process.
@@ -15,6 +21,43 @@ thread_local! {
     static CONTAINER: RefCell<Option<YoukiContainer>> = RefCell::default();
 }
 
+/// Why a container couldn't be signaled.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KillError {
+    /// The container has no process left to signal.
+    NotRunning,
+    /// The shim isn't allowed to signal the processes of the container.
+    PermissionDenied(String),
+    Other(String),
+}
+
+impl From<LibcontainerError> for KillError {
+    fn from(err: LibcontainerError) -> Self {
+        // the errno of libcontainer is from its own version of nix
+        match err {
+            LibcontainerError::IncorrectStatus => Self::NotRunning,
+            LibcontainerError::OtherSyscall(errno) if errno as i32 == libc::ESRCH => {
+                Self::NotRunning
+            }
+            LibcontainerError::OtherSyscall(errno) if errno as i32 == libc::EPERM => {
+                Self::PermissionDenied(format!("failed to signal the container: {errno}"))
+            }
+            err => Self::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<KillError> for SandboxError {
+    fn from(err: KillError) -> Self {
+        match err {
+            // runc's error, which containerd and its clients ignore
+            KillError::NotRunning => Self::NotFound("process already finished".to_string()),
+            KillError::PermissionDenied(err) => Self::PermissionDenied(err),
+            KillError::Other(err) => Self::Others(err),
+        }
+    }
+}
+
 // The exposed container is just a wrapper around the zygore process
 pub struct Container(Zygote);
 
@@ -30,6 +73,18 @@ impl Container {
 
         Ok(container)
     }
+
+    // Replaces the exited container with the one `f` builds in the same zygote, e.g., to restart
+    // it. The old one is deleted first, as the new one has the same id.
+    pub fn rebuild<Arg: Serialize + DeserializeOwned + 'static>(
+        &self,
+        f: fn(Arg) -> anyhow::Result<YoukiContainer>,
+        arg: Arg,
+    ) -> anyhow::Result<()> {
+        self.delete()
+            .context("failed to delete the exited container")?;
+        self.run_init(f, arg)
+    }
 }
 
 // Wrap the youki's Container methods that we use
@@ -39,21 +94,63 @@ impl Container {
             .context("Failed to obtain PID")
     }
 
+    // The status of the container, e.g., `Stopped` if its init process has exited
+    pub fn status(&self) -> anyhow::Result<String> {
+        self.run(
+            |c, _| {
+                c.refresh_status()?;
+                Ok(c.status().to_string())
+            },
+            (),
+        )
+    }
+
     pub fn start(&self) -> anyhow::Result<()> {
         self.run(|c, _| Ok(c.start()?), ())
     }
-    pub fn kill(&self, signal: u32) -> anyhow::Result<()> {
+    // Signals the init process, or every process of the container's cgroup if `all` is set.
+    // youki's errors don't make it out of the zygote, so they're classified in there.
+    pub fn kill(&self, signal: u32, all: bool) -> Result<(), KillError> {
         self.run(
-            |c, signal| {
+            |c, (signal, all)| {
                 let signal = Signal::try_from(signal as i32).context("invalid signal number")?;
-                Ok(c.kill(signal, true)?)
+                Ok(c.kill(signal, all).map_err(KillError::from))
             },
-            signal,
+            (signal, all),
         )
+        .unwrap_or_else(|err| Err(KillError::Other(format!("{err:#}"))))
     }
     pub fn delete(&self) -> anyhow::Result<()> {
         self.run(|c, _| Ok(c.delete(true)?), ())
     }
+    // youki freezes the container's cgroup, using `cgroup.freeze` on v2 and the freezer controller on v1
+    pub fn pause(&self) -> anyhow::Result<()> {
+        self.run(|c, _| Ok(c.pause()?), ())
+    }
+    pub fn resume(&self) -> anyhow::Result<()> {
+        self.run(|c, _| Ok(c.resume()?), ())
+    }
+    // Applies the new limits to the live cgroup the same way `youki update` does,
+    // using the cgroup manager (systemd or cgroupfs) the container was created with.
+    pub fn update(&self, resources: LinuxResources) -> anyhow::Result<()> {
+        self.run(
+            |c, resources| {
+                let manager = create_cgroup_manager(CgroupConfig {
+                    cgroup_path: c.spec()?.cgroup_path,
+                    systemd_cgroup: c.systemd(),
+                    container_name: c.id().to_string(),
+                })?;
+                manager.apply(&ControllerOpt {
+                    resources: &resources,
+                    disable_oom_killer: false,
+                    oom_score_adj: None,
+                    freezer_state: None,
+                })?;
+                Ok(())
+            },
+            resources,
+        )
+    }
 }
 
 impl Container {
@@ -112,3 +209,50 @@ impl Container {
         )
     }
 }
+
+// An exec'd process is a youki tenant container that lives in its own zygote process.
+// The tenant process is a child of that zygote, so that's where it gets reaped.
+pub struct Tenant(Zygote);
+
+impl Tenant {
+    /// Runs `f` in a new zygote process, `f` should build the tenant and return its pid.
+    pub fn build<Arg: Serialize + DeserializeOwned + 'static>(
+        f: fn(Arg) -> anyhow::Result<i32>,
+        arg: Arg,
+    ) -> anyhow::Result<(Self, i32)> {
+        let zygote = Zygote::global().spawn();
+        let pid = zygote
+            .run(
+                |(f, arg)| -> Result<i32, WireError> {
+                    let f: fn(Arg) -> anyhow::Result<i32> = unsafe { transmute(f) };
+                    Ok(f(arg).map_err(IoError::other)?)
+                },
+                (f as usize, arg),
+            )
+            .map_err(|e| anyhow!(e))?;
+
+        Ok((Tenant(zygote), pid))
+    }
+
+    /// Blocks until the tenant process with the given pid exits, and returns its exit code.
+    pub fn wait(&self, pid: i32) -> anyhow::Result<u32> {
+        self.0
+            .run(
+                |pid| -> Result<u32, WireError> {
+                    let status = match waitpid(Pid::from_raw(pid), None).map_err(IoError::from)? {
+                        WaitStatus::Exited(_, status) => status,
+                        WaitStatus::Signaled(_, sig, _) => 128 + sig as i32,
+                        res => {
+                            return Err(IoError::other(format!(
+                                "waitpid unexpected result: {res:?}"
+                            ))
+                            .into());
+                        }
+                    };
+                    Ok(status as u32)
+                },
+                pid,
+            )
+            .map_err(|e| anyhow!(e))
+    }
+}
@@ -0,0 +1,124 @@
+//! CPU time limits of wasm instances.
+//!
+//! A guest stuck in a loop burns a core until the pod is deleted. With the
+//! [`CPU_TIME_LIMIT_ANNOTATION`] annotation, the executor asks the engine to interrupt the guest
+//! with [`Sandbox::terminate`] once the instance used up its CPU time, and the instance exits with
+//! [`CPU_TIME_EXCEEDED_EXIT_CODE`].
+//!
+//! The limit is on the CPU time of the instance process, not on wall-clock time, so it doesn't
+//! run while the instance is paused, and a guest throttled by its CPU quota gets the same amount
+//! of work done before it's interrupted.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::Spec;
+
+use super::terminate::parse_duration;
+use crate::sandbox::Sandbox;
+use crate::sandbox::context::RuntimeContext;
+
+/// Annotation with the CPU time the instance can use, e.g., `30s`.
+/// Plain numbers are interpreted as seconds.
+pub(super) const CPU_TIME_LIMIT_ANNOTATION: &str = "io.runwasi.cpu-time-limit";
+
+/// Exit code of an instance that exceeded its CPU time limit, like a process killed by `SIGXCPU`
+pub(super) const CPU_TIME_EXCEEDED_EXIT_CODE: i32 = 128 + libc::SIGXCPU;
+
+// The CPU time used is checked at least this often, so that a guest using more than a core
+// can't go much over its limit
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the CPU time limit of the instance with runtime spec `spec`, if it has one.
+pub(super) fn cpu_time_limit(spec: &Spec) -> Result<Option<Duration>, SandboxError> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(CPU_TIME_LIMIT_ANNOTATION))
+    else {
+        return Ok(None);
+    };
+
+    match parse_duration(value) {
+        Some(limit) if !limit.is_zero() => Ok(Some(limit)),
+        _ => Err(SandboxError::InvalidArgument(format!(
+            "invalid {CPU_TIME_LIMIT_ANNOTATION} annotation: {value:?}"
+        ))),
+    }
+}
+
+/// Returns the CPU time used by the current process, in all its threads.
+pub(super) fn process_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec, and the clock is supported on all the unix targets
+    if unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Executor side: interrupt the guest once the process used `limit` of CPU time, if set.
+/// `exceeded` is set when the limit is reached, so that the executor can tell an interrupted
+/// guest from a failure.
+/// Resolves to the exit code of the process if it should exit right away, i.e., if the engine
+/// doesn't support interrupting the guest.
+pub(super) async fn enforce(
+    sandbox: &impl Sandbox,
+    ctx: &impl RuntimeContext,
+    limit: Option<Duration>,
+    exceeded: &AtomicBool,
+) -> Result<i32> {
+    let Some(limit) = limit else {
+        return std::future::pending().await;
+    };
+
+    let start = process_cpu_time();
+    let used = loop {
+        let used = process_cpu_time().saturating_sub(start);
+        if used >= limit {
+            break used;
+        }
+        tokio::time::sleep((limit - used).min(MAX_CHECK_INTERVAL)).await;
+    };
+
+    log::warn!("the instance used {used:?} of CPU time, over its limit of {limit:?}");
+    exceeded.store(true, Ordering::SeqCst);
+    if let Err(err) = sandbox.terminate(ctx).await {
+        log::info!("exiting without interrupting the guest: {err}");
+        return Ok(CPU_TIME_EXCEEDED_EXIT_CODE);
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with_cpu_time_limit(value: Option<&str>) -> Result<Spec> {
+        let annotation = value.map(|value| (CPU_TIME_LIMIT_ANNOTATION, value));
+        spec_with_annotations(annotation.as_slice())
+    }
+
+    #[test]
+    fn test_cpu_time_limit() -> Result<()> {
+        let spec = spec_with_cpu_time_limit(None)?;
+        assert_eq!(cpu_time_limit(&spec)?, None);
+
+        let spec = spec_with_cpu_time_limit(Some("30s"))?;
+        assert_eq!(cpu_time_limit(&spec)?, Some(Duration::from_secs(30)));
+
+        for value in ["0s", "forever", "-1"] {
+            let spec = spec_with_cpu_time_limit(Some(value))?;
+            let err = cpu_time_limit(&spec).unwrap_err();
+            assert!(matches!(err, SandboxError::InvalidArgument(_)));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! Devices of the host passed through to the containers, e.g., the GPUs or NPUs a wasi-nn
+//! backend of the engine runs the inference on.
+//!
+//! youki creates the nodes of the `linux.devices` of the spec in the container and allows them
+//! in its device cgroup, so that the engine can open them. Instead of listing every node, the
+//! containers can ask for the common sets of devices with the [`DEVICES_ANNOTATION`], e.g.,
+//! `io.runwasi.devices=dri`, which are added to their spec with the ids and the permissions of
+//! the nodes on the host.
+//!
+//! The devices of the spec are checked to exist on the host before the container is created, so
+//! that a missing one fails the creation with its path rather than with a bare `ENOENT`.
+
+use std::fs::{Metadata, read_dir};
+use std::io::Result as IoResult;
+use std::os::unix::fs::{FileTypeExt as _, MetadataExt as _};
+use std::path::{Path, PathBuf};
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::{
+    LinuxDevice, LinuxDeviceBuilder, LinuxDeviceCgroup, LinuxDeviceCgroupBuilder, LinuxDeviceType,
+    Spec,
+};
+
+/// Annotation with the comma-separated sets of host devices to pass through to the container:
+/// * `dri` for the nodes of `/dev/dri`, the GPUs of the DRM drivers, e.g., through Vulkan or
+///   OpenCL.
+/// * `nvidia` for the `/dev/nvidia*` nodes of the NVIDIA driver, e.g., through CUDA.
+pub(super) const DEVICES_ANNOTATION: &str = "io.runwasi.devices";
+
+// The devices of the host
+const HOST_DEV: &str = "/dev";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceSet {
+    Dri,
+    Nvidia,
+}
+
+impl DeviceSet {
+    const ALL: [Self; 2] = [Self::Dri, Self::Nvidia];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Dri => "dri",
+            Self::Nvidia => "nvidia",
+        }
+    }
+
+    // The paths of the char devices of the set in `dev`, the devices of the host
+    fn paths(self, dev: &Path) -> IoResult<Vec<PathBuf>> {
+        let (dir, prefix) = match self {
+            Self::Dri => (dev.join("dri"), ""),
+            Self::Nvidia => (dev.to_path_buf(), "nvidia"),
+        };
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !name.starts_with(prefix) {
+                continue;
+            }
+            // e.g., the `by-path` links of `/dev/dri`, or `/dev/nvidia-caps`
+            if path
+                .metadata()
+                .is_ok_and(|m| m.file_type().is_char_device())
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// Shim side: checks that the devices of the `spec` of the container `id` exist on the host,
+/// and adds the devices of its [`DEVICES_ANNOTATION`] to it, and to the `config.json` of its
+/// `bundle`.
+pub(super) fn add_devices(id: &str, bundle: &Path, spec: &mut Spec) -> Result<(), SandboxError> {
+    check_host_devices(id, spec)?;
+    let sets = device_sets(spec)?;
+    if sets.is_empty() {
+        return Ok(());
+    }
+
+    let mut added = vec![];
+    for set in sets {
+        let paths = set.paths(Path::new(HOST_DEV))?;
+        if paths.is_empty() {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "instance {id} asks for the {} devices of {DEVICES_ANNOTATION}, but the host has none",
+                set.name()
+            )));
+        }
+        for path in paths {
+            added.push(device(&path, Path::new(HOST_DEV), &path.metadata()?)?);
+        }
+    }
+
+    log::info!(
+        "passing the devices {:?} through to instance {id}",
+        added.iter().map(LinuxDevice::path).collect::<Vec<_>>()
+    );
+    with_devices(spec, added)?;
+    spec.save(bundle.join("config.json"))?;
+    Ok(())
+}
+
+fn device_sets(spec: &Spec) -> Result<Vec<DeviceSet>, SandboxError> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(DEVICES_ANNOTATION))
+    else {
+        return Ok(vec![]);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            DeviceSet::ALL
+                .into_iter()
+                .find(|set| set.name() == name)
+                .ok_or_else(|| {
+                    SandboxError::InvalidArgument(format!(
+                        "invalid {DEVICES_ANNOTATION} annotation: {value:?}"
+                    ))
+                })
+        })
+        .collect()
+}
+
+fn check_host_devices(id: &str, spec: &Spec) -> Result<(), SandboxError> {
+    let devices = spec
+        .linux()
+        .as_ref()
+        .and_then(|linux| linux.devices().as_ref());
+    for device in devices.into_iter().flatten() {
+        // youki creates the FIFOs itself
+        // typos:disable-next-line - false positive "typ"
+        if device.typ() == LinuxDeviceType::P {
+            continue;
+        }
+        let path = device.path();
+        if !path.exists() {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "the device {path:?} of instance {id} doesn't exist on the host"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// The device of the container for the node at `path` of the devices `dev` of the host, with its
+// `metadata`
+fn device(path: &Path, dev: &Path, metadata: &Metadata) -> Result<LinuxDevice, SandboxError> {
+    let relative = path.strip_prefix(dev).unwrap_or(path);
+    let rdev = metadata.rdev();
+    Ok(LinuxDeviceBuilder::default()
+        .path(Path::new(HOST_DEV).join(relative))
+        // typos:disable-next-line - false positive "typ"
+        .typ(LinuxDeviceType::C)
+        .major(i64::from(libc::major(rdev)))
+        .minor(i64::from(libc::minor(rdev)))
+        .file_mode(metadata.mode() & 0o7777)
+        .uid(metadata.uid())
+        .gid(metadata.gid())
+        .build()?)
+}
+
+// Adds `added` to the devices of `spec`, and allows them in its device cgroup
+fn with_devices(spec: &mut Spec, added: Vec<LinuxDevice>) -> Result<(), SandboxError> {
+    let Some(linux) = spec.linux_mut() else {
+        return Ok(());
+    };
+    let mut devices = linux.devices().clone().unwrap_or_default();
+    let mut resources = linux.resources().clone().unwrap_or_default();
+    let mut rules = resources.devices().clone().unwrap_or_default();
+
+    for device in added {
+        if devices.iter().any(|d| d.path() == device.path()) {
+            continue;
+        }
+        rules.push(cgroup_rule(&device)?);
+        devices.push(device);
+    }
+
+    resources.set_devices(Some(rules));
+    linux.set_resources(Some(resources));
+    linux.set_devices(Some(devices));
+    Ok(())
+}
+
+fn cgroup_rule(device: &LinuxDevice) -> Result<LinuxDeviceCgroup, SandboxError> {
+    Ok(LinuxDeviceCgroupBuilder::default()
+        .allow(true)
+        // typos:disable-next-line - false positive "typ"
+        .typ(device.typ())
+        .major(device.major())
+        .minor(device.minor())
+        .access("rwm")
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use anyhow::Result;
+    use oci_spec::runtime::LinuxBuilder;
+
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with(annotation: Option<&str>, devices: Vec<LinuxDevice>) -> Result<Spec> {
+        let annotation = annotation.map(|value| (DEVICES_ANNOTATION, value));
+        let mut spec = spec_with_annotations(annotation.as_slice())?;
+        spec.set_linux(Some(LinuxBuilder::default().devices(devices).build()?));
+        Ok(spec)
+    }
+
+    #[test]
+    fn test_device_sets() -> Result<()> {
+        assert!(device_sets(&spec_with(None, vec![])?)?.is_empty());
+        assert_eq!(
+            device_sets(&spec_with(Some("dri, nvidia"), vec![])?)?,
+            [DeviceSet::Dri, DeviceSet::Nvidia]
+        );
+        let err = device_sets(&spec_with(Some("dri,tpu"), vec![])?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_set_paths() -> Result<()> {
+        // char devices standing in for the ones of the host
+        let dev = tempfile::tempdir()?;
+        std::fs::create_dir_all(dev.path().join("dri/by-path"))?;
+        std::fs::create_dir(dev.path().join("nvidia-caps"))?;
+        for name in [
+            "dri/card0",
+            "dri/renderD128",
+            "nvidia0",
+            "nvidiactl",
+            "null",
+        ] {
+            symlink("/dev/null", dev.path().join(name))?;
+        }
+
+        assert_eq!(
+            DeviceSet::Dri.paths(dev.path())?,
+            [
+                dev.path().join("dri/card0"),
+                dev.path().join("dri/renderD128")
+            ]
+        );
+        assert_eq!(
+            DeviceSet::Nvidia.paths(dev.path())?,
+            [dev.path().join("nvidia0"), dev.path().join("nvidiactl")]
+        );
+        assert!(
+            DeviceSet::Dri
+                .paths(&dev.path().join("missing"))?
+                .is_empty()
+        );
+
+        let path = dev.path().join("dri/renderD128");
+        let device = device(&path, dev.path(), &path.metadata()?)?;
+        assert_eq!(device.path(), Path::new("/dev/dri/renderD128"));
+        // /dev/null
+        assert_eq!((device.major(), device.minor()), (1, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_devices() -> Result<()> {
+        let null = Path::new("/dev/null");
+        let listed = device(null, Path::new(HOST_DEV), &null.metadata()?)?;
+        let mut spec = spec_with(None, vec![listed.clone()])?;
+        check_host_devices("test", &spec)?;
+
+        let zero = Path::new("/dev/zero");
+        let added = device(zero, Path::new(HOST_DEV), &zero.metadata()?)?;
+        with_devices(&mut spec, vec![listed, added])?;
+
+        let linux = spec.linux().as_ref().unwrap();
+        let paths: Vec<_> = linux
+            .devices()
+            .iter()
+            .flatten()
+            .map(LinuxDevice::path)
+            .collect();
+        assert_eq!(paths, [null, zero]);
+        // only the added device gets a rule
+        let rules = linux
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .as_ref()
+            .unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!((rules[0].major(), rules[0].minor()), (Some(1), Some(5)));
+        assert_eq!(rules[0].access().as_deref(), Some("rwm"));
+
+        let missing = LinuxDeviceBuilder::default()
+            .path("/dev/missing-gpu")
+            // typos:disable-next-line - false positive "typ"
+            .typ(LinuxDeviceType::C)
+            .major(1)
+            .minor(1)
+            .build()?;
+        let err = check_host_devices("test", &spec_with(None, vec![missing])?).unwrap_err();
+        assert!(matches!(err, SandboxError::FailedPrecondition(_)), "{err}");
+        assert!(err.to_string().contains("/dev/missing-gpu"), "{err}");
+
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
 use std::cell::OnceCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::prelude::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use anyhow::{Context, Result, bail};
 use containerd_shimkit::AmbientRuntime;
@@ -15,15 +17,46 @@ use libcontainer::workload::{
 };
 use oci_spec::runtime::Spec;
 
+use super::{
+    checkpoint, cpu_time, exit_status, lsm, pause, privileges, rlimits, terminate, tmpfs, validate,
+};
 use crate::sandbox::Sandbox;
-use crate::sandbox::context::{RuntimeContext, Source, WasiContext, WasmLayer};
-use crate::sandbox::path::PathResolve;
+use crate::sandbox::context::{
+    CAPABILITIES_ANNOTATION, COREDUMP_ANNOTATION, Capabilities, Capability, ENTRYPOINT_ANNOTATION,
+    Listeners, Preopen, RuntimeContext, Source, WasiContext, WasmLayer, entrypoint_layer,
+    listen_ports, precompiled_files_allowed, validate_only,
+};
+use crate::sandbox::error::Error;
+use crate::sandbox::path::{PathResolve, WASM_SHEBANG, resolve_entrypoint};
+use crate::sandbox::wasi_config::WasiConfig;
 use crate::shim::Shim;
 
+/// Annotation with a comma-separated list of mount destinations that are not preopened for
+/// the guest, e.g., `/var/secrets`, which also covers the mounts under them.
+const NO_PREOPEN_ANNOTATION: &str = "io.runwasi.no-preopen";
+
+/// Annotation with the exported function to call instead of `_start`, e.g., `init`. It's the same
+/// as `module.wasm#init` in the args of the process, which take precedence over it.
+const INVOKE_ANNOTATION: &str = "io.runwasi.invoke";
+
+/// Annotations with comma-separated lists of glob patterns of env var names, e.g., `APP_*,RUST_LOG`.
+/// If `io.runwasi.env-allow` is set, only the env vars it matches are passed to the guest,
+/// and the ones `io.runwasi.env-deny` matches never are.
+const ENV_ALLOW_ANNOTATION: &str = "io.runwasi.env-allow";
+const ENV_DENY_ANNOTATION: &str = "io.runwasi.env-deny";
+
+// Mounts of the runtime, rather than of the user, that are not preopened, e.g., `/dev/shm`
+const SYSTEM_MOUNTS: &[&str] = &["/proc", "/sys", "/dev"];
+
+// Types of the mounts of kernel interfaces rather than of files, which are never preopened
+const PSEUDO_FS_TYPES: &[&str] = &["proc", "sysfs", "mqueue", "devpts", "cgroup", "cgroup2"];
+
 #[derive(Clone)]
 enum ExecutorType<S: Shim> {
     Wasm(S::Sandbox),
     Linux,
+    // the pause container of a pod
+    Pause,
     CantHandle,
 }
 
@@ -36,8 +69,22 @@ impl<S: Shim> Clone for Executor<S> {
 }
 
 pub(crate) struct InnerExecutor<S: Shim> {
+    // the id of the container
+    id: String,
+    // whether the container is the pause container of a pod, which has no wasm layers
+    pause: bool,
     ty: OnceCell<ExecutorType<S>>,
+    // the runtime spec, with the process of the image config if it has no args,
+    // the wasm layers, with the entrypoint one annotated,
+    // and the file of the entrypoint in the rootfs if there are no wasm layers
+    resolved: OnceCell<(Spec, Vec<WasmLayer>, Option<Result<PathBuf>>)>,
     wasm_layers: Vec<WasmLayer>,
+    // the FIFO the start of the guest is reported on, see `start_deadline`
+    started: Option<File>,
+    // the directory the checkpoints of the guest are kept in, see `checkpoint`
+    checkpoint: Option<File>,
+    // the file the exit code of the guest is recorded in, see `exit_status`
+    exit_status: Option<File>,
 }
 
 impl<S: Shim> LibcontainerExecutor for Executor<S> {
@@ -58,18 +105,130 @@ impl<S: Shim> LibcontainerExecutor for Executor<S> {
             ExecutorType::CantHandle => Err(LibcontainerExecutorError::CantHandle(S::name())),
             ExecutorType::Linux => {
                 log::info!("executing linux container");
+                self.ctx(spec).guest_started();
                 DefaultExecutor {}.exec(spec)
             }
+            ExecutorType::Pause => pause::run(),
             ExecutorType::Wasm(container) => {
+                let executing = Instant::now();
                 let ctx = self.ctx(spec);
-                log::info!("calling start function");
-                match container.run_wasi(&ctx).block_on() {
-                    Ok(code) => std::process::exit(code),
+                // the cwd of the spec was entered before the executor was called
+                match (ctx.spec.process(), spec.process()) {
+                    (Some(process), Some(spec_process)) if process.cwd() != spec_process.cwd() => {
+                        let cwd = process.cwd();
+                        std::env::set_current_dir(cwd).map_err(|err| {
+                            LibcontainerExecutorError::Other(format!(
+                                "failed to enter the working directory {cwd:?} of the image: {err}"
+                            ))
+                        })?;
+                    }
+                    _ => {}
+                }
+                if let Some(process) = ctx.spec.process() {
+                    rlimits::apply(process)
+                        .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                    lsm::apply(process)
+                        .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                    privileges::check(process)
+                        .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                }
+                check_run_config_preopens(ctx.wasm_layers)
+                    .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                check_cwd(ctx.cwd())
+                    .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                // the mounts are only visible now that the root of the container was entered
+                let preopens = mount_preopens(ctx.spec);
+                // validated when the instance was created
+                if validate_only(ctx.spec).unwrap_or_default() {
+                    // the guest isn't run, so its ports aren't bound either
+                    let ctx = WasiContext {
+                        preopens: &preopens,
+                        ..ctx
+                    };
+                    std::process::exit(validate::run::<S>(container, &ctx))
+                }
+                // and the ports are bound in the network namespace of the container
+                let listeners = bind_listeners(&ctx)
+                    .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                // from the env of the spec, before it's removed without the env capability
+                let wasi_config = WasiConfig::from_spec(spec)
+                    .map(Arc::new)
+                    .map_err(|err| LibcontainerExecutorError::Other(format!("{err:#}")))?;
+                let ctx = WasiContext {
+                    preopens: &preopens,
+                    listeners: Some(&listeners),
+                    wasi_config: Some(&wasi_config),
+                    ..ctx
+                };
+                let checkpoint_dir = self.0.checkpoint.as_ref();
+                let run = async {
+                    let setup = executing.elapsed();
+                    if !S::reports_guest_started() {
+                        ctx.guest_started();
+                    }
+                    match checkpoint_dir.and_then(checkpoint::take_restore_dir) {
+                        Some(dir) => {
+                            log::info!("restoring from checkpoint after {setup:?} of setup");
+                            container.restore(&ctx, &dir).await
+                        }
+                        None => {
+                            log::info!("calling start function after {setup:?} of setup");
+                            container.run_wasi(&ctx).await
+                        }
+                    }
+                };
+                let serve_checkpoints = async {
+                    // only the init process is checkpointed, the exec'd ones have no directory
+                    if let Some(dir) = checkpoint_dir {
+                        if let Err(err) = checkpoint::serve(container, &ctx, dir).await {
+                            log::warn!("checkpoint requests won't be served: {err}");
+                        }
+                    }
+                    std::future::pending::<Result<i32>>().await
+                };
+                let terminating = AtomicBool::new(false);
+                let handle_sigterm = terminate::serve(container, &ctx, &terminating);
+                // validated when the instance was created
+                let cpu_time_limit = cpu_time::cpu_time_limit(ctx.spec).unwrap_or_default();
+                let cpu_time_exceeded = AtomicBool::new(false);
+                let enforce_cpu_time =
+                    cpu_time::enforce(container, &ctx, cpu_time_limit, &cpu_time_exceeded);
+                let res = async {
+                    tokio::select! {
+                        res = run => res,
+                        res = serve_checkpoints => res,
+                        res = handle_sigterm => res,
+                        res = enforce_cpu_time => res,
+                    }
+                };
+                let code = match res.block_on() {
+                    res if cpu_time_exceeded.load(Ordering::SeqCst) => {
+                        log::info!("start function interrupted over its CPU time limit: {res:?}");
+                        cpu_time::CPU_TIME_EXCEEDED_EXIT_CODE
+                    }
+                    Ok(code) => code,
+                    Err(err) if terminating.load(Ordering::SeqCst) => {
+                        log::info!("start function interrupted: {err}");
+                        terminate::SIGTERM_EXIT_CODE
+                    }
                     Err(err) => {
-                        log::info!("error running start function: {err}");
-                        std::process::exit(137)
+                        match err.downcast_ref::<Error>() {
+                            Some(Error::Runtime {
+                                trap: Some(trap), ..
+                            }) => log::info!("start function trapped with {trap}: {err}"),
+                            _ => log::info!("error running start function: {err}"),
+                        }
+                        137
                     }
                 };
+                log::info!(
+                    "exiting with code {code} after {:?} of CPU time",
+                    cpu_time::process_cpu_time()
+                );
+                if let Some(file) = &self.0.exit_status {
+                    exit_status::record(file, code);
+                }
+                std::process::exit(code)
             }
         }
     }
@@ -91,26 +250,99 @@ impl<S: Shim> LibcontainerExecutor for Executor<S> {
 }
 
 impl<S: Shim> Executor<S> {
-    pub fn new(wasm_layers: Vec<WasmLayer>) -> Self {
+    /// An executor of the container `id`, reporting the start of its guest on `started`, and
+    /// checkpointing and restoring it in the `checkpoint` directory, and recording the exit code
+    /// of its guest in `exit_status`.
+    pub fn new(
+        id: String,
+        wasm_layers: Vec<WasmLayer>,
+        started: Option<File>,
+        checkpoint: Option<File>,
+        exit_status: Option<File>,
+    ) -> Self {
         Self(Arc::new(InnerExecutor {
+            id,
+            pause: false,
             ty: Default::default(),
+            resolved: Default::default(),
             wasm_layers,
+            started,
+            checkpoint,
+            exit_status,
         }))
     }
 
-    fn ctx<'a>(&'a self, spec: &'a Spec) -> WasiContext<'a> {
-        let wasm_layers = &self.0.wasm_layers;
-        WasiContext { spec, wasm_layers }
+    /// An executor of the pause container `id` of a pod, which runs the built-in pause.
+    pub fn pause(id: String) -> Self {
+        Self(Arc::new(InnerExecutor {
+            id,
+            pause: true,
+            ty: Default::default(),
+            resolved: Default::default(),
+            wasm_layers: vec![],
+            started: None,
+            checkpoint: None,
+            exit_status: None,
+        }))
+    }
+
+    fn ctx<'a>(&'a self, spec: &Spec) -> WasiContext<'a> {
+        let (spec, wasm_layers, entrypoint_file) = self.resolved(spec);
+        WasiContext {
+            spec,
+            wasm_layers,
+            preopens: &[],
+            precompiled_extensions: S::precompiled_extensions(),
+            entrypoint_file: entrypoint_file
+                .as_ref()
+                .and_then(|file| file.as_ref().ok())
+                .map(PathBuf::as_path),
+            started: self.0.started.as_ref(),
+            listeners: None,
+            wasi_config: None,
+        }
+    }
+
+    fn resolved(&self, spec: &Spec) -> &(Spec, Vec<WasmLayer>, Option<Result<PathBuf>>) {
+        self.0.resolved.get_or_init(|| {
+            let spec = with_image_process(spec, &self.0.wasm_layers);
+            let spec = with_invoke(&spec);
+            let spec = with_run_config(&spec, &self.0.wasm_layers);
+            let spec = with_default_args(&spec, &self.0.wasm_layers);
+            let spec = with_process_env(&spec);
+            let spec = with_filtered_env(&spec);
+            let spec = with_env_capability(&spec);
+            let spec = with_coredump_id(&spec, &self.0.id);
+            let wasm_layers = with_entrypoint_layer(&spec, &self.0.wasm_layers);
+            let entrypoint_file = wasm_layers
+                .is_empty()
+                .then(|| resolve_entrypoint_file(&spec));
+            (spec, wasm_layers, entrypoint_file)
+        })
     }
 
     fn ty(&self, spec: &Spec) -> &ExecutorType<S> {
         self.0.ty.get_or_init(|| {
+            if self.0.pause {
+                return ExecutorType::Pause;
+            }
             let ctx = &self.ctx(spec);
             match is_linux_container(ctx) {
                 Ok(_) => ExecutorType::Linux,
                 Err(err) => {
                     log::debug!("error checking if linux container: {err}. Fallback to wasm container");
-                    let container = S::Sandbox::default();
+                    if let (_, _, Some(Err(err))) = self.resolved(spec) {
+                        log::error!("{err:#}");
+                        return ExecutorType::CantHandle;
+                    }
+                    if ctx.is_precompiled_file() && !precompiled_files_allowed() {
+                        log::error!(
+                            "the entrypoint is a file precompiled by {}, which is only run when the shim is started with `layers.allow_precompiled_files`",
+                            S::name()
+                        );
+                        return ExecutorType::CantHandle;
+                    }
+                    let container = S::Sandbox::new(ctx);
                     match container.can_handle(ctx).block_on() {
                         Ok(_) => ExecutorType::Wasm(container),
                         Err(err) => {
@@ -125,10 +357,498 @@ impl<S: Shim> Executor<S> {
     }
 }
 
+// Resolves the entrypoint of a container without wasm layers in its rootfs, which is the root
+// of the filesystem once libcontainer entered it. The bare names are searched in the `PATH` of
+// the container rather than in the one of the shim, as the executor doesn't set the env vars.
+fn resolve_entrypoint_file(spec: &Spec) -> Result<PathBuf> {
+    let process = spec.process().as_ref();
+    let arg0 = process
+        .and_then(|p| p.args().as_ref())
+        .and_then(|args| args.first())
+        .context("no entrypoint provided")?;
+    let (arg0, _) = arg0.split_once('#').unwrap_or((arg0, ""));
+    let shim_path = std::env::var("PATH").ok();
+    let search_path = process
+        .and_then(|p| p.env().as_ref())
+        .into_iter()
+        .flatten()
+        .find_map(|env| env.strip_prefix("PATH="))
+        .or(shim_path.as_deref());
+    let cwd = process.map_or(Path::new("/"), |p| p.cwd().as_path());
+    resolve_entrypoint(Path::new("/"), Path::new(arg0), search_path, cwd)
+}
+
+// Fills the process of `spec` from the image config of the wasm layers if it has no args.
+// containerd derives the process from the image config of container images, but not from the
+// one of wasm OCI artifacts. The env and cwd of the spec still win over the image ones.
+fn with_image_process(spec: &Spec, wasm_layers: &[WasmLayer]) -> Spec {
+    let mut spec = spec.clone();
+    let Some(config) = wasm_layers
+        .iter()
+        .find_map(|layer| layer.image_config.as_ref())
+    else {
+        return spec;
+    };
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+    if process.args().as_ref().is_some_and(|args| !args.is_empty()) {
+        return spec;
+    }
+
+    let args: Vec<_> = config
+        .entrypoint()
+        .iter()
+        .chain(config.cmd())
+        .flatten()
+        .cloned()
+        .collect();
+    if args.is_empty() {
+        return spec;
+    }
+    log::info!("using the process of the image config: {args:?}");
+    process.set_args(Some(args));
+
+    let env = with_default_env(
+        config.env().as_deref().unwrap_or_default(),
+        process.env().clone().unwrap_or_default(),
+    );
+    process.set_env(Some(env));
+
+    match config.working_dir() {
+        Some(dir) if !dir.is_empty() && process.cwd() == Path::new("/") => {
+            process.set_cwd(dir.into());
+        }
+        _ => {}
+    }
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Appends the function of `INVOKE_ANNOTATION` to the entrypoint of the process of `spec`,
+// unless it already names one.
+fn with_invoke(spec: &Spec) -> Spec {
+    let mut spec = spec.clone();
+    let Some(func) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(INVOKE_ANNOTATION))
+        .filter(|func| !func.is_empty())
+        .cloned()
+    else {
+        return spec;
+    };
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+
+    let mut args = process.args().clone().unwrap_or_default();
+    if args.is_empty() {
+        // the module / component is selected by the wasm layers rather than by its path
+        args.push(String::new());
+    }
+    if args[0].contains('#') {
+        return spec;
+    }
+    log::info!("calling the exported function {func:?} of {INVOKE_ANNOTATION}");
+    args[0] = format!("{}#{func}", args[0]);
+    process.set_args(Some(args));
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Fills the process of `spec` from the run config of the wasm layers: the args after the
+// entrypoint if it has none, the exported function if the entrypoint doesn't name one,
+// and the env vars it doesn't set.
+fn with_run_config(spec: &Spec, wasm_layers: &[WasmLayer]) -> Spec {
+    let mut spec = spec.clone();
+    let Some(config) = wasm_layers
+        .iter()
+        .find_map(|layer| layer.run_config.as_ref())
+    else {
+        return spec;
+    };
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+
+    let mut args = process.args().clone().unwrap_or_default();
+    if args.is_empty() && (config.export.is_some() || !config.args.is_empty()) {
+        // the module / component is selected by the wasm layers rather than by its path
+        args.push(String::new());
+    }
+    match &config.export {
+        Some(export) if !args[0].contains('#') => args[0] = format!("{}#{export}", args[0]),
+        _ => {}
+    }
+    if args.len() == 1 {
+        args.extend(config.args.iter().cloned());
+    }
+    log::info!("using the run config of the image: {args:?}");
+    process.set_args(Some(args));
+
+    let env = with_default_env(&config.env, process.env().clone().unwrap_or_default());
+    process.set_env(Some(env));
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Sets the args of the process of `spec` to start the single wasm layer, i.e., to call its
+// `_start`, when neither the spec, the image config nor the run config of the layer have them.
+fn with_default_args(spec: &Spec, wasm_layers: &[WasmLayer]) -> Spec {
+    let mut spec = spec.clone();
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+    if wasm_layers.len() != 1 || process.args().as_ref().is_some_and(|args| !args.is_empty()) {
+        return spec;
+    }
+    log::info!("starting the single wasm layer without the args of a process");
+    // the module / component is selected by the wasm layers rather than by its path
+    process.set_args(Some(vec![String::new()]));
+
+    spec.set_process(Some(process));
+    spec
+}
+
+/// Checks that the process of `spec` has args, or that the executor can derive them for the
+/// `wasm_layers` of the container, so that a container it can't start fails to be created.
+/// CRI can create the containers of artifact images with no `process.args`.
+pub(crate) fn check_process_args(spec: &Spec, wasm_layers: &[WasmLayer]) -> Result<()> {
+    let spec = with_image_process(spec, wasm_layers);
+    let spec = with_invoke(&spec);
+    let spec = with_run_config(&spec, wasm_layers);
+    let spec = with_default_args(&spec, wasm_layers);
+    let args = spec.process().as_ref().and_then(|p| p.args().as_ref());
+    if args.is_some_and(|args| !args.is_empty()) {
+        return Ok(());
+    }
+    bail!(
+        "the process of the container has no args, and the image has {} wasm layers and no entrypoint: \
+        set the args of the process, or the Entrypoint or Cmd of the image config",
+        wasm_layers.len()
+    )
+}
+
+// Sets the `PWD`, `UID`, `GID` and `GROUPS` env vars of the process of `spec`, unless it already
+// does, from its cwd and user, which WASI doesn't expose to the guest otherwise.
+// `GROUPS` is a comma-separated list of the additional gids.
+fn with_process_env(spec: &Spec) -> Spec {
+    let mut spec = spec.clone();
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+
+    let user = process.user();
+    let groups: Vec<_> = user
+        .additional_gids()
+        .iter()
+        .flatten()
+        .map(u32::to_string)
+        .collect();
+    let defaults = [
+        format!("PWD={}", process.cwd().display()),
+        format!("UID={}", user.uid()),
+        format!("GID={}", user.gid()),
+        format!("GROUPS={}", groups.join(",")),
+    ];
+    let env = with_default_env(&defaults, process.env().clone().unwrap_or_default());
+    process.set_env(Some(env));
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Removes the env vars of the process of `spec` that `ENV_ALLOW_ANNOTATION` doesn't match,
+// if it's set, or that `ENV_DENY_ANNOTATION` matches.
+fn with_filtered_env(spec: &Spec) -> Spec {
+    let mut spec = spec.clone();
+    let patterns = |annotation: &str| -> Option<Vec<String>> {
+        let patterns = spec.annotations().as_ref()?.get(annotation)?;
+        let patterns = patterns.split(',').map(str::trim);
+        Some(
+            patterns
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect(),
+        )
+    };
+    let allow = patterns(ENV_ALLOW_ANNOTATION);
+    let deny = patterns(ENV_DENY_ANNOTATION).unwrap_or_default();
+    if allow.is_none() && deny.is_empty() {
+        return spec;
+    }
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+
+    let matches = |patterns: &[String], name: &str| patterns.iter().any(|p| glob_match(p, name));
+    let (env, filtered): (Vec<_>, Vec<_>) = process
+        .env()
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .partition(|var| {
+            let name = var.split('=').next().unwrap_or_default();
+            !matches(&deny, name) && allow.as_ref().is_none_or(|allow| matches(allow, name))
+        });
+    if !filtered.is_empty() {
+        // only the names, the values may be secrets
+        let names: Vec<_> = filtered
+            .iter()
+            .map(|var| var.split('=').next().unwrap_or_default())
+            .collect();
+        log::debug!("not passing the env vars {names:?} to the guest");
+    }
+    process.set_env(Some(env));
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Replaces `%id%` in the path of the `COREDUMP_ANNOTATION` with the id of the container,
+// engines replace `%time%` when they write the coredump
+fn with_coredump_id(spec: &Spec, id: &str) -> Spec {
+    let mut spec = spec.clone();
+    let Some(mut annotations) = spec.annotations().clone() else {
+        return spec;
+    };
+    if let Some(path) = annotations.get_mut(COREDUMP_ANNOTATION) {
+        *path = path.replace("%id%", id);
+        spec.set_annotations(Some(annotations));
+    }
+    spec
+}
+
+// Matches `name` against `pattern`, where `*` matches any characters and `?` a single one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let name: Vec<_> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // the position of the last `*` in the pattern, and of the name it matches up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last `*` match one more character
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Returns the `defaults` env vars that are not set in `env`, followed by `env`
+fn with_default_env(defaults: &[String], env: Vec<String>) -> Vec<String> {
+    let name = |var: &String| var.split('=').next().unwrap_or_default().to_string();
+    let names: HashSet<_> = env.iter().map(name).collect();
+    let mut merged: Vec<_> = defaults
+        .iter()
+        .filter(|var| !names.contains(&name(var)))
+        .cloned()
+        .collect();
+    merged.extend(env);
+    merged
+}
+
+// Removes the env vars of the process of `spec` when the guest is denied `Capability::Env`
+fn with_env_capability(spec: &Spec) -> Spec {
+    let mut spec = spec.clone();
+    // validated when the container was created
+    let capabilities = Capabilities::from_spec(&spec).unwrap_or_default();
+    if capabilities.contains(Capability::Env) {
+        return spec;
+    }
+    let Some(mut process) = spec.process().clone() else {
+        return spec;
+    };
+    if process.env().as_ref().is_some_and(|env| !env.is_empty()) {
+        log::debug!(
+            "not passing the env vars to the guest, the env capability is denied by {CAPABILITIES_ANNOTATION}"
+        );
+    }
+    process.set_env(None);
+
+    spec.set_process(Some(process));
+    spec
+}
+
+// Checks that the directories the run config of the wasm layers needs exist in the container
+fn check_run_config_preopens(wasm_layers: &[WasmLayer]) -> Result<()> {
+    let Some(config) = wasm_layers
+        .iter()
+        .find_map(|layer| layer.run_config.as_ref())
+    else {
+        return Ok(());
+    };
+    for dir in &config.preopens {
+        if !Path::new(dir).is_dir() {
+            bail!("the directory {dir:?} required by the run config of the image doesn't exist");
+        }
+    }
+    Ok(())
+}
+
+// Checks that the cwd of the process is a directory the guest can see, which the root directory
+// is preopened for
+fn check_cwd(cwd: &Path) -> Result<()> {
+    if !cwd.is_dir() {
+        bail!("the working directory {cwd:?} of the process isn't a directory in the container");
+    }
+    Ok(())
+}
+
+// Returns the directories bind mounted in the container, and its tmpfs mounts, to preopen for
+// the guest, read-only for the mounts with the `ro` option, in the `readonlyPaths` of the spec,
+// or without `Capability::FsWrite`, except for the ones opted out with `NO_PREOPEN_ANNOTATION`,
+// and the ones in the `maskedPaths` of the spec, which the guest only sees masked through the
+// root. Other mounts can't be preopened, but the guest still sees them through the root
+// directory. So do the mounted files, e.g., `/etc/hosts`.
+fn mount_preopens(spec: &Spec) -> Vec<Preopen> {
+    let opted_out: Vec<&Path> = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(NO_PREOPEN_ANNOTATION))
+        .map(|paths| {
+            paths
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(Path::new)
+                .collect()
+        })
+        .unwrap_or_default();
+    let linux = spec.linux().as_ref();
+    let masked_paths: Vec<&Path> = linux
+        .and_then(|linux| linux.masked_paths().as_ref())
+        .into_iter()
+        .flatten()
+        .map(Path::new)
+        .collect();
+    let readonly_paths: Vec<&Path> = linux
+        .and_then(|linux| linux.readonly_paths().as_ref())
+        .into_iter()
+        .flatten()
+        .map(Path::new)
+        .collect();
+    // validated when the container was created
+    let fs_write = Capabilities::from_spec(spec)
+        .unwrap_or_default()
+        .contains(Capability::FsWrite);
+
+    let mut preopens = vec![];
+    for mount in spec.mounts().iter().flatten() {
+        let destination = mount.destination();
+        if SYSTEM_MOUNTS.iter().any(|dir| destination.starts_with(dir)) {
+            continue;
+        }
+        if opted_out.iter().any(|path| destination.starts_with(path)) {
+            log::debug!("not preopening {destination:?}, opted out with {NO_PREOPEN_ANNOTATION}");
+            continue;
+        }
+        if masked_paths
+            .iter()
+            .any(|path| destination.starts_with(path))
+        {
+            log::debug!("not preopening {destination:?}, it's masked");
+            continue;
+        }
+
+        let options = mount.options().as_deref().unwrap_or_default();
+        let is_bind = mount.typ().as_deref() == Some("bind")
+            || options
+                .iter()
+                .any(|option| option == "bind" || option == "rbind");
+        let typ = mount.typ().as_deref().unwrap_or("unknown");
+        if PSEUDO_FS_TYPES.contains(&typ) {
+            log::debug!("not preopening the {typ} mount at {destination:?}");
+            continue;
+        }
+        if !is_bind && !tmpfs::is_tmpfs(mount) {
+            log::warn!("not preopening the {typ} mount at {destination:?} for the guest");
+            continue;
+        }
+        if !destination.is_absolute() || !destination.is_dir() {
+            log::debug!("not preopening the {typ} mount at {destination:?}, it's not a directory");
+            continue;
+        }
+
+        let read_only = !fs_write
+            || options.iter().any(|option| option == "ro")
+            || readonly_paths
+                .iter()
+                .any(|path| destination.starts_with(path));
+        preopens.push(Preopen {
+            path: destination.clone(),
+            read_only,
+        });
+    }
+    preopens
+}
+
+// Binds the ports the guest listens on, if it's granted `Capability::NetInbound`
+fn bind_listeners(ctx: &WasiContext) -> Result<Arc<Listeners>> {
+    if !ctx.capabilities().contains(Capability::NetInbound) {
+        return Ok(Arc::default());
+    }
+    let ports = listen_ports(ctx.spec, ctx.wasm_layers)?;
+    if ports.is_empty() {
+        return Ok(Arc::default());
+    }
+    log::debug!("binding the ports {ports:?} of the guest");
+    Ok(Arc::new(Listeners::bind(&ports)?))
+}
+
+// Annotates the layer to start in images with several wasm layers, which may be selected by the
+// args of the process, so that engines only have to look at the annotation.
+// If no layer can be selected, the layers are left as they are and the engine reports why.
+fn with_entrypoint_layer(spec: &Spec, wasm_layers: &[WasmLayer]) -> Vec<WasmLayer> {
+    let mut wasm_layers = wasm_layers.to_vec();
+    if wasm_layers.len() < 2 {
+        return wasm_layers;
+    }
+    let arg0 = spec
+        .process()
+        .as_ref()
+        .and_then(|process| process.args().as_ref()?.first());
+    let Ok(entrypoint) = entrypoint_layer(&wasm_layers, arg0.map(String::as_str)) else {
+        return wasm_layers;
+    };
+    for (i, layer) in wasm_layers.iter_mut().enumerate() {
+        let mut annotations = layer.config.annotations().clone().unwrap_or_default();
+        annotations.remove(ENTRYPOINT_ANNOTATION);
+        if i == entrypoint {
+            annotations.insert(ENTRYPOINT_ANNOTATION.to_string(), "true".to_string());
+        }
+        layer.config.set_annotations(Some(annotations));
+    }
+    wasm_layers
+}
+
 fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
     if let Source::Oci(_) = ctx.entrypoint().source {
         bail!("the entry point contains wasm layers")
     };
+    // precompiled files are native code, e.g., ELF files, that only the engine can run
+    if ctx.is_precompiled_file() {
+        bail!("the entry point is a precompiled file")
+    }
 
     let executable = ctx
         .entrypoint()
@@ -143,12 +863,509 @@ fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
 
     // check the shebang and ELF magic number
     // https://en.wikipedia.org/wiki/Executable_and_Linkable_Format#File_header
-    let mut buffer = [0; 4];
-    File::open(executable)?.read_exact(&mut buffer)?;
+    let mut buffer = vec![];
+    File::open(executable)?
+        .take(WASM_SHEBANG.len() as u64)
+        .read_to_end(&mut buffer)?;
 
-    match buffer {
-        [0x7f, 0x45, 0x4c, 0x46] => Ok(()), // ELF magic number
-        [0x23, 0x21, ..] => Ok(()),         // shebang
+    match buffer.as_slice() {
+        [0x7f, 0x45, 0x4c, 0x46, ..] => Ok(()), // ELF magic number
+        // the `#!wasm` indirection files to a wasm module aren't scripts
+        buffer if buffer.starts_with(WASM_SHEBANG) => {
+            bail!("the entry point is a wasm indirection")
+        }
+        [0x23, 0x21, ..] => Ok(()), // shebang
         _ => bail!("not a valid script or elf file"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{ConfigBuilder, Descriptor, Digest, MediaType};
+    use oci_spec::runtime::{
+        LinuxBuilder, Mount, MountBuilder, ProcessBuilder, SpecBuilder, UserBuilder,
+    };
+
+    use super::*;
+    use crate::sandbox::context::RunConfig;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with_args(args: Vec<String>) -> Spec {
+        let process = ProcessBuilder::default()
+            .cwd("/")
+            .args(args)
+            .env(vec!["PATH=/bin".to_string(), "FOO=spec".to_string()])
+            .build()
+            .unwrap();
+        SpecBuilder::default().process(process).build().unwrap()
+    }
+
+    fn layer_with_image_config() -> WasmLayer {
+        let config = ConfigBuilder::default()
+            .entrypoint(vec!["/app.wasm".to_string()])
+            .cmd(vec!["serve".to_string()])
+            .env(vec!["FOO=image".to_string(), "BAR=image".to_string()])
+            .working_dir("/app")
+            .build()
+            .unwrap();
+        WasmLayer {
+            config: Descriptor::new(
+                MediaType::Other("application/wasm".to_string()),
+                0,
+                Digest::try_from(format!("sha256:{:064}", 0)).unwrap(),
+            ),
+            layer: vec![].into(),
+            wasm_config: None,
+            image_config: Some(config),
+            run_config: None,
+            kind: None,
+        }
+    }
+
+    #[test]
+    fn test_check_process_args() -> Result<()> {
+        let mut layer = layer_with_image_config();
+        layer.image_config = None;
+        let args = |spec: &Spec| spec.process().as_ref().unwrap().args().clone();
+
+        // the args of the spec
+        check_process_args(&spec_with_args(vec!["/app.wasm".to_string()]), &[])?;
+
+        // the args of the image config
+        let layers = [layer_with_image_config()];
+        check_process_args(&spec_with_args(vec![]), &layers)?;
+        let spec = with_default_args(
+            &with_image_process(&spec_with_args(vec![]), &layers),
+            &layers,
+        );
+        assert_eq!(
+            args(&spec),
+            Some(vec!["/app.wasm".to_string(), "serve".to_string()])
+        );
+
+        // the `_start` of the single wasm layer
+        let layers = [layer.clone()];
+        check_process_args(&spec_with_args(vec![]), &layers)?;
+        let spec = with_default_args(&spec_with_args(vec![]), &layers);
+        assert_eq!(args(&spec), Some(vec![String::new()]));
+
+        // nothing to start
+        assert!(check_process_args(&spec_with_args(vec![]), &[]).is_err());
+        let err = check_process_args(&spec_with_args(vec![]), &[layer.clone(), layer]).unwrap_err();
+        assert!(err.to_string().contains("2 wasm layers"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_image_process() {
+        let layers = [layer_with_image_config()];
+
+        let spec = with_image_process(&spec_with_args(vec![]), &layers);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(
+            process.args().as_deref(),
+            Some(&["/app.wasm".to_string(), "serve".to_string()][..])
+        );
+        assert_eq!(
+            process.env().as_deref(),
+            Some(
+                &[
+                    "BAR=image".to_string(),
+                    "PATH=/bin".to_string(),
+                    "FOO=spec".to_string()
+                ][..]
+            )
+        );
+        assert_eq!(process.cwd(), Path::new("/app"));
+
+        // the process of the spec wins
+        let args = vec!["/other.wasm".to_string()];
+        let spec = with_image_process(&spec_with_args(args.clone()), &layers);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.args().as_ref(), Some(&args));
+        assert_eq!(process.cwd(), Path::new("/"));
+
+        // without an image config the spec is unchanged
+        let spec = with_image_process(&spec_with_args(vec![]), &[]);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.args().as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn test_with_invoke() -> Result<()> {
+        let spec_with = |args: &[&str], func: Option<&str>| -> Result<Spec> {
+            let func = func.map(|func| (INVOKE_ANNOTATION, func));
+            let mut spec = spec_with_annotations(func.as_slice())?;
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+            spec.set_process(spec_with_args(args).process().clone());
+            Ok(spec)
+        };
+        let args = |spec: Spec| spec.process().as_ref().unwrap().args().clone().unwrap();
+
+        let spec = with_invoke(&spec_with(&["/app.wasm", "--verbose"], Some("handle"))?);
+        assert_eq!(args(spec), ["/app.wasm#handle", "--verbose"]);
+
+        // the module is selected by the wasm layers
+        let spec = with_invoke(&spec_with(&[], Some("init"))?);
+        assert_eq!(args(spec), ["#init"]);
+
+        // the function of the args wins
+        let spec = with_invoke(&spec_with(&["/app.wasm#run"], Some("init"))?);
+        assert_eq!(args(spec), ["/app.wasm#run"]);
+
+        let spec = with_invoke(&spec_with(&["/app.wasm"], None)?);
+        assert_eq!(args(spec), ["/app.wasm"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_coredump_id() -> Result<()> {
+        let spec = spec_with_annotations(&[(COREDUMP_ANNOTATION, "/dumps/core.%id%.%time%.wasm")])?;
+
+        let spec = with_coredump_id(&spec, "my-container");
+        let path = &spec.annotations().as_ref().unwrap()[COREDUMP_ANNOTATION];
+        assert_eq!(path, "/dumps/core.my-container.%time%.wasm");
+
+        let spec = with_coredump_id(&spec_with_args(vec![]), "my-container");
+        assert_eq!(spec.annotations(), spec_with_args(vec![]).annotations());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("RUST_LOG", "RUST_LOG"));
+        assert!(!glob_match("RUST_LOG", "RUST_LOG_STYLE"));
+        assert!(glob_match("APP_*", "APP_"));
+        assert!(glob_match("APP_*", "APP_PORT"));
+        assert!(!glob_match("APP_*", "MY_APP_PORT"));
+        assert!(glob_match("*_SERVICE_*", "KUBERNETES_SERVICE_HOST"));
+        assert!(glob_match("*_PORT", "A_PORT_B_PORT"));
+        assert!(!glob_match("*_PORT", "A_PORT_B"));
+        assert!(glob_match("?OO", "FOO"));
+        assert!(!glob_match("?OO", "OO"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_with_filtered_env() -> Result<()> {
+        let spec_with = |annotations: &[(&str, &str)]| -> Result<Spec> {
+            let mut spec = spec_with_annotations(annotations)?;
+            let mut process = spec_with_args(vec![]).process().clone().unwrap();
+            process.set_env(Some(
+                [
+                    "PATH=/bin",
+                    "APP_PORT=8080",
+                    "APP_TOKEN=secret",
+                    "KUBERNETES_SERVICE_HOST=10.0.0.1",
+                ]
+                .map(String::from)
+                .to_vec(),
+            ));
+            spec.set_process(Some(process));
+            Ok(spec)
+        };
+        let env = |annotations: &[(&str, &str)]| -> Result<Vec<String>> {
+            let spec = with_filtered_env(&spec_with(annotations)?);
+            Ok(spec.process().as_ref().unwrap().env().clone().unwrap())
+        };
+
+        assert_eq!(env(&[])?.len(), 4);
+        assert_eq!(
+            env(&[(ENV_ALLOW_ANNOTATION, "PATH, APP_*")])?,
+            ["PATH=/bin", "APP_PORT=8080", "APP_TOKEN=secret"]
+        );
+        assert_eq!(
+            env(&[(ENV_DENY_ANNOTATION, "KUBERNETES_*")])?,
+            ["PATH=/bin", "APP_PORT=8080", "APP_TOKEN=secret"]
+        );
+        // deny takes precedence over allow
+        assert_eq!(
+            env(&[
+                (ENV_ALLOW_ANNOTATION, "APP_*"),
+                (ENV_DENY_ANNOTATION, "*_TOKEN")
+            ])?,
+            ["APP_PORT=8080"]
+        );
+        // an empty allowlist allows nothing
+        assert!(env(&[(ENV_ALLOW_ANNOTATION, "")])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_process_env() -> Result<()> {
+        let mut spec = spec_with_args(vec![]);
+        let mut process = spec.process().clone().unwrap();
+        process.set_cwd("/app".into());
+        process.set_user(
+            UserBuilder::default()
+                .uid(1000u32)
+                .gid(1000u32)
+                .additional_gids(vec![10, 20])
+                .build()?,
+        );
+        let mut env = process.env().clone().unwrap();
+        env.push("UID=42".to_string());
+        process.set_env(Some(env));
+        spec.set_process(Some(process));
+
+        let spec = with_process_env(&spec);
+        assert_eq!(
+            spec.process().as_ref().unwrap().env().clone().unwrap(),
+            [
+                "PWD=/app",
+                "GID=1000",
+                "GROUPS=10,20",
+                "PATH=/bin",
+                "FOO=spec",
+                "UID=42"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_env_capability() -> Result<()> {
+        let env = |capabilities: Option<&str>| -> Result<Option<Vec<String>>> {
+            let capabilities = capabilities.map(|value| (CAPABILITIES_ANNOTATION, value));
+            let mut spec = spec_with_annotations(capabilities.as_slice())?;
+            spec.set_process(spec_with_args(vec![]).process().clone());
+            let spec = with_env_capability(&spec);
+            Ok(spec.process().as_ref().unwrap().env().clone())
+        };
+
+        let passed = Some(vec!["PATH=/bin".to_string(), "FOO=spec".to_string()]);
+        assert_eq!(env(None)?, passed);
+        assert_eq!(env(Some("fs-write"))?, passed);
+        assert_eq!(env(Some("env"))?, passed);
+        assert_eq!(env(Some("fs-write,-env"))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cwd() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        check_cwd(dir.path())?;
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "")?;
+        assert!(check_cwd(&file).is_err());
+        assert!(check_cwd(&dir.path().join("missing")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_preopens() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| -> Result<PathBuf> {
+            let path = dir.path().join(name);
+            std::fs::create_dir(&path)?;
+            Ok(path)
+        };
+        let mount = |typ: &str, destination: PathBuf, options: &[&str]| -> Result<Mount> {
+            Ok(MountBuilder::default()
+                .typ(typ)
+                .source("/host/path")
+                .destination(destination)
+                .options(options.iter().map(|o| o.to_string()).collect::<Vec<_>>())
+                .build()?)
+        };
+        let data = path("data")?;
+        let config = path("config")?;
+        let secrets = path("secrets")?;
+        let tmp = path("tmp")?;
+        let mqueue = path("mqueue")?;
+        let hosts = dir.path().join("hosts");
+        std::fs::write(&hosts, "127.0.0.1 localhost")?;
+
+        let mounts = vec![
+            mount("proc", "/proc".into(), &[])?,
+            mount("tmpfs", "/dev/shm".into(), &[])?,
+            mount("bind", data.clone(), &["rbind", "rw"])?,
+            mount("none", config.clone(), &["bind", "ro"])?,
+            mount("bind", secrets.clone(), &["rbind", "ro"])?,
+            mount("tmpfs", tmp.clone(), &["size=64m"])?,
+            mount("mqueue", mqueue, &[])?,
+            mount("bind", hosts, &["rbind", "ro"])?,
+        ];
+        let mut annotations = HashMap::from([
+            (
+                NO_PREOPEN_ANNOTATION.to_string(),
+                format!("/var/run, {}", secrets.display()),
+            ),
+            (CAPABILITIES_ANNOTATION.to_string(), "fs-write".to_string()),
+        ]);
+        let spec = SpecBuilder::default()
+            .mounts(mounts.clone())
+            .annotations(annotations.clone())
+            .build()?;
+
+        assert_eq!(
+            mount_preopens(&spec),
+            [
+                Preopen {
+                    path: data.clone(),
+                    read_only: false
+                },
+                Preopen {
+                    path: config.clone(),
+                    read_only: true
+                },
+                Preopen {
+                    path: tmp.clone(),
+                    read_only: false
+                },
+            ]
+        );
+
+        // the masked paths aren't preopened, and the read-only ones are preopened read-only
+        let linux = LinuxBuilder::default()
+            .masked_paths(vec![config.display().to_string()])
+            .readonly_paths(vec![dir.path().display().to_string()])
+            .build()?;
+        let spec = SpecBuilder::default()
+            .mounts(mounts.clone())
+            .annotations(annotations.clone())
+            .linux(linux)
+            .build()?;
+        assert_eq!(
+            mount_preopens(&spec),
+            [
+                Preopen {
+                    path: data.clone(),
+                    read_only: true
+                },
+                Preopen {
+                    path: tmp.clone(),
+                    read_only: true
+                },
+            ]
+        );
+
+        // all the preopens are read-only without the fs-write capability
+        annotations.remove(CAPABILITIES_ANNOTATION);
+        let spec = SpecBuilder::default()
+            .mounts(mounts)
+            .annotations(annotations)
+            .build()?;
+        assert_eq!(
+            mount_preopens(&spec),
+            [
+                Preopen {
+                    path: data,
+                    read_only: true
+                },
+                Preopen {
+                    path: config,
+                    read_only: true
+                },
+                Preopen {
+                    path: tmp,
+                    read_only: true
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_run_config() {
+        let mut layer = layer_with_image_config();
+        layer.run_config = Some(RunConfig {
+            export: Some("serve".to_string()),
+            args: vec!["--port".to_string(), "8080".to_string()],
+            env: vec!["FOO=config".to_string(), "RUST_LOG=info".to_string()],
+            preopens: vec![],
+        });
+        let layers = [layer];
+
+        let spec = with_run_config(&spec_with_args(vec!["/app.wasm".to_string()]), &layers);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(
+            process.args().as_deref(),
+            Some(
+                &[
+                    "/app.wasm#serve".to_string(),
+                    "--port".to_string(),
+                    "8080".to_string()
+                ][..]
+            )
+        );
+        assert_eq!(
+            process.env().as_deref(),
+            Some(
+                &[
+                    "RUST_LOG=info".to_string(),
+                    "PATH=/bin".to_string(),
+                    "FOO=spec".to_string()
+                ][..]
+            )
+        );
+
+        // the args and the exported function of the spec win
+        let args = vec!["/app.wasm#main".to_string(), "--verbose".to_string()];
+        let spec = with_run_config(&spec_with_args(args.clone()), &layers);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.args().as_ref(), Some(&args));
+
+        // the exported function is called without an entrypoint in the spec
+        let spec = with_run_config(&spec_with_args(vec![]), &layers);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.args().as_ref().unwrap()[0], "#serve",);
+
+        // without a run config the spec is unchanged
+        let spec = with_run_config(&spec_with_args(vec![]), &[layer_with_image_config()]);
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.args().as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn test_check_run_config_preopens() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut layer = layer_with_image_config();
+        layer.run_config = Some(RunConfig {
+            preopens: vec![dir.path().display().to_string()],
+            ..Default::default()
+        });
+        assert!(check_run_config_preopens(std::slice::from_ref(&layer)).is_ok());
+
+        let missing = dir.path().join("missing").display().to_string();
+        layer.run_config.as_mut().unwrap().preopens.push(missing);
+        let err = check_run_config_preopens(&[layer]).unwrap_err();
+        assert!(err.to_string().contains("missing"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_entrypoint_layer() {
+        let mut main = layer_with_image_config();
+        main.config.set_annotations(Some(HashMap::from([(
+            "org.opencontainers.image.title".to_string(),
+            "app.wasm".to_string(),
+        )])));
+        let mut lib = layer_with_image_config();
+        lib.config.set_annotations(Some(HashMap::from([(
+            ENTRYPOINT_ANNOTATION.to_string(),
+            "false".to_string(),
+        )])));
+
+        let spec = spec_with_args(vec!["/app.wasm".to_string()]);
+        let layers = with_entrypoint_layer(&spec, &[lib.clone(), main.clone()]);
+        assert!(!layers[0].is_entrypoint());
+        assert!(layers[1].is_entrypoint());
+
+        // layers are left as they are if no entrypoint can be selected
+        let spec = spec_with_args(vec!["/other.wasm".to_string()]);
+        let layers = with_entrypoint_layer(&spec, &[lib.clone(), main.clone()]);
+        assert_eq!(layers[0].config, lib.config);
+        assert_eq!(layers[1].config, main.config);
+    }
+}
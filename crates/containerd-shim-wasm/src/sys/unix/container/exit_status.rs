@@ -0,0 +1,92 @@
+//! Exit status of the init process of wasm instances.
+//!
+//! A recovered instance was started by a previous shim process, so the shim that recovers it
+//! can't reap its init process to get its exit status. Instead, the executor records the exit
+//! code of the guest in a file in the bundle right before exiting, through a descriptor it
+//! inherits from the zygote, and the recovering shim reads it once the process exited.
+//!
+//! Processes that don't get to record it, like the ones killed by a signal or the linux
+//! containers, are reported as killed by a `SIGKILL`.
+
+use std::fs::{File, OpenOptions, read_to_string, remove_file};
+use std::io::{ErrorKind, Result as IoResult, Write as _};
+use std::path::Path;
+
+// The file in the bundle of the instance
+const FILE_NAME: &str = "exit-status";
+
+/// The exit code of the instances whose exit code isn't recorded.
+pub(super) const UNKNOWN_EXIT_CODE: u32 = 137;
+
+/// Zygote side: creates the exit status file of the `bundle`, emptied of the status of a
+/// previous init process, for the executor of the init process.
+pub(super) fn open(bundle: &Path) -> IoResult<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(bundle.join(FILE_NAME))
+}
+
+/// Executor side: records the exit `code` of the guest in the exit status `file`.
+pub(super) fn record(mut file: &File, code: i32) {
+    if let Err(err) = file.write_all(code.to_string().as_bytes()) {
+        log::warn!("failed to record exit code {code}: {err}");
+    }
+}
+
+/// Shim side: returns the exit code recorded in the `bundle`, or [`UNKNOWN_EXIT_CODE`] if the
+/// process didn't record one.
+pub(super) fn read(bundle: &Path) -> u32 {
+    let path = bundle.join(FILE_NAME);
+    match read_to_string(&path) {
+        Ok(status) if status.is_empty() => UNKNOWN_EXIT_CODE,
+        Ok(status) => match status.trim().parse::<i32>() {
+            // like the shell, the exit code is reported modulo 256
+            Ok(code) => code as u32 & 0xff,
+            Err(_) => {
+                log::warn!("invalid exit status {status:?} in {path:?}");
+                UNKNOWN_EXIT_CODE
+            }
+        },
+        Err(err) => {
+            log::warn!("failed to read the exit status in {path:?}: {err}");
+            UNKNOWN_EXIT_CODE
+        }
+    }
+}
+
+/// Shim side: removes the exit status file of the `bundle`.
+pub(super) fn remove(bundle: &Path) {
+    match remove_file(bundle.join(FILE_NAME)) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => log::warn!("failed to remove the exit status file: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorded_exit_code_is_read() -> anyhow::Result<()> {
+        let bundle = tempfile::tempdir()?;
+
+        let file = open(bundle.path())?;
+        assert_eq!(read(bundle.path()), UNKNOWN_EXIT_CODE);
+
+        record(&file, 42);
+        assert_eq!(read(bundle.path()), 42);
+
+        // a restarted init process doesn't report the status of the previous one
+        drop(open(bundle.path())?);
+        assert_eq!(read(bundle.path()), UNKNOWN_EXIT_CODE);
+
+        remove(bundle.path());
+        assert_eq!(read(bundle.path()), UNKNOWN_EXIT_CODE);
+        remove(bundle.path());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+//! OCI lifecycle hooks of the containers, e.g., the ones of service meshes and chained CNI
+//! plugins setting up the network of a pod.
+//!
+//! The shim runs the hooks of the spec itself, at the points of the lifecycle of the instance
+//! they're for, and takes them out of the `config.json` of the bundle so that youki doesn't run
+//! them again:
+//! * `prestart`, `createRuntime` and `createContainer` once the container is created.
+//! * `startContainer` before its init process is started, and `poststart` once it is.
+//! * `poststop` once the container is deleted.
+//!
+//! They all run on the host, with the state of the container as JSON on their stdin, and are
+//! killed once they run for longer than their `timeout`. A failing hook fails the creation, or
+//! the start, of the instance, except for the `poststop` ones, whose failures are only logged.
+//! The hooks of a recovered instance are lost with the shim that created it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::{Hook, Hooks as SpecHooks, Spec};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+
+/// The points of the lifecycle of an instance its hooks run at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Phase {
+    /// The `prestart`, `createRuntime` and `createContainer` hooks.
+    Create,
+    StartContainer,
+    Poststart,
+    Poststop,
+}
+
+impl Phase {
+    fn hooks(self, hooks: &SpecHooks) -> Vec<&Hook> {
+        let phase_hooks = match self {
+            Self::Create => vec![
+                hooks.prestart(),
+                hooks.create_runtime(),
+                hooks.create_container(),
+            ],
+            Self::StartContainer => vec![hooks.start_container()],
+            Self::Poststart => vec![hooks.poststart()],
+            Self::Poststop => vec![hooks.poststop()],
+        };
+        phase_hooks.into_iter().flatten().flatten().collect()
+    }
+
+    // The status of the container in the state the hooks get
+    fn status(self) -> &'static str {
+        match self {
+            Self::Create | Self::StartContainer => "created",
+            Self::Poststart => "running",
+            Self::Poststop => "stopped",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Create => "create",
+            Self::StartContainer => "startContainer",
+            Self::Poststart => "poststart",
+            Self::Poststop => "poststop",
+        })
+    }
+}
+
+// The state of the container, as the runtime spec defines it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct State<'a> {
+    oci_version: &'a str,
+    id: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+    bundle: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<&'a HashMap<String, String>>,
+}
+
+/// The hooks of an instance, with what they're told about its container.
+#[derive(Default)]
+pub(super) struct Hooks {
+    id: String,
+    bundle: PathBuf,
+    oci_version: String,
+    annotations: Option<HashMap<String, String>>,
+    hooks: SpecHooks,
+}
+
+impl Hooks {
+    /// Shim side: takes the hooks out of the `spec` of the container `id`, and out of the
+    /// `config.json` of its `bundle`.
+    pub(super) fn take(id: &str, bundle: &Path, spec: &mut Spec) -> Result<Self, SandboxError> {
+        let Some(hooks) = spec.hooks().clone() else {
+            return Ok(Self::default());
+        };
+        let phases = [
+            Phase::Create,
+            Phase::StartContainer,
+            Phase::Poststart,
+            Phase::Poststop,
+        ];
+        for hook in phases.into_iter().flat_map(|phase| phase.hooks(&hooks)) {
+            if let Some(timeout) = hook.timeout().filter(|timeout| *timeout <= 0) {
+                return Err(SandboxError::InvalidArgument(format!(
+                    "invalid timeout {timeout} of hook {:?} of instance {id}",
+                    hook.path()
+                )));
+            }
+        }
+
+        spec.set_hooks(None);
+        spec.save(bundle.join("config.json"))?;
+        Ok(Self {
+            id: id.to_string(),
+            bundle: bundle.to_path_buf(),
+            oci_version: spec.version().clone(),
+            annotations: spec.annotations().clone(),
+            hooks,
+        })
+    }
+
+    /// Shim side: runs the hooks of `phase` one after the other, telling them that the init
+    /// process of the container is `pid`, and fails with the first one that fails.
+    pub(super) async fn run(&self, phase: Phase, pid: Option<i32>) -> Result<()> {
+        let hooks = phase.hooks(&self.hooks);
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        let state = serde_json::to_vec(&State {
+            oci_version: &self.oci_version,
+            id: &self.id,
+            status: phase.status(),
+            pid,
+            bundle: &self.bundle,
+            annotations: self.annotations.as_ref(),
+        })?;
+
+        for hook in hooks {
+            let path = hook.path();
+            log::info!("running the {phase} hook {path:?} of instance {}", self.id);
+            run_hook(hook, &state).await.with_context(|| {
+                format!("the {phase} hook {path:?} of instance {} failed", self.id)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+async fn run_hook(hook: &Hook, state: &[u8]) -> Result<()> {
+    let mut command = Command::new(hook.path());
+    if let Some((arg0, args)) = hook.args().as_deref().unwrap_or_default().split_first() {
+        command.arg0(arg0).args(args);
+    }
+    let env = hook.env().iter().flatten();
+    command
+        .env_clear()
+        .envs(env.filter_map(|var| var.split_once('=')))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = command.spawn().context("failed to run it")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the state fits in the pipe, and hooks that don't read it may have exited already
+        let _ = stdin.write_all(state).await;
+    }
+    let output = child.wait_with_output();
+    let output = match hook.timeout() {
+        Some(timeout) => {
+            let timeout = Duration::from_secs(timeout.unsigned_abs());
+            tokio::time::timeout(timeout, output)
+                .await
+                .map_err(|_| anyhow!("timed out after {timeout:?}"))??
+        }
+        None => output.await?,
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{}: {}", output.status, stderr.trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{HookBuilder, HooksBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn shell_hook(script: &str, timeout: Option<i64>) -> Result<Hook> {
+        let mut hook = HookBuilder::default()
+            .path("/bin/sh")
+            .args(["sh", "-c", script].map(String::from).to_vec());
+        if let Some(timeout) = timeout {
+            hook = hook.timeout(timeout);
+        }
+        Ok(hook.build()?)
+    }
+
+    fn take(bundle: &Path, hooks: SpecHooks) -> Result<Hooks> {
+        let mut spec = SpecBuilder::default().hooks(hooks).build()?;
+        Ok(Hooks::take("test", bundle, &mut spec)?)
+    }
+
+    #[tokio::test]
+    async fn test_run_hooks() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        let out = bundle.path().join("state.json");
+        let write_state = shell_hook(&format!("cat > {out:?}"), None)?;
+        let hooks = HooksBuilder::default()
+            .create_runtime(vec![write_state.clone()])
+            .poststart(vec![write_state])
+            .build()?;
+        let hooks = take(bundle.path(), hooks)?;
+
+        // youki doesn't get them
+        let spec = Spec::load(bundle.path().join("config.json"))?;
+        assert!(spec.hooks().is_none());
+
+        hooks.run(Phase::Create, Some(42)).await?;
+        let state: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+        assert_eq!(state["id"], "test");
+        assert_eq!(state["status"], "created");
+        assert_eq!(state["pid"], 42);
+        assert_eq!(state["bundle"], bundle.path().to_str().unwrap());
+
+        hooks.run(Phase::Poststart, Some(42)).await?;
+        let state: serde_json::Value = serde_json::from_slice(&std::fs::read(&out)?)?;
+        assert_eq!(state["status"], "running");
+
+        // there are no hooks for the other phases
+        hooks.run(Phase::Poststop, None).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failing_hooks() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        let hooks = HooksBuilder::default()
+            .start_container(vec![shell_hook("echo broken >&2; exit 3", None)?])
+            .poststop(vec![shell_hook("sleep 10", Some(1))?])
+            .build()?;
+        let hooks = take(bundle.path(), hooks)?;
+
+        let err = hooks.run(Phase::StartContainer, Some(1)).await.unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("startContainer hook"), "{err}");
+        assert!(err.contains("broken"), "{err}");
+
+        let err = hooks.run(Phase::Poststop, None).await.unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"), "{err:#}");
+
+        let hooks = HooksBuilder::default()
+            .poststop(vec![shell_hook("true", Some(0))?])
+            .build()?;
+        let err = take(bundle.path(), hooks).err().unwrap();
+        assert!(err.to_string().contains("invalid timeout"), "{err}");
+
+        Ok(())
+    }
+}
@@ -1,35 +1,391 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
+use anyhow::Context as _;
 use chrono::{DateTime, Utc};
 use containerd_client::tonic::async_trait;
+use containerd_shimkit::sandbox::cli::EngineVersion;
 use containerd_shimkit::sandbox::sync::WaitableCell;
 use containerd_shimkit::sandbox::{
-    Error as SandboxError, Instance as SandboxInstance, InstanceConfig,
+    DropPolicy, Error as SandboxError, FifoBuffer, Instance as SandboxInstance, InstanceConfig,
+    LogRotation, OutputOptions, ProcessInfo, Stats, is_sandbox_container, sandbox_id,
 };
-use containerd_shimkit::set_logger_kv;
+use containerd_shimkit::{set_instance_log_level, set_logger_kv};
+use futures::FutureExt as _;
+use libcontainer::container::Container as YoukiContainer;
 use libcontainer::container::builder::ContainerBuilder;
 use libcontainer::syscall::syscall::SyscallType;
+use log::LevelFilter;
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, kill};
 use nix::sys::wait::WaitStatus;
-use oci_spec::runtime::Spec;
-use tokio::sync::OnceCell;
+use nix::unistd::Pid;
+use oci_spec::runtime::{LinuxResources, Process, Spec};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::{Mutex, OnceCell, RwLock};
 
-use super::container::Container;
-use crate::containerd;
-use crate::sandbox::context::WasmLayer;
-use crate::shim::{Compiler, Shim};
-use crate::sys::container::executor::Executor;
-use crate::sys::pid_fd::PidFd;
+use super::container::{Container, Tenant};
+use super::hooks::{Hooks, Phase};
+use super::log_format::LogFormat;
+use super::output::{self, Output, OutputConfig, Pipes};
+use super::pty::{self, Pty, Terminal};
+use super::restart::{RESTART_ANNOTATION, RestartPolicy, Restarts};
+use super::scheduling::Scheduling;
+use super::start_deadline::{self, StartWatch};
+use super::startup::{Startup, Timings};
+use super::tmpfs::TmpfsMounts;
+use super::{
+    checkpoint, cleanup, cpu_time, devices, exit_status, legacy_cgroup, lsm, privileges, rlimits,
+    rootless, terminate, user_namespace,
+};
+use crate::containerd::{self, LayerPolicy};
+use crate::sandbox::context::{
+    CAPABILITIES_ANNOTATION, Capabilities, Capability, DETERMINISTIC_ANNOTATION, Deterministic,
+    LISTEN_ANNOTATION, NET_ALLOW_ANNOTATION, NetAllowlist, StackLimits, VALIDATE_ONLY_ANNOTATION,
+    WasmLayer, listen_ports, validate_only,
+};
+use crate::sandbox::error::Error;
+use crate::sandbox::wasi_config::WasiConfig;
+use crate::shim::{Compiler, Shim, ShimConfig};
+use crate::sys::cgroup::Cgroup;
+use crate::sys::container::executor::{Executor, check_process_args};
+use crate::sys::metrics;
+use crate::sys::oom::OomWatcher;
+use crate::sys::pid_fd::{PidFd, is_reaped};
 
 pub struct Instance<S: Shim> {
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
-    container: Container,
+    // shared with the task restarting the init process, if the instance has a restart policy
+    container: Arc<Container>,
     id: String,
+    cfg: InstanceConfig,
+    paused: AtomicBool,
+    cgroup: OnceLock<Cgroup>,
+    oom_kills: OnceLock<Mutex<UnboundedReceiver<()>>>,
+    // the error the instance failed to start with
+    start_error: OnceLock<String>,
+    stop_grace_period: Duration,
+    terminal: Option<Terminal>,
+    output: Output,
+    startup: Startup,
+    // whether the instance is the pause container of a pod
+    pause: bool,
+    // the report of the start of the guest, and the time it's given to start
+    start_deadline: Option<(StartWatch, Duration)>,
+    // the restarts of the init process, with the wasm layers it's rebuilt with
+    restart: Option<(Arc<Restarts>, Vec<WasmLayer>)>,
+    // the OCI hooks of the spec, which the shim runs instead of youki
+    hooks: Hooks,
+    tmpfs_mounts: TmpfsMounts,
+    execs: RwLock<HashMap<String, ExecProcess>>,
     _phantom: PhantomData<S>,
 }
 
+struct ExecProcess {
+    pid: i32,
+    exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    terminal: Option<Arc<Terminal>>,
+    // keep the zygote that owns the exec'd process alive until it is deleted
+    _tenant: Arc<Tenant>,
+}
+
+/// The exit code of an instance that failed to start, unlike the 137 of a killed instance.
+const START_FAILED_EXIT_CODE: u32 = 128;
+
+/// Annotation to control the precompilation of the layers of a container:
+/// * `false` runs the original layers, which is faster for short-lived jobs.
+/// * `force` recompiles the layers even if they were already precompiled,
+///   which helps when debugging a corrupted cache.
+const PRECOMPILE_ANNOTATION: &str = "io.runwasi.precompile";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Precompile {
+    Enabled,
+    Disabled,
+    Forced,
+}
+
+impl Precompile {
+    fn from_spec(spec: &Spec) -> Result<Self, SandboxError> {
+        let value = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(PRECOMPILE_ANNOTATION));
+        match value.map(String::as_str) {
+            None | Some("true") => Ok(Self::Enabled),
+            Some("false") => Ok(Self::Disabled),
+            Some("force") => Ok(Self::Forced),
+            Some(value) => Err(SandboxError::InvalidArgument(format!(
+                "invalid {PRECOMPILE_ANNOTATION} annotation: {value:?}"
+            ))),
+        }
+    }
+
+    /// Returns the precompilation of the layers of the container of `spec` by `S`, which is
+    /// disabled when the compiler of `S` doesn't compile for the container.
+    fn for_shim<S: Shim>(spec: &Spec) -> Result<Self, SandboxError> {
+        let precompile = Self::from_spec(spec)?;
+        let annotations = spec.annotations().clone().unwrap_or_default();
+        if precompile != Self::Disabled && !S::precompiles(&annotations) {
+            log::info!(
+                "running the original layers, which {} doesn't precompile for this container",
+                S::name()
+            );
+            return Ok(Self::Disabled);
+        }
+        Ok(precompile)
+    }
+}
+
+/// Annotations to rotate the stdout and stderr of the processes of a container when containerd
+/// writes them to files rather than named pipes: the maximum size of a file, in bytes or with a
+/// `k`, `m` or `g` suffix, e.g., `10m`, and the number of rotated files to keep, 1 by default.
+const LOG_MAX_SIZE_ANNOTATION: &str = "io.runwasi.log-max-size";
+const LOG_MAX_FILES_ANNOTATION: &str = "io.runwasi.log-max-files";
+
+fn log_rotation(spec: &Spec) -> Result<Option<LogRotation>, SandboxError> {
+    let annotations = spec.annotations().as_ref();
+    let annotation = |key: &str| annotations.and_then(|a| a.get(key));
+    let invalid = |key: &str, value: &str| {
+        SandboxError::InvalidArgument(format!("invalid {key} annotation: {value:?}"))
+    };
+    let max_files = match annotation(LOG_MAX_FILES_ANNOTATION) {
+        None => None,
+        Some(value) => match value.parse() {
+            Ok(max_files) if max_files > 0 => Some(max_files),
+            _ => return Err(invalid(LOG_MAX_FILES_ANNOTATION, value)),
+        },
+    };
+    let Some(value) = annotation(LOG_MAX_SIZE_ANNOTATION) else {
+        if max_files.is_some() {
+            return Err(SandboxError::InvalidArgument(format!(
+                "the {LOG_MAX_FILES_ANNOTATION} annotation requires the {LOG_MAX_SIZE_ANNOTATION} annotation"
+            )));
+        }
+        return Ok(None);
+    };
+    let max_size = parse_size(value).ok_or_else(|| invalid(LOG_MAX_SIZE_ANNOTATION, value))?;
+    Ok(Some(LogRotation {
+        max_size,
+        max_files: max_files.unwrap_or(1),
+    }))
+}
+
+/// Annotations to buffer the output of the processes of a container while its named pipes have
+/// no reader, e.g., while the log collector of containerd restarts: the maximum size of the
+/// buffer, with the syntax of [`LOG_MAX_SIZE_ANNOTATION`], 1m by default, and what to drop once
+/// it's full, `oldest` by default or `newest`.
+const FIFO_BUFFER_SIZE_ANNOTATION: &str = "io.runwasi.fifo-buffer-size";
+const FIFO_BUFFER_DROP_ANNOTATION: &str = "io.runwasi.fifo-buffer-drop";
+
+fn fifo_buffer(spec: &Spec) -> Result<FifoBuffer, SandboxError> {
+    let annotations = spec.annotations().as_ref();
+    let annotation = |key: &str| annotations.and_then(|a| a.get(key));
+    let invalid = |key: &str, value: &str| {
+        SandboxError::InvalidArgument(format!("invalid {key} annotation: {value:?}"))
+    };
+    let mut buffer = FifoBuffer::default();
+    if let Some(value) = annotation(FIFO_BUFFER_SIZE_ANNOTATION) {
+        buffer.max_size = parse_size(value)
+            .and_then(|size| usize::try_from(size).ok())
+            .ok_or_else(|| invalid(FIFO_BUFFER_SIZE_ANNOTATION, value))?;
+    }
+    if let Some(value) = annotation(FIFO_BUFFER_DROP_ANNOTATION) {
+        buffer.drop = match value.as_str() {
+            "oldest" => DropPolicy::Oldest,
+            "newest" => DropPolicy::Newest,
+            _ => return Err(invalid(FIFO_BUFFER_DROP_ANNOTATION, value)),
+        };
+    }
+    Ok(buffer)
+}
+
+/// Annotation with the format of the stdout and stderr files of the processes of a container:
+/// * `raw`, the default, writes the output as the processes write it.
+/// * `cri` writes it in the CRI log format, with the timestamp and stream of every line.
+///
+/// Named pipes always get the raw output, as their reader is the one to format it.
+const LOG_FORMAT_ANNOTATION: &str = "io.runwasi.log-format";
+
+fn log_format(spec: &Spec) -> Result<LogFormat, SandboxError> {
+    let value = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(LOG_FORMAT_ANNOTATION));
+    match value.map(String::as_str) {
+        None | Some("raw") => Ok(LogFormat::Raw),
+        Some("cri") => Ok(LogFormat::Cri),
+        Some(value) => Err(SandboxError::InvalidArgument(format!(
+            "invalid {LOG_FORMAT_ANNOTATION} annotation: {value:?}"
+        ))),
+    }
+}
+
+/// Annotation with the level of the logs of the instance, e.g., `debug`, to debug a single
+/// instance without raising the level of the whole shim, which the logs of the other
+/// instances keep.
+const LOG_LEVEL_ANNOTATION: &str = "io.runwasi.log-level";
+
+fn log_level(spec: &Spec) -> Result<Option<LevelFilter>, SandboxError> {
+    let value = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(LOG_LEVEL_ANNOTATION));
+    value
+        .map(|value| {
+            value.parse().map_err(|_| {
+                SandboxError::InvalidArgument(format!(
+                    "invalid {LOG_LEVEL_ANNOTATION} annotation: {value:?}"
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn output_config(spec: &Spec) -> Result<OutputConfig, SandboxError> {
+    Ok(OutputConfig {
+        options: OutputOptions {
+            rotation: log_rotation(spec)?,
+            fifo_buffer: fifo_buffer(spec)?,
+        },
+        format: log_format(spec)?,
+    })
+}
+
+// Parses a positive size, in bytes or with a `k`, `m` or `g` suffix
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return None,
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .filter(|size| *size > 0)
+}
+
+/// Annotation to control the layers with a media type the engine doesn't support:
+/// * `true` fails the creation of the container, listing all their media types.
+/// * `false` skips them with a warning.
+///
+/// Without the annotation, the layers are handled per the `layers.strict` setting of the shim.
+const STRICT_LAYERS_ANNOTATION: &str = "io.runwasi.strict-layers";
+
+fn layer_policy(spec: &Spec) -> Result<LayerPolicy, SandboxError> {
+    let value = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(STRICT_LAYERS_ANNOTATION));
+    match value.map(String::as_str) {
+        None => Ok(LayerPolicy::from_config()),
+        Some("true") => Ok(LayerPolicy::Strict),
+        Some("false") => Ok(LayerPolicy::Lenient),
+        Some(value) => Err(SandboxError::InvalidArgument(format!(
+            "invalid {STRICT_LAYERS_ANNOTATION} annotation: {value:?}"
+        ))),
+    }
+}
+
+// Checks the stack limits of the annotations of `spec` against the ones the engine supports
+fn check_stack_limits<S: Shim>(spec: &Spec) -> Result<(), SandboxError> {
+    let limits = StackLimits::from_spec(spec)
+        .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    S::supported_stack_limits()
+        .check(S::name(), &limits)
+        .map_err(SandboxError::InvalidArgument)
+}
+
+fn check_deterministic<S: Shim>(spec: &Spec) -> Result<(), SandboxError> {
+    let deterministic = Deterministic::from_spec(spec)
+        .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    if deterministic.is_some() && !S::supports_deterministic() {
+        return Err(SandboxError::InvalidArgument(format!(
+            "{} doesn't support the {DETERMINISTIC_ANNOTATION} annotation",
+            S::name()
+        )));
+    }
+    Ok(())
+}
+
+fn check_validate_only<S: Shim>(spec: &Spec) -> Result<(), SandboxError> {
+    let validate_only =
+        validate_only(spec).map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    if validate_only && !S::supports_validation() {
+        return Err(SandboxError::InvalidArgument(format!(
+            "{} doesn't support the {VALIDATE_ONLY_ANNOTATION} annotation",
+            S::name()
+        )));
+    }
+    Ok(())
+}
+
+fn check_capabilities<S: Shim>(spec: &Spec) -> Result<(), SandboxError> {
+    let capabilities = Capabilities::from_spec(spec)
+        .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    let supported = S::supported_capabilities();
+    if let Some(capability) = capabilities.iter().find(|c| !supported.contains(*c)) {
+        return Err(SandboxError::InvalidArgument(format!(
+            "{} doesn't support the {capability} capability of the {CAPABILITIES_ANNOTATION} annotation",
+            S::name()
+        )));
+    }
+
+    let allowlist = NetAllowlist::from_spec(spec)
+        .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    if allowlist.is_some() && !capabilities.contains(Capability::NetOutbound) {
+        return Err(SandboxError::InvalidArgument(format!(
+            "the {NET_ALLOW_ANNOTATION} annotation needs the {} capability of the {CAPABILITIES_ANNOTATION} annotation",
+            Capability::NetOutbound
+        )));
+    }
+
+    // without the annotation, the ports exposed by the image are only bound for the guests
+    // granted net-inbound
+    let ports =
+        listen_ports(spec, &[]).map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+    if !ports.is_empty() && !capabilities.contains(Capability::NetInbound) {
+        return Err(SandboxError::InvalidArgument(format!(
+            "the {LISTEN_ANNOTATION} annotation needs the {} capability of the {CAPABILITIES_ANNOTATION} annotation",
+            Capability::NetInbound
+        )));
+    }
+    Ok(())
+}
+
+// The OCI spec requires the cwd of the process to be an absolute path
+fn check_cwd(process: &Process) -> Result<(), SandboxError> {
+    let cwd = process.cwd();
+    if !cwd.is_absolute() {
+        return Err(SandboxError::InvalidArgument(format!(
+            "the working directory of the process must be an absolute path: {cwd:?}"
+        )));
+    }
+    Ok(())
+}
+
 #[async_trait]
 trait OciClient {
-    async fn load_modules(&self, id: &str) -> Result<Vec<WasmLayer>, SandboxError>;
+    async fn load_modules(
+        &self,
+        id: &str,
+        precompile: Precompile,
+        layer_policy: LayerPolicy,
+    ) -> Result<(Vec<WasmLayer>, Option<Duration>), Error>;
+
+    async fn image(&self, id: &str) -> Result<Option<(String, String)>, SandboxError>;
 }
 
 struct EngineOciClient<P: Compiler> {
@@ -41,92 +397,666 @@ struct EngineOciClient<P: Compiler> {
 
 #[async_trait]
 impl<P: Compiler> OciClient for EngineOciClient<P> {
-    async fn load_modules(&self, id: &str) -> Result<Vec<WasmLayer>, SandboxError> {
+    async fn load_modules(
+        &self,
+        id: &str,
+        precompile: Precompile,
+        layer_policy: LayerPolicy,
+    ) -> Result<(Vec<WasmLayer>, Option<Duration>), Error> {
+        let precompiler = match precompile {
+            Precompile::Disabled => None,
+            _ => self.precompiler.as_ref(),
+        };
         self.client
             .load_modules(
                 id,
                 self.name,
                 self.supported_layer_types,
-                self.precompiler.as_ref(),
+                precompiler,
+                precompile == Precompile::Forced,
+                layer_policy,
             )
             .await
     }
+
+    async fn image(&self, id: &str) -> Result<Option<(String, String)>, SandboxError> {
+        self.client.container_image(id).await
+    }
 }
 
-static OCI_CLIENT: OnceCell<Box<dyn OciClient + Send + Sync + 'static>> = OnceCell::const_new();
+/// Clients to containerd, keyed by containerd address and namespace.
+/// A single shim can serve instances from different namespaces, and each of them
+/// must read its image from its own namespace.
+struct OciClients<C: ?Sized> {
+    clients: Mutex<BTreeMap<(String, String), OciClientCell<C>>>,
+}
 
-impl<S: Shim> SandboxInstance for Instance<S> {
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
-    async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
-        let oci_client = OCI_CLIENT
-            .get_or_try_init(|| async {
-                let client =
-                    containerd::Client::connect(&cfg.containerd_address, &cfg.namespace).await?;
-                let precompiler = S::compiler().await;
+type OciClientCell<C> = Arc<OnceCell<Arc<C>>>;
+
+impl<C: ?Sized> OciClients<C> {
+    const fn new() -> Self {
+        Self {
+            clients: Mutex::const_new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the client for the containerd address and namespace in `cfg`,
+    /// creating it with `init` if there is none yet.
+    async fn get_or_try_init<F, Fut>(
+        &self,
+        cfg: &InstanceConfig,
+        init: F,
+    ) -> Result<Arc<C>, SandboxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Arc<C>, SandboxError>>,
+    {
+        let key = (cfg.containerd_address.clone(), cfg.namespace.clone());
+        // don't hold the lock while connecting, so that a slow connection
+        // to one namespace doesn't block the others
+        let cell = self.clients.lock().await.entry(key).or_default().clone();
+        cell.get_or_try_init(init).await.cloned()
+    }
+}
+
+static OCI_CLIENTS: OciClients<dyn OciClient + Send + Sync + 'static> = OciClients::new();
+
+impl<S: Shim> Instance<S> {
+    /// Loads the wasm layers of the container `id`, returning them with the timings of the
+    /// phases of their load, without the build of the container.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(cfg), level = "Info"))]
+    async fn load_modules(
+        id: &str,
+        cfg: &InstanceConfig,
+        precompile: Precompile,
+        layer_policy: LayerPolicy,
+    ) -> Result<(Vec<WasmLayer>, Timings), Error> {
+        let config = ShimConfig::get();
+        let backoff = containerd::Backoff::new(&config.containerd);
+        let connecting = Instant::now();
+        let oci_client = OCI_CLIENTS
+            .get_or_try_init(cfg, || async {
+                // an invalid policy fails all the instances, rather than running them unverified
+                let signature_policy = containerd::SignaturePolicy::from_config()
+                    .map_err(SandboxError::InvalidArgument)?;
+                let client = containerd::Client::connect_with_retry(
+                    &cfg.containerd_address,
+                    &cfg.namespace,
+                    &backoff,
+                )
+                .await?
+                .with_signature_policy(signature_policy);
+                let precompiler = S::compiler(config).await;
                 let supported_layer_types = S::supported_layers_types();
                 let name = S::name();
-                Result::<_, SandboxError>::Ok(Box::new(EngineOciClient {
+                Result::<_, SandboxError>::Ok(Arc::new(EngineOciClient {
                     client,
                     precompiler,
                     supported_layer_types,
                     name,
                 }) as _)
             })
-            .await?;
+            .await
+            .map_err(Error::ImageResolution)?;
+        let connect = connecting.elapsed();
+
+        // the images the policy denies fail the instance before any of their layers is read
+        let policy = containerd::ImagePolicy::from_config();
+        if policy.is_some() || metrics::enabled() {
+            let image = backoff
+                .retry(
+                    "resolve the image",
+                    || oci_client.image(id),
+                    containerd::is_transient,
+                )
+                .await;
+            let image = match (image, policy) {
+                (Ok(image), _) => image,
+                (Err(err), Some(_)) => return Err(Error::ImageResolution(err)),
+                // without a policy, the image is only recorded for the metrics and debug dumps
+                (Err(err), None) => {
+                    log::warn!("failed to resolve the image of instance {id}: {err}");
+                    None
+                }
+            };
+            if let Some(policy) = policy {
+                policy
+                    .check(
+                        image
+                            .as_ref()
+                            .map(|(name, digest)| (name.as_str(), digest.as_str())),
+                    )
+                    .map_err(Error::ImageResolution)?;
+            }
+            if let Some((name, _)) = &image {
+                metrics::image(id, name);
+            }
+        }
 
         // check if container is OCI image with wasm layers and attempt to read the module
-        let modules = oci_client
-            .load_modules(&id)
+        let loading = Instant::now();
+        let load_modules = backoff.retry(
+            "load the wasm layers",
+            || oci_client.load_modules(id, precompile, layer_policy),
+            Error::is_transient,
+        );
+        let timeout = containerd::Timeouts::new(&config.containerd).load_modules;
+        let modules = containerd::with_timeout("load the wasm layers", timeout, load_modules)
             .await
-            .unwrap_or_else(|e| {
-                log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                vec![]
-            });
+            .map_err(Error::ImageResolution)
+            .and_then(|res| res);
+        let (modules, compile) = match modules {
+            Ok(modules) => modules,
+            // the wasm layers are invalid, e.g., their dependencies form a cycle, or the image
+            // isn't signed as the signature policy requires
+            Err(
+                err @ Error::ImageResolution(
+                    SandboxError::InvalidArgument(_) | SandboxError::SignatureVerification { .. },
+                ),
+            ) => {
+                return Err(err);
+            }
+            // the files inside the container image would run without their signatures verified
+            Err(err) if containerd::SignaturePolicy::is_enforced() => return Err(err),
+            Err(e) => {
+                log::warn!(
+                    "Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}"
+                );
+                (vec![], None)
+            }
+        };
+        if let Some(compile) = compile {
+            metrics::compiled(id, compile);
+        }
+
+        let timings = Timings {
+            connect,
+            fetch: loading
+                .elapsed()
+                .saturating_sub(compile.unwrap_or_default()),
+            compile,
+            build: Duration::ZERO,
+        };
+        Ok((modules, timings))
+    }
+
+    /// Creates the instance `id`, failing with the phase of its creation that failed.
+    async fn create(id: String, cfg: &InstanceConfig) -> Result<Self, Error> {
+        if let Some(path) = &cfg.checkpoint {
+            log::info!("restoring instance {id} from checkpoint {path:?}");
+            checkpoint::prepare_restore(path, &cfg.bundle).map_err(Error::ContainerSetup)?;
+        }
+
+        let mut spec =
+            Spec::load(cfg.bundle.join("config.json")).map_err(|err| Error::Spec(err.into()))?;
+        let stop_grace_period = terminate::grace_period(&spec).map_err(Error::Spec)?;
+        let start_timeout = start_deadline::start_timeout(&spec, &ShimConfig::get().instances)
+            .map_err(Error::Spec)?;
+        let restart_policy = RestartPolicy::from_spec(&spec).map_err(Error::Spec)?;
+        // the pty of the process is only connected once, so a restarted process couldn't get it
+        if restart_policy.is_some() && cfg.terminal {
+            return Err(Error::Spec(SandboxError::InvalidArgument(format!(
+                "the {RESTART_ANNOTATION} annotation isn't supported for containers with a terminal"
+            ))));
+        }
+        // checked here so that an invalid limit fails the creation of the container
+        cpu_time::cpu_time_limit(&spec).map_err(Error::Spec)?;
+        check_stack_limits::<S>(&spec).map_err(Error::Spec)?;
+        check_deterministic::<S>(&spec).map_err(Error::Spec)?;
+        check_validate_only::<S>(&spec).map_err(Error::Spec)?;
+        check_capabilities::<S>(&spec).map_err(Error::Spec)?;
+        WasiConfig::check(&spec)
+            .map_err(|err| Error::Spec(SandboxError::InvalidArgument(format!("{err:#}"))))?;
+        if let Some(process) = spec.process() {
+            check_cwd(process).map_err(Error::Spec)?;
+            rlimits::check(&id, process).map_err(Error::Spec)?;
+        }
+        if rootless::is_rootless() {
+            rootless::use_user_namespace(&id, &cfg.bundle, &mut spec)
+                .map_err(Error::ContainerSetup)?;
+        }
+        if cfg.config.systemd_cgroup {
+            legacy_cgroup::use_scope_path(&id, &cfg.bundle, &mut spec)
+                .map_err(Error::ContainerSetup)?;
+        }
+        user_namespace::prepare(&id, &cfg.bundle, &spec).map_err(Error::ContainerSetup)?;
+        devices::add_devices(&id, &cfg.bundle, &mut spec).map_err(Error::ContainerSetup)?;
+        lsm::prepare(&id, &cfg.bundle, &mut spec).map_err(Error::ContainerSetup)?;
+        privileges::prepare(&id, &cfg.bundle, &mut spec).map_err(Error::ContainerSetup)?;
+        let hooks = Hooks::take(&id, &cfg.bundle, &mut spec).map_err(Error::ContainerSetup)?;
+        let scheduling = Scheduling::from_spec(&spec).map_err(Error::Spec)?;
+        let tmpfs_mounts = TmpfsMounts::from_spec(&id, &cfg.bundle, &spec).map_err(Error::Spec)?;
+        let precompile = Precompile::for_shim::<S>(&spec).map_err(Error::Spec)?;
+        let layer_policy = layer_policy(&spec).map_err(Error::Spec)?;
+        let output_config = output_config(&spec).map_err(Error::Spec)?;
+        let level = log_level(&spec).map_err(Error::Spec)?;
+        metrics::engine(S::engine_version, S::version().version);
+        let registration = metrics::register(&id, pod_id(&spec));
 
+        // the pause container of a pod has no wasm layers, it runs the built-in pause instead
+        let pause = is_sandbox_container(&spec);
+        let (modules, mut timings) = if pause {
+            (vec![], Timings::default())
+        } else {
+            Self::load_modules(&id, cfg, precompile, layer_policy).await?
+        };
+        if !pause {
+            check_process_args(&spec, &modules)
+                .map_err(|err| Error::Spec(SandboxError::InvalidArgument(format!("{err:#}"))))
+                .inspect_err(|_| containerd::LAYER_CACHE.release(&id))?;
+        }
+
+        // the pause container has no guest to wait for
+        let start_deadline = match start_timeout {
+            Some(timeout) if !pause => {
+                let watch = StartWatch::create(&cfg.bundle)
+                    .context("failed to create the start deadline FIFO")
+                    .map_err(|err| Error::ContainerSetup(SandboxError::Others(format!("{err:#}"))))
+                    .inspect_err(|_| containerd::LAYER_CACHE.release(&id))?;
+                Some((watch, timeout))
+            }
+            _ => None,
+        };
+        let started = start_deadline
+            .as_ref()
+            .map(|(watch, _)| watch.path().to_path_buf());
+        // the pause container is stopped by killing it, so it's never restarted
+        let restart = restart_policy
+            .filter(|_| !pause)
+            .map(|policy| (Arc::new(Restarts::new(policy)), modules.clone()));
+
+        let stdio = ProcessStdio::open_init(cfg)
+            .inspect_err(|_| containerd::LAYER_CACHE.release(&id))
+            .map_err(|error| Error::Io {
+                context: format!("failed to open the stdio of instance {id}"),
+                error,
+            })?;
+        let (zygote_cfg, tty) = stdio.zygote_config(cfg);
+
+        // a failed build leaves the state of the container behind, unless it was someone else's
+        let rootdir = cfg
+            .determine_rootdir(S::name())
+            .inspect_err(|_| containerd::LAYER_CACHE.release(&id))
+            .map_err(Error::ContainerSetup)?;
+        let existed = rootdir.join(&id).exists();
+        cleanup::sweep_orphans_once(&rootdir);
+
+        let clean_up = || {
+            containerd::LAYER_CACHE.release(&id);
+            if existed {
+                return;
+            }
+            if let Err(err) = cleanup::sweep(&rootdir, &id, cfg.config.systemd_cgroup) {
+                log::warn!("failed to clean up instance {id} after failing to create it: {err:#}");
+            }
+        };
+
+        let building = Instant::now();
         let container = Container::build(
-            |(id, cfg, modules)| {
-                let source_spec_path = cfg.bundle.join("config.json");
-                let spec = Spec::load(source_spec_path)?;
-                let pod_id = pod_id(&spec);
-
-                match pod_id {
-                    Some(pod_id) => set_logger_kv([("instance", id.as_str()), ("pod", pod_id)]),
-                    None => set_logger_kv([("instance", id.as_str())]),
+            build_init::<S>,
+            (id.clone(), zygote_cfg, modules, tty, pause, started),
+        )
+        .inspect_err(|_| clean_up())
+        .map_err(|err| Error::ContainerSetup(err.into()))?;
+        timings.build = building.elapsed();
+        let created = async {
+            let pid = container.pid()?;
+            scheduling.apply(&id, pid)?;
+            hooks.run(Phase::Create, Some(pid)).await
+        };
+        if let Err(err) = created.await {
+            let _ = container.kill(libc::SIGKILL as u32, true);
+            let _ = container.delete();
+            clean_up();
+            return Err(Error::ContainerSetup(SandboxError::Others(format!(
+                "{err:#}"
+            ))));
+        }
+        let (terminal, output) = match stdio.connect(cfg, output_config) {
+            Ok(connected) => connected,
+            Err(error) => {
+                let _ = container.kill(libc::SIGKILL as u32, true);
+                let _ = container.delete();
+                clean_up();
+                return Err(Error::Io {
+                    context: format!("failed to connect the stdio of instance {id}"),
+                    error,
+                });
+            }
+        };
+        registration.keep();
+        // for the records of the shim process tagged with the instance
+        set_instance_log_level(&id, level);
+
+        Ok(Self {
+            pause,
+            start_deadline,
+            restart,
+            hooks,
+            tmpfs_mounts,
+            ..Self::with_container(
+                id,
+                cfg,
+                container,
+                stop_grace_period,
+                terminal,
+                output,
+                Startup::new(timings),
+            )
+        })
+    }
+
+    fn thaw(&self) -> Result<(), SandboxError> {
+        if self.paused.load(Ordering::SeqCst) {
+            self.container.resume()?;
+            self.paused.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn with_container(
+        id: String,
+        cfg: &InstanceConfig,
+        container: Container,
+        stop_grace_period: Duration,
+        terminal: Option<Terminal>,
+        output: Output,
+        startup: Startup,
+    ) -> Self {
+        Self {
+            id,
+            cfg: cfg.clone(),
+            exit_code: WaitableCell::new(),
+            container: Arc::new(container),
+            paused: AtomicBool::new(false),
+            cgroup: OnceLock::new(),
+            oom_kills: OnceLock::new(),
+            start_error: OnceLock::new(),
+            stop_grace_period,
+            terminal,
+            output,
+            startup,
+            pause: false,
+            start_deadline: None,
+            restart: None,
+            hooks: Hooks::default(),
+            tmpfs_mounts: TmpfsMounts::default(),
+            execs: RwLock::default(),
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Resolve the cgroup of the init process `pid` and start watching it for OOM kills.
+    /// This is done while the process is alive, so that stats can tell the cgroup
+    /// has been torn down after the process exits.
+    fn watch_cgroup(&self, pid: i32) -> (Option<OomWatcher>, UnboundedSender<()>) {
+        match Cgroup::for_pid(pid) {
+            Ok(cgroup) => {
+                let _ = self.cgroup.set(cgroup);
+            }
+            Err(err) => log::warn!("failed to resolve cgroup of instance {}: {err}", self.id),
+        }
+
+        let (oom_tx, oom_rx) = unbounded_channel();
+        let _ = self.oom_kills.set(Mutex::new(oom_rx));
+        let oom_watcher = self.cgroup.get().cloned().map(OomWatcher::new).transpose();
+        let oom_watcher = oom_watcher.unwrap_or_else(|err| {
+            log::warn!("OOM kills of instance {} won't be reported: {err}", self.id);
+            None
+        });
+
+        (oom_watcher, oom_tx)
+    }
+
+    /// Record the exit code of the init process once `exit` resolves to it.
+    /// The OOM kills are forwarded before setting the exit code, so that they are
+    /// reported before the exit.
+    fn spawn_exit_task(
+        &self,
+        guard: impl Drop + Send + 'static,
+        (oom_watcher, oom_tx): (Option<OomWatcher>, UnboundedSender<()>),
+        exit: impl Future<Output = u32> + Send + 'static,
+    ) {
+        let exit_code = self.exit_code.clone();
+        let id = self.id.clone();
+        tokio::spawn(async move {
+            // move the exit code guard into this task
+            let _guard = guard;
+
+            let status = forward_oom_kills(oom_watcher, oom_tx, exit).await;
+            metrics::exited(&id, status);
+            let _ = exit_code.set((status, Utc::now()));
+        });
+    }
+
+    fn cancel_restarts(&self) {
+        if let Some((restarts, _)) = &self.restart {
+            restarts.cancel();
+        }
+    }
+
+    // Restarts the init process each time it fails, until `restarts` run out or are cancelled,
+    // and resolves to the status of the exit that isn't restarted, starting with `first`
+    fn supervise(
+        &self,
+        restarts: Arc<Restarts>,
+        modules: Vec<WasmLayer>,
+        first: impl Future<Output = u32> + Send + 'static,
+        oom_tx: UnboundedSender<()>,
+    ) -> impl Future<Output = u32> + Send + 'static {
+        let container = self.container.clone();
+        let id = self.id.clone();
+        let cfg = self.cfg.clone();
+        // the rebuilt process gets a cgroup at the same path
+        let cgroup = self.cgroup.get().cloned();
+        async move {
+            let mut status = first.await;
+            while let Some(restart) = restarts.next(&id, status).await {
+                let exit = match restart_init::<S>(&container, &id, &cfg, modules.clone()) {
+                    Ok(exit) => exit,
+                    Err(err) => {
+                        log::error!("failed to restart instance {id}: {err:#}");
+                        break;
+                    }
                 };
+                log::info!("restarted instance {id}, restart {restart}");
+                let oom_watcher = cgroup.clone().map(OomWatcher::new).transpose();
+                let oom_watcher = oom_watcher.unwrap_or_else(|err| {
+                    log::warn!("OOM kills of restarted instance {id} won't be reported: {err}");
+                    None
+                });
+                status = forward_oom_kills(oom_watcher, oom_tx.clone(), exit).await;
+            }
+            status
+        }
+    }
 
-                let rootdir = cfg.determine_rootdir(S::name())?;
+    /// Record that the instance failed to start with `err`, returning the error of `start`.
+    /// The instance exits with [`START_FAILED_EXIT_CODE`] rather than as if it was killed, and
+    /// can still be deleted, which kills its init process and removes its cgroup.
+    fn start_failed(&self, err: anyhow::Error) -> SandboxError {
+        let err = format!("{err:#}");
+        log::error!("failed to start instance {}: {err}", self.id);
+        let _ = self.start_error.set(err.clone());
+        let _ = self.exit_code.set((START_FAILED_EXIT_CODE, Utc::now()));
+        SandboxError::Others(err)
+    }
 
-                let mut builder = ContainerBuilder::new(id, SyscallType::Linux)
-                    .with_executor(Executor::<S>::new(modules))
-                    .with_root_path(rootdir.clone())?;
+    // Runs the hooks of the start `phase` of the init process `pid`, which is killed if one of
+    // them fails
+    async fn run_start_hooks(&self, phase: Phase, pid: i32) -> Result<(), SandboxError> {
+        let Err(err) = self.hooks.run(phase, Some(pid)).await else {
+            return Ok(());
+        };
+        if let Err(kill_err) = self.container.kill(libc::SIGKILL as u32, true) {
+            log::warn!(
+                "failed to kill instance {} after its {phase} hooks failed: {kill_err:?}",
+                self.id
+            );
+        }
+        Err(self.start_failed(err))
+    }
 
-                if let Ok(f) = cfg.open_stdin() {
-                    builder = builder.with_stdin(f);
+    // Waits for the executor of the init process `pid` to report the start of the guest, and
+    // kills it if that doesn't happen within `timeout`
+    async fn wait_started(
+        &self,
+        watch: &StartWatch,
+        timeout: Duration,
+        pid: i32,
+    ) -> Result<(), SandboxError> {
+        tokio::select! {
+            res = watch.wait() => {
+                if let Err(err) = res {
+                    log::warn!("failed to wait for the start of instance {}: {err}", self.id);
                 }
-                if let Ok(f) = cfg.open_stdout() {
-                    builder = builder.with_stdout(f);
+            }
+            // the exit of a guest that failed before starting is reported as any other
+            _ = self.exit_code.wait() => {}
+            _ = tokio::time::sleep(timeout) => {
+                self.cancel_restarts();
+                let _ = self.start_failed(anyhow::anyhow!(
+                    "the guest didn't start within {timeout:?}"
+                ));
+                if let Err(err) = kill(Pid::from_raw(pid), Signal::SIGKILL) {
+                    log::warn!("failed to kill instance {}: {err}", self.id);
                 }
-                if let Ok(f) = cfg.open_stderr() {
-                    builder = builder.with_stderr(f);
+                return Err(SandboxError::Timeout {
+                    what: format!("start of instance {}", self.id),
+                    timeout,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Signals the init process of the instance, or all of its processes if `all` is set
+    fn signal(&self, signal: u32, all: bool) -> Result<(), SandboxError> {
+        if Signal::try_from(signal as i32).is_err() {
+            return Err(SandboxError::InvalidArgument(format!(
+                "invalid signal number {signal}"
+            )));
+        }
+        // the instance is being stopped, so it must not come back
+        self.cancel_restarts();
+        // Other signals stay pending while frozen and are handled once the instance is resumed,
+        // but SIGKILL should take a paused instance down right away.
+        if signal == libc::SIGKILL as u32 {
+            self.thaw()?;
+        }
+        self.container.kill(signal, all)?;
+        if signal == libc::SIGTERM as u32 {
+            self.kill_after_grace_period()?;
+        }
+        Ok(())
+    }
+
+    /// Send SIGKILL to the instance if it's still running once the grace period elapses
+    fn kill_after_grace_period(&self) -> Result<(), SandboxError> {
+        let pid = Pid::from_raw(self.container.pid()?);
+        let exit_code = self.exit_code.clone();
+        let grace_period = self.stop_grace_period;
+        let id = self.id.clone();
+
+        tokio::spawn(async move {
+            if tokio::time::timeout(grace_period, exit_code.wait())
+                .await
+                .is_err()
+            {
+                log::info!("instance {id} didn't stop within {grace_period:?}, sending SIGKILL");
+                if let Err(err) = kill(pid, Signal::SIGKILL) {
+                    log::warn!("failed to kill instance {id}: {err}");
                 }
+            }
+        });
 
-                let container = builder
-                    .as_init(&cfg.bundle)
-                    .as_sibling(true)
-                    .with_systemd(cfg.config.systemd_cgroup)
-                    .build()?;
+        Ok(())
+    }
+}
 
-                Ok(container)
+impl<S: Shim> SandboxInstance for Instance<S> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
+    async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
+        Ok(Self::create(id, cfg).await?)
+    }
+
+    fn engine_version() -> EngineVersion {
+        S::engine_version()
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
+    async fn recover(id: String, cfg: &InstanceConfig, pid: u32) -> Result<Self, SandboxError> {
+        log::info!("recovering instance {id} with pid {pid}");
+
+        let spec = Spec::load(cfg.bundle.join("config.json"))?;
+        let stop_grace_period = terminate::grace_period(&spec)?;
+        let level = log_level(&spec)?;
+
+        let container = Container::build(
+            |(id, cfg, level)| {
+                set_logger_kv([("instance", id.as_str())]);
+                set_instance_log_level(&id, level.and_then(|level| level.parse().ok()));
+                let rootdir = cfg.determine_rootdir(S::name())?;
+                Ok(YoukiContainer::load(rootdir.join(id))?)
             },
-            (id.clone(), cfg.clone(), modules),
+            (
+                id.clone(),
+                cfg.clone(),
+                level.map(|level| level.to_string()),
+            ),
         )?;
 
-        Ok(Self {
+        let container_pid = container.pid()?;
+        if container_pid != pid as i32 {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "instance {id} has pid {container_pid} instead of {pid}"
+            )));
+        }
+        set_instance_log_level(&id, level);
+
+        // the output of the process goes through the FIFOs the previous shim process created,
+        // unlike the terminal it allocated
+        let output = if cfg.terminal {
+            log::warn!("the terminal of recovered instance {id} can't be reconnected");
+            Output::default()
+        } else {
+            output::reconnect(cfg, &cfg.bundle, output_config(&spec)?)
+        };
+        let instance = Self::with_container(
             id,
-            exit_code: WaitableCell::new(),
+            cfg,
             container,
-            _phantom: Default::default(),
-        })
+            stop_grace_period,
+            None,
+            output,
+            Startup::default(),
+        );
+
+        let guard = instance
+            .exit_code
+            .clone()
+            .set_guard_with(|| (137, Utc::now()));
+        let pidfd = PidFd::new(container_pid)?;
+        let oom_watcher = instance.watch_cgroup(container_pid);
+        metrics::recovered(&instance.id, pod_id(&spec), instance.cgroup.get().cloned());
+
+        let id = instance.id.clone();
+        let bundle = cfg.bundle.clone();
+        let drained = instance.output.drained();
+        let exit = async move {
+            // The process was started by the previous shim process, so it can't be reaped
+            // by this one, its exit code is the one its executor recorded in the bundle.
+            if let Err(err) = pidfd.wait_exited().await {
+                log::error!("failed to wait for recovered instance {id}: {err}");
+            }
+            drained.await;
+            let code = exit_status::read(&bundle);
+            log::info!("recovered instance {id} exited with code {code}");
+            code
+        };
+        instance.spawn_exit_task(guard, oom_watcher, exit);
+
+        Ok(instance)
     }
 
     /// Start the instance
@@ -138,44 +1068,73 @@ impl<S: Shim> SandboxInstance for Instance<S> {
         // make sure we have an exit code by the time we finish (even if there's a panic)
         let guard = self.exit_code.clone().set_guard_with(|| (137, Utc::now()));
 
-        let pid = self.container.pid()?;
+        let pid = self.container.pid().map_err(|err| self.start_failed(err))?;
 
         // Use a pidfd FD so that we can wait for the process to exit asynchronously.
         // This should be created BEFORE calling container.start() to ensure we never
         // miss the SIGCHLD event.
-        let pidfd = PidFd::new(pid)?;
+        let pidfd = match PidFd::new(pid) {
+            Ok(pidfd) => pidfd,
+            // e.g., the executor failed right away, as a runc container failing to start
+            Err(err) if is_reaped(&err) => {
+                let status = self
+                    .container
+                    .status()
+                    .unwrap_or_else(|err| format!("unknown: {err}"));
+                let err = err.context(format!(
+                    "the init process {pid} of the container exited before it was started, with the status {status}"
+                ));
+                return Err(self.start_failed(err));
+            }
+            Err(err) => return Err(self.start_failed(err)),
+        };
 
-        self.container.start()?;
+        // Start watching for OOM kills before the workload runs, so that none are missed
+        let oom_watcher = self.watch_cgroup(pid);
 
-        let exit_code = self.exit_code.clone();
-        tokio::spawn(async move {
-            // move the exit code guard into this task
-            let _guard = guard;
+        self.run_start_hooks(Phase::StartContainer, pid).await?;
+        self.container
+            .start()
+            .with_context(|| format!("failed to start the init process {pid} of the container"))
+            .map_err(|err| self.start_failed(err))?;
+        metrics::started(&self.id, self.cgroup.get().cloned());
+        self.startup.started(&self.id);
+        self.run_start_hooks(Phase::Poststart, pid).await?;
 
-            let status = match pidfd.wait().await {
-                Ok(WaitStatus::Exited(_, status)) => status,
-                Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
-                Ok(res) => {
-                    log::error!("waitpid unexpected result: {res:?}");
-                    137
-                }
-                Err(e) => {
-                    log::error!("waitpid failed: {e}");
-                    137
-                }
-            } as u32;
-            let _ = exit_code.set((status, Utc::now()));
-        });
+        let exit = exit_status(pidfd, self.output.drained(), self.pause);
+        match &self.restart {
+            Some((restarts, modules)) => {
+                let (oom_watcher, oom_tx) = oom_watcher;
+                let first = forward_oom_kills(oom_watcher, oom_tx.clone(), exit);
+                let exit = self.supervise(restarts.clone(), modules.clone(), first, oom_tx.clone());
+                self.spawn_exit_task(guard, (None, oom_tx), exit);
+            }
+            None => self.spawn_exit_task(guard, oom_watcher, exit),
+        }
+
+        if let Some((watch, timeout)) = &self.start_deadline {
+            self.wait_started(watch, *timeout, pid).await?;
+        }
 
         Ok(pid as _)
     }
 
     /// Send a signal to the instance
+    /// SIGTERM is escalated to SIGKILL if the instance doesn't stop within its grace period.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     async fn kill(&self, signal: u32) -> Result<(), SandboxError> {
         log::info!("sending signal {signal} to instance: {}", self.id);
-        self.container.kill(signal)?;
-        Ok(())
+        self.signal(signal, false)
+    }
+
+    /// Send a signal to every process in the cgroup of the instance, including the exec'd ones
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn kill_all(&self, signal: u32) -> Result<(), SandboxError> {
+        log::info!(
+            "sending signal {signal} to all the processes of instance: {}",
+            self.id
+        );
+        self.signal(signal, true)
     }
 
     /// Delete any reference to the instance
@@ -183,7 +1142,53 @@ impl<S: Shim> SandboxInstance for Instance<S> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     async fn delete(&self) -> Result<(), SandboxError> {
         log::info!("deleting instance: {}", self.id);
-        self.container.delete()?;
+        self.cancel_restarts();
+        // Don't leak a frozen cgroup: thaw the instance and make sure it's gone
+        if self.paused.load(Ordering::SeqCst) {
+            self.thaw()?;
+            self.container.kill(libc::SIGKILL as u32, true)?;
+            self.exit_code.wait().await;
+        }
+        // youki leaves the state of the container behind if it fails half-way, and fails to
+        // delete it again, so whatever is left is swept instead
+        if let Err(err) = self.container.delete() {
+            log::debug!(
+                "failed to delete instance {}, sweeping it: {err:#}",
+                self.id
+            );
+        }
+        let rootdir = self.cfg.determine_rootdir(S::name())?;
+        cleanup::sweep(&rootdir, &self.id, self.cfg.config.systemd_cgroup)
+            .map_err(|err| SandboxError::Others(format!("{err:#}")))?;
+        self.tmpfs_mounts.unmount(&self.id);
+        output::remove_fifos(&self.cfg.bundle);
+        exit_status::remove(&self.cfg.bundle);
+        if let Err(err) = self.hooks.run(Phase::Poststop, None).await {
+            log::warn!("{err:#}");
+        }
+        containerd::LAYER_CACHE.release(&self.id);
+        metrics::deleted(&self.id);
+        set_instance_log_level(&self.id, None);
+        Ok(())
+    }
+
+    /// Pause the instance by freezing its cgroup
+    /// The init process can't exit while frozen, so `wait` won't report an exit until it's resumed.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn pause(&self) -> Result<(), SandboxError> {
+        log::info!("pausing instance: {}", self.id);
+        rootless::check_cgroup_writable(&self.id, self.cgroup.get(), "paused")?;
+        self.container.pause()?;
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Resume the instance by thawing its cgroup
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn resume(&self) -> Result<(), SandboxError> {
+        log::info!("resuming instance: {}", self.id);
+        self.container.resume()?;
+        self.paused.store(false, Ordering::SeqCst);
         Ok(())
     }
 
@@ -194,31 +1199,532 @@ impl<S: Shim> SandboxInstance for Instance<S> {
     async fn wait(&self) -> (u32, DateTime<Utc>) {
         *self.exit_code.wait().await
     }
+
+    /// Collect the resource usage of the instance from its cgroup, with its startup timings
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn stats(&self) -> Result<Stats, SandboxError> {
+        let cgroup = self
+            .cgroup
+            .get()
+            .ok_or_else(|| match self.start_error.get() {
+                Some(err) => SandboxError::FailedPrecondition(format!(
+                    "instance {} failed to start: {err}",
+                    self.id
+                )),
+                None => {
+                    SandboxError::FailedPrecondition(format!("instance {} is not running", self.id))
+                }
+            })?;
+
+        let mut stats = cgroup.metrics().map_err(|err| match err.kind() {
+            ErrorKind::NotFound => {
+                SandboxError::NotFound(format!("cgroup of instance {}: {err}", self.id))
+            }
+            _ => err.into(),
+        })?;
+        self.startup.add_to(&mut stats, self.output.first_write());
+        if let Some((restarts, _)) = &self.restart {
+            restarts.add_to(&mut stats);
+        }
+        Ok(stats)
+    }
+
+    /// Wait for the OOM killer to kill a process in the cgroup of the instance
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn wait_oom(&self) -> Result<(), SandboxError> {
+        let oom_kills = self.oom_kills.get().ok_or_else(|| {
+            SandboxError::FailedPrecondition(format!("instance {} is not running", self.id))
+        })?;
+
+        match oom_kills.lock().await.recv().await {
+            Some(()) => Ok(()),
+            None => Err(SandboxError::FailedPrecondition(format!(
+                "instance {} has exited",
+                self.id
+            ))),
+        }
+    }
+
+    /// List the processes in the cgroup of the instance
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn pids(&self) -> Result<Vec<ProcessInfo>, SandboxError> {
+        let cgroup = self.cgroup.get().ok_or_else(|| {
+            SandboxError::FailedPrecondition(format!("instance {} is not running", self.id))
+        })?;
+
+        let pids = cgroup.procs().map_err(|err| match err.kind() {
+            ErrorKind::NotFound => {
+                SandboxError::NotFound(format!("cgroup of instance {}: {err}", self.id))
+            }
+            _ => err.into(),
+        })?;
+
+        let execs = self.execs.read().await;
+        let processes = pids
+            .into_iter()
+            .map(|pid| ProcessInfo {
+                pid,
+                exec_id: execs
+                    .iter()
+                    .find(|(_, exec)| exec.pid as u32 == pid)
+                    .map(|(exec_id, _)| exec_id.clone()),
+            })
+            .collect();
+
+        Ok(processes)
+    }
+
+    /// Update the cgroup limits of the running instance
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, resources), level = "Info")
+    )]
+    async fn update(&self, resources: &LinuxResources) -> Result<(), SandboxError> {
+        log::info!("updating resources of instance: {}", self.id);
+
+        // Refuse to shrink the memory limit below the current usage,
+        // as that would get the workload OOM-killed.
+        let limit = resources.memory().as_ref().and_then(|m| m.limit());
+        if let (Some(limit), Some(cgroup)) = (limit.filter(|l| *l > 0), self.cgroup.get()) {
+            let usage = cgroup.memory_usage()?;
+            if (limit as u64) < usage {
+                return Err(SandboxError::InvalidArgument(format!(
+                    "memory limit of {limit} bytes is below the current usage of {usage} bytes of instance {}",
+                    self.id
+                )));
+            }
+        }
+
+        self.container.update(resources.clone())?;
+
+        S::update_resources(&self.id, resources).await?;
+
+        Ok(())
+    }
+
+    /// Checkpoint the instance into the `path` directory
+    /// The engine serializes its state, and the OCI spec and stdio layout are persisted alongside.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn checkpoint(&self, path: &Path) -> Result<(), SandboxError> {
+        log::info!("checkpointing instance {} into {path:?}", self.id);
+
+        if self.paused.load(Ordering::SeqCst) {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "instance {} is paused",
+                self.id
+            )));
+        }
+
+        let pid = self.container.pid()?;
+        checkpoint::checkpoint(pid, &self.cfg, path).await
+    }
+
+    /// Execute an additional process inside the running instance
+    /// The process runs as a youki tenant, sharing the namespaces and cgroup of the instance.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, process, cfg), level = "Info")
+    )]
+    async fn exec(
+        &self,
+        exec_id: &str,
+        process: &Process,
+        cfg: &InstanceConfig,
+    ) -> Result<u32, SandboxError> {
+        log::info!("executing process {exec_id} in instance: {}", self.id);
+
+        if self.exit_code.wait().now_or_never().is_some() {
+            return Err(SandboxError::FailedPrecondition(format!(
+                "instance {} has already exited",
+                self.id
+            )));
+        }
+        check_cwd(process)?;
+
+        // the layers were already recompiled for the init process if that was forced
+        let spec = Spec::load(self.cfg.bundle.join("config.json"))?;
+        let precompile = match Precompile::for_shim::<S>(&spec)? {
+            Precompile::Forced => Precompile::Enabled,
+            precompile => precompile,
+        };
+        let layer_policy = layer_policy(&spec)?;
+        let output_config = output_config(&spec)?;
+        // the level isn't serializable, so it's sent by name
+        let level = log_level(&spec)?.map(|level| level.to_string());
+        let (modules, _) = Self::load_modules(&self.id, cfg, precompile, layer_policy).await?;
+
+        let stdio = ProcessStdio::open(cfg)?;
+        let (zygote_cfg, tty) = stdio.zygote_config(cfg);
+
+        let (tenant, pid) = Tenant::build(
+            |(id, exec_id, cfg, modules, process, tty, level)| {
+                set_logger_kv([("instance", id.as_str()), ("exec", exec_id.as_str())]);
+                set_instance_log_level(&id, level.and_then(|level| level.parse().ok()));
+
+                let rootdir = cfg.determine_rootdir(S::name())?;
+
+                // the start deadline is only enforced for the init process
+                let executor = Executor::<S>::new(id.clone(), modules, None, None, None);
+                let builder = ContainerBuilder::new(id, SyscallType::Linux)
+                    .with_executor(executor)
+                    .with_root_path(rootdir)?;
+                let builder = with_stdio(builder, &cfg, tty.as_deref())?;
+
+                let env = process
+                    .env()
+                    .iter()
+                    .flatten()
+                    .filter_map(|e| e.split_once('='))
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<_, _>>();
+
+                let pid = builder
+                    .as_tenant()
+                    .with_detach(true)
+                    .with_cwd(Some(process.cwd().clone()))
+                    .with_env(env)
+                    .with_container_args(process.args().clone().unwrap_or_default())
+                    .build()?;
+
+                Ok(pid.as_raw())
+            },
+            (
+                self.id.clone(),
+                exec_id.to_string(),
+                zygote_cfg,
+                modules,
+                process.clone(),
+                tty,
+                level,
+            ),
+        )?;
+
+        let tenant = Arc::new(tenant);
+        let exit_code = WaitableCell::new();
+        let (terminal, output) = stdio.connect(cfg, output_config)?;
+        let terminal = terminal.map(Arc::new);
+
+        // Each exec'd process gets its own exit code, so concurrent execs can't clobber each other.
+        // Waiting blocks the tenant zygote, so do it on a blocking thread.
+        let guard = exit_code.clone().set_guard_with(|| (137, Utc::now()));
+        let waiter = tenant.clone();
+        let exec_exit_code = exit_code.clone();
+        let drained = output.drained();
+        tokio::spawn(async move {
+            // move the exit code guard into this task
+            let _guard = guard;
+
+            let status = tokio::task::spawn_blocking(move || {
+                waiter.wait(pid).unwrap_or_else(|e| {
+                    log::error!("waitpid failed: {e}");
+                    137
+                })
+            })
+            .await
+            .unwrap_or(137);
+            drained.await;
+            let _ = exec_exit_code.set((status, Utc::now()));
+        });
+
+        self.execs.write().await.insert(
+            exec_id.to_string(),
+            ExecProcess {
+                pid,
+                exit_code,
+                terminal,
+                _tenant: tenant,
+            },
+        );
+
+        Ok(pid as _)
+    }
+
+    /// Send a signal to an exec'd process
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn kill_exec(&self, exec_id: &str, signal: u32) -> Result<(), SandboxError> {
+        log::info!(
+            "sending signal {signal} to exec {exec_id} in instance: {}",
+            self.id
+        );
+        let execs = self.execs.read().await;
+        let exec = execs
+            .get(exec_id)
+            .ok_or_else(|| SandboxError::NotFound(exec_id.to_string()))?;
+        let signal = Signal::try_from(signal as i32).map_err(|_| {
+            SandboxError::InvalidArgument(format!("invalid signal number {signal}"))
+        })?;
+        kill(Pid::from_raw(exec.pid), signal).map_err(|errno| match errno {
+            Errno::ESRCH => SandboxError::NotFound("process already finished".to_string()),
+            Errno::EPERM => {
+                SandboxError::PermissionDenied(format!("failed to signal exec {exec_id}: {errno}"))
+            }
+            errno => errno.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Resize the terminal of the init process, or of an exec'd process
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn resize_pty(
+        &self,
+        exec_id: Option<&str>,
+        width: u32,
+        height: u32,
+    ) -> Result<(), SandboxError> {
+        let execs = self.execs.read().await;
+        let terminal = match exec_id {
+            Some(exec_id) => execs
+                .get(exec_id)
+                .ok_or_else(|| SandboxError::NotFound(exec_id.to_string()))?
+                .terminal
+                .as_deref(),
+            None => self.terminal.as_ref(),
+        };
+        let terminal = terminal.ok_or_else(|| {
+            SandboxError::FailedPrecondition("the process doesn't have a terminal".to_string())
+        })?;
+        terminal.resize(width, height)?;
+        Ok(())
+    }
+
+    /// Delete any reference to an exec'd process
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn delete_exec(&self, exec_id: &str) -> Result<(), SandboxError> {
+        log::info!("deleting exec {exec_id} in instance: {}", self.id);
+        self.execs.write().await.remove(exec_id);
+        Ok(())
+    }
+
+    /// Waits for an exec'd process to finish and returns its exit code
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn wait_exec(&self, exec_id: &str) -> Result<(u32, DateTime<Utc>), SandboxError> {
+        let exit_code = self
+            .execs
+            .read()
+            .await
+            .get(exec_id)
+            .map(|exec| exec.exit_code.clone())
+            .ok_or_else(|| SandboxError::NotFound(exec_id.to_string()))?;
+        Ok(*exit_code.wait().await)
+    }
+}
+
+/// The pty or the pipes of the output of a process, allocated before it's started, as the
+/// zygote couldn't send them back.
+enum ProcessStdio {
+    Pty(Pty),
+    Pipes(Pipes),
+}
+
+impl ProcessStdio {
+    /// Opens the stdio of an exec'd process, which isn't recovered after a shim restart.
+    fn open(cfg: &InstanceConfig) -> std::io::Result<Self> {
+        if cfg.terminal {
+            Pty::open().map(Self::Pty)
+        } else {
+            Pipes::open(cfg).map(Self::Pipes)
+        }
+    }
+
+    /// Opens the stdio of the init process, whose pipes a restarted shim reconnects to, see
+    /// [`output::reconnect`].
+    fn open_init(cfg: &InstanceConfig) -> std::io::Result<Self> {
+        if cfg.terminal {
+            Pty::open().map(Self::Pty)
+        } else {
+            Pipes::create_fifos(cfg, &cfg.bundle).map(Self::Pipes)
+        }
+    }
+
+    /// Returns the config and the tty the zygote wires the stdio of the process with, see
+    /// [`with_stdio`].
+    fn zygote_config(&self, cfg: &InstanceConfig) -> (InstanceConfig, Option<PathBuf>) {
+        match self {
+            Self::Pty(pty) => (cfg.clone(), Some(pty.slave_path().to_path_buf())),
+            Self::Pipes(pipes) => (pipes.zygote_config(cfg), None),
+        }
+    }
+
+    /// Connects the pty or the pipes to the stdio of `cfg`, once the process opened them.
+    fn connect(
+        self,
+        cfg: &InstanceConfig,
+        config: OutputConfig,
+    ) -> std::io::Result<(Option<Terminal>, Output)> {
+        match self {
+            Self::Pty(pty) => pty
+                .connect(cfg, config)
+                .map(|(terminal, output)| (Some(terminal), output)),
+            Self::Pipes(pipes) => Ok((None, pipes.connect(cfg, config))),
+        }
+    }
 }
 
+/// Wires the stdio of `cfg` to the process, or the slave side of the pty at `tty` if the process
+/// has a terminal.
+fn with_stdio(
+    mut builder: ContainerBuilder,
+    cfg: &InstanceConfig,
+    tty: Option<&Path>,
+) -> std::io::Result<ContainerBuilder> {
+    if let Some(tty) = tty {
+        return Ok(builder
+            .with_stdin(pty::open_slave(tty)?)
+            .with_stdout(pty::open_slave(tty)?)
+            .with_stderr(pty::open_slave(tty)?));
+    }
+
+    if let Ok(f) = cfg.open_stdin() {
+        builder = builder.with_stdin(f);
+    }
+    if let Ok(f) = cfg.open_stdout() {
+        builder = builder.with_stdout(f);
+    }
+    if let Ok(f) = cfg.open_stderr() {
+        builder = builder.with_stderr(f);
+    }
+    Ok(builder)
+}
+
+// Resolves to the exit status of the init process `pidfd` waits for, once its output is drained
+async fn exit_status(pidfd: PidFd, drained: impl Future<Output = ()>, pause: bool) -> u32 {
+    let status = match pidfd.wait().await {
+        Ok(WaitStatus::Exited(_, status)) => status,
+        // the CRI plugin stops the pods by killing their pause container
+        Ok(WaitStatus::Signaled(_, Signal::SIGKILL, _)) if pause => 0,
+        Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
+        Ok(res) => {
+            log::error!("waitpid unexpected result: {res:?}");
+            137
+        }
+        Err(e) => {
+            log::error!("waitpid failed: {e}");
+            137
+        }
+    };
+    drained.await;
+    status as u32
+}
+
+// Resolves to the output of `exit`, forwarding the OOM kills `oom_watcher` sees to `oom_tx`
+// meanwhile
+async fn forward_oom_kills<T>(
+    oom_watcher: Option<OomWatcher>,
+    oom_tx: UnboundedSender<()>,
+    exit: impl Future<Output = T>,
+) -> T {
+    match oom_watcher {
+        Some(watcher) => watcher.forward_until(oom_tx, exit).await,
+        None => exit.await,
+    }
+}
+
+// Rebuilds the exited init process of the instance `id` in `container` and starts it,
+// returning its exit status
+fn restart_init<S: Shim>(
+    container: &Container,
+    id: &str,
+    cfg: &InstanceConfig,
+    modules: Vec<WasmLayer>,
+) -> anyhow::Result<impl Future<Output = u32> + Send + 'static> {
+    let spec = Spec::load(cfg.bundle.join("config.json"))?;
+    let output_config = output_config(&spec)?;
+    let stdio = ProcessStdio::open_init(cfg)?;
+    let (zygote_cfg, tty) = stdio.zygote_config(cfg);
+    // the start deadline only covers the first start of the instance
+    let args = (id.to_string(), zygote_cfg, modules, tty, false, None);
+    container.rebuild(build_init::<S>, args)?;
+
+    let pid = container.pid()?;
+    Scheduling::from_spec(&spec)?.apply(id, pid)?;
+    let pidfd = PidFd::new(pid)?;
+    let (_, output) = stdio.connect(cfg, output_config)?;
+    container
+        .start()
+        .with_context(|| format!("failed to start the init process {pid} of the container"))?;
+    Ok(exit_status(pidfd, output.drained(), false))
+}
+
+// What the zygote builds the init process of an instance with: its id, the config of the
+// instance, its wasm layers, the tty of the process, whether it's the pause container of a pod,
+// and the FIFO its guest reports its start on
+type InitArgs = (
+    String,
+    InstanceConfig,
+    Vec<WasmLayer>,
+    Option<PathBuf>,
+    bool,
+    Option<PathBuf>,
+);
+
+/// Builds the init process of an instance in its zygote.
+fn build_init<S: Shim>(
+    (id, cfg, modules, tty, pause, started): InitArgs,
+) -> anyhow::Result<YoukiContainer> {
+    let source_spec_path = cfg.bundle.join("config.json");
+    let spec = Spec::load(source_spec_path)?;
+    let pod_id = pod_id(&spec);
+
+    match pod_id {
+        Some(pod_id) => set_logger_kv([("instance", id.as_str()), ("pod", pod_id)]),
+        None => set_logger_kv([("instance", id.as_str())]),
+    };
+    set_instance_log_level(&id, log_level(&spec)?);
+
+    let rootdir = cfg.determine_rootdir(S::name())?;
+
+    let executor = if pause {
+        Executor::<S>::pause(id.clone())
+    } else {
+        let started = started
+            .map(|path| start_deadline::open(&path))
+            .transpose()?;
+        let checkpoint = checkpoint::open(&cfg.bundle, &spec)?;
+        let exit_status = exit_status::open(&cfg.bundle)?;
+        Executor::<S>::new(
+            id.clone(),
+            modules,
+            started,
+            Some(checkpoint),
+            Some(exit_status),
+        )
+    };
+    let builder = ContainerBuilder::new(id, SyscallType::Linux)
+        .with_executor(executor)
+        .with_root_path(rootdir.clone())?;
+    let builder = with_stdio(builder, &cfg, tty.as_deref())?;
+
+    let container = builder
+        .as_init(&cfg.bundle)
+        .as_sibling(true)
+        .with_systemd(cfg.config.systemd_cgroup)
+        .build()?;
+
+    Ok(container)
+}
+
+// The pod of the instance, which the CRI plugin names in its sandbox-id annotation
 fn pod_id(spec: &Spec) -> Option<&str> {
-    spec.annotations()
-        .as_ref()
-        .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
-        .map(|s| s.as_str())
+    sandbox_id(spec)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex as StdMutex;
+
     use anyhow::Result;
+    use containerd_shimkit::sandbox::{CONTAINER_TYPE_ANNOTATION, SANDBOX_ID_ANNOTATION};
     use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
 
     use super::*;
+    use crate::testing::spec_with_annotations;
 
     #[test]
     fn test_get_pod_id() -> Result<()> {
         use std::collections::HashMap;
 
         let mut annotations = HashMap::new();
-        annotations.insert(
-            "io.kubernetes.cri.sandbox-id".to_string(),
-            "test-pod-id".to_string(),
-        );
+        annotations.insert(SANDBOX_ID_ANNOTATION.to_string(), "test-pod-id".to_string());
 
         let spec = SpecBuilder::default()
             .root(RootBuilder::default().path("rootfs").build()?)
@@ -231,6 +1737,17 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_pause_container() -> Result<()> {
+        let spec = |ty: &str| spec_with_annotations(&[(CONTAINER_TYPE_ANNOTATION, ty)]);
+
+        assert!(is_sandbox_container(&spec("sandbox")?));
+        assert!(!is_sandbox_container(&spec("container")?));
+        assert!(!is_sandbox_container(&Spec::default()));
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_pod_id_no_annotation() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -242,4 +1759,229 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_precompile_annotation() -> Result<()> {
+        let spec_with = |value: Option<&str>| {
+            let annotation = value.map(|value| (PRECOMPILE_ANNOTATION, value));
+            spec_with_annotations(annotation.as_slice())
+        };
+
+        assert_eq!(
+            Precompile::from_spec(&spec_with(None)?)?,
+            Precompile::Enabled
+        );
+        assert_eq!(
+            Precompile::from_spec(&spec_with(Some("true"))?)?,
+            Precompile::Enabled
+        );
+        assert_eq!(
+            Precompile::from_spec(&spec_with(Some("false"))?)?,
+            Precompile::Disabled
+        );
+        assert_eq!(
+            Precompile::from_spec(&spec_with(Some("force"))?)?,
+            Precompile::Forced
+        );
+
+        let err = Precompile::from_spec(&spec_with(Some("always"))?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_layers_annotation() -> Result<()> {
+        let spec_with = |value: &str| spec_with_annotations(&[(STRICT_LAYERS_ANNOTATION, value)]);
+
+        assert_eq!(layer_policy(&spec_with("true")?)?, LayerPolicy::Strict);
+        assert_eq!(layer_policy(&spec_with("false")?)?, LayerPolicy::Lenient);
+
+        let err = layer_policy(&spec_with("yes")?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_rotation_annotations() -> Result<()> {
+        assert_eq!(log_rotation(&spec_with_annotations(&[])?)?, None);
+        assert_eq!(
+            log_rotation(&spec_with_annotations(&[(
+                LOG_MAX_SIZE_ANNOTATION,
+                "1024"
+            )])?)?,
+            Some(LogRotation {
+                max_size: 1024,
+                max_files: 1
+            })
+        );
+        assert_eq!(
+            log_rotation(&spec_with_annotations(&[
+                (LOG_MAX_SIZE_ANNOTATION, "10m"),
+                (LOG_MAX_FILES_ANNOTATION, "3")
+            ])?)?,
+            Some(LogRotation {
+                max_size: 10 << 20,
+                max_files: 3
+            })
+        );
+
+        for annotations in [
+            &[(LOG_MAX_SIZE_ANNOTATION, "0")][..],
+            &[(LOG_MAX_SIZE_ANNOTATION, "10mb")],
+            &[(LOG_MAX_SIZE_ANNOTATION, "m")],
+            &[
+                (LOG_MAX_SIZE_ANNOTATION, "10m"),
+                (LOG_MAX_FILES_ANNOTATION, "0"),
+            ],
+            &[(LOG_MAX_FILES_ANNOTATION, "3")],
+        ] {
+            let err = log_rotation(&spec_with_annotations(annotations)?).unwrap_err();
+            assert!(matches!(err, SandboxError::InvalidArgument(_)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_format_annotation() -> Result<()> {
+        let spec_with = |value: &str| spec_with_annotations(&[(LOG_FORMAT_ANNOTATION, value)]);
+
+        assert_eq!(log_format(&spec_with("raw")?)?, LogFormat::Raw);
+        assert_eq!(log_format(&spec_with("cri")?)?, LogFormat::Cri);
+
+        let err = log_format(&spec_with("json")?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_level_annotation() -> Result<()> {
+        let spec_with = |value: Option<&str>| {
+            let annotation = value.map(|value| (LOG_LEVEL_ANNOTATION, value));
+            spec_with_annotations(annotation.as_slice())
+        };
+
+        assert_eq!(log_level(&spec_with(None)?)?, None);
+        assert_eq!(
+            log_level(&spec_with(Some("debug"))?)?,
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            log_level(&spec_with(Some("TRACE"))?)?,
+            Some(LevelFilter::Trace)
+        );
+
+        let err = log_level(&spec_with(Some("verbose"))?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fifo_buffer_annotations() -> Result<()> {
+        assert_eq!(
+            fifo_buffer(&spec_with_annotations(&[])?)?,
+            FifoBuffer::default()
+        );
+        assert_eq!(
+            fifo_buffer(&spec_with_annotations(&[
+                (FIFO_BUFFER_SIZE_ANNOTATION, "64k"),
+                (FIFO_BUFFER_DROP_ANNOTATION, "newest")
+            ])?)?,
+            FifoBuffer {
+                max_size: 64 << 10,
+                drop: DropPolicy::Newest
+            }
+        );
+
+        for annotations in [
+            &[(FIFO_BUFFER_SIZE_ANNOTATION, "0")][..],
+            &[(FIFO_BUFFER_DROP_ANNOTATION, "all")],
+        ] {
+            let err = fifo_buffer(&spec_with_annotations(annotations)?).unwrap_err();
+            assert!(matches!(err, SandboxError::InvalidArgument(_)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cwd() -> Result<()> {
+        check_cwd(&ProcessBuilder::default().cwd("/app").build()?)?;
+
+        let err = check_cwd(&ProcessBuilder::default().cwd("app").build()?).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    struct FakeOciClient {
+        namespace: String,
+        calls: Arc<StdMutex<Vec<(String, String)>>>,
+    }
+
+    #[async_trait]
+    impl OciClient for FakeOciClient {
+        async fn load_modules(
+            &self,
+            id: &str,
+            _precompile: Precompile,
+            _layer_policy: LayerPolicy,
+        ) -> Result<(Vec<WasmLayer>, Option<Duration>), Error> {
+            let call = (self.namespace.clone(), id.to_string());
+            self.calls.lock().unwrap().push(call);
+            Ok((vec![], None))
+        }
+
+        async fn image(&self, _id: &str) -> Result<Option<(String, String)>, SandboxError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oci_clients_per_namespace() -> Result<()> {
+        let clients = OciClients::<dyn OciClient + Send + Sync>::new();
+        let calls = Arc::new(StdMutex::new(vec![]));
+        let connects = Arc::new(StdMutex::new(vec![]));
+
+        let load = async |id: &str, namespace: &str| -> Result<()> {
+            let cfg = InstanceConfig {
+                namespace: namespace.to_string(),
+                containerd_address: "/run/containerd/containerd.sock".to_string(),
+                ..Default::default()
+            };
+            let client = clients
+                .get_or_try_init(&cfg, || async {
+                    connects.lock().unwrap().push(cfg.namespace.clone());
+                    Ok(Arc::new(FakeOciClient {
+                        namespace: cfg.namespace.clone(),
+                        calls: calls.clone(),
+                    }) as _)
+                })
+                .await?;
+            client
+                .load_modules(id, Precompile::Enabled, LayerPolicy::Lenient)
+                .await?;
+            Ok(())
+        };
+
+        load("a1", "ns-a").await?;
+        load("b1", "ns-b").await?;
+        load("a2", "ns-a").await?;
+
+        assert_eq!(*connects.lock().unwrap(), ["ns-a", "ns-b"]);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ("ns-a".to_string(), "a1".to_string()),
+                ("ns-b".to_string(), "b1".to_string()),
+                ("ns-a".to_string(), "a2".to_string()),
+            ]
+        );
+
+        Ok(())
+    }
 }
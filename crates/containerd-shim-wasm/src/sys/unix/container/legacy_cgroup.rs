@@ -0,0 +1,151 @@
+//! Cgroups of the containers created with the systemd cgroup driver on hosts with the legacy,
+//! or the hybrid, cgroup hierarchy.
+//!
+//! youki only manages cgroups through systemd on the unified hierarchy. On the other ones, it
+//! creates the cgroup of the container in each v1 hierarchy at the cgroups path of its spec,
+//! which the systemd driver sets to a `slice:prefix:name` triple instead of a path: youki would
+//! take it for the name of a cgroup at the root of the hierarchies, out of the slice of the pod
+//! and its limits. The triple is rewritten to the path of the scope that systemd would create
+//! for the container before youki creates it, e.g.,
+//! `kubepods-besteffort-pod1.slice:cri-containerd:abc` to
+//! `/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice/cri-containerd-abc.scope`.
+//! The resource limits, the freezer, the stats and the OOM kills of the container then all go
+//! through its cgroups in the v1 hierarchies, under the slice of its pod.
+
+use std::path::{Path, PathBuf};
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use libcgroups::common::{CgroupSetup, get_cgroup_setup};
+use oci_spec::runtime::Spec;
+
+// The slice of the units of the systemd cgroups paths without one
+const DEFAULT_SLICE: &str = "system.slice";
+
+/// Shim side: rewrites the systemd cgroups path of the container `id` in its `spec`, and in the
+/// `config.json` of its `bundle`, to the path of its scope if the host doesn't have the unified
+/// hierarchy.
+pub(super) fn use_scope_path(id: &str, bundle: &Path, spec: &mut Spec) -> Result<(), SandboxError> {
+    let setup = get_cgroup_setup().map_err(|err| {
+        SandboxError::Others(format!("failed to detect the cgroup hierarchy: {err}"))
+    })?;
+    let Some(path) = legacy_cgroups_path(spec, setup) else {
+        return Ok(());
+    };
+
+    log::info!(
+        "creating the cgroup of instance {id} at {path:?}, as systemd only manages the cgroups of the unified hierarchy"
+    );
+    if let Some(linux) = spec.linux_mut() {
+        linux.set_cgroups_path(Some(path));
+    }
+    spec.save(bundle.join("config.json"))?;
+    Ok(())
+}
+
+// The path of the scope of the container with `spec`, if its cgroups path is a systemd one on a
+// host with the cgroup `setup`
+fn legacy_cgroups_path(spec: &Spec, setup: CgroupSetup) -> Option<PathBuf> {
+    if matches!(setup, CgroupSetup::Unified) {
+        return None;
+    }
+    let path = spec.linux().as_ref()?.cgroups_path().as_deref()?;
+    scope_path(path)
+}
+
+// The path of the scope of the systemd cgroups path `slice:prefix:name`, or `None` if it's not
+// one, e.g., when it's already a path
+fn scope_path(cgroups_path: &Path) -> Option<PathBuf> {
+    let fields: Vec<_> = cgroups_path.to_str()?.split(':').collect();
+    let [slice, prefix, name] = fields[..] else {
+        return None;
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let slice = if slice.is_empty() {
+        DEFAULT_SLICE
+    } else {
+        slice
+    };
+    let scope = if prefix.is_empty() {
+        format!("{name}.scope")
+    } else {
+        format!("{prefix}-{name}.scope")
+    };
+    Some(expand_slice(slice)?.join(scope))
+}
+
+// The path of the cgroup of a slice, nested in the slices of the prefixes of its name, e.g.,
+// `/a.slice/a-b.slice` for `a-b.slice`
+fn expand_slice(slice: &str) -> Option<PathBuf> {
+    let name = slice.strip_suffix(".slice")?;
+    // the root slice
+    if name == "-" {
+        return Some(PathBuf::from("/"));
+    }
+    if name.is_empty() || name.contains('/') || name.split('-').any(str::is_empty) {
+        return None;
+    }
+
+    let mut path = PathBuf::from("/");
+    let mut prefix = String::new();
+    for component in name.split('-') {
+        prefix.push_str(component);
+        path.push(format!("{prefix}.slice"));
+        prefix.push('-');
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use oci_spec::runtime::{LinuxBuilder, SpecBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_scope_path() {
+        let scope = |path: &str| scope_path(Path::new(path));
+        assert_eq!(
+            scope("kubepods-besteffort-pod1.slice:cri-containerd:abc"),
+            Some(PathBuf::from(
+                "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice/cri-containerd-abc.scope"
+            ))
+        );
+        assert_eq!(
+            scope(":runwasi:abc"),
+            Some(PathBuf::from("/system.slice/runwasi-abc.scope"))
+        );
+        assert_eq!(scope("-.slice::abc"), Some(PathBuf::from("/abc.scope")));
+
+        // cgroupfs paths, and invalid slices
+        for path in [
+            "/default/abc",
+            "default/abc",
+            "pod--1.slice:cri:abc",
+            "pod:cri:abc",
+        ] {
+            assert_eq!(scope(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_legacy_cgroups_path() -> Result<()> {
+        let linux = LinuxBuilder::default()
+            .cgroups_path("user.slice:runwasi:abc")
+            .build()?;
+        let spec = SpecBuilder::default().linux(linux).build()?;
+
+        let scope = PathBuf::from("/user.slice/runwasi-abc.scope");
+        assert_eq!(
+            legacy_cgroups_path(&spec, CgroupSetup::Legacy),
+            Some(scope.clone())
+        );
+        assert_eq!(legacy_cgroups_path(&spec, CgroupSetup::Hybrid), Some(scope));
+        // systemd creates the scope itself
+        assert_eq!(legacy_cgroups_path(&spec, CgroupSetup::Unified), None);
+
+        Ok(())
+    }
+}
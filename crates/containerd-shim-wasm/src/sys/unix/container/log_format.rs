@@ -0,0 +1,212 @@
+//! Formats of the output of the processes written to files.
+//!
+//! Without a CRI log pipeline, e.g., with `ctr`, containerd hands the shim plain files for the
+//! stdout and stderr, which the shim can write in the CRI log format, so that every line has
+//! its timestamp and stream like in the logs of the pods.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::{SecondsFormat, Utc};
+
+// The maximum length of the content of a record, longer lines are split in partial records.
+// This matches the default `max_container_log_line_size` of the CRI plugin of containerd.
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+// The tags of the records of the partial lines, and of the ends of the lines
+const PARTIAL: &str = "P";
+const FULL: &str = "F";
+
+/// The format of the output of the processes written to files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum LogFormat {
+    /// The output as the process writes it.
+    #[default]
+    Raw,
+    /// The output in the CRI log format, `<timestamp> <stream> <P|F> <content>` lines.
+    Cri,
+}
+
+impl LogFormat {
+    /// Wraps `writer`, which writes the `stream` output of a process to `path`, so that it
+    /// writes the output in this format if `path` is a file.
+    pub(super) fn writer(
+        self,
+        writer: Box<dyn Write + Send>,
+        path: &Path,
+        stream: &'static str,
+    ) -> Box<dyn Write + Send> {
+        match self {
+            Self::Cri if path.metadata().is_ok_and(|metadata| metadata.is_file()) => {
+                Box::new(CriWriter::new(writer, stream, MAX_LINE_LEN))
+            }
+            _ => writer,
+        }
+    }
+}
+
+/// A writer of output in the CRI log format.
+///
+/// Lines are written once they end, or once they reach the maximum length, in which case they
+/// go on in the next records. A line the process didn't end is written when the writer is
+/// dropped.
+struct CriWriter<W: Write> {
+    writer: W,
+    stream: &'static str,
+    max_len: usize,
+    // the content of the line being written
+    line: Vec<u8>,
+}
+
+impl<W: Write> CriWriter<W> {
+    fn new(writer: W, stream: &'static str, max_len: usize) -> Self {
+        Self {
+            writer,
+            stream,
+            max_len,
+            line: Vec::new(),
+        }
+    }
+
+    fn write_record(&mut self, tag: &str) -> io::Result<()> {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut record = format!("{timestamp} {} {tag} ", self.stream).into_bytes();
+        record.extend_from_slice(&self.line);
+        record.push(b'\n');
+        self.line.clear();
+        // the record is written at once, so that files are rotated between records
+        self.writer.write_all(&record)
+    }
+}
+
+impl<W: Write> Write for CriWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(&first) = rest.first() {
+            if self.line.len() == self.max_len {
+                // the line goes on past the maximum length, unless it ends right there
+                if first == b'\n' {
+                    self.write_record(FULL)?;
+                    rest = &rest[1..];
+                } else {
+                    self.write_record(PARTIAL)?;
+                }
+                continue;
+            }
+            let room = self.max_len - self.line.len();
+            let chunk = &rest[..rest.len().min(room)];
+            match chunk.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&chunk[..end]);
+                    self.write_record(FULL)?;
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    self.line.extend_from_slice(chunk);
+                    rest = &rest[chunk.len()..];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for CriWriter<W> {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.write_record(FULL);
+        }
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    // Writes `chunks` in the CRI log format, and returns the stream, tag and content of the records
+    fn write_cri(chunks: &[&str], max_len: usize) -> Vec<(String, String, String)> {
+        let mut output = Vec::new();
+        let mut writer = CriWriter::new(&mut output, "stdout", max_len);
+        for chunk in chunks {
+            writer.write_all(chunk.as_bytes()).unwrap();
+        }
+        drop(writer);
+
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|record| {
+                let fields = record.splitn(4, ' ').collect::<Vec<_>>();
+                let [timestamp, stream, tag, content] = fields[..] else {
+                    panic!("invalid record: {record:?}");
+                };
+                DateTime::parse_from_rfc3339(timestamp).unwrap();
+                (stream.into(), tag.into(), content.into())
+            })
+            .collect()
+    }
+
+    fn record(tag: &str, content: &str) -> (String, String, String) {
+        ("stdout".into(), tag.into(), content.into())
+    }
+
+    #[test]
+    fn test_cri_writer() {
+        // lines written in several parts, and several lines written at once
+        assert_eq!(
+            write_cri(&["hello ", "world\nfoo\n", "bar\n"], 16),
+            [
+                record(FULL, "hello world"),
+                record(FULL, "foo"),
+                record(FULL, "bar")
+            ]
+        );
+
+        // lines longer than the maximum length are split in partial records
+        assert_eq!(
+            write_cri(&["0123456789abcdef0123\n", "0123", "456789abcdef\n"], 8),
+            [
+                record(PARTIAL, "01234567"),
+                record(PARTIAL, "89abcdef"),
+                record(FULL, "0123"),
+                record(PARTIAL, "01234567"),
+                record(FULL, "89abcdef"),
+            ]
+        );
+
+        // a line the process didn't end is written on drop
+        assert_eq!(
+            write_cri(&["done\n", "no newline"], 16),
+            [record(FULL, "done"), record(FULL, "no newline")]
+        );
+    }
+
+    #[test]
+    fn test_log_format_writer() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stdout");
+        std::fs::write(&path, "")?;
+
+        let file = Box::new(std::fs::OpenOptions::new().append(true).open(&path)?);
+        let mut writer = LogFormat::Cri.writer(file, &path, "stderr");
+        writer.write_all(b"hello\n")?;
+        drop(writer);
+        let output = std::fs::read_to_string(&path)?;
+        assert!(output.ends_with(" stderr F hello\n"), "{output:?}");
+
+        let file = Box::new(std::fs::OpenOptions::new().append(true).open(&path)?);
+        let mut writer = LogFormat::Raw.writer(file, &path, "stderr");
+        writer.write_all(b"raw\n")?;
+        drop(writer);
+        assert!(std::fs::read_to_string(&path)?.ends_with(" stderr F hello\nraw\n"));
+
+        Ok(())
+    }
+}
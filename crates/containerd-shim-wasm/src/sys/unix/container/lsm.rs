@@ -0,0 +1,248 @@
+//! SELinux and AppArmor labels of the containers, from `process.selinuxLabel`,
+//! `process.apparmorProfile` and `linux.mountLabel`, e.g., the `container_t` type or the
+//! `cri-containerd.apparmor.d` profile containerd gives the pods.
+//!
+//! youki only labels the processes it `execve`s, while the engine runs the guest in the init
+//! process of the container, so the executor moves the init process to its labels itself before
+//! it runs the guest. As youki sets `no_new_privs` first, the policy has to allow that
+//! transition from the label of the shim, e.g., with a bounded SELinux type or an AppArmor
+//! `change_profile` rule.
+//!
+//! The mount label is set on the tmpfs, shm, devpts and mqueue mounts of the spec with a `context`
+//! option. Like runc, the shim ignores the labels of the LSMs the host doesn't run, with a
+//! warning, but fails the start of the container when a label of an LSM it runs can't be
+//! applied.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::{Process, Spec};
+
+// The mounts of the file systems that take the mount label, as the other ones have the labels
+// of the files they mount
+const LABELED_MOUNT_TYPES: [&str; 4] = ["tmpfs", "devpts", "mqueue", "shm"];
+
+// The LSMs enabled on the host
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Lsms {
+    selinux: bool,
+    apparmor: bool,
+}
+
+impl Lsms {
+    fn host() -> Self {
+        let apparmor = std::fs::read_to_string("/sys/module/apparmor/parameters/enabled")
+            .is_ok_and(|enabled| enabled.starts_with('Y'));
+        Self {
+            selinux: Path::new("/sys/fs/selinux/enforce").exists(),
+            apparmor,
+        }
+    }
+}
+
+/// Shim side: drops the labels of the `spec` of the container `id` for the LSMs the host
+/// doesn't run, and sets its mount label on its mounts, in the `config.json` of its `bundle`
+/// too.
+pub(super) fn prepare(id: &str, bundle: &Path, spec: &mut Spec) -> Result<(), SandboxError> {
+    if prepare_for(id, spec, Lsms::host()) {
+        spec.save(bundle.join("config.json"))?;
+    }
+    Ok(())
+}
+
+// Prepares the labels of `spec` for the LSMs `lsms`, and returns whether it changed
+fn prepare_for(id: &str, spec: &mut Spec, lsms: Lsms) -> bool {
+    let mut changed = false;
+    if let Some(process) = spec.process_mut() {
+        if process.selinux_label().is_some() && !lsms.selinux {
+            log::warn!("ignoring the SELinux label of instance {id}, SELinux isn't enabled");
+            process.set_selinux_label(None);
+            changed = true;
+        }
+        if process.apparmor_profile().is_some() && !lsms.apparmor {
+            log::warn!("ignoring the AppArmor profile of instance {id}, AppArmor isn't enabled");
+            process.set_apparmor_profile(None);
+            changed = true;
+        }
+    }
+
+    let Some(label) = spec.linux().as_ref().and_then(|l| l.mount_label().clone()) else {
+        return changed;
+    };
+    if !lsms.selinux {
+        log::warn!("ignoring the mount label of instance {id}, SELinux isn't enabled");
+        if let Some(linux) = spec.linux_mut() {
+            linux.set_mount_label(None);
+        }
+        return true;
+    }
+
+    for mount in spec.mounts_mut().iter_mut().flatten() {
+        // typos:disable-next-line - false positive "typ"
+        let fs_type = mount.typ().as_deref().unwrap_or_default();
+        if !LABELED_MOUNT_TYPES.contains(&fs_type) {
+            continue;
+        }
+        let mut options = mount.options().clone().unwrap_or_default();
+        // the label of the mount itself wins
+        if options.iter().any(|option| option.starts_with("context=")) {
+            continue;
+        }
+        options.push(format!("context=\"{label}\""));
+        mount.set_options(Some(options));
+        changed = true;
+    }
+    changed
+}
+
+/// Executor side: moves the current process to the SELinux label and the AppArmor profile of
+/// `process`, which the shim only kept for the LSMs the host runs.
+pub(super) fn apply(process: &Process) -> Result<()> {
+    if let Some(label) = process.selinux_label() {
+        // the labels are per thread, and the executor didn't start any other thread yet
+        std::fs::write("/proc/thread-self/attr/current", label)
+            .with_context(|| format!("failed to set the SELinux label to {label:?}"))?;
+        log::debug!("set the SELinux label to {label:?}");
+    }
+    if let Some(profile) = process.apparmor_profile() {
+        // the interface of AppArmor when stacked with other LSMs, since linux 5.1
+        let attr = Path::new("/proc/thread-self/attr/apparmor/current");
+        let attr = if attr.exists() {
+            attr
+        } else {
+            Path::new("/proc/thread-self/attr/current")
+        };
+        std::fs::write(attr, format!("changeprofile {profile}"))
+            .with_context(|| format!("failed to change to the AppArmor profile {profile:?}"))?;
+        log::debug!("changed to the AppArmor profile {profile:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{LinuxBuilder, MountBuilder, ProcessBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn spec() -> Result<Spec> {
+        let process = ProcessBuilder::default()
+            .selinux_label("system_u:system_r:container_t:s0:c1,c2")
+            .apparmor_profile("cri-containerd.apparmor.d")
+            .build()?;
+        let mounts = [
+            ("/dev/shm", "tmpfs", vec!["nosuid".to_string()]),
+            (
+                "/tmp",
+                "tmpfs",
+                vec!["context=\"system_u:object_r:tmp_t:s0\"".to_string()],
+            ),
+            ("/data", "bind", vec!["rbind".to_string()]),
+        ];
+        let mounts = mounts
+            .into_iter()
+            .map(|(destination, fs_type, options)| {
+                MountBuilder::default()
+                    .destination(destination)
+                    // typos:disable-next-line - false positive "typ"
+                    .typ(fs_type)
+                    .options(options)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let linux = LinuxBuilder::default()
+            .mount_label("system_u:object_r:container_file_t:s0:c1,c2")
+            .build()?;
+        Ok(SpecBuilder::default()
+            .process(process)
+            .mounts(mounts)
+            .linux(linux)
+            .build()?)
+    }
+
+    fn options(spec: &Spec, destination: &str) -> Vec<String> {
+        let mount = spec
+            .mounts()
+            .iter()
+            .flatten()
+            .find(|m| m.destination() == Path::new(destination));
+        mount.and_then(|m| m.options().clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_prepare_without_lsms() -> Result<()> {
+        let mut spec = spec()?;
+        assert!(prepare_for("test", &mut spec, Lsms::default()));
+
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.selinux_label(), &None);
+        assert_eq!(process.apparmor_profile(), &None);
+        assert_eq!(spec.linux().as_ref().unwrap().mount_label(), &None);
+        assert_eq!(options(&spec, "/dev/shm"), ["nosuid"]);
+
+        // nothing left to change
+        assert!(!prepare_for("test", &mut spec, Lsms::default()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare_with_selinux() -> Result<()> {
+        let mut spec = spec()?;
+        let lsms = Lsms {
+            selinux: true,
+            apparmor: false,
+        };
+        assert!(prepare_for("test", &mut spec, lsms));
+
+        let process = spec.process().as_ref().unwrap();
+        assert!(process.selinux_label().is_some());
+        assert_eq!(process.apparmor_profile(), &None);
+        assert_eq!(
+            options(&spec, "/dev/shm"),
+            [
+                "nosuid",
+                "context=\"system_u:object_r:container_file_t:s0:c1,c2\""
+            ]
+        );
+        assert_eq!(
+            options(&spec, "/tmp"),
+            ["context=\"system_u:object_r:tmp_t:s0\""]
+        );
+        assert_eq!(options(&spec, "/data"), ["rbind"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_current_labels() -> Result<()> {
+        // moving to the labels the test already runs with needs no rule of the policy, and only
+        // changes the labels of the thread of the test
+        let lsms = Lsms::host();
+        let mut process = ProcessBuilder::default().build()?;
+        if lsms.selinux {
+            let label = std::fs::read_to_string("/proc/thread-self/attr/current")?;
+            process.set_selinux_label(Some(label.trim_end_matches(['\0', '\n']).to_string()));
+        }
+        let apparmor_attr = Path::new("/proc/thread-self/attr/apparmor/current");
+        if lsms.apparmor && apparmor_attr.exists() {
+            // e.g. `unconfined`, or `docker-default (enforce)`
+            let current = std::fs::read_to_string(apparmor_attr)?;
+            let profile = current.split(" (").next().unwrap_or_default().trim();
+            process.set_apparmor_profile(Some(profile.to_string()));
+        }
+        apply(&process)?;
+
+        if let Some(label) = process.selinux_label() {
+            let current = std::fs::read_to_string("/proc/thread-self/attr/current")?;
+            assert_eq!(current.trim_end_matches(['\0', '\n']), label);
+        }
+        if let Some(profile) = process.apparmor_profile() {
+            let current = std::fs::read_to_string(apparmor_attr)?;
+            assert!(current.starts_with(profile.as_str()), "{current}");
+        }
+
+        Ok(())
+    }
+}
@@ -1,5 +1,30 @@
+mod checkpoint;
+pub(crate) mod cleanup;
 #[allow(clippy::module_inception)]
 mod container;
 
+mod cpu_time;
+mod devices;
 mod executor;
+mod exit_status;
+mod hooks;
 pub mod instance;
+mod legacy_cgroup;
+mod log_format;
+mod lsm;
+mod output;
+mod pause;
+mod privileges;
+mod pty;
+mod restart;
+mod rlimits;
+mod rootless;
+mod scheduling;
+mod start_deadline;
+mod startup;
+mod terminate;
+mod tmpfs;
+mod user_namespace;
+mod validate;
+
+pub(crate) use terminate::parse_duration;
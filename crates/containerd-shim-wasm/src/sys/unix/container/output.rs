@@ -0,0 +1,353 @@
+//! Output of the processes of the instances.
+//!
+//! The stdout and stderr of a process without a terminal are pipes whose read side the shim
+//! keeps, rather than the stdio of containerd, so that the exit of the process is only reported
+//! once its output is copied to containerd, including what it wrote right before exiting.
+//! The zygote opens the write side by its path, as file descriptors can't be sent to it.
+//!
+//! The pipes of the init process are FIFOs in the bundle of the instance, which a restarted
+//! shim reopens to copy the output of the instances it recovers. The process opens them for
+//! reading too, so that its writes block rather than fail while no shim reads them.
+
+use std::fs::{File, OpenOptions, remove_file};
+use std::future::Future;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd as _, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use containerd_shimkit::sandbox::sync::WaitableCell;
+use containerd_shimkit::sandbox::{InstanceConfig, OutputOptions};
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use nix::sys::stat::Mode;
+use nix::unistd::{mkfifo, pipe2};
+
+use super::log_format::LogFormat;
+
+// How long the exit of a process waits for its output to be copied, as the processes it
+// spawned may still hold its stdout
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The FIFOs of the stdout and stderr of the init process, in the bundle of the instance
+const STDOUT_FIFO: &str = "stdout.fifo";
+const STDERR_FIFO: &str = "stderr.fifo";
+
+/// How the output of the processes is written to their stdout and stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct OutputConfig {
+    pub(super) options: OutputOptions,
+    pub(super) format: LogFormat,
+}
+
+impl OutputConfig {
+    pub(super) fn open_stdout(&self, cfg: &InstanceConfig) -> io::Result<Box<dyn Write + Send>> {
+        let writer = cfg.open_stdout_with(self.options)?;
+        Ok(self.format.writer(writer, &cfg.stdout, "stdout"))
+    }
+
+    pub(super) fn open_stderr(&self, cfg: &InstanceConfig) -> io::Result<Box<dyn Write + Send>> {
+        let writer = cfg.open_stderr_with(self.options)?;
+        Ok(self.format.writer(writer, &cfg.stderr, "stderr"))
+    }
+}
+
+/// The pipes of the stdout and stderr of a process that isn't started yet.
+pub(super) struct Pipes {
+    stdout: Option<Pipe>,
+    stderr: Option<Pipe>,
+}
+
+struct Pipe {
+    reader: File,
+    writer: OwnedFd,
+    // the path the zygote opens the write side with
+    path: PathBuf,
+}
+
+impl Pipe {
+    fn open() -> io::Result<Self> {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+        let pid = std::process::id();
+        let path = PathBuf::from(format!("/proc/{pid}/fd/{}", writer.as_raw_fd()));
+        Ok(Self {
+            reader: reader.into(),
+            writer,
+            path,
+        })
+    }
+
+    // Creates the FIFO at `path`, replacing the one of a previous init process
+    fn create_fifo(path: PathBuf) -> io::Result<Self> {
+        match remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        let reader = open_fifo_reader(&path)?;
+        // kept until the process opened the FIFO, so that reading it doesn't see the EOF of a
+        // FIFO without writers before
+        let writer = OpenOptions::new().write(true).open(&path)?.into();
+        Ok(Self {
+            reader,
+            writer,
+            path,
+        })
+    }
+}
+
+impl Pipes {
+    /// Opens the pipes of the stdout and stderr `cfg` has.
+    pub(super) fn open(cfg: &InstanceConfig) -> io::Result<Self> {
+        let open = |path: &Path| (!path.as_os_str().is_empty()).then(Pipe::open).transpose();
+        Ok(Self {
+            stdout: open(&cfg.stdout)?,
+            stderr: open(&cfg.stderr)?,
+        })
+    }
+
+    /// Creates the FIFOs of the stdout and stderr `cfg` has for the init process, in its `bundle`,
+    /// see [`reconnect`].
+    pub(super) fn create_fifos(cfg: &InstanceConfig, bundle: &Path) -> io::Result<Self> {
+        let create = |path: &Path, name| {
+            (!path.as_os_str().is_empty())
+                .then(|| Pipe::create_fifo(bundle.join(name)))
+                .transpose()
+        };
+        Ok(Self {
+            stdout: create(&cfg.stdout, STDOUT_FIFO)?,
+            stderr: create(&cfg.stderr, STDERR_FIFO)?,
+        })
+    }
+
+    /// Returns `cfg` with the write sides of the pipes as its stdout and stderr, for the zygote
+    /// to open them with.
+    pub(super) fn zygote_config(&self, cfg: &InstanceConfig) -> InstanceConfig {
+        let mut cfg = cfg.clone();
+        if let Some(pipe) = &self.stdout {
+            cfg.stdout = pipe.path.clone();
+        }
+        if let Some(pipe) = &self.stderr {
+            cfg.stderr = pipe.path.clone();
+        }
+        cfg
+    }
+
+    /// Starts copying the pipes to the stdout and stderr of `cfg`, written per `config`.
+    /// This must be called once the process opened the write sides, so that the copies end when
+    /// it exits.
+    pub(super) fn connect(self, cfg: &InstanceConfig, config: OutputConfig) -> Output {
+        let mut output = Output::default();
+        if let Some(Pipe { reader, writer, .. }) = self.stdout {
+            drop(writer);
+            output.copy(reader, config.open_stdout(cfg), "stdout");
+        }
+        if let Some(Pipe { reader, writer, .. }) = self.stderr {
+            drop(writer);
+            output.copy(reader, config.open_stderr(cfg), "stderr");
+        }
+        output
+    }
+}
+
+/// Reopens the FIFOs of the init process of an instance in its `bundle`, which a previous shim
+/// created with [`Pipes::create_fifos`], and copies them to the stdout and stderr of `cfg`.
+/// The output the process wrote while no shim read it is copied as well, up to the capacity of
+/// the FIFOs, beyond which the process was blocked.
+pub(super) fn reconnect(cfg: &InstanceConfig, bundle: &Path, config: OutputConfig) -> Output {
+    let mut output = Output::default();
+    let reopen = |path: &Path, name, what| {
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+        match open_fifo_reader(&bundle.join(name)) {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                log::warn!("failed to reopen the {what} of the process: {err}");
+                None
+            }
+        }
+    };
+    if let Some(reader) = reopen(&cfg.stdout, STDOUT_FIFO, "stdout") {
+        output.copy(reader, config.open_stdout(cfg), "stdout");
+    }
+    if let Some(reader) = reopen(&cfg.stderr, STDERR_FIFO, "stderr") {
+        output.copy(reader, config.open_stderr(cfg), "stderr");
+    }
+    output
+}
+
+/// Removes the FIFOs of the init process of an instance from its `bundle`.
+pub(super) fn remove_fifos(bundle: &Path) {
+    for name in [STDOUT_FIFO, STDERR_FIFO] {
+        match remove_file(bundle.join(name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                log::warn!("failed to remove {name}: {err}");
+            }
+            _ => {}
+        }
+    }
+}
+
+// Opens the FIFO at `path` for reading only, so that the reader sees EOF once the process
+// exits. Opening doesn't wait for a writer, but reading does.
+fn open_fifo_reader(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let flags = OFlag::from_bits_retain(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(
+        file.as_raw_fd(),
+        FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK),
+    )?;
+    Ok(file)
+}
+
+/// The output of a process being copied to its stdio.
+#[derive(Default)]
+pub(super) struct Output {
+    copied: Vec<WaitableCell<()>>,
+    first_write: Arc<OnceLock<Instant>>,
+}
+
+impl Output {
+    /// Copies `reader` to `writer` on a thread until `reader` ends. The output is discarded
+    /// without a `writer`, so that writing it never blocks the process.
+    pub(super) fn copy(
+        &mut self,
+        reader: impl Read + Send + 'static,
+        writer: io::Result<impl Write + Send + 'static>,
+        what: &'static str,
+    ) {
+        let copied = WaitableCell::new();
+        let guard = copied.set_guard_with(|| ());
+        let reader = FirstWrite {
+            reader,
+            first_write: self.first_write.clone(),
+        };
+        std::thread::spawn(move || {
+            let _guard = guard;
+            match writer {
+                Ok(writer) => copy(reader, writer, what),
+                Err(_) => copy(reader, io::sink(), what),
+            }
+        });
+        self.copied.push(copied);
+    }
+
+    /// When the process first wrote its output, if it did.
+    pub(super) fn first_write(&self) -> Option<Instant> {
+        self.first_write.get().copied()
+    }
+
+    /// Resolves once the output of the exited process is copied, so that its exit isn't
+    /// reported before its last output.
+    pub(super) fn drained(&self) -> impl Future<Output = ()> + Send + use<> {
+        let copied = self.copied.clone();
+        async move {
+            let copied = futures::future::join_all(copied.iter().map(WaitableCell::wait));
+            if tokio::time::timeout(DRAIN_TIMEOUT, copied).await.is_err() {
+                log::warn!("the output of the process wasn't copied within {DRAIN_TIMEOUT:?}");
+            }
+        }
+    }
+}
+
+// A reader of the output of a process, recording when the process first wrote it
+struct FirstWrite<R> {
+    reader: R,
+    first_write: Arc<OnceLock<Instant>>,
+}
+
+impl<R: Read> Read for FirstWrite<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if n > 0 {
+            self.first_write.get_or_init(Instant::now);
+        }
+        Ok(n)
+    }
+}
+
+pub(super) fn copy(mut reader: impl Read, mut writer: impl Write, what: &str) {
+    match io::copy(&mut reader, &mut writer) {
+        Ok(_) => {}
+        // reading from the master side of a pty fails once the process closed the slave side
+        Err(err) if err.raw_os_error() == Some(libc::EIO) => {}
+        Err(err) => log::warn!("failed to copy the {what} of the process: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pipes() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cfg = InstanceConfig {
+            stdout: dir.path().join("stdout"),
+            ..Default::default()
+        };
+        std::fs::write(&cfg.stdout, "")?;
+
+        let pipes = Pipes::open(&cfg)?;
+        assert!(pipes.stderr.is_none());
+        let before = Instant::now();
+        let zygote_cfg = pipes.zygote_config(&cfg);
+        assert_ne!(zygote_cfg.stdout, cfg.stdout);
+        assert_eq!(zygote_cfg.stderr, cfg.stderr);
+
+        // the process writes more than the pipe holds without a newline, then exits
+        let payload = "x".repeat(1024 * 1024);
+        let mut process_stdout = zygote_cfg.open_stdout()?;
+        let output = pipes.connect(&cfg, OutputConfig::default());
+        let writer = std::thread::spawn(move || process_stdout.write_all(payload.as_bytes()));
+        writer.join().unwrap()?;
+
+        output.drained().await;
+        assert_eq!(std::fs::read(&cfg.stdout)?.len(), 1024 * 1024);
+        assert!(
+            output
+                .first_write()
+                .is_some_and(|first_write| first_write >= before)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fifos_are_reconnected() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bundle = dir.path().join("bundle");
+        std::fs::create_dir(&bundle)?;
+        let cfg = InstanceConfig {
+            stdout: dir.path().join("stdout"),
+            ..Default::default()
+        };
+        std::fs::write(&cfg.stdout, "")?;
+
+        let pipes = Pipes::create_fifos(&cfg, &bundle)?;
+        let zygote_cfg = pipes.zygote_config(&cfg);
+        assert_eq!(zygote_cfg.stdout, bundle.join(STDOUT_FIFO));
+        let mut process_stdout = zygote_cfg.open_stdout()?;
+
+        // the shim goes away, and the process keeps writing its output
+        drop(pipes);
+        process_stdout.write_all(b"before ")?;
+
+        let output = reconnect(&cfg, &bundle, OutputConfig::default());
+        process_stdout.write_all(b"after")?;
+        drop(process_stdout);
+
+        output.drained().await;
+        assert_eq!(std::fs::read_to_string(&cfg.stdout)?, "before after");
+
+        remove_fifos(&bundle);
+        assert!(!bundle.join(STDOUT_FIFO).exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! The pause container of the pods, which only holds their namespaces.
+//!
+//! Its image has no wasm module, so the executor runs a built-in pause in its place: it sleeps
+//! until it's signaled, reaping the orphaned processes of the pod meanwhile, as it's their
+//! init when the pod shares its PID namespace.
+
+use nix::errno::Errno;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+
+/// Waits for SIGTERM or SIGINT, and exits with code 0 once it's received.
+pub(super) fn run() -> ! {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGCHLD);
+    // blocked signals are queued for the init of a PID namespace, unlike the ones it doesn't handle
+    if let Err(err) = signals.thread_block() {
+        log::error!("failed to block the signals of the pause: {err}");
+        std::process::exit(1);
+    }
+
+    log::info!("pausing until signaled");
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => reap(),
+            Ok(signal) => {
+                log::info!("exiting on {signal}");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                log::error!("failed to wait for signals: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Reaps the exited children, whose SIGCHLDs may have been merged into one
+fn reap() {
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return,
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => {
+                log::warn!("failed to reap the children of the pause: {err}");
+                return;
+            }
+        }
+    }
+}
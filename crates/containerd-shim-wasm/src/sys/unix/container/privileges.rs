@@ -0,0 +1,221 @@
+//! Capabilities and `no_new_privs` of the process of the containers, from
+//! `process.capabilities` and `process.noNewPrivileges`.
+//!
+//! The guest is sandboxed by wasm, but the engine running it is native code, so its process
+//! shouldn't keep the capabilities of the shim. youki drops the init process to the capability
+//! sets of the spec, and sets `no_new_privs`, before it calls the executor, and the executor
+//! checks that it did before it runs any code of the engine.
+//!
+//! A spec without capabilities keeps the ones of the shim, except for the containers of the
+//! pods of the restricted Pod Security Standard, which can neither run as root nor escalate
+//! their privileges, so they get no capabilities at all.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use caps::{CapSet, CapsHashSet};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use libcontainer::capabilities::CapabilityExt as _;
+use oci_spec::runtime::{Capabilities, LinuxCapabilities, LinuxCapabilitiesBuilder, Process, Spec};
+
+/// Shim side: checks that the kernel supports the capabilities of the `spec` of the container
+/// `id`, and drops its capabilities when it's restricted, in the `config.json` of its `bundle`
+/// too.
+pub(super) fn prepare(id: &str, bundle: &Path, spec: &mut Spec) -> Result<(), SandboxError> {
+    let Some(process) = spec.process_mut() else {
+        return Ok(());
+    };
+    if let Some(capabilities) = process.capabilities() {
+        let supported = caps::runtime::procfs_all_supported(None).map_err(|err| {
+            SandboxError::Others(format!(
+                "failed to read the capabilities of the kernel: {err}"
+            ))
+        })?;
+        return check_supported(id, capabilities, &supported);
+    }
+    if !is_restricted(process) {
+        return Ok(());
+    }
+
+    log::info!("dropping all the capabilities of the restricted instance {id}");
+    process.set_capabilities(Some(no_capabilities()?));
+    spec.save(bundle.join("config.json"))?;
+    Ok(())
+}
+
+fn check_supported(
+    id: &str,
+    capabilities: &LinuxCapabilities,
+    supported: &CapsHashSet,
+) -> Result<(), SandboxError> {
+    match sets(capabilities)
+        .flatten()
+        .find(|capability| !supported.contains(&capability.to_cap()))
+    {
+        Some(capability) => Err(SandboxError::InvalidArgument(format!(
+            "the kernel doesn't support the capability {:?} of instance {id}",
+            capability.to_cap()
+        ))),
+        None => Ok(()),
+    }
+}
+
+// The processes that can neither run as root nor gain privileges, as the restricted Pod
+// Security Standard requires
+fn is_restricted(process: &Process) -> bool {
+    process.no_new_privileges() == Some(true) && process.user().uid() != 0
+}
+
+fn no_capabilities() -> Result<LinuxCapabilities, SandboxError> {
+    Ok(LinuxCapabilitiesBuilder::default()
+        .bounding(Capabilities::new())
+        .effective(Capabilities::new())
+        .inheritable(Capabilities::new())
+        .permitted(Capabilities::new())
+        .ambient(Capabilities::new())
+        .build()?)
+}
+
+fn sets(capabilities: &LinuxCapabilities) -> impl Iterator<Item = &Capabilities> {
+    [
+        capabilities.bounding(),
+        capabilities.effective(),
+        capabilities.inheritable(),
+        capabilities.permitted(),
+        capabilities.ambient(),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Executor side: checks that the current process has no other effective capabilities than
+/// the ones of `process`, and has `no_new_privs` set if `process` asks for it.
+pub(super) fn check(process: &Process) -> Result<()> {
+    let effective = process
+        .capabilities()
+        .as_ref()
+        .and_then(|capabilities| capabilities.effective().as_ref());
+    // youki leaves the effective set alone when the spec has none
+    if let Some(effective) = effective {
+        let allowed: CapsHashSet = effective.iter().map(|c| c.to_cap()).collect();
+        let current = caps::read(None, CapSet::Effective)?;
+        let kept: HashSet<_> = current.difference(&allowed).collect();
+        if !kept.is_empty() {
+            bail!("the process kept the capabilities {kept:?} the spec doesn't have");
+        }
+    }
+    if process.no_new_privileges() == Some(true) && !no_new_privs()? {
+        bail!("the process doesn't have no_new_privs set");
+    }
+    Ok(())
+}
+
+fn no_new_privs() -> Result<bool> {
+    // SAFETY: PR_GET_NO_NEW_PRIVS takes no pointers
+    let res = unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(res == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+    use oci_spec::runtime::{Capability, ProcessBuilder, SpecBuilder, UserBuilder};
+
+    use super::*;
+
+    // The effective capabilities of the current process, as `/proc/self/status` lists them
+    fn status_effective() -> Result<CapsHashSet> {
+        let status = std::fs::read_to_string("/proc/self/status")?;
+        let mask = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .context("no CapEff in /proc/self/status")?;
+        let mask = u64::from_str_radix(mask.trim(), 16)?;
+        Ok(caps::all()
+            .into_iter()
+            .filter(|cap| mask & cap.bitmask() != 0)
+            .collect())
+    }
+
+    fn process(uid: u32, no_new_privileges: bool) -> Result<Process> {
+        let mut process = ProcessBuilder::default()
+            .user(UserBuilder::default().uid(uid).build()?)
+            .no_new_privileges(no_new_privileges)
+            .build()?;
+        // the default process has the default capabilities of the runtime spec
+        process.set_capabilities(None);
+        Ok(process)
+    }
+
+    #[test]
+    fn test_prepare() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+
+        let mut spec = SpecBuilder::default()
+            .process(process(1000, true)?)
+            .build()?;
+        prepare("test", bundle.path(), &mut spec)?;
+        let saved = Spec::load(bundle.path().join("config.json"))?;
+        let capabilities = saved.process().as_ref().unwrap().capabilities().clone();
+        assert_eq!(capabilities, Some(no_capabilities()?));
+
+        // root, or processes that may escalate their privileges, keep the ones of the shim
+        for process in [process(0, true)?, process(1000, false)?] {
+            assert!(!is_restricted(&process));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_supported() -> Result<()> {
+        let capabilities = LinuxCapabilitiesBuilder::default()
+            .bounding(Capabilities::from([Capability::Chown, Capability::Bpf]))
+            .build()?;
+        // e.g., linux before 5.8
+        let mut supported = caps::all();
+        supported.remove(&caps::Capability::CAP_BPF);
+        let err = check_supported("test", &capabilities, &supported).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        assert!(err.to_string().contains("CAP_BPF"), "{err}");
+
+        check_supported("test", &capabilities, &caps::all())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        // the test runs with the capabilities it has
+        let current = status_effective()?;
+        assert_eq!(current, caps::read(None, CapSet::Effective)?);
+
+        let effective: Capabilities = current
+            .iter()
+            .map(|cap| Capability::from_cap(*cap))
+            .collect();
+        let capabilities = LinuxCapabilitiesBuilder::default()
+            .effective(effective)
+            .build()?;
+        let mut process = ProcessBuilder::default()
+            .capabilities(capabilities)
+            .no_new_privileges(false)
+            .build()?;
+        check(&process)?;
+
+        if !current.is_empty() {
+            let none = LinuxCapabilitiesBuilder::default()
+                .effective(Capabilities::new())
+                .build()?;
+            process.set_capabilities(Some(none));
+            let err = check(&process).unwrap_err();
+            assert!(err.to_string().contains("kept the capabilities"), "{err}");
+        }
+
+        Ok(())
+    }
+}
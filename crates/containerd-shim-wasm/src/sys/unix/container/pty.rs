@@ -0,0 +1,105 @@
+//! Terminals of the processes of the instances.
+//!
+//! When containerd asks for a terminal, the shim allocates a pty, whose slave side becomes the
+//! stdio of the process. The slave is opened by its path in the zygote, as file descriptors
+//! can't be sent to it. The shim then copies the stdin of the process to the master side, and
+//! the output of the master side to the stdout of the process.
+
+use std::fs::{File, OpenOptions, read_link};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd as _, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+
+use containerd_shimkit::sandbox::InstanceConfig;
+use nix::pty::openpty;
+
+use super::output::{Output, OutputConfig, copy};
+
+// The character a terminal in canonical mode reads as the end of its input, i.e., ctrl-D
+const VEOF: u8 = 0x04;
+
+/// A pty allocated for a process that isn't connected to its stdio yet.
+pub(super) struct Pty {
+    master: File,
+    slave: OwnedFd,
+    slave_path: PathBuf,
+}
+
+impl Pty {
+    pub(super) fn open() -> io::Result<Self> {
+        let pty = openpty(None, None)?;
+        let slave_path = read_link(format!("/proc/self/fd/{}", pty.slave.as_raw_fd()))?;
+        Ok(Self {
+            master: pty.master.into(),
+            slave: pty.slave,
+            slave_path,
+        })
+    }
+
+    /// The path to open the slave side with, see [`open_slave`]
+    pub(super) fn slave_path(&self) -> &Path {
+        &self.slave_path
+    }
+
+    /// Starts copying the stdin of `cfg` to the pty, and the output of the pty to the stdout
+    /// of `cfg`. This must be called once the process opened the slave side, so that reading
+    /// from the master side stops when the process exits.
+    pub(super) fn connect(
+        self,
+        cfg: &InstanceConfig,
+        config: OutputConfig,
+    ) -> io::Result<(Terminal, Output)> {
+        let Self { master, slave, .. } = self;
+        drop(slave);
+
+        if let Ok(stdin) = cfg.open_stdin() {
+            let mut input = master.try_clone()?;
+            std::thread::spawn(move || {
+                copy(stdin, &mut input, "terminal input");
+                // let the process read the end of its input
+                let _ = input.write_all(&[VEOF]);
+            });
+        }
+
+        let mut output = Output::default();
+        output.copy(
+            master.try_clone()?,
+            config.open_stdout(cfg),
+            "terminal output",
+        );
+
+        Ok((Terminal { master }, output))
+    }
+}
+
+/// The terminal of a process, connected to its stdio.
+pub(super) struct Terminal {
+    master: File,
+}
+
+impl Terminal {
+    /// Resizes the terminal to `width` columns and `height` rows.
+    pub(super) fn resize(&self, width: u32, height: u32) -> io::Result<()> {
+        let size = libc::winsize {
+            ws_row: height.try_into().unwrap_or(u16::MAX),
+            ws_col: width.try_into().unwrap_or(u16::MAX),
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: `master` is the master side of a pty, and `size` is a valid winsize
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Opens the slave side of a pty, without making it the controlling terminal of the zygote.
+pub(super) fn open_slave(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)
+}
@@ -0,0 +1,256 @@
+//! Restarts of the instances whose init process fails, for the deployments without an
+//! orchestrator to restart them, e.g., on the edge.
+//!
+//! With `io.runwasi.restart=on-failure:5`, an init process that exits with a non-zero status is
+//! rebuilt from the same bundle and wasm layers, and started again after an exponential backoff,
+//! up to 5 times. Only the exit that isn't restarted is reported as the exit of the instance.
+//! A kill of the instance cancels the restarts, including the pending one.
+//!
+//! The restarts are logged, and counted in the stats of the instance as a [`RestartCount`]
+//! extension.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use containerd_shimkit::sandbox::sync::WaitableCell;
+use containerd_shimkit::sandbox::{Error as SandboxError, Stats};
+use futures::FutureExt as _;
+use oci_spec::runtime::Spec;
+use prost::Message;
+
+/// Annotation with the restart policy of the instance: `no`, the default, `on-failure` to
+/// restart it every time it fails, or `on-failure:<n>` to restart it up to `n` times.
+pub(super) const RESTART_ANNOTATION: &str = "io.runwasi.restart";
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The restart count of an instance, as reported in its stats.
+#[derive(Message, Clone, PartialEq)]
+pub(super) struct RestartCount {
+    #[prost(uint32, tag = "1")]
+    pub(super) restarts: u32,
+}
+
+impl RestartCount {
+    pub(super) const TYPE_URL: &str = "io.runwasi.v1.RestartCount";
+}
+
+/// When the init process of an instance is restarted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct RestartPolicy {
+    // `None` restarts it every time it fails
+    max_restarts: Option<u32>,
+}
+
+impl RestartPolicy {
+    /// Returns the restart policy of the instance with runtime spec `spec`, or `None` if it's
+    /// never restarted.
+    pub(super) fn from_spec(spec: &Spec) -> Result<Option<Self>, SandboxError> {
+        let Some(value) = spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(RESTART_ANNOTATION))
+        else {
+            return Ok(None);
+        };
+
+        Self::parse(value).ok_or_else(|| {
+            SandboxError::InvalidArgument(format!(
+                "invalid {RESTART_ANNOTATION} annotation: {value:?}"
+            ))
+        })
+    }
+
+    fn parse(value: &str) -> Option<Option<Self>> {
+        match value.trim().split_once(':') {
+            None if value.trim() == "no" => Some(None),
+            None if value.trim() == "on-failure" => Some(Some(Self { max_restarts: None })),
+            Some(("on-failure", max)) => {
+                let max_restarts = max.parse().ok()?;
+                Some(Some(Self {
+                    max_restarts: Some(max_restarts),
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The restarts of an instance.
+pub(super) struct Restarts {
+    policy: RestartPolicy,
+    initial_backoff: Duration,
+    count: AtomicU32,
+    cancelled: WaitableCell<()>,
+}
+
+impl Restarts {
+    pub(super) fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            initial_backoff: INITIAL_BACKOFF,
+            count: AtomicU32::new(0),
+            cancelled: WaitableCell::new(),
+        }
+    }
+
+    /// The number of times the instance was restarted.
+    pub(super) fn count(&self) -> u32 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the restarts of the instance, e.g., once it's killed.
+    pub(super) fn cancel(&self) {
+        let _ = self.cancelled.set(());
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.wait().now_or_never().is_some()
+    }
+
+    /// Waits for the backoff before restarting the instance `id`, whose init process exited
+    /// with `status`, and returns the number of the restart, or `None` if it's not restarted.
+    pub(super) async fn next(&self, id: &str, status: u32) -> Option<u32> {
+        if status == 0 || self.is_cancelled() {
+            return None;
+        }
+        let restart = self.count() + 1;
+        if self.policy.max_restarts.is_some_and(|max| restart > max) {
+            log::info!(
+                "instance {id} exited with status {status}, and isn't restarted after {} restarts",
+                restart - 1
+            );
+            return None;
+        }
+
+        let delay = self.backoff(restart);
+        let of_max = (self.policy.max_restarts)
+            .map(|max| format!(" of {max}"))
+            .unwrap_or_default();
+        log::info!(
+            "instance {id} exited with status {status}, restarting it in {delay:?}, restart {restart}{of_max}"
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = self.cancelled.wait() => {
+                log::info!("restart {restart} of instance {id} cancelled");
+                return None;
+            }
+        }
+        self.count.store(restart, Ordering::SeqCst);
+        Some(restart)
+    }
+
+    /// Adds the restart count of the instance to its `stats`.
+    pub(super) fn add_to(&self, stats: &mut Stats) {
+        let count = RestartCount {
+            restarts: self.count(),
+        };
+        stats.add_extension(RestartCount::TYPE_URL, count.encode_to_vec());
+    }
+
+    // The exponential backoff before the `restart`th restart
+    fn backoff(&self, restart: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restart.saturating_sub(1)))
+            .min(MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use containerd_shimkit::sandbox::stats::v2;
+
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn policy(value: Option<&str>) -> Result<Option<RestartPolicy>> {
+        let annotation = value.map(|value| (RESTART_ANNOTATION, value));
+        let spec = spec_with_annotations(annotation.as_slice())?;
+        Ok(RestartPolicy::from_spec(&spec)?)
+    }
+
+    #[test]
+    fn test_restart_annotation() -> Result<()> {
+        assert_eq!(policy(None)?, None);
+        assert_eq!(policy(Some("no"))?, None);
+        assert_eq!(
+            policy(Some("on-failure"))?,
+            Some(RestartPolicy { max_restarts: None })
+        );
+        assert_eq!(
+            policy(Some("on-failure:5"))?,
+            Some(RestartPolicy {
+                max_restarts: Some(5)
+            })
+        );
+        for invalid in ["always", "on-failure:", "on-failure:-1", "no:5"] {
+            assert!(policy(Some(invalid)).is_err(), "{invalid}");
+        }
+
+        Ok(())
+    }
+
+    fn restarts(max_restarts: Option<u32>) -> Restarts {
+        Restarts {
+            initial_backoff: Duration::from_millis(10),
+            ..Restarts::new(RestartPolicy { max_restarts })
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let restarts = Restarts::new(RestartPolicy { max_restarts: None });
+        assert_eq!(restarts.backoff(1), Duration::from_secs(1));
+        assert_eq!(restarts.backoff(3), Duration::from_secs(4));
+        assert_eq!(restarts.backoff(100), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_restarts() -> Result<()> {
+        let restarts = restarts(Some(2));
+
+        // successful exits are never restarted
+        assert_eq!(restarts.next("test", 0).await, None);
+
+        assert_eq!(restarts.next("test", 1).await, Some(1));
+        assert_eq!(restarts.next("test", 137).await, Some(2));
+        assert_eq!(restarts.next("test", 1).await, None);
+        assert_eq!(restarts.count(), 2);
+
+        let mut stats = Stats::from(v2::Metrics::default());
+        restarts.add_to(&mut stats);
+        let Stats::V2(metrics) = stats else {
+            unreachable!();
+        };
+        let [extension] = &metrics.extensions[..] else {
+            panic!("unexpected extensions: {:?}", metrics.extensions);
+        };
+        assert_eq!(extension.type_url, RestartCount::TYPE_URL);
+        assert_eq!(
+            RestartCount::decode(extension.value.as_slice())?.restarts,
+            2
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_pending_restart() {
+        let restarts = Restarts::new(RestartPolicy { max_restarts: None });
+
+        let next = restarts.next("test", 1);
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            restarts.cancel();
+        };
+        let (next, ()) = tokio::join!(next, cancel);
+        assert_eq!(next, None);
+        assert_eq!(restarts.count(), 0);
+
+        // nor is any later one
+        assert_eq!(restarts.next("test", 1).await, None);
+    }
+}
@@ -0,0 +1,122 @@
+//! Resource limits of the process of the containers, from `process.rlimits`, e.g., a raised
+//! `RLIMIT_NOFILE` for a guest serving many sockets, or `RLIMIT_CORE=0` to never dump the core
+//! of the engine.
+//!
+//! The engine runs the guest in the init process of the container, so the limits are set in it
+//! before the executor runs the guest. The limits that aren't listed keep the values the init
+//! process inherited from the shim.
+
+use anyhow::{Context as _, Result};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use nix::sys::resource::{Resource, setrlimit};
+use oci_spec::runtime::{PosixRlimit, PosixRlimitType, Process};
+
+/// Shim side: checks that the rlimits of the `process` of the instance `id` can be set, so that
+/// an invalid one fails the creation of the container rather than its start.
+pub(super) fn check(id: &str, process: &Process) -> Result<(), SandboxError> {
+    for rlimit in process.rlimits().iter().flatten() {
+        if rlimit.soft() > rlimit.hard() {
+            return Err(SandboxError::InvalidArgument(format!(
+                "invalid {:?} rlimit of instance {id}: the soft limit {} is above the hard limit {}",
+                resource(rlimit),
+                rlimit.soft(),
+                rlimit.hard()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Executor side: sets the rlimits of `process` on the current process.
+pub(super) fn apply(process: &Process) -> Result<()> {
+    for rlimit in process.rlimits().iter().flatten() {
+        let resource = resource(rlimit);
+        let (soft, hard) = (rlimit.soft(), rlimit.hard());
+        setrlimit(resource, soft, hard)
+            .with_context(|| format!("failed to set the {resource:?} rlimit to {soft}/{hard}"))?;
+        log::debug!("set the {resource:?} rlimit to {soft}/{hard}");
+    }
+    Ok(())
+}
+
+fn resource(rlimit: &PosixRlimit) -> Resource {
+    // typos:disable-next-line - false positive "typ"
+    match rlimit.typ() {
+        PosixRlimitType::RlimitCpu => Resource::RLIMIT_CPU,
+        PosixRlimitType::RlimitFsize => Resource::RLIMIT_FSIZE,
+        PosixRlimitType::RlimitData => Resource::RLIMIT_DATA,
+        PosixRlimitType::RlimitStack => Resource::RLIMIT_STACK,
+        PosixRlimitType::RlimitCore => Resource::RLIMIT_CORE,
+        PosixRlimitType::RlimitRss => Resource::RLIMIT_RSS,
+        PosixRlimitType::RlimitNproc => Resource::RLIMIT_NPROC,
+        PosixRlimitType::RlimitNofile => Resource::RLIMIT_NOFILE,
+        PosixRlimitType::RlimitMemlock => Resource::RLIMIT_MEMLOCK,
+        PosixRlimitType::RlimitAs => Resource::RLIMIT_AS,
+        PosixRlimitType::RlimitLocks => Resource::RLIMIT_LOCKS,
+        PosixRlimitType::RlimitSigpending => Resource::RLIMIT_SIGPENDING,
+        PosixRlimitType::RlimitMsgqueue => Resource::RLIMIT_MSGQUEUE,
+        PosixRlimitType::RlimitNice => Resource::RLIMIT_NICE,
+        PosixRlimitType::RlimitRtprio => Resource::RLIMIT_RTPRIO,
+        PosixRlimitType::RlimitRttime => Resource::RLIMIT_RTTIME,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+    use nix::sys::resource::getrlimit;
+    use oci_spec::runtime::{PosixRlimitBuilder, ProcessBuilder};
+
+    use super::*;
+
+    fn process(kind: PosixRlimitType, soft: u64, hard: u64) -> Result<Process> {
+        let rlimit = PosixRlimitBuilder::default()
+            // typos:disable-next-line - false positive "typ"
+            .typ(kind)
+            .soft(soft)
+            .hard(hard)
+            .build()?;
+        Ok(ProcessBuilder::default().rlimits(vec![rlimit]).build()?)
+    }
+
+    // The soft limit of `name` in `/proc/self/limits`, e.g. `Max core file size`
+    fn read_soft_limit(name: &str) -> Result<String> {
+        let limits = std::fs::read_to_string("/proc/self/limits")?;
+        let line = limits
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .with_context(|| format!("no {name:?} in /proc/self/limits"))?;
+        let soft = line.split_whitespace().next().context("no soft limit")?;
+        Ok(soft.to_string())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        check("test", &process(PosixRlimitType::RlimitNofile, 1024, 4096)?)?;
+
+        let invalid = process(PosixRlimitType::RlimitNofile, 4096, 1024)?;
+        let err = check("test", &invalid).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        assert!(err.to_string().contains("RLIMIT_NOFILE"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        // lowering the soft limit of the core dumps is harmless to the other tests
+        let (_, hard) = getrlimit(Resource::RLIMIT_CORE)?;
+        apply(&process(PosixRlimitType::RlimitCore, 0, hard)?)?;
+        assert_eq!(read_soft_limit("Max core file size")?, "0");
+
+        // the hard limit can't be raised above the one of the process, without privileges
+        if !nix::unistd::geteuid().is_root() {
+            let (_, hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+            let raised = process(PosixRlimitType::RlimitNofile, hard, hard.saturating_add(1))?;
+            let err = apply(&raised).unwrap_err();
+            assert!(err.to_string().contains("RLIMIT_NOFILE"), "{err}");
+        }
+
+        Ok(())
+    }
+}
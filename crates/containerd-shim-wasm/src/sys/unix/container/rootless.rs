@@ -0,0 +1,188 @@
+//! Rootless operation of the shim, e.g., with nerdctl rootless, where containerd and the shim
+//! run in a user namespace, or when the shim runs as an unprivileged user.
+//!
+//! youki creates rootless containers when their spec has a user namespace. In a user namespace,
+//! the shim is root and can create the other namespaces, but a shim running as an unprivileged
+//! user can't, so its instances get a user namespace mapping its user to root instead.
+//!
+//! Either way, the shim can only write to the cgroups that were delegated to its user. Without a
+//! writable cgroup, the instances can't be paused, and their OOM kills aren't reported on cgroup
+//! v1, where watching for them writes to the cgroup. Reading their stats only needs the cgroup
+//! to be readable.
+
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use nix::unistd::{AccessFlags, Gid, Uid, access, getegid, geteuid};
+use oci_spec::runtime::{LinuxIdMappingBuilder, LinuxNamespaceBuilder, LinuxNamespaceType, Spec};
+
+use crate::sys::cgroup::Cgroup;
+
+/// Whether the shim runs rootless: as an unprivileged user, or in a user namespace.
+pub(super) fn is_rootless() -> bool {
+    static ROOTLESS: OnceLock<bool> = OnceLock::new();
+
+    *ROOTLESS.get_or_init(|| {
+        let uid_map = read_to_string("/proc/self/uid_map").unwrap_or_default();
+        let rootless = !geteuid().is_root() || in_user_namespace(&uid_map);
+        if rootless {
+            log::info!(
+                "the shim runs rootless, the instances without a writable cgroup can't be paused, and their OOM kills aren't reported on cgroup v1"
+            );
+        }
+        rootless
+    })
+}
+
+// Whether the `uid_map` of the shim is the one of a user namespace, rather than the identity
+// mapping of the initial namespace
+fn in_user_namespace(uid_map: &str) -> bool {
+    let fields: Vec<_> = uid_map.split_whitespace().collect();
+    !fields.is_empty() && fields != ["0", "0", "4294967295"]
+}
+
+/// Shim side: adds a user namespace mapping the user of the shim to root to the `spec` of the
+/// container `id`, and to the `config.json` of its `bundle`, if the shim runs as an
+/// unprivileged user and the spec has no user namespace.
+pub(super) fn use_user_namespace(
+    id: &str,
+    bundle: &Path,
+    spec: &mut Spec,
+) -> Result<(), SandboxError> {
+    let uid = geteuid();
+    if uid.is_root() {
+        return Ok(());
+    }
+    if !add_user_namespace(spec, uid, getegid())? {
+        return Ok(());
+    }
+
+    log::info!("creating instance {id} in a user namespace mapping uid {uid} to root");
+    spec.save(bundle.join("config.json"))?;
+    Ok(())
+}
+
+// Adds a user namespace mapping `uid` and `gid` to root to `spec`, and returns whether it didn't
+// have one
+fn add_user_namespace(spec: &mut Spec, uid: Uid, gid: Gid) -> Result<bool, SandboxError> {
+    let Some(linux) = spec.linux_mut() else {
+        return Ok(false);
+    };
+    let mut namespaces = linux.namespaces().clone().unwrap_or_default();
+    let has_user = namespaces
+        .iter()
+        // typos:disable-next-line - false positive "typ"
+        .any(|ns| ns.typ() == LinuxNamespaceType::User);
+    if has_user {
+        return Ok(false);
+    }
+
+    namespaces.push(
+        LinuxNamespaceBuilder::default()
+            // typos:disable-next-line - false positive "typ"
+            .typ(LinuxNamespaceType::User)
+            .build()?,
+    );
+    let root = |id: u32| {
+        LinuxIdMappingBuilder::default()
+            .container_id(0u32)
+            .host_id(id)
+            .size(1u32)
+            .build()
+    };
+    linux.set_uid_mappings(Some(vec![root(uid.as_raw())?]));
+    linux.set_gid_mappings(Some(vec![root(gid.as_raw())?]));
+    linux.set_namespaces(Some(namespaces));
+    Ok(true)
+}
+
+/// Shim side: fails with a precondition error if the instance `id` needs to write to its
+/// `cgroup` to be `what`, e.g., `paused`, and the shim runs rootless without a writable one.
+pub(super) fn check_cgroup_writable(
+    id: &str,
+    cgroup: Option<&Cgroup>,
+    what: &str,
+) -> Result<(), SandboxError> {
+    if !is_rootless() {
+        return Ok(());
+    }
+    let dir = match cgroup {
+        Some(Cgroup::V2(path)) => Some(path),
+        Some(Cgroup::V1(controllers)) => controllers.get("freezer"),
+        // youki still finds out on its own
+        None => return Ok(()),
+    };
+    match dir {
+        Some(dir) if access(dir, AccessFlags::W_OK).is_ok() => Ok(()),
+        _ => Err(SandboxError::FailedPrecondition(format!(
+            "rootless instance {id} can't be {what} without a writable cgroup, delegate its cgroup to the user of the shim"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use oci_spec::runtime::{LinuxBuilder, SpecBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_in_user_namespace() {
+        assert!(!in_user_namespace("         0          0 4294967295\n"));
+        // `unshare -Ur`
+        assert!(in_user_namespace("         0       1000          1\n"));
+        // rootlesskit, with the subordinate ids of the user
+        assert!(in_user_namespace(
+            "         0       1000          1\n         1     100000      65536\n"
+        ));
+        // no procfs
+        assert!(!in_user_namespace(""));
+    }
+
+    #[test]
+    fn test_add_user_namespace() -> Result<()> {
+        let mount = LinuxNamespaceBuilder::default()
+            // typos:disable-next-line - false positive "typ"
+            .typ(LinuxNamespaceType::Mount)
+            .build()?;
+        let linux = LinuxBuilder::default().namespaces(vec![mount]).build()?;
+        let mut spec = SpecBuilder::default().linux(linux).build()?;
+
+        let (uid, gid) = (Uid::from_raw(1000), Gid::from_raw(100));
+        assert!(add_user_namespace(&mut spec, uid, gid)?);
+        let linux = spec.linux().as_ref().unwrap();
+        let namespaces = linux.namespaces().as_ref().unwrap();
+        assert_eq!(namespaces.len(), 2);
+        // typos:disable-next-line - false positive "typ"
+        assert_eq!(namespaces[1].typ(), LinuxNamespaceType::User);
+        let uid_mappings = linux.uid_mappings().as_ref().unwrap();
+        assert_eq!(uid_mappings[0].host_id(), 1000);
+        assert_eq!(uid_mappings[0].container_id(), 0);
+        assert_eq!(uid_mappings[0].size(), 1);
+        assert_eq!(linux.gid_mappings().as_ref().unwrap()[0].host_id(), 100);
+
+        // the user namespace of the spec is kept as is
+        assert!(!add_user_namespace(&mut spec, uid, gid)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cgroup_writable() -> Result<()> {
+        if !is_rootless() {
+            return Ok(());
+        }
+        let root = tempfile::tempdir()?;
+        let cgroup = Cgroup::V2(root.path().to_path_buf());
+        check_cgroup_writable("test", Some(&cgroup), "paused")?;
+
+        let cgroup = Cgroup::V2(root.path().join("missing"));
+        let err = check_cgroup_writable("test", Some(&cgroup), "paused").unwrap_err();
+        assert!(matches!(err, SandboxError::FailedPrecondition(_)), "{err}");
+
+        Ok(())
+    }
+}
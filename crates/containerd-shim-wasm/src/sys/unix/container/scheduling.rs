@@ -0,0 +1,195 @@
+//! OOM score and scheduling priority of the init process of the containers.
+//!
+//! Kubernetes sets the `process.oomScoreAdj` of the containers from their QoS class, so that the
+//! burstable pods are OOM-killed before the guaranteed ones. The shim sets it on the init process
+//! once it's created, before the executor runs the guest, as the init process can't lower its
+//! own score once it dropped its capabilities. So does the niceness of the
+//! [`NICE_ANNOTATION`], which the threads of the engine inherit.
+//!
+//! The CPU shares or weight of the container are applied to its cgroup by youki, and only
+//! checked against the ranges of the kernel here, so that an invalid one fails the creation of
+//! the container with its value.
+
+use std::ops::RangeInclusive;
+
+use anyhow::{Context as _, Result};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::Spec;
+
+/// Annotation with the niceness of the init process of the instance, from `-20`, the highest
+/// priority, to `19`.
+pub(super) const NICE_ANNOTATION: &str = "io.runwasi.nice";
+
+const OOM_SCORE_ADJ_RANGE: RangeInclusive<i32> = -1000..=1000;
+const NICE_RANGE: RangeInclusive<i32> = -20..=19;
+const CPU_SHARES_RANGE: RangeInclusive<u64> = 2..=262144;
+const CPU_WEIGHT_RANGE: RangeInclusive<u64> = 1..=10000;
+
+/// The OOM score and the scheduling priority of the init process of an instance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) struct Scheduling {
+    oom_score_adj: Option<i32>,
+    nice: Option<i32>,
+}
+
+impl Scheduling {
+    /// Shim side: returns the scheduling of the instance with runtime spec `spec`.
+    pub(super) fn from_spec(spec: &Spec) -> Result<Self, SandboxError> {
+        let oom_score_adj = spec.process().as_ref().and_then(|p| p.oom_score_adj());
+        if let Some(adj) = oom_score_adj.filter(|adj| !OOM_SCORE_ADJ_RANGE.contains(adj)) {
+            return Err(SandboxError::InvalidArgument(format!(
+                "invalid process.oomScoreAdj {adj}, it must be in {OOM_SCORE_ADJ_RANGE:?}"
+            )));
+        }
+
+        let nice = match spec
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(NICE_ANNOTATION))
+        {
+            None => None,
+            Some(value) => match value.trim().parse::<i32>() {
+                Ok(nice) if NICE_RANGE.contains(&nice) => Some(nice),
+                _ => {
+                    return Err(SandboxError::InvalidArgument(format!(
+                        "invalid {NICE_ANNOTATION} annotation: {value:?}"
+                    )));
+                }
+            },
+        };
+
+        check_cpu(spec)?;
+        Ok(Self {
+            oom_score_adj,
+            nice,
+        })
+    }
+
+    /// Shim side: sets the OOM score and the niceness of the init process `pid` of the
+    /// instance `id`.
+    pub(super) fn apply(&self, id: &str, pid: i32) -> Result<()> {
+        if let Some(adj) = self.oom_score_adj {
+            std::fs::write(format!("/proc/{pid}/oom_score_adj"), adj.to_string()).with_context(
+                || format!("failed to set the oom_score_adj of instance {id} to {adj}"),
+            )?;
+        }
+        if let Some(nice) = self.nice {
+            // SAFETY: setpriority only reads its arguments
+            let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, nice) };
+            if res != 0 {
+                return Err(std::io::Error::last_os_error()).with_context(|| {
+                    format!("failed to set the niceness of instance {id} to {nice}")
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_cpu(spec: &Spec) -> Result<(), SandboxError> {
+    let Some(resources) = spec.linux().as_ref().and_then(|l| l.resources().as_ref()) else {
+        return Ok(());
+    };
+    let shares = resources.cpu().as_ref().and_then(|cpu| cpu.shares());
+    if let Some(shares) = shares.filter(|shares| !CPU_SHARES_RANGE.contains(shares)) {
+        return Err(SandboxError::InvalidArgument(format!(
+            "invalid linux.resources.cpu.shares {shares}, it must be in {CPU_SHARES_RANGE:?}"
+        )));
+    }
+
+    let Some(weight) = resources
+        .unified()
+        .as_ref()
+        .and_then(|u| u.get("cpu.weight"))
+    else {
+        return Ok(());
+    };
+    match weight.trim().parse::<u64>() {
+        Ok(parsed) if CPU_WEIGHT_RANGE.contains(&parsed) => Ok(()),
+        _ => Err(SandboxError::InvalidArgument(format!(
+            "invalid cpu.weight {weight:?} in linux.resources.unified, it must be in {CPU_WEIGHT_RANGE:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use oci_spec::runtime::{
+        LinuxBuilder, LinuxCpuBuilder, LinuxResourcesBuilder, ProcessBuilder, SpecBuilder,
+    };
+
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with(oom_score_adj: Option<i32>, nice: Option<&str>) -> Result<Spec> {
+        let mut process = ProcessBuilder::default();
+        if let Some(adj) = oom_score_adj {
+            process = process.oom_score_adj(adj);
+        }
+        let nice = nice.map(|value| (NICE_ANNOTATION, value));
+        let mut spec = spec_with_annotations(nice.as_slice())?;
+        spec.set_process(Some(process.build()?));
+        Ok(spec)
+    }
+
+    #[test]
+    fn test_from_spec() -> Result<()> {
+        assert_eq!(
+            Scheduling::from_spec(&spec_with(None, None)?)?,
+            Scheduling::default()
+        );
+        assert_eq!(
+            Scheduling::from_spec(&spec_with(Some(-997), Some("10"))?)?,
+            Scheduling {
+                oom_score_adj: Some(-997),
+                nice: Some(10)
+            }
+        );
+        for spec in [
+            spec_with(Some(1001), None)?,
+            spec_with(None, Some("20"))?,
+            spec_with(None, Some("low"))?,
+        ] {
+            let err = Scheduling::from_spec(&spec).unwrap_err();
+            assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        }
+
+        let shares = |shares: u64| -> Result<Spec> {
+            let cpu = LinuxCpuBuilder::default().shares(shares).build()?;
+            let resources = LinuxResourcesBuilder::default().cpu(cpu).build()?;
+            let linux = LinuxBuilder::default().resources(resources).build()?;
+            Ok(SpecBuilder::default().linux(linux).build()?)
+        };
+        assert!(Scheduling::from_spec(&shares(1024)?).is_ok());
+        assert!(Scheduling::from_spec(&shares(1)?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let mut child = Command::new("sleep").arg("10").spawn()?;
+        let pid = child.id() as i32;
+        // raising them needs no privileges
+        let scheduling = Scheduling {
+            oom_score_adj: Some(500),
+            nice: Some(5),
+        };
+        let res = scheduling.apply("test", pid).and_then(|()| {
+            let adj = std::fs::read_to_string(format!("/proc/{pid}/oom_score_adj"))?;
+            // SAFETY: getpriority only reads its arguments
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+            Ok((adj, nice))
+        });
+        child.kill()?;
+        child.wait()?;
+
+        let (adj, nice) = res?;
+        assert_eq!(adj.trim(), "500");
+        assert_eq!(nice, 5);
+
+        Ok(())
+    }
+}
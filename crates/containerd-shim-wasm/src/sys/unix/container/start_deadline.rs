@@ -0,0 +1,186 @@
+//! Start deadline of wasm instances.
+//!
+//! A guest whose imports can't be resolved can hang the engine instantiating it, which would
+//! leave the container starting forever. `start` waits for the executor to report that the guest
+//! was instantiated, through a FIFO in the bundle that the executor inherits from the zygote.
+//! If it doesn't report it within the [`START_TIMEOUT_ANNOTATION`] of the container, or else the
+//! `instances.start_timeout` of the shim, the instance is killed and `start` fails with a timeout.
+//!
+//! Engines report the start of the guest with [`RuntimeContext::guest_started`] once they
+//! declare [`Shim::reports_guest_started`]. The guests of the other engines, and the linux
+//! containers, count as started when the executor runs them.
+//!
+//! [`RuntimeContext::guest_started`]: crate::sandbox::context::RuntimeContext::guest_started
+//! [`Shim::reports_guest_started`]: crate::shim::Shim::reports_guest_started
+
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{ErrorKind, Result as IoResult};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use oci_spec::runtime::Spec;
+use tokio::net::unix::pipe::{OpenOptions as PipeOptions, Receiver};
+
+use super::terminate::parse_duration;
+use crate::shim::InstancesConfig;
+
+/// Annotation with the time the guest of the instance is given to start, e.g., `60s`.
+/// Plain numbers are interpreted as seconds, and `0` disables the deadline.
+pub(super) const START_TIMEOUT_ANNOTATION: &str = "io.runwasi.start-timeout";
+
+const DEFAULT_START_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// The FIFO in the bundle of the instance
+const FIFO_NAME: &str = "started.fifo";
+
+/// Shim side: returns the start timeout of the instance with runtime spec `spec`, or `None` if
+/// it has no deadline. The instances without a [`START_TIMEOUT_ANNOTATION`] have the
+/// `start_timeout` of `config`, in the same format.
+pub(super) fn start_timeout(
+    spec: &Spec,
+    config: &InstancesConfig,
+) -> Result<Option<Duration>, SandboxError> {
+    let annotation = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(START_TIMEOUT_ANNOTATION));
+    let timeout = match annotation {
+        Some(value) => parse_duration(value).ok_or_else(|| {
+            SandboxError::InvalidArgument(format!(
+                "invalid {START_TIMEOUT_ANNOTATION} annotation: {value:?}"
+            ))
+        })?,
+        None => match &config.start_timeout {
+            Some(value) => parse_duration(value).ok_or_else(|| {
+                SandboxError::InvalidArgument(format!(
+                    "invalid `instances.start_timeout` {value:?}"
+                ))
+            })?,
+            None => DEFAULT_START_TIMEOUT,
+        },
+    };
+    Ok((!timeout.is_zero()).then_some(timeout))
+}
+
+/// Shim side: the FIFO the executor of an instance reports the start of its guest on.
+/// The FIFO is removed when it's dropped.
+pub(super) struct StartWatch {
+    path: PathBuf,
+    fifo: Receiver,
+}
+
+impl StartWatch {
+    /// Creates the FIFO in the `bundle` of the instance.
+    pub(super) fn create(bundle: &Path) -> IoResult<Self> {
+        let path = bundle.join(FIFO_NAME);
+        // left behind by a shim that didn't get to drop it
+        match remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        // opened for writing too, so that reading it doesn't see the EOF of a FIFO without
+        // writers before the executor opens it, or once it exits
+        let fifo = PipeOptions::new().read_write(true).open_receiver(&path)?;
+        Ok(Self { path, fifo })
+    }
+
+    /// The path of the FIFO, for the zygote to open with [`open`].
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the executor to report the start of the guest.
+    pub(super) async fn wait(&self) -> IoResult<()> {
+        let mut buf = [0; 1];
+        loop {
+            self.fifo.readable().await?;
+            match self.fifo.try_read(&mut buf) {
+                Ok(_) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for StartWatch {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Zygote side: opens the FIFO at `path` for the executor to write to. The file stays open in
+/// the init process the zygote forks, as libcontainer only closes the inherited files on exec.
+pub(super) fn open(path: &Path) -> IoResult<File> {
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with_start_timeout(value: Option<&str>) -> Result<Spec> {
+        let annotation = value.map(|value| (START_TIMEOUT_ANNOTATION, value));
+        spec_with_annotations(annotation.as_slice())
+    }
+
+    #[test]
+    fn test_start_timeout() -> Result<()> {
+        let config = InstancesConfig::default();
+        let timeout = |value: Option<&str>, config: &InstancesConfig| -> Result<Option<Duration>> {
+            Ok(start_timeout(&spec_with_start_timeout(value)?, config)?)
+        };
+        assert_eq!(timeout(None, &config)?, Some(DEFAULT_START_TIMEOUT));
+        assert_eq!(
+            timeout(Some("60s"), &config)?,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(timeout(Some("0"), &config)?, None);
+        assert!(timeout(Some("soon"), &config).is_err());
+
+        // the annotation wins over the default of the shim
+        let config = InstancesConfig {
+            start_timeout: Some("2m".into()),
+            ..Default::default()
+        };
+        assert_eq!(timeout(None, &config)?, Some(Duration::from_secs(120)));
+        assert_eq!(
+            timeout(Some("10s"), &config)?,
+            Some(Duration::from_secs(10))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_start_watch() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        let watch = StartWatch::create(bundle.path())?;
+        let path = watch.path().to_path_buf();
+
+        // nothing is reported before the executor writes to the FIFO, even once it closed it
+        drop(open(&path)?);
+        let waiting = tokio::time::timeout(Duration::from_millis(100), watch.wait()).await;
+        assert!(waiting.is_err());
+
+        open(&path)?.write_all(b"s")?;
+        tokio::time::timeout(Duration::from_secs(5), watch.wait()).await??;
+
+        drop(watch);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Timings of the startup of the instances, measured with the monotonic clock of the shim:
+//! * connecting to containerd, which only the first instance of a namespace waits for,
+//! * fetching the wasm layers or their precompiled artifacts from the content store,
+//! * precompiling the layers, if they weren't precompiled yet,
+//! * building the container, with its zygote,
+//! * and from the start of the process to the first output of the guest, which is only known
+//!   for guests that write to their stdout or stderr without a terminal.
+//!
+//! They're logged once the instance starts, and reported in its stats as a [`StartupTimings`]
+//! extension.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use containerd_shimkit::sandbox::Stats;
+use prost::Message;
+
+/// The startup timings of an instance, as reported in its stats.
+#[derive(Message, Clone, PartialEq)]
+pub(super) struct StartupTimings {
+    #[prost(double, tag = "1")]
+    pub(super) connect_seconds: f64,
+    #[prost(double, tag = "2")]
+    pub(super) fetch_seconds: f64,
+    #[prost(double, optional, tag = "3")]
+    pub(super) compile_seconds: Option<f64>,
+    #[prost(double, tag = "4")]
+    pub(super) build_seconds: f64,
+    #[prost(double, optional, tag = "5")]
+    pub(super) first_output_seconds: Option<f64>,
+}
+
+impl StartupTimings {
+    pub(super) const TYPE_URL: &str = "io.runwasi.v1.StartupTimings";
+}
+
+/// The durations of the phases of the creation of an instance.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Timings {
+    pub(super) connect: Duration,
+    pub(super) fetch: Duration,
+    pub(super) compile: Option<Duration>,
+    pub(super) build: Duration,
+}
+
+/// The startup of an instance, from its creation to the first output of its guest.
+#[derive(Debug, Default)]
+pub(super) struct Startup {
+    // unknown for recovered instances
+    timings: Option<Timings>,
+    started: OnceLock<Instant>,
+}
+
+impl Startup {
+    pub(super) fn new(timings: Timings) -> Self {
+        Self {
+            timings: Some(timings),
+            started: OnceLock::new(),
+        }
+    }
+
+    /// Records the start of the process of the instance `id`, and logs its timings.
+    pub(super) fn started(&self, id: &str) {
+        let _ = self.started.set(Instant::now());
+        let Some(timings) = &self.timings else {
+            return;
+        };
+        log::info!(
+            instance = id,
+            connect_seconds = timings.connect.as_secs_f64(),
+            fetch_seconds = timings.fetch.as_secs_f64(),
+            compile_seconds = timings.compile.map(|compile| compile.as_secs_f64()),
+            build_seconds = timings.build.as_secs_f64();
+            "instance {id} started"
+        );
+    }
+
+    /// The timings of the instance, whose guest first wrote its output at `first_output`.
+    pub(super) fn timings(&self, first_output: Option<Instant>) -> Option<StartupTimings> {
+        let timings = self.timings?;
+        let first_output = match (self.started.get(), first_output) {
+            (Some(started), Some(first_output)) => {
+                Some(first_output.saturating_duration_since(*started))
+            }
+            _ => None,
+        };
+        Some(StartupTimings {
+            connect_seconds: timings.connect.as_secs_f64(),
+            fetch_seconds: timings.fetch.as_secs_f64(),
+            compile_seconds: timings.compile.map(|compile| compile.as_secs_f64()),
+            build_seconds: timings.build.as_secs_f64(),
+            first_output_seconds: first_output.map(|first_output| first_output.as_secs_f64()),
+        })
+    }
+
+    /// Adds the timings of the instance to its `stats`.
+    pub(super) fn add_to(&self, stats: &mut Stats, first_output: Option<Instant>) {
+        if let Some(timings) = self.timings(first_output) {
+            stats.add_extension(StartupTimings::TYPE_URL, timings.encode_to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shimkit::sandbox::stats::v2;
+
+    use super::*;
+
+    #[test]
+    fn test_startup_timings() -> anyhow::Result<()> {
+        let timings = Timings {
+            connect: Duration::from_millis(10),
+            fetch: Duration::from_millis(20),
+            compile: None,
+            build: Duration::from_millis(30),
+        };
+        let startup = Startup::new(timings);
+
+        // the first output is only known once the instance started
+        let before = Instant::now();
+        let reported = startup.timings(Some(before)).unwrap();
+        assert_eq!(reported.connect_seconds, 0.01);
+        assert_eq!(reported.compile_seconds, None);
+        assert_eq!(reported.build_seconds, 0.03);
+        assert_eq!(reported.first_output_seconds, None);
+
+        startup.started("test");
+        let first_output = Instant::now() + Duration::from_millis(40);
+        let reported = startup.timings(Some(first_output)).unwrap();
+        assert!(reported.first_output_seconds.unwrap() >= 0.04);
+        assert_eq!(startup.timings(None).unwrap().first_output_seconds, None);
+
+        // the timings of recovered instances aren't known
+        assert_eq!(Startup::default().timings(Some(first_output)), None);
+
+        let mut stats = Stats::from(v2::Metrics::default());
+        startup.add_to(&mut stats, None);
+        let Stats::V2(metrics) = stats else {
+            unreachable!();
+        };
+        let [extension] = &metrics.extensions[..] else {
+            panic!("unexpected extensions: {:?}", metrics.extensions);
+        };
+        assert_eq!(extension.type_url, StartupTimings::TYPE_URL);
+        let decoded = StartupTimings::decode(extension.value.as_slice())?;
+        assert_eq!(decoded.fetch_seconds, 0.02);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Graceful termination of wasm instances.
+//!
+//! When the init process receives `SIGTERM`, the executor asks the engine to interrupt the
+//! guest with [`Sandbox::terminate`], so that it gets a chance to flush its state and exit.
+//! The shim escalates to `SIGKILL` if the instance is still running after the grace period
+//! set with the [`STOP_GRACE_PERIOD_ANNOTATION`] annotation.
+//!
+//! The ports bound for the guest that it isn't listening on yet are closed first, so that
+//! the clients get refused rather than queued during the grace period.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use containerd_shimkit::sandbox::Error as SandboxError;
+use oci_spec::runtime::Spec;
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::sandbox::Sandbox;
+use crate::sandbox::context::RuntimeContext;
+
+/// Annotation with the time the instance is given to stop after `SIGTERM`, e.g., `30s`.
+/// Plain numbers are interpreted as seconds.
+pub(super) const STOP_GRACE_PERIOD_ANNOTATION: &str = "io.runwasi.stop-grace-period";
+
+const DEFAULT_STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Exit code of a process terminated by `SIGTERM`
+pub(super) const SIGTERM_EXIT_CODE: i32 = 128 + libc::SIGTERM;
+
+/// Shim side: returns the grace period of the instance with runtime spec `spec`.
+pub(super) fn grace_period(spec: &Spec) -> Result<Duration, SandboxError> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(STOP_GRACE_PERIOD_ANNOTATION))
+    else {
+        return Ok(DEFAULT_STOP_GRACE_PERIOD);
+    };
+
+    parse_duration(value).ok_or_else(|| {
+        SandboxError::InvalidArgument(format!(
+            "invalid {STOP_GRACE_PERIOD_ANNOTATION} annotation: {value:?}"
+        ))
+    })
+}
+
+/// Executor side: handle `SIGTERM` by asking the engine to interrupt the guest.
+/// `terminating` is set once the engine accepted the request, so that the executor can tell
+/// an interrupted guest from a failure.
+/// Resolves to the exit code of the process if it should exit right away, i.e., if the
+/// engine doesn't support graceful termination, or on a second `SIGTERM`.
+pub(super) async fn serve(
+    sandbox: &impl Sandbox,
+    ctx: &impl RuntimeContext,
+    terminating: &AtomicBool,
+) -> Result<i32> {
+    let mut signals = signal(SignalKind::terminate())?;
+
+    if signals.recv().await.is_none() {
+        bail!("termination signal stream closed");
+    }
+
+    log::info!("terminating instance");
+    // no new connections while the guest stops
+    ctx.listeners().close();
+    if let Err(err) = sandbox.terminate(ctx).await {
+        log::info!("exiting without graceful termination: {err}");
+        return Ok(SIGTERM_EXIT_CODE);
+    }
+    terminating.store(true, Ordering::SeqCst);
+
+    // On a second SIGTERM, exit without waiting for the guest
+    if signals.recv().await.is_none() {
+        bail!("termination signal stream closed");
+    }
+    Ok(SIGTERM_EXIT_CODE)
+}
+
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(amount)),
+        "" | "s" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_secs(amount.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::spec_with_annotations;
+
+    fn spec_with_grace_period(value: Option<&str>) -> Result<Spec> {
+        let annotation = value.map(|value| (STOP_GRACE_PERIOD_ANNOTATION, value));
+        spec_with_annotations(annotation.as_slice())
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration(" 0s "), Some(Duration::ZERO));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("-1s"), None);
+        assert_eq!(parse_duration("1.5s"), None);
+        assert_eq!(parse_duration("1h"), None);
+    }
+
+    #[test]
+    fn test_grace_period() -> Result<()> {
+        let spec = spec_with_grace_period(None)?;
+        assert_eq!(grace_period(&spec)?, DEFAULT_STOP_GRACE_PERIOD);
+
+        let spec = spec_with_grace_period(Some("3s"))?;
+        assert_eq!(grace_period(&spec)?, Duration::from_secs(3));
+
+        let spec = spec_with_grace_period(Some("soon"))?;
+        let err = grace_period(&spec).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)));
+
+        Ok(())
+    }
+}
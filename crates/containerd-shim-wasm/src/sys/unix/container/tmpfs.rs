@@ -0,0 +1,187 @@
+//! tmpfs mounts of the containers, e.g., the `emptyDir` volumes of Kubernetes with
+//! `medium: Memory`.
+//!
+//! youki mounts them in the mount namespace of the container, with their `size` and other
+//! options, and the executor preopens them for the guest like the bind mounts. Their `size` is
+//! checked first, so that an invalid one fails the creation of the container with its value
+//! rather than with a bare `EINVAL`.
+//!
+//! A tmpfs goes away with the mount namespace of the container, unless the propagation of the
+//! rootfs, or of the mount, is shared, which propagates it to the rootfs on the host too. The
+//! shim unmounts those ones itself when the instance is deleted.
+
+use std::path::{Path, PathBuf};
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, umount2};
+use oci_spec::runtime::{Mount, Spec};
+
+const SHARED_PROPAGATIONS: [&str; 2] = ["shared", "rshared"];
+
+/// The tmpfs mounts of an instance that propagate to the host.
+#[derive(Debug, Default)]
+pub(super) struct TmpfsMounts {
+    paths: Vec<PathBuf>,
+}
+
+impl TmpfsMounts {
+    /// Shim side: checks the tmpfs mounts of the `spec` of the container `id`, and returns the
+    /// ones that propagate to its rootfs in its `bundle`.
+    pub(super) fn from_spec(id: &str, bundle: &Path, spec: &Spec) -> Result<Self, SandboxError> {
+        let mounts: Vec<&Mount> = spec
+            .mounts()
+            .iter()
+            .flatten()
+            .filter(|mount| is_tmpfs(mount))
+            .collect();
+        for mount in &mounts {
+            check_size(id, mount)?;
+        }
+
+        let rootfs_shared = spec
+            .linux()
+            .as_ref()
+            .and_then(|linux| linux.rootfs_propagation().as_deref())
+            .is_some_and(|propagation| SHARED_PROPAGATIONS.contains(&propagation));
+        let Some(root) = spec.root() else {
+            return Ok(Self::default());
+        };
+        let rootfs = bundle.join(root.path());
+        let paths = mounts
+            .into_iter()
+            .filter(|mount| rootfs_shared || is_shared(mount))
+            .map(|mount| {
+                let destination = mount.destination();
+                rootfs.join(destination.strip_prefix("/").unwrap_or(destination))
+            })
+            .collect();
+        Ok(Self { paths })
+    }
+
+    /// Shim side: unmounts the tmpfs mounts of the instance `id` from its rootfs on the host,
+    /// innermost first.
+    pub(super) fn unmount(&self, id: &str) {
+        for path in self.paths.iter().rev() {
+            match umount2(path, MntFlags::MNT_DETACH) {
+                // not mounted, or already unmounted
+                Ok(()) | Err(Errno::EINVAL | Errno::ENOENT) => {}
+                Err(err) => {
+                    log::warn!("failed to unmount the tmpfs {path:?} of instance {id}: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether `mount` is a tmpfs mount.
+pub(super) fn is_tmpfs(mount: &Mount) -> bool {
+    // typos:disable-next-line - false positive "typ"
+    mount.typ().as_deref() == Some("tmpfs")
+}
+
+fn is_shared(mount: &Mount) -> bool {
+    let options = mount.options().as_deref().unwrap_or_default();
+    options
+        .iter()
+        .any(|option| SHARED_PROPAGATIONS.contains(&option.as_str()))
+}
+
+fn check_size(id: &str, mount: &Mount) -> Result<(), SandboxError> {
+    let options = mount.options().as_deref().unwrap_or_default();
+    let Some(size) = options
+        .iter()
+        .find_map(|option| option.strip_prefix("size="))
+    else {
+        return Ok(());
+    };
+    if is_valid_size(size) {
+        return Ok(());
+    }
+    Err(SandboxError::InvalidArgument(format!(
+        "invalid size {size:?} of the tmpfs mount at {:?} of instance {id}",
+        mount.destination()
+    )))
+}
+
+// The sizes tmpfs takes: a number of bytes with an optional `k`, `m`, `g`, `t`, `p` or `e`
+// suffix, or a percentage of the memory
+fn is_valid_size(size: &str) -> bool {
+    let digits = size.trim_end_matches([
+        'k', 'K', 'm', 'M', 'g', 'G', 't', 'T', 'p', 'P', 'e', 'E', '%',
+    ]);
+    let suffix = &size[digits.len()..];
+    suffix.len() <= 1 && !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use oci_spec::runtime::{LinuxBuilder, MountBuilder, RootBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn tmpfs(destination: &str, options: &[&str]) -> Result<Mount> {
+        Ok(MountBuilder::default()
+            .destination(destination)
+            // typos:disable-next-line - false positive "typ"
+            .typ("tmpfs")
+            .source("tmpfs")
+            .options(options.iter().map(|o| o.to_string()).collect::<Vec<_>>())
+            .build()?)
+    }
+
+    #[test]
+    fn test_is_valid_size() {
+        for size in ["65536", "64k", "64M", "1g", "50%"] {
+            assert!(is_valid_size(size), "{size}");
+        }
+        for size in ["", "M", "64Mi", "1.5g", "-1", "64 M"] {
+            assert!(!is_valid_size(size), "{size}");
+        }
+    }
+
+    #[test]
+    fn test_from_spec() -> Result<()> {
+        let bundle = Path::new("/run/bundle");
+        let spec = |mounts: Vec<Mount>, propagation: Option<&str>| -> Result<Spec> {
+            let mut linux = LinuxBuilder::default();
+            if let Some(propagation) = propagation {
+                linux = linux.rootfs_propagation(propagation);
+            }
+            Ok(SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .mounts(mounts)
+                .linux(linux.build()?)
+                .build()?)
+        };
+        let mounts = vec![
+            tmpfs("/cache", &["size=64m"])?,
+            tmpfs("/scratch", &["rshared"])?,
+        ];
+
+        let paths = |spec: Spec| -> Result<Vec<PathBuf>> {
+            Ok(TmpfsMounts::from_spec("test", bundle, &spec)?.paths)
+        };
+
+        // the namespace of the container takes the private ones away
+        let private = spec(mounts.clone(), None)?;
+        assert_eq!(paths(private)?, [bundle.join("rootfs/scratch")]);
+
+        let shared = spec(mounts, Some("rshared"))?;
+        assert_eq!(
+            paths(shared)?,
+            [bundle.join("rootfs/cache"), bundle.join("rootfs/scratch")]
+        );
+
+        let invalid = spec(vec![tmpfs("/cache", &["size=64Mi"])?], None)?;
+        let err = TmpfsMounts::from_spec("test", bundle, &invalid).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        assert!(err.to_string().contains("64Mi"), "{err}");
+
+        // there's nothing to unmount
+        TmpfsMounts::from_spec("test", bundle, &spec(vec![], None)?)?.unmount("test");
+
+        Ok(())
+    }
+}
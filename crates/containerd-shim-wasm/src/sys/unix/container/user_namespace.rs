@@ -0,0 +1,208 @@
+//! User namespaces of the containers with `linux.uidMappings` and `linux.gidMappings`, e.g., the
+//! pods of Kubernetes with `hostUsers: false`.
+//!
+//! youki creates the user namespace of the spec with its mappings, or joins the one at its path.
+//! The shim opens the bundle and the stdio of the instance before its init process enters the
+//! namespace, so only the rootfs and the mounts are accessed with the mapped ids:
+//! * A rootfs that isn't owned by a mapped id, e.g., when the snapshotter of containerd doesn't
+//!   remap the snapshots, is chowned to the mapped ids before the container is created.
+//! * The mounts with the `idmap` or `ridmap` option are idmapped mounts, which youki fails to
+//!   create with a bare `EPERM` when the kernel doesn't support them, so their support is
+//!   checked first.
+
+use std::io::Result as IoResult;
+use std::os::unix::fs::{MetadataExt as _, lchown};
+use std::path::Path;
+
+use containerd_shimkit::sandbox::Error as SandboxError;
+use nix::errno::Errno;
+use oci_spec::runtime::{LinuxIdMapping, LinuxNamespaceType, Spec};
+
+/// Shim side: checks the user namespace of the container `id` with runtime spec `spec`, and
+/// remaps the ownership of the rootfs in its `bundle` if the user namespace needs it.
+pub(super) fn prepare(id: &str, bundle: &Path, spec: &Spec) -> Result<(), SandboxError> {
+    let Some(linux) = spec.linux() else {
+        return Ok(());
+    };
+    let uid_mappings = linux.uid_mappings().as_deref().unwrap_or_default();
+    let gid_mappings = linux.gid_mappings().as_deref().unwrap_or_default();
+    let has_user_namespace = linux
+        .namespaces()
+        .iter()
+        .flatten()
+        // typos:disable-next-line - false positive "typ"
+        .any(|ns| ns.typ() == LinuxNamespaceType::User);
+
+    if !has_user_namespace {
+        if uid_mappings.is_empty() && gid_mappings.is_empty() {
+            return Ok(());
+        }
+        return Err(SandboxError::InvalidArgument(format!(
+            "the uid and gid mappings of instance {id} need a user namespace"
+        )));
+    }
+
+    let idmapped = spec.mounts().iter().flatten().find(|mount| {
+        mount
+            .options()
+            .iter()
+            .flatten()
+            .any(|option| option == "idmap" || option == "ridmap")
+    });
+    if let Some(mount) = idmapped.filter(|_| !idmapped_mounts_supported()) {
+        return Err(SandboxError::FailedPrecondition(format!(
+            "mount {:?} of instance {id} is an idmapped mount, which needs linux 5.12 or later",
+            mount.destination()
+        )));
+    }
+
+    let Some(root) = spec.root() else {
+        return Ok(());
+    };
+    let rootfs = bundle.join(root.path());
+    let owner = rootfs.metadata()?.uid();
+    // already remapped by containerd, or the mappings don't map anything
+    if uid_mappings.is_empty() || is_mapped_host_id(owner, uid_mappings) {
+        return Ok(());
+    }
+
+    log::info!("remapping the ownership of the rootfs of instance {id} to its user namespace");
+    let dev = rootfs.metadata()?.dev();
+    remap(&rootfs, dev, uid_mappings, gid_mappings).map_err(|err| {
+        SandboxError::Others(format!(
+            "failed to remap the ownership of the rootfs {rootfs:?} of instance {id}: {err}"
+        ))
+    })
+}
+
+// Chowns `path` and the files under it on the device `dev` from their ids in the container to
+// their ids on the host
+fn remap(
+    path: &Path,
+    dev: u64,
+    uid_mappings: &[LinuxIdMapping],
+    gid_mappings: &[LinuxIdMapping],
+) -> IoResult<()> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.dev() != dev {
+        return Ok(());
+    }
+    let uid = to_host_id(metadata.uid(), uid_mappings);
+    let gid = to_host_id(metadata.gid(), gid_mappings);
+    lchown(path, uid, gid)?;
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            remap(&entry?.path(), dev, uid_mappings, gid_mappings)?;
+        }
+    }
+    Ok(())
+}
+
+// The host id of the container id `id`, or `None` if it isn't mapped
+pub(super) fn to_host_id(id: u32, mappings: &[LinuxIdMapping]) -> Option<u32> {
+    mappings.iter().find_map(|mapping| {
+        let offset = id.checked_sub(mapping.container_id())?;
+        if offset >= mapping.size() {
+            return None;
+        }
+        mapping.host_id().checked_add(offset)
+    })
+}
+
+fn is_mapped_host_id(id: u32, mappings: &[LinuxIdMapping]) -> bool {
+    mappings.iter().any(|mapping| {
+        id.checked_sub(mapping.host_id())
+            .is_some_and(|offset| offset < mapping.size())
+    })
+}
+
+// mount_setattr(2) came with the idmapped mounts in linux 5.12, so an invalid call fails with
+// another error than `ENOSYS` where they're supported
+fn idmapped_mounts_supported() -> bool {
+    // SAFETY: the call doesn't change anything, as the fd is invalid
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            -1,
+            c"".as_ptr(),
+            0,
+            std::ptr::null_mut::<libc::c_void>(),
+            0,
+        )
+    };
+    !(res == -1 && Errno::last() == Errno::ENOSYS)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use oci_spec::runtime::{
+        LinuxBuilder, LinuxIdMappingBuilder, LinuxNamespace, LinuxNamespaceBuilder, RootBuilder,
+        SpecBuilder,
+    };
+
+    use super::*;
+
+    fn mapping(container_id: u32, host_id: u32, size: u32) -> Result<LinuxIdMapping> {
+        Ok(LinuxIdMappingBuilder::default()
+            .container_id(container_id)
+            .host_id(host_id)
+            .size(size)
+            .build()?)
+    }
+
+    #[test]
+    fn test_to_host_id() -> Result<()> {
+        let mappings = [mapping(0, 100000, 1000)?, mapping(1000, 5000, 1)?];
+        assert_eq!(to_host_id(0, &mappings), Some(100000));
+        assert_eq!(to_host_id(999, &mappings), Some(100999));
+        assert_eq!(to_host_id(1000, &mappings), Some(5000));
+        assert_eq!(to_host_id(1001, &mappings), None);
+
+        assert!(is_mapped_host_id(100000, &mappings));
+        assert!(is_mapped_host_id(5000, &mappings));
+        assert!(!is_mapped_host_id(0, &mappings));
+        assert!(!is_mapped_host_id(101000, &mappings));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare() -> Result<()> {
+        let bundle = tempfile::tempdir()?;
+        std::fs::create_dir(bundle.path().join("rootfs"))?;
+        let owner = bundle.path().join("rootfs").metadata()?.uid();
+        let spec =
+            |namespaces: Vec<LinuxNamespace>, mappings: Vec<LinuxIdMapping>| -> Result<Spec> {
+                let linux = LinuxBuilder::default()
+                    .namespaces(namespaces)
+                    .uid_mappings(mappings)
+                    .build()?;
+                Ok(SpecBuilder::default()
+                    .root(RootBuilder::default().path("rootfs").build()?)
+                    .linux(linux)
+                    .build()?)
+            };
+        let user = LinuxNamespaceBuilder::default()
+            // typos:disable-next-line - false positive "typ"
+            .typ(LinuxNamespaceType::User)
+            .build()?;
+
+        // the mappings aren't ignored without a user namespace
+        let err = prepare(
+            "test",
+            bundle.path(),
+            &spec(vec![], vec![mapping(0, 1, 1)?])?,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+
+        // the rootfs is already owned by a mapped id
+        let mapped = spec(vec![user], vec![mapping(0, owner, 1)?])?;
+        prepare("test", bundle.path(), &mapped)?;
+        assert_eq!(bundle.path().join("rootfs").metadata()?.uid(), owner);
+
+        Ok(())
+    }
+}
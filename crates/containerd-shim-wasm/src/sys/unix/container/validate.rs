@@ -0,0 +1,104 @@
+//! Validation of the guests of the containers with the [`VALIDATE_ONLY_ANNOTATION`].
+//!
+//! The executor sets the container up as it would to run it, then asks the engine to validate
+//! the guest with [`Sandbox::validate`] instead of running it. The container writes the result
+//! as a line of JSON on its stdout, e.g.,
+//! `{"valid":false,"engine":"wasmtime","entrypoint":"_start","world":"wasi_snapshot_preview1","missing_imports":["env::now"],"errors":[]}`,
+//! and exits with [`VALID_EXIT_CODE`] or [`INVALID_EXIT_CODE`].
+//!
+//! [`VALIDATE_ONLY_ANNOTATION`]: crate::sandbox::context::VALIDATE_ONLY_ANNOTATION
+
+use std::io::Write as _;
+
+use containerd_shimkit::AmbientRuntime;
+use serde::Serialize;
+
+use crate::sandbox::context::RuntimeContext;
+use crate::sandbox::{Sandbox, Validation};
+use crate::shim::Shim;
+
+/// Exit code of a container whose guest could run.
+pub(super) const VALID_EXIT_CODE: i32 = 0;
+
+/// Exit code of a container whose guest can't run, or couldn't be validated.
+pub(super) const INVALID_EXIT_CODE: i32 = 1;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    valid: bool,
+    engine: &'a str,
+    entrypoint: &'a str,
+    #[serde(flatten)]
+    validation: Validation,
+}
+
+/// Validates the guest of the `container` and writes the report on the stdout of the container,
+/// returning the exit code of the container.
+pub(super) fn run<S: Shim>(container: &S::Sandbox, ctx: &impl RuntimeContext) -> i32 {
+    let validation = container.validate(ctx).block_on().unwrap_or_else(|err| {
+        log::info!("failed to validate the guest: {err:#}");
+        Validation {
+            errors: vec![format!("{err:#}")],
+            ..Default::default()
+        }
+    });
+    let report = Report {
+        valid: validation.is_valid(),
+        engine: S::name(),
+        entrypoint: &ctx.entrypoint().func,
+        validation,
+    };
+    log::info!(
+        "validated the guest, valid: {}, missing imports: {:?}, errors: {:?}",
+        report.valid,
+        report.validation.missing_imports,
+        report.validation.errors
+    );
+
+    // the process exits without flushing stdout
+    let mut stdout = std::io::stdout().lock();
+    if let Err(err) = serde_json::to_writer(&mut stdout, &report)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(stdout))
+        .and_then(|()| stdout.flush())
+    {
+        log::warn!("failed to write the validation report: {err}");
+    }
+
+    if report.valid {
+        VALID_EXIT_CODE
+    } else {
+        INVALID_EXIT_CODE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() -> anyhow::Result<()> {
+        let report = Report {
+            valid: false,
+            engine: "wasmtime",
+            entrypoint: "_start",
+            validation: Validation {
+                world: Some("wasi_snapshot_preview1".to_string()),
+                missing_imports: vec!["env::now".to_string(), "env::sleep".to_string()],
+                errors: vec![],
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&report)?,
+            serde_json::json!({
+                "valid": false,
+                "engine": "wasmtime",
+                "entrypoint": "_start",
+                "world": "wasi_snapshot_preview1",
+                "missing_imports": ["env::now", "env::sleep"],
+                "errors": [],
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Debug dumps of the state of the shim.
+//!
+//! When `instances.debug_dump` is set, the shim writes a JSON snapshot of its instances, of the
+//! layer cache and of the compilations of the layers to the path in it on `SIGUSR1`, where
+//! `{pid}` is replaced by the pid of the shim, e.g., with `/run/runwasi/debug-{pid}.json`:
+//!
+//! ```sh
+//! kill -USR1 <pid> && cat /run/runwasi/debug-<pid>.json
+//! ```
+//!
+//! The snapshot is copied out of the registries, and serialized and written on the thread of
+//! the dumps, so that dumping never holds the locks the instances take.
+//! Like the metrics, the dumps start with the first instance of the shim.
+
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::signal::unix::{SignalKind, signal};
+
+use super::metrics::{self, InstanceSnapshot};
+use crate::containerd::{COMPILATIONS, CompilationsSnapshot, LAYER_CACHE, LayerCacheSnapshot};
+use crate::shim::ShimConfig;
+
+static DUMPS: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Whether the debug dumps are enabled, starting to listen for `SIGUSR1` if they're not
+/// started yet.
+pub(super) fn enabled() -> bool {
+    DUMPS.get_or_init(start).is_some()
+}
+
+fn start() -> Option<PathBuf> {
+    let value = ShimConfig::get().instances.debug_dump.as_ref()?;
+    if value.is_empty() {
+        return None;
+    }
+    let path = PathBuf::from(value.replace("{pid}", &std::process::id().to_string()));
+    match listen(path.clone()) {
+        Ok(()) => {
+            log::info!("writing debug dumps to {path:?} on SIGUSR1");
+            Some(path)
+        }
+        Err(err) => {
+            log::warn!("failed to listen for SIGUSR1, debug dumps are disabled: {err}");
+            None
+        }
+    }
+}
+
+// Writes a dump to `path` on every `SIGUSR1`, on a thread of its own. The signal is handled
+// before this returns, so that it never terminates the shim from then on.
+fn listen(path: PathBuf) -> IoResult<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let mut signals = {
+        let _guard = runtime.enter();
+        signal(SignalKind::user_defined1())?
+    };
+    std::thread::Builder::new()
+        .name("debug-dumps".to_string())
+        .spawn(move || {
+            runtime.block_on(async {
+                while signals.recv().await.is_some() {
+                    match write(&path, &Dump::take()) {
+                        Ok(()) => log::info!("wrote a debug dump to {path:?}"),
+                        Err(err) => log::warn!("failed to write a debug dump to {path:?}: {err}"),
+                    }
+                }
+            })
+        })?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Dump {
+    pid: u32,
+    // seconds since the epoch
+    time: f64,
+    instances: Vec<InstanceSnapshot>,
+    layer_cache: LayerCacheSnapshot,
+    compilations: CompilationsSnapshot,
+}
+
+impl Dump {
+    fn take() -> Self {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            pid: std::process::id(),
+            time: time.as_secs_f64(),
+            instances: metrics::snapshot(),
+            layer_cache: LAYER_CACHE.snapshot(),
+            compilations: COMPILATIONS.snapshot(),
+        }
+    }
+}
+
+// Writes `dump` to `path`, atomically so that readers never see a partial dump
+fn write(path: &Path, dump: &Dump) -> IoResult<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(dump)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_dump() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("debug.json");
+
+        write(&path, &Dump::take())?;
+
+        let dump: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        assert_eq!(dump["pid"], std::process::id());
+        assert!(dump["instances"].is_array());
+        assert!(dump["layer_cache"]["layers"].is_array());
+        assert!(dump["compilations"]["max"].as_u64() >= Some(1));
+        assert!(!path.with_extension("tmp").exists());
+
+        Ok(())
+    }
+}
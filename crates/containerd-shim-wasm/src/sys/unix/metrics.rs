@@ -0,0 +1,756 @@
+//! Prometheus metrics of the instances of the shim.
+//!
+//! The metrics are served in the Prometheus text format on `GET /metrics`, from the address in
+//! `instances.metrics_address`, either `unix:<path>` for a unix socket, where `{pid}` is replaced
+//! by the pid of the shim so that the shims of a node don't collide, or a loopback
+//! `<ip>:<port>` (or `localhost:<port>`). Without it, nothing is served, and nothing is
+//! recorded unless the instances are dumped for debugging, see [`super::debug`].
+//!
+//! The server starts with the first instance of the shim, so that the processes the shim
+//! spawns for the other commands of containerd don't bind the address, and runs on its own
+//! thread until the shim exits. It also samples the memory and CPU usage of the cgroups of
+//! the running instances every [`SAMPLE_INTERVAL`].
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as _};
+use std::io::{ErrorKind, Result as IoResult};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::oneshot;
+
+use super::debug;
+use crate::containerd::LAYER_CACHE;
+use crate::shim::{EngineVersion, ShimConfig};
+use crate::sys::cgroup::Cgroup;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Limits of the requests, which are only expected from a scraper on the same node
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+static SERVER: OnceLock<Option<Server>> = OnceLock::new();
+
+/// Whether the metrics are recorded, starting the server and the debug dumps if they're not
+/// started yet.
+pub(super) fn enabled() -> bool {
+    let served = SERVER.get_or_init(Server::from_config).is_some();
+    // the dumps read the instances from the registry too
+    let dumped = debug::enabled();
+    served || dumped
+}
+
+/// Records the creation of the instance `id` of the pod `pod_id`, until the returned
+/// registration is dropped, unless the instance is created and [`Registration::keep`] is called.
+pub(super) fn register(id: &str, pod_id: Option<&str>) -> Registration {
+    if !enabled() {
+        return Registration { id: None };
+    }
+    let instance = InstanceMetrics::new(pod_id);
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.instances.insert(id.to_string(), instance);
+    Registration {
+        id: Some(id.to_string()),
+    }
+}
+
+pub(super) struct Registration {
+    // the instance to forget on drop, unless it was created
+    id: Option<String>,
+}
+
+impl Registration {
+    pub(super) fn keep(mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            deleted(id);
+        }
+    }
+}
+
+/// Records the `engine` and the version of the shim, for the labels of `runwasi_build_info`.
+pub(super) fn engine(engine: impl FnOnce() -> EngineVersion, shim_version: &str) {
+    if !enabled() {
+        return;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    if registry.engine.is_none() {
+        registry.engine = Some((engine(), shim_version.to_string()));
+    }
+}
+
+/// Records the `image` of the instance `id`.
+pub(super) fn image(id: &str, image: &str) {
+    with_instance(id, |instance| instance.image = Some(image.to_string()));
+}
+
+/// Records the time it took to precompile the wasm layers of the instance `id`.
+pub(super) fn compiled(id: &str, elapsed: Duration) {
+    with_instance(id, |instance| instance.compile = Some(elapsed));
+}
+
+/// Records the start of the process of the instance `id`, which runs in `cgroup`.
+pub(super) fn started(id: &str, cgroup: Option<Cgroup>) {
+    with_instance(id, |instance| {
+        instance.running = true;
+        instance.startup = Some(instance.created.elapsed());
+        instance.cgroup = cgroup;
+    });
+}
+
+/// Records the instance `id` recovered by the shim, whose startup latency isn't known.
+pub(super) fn recovered(id: &str, pod_id: Option<&str>, cgroup: Option<Cgroup>) {
+    if !enabled() {
+        return;
+    }
+    let mut instance = InstanceMetrics::new(pod_id);
+    instance.running = true;
+    instance.cgroup = cgroup;
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.instances.insert(id.to_string(), instance);
+}
+
+/// Records the exit of the process of the instance `id` with `status`.
+pub(super) fn exited(id: &str, status: u32) {
+    if !enabled() {
+        return;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.exits.entry(exit_class(status)).or_default() += 1;
+    if let Some(instance) = registry.instances.get_mut(id) {
+        instance.running = false;
+    }
+}
+
+/// Forgets the instance `id`.
+pub(super) fn deleted(id: &str) {
+    if !enabled() {
+        return;
+    }
+    REGISTRY.lock().unwrap().instances.remove(id);
+}
+
+/// Stops serving the metrics, if they are served.
+pub(crate) fn shutdown() {
+    if let Some(Some(server)) = SERVER.get() {
+        server.shutdown();
+    }
+}
+
+fn with_instance(id: &str, f: impl FnOnce(&mut InstanceMetrics)) {
+    if !enabled() {
+        return;
+    }
+    if let Some(instance) = REGISTRY.lock().unwrap().instances.get_mut(id) {
+        f(instance);
+    }
+}
+
+fn exit_class(status: u32) -> &'static str {
+    match status {
+        0 => "success",
+        // the shell convention for the processes killed by a signal
+        129..=159 => "signal",
+        _ => "error",
+    }
+}
+
+struct Registry {
+    instances: BTreeMap<String, InstanceMetrics>,
+    // the number of exits, by class of exit code
+    exits: BTreeMap<&'static str, u64>,
+    // the engine and the version of the shim, once an instance is created
+    engine: Option<(EngineVersion, String)>,
+}
+
+struct InstanceMetrics {
+    pod_id: String,
+    image: Option<String>,
+    created: Instant,
+    running: bool,
+    startup: Option<Duration>,
+    compile: Option<Duration>,
+    cgroup: Option<Cgroup>,
+    // the last sample of the cgroup
+    memory: Option<u64>,
+    cpu: Option<Duration>,
+}
+
+// The value of a metric of an instance, if it has one
+type InstanceValue = fn(&InstanceMetrics) -> Option<f64>;
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            instances: BTreeMap::new(),
+            exits: BTreeMap::new(),
+            engine: None,
+        }
+    }
+}
+
+impl InstanceMetrics {
+    fn new(pod_id: Option<&str>) -> Self {
+        Self {
+            pod_id: pod_id.unwrap_or_default().to_string(),
+            image: None,
+            created: Instant::now(),
+            running: false,
+            startup: None,
+            compile: None,
+            cgroup: None,
+            memory: None,
+            cpu: None,
+        }
+    }
+}
+
+/// The state of an instance, for the debug dumps.
+#[derive(Serialize)]
+pub(super) struct InstanceSnapshot {
+    id: String,
+    pod_id: String,
+    image: Option<String>,
+    running: bool,
+    age_seconds: f64,
+    startup_seconds: Option<f64>,
+    compile_seconds: Option<f64>,
+    cgroup: Option<Cgroup>,
+    memory_bytes: Option<u64>,
+    cpu_seconds: Option<f64>,
+}
+
+/// Returns the state of the instances, without holding the lock of the registry for longer
+/// than it takes to copy it.
+pub(super) fn snapshot() -> Vec<InstanceSnapshot> {
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .instances
+        .iter()
+        .map(|(id, instance)| InstanceSnapshot {
+            id: id.clone(),
+            pod_id: instance.pod_id.clone(),
+            image: instance.image.clone(),
+            running: instance.running,
+            age_seconds: instance.created.elapsed().as_secs_f64(),
+            startup_seconds: instance.startup.map(|d| d.as_secs_f64()),
+            compile_seconds: instance.compile.map(|d| d.as_secs_f64()),
+            cgroup: instance.cgroup.clone(),
+            memory_bytes: instance.memory,
+            cpu_seconds: instance.cpu.map(|d| d.as_secs_f64()),
+        })
+        .collect()
+}
+
+// Samples the usage of the cgroups of the running instances. The cgroups are read without
+// holding the lock of the registry.
+fn sample_cgroups() {
+    let cgroups = REGISTRY
+        .lock()
+        .unwrap()
+        .instances
+        .iter()
+        .filter(|(_, instance)| instance.running)
+        .filter_map(|(id, instance)| Some((id.clone(), instance.cgroup.clone()?)))
+        .collect::<Vec<_>>();
+
+    for (id, cgroup) in cgroups {
+        let memory = cgroup.memory_usage();
+        let cpu = cgroup.cpu_usage();
+        match &memory {
+            // the cgroup is removed once the process exits
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                log::debug!("failed to sample the cgroup of instance {id}: {err}");
+            }
+            _ => {}
+        }
+        if let Some(instance) = REGISTRY.lock().unwrap().instances.get_mut(&id) {
+            instance.memory = memory.ok();
+            instance.cpu = cpu.ok();
+        }
+    }
+}
+
+/// Renders the metrics in the Prometheus text format, with the `(hits, misses)` of the
+/// layer cache.
+fn render(registry: &Registry, (hits, misses): (u64, u64)) -> String {
+    let mut out = String::new();
+
+    if let Some((engine, shim_version)) = &registry.engine {
+        family(
+            &mut out,
+            "runwasi_build_info",
+            "gauge",
+            "The engine and the version of the shim, in its labels.",
+        );
+        let labels = format!(
+            "engine=\"{}\",engine_version=\"{}\",engine_features=\"{}\",shim_version=\"{}\"",
+            escape(&engine.name),
+            escape(&engine.version),
+            escape(&engine.features.join(",")),
+            escape(shim_version)
+        );
+        sample(&mut out, "runwasi_build_info", &labels, 1);
+    }
+
+    let running = registry.instances.values().filter(|i| i.running).count();
+    family(
+        &mut out,
+        "runwasi_instances_running",
+        "gauge",
+        "Number of running instances.",
+    );
+    sample(&mut out, "runwasi_instances_running", "", running);
+
+    let per_instance: [(&str, &str, &str, InstanceValue); 5] = [
+        (
+            "runwasi_instance_running",
+            "gauge",
+            "Whether the process of the instance is running.",
+            |i| Some(if i.running { 1.0 } else { 0.0 }),
+        ),
+        (
+            "runwasi_instance_startup_seconds",
+            "gauge",
+            "Time from the creation of the instance until its process started.",
+            |i| i.startup.map(|d| d.as_secs_f64()),
+        ),
+        (
+            "runwasi_instance_compile_seconds",
+            "gauge",
+            "Time spent precompiling the wasm layers of the instance.",
+            |i| i.compile.map(|d| d.as_secs_f64()),
+        ),
+        (
+            "runwasi_instance_memory_bytes",
+            "gauge",
+            "Memory usage of the cgroup of the instance.",
+            |i| i.memory.map(|m| m as f64),
+        ),
+        (
+            "runwasi_instance_cpu_seconds_total",
+            "counter",
+            "CPU time used by the cgroup of the instance.",
+            |i| i.cpu.map(|d| d.as_secs_f64()),
+        ),
+    ];
+    for (name, kind, help, value) in per_instance {
+        family(&mut out, name, kind, help);
+        for (id, instance) in &registry.instances {
+            if let Some(value) = value(instance) {
+                let labels = format!(
+                    "container_id=\"{}\",pod_id=\"{}\"",
+                    escape(id),
+                    escape(&instance.pod_id)
+                );
+                sample(&mut out, name, &labels, value);
+            }
+        }
+    }
+
+    family(
+        &mut out,
+        "runwasi_instance_exits_total",
+        "counter",
+        "Number of exits of the processes of the instances, by class of exit code.",
+    );
+    for (class, count) in &registry.exits {
+        sample(
+            &mut out,
+            "runwasi_instance_exits_total",
+            &format!("class=\"{class}\""),
+            count,
+        );
+    }
+
+    family(
+        &mut out,
+        "runwasi_layer_cache_hits_total",
+        "counter",
+        "Number of wasm layers read from the layer cache.",
+    );
+    sample(&mut out, "runwasi_layer_cache_hits_total", "", hits);
+    family(
+        &mut out,
+        "runwasi_layer_cache_misses_total",
+        "counter",
+        "Number of wasm layers read from containerd.",
+    );
+    sample(&mut out, "runwasi_layer_cache_misses_total", "", misses);
+
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl Display) {
+    let _ = match labels {
+        "" => writeln!(out, "{name} {value}"),
+        labels => writeln!(out, "{name}{{{labels}}} {value}"),
+    };
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The address the metrics are served on.
+#[derive(Debug, Clone, PartialEq)]
+enum Address {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl Address {
+    /// Parses the value of `instances.metrics_address` for a shim with `pid`.
+    fn parse(value: &str, pid: u32) -> Result<Self, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            let path = PathBuf::from(path.replace("{pid}", &pid.to_string()));
+            if !path.is_absolute() {
+                return Err("the path of the socket must be absolute".to_string());
+            }
+            return Ok(Self::Unix(path));
+        }
+
+        let addr = match value.strip_prefix("localhost:") {
+            Some(port) => port
+                .parse()
+                .map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+                .map_err(|err| err.to_string())?,
+            None => value.parse::<SocketAddr>().map_err(|err| err.to_string())?,
+        };
+        // the metrics aren't authenticated, so they must not be reachable from other nodes
+        if !addr.ip().is_loopback() {
+            return Err("only loopback addresses are allowed".to_string());
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+enum Listener<U = std::os::unix::net::UnixListener, T = std::net::TcpListener> {
+    Unix(U),
+    Tcp(T),
+}
+
+type AsyncListener = Listener<tokio::net::UnixListener, tokio::net::TcpListener>;
+
+impl Listener {
+    fn bind(address: &Address) -> IoResult<Self> {
+        let listener = match address {
+            Address::Unix(path) => {
+                // a socket left behind by a previous shim with the same pid
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let listener = std::os::unix::net::UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+                Self::Unix(listener)
+            }
+            Address::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Self::Tcp(listener)
+            }
+        };
+        Ok(listener)
+    }
+
+    // Registers the listener with the runtime the server runs on
+    fn into_async(self) -> IoResult<AsyncListener> {
+        Ok(match self {
+            Self::Unix(listener) => Listener::Unix(tokio::net::UnixListener::from_std(listener)?),
+            Self::Tcp(listener) => Listener::Tcp(tokio::net::TcpListener::from_std(listener)?),
+        })
+    }
+}
+
+impl AsyncListener {
+    // Accepts a connection, and serves it on a task
+    async fn accept(&self) -> IoResult<()> {
+        match self {
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle(stream));
+            }
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle(stream));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Server {
+    address: Address,
+    // the shutdown signal and the thread of the server, until it's shut down
+    running: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Server {
+    fn from_config() -> Option<Self> {
+        let value = ShimConfig::get().instances.metrics_address.as_ref()?;
+        if value.is_empty() {
+            return None;
+        }
+        let address = match Address::parse(value, std::process::id()) {
+            Ok(address) => address,
+            Err(err) => {
+                log::warn!(
+                    "invalid `instances.metrics_address` {value:?}, metrics are disabled: {err}"
+                );
+                return None;
+            }
+        };
+        match Self::start(address.clone()) {
+            Ok(server) => {
+                log::info!("serving metrics on {address}");
+                Some(server)
+            }
+            Err(err) => {
+                log::warn!("failed to serve metrics on {address}, metrics are disabled: {err}");
+                None
+            }
+        }
+    }
+
+    fn start(address: Address) -> IoResult<Self> {
+        let listener = Listener::bind(&address)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let thread = std::thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || runtime.block_on(serve(listener, shutdown_rx)))?;
+        Ok(Self {
+            address,
+            running: Mutex::new(Some((shutdown_tx, thread))),
+        })
+    }
+
+    fn shutdown(&self) {
+        let Some((shutdown_tx, thread)) = self.running.lock().unwrap().take() else {
+            return;
+        };
+        let _ = shutdown_tx.send(());
+        let _ = thread.join();
+        let Address::Unix(path) = &self.address else {
+            return;
+        };
+        if let Err(err) = std::fs::remove_file(path) {
+            log::warn!("failed to remove the metrics socket {path:?}: {err}");
+        }
+    }
+}
+
+async fn serve(listener: Listener, mut shutdown: oneshot::Receiver<()>) {
+    let listener = match listener.into_async() {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("failed to serve metrics: {err}");
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = interval.tick() => sample_cgroups(),
+            res = listener.accept() => if let Err(err) = res {
+                log::warn!("failed to accept a metrics connection: {err}");
+            },
+        }
+    }
+}
+
+async fn handle(stream: impl AsyncRead + AsyncWrite + Unpin) {
+    match tokio::time::timeout(REQUEST_TIMEOUT, serve_connection(stream)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::debug!("failed to serve metrics: {err}"),
+        Err(_) => log::debug!("metrics request timed out"),
+    }
+}
+
+// Serves a single request, closing the connection after the response
+async fn serve_connection(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> IoResult<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let (status, body) = respond(head.lines().next().unwrap_or_default());
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Returns the status and the body of the response to the request with `request_line`
+fn respond(request_line: &str) -> (&'static str, String) {
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", "/metrics") => {
+            let cache = (LAYER_CACHE.hits(), LAYER_CACHE.misses());
+            ("200 OK", render(&REGISTRY.lock().unwrap(), cache))
+        }
+        ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            Address::parse("unix:/run/runwasi/metrics-{pid}.sock", 42),
+            Ok(Address::Unix("/run/runwasi/metrics-42.sock".into()))
+        );
+        assert_eq!(
+            Address::parse("127.0.0.1:9100", 42),
+            Ok(Address::Tcp("127.0.0.1:9100".parse().unwrap()))
+        );
+        assert_eq!(
+            Address::parse("[::1]:9100", 42),
+            Ok(Address::Tcp("[::1]:9100".parse().unwrap()))
+        );
+        assert_eq!(
+            Address::parse("localhost:9100", 42),
+            Ok(Address::Tcp("127.0.0.1:9100".parse().unwrap()))
+        );
+
+        assert!(Address::parse("unix:metrics.sock", 42).is_err());
+        assert!(Address::parse("0.0.0.0:9100", 42).is_err());
+        assert!(Address::parse("localhost", 42).is_err());
+    }
+
+    #[test]
+    fn test_exit_class() {
+        assert_eq!(exit_class(0), "success");
+        assert_eq!(exit_class(1), "error");
+        assert_eq!(exit_class(137), "signal");
+        assert_eq!(exit_class(255), "error");
+    }
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::new();
+        let mut running = InstanceMetrics::new(Some("pod\"1"));
+        running.running = true;
+        running.startup = Some(Duration::from_millis(1500));
+        running.compile = Some(Duration::from_millis(250));
+        running.memory = Some(4096);
+        running.cpu = Some(Duration::from_millis(20));
+        registry.instances.insert("running".to_string(), running);
+        registry
+            .instances
+            .insert("exited".to_string(), InstanceMetrics::new(None));
+        registry.exits.insert("success", 2);
+        registry.exits.insert("signal", 1);
+        let engine = EngineVersion {
+            name: "wasmtime".to_string(),
+            version: "27.0.0".to_string(),
+            features: vec!["component-model".to_string(), "threads".to_string()],
+        };
+        registry.engine = Some((engine, "0.6.0".to_string()));
+
+        let out = render(&registry, (3, 1));
+        let lines = out.lines().collect::<Vec<_>>();
+        let labels = r#"container_id="running",pod_id="pod\"1""#;
+        for expected in [
+            "# TYPE runwasi_instances_running gauge",
+            "runwasi_instances_running 1",
+            &format!("runwasi_instance_running{{{labels}}} 1"),
+            r#"runwasi_instance_running{container_id="exited",pod_id=""} 0"#,
+            &format!("runwasi_instance_startup_seconds{{{labels}}} 1.5"),
+            &format!("runwasi_instance_compile_seconds{{{labels}}} 0.25"),
+            &format!("runwasi_instance_memory_bytes{{{labels}}} 4096"),
+            "# TYPE runwasi_instance_cpu_seconds_total counter",
+            &format!("runwasi_instance_cpu_seconds_total{{{labels}}} 0.02"),
+            r#"runwasi_instance_exits_total{class="signal"} 1"#,
+            r#"runwasi_instance_exits_total{class="success"} 2"#,
+            "runwasi_layer_cache_hits_total 3",
+            "runwasi_layer_cache_misses_total 1",
+            r#"runwasi_build_info{engine="wasmtime",engine_version="27.0.0",engine_features="component-model,threads",shim_version="0.6.0"} 1"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {expected:?} in:\n{out}");
+        }
+        // the exited instance wasn't sampled nor started
+        assert!(!out.contains(r#"runwasi_instance_startup_seconds{container_id="exited""#));
+    }
+
+    #[tokio::test]
+    async fn test_serve_connection() -> IoResult<()> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(serve_connection(server));
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        served.await.unwrap()?;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains(&format!("Content-Type: {CONTENT_TYPE}\r\n")));
+        assert!(response.contains("\r\n\r\n# HELP runwasi_instances_running "));
+
+        assert_eq!(respond("GET /other HTTP/1.1").0, "404 Not Found");
+        assert_eq!(
+            respond("POST /metrics HTTP/1.1").0,
+            "405 Method Not Allowed"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Read-only memory mappings of files.
+//!
+//! The mapping stays valid after the file is closed or unlinked, and it's inherited by the
+//! processes forked after it was created, so the container init process can use a layer mapped
+//! by its zygote even though the file isn't visible from inside the container.
+//!
+//! A mapped file is locked shared until it's unmapped, so that the processes sharing its
+//! directory, e.g., the shims of a node sharing the layer files, don't remove it while it's in
+//! use, see [`remove_unmapped`].
+
+use std::fs::{File, remove_file};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::ops::Deref;
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::Path;
+use std::ptr::null_mut;
+
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+    // the file, locked shared while it's mapped
+    _file: Flock<File>,
+}
+
+// SAFETY: the mapping is read-only, so it can be shared between threads
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps the whole file at `path`.
+    /// The file must not be modified while it's mapped, so callers replace files rather than
+    /// writing to them in place.
+    pub(crate) fn open(path: impl AsRef<Path>) -> IoResult<Self> {
+        let path = path.as_ref();
+        let file = Flock::lock(File::open(path)?, FlockArg::LockShared)
+            .map_err(|(_, errno)| IoError::from(errno))?;
+        // the file may have been removed, or replaced, while it was being locked
+        let (locked, current) = (file.metadata()?, std::fs::metadata(path)?);
+        if (locked.dev(), locked.ino()) != (current.dev(), current.ino()) {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        let len = usize::try_from(locked.len()).map_err(IoError::other)?;
+        if len == 0 {
+            // mmap fails with EINVAL on empty mappings
+            return Ok(Self {
+                ptr: null_mut(),
+                len,
+                _file: file,
+            });
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(Self {
+            ptr,
+            len,
+            _file: file,
+        })
+    }
+}
+
+/// Removes the file at `path` unless a process maps it, and returns whether it's gone.
+/// The mappings of the calling process count too.
+pub(crate) fn remove_unmapped(path: impl AsRef<Path>) -> IoResult<bool> {
+    let path = path.as_ref();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err),
+    };
+    // held until the file is removed, so that it isn't mapped in the meantime
+    let _lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => lock,
+        Err((_, Errno::EWOULDBLOCK)) => return Ok(false),
+        Err((_, errno)) => return Err(errno.into()),
+    };
+    match remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(true),
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_mmap() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("layer");
+
+        write(&path, b"hello world")?;
+        let map = Mmap::open(&path)?;
+
+        // the mapping outlives the file
+        remove_file(&path)?;
+        assert_eq!(&*map, b"hello world");
+
+        write(&path, b"")?;
+        assert_eq!(&*Mmap::open(&path)?, b"");
+
+        assert!(Mmap::open(dir.path().join("missing")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_files_are_not_removed() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("layer");
+
+        write(&path, b"hello world")?;
+        let map = Mmap::open(&path)?;
+        assert!(!remove_unmapped(&path)?);
+        assert!(path.exists());
+
+        drop(map);
+        assert!(remove_unmapped(&path)?);
+        assert!(!path.exists());
+        assert!(remove_unmapped(&path)?);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,8 @@
 pub mod container;
 
+mod cgroup;
+mod debug;
+pub(crate) mod metrics;
+pub(crate) mod mmap;
+mod oom;
 mod pid_fd;
@@ -0,0 +1,210 @@
+//! Watches the cgroup of a container for OOM kills.
+//! On cgroup v2 the kernel signals changes to `memory.events` through inotify,
+//! while on cgroup v1 it signals OOM conditions through an eventfd registered
+//! in `cgroup.event_control`.
+
+use std::ffi::CString;
+use std::fs::{File, write};
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::Path;
+use std::pin::pin;
+
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::cgroup::Cgroup;
+
+pub(super) struct OomWatcher {
+    cgroup: Cgroup,
+    fd: AsyncFd<OwnedFd>,
+    kills: Option<u64>,
+    // On cgroup v1, the eventfd is only notified while this file is open
+    _oom_control: Option<File>,
+}
+
+impl OomWatcher {
+    pub(super) fn new(cgroup: Cgroup) -> IoResult<Self> {
+        let path = cgroup.oom_control().ok_or_else(|| {
+            IoError::new(ErrorKind::NotFound, "memory controller is not available")
+        })?;
+
+        let (fd, oom_control) = match &cgroup {
+            Cgroup::V2(_) => (inotify(&path)?, None),
+            Cgroup::V1(_) => {
+                let (fd, oom_control) = eventfd(&path)?;
+                (fd, Some(oom_control))
+            }
+        };
+        let kills = cgroup.oom_kills()?;
+
+        Ok(Self {
+            cgroup,
+            fd: AsyncFd::new(fd)?,
+            kills,
+            _oom_control: oom_control,
+        })
+    }
+
+    /// Forwards the OOM kills in the cgroup to `tx` until `exit` resolves.
+    /// The OOM kills are counted once more after that, so that a kill that caused
+    /// the exit is reported even if its notification is not delivered yet.
+    pub(super) async fn forward_until<T>(
+        mut self,
+        tx: UnboundedSender<()>,
+        exit: impl Future<Output = T>,
+    ) -> T {
+        let mut exit = pin!(exit);
+
+        let res = loop {
+            tokio::select! {
+                res = &mut exit => break res,
+                kills = self.wait() => match kills {
+                    Ok(kills) => notify(&tx, kills),
+                    Err(err) => {
+                        log::warn!("failed to watch for OOM kills: {err}");
+                        break exit.await;
+                    }
+                },
+            }
+        };
+
+        match self.count(false) {
+            Ok(kills) => notify(&tx, kills),
+            Err(err) => log::debug!("failed to count OOM kills: {err}"),
+        }
+
+        res
+    }
+
+    /// Waits for a notification from the memory controller and returns the number of new OOM kills.
+    async fn wait(&mut self) -> IoResult<u64> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| drain(fd.get_ref())) {
+                res?;
+                return self.count(true);
+            }
+        }
+    }
+
+    /// Returns the number of OOM kills since the last call.
+    /// If the kernel doesn't count OOM kills, every notification counts as one.
+    fn count(&mut self, notified: bool) -> IoResult<u64> {
+        let kills = self.cgroup.oom_kills()?;
+        let new = match (self.kills, kills) {
+            (Some(old), Some(kills)) => kills.saturating_sub(old),
+            (None, Some(kills)) => kills,
+            (None, None) => notified as u64,
+            (Some(_), None) => 0,
+        };
+        self.kills = kills.or(self.kills);
+        Ok(new)
+    }
+}
+
+fn notify(tx: &UnboundedSender<()>, kills: u64) {
+    for _ in 0..kills {
+        let _ = tx.send(());
+    }
+}
+
+fn inotify(path: &Path) -> IoResult<OwnedFd> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd == -1 {
+        return Err(IoError::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), libc::IN_MODIFY) } == -1 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+fn eventfd(oom_control: &Path) -> IoResult<(OwnedFd, File)> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd == -1 {
+        return Err(IoError::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let file = File::open(oom_control)?;
+    write(
+        oom_control.with_file_name("cgroup.event_control"),
+        format!("{} {}", fd.as_raw_fd(), file.as_raw_fd()),
+    )?;
+
+    Ok((fd, file))
+}
+
+/// Reads all the pending notifications from `fd`.
+/// Fails with `WouldBlock` if there were none.
+fn drain(fd: &OwnedFd) -> IoResult<()> {
+    let mut buf = [0u8; 4096];
+    let mut drained = false;
+    loop {
+        let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        match n {
+            0 => return Ok(()),
+            n if n > 0 => drained = true,
+            _ => {
+                let err = IoError::last_os_error();
+                return match err.kind() {
+                    ErrorKind::WouldBlock if drained => Ok(()),
+                    _ => Err(err),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir_all;
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_forward_oom_kills() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test");
+        create_dir_all(&path)?;
+        write(path.join("memory.events"), "oom 0\noom_kill 0\n")?;
+
+        let watcher = OomWatcher::new(Cgroup::V2(path.clone()))?;
+        let (tx, mut rx) = unbounded_channel();
+        let (exit_tx, exit_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(watcher.forward_until(tx, exit_rx));
+
+        write(path.join("memory.events"), "oom 1\noom_kill 1\n")?;
+        assert_eq!(rx.recv().await, Some(()));
+
+        // a kill right before the exit is reported before the watcher stops
+        write(path.join("memory.events"), "oom 2\noom_kill 2\n")?;
+        exit_tx.send(()).unwrap();
+        task.await??;
+
+        assert_eq!(rx.recv().await, Some(()));
+        assert_eq!(rx.recv().await, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_memory_controller() {
+        let err = OomWatcher::new(Cgroup::V1(Default::default()))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -15,18 +15,30 @@ pub(super) struct PidFd {
 }
 
 impl PidFd {
+    /// Opens a pidfd for the child `pid` of the shim. The exit events of the reaper are
+    /// subscribed to first, so that the status of a child it reaps is never missed.
+    /// Fails with `ESRCH`, see [`is_reaped`], if the child has already been reaped.
     pub(super) fn new(pid: impl Into<pid_t>) -> anyhow::Result<Self> {
         use libc::{PIDFD_NONBLOCK, SYS_pidfd_open, syscall};
         let pid = pid.into();
         let subs = monitor_subscribe(Topic::Pid)?;
         let pidfd = unsafe { syscall(SYS_pidfd_open, pid, PIDFD_NONBLOCK) };
         if pidfd == -1 {
-            return Err(std::io::Error::last_os_error().into());
+            let err = std::io::Error::last_os_error();
+            return Err(anyhow::Error::new(err).context(format!("pidfd_open of pid {pid} failed")));
         }
         let fd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
         Ok(Self { fd, pid, subs })
     }
 
+    /// Waits for a process that is not a child of this process to exit.
+    /// Only its parent can reap it, so its exit status is not available.
+    pub(super) async fn wait_exited(self) -> std::io::Result<()> {
+        let fd = AsyncFd::new(self.fd)?;
+        let _ = fd.readable().await?;
+        Ok(())
+    }
+
     pub(super) async fn wait(self) -> std::io::Result<WaitStatus> {
         let fd = AsyncFd::new(self.fd)?;
         loop {
@@ -47,7 +59,8 @@ impl PidFd {
                     return Ok(status);
                 }
                 Err(Errno::ECHILD) => {
-                    // The process has already been reaped by the containerd-shim reaper.
+                    // The process has already been reaped by the containerd-shim reaper,
+                    // the only one that waits for any child of the shim, with `waitpid(-1)`.
                     // Get the status from there.
                     let status = try_wait_pid(self.pid, self.subs).await?;
                     return Ok(WaitStatus::Exited(Pid::from_raw(self.pid), status));
@@ -58,6 +71,13 @@ impl PidFd {
     }
 }
 
+/// Whether the error of [`PidFd::new`] is because the process has exited and been reaped.
+pub(super) fn is_reaped(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .and_then(std::io::Error::raw_os_error)
+        == Some(libc::ESRCH)
+}
+
 pub async fn try_wait_pid(pid: i32, s: Subscription) -> Result<i32, Errno> {
     tokio::task::spawn_blocking(move || {
         while let Ok(ExitEvent { subject, exit_code }) = s.rx.recv_timeout(Duration::from_secs(2)) {
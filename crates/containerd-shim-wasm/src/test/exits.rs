@@ -0,0 +1,115 @@
+//! Tests for the guests that exit right away, possibly before the shim waits for them.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result, bail};
+use containerd_shim::protos::ttrpc;
+use containerd_shim_wasm_test_modules::HELLO_WORLD;
+use containerd_shimkit::AmbientRuntime as _;
+use containerd_shimkit::sandbox::Instance as _;
+
+use crate::sandbox::Sandbox;
+use crate::sandbox::context::RuntimeContext;
+use crate::shim::Shim;
+use crate::testing::WasiTest;
+
+pub struct ExitingEngine;
+
+#[derive(Default)]
+pub struct ExitingContainer;
+
+impl Shim for ExitingEngine {
+    fn name() -> &'static str {
+        "exiting-engine"
+    }
+
+    type Sandbox = ExitingContainer;
+}
+
+impl Sandbox for ExitingContainer {
+    async fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+        // exit as soon as the executor runs, without returning to it
+        std::process::exit(42)
+    }
+}
+
+#[test]
+fn test_exit_before_wait() -> Result<()> {
+    for _ in 0..10 {
+        let container = WasiTest::<ExitingEngine>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+
+        // the status is the one of the process, like for a runc container,
+        // and the container is deleted once it's waited for
+        let (code, ..) = container.start()?.wait(Duration::from_secs(10))?;
+        assert_eq!(code, 42);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_kill_after_exit() -> Result<()> {
+    let container = WasiTest::<ExitingEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .build()?;
+    let instance = container.start()?.instance();
+    let (code, _) = instance
+        .wait()
+        .with_timeout(Duration::from_secs(10))
+        .block_on()
+        .context("timeout while waiting for the instance")?;
+    assert_eq!(code, 42);
+
+    // the codes a client sees, e.g., `ctr task kill` treats not found as benign like for runc
+    let code = |signal| -> Result<ttrpc::Code> {
+        let Err(err) = instance.kill(signal).block_on() else {
+            bail!("signal {signal} was sent to an exited instance");
+        };
+        match ttrpc::Error::from(err) {
+            ttrpc::Error::RpcStatus(status) => Ok(status.code()),
+            err => bail!("unexpected error: {err:?}"),
+        }
+    };
+    assert_eq!(code(libc::SIGKILL as u32)?, ttrpc::Code::NOT_FOUND);
+    assert_eq!(code(1000)?, ttrpc::Code::INVALID_ARGUMENT);
+
+    // deleting again, e.g., when containerd retries, finds nothing left to delete
+    container.delete()?;
+    container.delete()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_restart_on_failure() -> Result<()> {
+    let container = WasiTest::<ExitingEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("io.runwasi.restart", "on-failure:1")
+        .build()?;
+
+    // the exit is only reported once the restarted process failed too, after the backoff
+    let starting = Instant::now();
+    let (code, ..) = container.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(code, 42);
+    assert!(starting.elapsed() >= Duration::from_secs(1));
+
+    // a kill cancels the pending restart, of an instance that would be restarted forever
+    let container = WasiTest::<ExitingEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("io.runwasi.restart", "on-failure")
+        .build()?;
+    let instance = container.start()?.instance();
+    std::thread::sleep(Duration::from_millis(500));
+    let _ = instance.kill(libc::SIGKILL as u32).block_on();
+    let (code, _) = instance
+        .wait()
+        .with_timeout(Duration::from_secs(10))
+        .block_on()
+        .context("timeout while waiting for the instance")?;
+    assert!(matches!(code, 42 | 137), "{code}");
+    container.delete()?;
+
+    Ok(())
+}
@@ -1,3 +1,5 @@
+mod exits;
+mod rootless;
 mod signals;
 
 #[ctor::ctor]
@@ -0,0 +1,58 @@
+//! A test of the basic lifecycle of a rootless instance.
+//!
+//! It's ignored, as it has to run in a user namespace, with a cgroup delegated to the user:
+//! ```
+//! systemd-run --user --scope -p Delegate=yes unshare -Ur \
+//!     cargo test -p containerd-shim-wasm -- test::rootless --show-output --nocapture --ignored
+//! ```
+
+use std::time::Duration;
+
+use anyhow::Result;
+use containerd_shim_wasm_test_modules::HELLO_WORLD;
+
+use crate::sandbox::Sandbox;
+use crate::sandbox::context::RuntimeContext;
+use crate::shim::Shim;
+use crate::testing::WasiTest;
+
+pub struct RootlessEngine;
+
+#[derive(Default)]
+pub struct RootlessContainer;
+
+impl Shim for RootlessEngine {
+    fn name() -> &'static str {
+        "rootless-engine"
+    }
+
+    type Sandbox = RootlessContainer;
+}
+
+impl Sandbox for RootlessContainer {
+    async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
+        println!("hello from {}", ctx.entrypoint().func);
+        Ok(0)
+    }
+}
+
+#[test]
+#[ignore]
+fn test_rootless_lifecycle() -> Result<()> {
+    let uid_map = std::fs::read_to_string("/proc/self/uid_map")?;
+    assert_ne!(
+        uid_map.split_whitespace().collect::<Vec<_>>(),
+        ["0", "0", "4294967295"],
+        "the test doesn't run in a user namespace"
+    );
+
+    let container = WasiTest::<RootlessEngine>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .build()?;
+
+    let (code, stdout, _) = container.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(code, 0);
+    assert!(stdout.contains("hello from _start"), "{stdout}");
+
+    Ok(())
+}
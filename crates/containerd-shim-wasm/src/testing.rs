@@ -8,7 +8,7 @@ use std::ops::Add;
 use std::os::unix::fs::symlink;
 #[cfg(windows)]
 use std::os::windows::fs::symlink_file as symlink;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::{Result, bail};
@@ -30,6 +30,10 @@ pub struct WasiTestBuilder<WasiEngine: Shim> {
     container_name: String,
     start_fn: String,
     namespaces: Vec<LinuxNamespace>,
+    masked_paths: Vec<String>,
+    annotations: HashMap<String, String>,
+    terminal: bool,
+    checkpoint: Option<PathBuf>,
     tempdir: tempfile::TempDir,
     _phantom: PhantomData<WasiEngine>,
 }
@@ -68,6 +72,10 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
             container_name: "test".to_string(),
             start_fn: "".to_string(),
             namespaces: get_default_namespaces(),
+            masked_paths: vec![],
+            annotations: HashMap::new(),
+            terminal: false,
+            checkpoint: None,
             _phantom: Default::default(),
         }
         .with_wasm([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?
@@ -90,6 +98,32 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
         self
     }
 
+    pub fn with_annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    /// Masks the directory `path` of the container, which is created in the rootfs.
+    pub fn with_masked_path(mut self, path: impl Into<String>) -> Result<Self> {
+        let path = path.into();
+        let dir = self.tempdir.path().join("rootfs");
+        fs::create_dir_all(dir.join(path.trim_start_matches('/')))?;
+        self.masked_paths.push(path);
+        Ok(self)
+    }
+
+    /// Runs the module attached to a terminal, which the instance connects to stdin and stdout.
+    pub fn with_terminal(mut self) -> Self {
+        self.terminal = true;
+        self
+    }
+
+    /// Restores the instance from the checkpoint in the directory `path`.
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
     pub fn with_start_fn(mut self, start_fn: impl AsRef<str>) -> Self {
         start_fn.as_ref().clone_into(&mut self.start_fn);
         self
@@ -194,14 +228,17 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
             .linux(
                 LinuxBuilder::default()
                     .namespaces(self.namespaces)
+                    .masked_paths(self.masked_paths)
                     .build()?,
             )
             .process(
                 ProcessBuilder::default()
                     .cwd("/")
                     .args([entrypoint])
+                    .terminal(self.terminal)
                     .build()?,
             )
+            .annotations(self.annotations)
             .build()?;
 
         spec.save(dir.join("config.json"))?;
@@ -215,6 +252,8 @@ impl<WasiEngine: Shim> WasiTestBuilder<WasiEngine> {
             stdout: dir.join("stdout"),
             stderr: dir.join("stderr"),
             stdin: dir.join("stdin"),
+            terminal: self.terminal,
+            checkpoint: self.checkpoint,
             ..Default::default()
         };
 
@@ -260,7 +299,19 @@ impl<WasiEngine: Shim> WasiTest<WasiEngine> {
 
     pub fn kill(&self) -> Result<&Self> {
         log::info!("sending SIGKILL");
-        self.instance.kill(SIGKILL as u32).block_on()?;
+        self.instance.kill(SIGKILL).block_on()?;
+        Ok(self)
+    }
+
+    pub fn checkpoint(&self, path: impl AsRef<Path>) -> Result<&Self> {
+        log::info!("checkpointing wasi test");
+        self.instance.checkpoint(path.as_ref()).block_on()?;
+        Ok(self)
+    }
+
+    pub fn resize_pty(&self, width: u32, height: u32) -> Result<&Self> {
+        log::info!("resizing the terminal to {width}x{height}");
+        self.instance.resize_pty(None, width, height).block_on()?;
         Ok(self)
     }
 
@@ -305,16 +356,55 @@ impl<WasiEngine: Shim> WasiTest<WasiEngine> {
     }
 }
 
+/// Returns the spec of a container running in `rootfs`, with the `annotations`.
+#[cfg(test)]
+pub(crate) fn spec_with_annotations(
+    annotations: &[(&str, &str)],
+) -> Result<oci_spec::runtime::Spec> {
+    let annotations = annotations
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+    Ok(SpecBuilder::default()
+        .root(RootBuilder::default().path("rootfs").build()?)
+        .process(ProcessBuilder::default().cwd("/").build()?)
+        .annotations(annotations)
+        .build()?)
+}
+
 pub mod oci_helpers {
     use std::fs::{File, write};
+    use std::path::Path;
     use std::process::{Command, Stdio};
     use std::time::{Duration, Instant};
 
     use anyhow::{Result, bail};
     use oci_spec::image::{self as spec, Arch};
-    use oci_tar_builder::Builder;
+    use oci_tar_builder::{Builder, OciConfig};
 
     use super::TEST_NAMESPACE;
+    use crate::sandbox::context::WasmConfig;
+
+    pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v0+json";
+    pub const WASM_ARTIFACT_LAYER_MEDIA_TYPE: &str = "application/wasm";
+
+    impl OciConfig for WasmConfig {
+        fn os(&self) -> String {
+            self.os.clone()
+        }
+
+        fn architecture(&self) -> String {
+            self.architecture.clone()
+        }
+
+        fn layers(&self) -> Vec<String> {
+            self.layer_digests.clone()
+        }
+
+        fn to_string(&self) -> String {
+            serde_json::to_string(self).unwrap()
+        }
+    }
 
     pub struct OCICleanup {
         pub image_name: String,
@@ -378,12 +468,7 @@ pub mod oci_helpers {
         let dir = tempdir.path();
 
         let mut builder = Builder::default();
-
-        for (i, content) in wasm_content.iter().enumerate() {
-            let path = tempdir.path().join(format!("{}.wasm", i));
-            write(path.clone(), content.bytes.clone())?;
-            builder.add_layer_with_media_type(&path, content.media_type.clone());
-        }
+        add_layers(&mut builder, dir, wasm_content)?;
 
         let config = spec::ConfigBuilder::default()
             .entrypoint(vec!["_start".to_string()])
@@ -401,6 +486,52 @@ pub mod oci_helpers {
             )
             .build()?;
         builder.add_config(img, image_name.to_string(), spec::MediaType::ImageConfig);
+        import_tar(builder, dir)
+    }
+
+    /// Imports an image in the wasm OCI artifact layout, with a wasm config
+    /// and the layers in `wasm_content`.
+    pub fn import_wasm_artifact(
+        image_name: &str,
+        wasm_content: &[&ImageContent],
+    ) -> Result<(), anyhow::Error> {
+        let tempdir = tempfile::tempdir()?;
+        let dir = tempdir.path();
+
+        let mut builder = Builder::default();
+        add_layers(&mut builder, dir, wasm_content)?;
+
+        let config = WasmConfig {
+            architecture: "wasm".to_string(),
+            os: "wasip1".to_string(),
+            layer_digests: wasm_content
+                .iter()
+                .map(|content| format!("sha256:{}", sha256::digest(content.bytes.as_slice())))
+                .collect(),
+            ..Default::default()
+        };
+        builder.add_config(
+            config,
+            image_name.to_string(),
+            spec::MediaType::Other(WASM_CONFIG_MEDIA_TYPE.to_string()),
+        );
+        import_tar(builder, dir)
+    }
+
+    fn add_layers<C: OciConfig>(
+        builder: &mut Builder<C>,
+        dir: &Path,
+        wasm_content: &[&ImageContent],
+    ) -> Result<()> {
+        for (i, content) in wasm_content.iter().enumerate() {
+            let path = dir.join(format!("{}.wasm", i));
+            write(path.clone(), content.bytes.clone())?;
+            builder.add_layer_with_media_type(&path, content.media_type.clone());
+        }
+        Ok(())
+    }
+
+    fn import_tar<C: OciConfig>(mut builder: Builder<C>, dir: &Path) -> Result<()> {
         let img_path = dir.join("img.tar");
         let f = File::create(img_path.clone())?;
         builder.build(f)?;
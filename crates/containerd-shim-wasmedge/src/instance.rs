@@ -1,13 +1,16 @@
 use std::collections::HashMap;
 use std::env;
+use std::path::Path;
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
 use std::str::FromStr;
 
 use anyhow::{Context, Result};
 use cfg_if::cfg_if;
-use containerd_shim_wasm::sandbox::Sandbox;
-use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
-use containerd_shim_wasm::shim::{Shim, Version, version};
+use containerd_shim_wasm::sandbox::context::{
+    Capabilities, Capability, Entrypoint, RuntimeContext,
+};
+use containerd_shim_wasm::sandbox::{Sandbox, check_entrypoint};
+use containerd_shim_wasm::shim::{Shim, ShimConfig, Version, version};
 #[cfg(all(feature = "plugin", not(target_env = "musl")))]
 use wasmedge_sdk::AsInstance;
 use wasmedge_sdk::config::{CommonConfigOptions, Config, ConfigBuilder};
@@ -18,6 +21,8 @@ use wasmedge_sdk::plugin::PluginManager;
 use wasmedge_sdk::wasi::WasiModule;
 use wasmedge_sdk::{Module, Store, Vm};
 
+use crate::plugins;
+
 pub struct WasmEdgeShim;
 
 pub struct WasmEdgeSandbox {
@@ -43,9 +48,39 @@ impl Shim for WasmEdgeShim {
     }
 
     type Sandbox = WasmEdgeSandbox;
+
+    fn engine_config_keys() -> &'static [&'static str] {
+        &[plugins::PLUGINS_KEY]
+    }
+
+    fn supported_capabilities() -> Capabilities {
+        // the sockets of WasmEdge come with its WASI module, so they can't be granted on their own
+        [Capability::FsWrite, Capability::Env].into_iter().collect()
+    }
+
+    fn reports_guest_started() -> bool {
+        true
+    }
 }
 
 impl Sandbox for WasmEdgeSandbox {
+    async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        async move {
+            if let Some(requested) = plugins::requested(ctx, ShimConfig::get())? {
+                cfg_if! {
+                    if #[cfg(not(all(feature = "plugin", not(target_env = "musl"))))] {
+                        if let Some(plugin) = requested.first() {
+                            anyhow::bail!("plugin {} is requested, but this shim is built without plugins", plugin.name);
+                        }
+                    }
+                }
+                plugins::find(&requested, &plugins::plugin_dirs(ctx.envs()))?;
+            }
+            plugins::check_gpu_devices(ctx.envs(), Path::new("/dev"))?;
+            check_entrypoint(ctx)
+        }
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx.envs();
@@ -70,28 +105,70 @@ impl Sandbox for WasmEdgeSandbox {
         let mut instances = HashMap::new();
         cfg_if! {
             if #[cfg(all(feature = "plugin", not(target_env = "musl")))] {
-                PluginManager::load(None)?;
+                let requested = plugins::requested(ctx, ShimConfig::get())?;
+                match &requested {
+                    Some(requested) => {
+                        for path in plugins::find(requested, &plugins::plugin_dirs(ctx.envs()))? {
+                            log::debug!("loading wasmedge plugin {path:?}");
+                            PluginManager::load(Some(path.as_path()))
+                                .with_context(|| format!("loading wasmedge plugin {path:?}"))?;
+                        }
+                    }
+                    None => PluginManager::load(None)?,
+                }
                 match env::var("WASMEDGE_WASINN_PRELOAD") {
                     Ok(value) => PluginManager::nn_preload(vec![NNPreload::from_str(value.as_str())?]),
                     Err(_) => log::debug!("No specific nn_preload parameter for wasi_nn plugin"),
                 }
 
-                // Load the wasi_nn plugin manually as a workaround.
-                // It should call auto_detect_plugins after the issue is fixed.
-                let mut wasi_nn = PluginManager::names()
-                    .contains(&"wasi_nn".to_string())
-                    .then(PluginManager::load_plugin_wasi_nn)
-                    .transpose()?;
-                if let Some(ref mut nn) = wasi_nn {
-                    instances.insert(nn.name().unwrap().to_string(), nn);
+                let mut plugin_instances = vec![];
+                match requested {
+                    Some(requested) => {
+                        for plugin in requested {
+                            // the wasi_nn plugin needs the workaround below too
+                            if plugin.name == "wasi_nn" {
+                                plugin_instances.push(PluginManager::load_plugin_wasi_nn()?);
+                                continue;
+                            }
+                            for module in plugin.modules {
+                                let instance = PluginManager::create_plugin_instance(plugin.name, module)
+                                    .with_context(|| format!("instantiating module {module} of wasmedge plugin {}", plugin.name))?;
+                                plugin_instances.push(instance);
+                            }
+                        }
+                    }
+                    None => {
+                        // Load the wasi_nn plugin manually as a workaround.
+                        // It should call auto_detect_plugins after the issue is fixed.
+                        let wasi_nn = PluginManager::names()
+                            .contains(&"wasi_nn".to_string())
+                            .then(PluginManager::load_plugin_wasi_nn)
+                            .transpose()?;
+                        plugin_instances.extend(wasi_nn);
+                    }
+                }
+                for instance in &mut plugin_instances {
+                    instances.insert(instance.name().unwrap().to_string(), instance);
                 }
             }
         }
 
+        // preopens are given as `guest:host`, with a `:readonly` suffix for read-only ones
+        let root = if ctx.root_read_only() {
+            "/:/:readonly"
+        } else {
+            "/:/"
+        };
+        let mut dirs = vec![root.to_string()];
+        for preopen in ctx.preopens() {
+            let path = preopen.path.display();
+            let suffix = if preopen.read_only { ":readonly" } else { "" };
+            dirs.push(format!("{path}:{path}{suffix}"));
+        }
         let mut wasi_module = WasiModule::create(
             Some(args.iter().map(String::as_str).collect()),
             Some(envs.iter().map(String::as_str).collect()),
-            Some(vec!["/:/"]),
+            Some(dirs.iter().map(String::as_str).collect()),
         )?;
         instances.insert(wasi_module.name().to_string(), wasi_module.as_mut());
 
@@ -105,6 +182,7 @@ impl Sandbox for WasmEdgeSandbox {
             .context("registering module")?;
 
         log::debug!("running with method {func:?}");
+        ctx.guest_started();
         vm.run_func(Some(&mod_name), func, vec![])?;
 
         Ok(wasi_module.exit_code() as i32)
@@ -1,6 +1,8 @@
 pub mod instance;
+mod plugins;
 
 pub use instance::WasmEdgeShim;
+pub use plugins::{PLUGINS_ANNOTATION, PLUGINS_KEY};
 
 #[cfg(unix)]
 #[cfg(test)]
@@ -0,0 +1,269 @@
+//! Host plugins of WasmEdge, e.g., `wasi_nn` for the guests running inference.
+//!
+//! The plugins a container needs are listed in its [`PLUGINS_ANNOTATION`], e.g.,
+//! `io.runwasi.wasmedge.plugins=wasi_nn,wasi_crypto`, or else in the [`PLUGINS_KEY`] engine
+//! setting of the shim.
+//! They are loaded from the `WASMEDGE_PLUGIN_PATH` directories of the container or of the shim,
+//! or else from `/usr/local/lib/wasmedge`, and registered before the guest is instantiated.
+//! Without a list, the plugins of the default paths of WasmEdge are loaded, and only `wasi_nn`
+//! is registered.
+
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use containerd_shim_wasm::shim::ShimConfig;
+
+/// Annotation with the comma separated plugins to load for the container.
+pub const PLUGINS_ANNOTATION: &str = "io.runwasi.wasmedge.plugins";
+
+/// Engine setting with the plugins to load for the containers without a
+/// [`PLUGINS_ANNOTATION`], in the same format.
+pub const PLUGINS_KEY: &str = "plugins";
+
+// The directories of the plugins, separated like `PATH`
+const PLUGIN_PATH_ENV: &str = "WASMEDGE_PLUGIN_PATH";
+
+const DEFAULT_PLUGIN_PATH: &str = "/usr/local/lib/wasmedge";
+
+// The model `wasi_nn` preloads, as `name:encoding:target:path`, e.g.,
+// `default:GGML:GPU:/models/llama.gguf`
+const WASINN_PRELOAD_ENV: &str = "WASMEDGE_WASINN_PRELOAD";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Plugin {
+    pub(crate) name: &'static str,
+    // the name of its library, without the prefix, the `wasmedgePlugin` stem and the extension
+    library: &'static str,
+    /// The modules of the plugin the guests import from.
+    pub(crate) modules: &'static [&'static str],
+}
+
+const PLUGINS: &[Plugin] = &[
+    Plugin {
+        name: "wasi_nn",
+        library: "WasiNN",
+        modules: &["wasi_ephemeral_nn"],
+    },
+    Plugin {
+        name: "wasi_crypto",
+        library: "WasiCrypto",
+        modules: &[
+            "wasi_ephemeral_crypto_common",
+            "wasi_ephemeral_crypto_asymmetric_common",
+            "wasi_ephemeral_crypto_kx",
+            "wasi_ephemeral_crypto_signatures",
+            "wasi_ephemeral_crypto_symmetric",
+        ],
+    },
+    Plugin {
+        name: "wasi_logging",
+        library: "WasiLogging",
+        modules: &["wasi:logging/logging"],
+    },
+    Plugin {
+        name: "wasmedge_image",
+        library: "WasmEdgeImage",
+        modules: &["wasmedge_image"],
+    },
+    Plugin {
+        name: "wasmedge_process",
+        library: "WasmEdgeProcess",
+        modules: &["wasmedge_process"],
+    },
+    Plugin {
+        name: "wasmedge_tensorflow",
+        library: "WasmEdgeTensorflow",
+        modules: &["wasmedge_tensorflow"],
+    },
+    Plugin {
+        name: "wasmedge_tensorflowlite",
+        library: "WasmEdgeTensorflowLite",
+        modules: &["wasmedge_tensorflowlite"],
+    },
+];
+
+impl Plugin {
+    fn file_name(&self) -> String {
+        format!("{DLL_PREFIX}wasmedgePlugin{}{DLL_SUFFIX}", self.library)
+    }
+}
+
+/// Returns the plugins listed for the container, or else in the `config` of the shim, or `None`
+/// if there is no list.
+pub(crate) fn requested(
+    ctx: &impl RuntimeContext,
+    config: &ShimConfig,
+) -> Result<Option<Vec<&'static Plugin>>> {
+    if let Some(list) = ctx.annotation(PLUGINS_ANNOTATION) {
+        return parse(list)
+            .map(Some)
+            .with_context(|| format!("invalid {PLUGINS_ANNOTATION} annotation: {list:?}"));
+    }
+    match config.engine_value(PLUGINS_KEY) {
+        Some(list) => parse(&list)
+            .map(Some)
+            .with_context(|| format!("invalid `engine.{PLUGINS_KEY}` value {list:?}")),
+        None => Ok(None),
+    }
+}
+
+fn parse(list: &str) -> Result<Vec<&'static Plugin>> {
+    let mut plugins = vec![];
+    for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let Some(plugin) = PLUGINS.iter().find(|plugin| plugin.name == name) else {
+            let known: Vec<_> = PLUGINS.iter().map(|plugin| plugin.name).collect();
+            bail!(
+                "unknown plugin {name:?}, expected one of {}",
+                known.join(", ")
+            );
+        };
+        if !plugins.contains(&plugin) {
+            plugins.push(plugin);
+        }
+    }
+    Ok(plugins)
+}
+
+/// Returns the directories to load the plugins from, for the env vars `envs` of the container.
+pub(crate) fn plugin_dirs(envs: &[String]) -> Vec<PathBuf> {
+    let path = envs
+        .iter()
+        .find_map(|env| env.strip_prefix(PLUGIN_PATH_ENV)?.strip_prefix('='))
+        .map(str::to_string)
+        .or_else(|| std::env::var(PLUGIN_PATH_ENV).ok())
+        .filter(|path| !path.is_empty());
+    match path {
+        Some(path) => std::env::split_paths(&path).collect(),
+        None => vec![PathBuf::from(DEFAULT_PLUGIN_PATH)],
+    }
+}
+
+/// Returns the library of each of the `plugins` in the first of `dirs` that has it, failing
+/// with the paths that were tried when one of them is missing.
+pub(crate) fn find(plugins: &[&Plugin], dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    plugins
+        .iter()
+        .map(|plugin| {
+            let tried: Vec<_> = dirs
+                .iter()
+                .map(|dir| dir.join(plugin.file_name()))
+                .collect();
+            match tried.iter().find(|path| path.is_file()) {
+                Some(path) => Ok(path.clone()),
+                None => {
+                    let tried: Vec<_> = tried.iter().map(|path| format!("{path:?}")).collect();
+                    bail!(
+                        "wasmedge plugin {} not found, tried {}",
+                        plugin.name,
+                        tried.join(", ")
+                    )
+                }
+            }
+        })
+        .collect()
+}
+
+/// Checks that the `dev` directory of the container has the device nodes of a GPU when the
+/// env vars `envs` of the container preload a `wasi_nn` model on the GPU. They must be passed
+/// to the container, e.g., by the device plugin of the GPU, for the backends to use it.
+pub(crate) fn check_gpu_devices(envs: &[String], dev: &Path) -> Result<()> {
+    let Some(preload) = envs
+        .iter()
+        .find_map(|env| env.strip_prefix(WASINN_PRELOAD_ENV)?.strip_prefix('='))
+    else {
+        return Ok(());
+    };
+    let target = preload.split(':').nth(2).unwrap_or_default();
+    if !target.eq_ignore_ascii_case("gpu") || has_gpu_devices(dev) {
+        return Ok(());
+    }
+    bail!(
+        "{WASINN_PRELOAD_ENV} preloads {preload:?} on the GPU, but there is no GPU device in {dev:?}, \
+        e.g., nvidia0, kfd or dri/renderD128"
+    )
+}
+
+fn has_gpu_devices(dev: &Path) -> bool {
+    let names = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+    };
+    let is_gpu = |name: String| match name.strip_prefix("nvidia") {
+        Some(index) => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+        None => name == "kfd",
+    };
+    names(dev).any(is_gpu) || names(&dev.join("dri")).any(|name| name.starts_with("renderD"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plugins() -> Result<()> {
+        let plugins = parse(" wasi_nn, wasmedge_image,wasi_nn,")?;
+        let names: Vec<_> = plugins.iter().map(|plugin| plugin.name).collect();
+        assert_eq!(names, ["wasi_nn", "wasmedge_image"]);
+        assert!(parse("")?.is_empty());
+
+        let err = parse("wasi_nn,wasi_gpu").unwrap_err();
+        assert!(err.to_string().contains("\"wasi_gpu\""), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_plugins() -> Result<()> {
+        let first = tempfile::tempdir()?;
+        let second = tempfile::tempdir()?;
+        let dirs = [first.path().to_path_buf(), second.path().to_path_buf()];
+        let nn = parse("wasi_nn")?;
+        let library = second.path().join(nn[0].file_name());
+        std::fs::write(&library, "")?;
+
+        assert_eq!(find(&nn, &dirs)?, [library]);
+
+        // the error has all the paths that were tried
+        let err = find(&parse("wasi_nn,wasi_crypto")?, &dirs).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("wasi_crypto"), "{err}");
+        for dir in &dirs {
+            assert!(err.contains(&*dir.to_string_lossy()), "{err}");
+        }
+
+        let envs = [format!("{PLUGIN_PATH_ENV}=/opt/a:/opt/b")];
+        assert_eq!(
+            plugin_dirs(&envs),
+            [PathBuf::from("/opt/a"), PathBuf::from("/opt/b")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_gpu_devices() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        let gpu = [format!(
+            "{WASINN_PRELOAD_ENV}=default:GGML:GPU:/models/llama.gguf"
+        )];
+        let cpu = [format!(
+            "{WASINN_PRELOAD_ENV}=default:GGML:CPU:/models/llama.gguf"
+        )];
+
+        check_gpu_devices(&[], dev.path())?;
+        check_gpu_devices(&cpu, dev.path())?;
+        assert!(check_gpu_devices(&gpu, dev.path()).is_err());
+
+        std::fs::write(dev.path().join("nvidiactl"), "")?;
+        assert!(check_gpu_devices(&gpu, dev.path()).is_err());
+        std::fs::write(dev.path().join("nvidia0"), "")?;
+        check_gpu_devices(&gpu, dev.path())?;
+
+        Ok(())
+    }
+}
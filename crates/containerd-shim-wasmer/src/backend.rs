@@ -0,0 +1,164 @@
+//! The compiler backends of wasmer.
+//!
+//! Cranelift is the default, singlepass compiles the fastest for the cold starts of short jobs,
+//! and LLVM generates the fastest code for long-running compute. The backend of a container is
+//! set with its [`BACKEND_ANNOTATION`], and defaults to the [`BACKEND_KEY`] engine setting of
+//! the shim.
+//! Singlepass and LLVM are only available when the shim is built with their features.
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::shim::ShimConfig;
+
+/// Annotation with the compiler backend of the container: `cranelift`, `singlepass` or `llvm`.
+pub const BACKEND_ANNOTATION: &str = "io.runwasi.wasmer.backend";
+
+/// Engine setting with the compiler backend of the containers without a [`BACKEND_ANNOTATION`].
+/// The layers are precompiled with it.
+pub const BACKEND_KEY: &str = "backend";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Backend {
+    #[default]
+    Cranelift,
+    Singlepass,
+    Llvm,
+}
+
+impl Backend {
+    const ALL: [Self; 3] = [Self::Cranelift, Self::Singlepass, Self::Llvm];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Cranelift => "cranelift",
+            Self::Singlepass => "singlepass",
+            Self::Llvm => "llvm",
+        }
+    }
+
+    fn is_available(self) -> bool {
+        match self {
+            Self::Cranelift => true,
+            Self::Singlepass => cfg!(feature = "singlepass"),
+            Self::Llvm => cfg!(feature = "llvm"),
+        }
+    }
+
+    /// Returns the backend of the shim, from the [`BACKEND_KEY`] of its `config`.
+    pub fn from_config(config: &ShimConfig) -> Result<Self> {
+        match config.engine_value(BACKEND_KEY) {
+            Some(value) => Self::parse(&value)
+                .with_context(|| format!("invalid `engine.{BACKEND_KEY}` value {value:?}")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Returns the backend of a container with the `value` of its [`BACKEND_ANNOTATION`], or
+    /// else the one of the shim with `config`.
+    pub fn from_annotation(value: Option<&str>, config: &ShimConfig) -> Result<Self> {
+        match value {
+            Some(value) => Self::parse(value)
+                .with_context(|| format!("invalid {BACKEND_ANNOTATION} annotation: {value:?}")),
+            None => Self::from_config(config),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        let Some(backend) = Self::ALL.into_iter().find(|b| b.name() == value) else {
+            bail!(
+                "unknown wasmer backend {value:?}, expected one of cranelift, singlepass or llvm"
+            );
+        };
+        if !backend.is_available() {
+            let available: Vec<_> = Self::ALL
+                .into_iter()
+                .filter(|b| b.is_available())
+                .map(Self::name)
+                .collect();
+            bail!(
+                "wasmer backend {backend} is not built into this shim, the available ones are {}",
+                available.join(", ")
+            );
+        }
+        Ok(backend)
+    }
+
+    /// Returns an engine compiling with the backend.
+    pub fn engine(self) -> wasmer::Engine {
+        match self {
+            Self::Cranelift => wasmer::Cranelift::default().into(),
+            #[cfg(feature = "singlepass")]
+            Self::Singlepass => wasmer::Singlepass::default().into(),
+            #[cfg(feature = "llvm")]
+            Self::Llvm => wasmer::LLVM::default().into(),
+            #[cfg(not(all(feature = "singlepass", feature = "llvm")))]
+            backend => unreachable!("the unavailable backend {backend} is never parsed"),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_annotation() -> Result<()> {
+        let config = &ShimConfig::default();
+        assert_eq!(Backend::from_annotation(None, config)?, Backend::Cranelift);
+        assert_eq!(
+            Backend::from_annotation(Some("cranelift"), config)?,
+            Backend::Cranelift
+        );
+
+        let err = Backend::from_annotation(Some("v8"), config).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unknown wasmer backend"),
+            "{err:#}"
+        );
+
+        // the backends that aren't built in list the ones that are
+        for backend in [Backend::Singlepass, Backend::Llvm] {
+            match Backend::from_annotation(Some(backend.name()), config) {
+                Ok(parsed) => assert!(parsed.is_available() && parsed == backend),
+                Err(err) => {
+                    assert!(!backend.is_available());
+                    assert!(
+                        format!("{err:#}").contains("the available ones are cranelift"),
+                        "{err:#}"
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_config() -> Result<()> {
+        let config = |content: &str| -> Result<ShimConfig> {
+            Ok(ShimConfig::parse(content, &[BACKEND_KEY])?.0)
+        };
+        assert_eq!(
+            Backend::from_config(&ShimConfig::default())?,
+            Backend::Cranelift
+        );
+
+        let v8 = config("[engine]\nbackend = \"v8\"")?;
+        let err = Backend::from_config(&v8).unwrap_err();
+        assert!(format!("{err:#}").contains("`engine.backend`"), "{err:#}");
+        // the annotation wins over the backend of the shim
+        assert_eq!(
+            Backend::from_annotation(Some("cranelift"), &v8)?,
+            Backend::Cranelift
+        );
+
+        Ok(())
+    }
+}
@@ -1,17 +1,36 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
 use anyhow::Result;
-use containerd_shim_wasm::sandbox::Sandbox;
-use containerd_shim_wasm::sandbox::context::{Entrypoint, RuntimeContext};
-use containerd_shim_wasm::shim::{Shim, Version, version};
+use containerd_shim_wasm::sandbox::context::{
+    Capabilities, Capability, Entrypoint, RuntimeContext, WasmLayer, WasmLayerKind,
+};
+use containerd_shim_wasm::sandbox::{Sandbox, check_entrypoint};
+use containerd_shim_wasm::shim::{Compiler, EngineVersion, Shim, ShimConfig, Version, version};
 use tokio::runtime::Handle;
 use wasmer::{Module, Store};
 use wasmer_wasix::virtual_fs::host_fs::FileSystem;
 use wasmer_wasix::{WasiEnv, WasiError};
 
+use crate::backend::{BACKEND_ANNOTATION, BACKEND_KEY, Backend};
+
 pub struct WasmerShim;
 
-#[derive(Default)]
 pub struct WasmerSandbox {
-    engine: wasmer::Cranelift,
+    engine: wasmer::Engine,
+}
+
+impl Default for WasmerSandbox {
+    fn default() -> Self {
+        Self {
+            engine: Backend::default().engine(),
+        }
+    }
+}
+
+pub struct WasmerCompiler {
+    engine: wasmer::Engine,
+    backend: Backend,
 }
 
 impl Shim for WasmerShim {
@@ -23,10 +42,106 @@ impl Shim for WasmerShim {
         version!()
     }
 
+    fn engine_version() -> EngineVersion {
+        // wasmer only runs core modules, with the features of its defaults
+        EngineVersion {
+            name: Self::name().to_string(),
+            version: wasmer::VERSION.to_string(),
+            features: vec![],
+        }
+    }
+
     type Sandbox = WasmerSandbox;
+
+    fn engine_config_keys() -> &'static [&'static str] {
+        &[BACKEND_KEY]
+    }
+
+    fn check_config(config: &ShimConfig) -> Result<()> {
+        Backend::from_config(config)?;
+        Ok(())
+    }
+
+    #[allow(refining_impl_trait)]
+    async fn compiler(config: &ShimConfig) -> Option<WasmerCompiler> {
+        let backend = Backend::from_config(config).expect("invalid wasmer backend");
+        Some(WasmerCompiler {
+            engine: backend.engine(),
+            backend,
+        })
+    }
+
+    fn precompiles(annotations: &HashMap<String, String>) -> bool {
+        // the layers are precompiled with the backend of the shim
+        let annotation = annotations.get(BACKEND_ANNOTATION).map(String::as_str);
+        let config = ShimConfig::get();
+        Backend::from_annotation(annotation, config).ok() == Backend::from_config(config).ok()
+    }
+
+    fn supported_capabilities() -> Capabilities {
+        // the guests have no networking, which isn't configured
+        [Capability::FsWrite, Capability::Env].into_iter().collect()
+    }
+
+    fn reports_guest_started() -> bool {
+        true
+    }
+}
+
+impl Compiler for WasmerCompiler {
+    fn cache_key(&self) -> impl Hash {
+        // the artifacts of different backends and hosts never share a cache key
+        (
+            WasmerShim::engine_version(),
+            self.backend,
+            self.engine.deterministic_id().to_string(),
+        )
+    }
+
+    async fn compile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut compiled_layers = Vec::with_capacity(layers.len());
+
+        for layer in layers {
+            let kind = layer
+                .kind
+                .or_else(|| WasmLayerKind::from_bytes(&layer.layer));
+            if kind != Some(WasmLayerKind::CoreModule) {
+                // wasmer only runs core modules, and the others are already precompiled
+                compiled_layers.push(None);
+                continue;
+            }
+
+            let engine = self.engine.clone();
+            let wasm = layer.layer.clone();
+            let compiled_layer = tokio::task::spawn_blocking(move || {
+                let module = Module::from_binary(&engine, &wasm)?;
+                anyhow::Ok(module.serialize()?.to_vec())
+            })
+            .await??;
+
+            compiled_layers.push(Some(compiled_layer));
+        }
+
+        Ok(compiled_layers)
+    }
 }
 
 impl Sandbox for WasmerSandbox {
+    fn new(ctx: &impl RuntimeContext) -> Self {
+        // an invalid backend fails the container in `can_handle`
+        let backend =
+            Backend::from_annotation(ctx.annotation(BACKEND_ANNOTATION), ShimConfig::get())
+                .unwrap_or_default();
+        Self {
+            engine: backend.engine(),
+        }
+    }
+
+    async fn can_handle(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        Backend::from_annotation(ctx.annotation(BACKEND_ANNOTATION), ShimConfig::get())?;
+        check_entrypoint(ctx)
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx
@@ -50,20 +165,42 @@ impl Sandbox for WasmerSandbox {
         let mut store = Store::new(self.engine.clone());
 
         let wasm_bytes = source.as_bytes()?;
-        let module = Module::from_binary(&store, &wasm_bytes)?;
+        let module = match source.kind(&wasm_bytes) {
+            Some(WasmLayerKind::Precompiled { .. }) => {
+                log::info!("using precompiled module");
+                unsafe { Module::deserialize(&store, wasm_bytes.into_owned()) }?
+            }
+            _ => Module::from_binary(&store, &wasm_bytes)?,
+        };
 
         log::info!("Creating `WasiEnv`...: args {args:?}, envs: {envs:?}");
         let fs = FileSystem::new(Handle::current(), "/")?;
-        let (instance, wasi_env) = WasiEnv::builder(mod_name)
+        let fs_write = !ctx.root_read_only();
+        let mut builder = WasiEnv::builder(mod_name)
             .args(&args[1..])
             .envs(envs)
             .fs(Box::new(fs))
-            .preopen_dir("/")?
-            .instantiate(module, &mut store)?;
+            .preopen_build(|dir| {
+                dir.directory("/")
+                    .read(true)
+                    .write(fs_write)
+                    .create(fs_write)
+            })?;
+        for preopen in ctx.preopens() {
+            let writable = !preopen.read_only;
+            builder = builder.preopen_build(|dir| {
+                dir.directory(&preopen.path)
+                    .read(true)
+                    .write(writable)
+                    .create(writable)
+            })?;
+        }
+        let (instance, wasi_env) = builder.instantiate(module, &mut store)?;
 
         log::info!("Running {func:?}");
         let start = instance.exports.get_function(&func)?;
         wasi_env.data(&store).thread.set_status_running();
+        ctx.guest_started();
         let status = tokio::task::block_in_place(|| {
             start.call(&mut store, &[]).map(|_| 0).or_else(|err| {
                 match err.downcast_ref::<WasiError>() {
@@ -1,5 +1,7 @@
+mod backend;
 pub mod instance;
 
+pub use backend::{BACKEND_ANNOTATION, BACKEND_KEY, Backend};
 pub use instance::WasmerShim;
 
 #[cfg(unix)]
@@ -0,0 +1,55 @@
+//! Compares the latency of instantiating a guest with the pooling allocator of wasmtime, which
+//! the shim uses by default, and with the allocation of the instances on demand.
+//!
+//! Every iteration creates a store, instantiates the guest in it and drops it, like the shim
+//! does for every request of a `wasi/http` server.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, Linker, Module, PoolingAllocationConfig, Store,
+};
+
+// A guest with a linear memory and a table, like most modules compiled from C or Rust
+const GUEST: &str = r#"
+(module
+  (memory (export "memory") 17)
+  (table 16 funcref)
+  (data (i32.const 1024) "hello")
+  (func (export "_start")))
+"#;
+
+fn engine(strategy: InstanceAllocationStrategy) -> Engine {
+    let mut config = Config::new();
+    config.allocation_strategy(strategy);
+    Engine::new(&config).expect("failed to create the engine")
+}
+
+fn bench_instantiation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instantiation");
+    let strategies = [
+        ("on-demand", InstanceAllocationStrategy::OnDemand),
+        (
+            "pooling",
+            InstanceAllocationStrategy::Pooling(PoolingAllocationConfig::default()),
+        ),
+    ];
+    for (name, strategy) in strategies {
+        let engine = engine(strategy);
+        let module = Module::new(&engine, GUEST).expect("failed to compile the guest");
+        let guest = Linker::<()>::new(&engine)
+            .instantiate_pre(&module)
+            .expect("failed to link the guest");
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut store = Store::new(&engine, ());
+                guest
+                    .instantiate(&mut store)
+                    .expect("failed to instantiate the guest");
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_instantiation);
+criterion_main!(benches);
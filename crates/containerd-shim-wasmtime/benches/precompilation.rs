@@ -0,0 +1,97 @@
+//! Compares the precompilation of the layers of a 4-layer image in a single `compile` call,
+//! like the shim does for the compilers that don't opt in to compiling per layer, and in one
+//! concurrent call per layer, like it does for wasmtime.
+//!
+//! The concurrent calls should be about 4 times faster on a machine with at least 4 CPUs.
+
+use std::sync::Arc;
+
+use containerd_shim_wasm::sandbox::context::{WasmLayer, WasmLayerKind};
+use containerd_shim_wasm::shim::{Compiler, Shim, ShimConfig};
+use containerd_shim_wasmtime::WasmtimeShim;
+use criterion::{Criterion, criterion_group, criterion_main};
+use oci_spec::image::{Descriptor, Digest, MediaType};
+use tokio::runtime::Builder;
+use tokio::task::JoinSet;
+
+const LAYERS: usize = 4;
+
+// A module with enough functions that compiling it takes a few milliseconds
+fn guest(seed: usize) -> Vec<u8> {
+    let mut wat = String::from("(module");
+    for i in 0..200 {
+        let factor = seed * 1000 + i;
+        wat.push_str(&format!(
+            r#"(func (export "f{i}") (param i32) (result i32)
+                 (local i32)
+                 (loop $l
+                   (local.set 1 (i32.add (local.get 1) (i32.mul (local.get 0) (i32.const {factor}))))
+                   (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+                   (br_if $l (local.get 0)))
+                 (local.get 1))"#
+        ));
+    }
+    wat.push(')');
+    wat::parse_str(wat).expect("failed to parse the guest")
+}
+
+fn layer(seed: usize) -> WasmLayer {
+    let bytes = guest(seed);
+    // the compiler doesn't check the digest
+    let digest = format!("sha256:{seed:064x}");
+    WasmLayer {
+        config: Descriptor::new(
+            MediaType::Other("application/wasm".to_string()),
+            bytes.len() as u64,
+            digest.parse::<Digest>().expect("invalid digest"),
+        ),
+        layer: bytes.into(),
+        wasm_config: None,
+        image_config: None,
+        run_config: None,
+        kind: Some(WasmLayerKind::CoreModule),
+    }
+}
+
+fn bench_precompilation(c: &mut Criterion) {
+    // the compiler runs on the blocking threads of the runtime, so a single worker is enough
+    let runtime = Builder::new_current_thread()
+        .build()
+        .expect("failed to create the runtime");
+    let compiler = Arc::new(
+        runtime
+            .block_on(WasmtimeShim::compiler(ShimConfig::get()))
+            .expect("wasmtime has a compiler"),
+    );
+    let layers: Vec<_> = (0..LAYERS).map(layer).collect();
+
+    let mut group = c.benchmark_group("precompilation");
+    group.sample_size(10);
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(compiler.compile(&layers))
+                .expect("failed to compile the layers")
+        })
+    });
+    group.bench_function("per-layer", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut compilations = JoinSet::new();
+                for layer in layers.iter().cloned() {
+                    let compiler = compiler.clone();
+                    compilations.spawn(async move { compiler.compile(&[layer]).await });
+                }
+                while let Some(compiled) = compilations.join_next().await {
+                    compiled
+                        .expect("the compilation panicked")
+                        .expect("failed to compile the layer");
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_precompilation);
+criterion_main!(benches);
@@ -0,0 +1,445 @@
+//! Checkpoints of the core modules the shim runs, see `Sandbox::checkpoint`.
+//!
+//! A checkpoint request bumps the epoch of the engine, and the epoch deadline callback of the
+//! store of the guest saves its exported linear memories, the values of its exported mutable
+//! globals and the sizes of its exported tables before resuming it. Wasmtime can't capture the
+//! call stack of the guest, so a restored guest is a new instance of the module with the saved
+//! state, whose entrypoint is called again. Its state that isn't exported, e.g., its
+//! `__stack_pointer`, is the one of a new instance, and the elements of its tables are the ones
+//! of the elem segments of the module.
+//!
+//! Files the guest opened since it started can't be restored, and block the checkpoint.
+
+use std::collections::BTreeSet;
+use std::fs::{read, read_dir, read_link, write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail, ensure};
+use containerd_shim_wasm::sandbox::CheckpointBlocked;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use wasmtime::{AsContextMut, Extern, Instance, Mutability, Ref, Store, Trap, UpdateDeadline, Val};
+
+const STATE_FILE: &str = "state.json";
+
+// The files of the host that the guest can't open, e.g., the memfds of wasmtime
+const HOST_FILES: [&str; 3] = ["/proc/", "/dev/", "/memfd:"];
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct State {
+    memories: Vec<MemoryState>,
+    globals: Vec<GlobalState>,
+    tables: Vec<TableState>,
+}
+
+// The data of the memory with the index `i` is saved in `memory-<i>`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct MemoryState {
+    name: String,
+    pages: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct GlobalState {
+    name: String,
+    value: Value,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TableState {
+    name: String,
+    size: u64,
+}
+
+// The values of the globals that can be saved, the floats as their bits
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Value {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl From<Value> for Val {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::I32(v) => Val::I32(v),
+            Value::I64(v) => Val::I64(v),
+            Value::F32(v) => Val::F32(v),
+            Value::F64(v) => Val::F64(v),
+        }
+    }
+}
+
+/// The checkpoints of the guest of a sandbox, served by the store of the guest.
+#[derive(Default)]
+pub(crate) struct Checkpoints {
+    running: Mutex<Running>,
+    // whether the guest was interrupted, which the epoch deadline callback traps the guest on
+    interrupted: AtomicBool,
+    // whether the result of a checkpoint wasn't received yet, which the guest yields until, as
+    // it may run on the thread of the request
+    answering: AtomicBool,
+}
+
+#[derive(Default)]
+enum Running {
+    #[default]
+    Nothing,
+    Component,
+    Module(Option<Request>),
+}
+
+struct Request {
+    dir: PathBuf,
+    done: oneshot::Sender<Result<()>>,
+}
+
+impl Checkpoints {
+    /// Records that the guest is a component, whose state can't be saved.
+    pub(crate) fn running_component(&self) {
+        *self.running.lock().unwrap() = Running::Component;
+    }
+
+    /// Serves the checkpoints of the `instance` of a core module from the epoch deadline
+    /// callback of its `store`. The files the guest opens in the `dirs` of the host after this
+    /// block the checkpoints.
+    pub(crate) fn serve<T>(
+        self: &Arc<Self>,
+        store: &mut Store<T>,
+        instance: Instance,
+        dirs: Vec<PathBuf>,
+    ) {
+        let opened = open_files();
+        *self.running.lock().unwrap() = Running::Module(None);
+
+        let checkpoints = self.clone();
+        store.epoch_deadline_callback(move |mut store| {
+            let request = match &mut *checkpoints.running.lock().unwrap() {
+                Running::Module(request) => request.take(),
+                _ => None,
+            };
+            if let Some(Request { dir, done }) = request {
+                let blockers = open_files()
+                    .difference(&opened)
+                    .filter(|(_, path)| dirs.iter().any(|dir| path.starts_with(dir)))
+                    .filter(|(_, path)| {
+                        let path = path.to_string_lossy();
+                        !HOST_FILES.iter().any(|prefix| path.starts_with(prefix))
+                    })
+                    .map(|(fd, path)| format!("file {path:?} opened on fd {fd}"))
+                    .collect();
+                let res = save(&mut store, instance, blockers, &dir);
+                // the request was dropped if the guest is checkpointed as it exits
+                if done.send(res).is_ok() {
+                    checkpoints.answering.store(true, Ordering::SeqCst);
+                }
+            }
+            if checkpoints.interrupted.load(Ordering::SeqCst) {
+                return Err(Trap::Interrupt.into());
+            }
+            // a deadline of 0 ticks is already reached at the next epoch check
+            let ticks = if checkpoints.answering.load(Ordering::SeqCst) {
+                0
+            } else {
+                1
+            };
+            Ok(UpdateDeadline::Yield(ticks))
+        });
+    }
+
+    /// Makes the guest trap at its next epoch check, see `Sandbox::terminate`.
+    pub(crate) fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    /// Saves the state of the guest into `dir`, once the guest reaches its next epoch check.
+    pub(crate) async fn checkpoint(&self, engine: &wasmtime::Engine, dir: &Path) -> Result<()> {
+        let (done, saved) = oneshot::channel();
+        match &mut *self.running.lock().unwrap() {
+            Running::Nothing => bail!("the guest isn't running"),
+            Running::Component => {
+                return Err(CheckpointBlocked {
+                    blockers: vec!["the state of the component".to_string()],
+                }
+                .into());
+            }
+            Running::Module(Some(_)) => bail!("the guest is already being checkpointed"),
+            Running::Module(request) => {
+                *request = Some(Request {
+                    dir: dir.to_path_buf(),
+                    done,
+                })
+            }
+        }
+        engine.increment_epoch();
+        let res = saved.await;
+        self.answering.store(false, Ordering::SeqCst);
+        res.context("the guest exited before it was checkpointed")?
+    }
+}
+
+// Returns the files the process has open, by fd
+fn open_files() -> BTreeSet<(String, PathBuf)> {
+    let Ok(entries) = read_dir("/proc/self/fd") else {
+        return BTreeSet::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = read_link(entry.path()).ok()?;
+            Some((entry.file_name().to_string_lossy().into_owned(), path))
+        })
+        .collect()
+}
+
+// Saves the exported state of the `instance` into `dir`, unless some of it can't be saved, or
+// there are other `blockers`
+fn save(
+    mut store: impl AsContextMut,
+    instance: Instance,
+    mut blockers: Vec<String>,
+    dir: &Path,
+) -> Result<()> {
+    let exports: Vec<_> = instance
+        .exports(&mut store)
+        .map(|export| (export.name().to_string(), export.into_extern()))
+        .collect();
+
+    let mut state = State::default();
+    let mut memories = vec![];
+    for (name, export) in exports {
+        match export {
+            Extern::Memory(memory) => {
+                let pages = memory.size(&store);
+                state.memories.push(MemoryState { name, pages });
+                memories.push(memory);
+            }
+            Extern::SharedMemory(_) => blockers.push(format!("shared memory {name:?}")),
+            Extern::Global(global) if global.ty(&store).mutability() == Mutability::Var => {
+                let value = match global.get(&mut store) {
+                    Val::I32(v) => Value::I32(v),
+                    Val::I64(v) => Value::I64(v),
+                    Val::F32(v) => Value::F32(v),
+                    Val::F64(v) => Value::F64(v),
+                    _ => {
+                        let ty = global.ty(&store);
+                        blockers.push(format!("global {name:?} of type {}", ty.content()));
+                        continue;
+                    }
+                };
+                state.globals.push(GlobalState { name, value });
+            }
+            Extern::Table(table) => {
+                let size = table.size(&store);
+                state.tables.push(TableState { name, size });
+            }
+            _ => {}
+        }
+    }
+    if !blockers.is_empty() {
+        return Err(CheckpointBlocked { blockers }.into());
+    }
+
+    for (i, memory) in memories.iter().enumerate() {
+        let path = dir.join(format!("memory-{i}"));
+        write(&path, memory.data(&store)).with_context(|| format!("failed to write {path:?}"))?;
+    }
+    let path = dir.join(STATE_FILE);
+    write(&path, serde_json::to_vec(&state)?)
+        .with_context(|| format!("failed to write {path:?}"))?;
+    log::info!(
+        "checkpointed {} memories, {} globals and {} tables of the guest",
+        state.memories.len(),
+        state.globals.len(),
+        state.tables.len()
+    );
+    Ok(())
+}
+
+/// Restores the state saved into `dir` in the new `instance` of the module it was saved from.
+pub(crate) fn restore<T>(store: &mut Store<T>, instance: Instance, dir: &Path) -> Result<()> {
+    let path = dir.join(STATE_FILE);
+    let state = read(&path).with_context(|| format!("failed to read {path:?}"))?;
+    let state: State = serde_json::from_slice(&state).context("invalid checkpoint")?;
+
+    for (i, saved) in state.memories.into_iter().enumerate() {
+        let Some(memory) = instance.get_memory(&mut *store, &saved.name) else {
+            bail!("the module has no memory {:?} to restore", saved.name);
+        };
+        let pages = memory.size(&*store);
+        if saved.pages > pages {
+            memory.grow(&mut *store, saved.pages - pages)?;
+        }
+        let path = dir.join(format!("memory-{i}"));
+        let data = read(&path).with_context(|| format!("failed to read {path:?}"))?;
+        let memory = memory.data_mut(&mut *store);
+        ensure!(
+            data.len() == memory.len(),
+            "the memory {:?} of the module isn't the one of the checkpoint",
+            saved.name
+        );
+        memory.copy_from_slice(&data);
+    }
+
+    for saved in state.globals {
+        let Some(global) = instance.get_global(&mut *store, &saved.name) else {
+            bail!("the module has no global {:?} to restore", saved.name);
+        };
+        global.set(&mut *store, saved.value.into())?;
+    }
+
+    for saved in state.tables {
+        let Some(table) = instance.get_table(&mut *store, &saved.name) else {
+            bail!("the module has no table {:?} to restore", saved.name);
+        };
+        let size = table.size(&*store);
+        if saved.size > size {
+            let init = Ref::null(table.ty(&*store).element().heap_type());
+            table.grow(&mut *store, saved.size - size, init)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Config, Engine, Module};
+
+    use super::*;
+
+    const COUNTER: &str = r#"(module
+        (memory (export "memory") 1)
+        (global $count (export "counter") (mut i64) (i64.const 0))
+        (global $ratio (export "ratio") (mut f64) (f64.const 0.5))
+        (table (export "table") 1 funcref)
+        (func (export "count") (result i64)
+            (i32.store (i32.const 16) (i32.add (i32.load (i32.const 16)) (i32.const 1)))
+            (global.set $count (i64.add (global.get $count) (i64.const 1)))
+            (global.get $count))
+        (func (export "grow")
+            (drop (memory.grow (i32.const 1)))
+            (drop (table.grow (ref.null func) (i32.const 2)))
+            (global.set $ratio (f64.const 1.5)))
+        (func (export "spin")
+            (loop (call 0) (drop) (br 0))))"#;
+
+    fn engine() -> Engine {
+        let mut config = Config::new();
+        config.async_support(true).epoch_interruption(true);
+        Engine::new(&config).unwrap()
+    }
+
+    async fn instantiate(engine: &Engine) -> Result<(Store<()>, Instance)> {
+        let module = Module::new(engine, COUNTER)?;
+        let mut store = Store::new(engine, ());
+        store.set_epoch_deadline(1);
+        let instance = Instance::new_async(&mut store, &module, &[]).await?;
+        Ok((store, instance))
+    }
+
+    async fn call<R: wasmtime::WasmResults>(
+        store: &mut Store<()>,
+        instance: Instance,
+        name: &str,
+    ) -> Result<R> {
+        let func = instance.get_typed_func::<(), R>(&mut *store, name)?;
+        func.call_async(&mut *store, ()).await
+    }
+
+    #[tokio::test]
+    async fn test_save_and_restore() -> Result<()> {
+        let engine = engine();
+        let dir = tempfile::tempdir()?;
+
+        let (mut store, instance) = instantiate(&engine).await?;
+        for _ in 0..3 {
+            call::<i64>(&mut store, instance, "count").await?;
+        }
+        call::<()>(&mut store, instance, "grow").await?;
+        save(&mut store, instance, vec![], dir.path())?;
+
+        let (mut store, instance) = instantiate(&engine).await?;
+        restore(&mut store, instance, dir.path())?;
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        assert_eq!(memory.size(&store), 2);
+        assert_eq!(memory.data(&store)[16], 3);
+        let table = instance.get_table(&mut store, "table").unwrap();
+        assert_eq!(table.size(&store), 3);
+        let ratio = instance.get_global(&mut store, "ratio").unwrap();
+        assert_eq!(ratio.get(&mut store).unwrap_f64(), 1.5);
+
+        // the counters continue from the checkpoint
+        assert_eq!(call::<i64>(&mut store, instance, "count").await?, 4);
+        assert_eq!(memory.data(&store)[16], 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_blocked() -> Result<()> {
+        let engine = engine();
+        let dir = tempfile::tempdir()?;
+
+        let (mut store, instance) = instantiate(&engine).await?;
+        let blockers = vec!["file \"/data\" opened on fd 5".to_string()];
+        let err = save(&mut store, instance, blockers.clone(), dir.path()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CheckpointBlocked>(),
+            Some(&CheckpointBlocked { blockers })
+        );
+        assert_eq!(read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_running_guest() -> Result<()> {
+        let engine = engine();
+        let dir = tempfile::tempdir()?;
+        let checkpoints = Arc::<Checkpoints>::default();
+
+        let (mut store, instance) = instantiate(&engine).await?;
+        for _ in 0..3 {
+            call::<i64>(&mut store, instance, "count").await?;
+        }
+        checkpoints.serve(&mut store, instance, vec![]);
+        // the guest only yields to the checkpoint in the same task, as in the executor
+        let (checkpoint, guest) = tokio::join!(
+            async {
+                let res = checkpoints.checkpoint(&engine, dir.path()).await;
+                checkpoints.interrupt();
+                engine.increment_epoch();
+                res
+            },
+            call::<()>(&mut store, instance, "spin"),
+        );
+        checkpoint?;
+        let err = guest.unwrap_err();
+        assert_eq!(err.downcast_ref::<Trap>(), Some(&Trap::Interrupt));
+
+        let (mut store, instance) = instantiate(&engine).await?;
+        restore(&mut store, instance, dir.path())?;
+        let count = call::<i64>(&mut store, instance, "count").await?;
+        assert!(count > 3, "the guest counted {count} times");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_not_running() {
+        let engine = engine();
+        let checkpoints = Checkpoints::default();
+        let err = checkpoints.checkpoint(&engine, Path::new("/")).await;
+        assert!(err.is_err());
+
+        checkpoints.running_component();
+        let err = checkpoints.checkpoint(&engine, Path::new("/")).await;
+        assert!(
+            err.unwrap_err()
+                .downcast_ref::<CheckpointBlocked>()
+                .is_some()
+        );
+    }
+}
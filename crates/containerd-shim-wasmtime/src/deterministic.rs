@@ -0,0 +1,115 @@
+//! Deterministic clocks and random numbers of the guests, for the
+//! [`DETERMINISTIC_ANNOTATION`](containerd_shim_wasm::sandbox::context::DETERMINISTIC_ANNOTATION).
+//!
+//! The wall and the monotonic clocks share a virtual time, which starts at zero, i.e., at the unix
+//! epoch for the wall clock, and advances by the tick every time the guest reads either of them.
+//! The random numbers, including the ones of `wasi:random/random`, are generated from the seed.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use containerd_shim_wasm::sandbox::context::Deterministic;
+use rand_core::{RngCore, impls};
+use wasmtime_wasi::{HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+/// Replaces the clocks and the random numbers of the contexts `builder` builds with deterministic ones.
+pub(crate) fn make_deterministic(builder: &mut WasiCtxBuilder, deterministic: &Deterministic) {
+    let clock = VirtualClock {
+        now: Arc::default(),
+        tick: u64::try_from(deterministic.tick.as_nanos()).unwrap_or(u64::MAX),
+    };
+    builder
+        .wall_clock(clock.clone())
+        .monotonic_clock(clock)
+        .secure_random(SeededRandom(deterministic.seed))
+        .insecure_random(SeededRandom(!deterministic.seed))
+        .insecure_random_seed(deterministic.seed.into());
+}
+
+#[derive(Clone)]
+struct VirtualClock {
+    // in nanoseconds
+    now: Arc<AtomicU64>,
+    tick: u64,
+}
+
+impl VirtualClock {
+    fn advance(&self) -> u64 {
+        self.now.fetch_add(self.tick, Ordering::Relaxed) + self.tick
+    }
+}
+
+impl HostWallClock for VirtualClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(self.tick)
+    }
+
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.advance())
+    }
+}
+
+impl HostMonotonicClock for VirtualClock {
+    fn resolution(&self) -> u64 {
+        self.tick
+    }
+
+    fn now(&self) -> u64 {
+        self.advance()
+    }
+}
+
+/// Random numbers generated by SplitMix64 from a seed. Guests that ask to be deterministic want
+/// reproducible numbers rather than secret ones.
+struct SeededRandom(u64);
+
+impl RngCore for SeededRandom {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_random() {
+        let bytes = |seed| {
+            let mut bytes = [0; 32];
+            SeededRandom(seed).fill_bytes(&mut bytes);
+            bytes
+        };
+        assert_eq!(bytes(42), bytes(42));
+        assert_ne!(bytes(42), bytes(43));
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock {
+            now: Arc::default(),
+            tick: 1_000,
+        };
+        assert_eq!(HostWallClock::now(&clock), Duration::from_micros(1));
+        assert_eq!(HostMonotonicClock::now(&clock), 2_000);
+        assert_eq!(HostWallClock::now(&clock.clone()), Duration::from_micros(3));
+    }
+}
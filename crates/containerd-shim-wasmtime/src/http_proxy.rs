@@ -7,27 +7,42 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
-use anyhow::{Result, bail};
-use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use anyhow::{Context as _, Result, bail};
+use containerd_shim_wasm::sandbox::context::{Capability, Deterministic, RuntimeContext, Source};
+use containerd_shim_wasm::sandbox::dns::Resolver;
 use hyper::server::conn::http1;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use wasmtime::Store;
 use wasmtime::component::ResourceTable;
+use wasmtime::{Store, UpdateDeadline};
 use wasmtime_wasi_http::bindings::ProxyPre;
 use wasmtime_wasi_http::bindings::http::types::Scheme;
 use wasmtime_wasi_http::body::HyperOutgoingBody;
 use wasmtime_wasi_http::io::TokioIo;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
+use crate::deterministic::make_deterministic;
 use crate::instance::{WasiPreview2Ctx, envs_from_ctx};
+use crate::keyvalue::KeyValue;
+use crate::limits::{MemoryLimiter, limit_memory};
+use crate::logging::GuestLogger;
+use crate::wasi_config::GuestConfig;
 
 const DEFAULT_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 8080);
 
 const DEFAULT_BACKLOG: u32 = 100;
 
+/// Annotation with the address to serve HTTP on, e.g., `0.0.0.0:8080`. It defaults to the
+/// `WASMTIME_HTTP_PROXY_SOCKET_ADDR` env var, then to the first TCP port exposed by the image.
+const LISTEN_ANNOTATION: &str = "io.runwasi.http.listen";
+
+/// Annotation with the maximum number of requests handled at the same time.
+/// Further requests wait for one of them to complete. There is no limit by default.
+const MAX_IN_FLIGHT_ANNOTATION: &str = "io.runwasi.http.max-in-flight";
+
 type Request = hyper::Request<hyper::body::Incoming>;
 
 fn is_connection_error(e: &std::io::Error) -> bool {
@@ -74,36 +89,59 @@ pub(crate) async fn serve_conn(
     // Consume env variables for Proxy server settings before passing it to handler
     let addr = env
         .remove("WASMTIME_HTTP_PROXY_SOCKET_ADDR")
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_ADDR);
+        .and_then(|v| v.parse().ok());
+    let addr = match ctx.annotation(LISTEN_ANNOTATION) {
+        Some(addr) => addr
+            .parse()
+            .with_context(|| format!("invalid {LISTEN_ANNOTATION} annotation: {addr:?}"))?,
+        None => addr
+            .or_else(|| exposed_port(ctx).map(|port| SocketAddr::new(DEFAULT_ADDR.ip(), port)))
+            .unwrap_or(DEFAULT_ADDR),
+    };
     let backlog = env
         .remove("WASMTIME_HTTP_BACKLOG")
         .and_then(|v| v.parse().ok())
         .unwrap_or(DEFAULT_BACKLOG);
-
-    let socket = match addr {
-        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    let max_in_flight = ctx
+        .annotation(MAX_IN_FLIGHT_ANNOTATION)
+        .map(|max| {
+            max.parse::<usize>()
+                .ok()
+                .filter(|max| *max > 0)
+                .with_context(|| format!("invalid {MAX_IN_FLIGHT_ANNOTATION} annotation: {max:?}"))
+        })
+        .transpose()?;
+
+    let listener = match ctx.listeners().take(addr.port()) {
+        // the shim bound the port on all the addresses already, with the backlog of std
+        Some(listener) if addr.ip().is_unspecified() => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        // dropping the listener of the shim frees the port for the address
+        _ => bind(addr, backlog)?,
     };
-
-    // Conditionally enable `SO_REUSEADDR` depending on the current
-    // platform. On Unix we want this to be able to rebind an address in
-    // the `TIME_WAIT` state which can happen then a server is killed with
-    // active TCP connections and then restarted. On Windows though if
-    // `SO_REUSEADDR` is specified then it enables multiple applications to
-    // bind the port at the same time which is not something we want. Hence
-    // this is conditionally set based on the platform (and deviates from
-    // Tokio's default from always-on).
-    socket.set_reuseaddr(!cfg!(windows))?;
-    socket.bind(addr)?;
-
-    let listener = socket.listen(backlog)?;
     let tracker = TaskTracker::new();
 
     log::info!("Serving HTTP on http://{}/", listener.local_addr()?);
 
     let env = env.into_iter().collect();
-    let handler = Arc::new(ProxyHandler::new(instance, env, tracker.clone()));
+    let in_flight = max_in_flight.map(|max| Arc::new(Semaphore::new(max)));
+    let handler = Arc::new(ProxyHandler {
+        net_outbound: ctx.capabilities().contains(Capability::NetOutbound),
+        resolver: ctx.resolver(),
+        keyvalue: KeyValue::new(ctx)?,
+        config: GuestConfig::new(ctx),
+        ..ProxyHandler::new(
+            instance,
+            env,
+            tracker.clone(),
+            in_flight,
+            ctx.memory_limit(),
+            ctx.deterministic(),
+            GuestLogger::new(ctx)?,
+        )
+    });
 
     loop {
         let stream = tokio::select! {
@@ -120,32 +158,95 @@ pub(crate) async fn serve_conn(
 
         let stream = TokioIo::new(stream);
         let h = handler.clone();
-
-        tracker.spawn(async {
-            if let Err(e) = http1::Builder::new()
-                .keep_alive(true)
-                .serve_connection(
-                    stream,
-                    hyper::service::service_fn(move |req| h.clone().handle_request(req)),
-                )
-                .await
-            {
+        let cancel = cancel.clone();
+
+        tracker.spawn(async move {
+            let conn = http1::Builder::new().keep_alive(true).serve_connection(
+                stream,
+                hyper::service::service_fn(move |req| h.clone().handle_request(req)),
+            );
+            let mut conn = std::pin::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = cancel.cancelled() => {
+                    // finish the request in progress, if any, then close the connection
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = res {
                 log::error!("error: {e:?}");
             }
         });
     }
 
+    // refuse the new connections while the ones in flight drain
+    drop(listener);
+    log::info!("draining HTTP connections");
     tracker.close();
     tracker.wait().await;
 
     Ok(())
 }
 
+// Binds a new listener on `addr`
+fn bind(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+
+    // Conditionally enable `SO_REUSEADDR` depending on the current
+    // platform. On Unix we want this to be able to rebind an address in
+    // the `TIME_WAIT` state which can happen then a server is killed with
+    // active TCP connections and then restarted. On Windows though if
+    // `SO_REUSEADDR` is specified then it enables multiple applications to
+    // bind the port at the same time which is not something we want. Hence
+    // this is conditionally set based on the platform (and deviates from
+    // Tokio's default from always-on).
+    socket.set_reuseaddr(!cfg!(windows))?;
+    socket.bind(addr)?;
+    Ok(socket.listen(backlog)?)
+}
+
+// Returns the first TCP port exposed by the image config of the wasm layers
+fn exposed_port(ctx: &impl RuntimeContext) -> Option<u16> {
+    let Source::Oci(layers) = ctx.entrypoint().source else {
+        return None;
+    };
+    let ports = layers
+        .iter()
+        .find_map(|layer| layer.image_config.as_ref()?.exposed_ports().as_ref())?;
+    ports.iter().find_map(|port| {
+        // ports are exposed as `port/protocol`, or just `port` for TCP
+        let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+        if protocol == "tcp" {
+            port.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
 struct ProxyHandler {
     instance_pre: ProxyPre<WasiPreview2Ctx>,
     next_id: AtomicU64,
     env: Vec<(String, String)>,
     tracker: TaskTracker,
+    // limits the number of requests handled at the same time, if set
+    in_flight: Option<Arc<Semaphore>>,
+    // the memory limit of the guest handling each request, if any
+    memory_limit: Option<u64>,
+    deterministic: Option<Deterministic>,
+    logger: GuestLogger,
+    // whether the guests handling the requests can send outgoing HTTP requests
+    net_outbound: bool,
+    // the resolver of the pod, for the outgoing HTTP requests of the guests
+    resolver: Option<Arc<Resolver>>,
+    // the stores of wasi:keyvalue, shared by the guests
+    keyvalue: KeyValue,
+    // the configuration of wasi:config, shared by the guests
+    config: GuestConfig,
 }
 
 impl ProxyHandler {
@@ -153,11 +254,23 @@ impl ProxyHandler {
         instance_pre: ProxyPre<WasiPreview2Ctx>,
         env: Vec<(String, String)>,
         tracker: TaskTracker,
+        in_flight: Option<Arc<Semaphore>>,
+        memory_limit: Option<u64>,
+        deterministic: Option<Deterministic>,
+        logger: GuestLogger,
     ) -> Self {
         ProxyHandler {
             instance_pre,
             env,
             tracker,
+            in_flight,
+            memory_limit,
+            deterministic,
+            logger,
+            net_outbound: false,
+            resolver: None,
+            keyvalue: KeyValue::default(),
+            config: GuestConfig::default(),
             next_id: AtomicU64::from(0),
         }
     }
@@ -168,14 +281,33 @@ impl ProxyHandler {
 
         builder.envs(&self.env);
         builder.env("REQUEST_ID", req_id.to_string());
+        if let Some(deterministic) = &self.deterministic {
+            // seeded by request, so that the requests don't all get the same random numbers
+            let deterministic = Deterministic {
+                seed: deterministic.seed.wrapping_add(req_id),
+                ..*deterministic
+            };
+            make_deterministic(&mut builder, &deterministic);
+        }
 
         let ctx = WasiPreview2Ctx {
             wasi_ctx: builder.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limiter: self.memory_limit.map(MemoryLimiter::new),
+            logger: self.logger,
+            net_outbound: self.net_outbound,
+            resolver: self.resolver.clone(),
+            keyvalue: self.keyvalue.clone(),
+            config: self.config.clone(),
         };
 
-        Store::new(engine, ctx)
+        let mut store = Store::new(engine, ctx);
+        limit_memory(&mut store, |ctx| &mut ctx.limiter);
+        // Let in-flight requests complete when the instance is terminated,
+        // the server stops accepting new connections instead.
+        store.epoch_deadline_callback(|_| Ok(UpdateDeadline::Continue(1)));
+        store
     }
 
     async fn handle_request(
@@ -184,6 +316,11 @@ impl ProxyHandler {
     ) -> Result<hyper::Response<HyperOutgoingBody>> {
         let (sender, receiver) = tokio::sync::oneshot::channel();
 
+        // held until the guest is done with the request, including streaming the response body
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.clone().acquire_owned().await?),
+            None => None,
+        };
         let req_id = self.next_req_id();
 
         log::trace!(
@@ -199,6 +336,7 @@ impl ProxyHandler {
         let proxy = self.instance_pre.instantiate_async(&mut store).await?;
 
         let task = self.tracker.spawn(async move {
+            let _permit = permit;
             if let Err(e) = proxy
                 .wasi_http_incoming_handler()
                 .call_handle(store, req, out)
@@ -1,24 +1,48 @@
 use std::hash::Hash;
-use std::sync::LazyLock;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 
 use anyhow::{Context, Result, bail};
-use containerd_shim_wasm::sandbox::Sandbox;
 use containerd_shim_wasm::sandbox::context::{
-    Entrypoint, RuntimeContext, WasmBinaryType, WasmLayer,
+    Capabilities, Capability, Listeners, NetAllowlist, RuntimeContext, Source, StackLimits,
+    WasmBinaryType, WasmLayer, WasmLayerKind,
+};
+use containerd_shim_wasm::sandbox::dns::Resolver;
+use containerd_shim_wasm::sandbox::{Sandbox, Validation};
+use containerd_shim_wasm::shim::{
+    Compiler, EngineVersion, Shim, ShimConfig, StackLimitRange, SupportedStackLimits, Version,
+    version,
 };
-use containerd_shim_wasm::shim::{Compiler, Shim, Version, version};
 use tokio_util::sync::CancellationToken;
 use wasi_preview1::WasiP1Ctx;
-use wasi_preview2::bindings::Command;
+use wasi_preview2::bindings::{Command, CommandPre};
 use wasmtime::component::types::ComponentItem;
 use wasmtime::component::{self, Component, ResourceTable};
-use wasmtime::{Config, Module, Precompiled, Store};
+use wasmtime::{Config, ExternType, Module, Precompiled, Store};
 use wasmtime_wasi::preview1::{self as wasi_preview1};
-use wasmtime_wasi::{self as wasi_preview2};
+use wasmtime_wasi::{self as wasi_preview2, SocketAddrUse};
 use wasmtime_wasi_http::bindings::ProxyPre;
-use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+use wasmtime_wasi_http::bindings::http::types::{DnsErrorPayload, ErrorCode};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{
+    HostFutureIncomingResponse, OutgoingRequestConfig, default_send_request,
+    default_send_request_handler,
+};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx, WasiHttpView};
 
+use crate::checkpoint::{self, Checkpoints};
+use crate::deterministic::make_deterministic;
 use crate::http_proxy::serve_conn;
+use crate::keyvalue::{self, KeyValue};
+use crate::limits::{MemoryLimiter, limit_memory};
+use crate::logging::{self, GuestLogger};
+use crate::pooling::Pooling;
+use crate::precompiled::{self, PrecompiledFile};
+use crate::trap::{report_trap, write_coredump};
+use crate::wasi_config::{self, GuestConfig};
 
 /// Represents the WASI API that the component is targeting.
 enum ComponentTarget<'a> {
@@ -35,6 +59,11 @@ impl<'a> ComponentTarget<'a> {
     where
         I: IntoIterator<Item = (&'b str, ComponentItem)> + 'b,
     {
+        // An exported function named in the entrypoint is called whatever the world
+        if func != "_start" {
+            return Self::Core(func);
+        }
+
         // This is heuristic but seems to work
         exports
             .into_iter()
@@ -49,6 +78,21 @@ impl<'a> ComponentTarget<'a> {
             })
             .unwrap_or(Self::Core(func))
     }
+
+    // The WASI world of the target, if it's one
+    fn world(&self) -> Option<&'static str> {
+        match self {
+            Self::Command => Some("wasi:cli/command"),
+            Self::HttpProxy => Some("wasi:http/proxy"),
+            Self::Core(_) => None,
+        }
+    }
+}
+
+/// The entrypoint of a container, loaded by the engine.
+enum Guest {
+    Module(Module),
+    Component(Component),
 }
 
 pub struct WasmtimeShim;
@@ -58,28 +102,92 @@ pub struct WasmtimeCompiler(wasmtime::Engine);
 pub struct WasmtimeSandbox {
     engine: wasmtime::Engine,
     cancel: CancellationToken,
+    checkpoints: Arc<Checkpoints>,
+    terminated: AtomicBool,
 }
 
+/// Engine setting with the CPU features to compile wasm code for, as a comma separated list of
+/// Cranelift ISA flags prefixed with `+` to enable them or `-` to disable them, e.g.,
+/// `-has_avx512f,-has_avx512vl`. Features that aren't listed are detected from the host, so this
+/// is how nodes with different CPUs can share precompiled modules.
+pub const CPU_FEATURES_KEY: &str = "cpu_features";
+
+/// Engine setting with the wasm proposals to enable or disable, in the [`CPU_FEATURES_KEY`]
+/// format, e.g., `+memory64,+multi-memory`. The proposals that can be set are `memory64`,
+/// `multi-memory`, `threads` and `relaxed-simd`, and the ones that aren't listed keep the
+/// defaults of wasmtime.
+pub const WASM_FEATURES_KEY: &str = "wasm_features";
+
+// The setting of the engine for a wasm proposal
+type WasmFeatureSetting = fn(&mut Config, bool) -> &mut Config;
+
+/// The wasm proposals of [`WASM_FEATURES_KEY`], with the setting of the engine for them.
+const WASM_FEATURES: [(&str, WasmFeatureSetting); 4] = [
+    ("memory64", Config::wasm_memory64),
+    ("multi-memory", Config::wasm_multi_memory),
+    ("threads", Config::wasm_threads),
+    ("relaxed-simd", Config::wasm_relaxed_simd),
+];
+
+/// The version of wasmtime the shim is built with, which must follow the one of the workspace.
+const WASMTIME_VERSION: &str = "27.0.0";
+
+/// The default and the range of the size of the stack of the guest, see [`StackLimits`].
+const DEFAULT_MAX_WASM_STACK: usize = 512 * 1024;
+const MAX_WASM_STACK_RANGE: RangeInclusive<usize> = 64 * 1024..=64 * 1024 * 1024;
+
+// The guest runs on the stack of its fiber, which must be larger than the wasm stack
+// to leave room for the host calls
+const ASYNC_STACK_HEADROOM: usize = 2 * 1024 * 1024 - DEFAULT_MAX_WASM_STACK;
+
 impl Default for WasmtimeSandbox {
     fn default() -> Self {
-        let mut config = wasmtime::Config::new();
-
-        // Disable Wasmtime parallel compilation for the tests
-        // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
-        config.parallel_compilation(!cfg!(test));
-        config.wasm_component_model(true); // enable component linking
-        config.async_support(true); // must be on
+        Self::with_config(StackLimits::default(), false, None)
+    }
+}
 
-        if use_pooling_allocator_by_default() {
-            let cfg = wasmtime::PoolingAllocationConfig::default();
+impl WasmtimeSandbox {
+    fn with_config(
+        stack_limits: StackLimits,
+        coredump_on_trap: bool,
+        memory_limit: Option<u64>,
+    ) -> Self {
+        let shim_config = ShimConfig::get();
+        let mut config = engine_config(shim_config)
+            .context("failed to configure wasmtime engine")
+            .unwrap();
+
+        if let Some(max_stack_size) = stack_limits.max_stack_size {
+            config.max_wasm_stack(max_stack_size);
+            config.async_stack_size(max_stack_size + ASYNC_STACK_HEADROOM);
+        }
+        config.coredump_on_trap(coredump_on_trap);
+
+        let pooling = Pooling::new(shim_config)
+            .context("failed to configure the pooling allocator")
+            .unwrap()
+            .config(memory_limit, use_pooling_allocator_by_default);
+        let pooled = pooling.is_some();
+        if let Some(cfg) = pooling {
             config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
         }
 
+        let engine = wasmtime::Engine::new(&config).or_else(|err| {
+            if !pooled {
+                return Err(err);
+            }
+            // e.g., the address space of the shim is limited
+            log::warn!(
+                "failed to reserve the pooling allocator, allocating instances on demand: {err:#}"
+            );
+            config.allocation_strategy(wasmtime::InstanceAllocationStrategy::OnDemand);
+            wasmtime::Engine::new(&config)
+        });
         Self {
-            engine: wasmtime::Engine::new(&config)
-                .context("failed to create wasmtime engine")
-                .unwrap(),
+            engine: engine.context("failed to create wasmtime engine").unwrap(),
             cancel: CancellationToken::new(),
+            checkpoints: Arc::default(),
+            terminated: AtomicBool::new(false),
         }
     }
 }
@@ -88,6 +196,16 @@ pub struct WasiPreview2Ctx {
     pub(crate) wasi_ctx: wasi_preview2::WasiCtx,
     pub(crate) wasi_http: WasiHttpCtx,
     pub(crate) resource_table: ResourceTable,
+    pub(crate) limiter: Option<MemoryLimiter>,
+    pub(crate) logger: GuestLogger,
+    // whether the guest can send outgoing HTTP requests, with `Capability::NetOutbound`
+    pub(crate) net_outbound: bool,
+    // the resolver of the pod, for the hosts of the outgoing HTTP requests
+    pub(crate) resolver: Option<Arc<Resolver>>,
+    // the stores of the guest for wasi:keyvalue
+    pub(crate) keyvalue: KeyValue,
+    // the configuration of the guest for wasi:config
+    pub(crate) config: GuestConfig,
 }
 
 impl WasiPreview2Ctx {
@@ -97,10 +215,22 @@ impl WasiPreview2Ctx {
             wasi_ctx: wasi_builder(ctx)?.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limiter: ctx.memory_limit().map(MemoryLimiter::new),
+            logger: GuestLogger::new(ctx)?,
+            net_outbound: ctx.capabilities().contains(Capability::NetOutbound),
+            resolver: ctx.resolver(),
+            keyvalue: KeyValue::new(ctx)?,
+            config: GuestConfig::new(ctx),
         })
     }
 }
 
+/// The data of the stores of core modules.
+struct WasiPreview1Ctx {
+    wasi_ctx: WasiP1Ctx,
+    limiter: Option<MemoryLimiter>,
+}
+
 /// This impl is required to use wasmtime_wasi::preview2::WasiView trait.
 impl wasi_preview2::WasiView for WasiPreview2Ctx {
     fn table(&mut self) -> &mut ResourceTable {
@@ -120,6 +250,68 @@ impl WasiHttpView for WasiPreview2Ctx {
     fn ctx(&mut self) -> &mut wasmtime_wasi_http::WasiHttpCtx {
         &mut self.wasi_http
     }
+
+    fn send_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        if !self.net_outbound {
+            log::debug!("denying an outgoing HTTP request to {}", request.uri());
+            return Err(ErrorCode::HttpRequestDenied.into());
+        }
+        // the TLS requests keep the name of the authority for its certificate, and the
+        // lookups of wasi:sockets, which wasmtime-wasi doesn't let the shim hook, use the
+        // resolver of the C library, which reads the same files in the container
+        let Some(resolver) = self.resolver.clone().filter(|_| !config.use_tls) else {
+            return Ok(default_send_request(request, config));
+        };
+        let handle = wasi_preview2::runtime::spawn(async move {
+            let request = match resolve_authority(&resolver, request).await {
+                Ok(request) => request,
+                Err(code) => return Ok(Err(code)),
+            };
+            Ok(default_send_request_handler(request, config).await)
+        });
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
+}
+
+// Points the plain HTTP `request` at the address the `resolver` has for the host of its
+// authority, which is only used to connect, as its `Host` header keeps the name
+async fn resolve_authority(
+    resolver: &Resolver,
+    mut request: hyper::Request<HyperOutgoingBody>,
+) -> Result<hyper::Request<HyperOutgoingBody>, ErrorCode> {
+    let Some(authority) = request.uri().authority().cloned() else {
+        return Ok(request);
+    };
+    let host = authority.host();
+    // an IPv4 address, or an IPv6 one in brackets
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return Ok(request);
+    }
+    let addr = match resolver.lookup(host).await {
+        Ok(addrs) if !addrs.is_empty() => addrs[0],
+        res => {
+            log::debug!("failed to resolve the host of {}: {res:?}", request.uri());
+            return Err(ErrorCode::DnsError(DnsErrorPayload {
+                rcode: None,
+                info_code: None,
+            }));
+        }
+    };
+
+    let addr = SocketAddr::new(addr, authority.port_u16().unwrap_or(80));
+    let mut parts = request.uri().clone().into_parts();
+    parts.authority = Some(
+        addr.to_string()
+            .parse()
+            .map_err(|_| ErrorCode::HttpRequestUriInvalid)?,
+    );
+    *request.uri_mut() =
+        hyper::Uri::from_parts(parts).map_err(|_| ErrorCode::HttpRequestUriInvalid)?;
+    Ok(request)
 }
 
 impl Shim for WasmtimeShim {
@@ -131,110 +323,257 @@ impl Shim for WasmtimeShim {
         version!()
     }
 
+    fn engine_version() -> EngineVersion {
+        let features = ShimConfig::get()
+            .engine_value(WASM_FEATURES_KEY)
+            .unwrap_or_default();
+        EngineVersion {
+            name: Self::name().to_string(),
+            version: WASMTIME_VERSION.to_string(),
+            features: enabled_wasm_features(&features),
+        }
+    }
+
     type Sandbox = WasmtimeSandbox;
 
-    #[allow(refining_impl_trait)]
-    async fn compiler() -> Option<WasmtimeCompiler> {
-        let mut config = wasmtime::Config::new();
+    fn engine_config_keys() -> &'static [&'static str] {
+        &[
+            "cpu_features",
+            "wasm_features",
+            "pooling",
+            "pooling_total_instances",
+            "pooling_memory_pages",
+            "pooling_total_tables",
+            "pooling_table_elements",
+        ]
+    }
 
-        // Disable Wasmtime parallel compilation for the tests
-        // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
-        config.parallel_compilation(!cfg!(test));
-        config.wasm_component_model(true); // enable component linking
-        config.async_support(true); // must be on
+    // including the `CPU_FEATURES_KEY` and `WASM_FEATURES_KEY` features, and the pool
+    fn check_config(config: &ShimConfig) -> Result<()> {
+        wasmtime::Engine::new(&engine_config(config)?)?;
+        Pooling::new(config)?;
+        Ok(())
+    }
 
+    #[allow(refining_impl_trait)]
+    async fn compiler(config: &ShimConfig) -> Option<WasmtimeCompiler> {
+        let config =
+            engine_config(config).expect("failed to configure wasmtime precompilation engine");
         let engine = wasmtime::Engine::new(&config)
             .expect("failed to create wasmtime precompilation engine");
 
         Some(WasmtimeCompiler(engine))
     }
+
+    fn supported_stack_limits() -> SupportedStackLimits {
+        // wasmtime bounds the stack by its size rather than by the depth of the calls
+        SupportedStackLimits {
+            max_stack_size: Some(StackLimitRange {
+                range: MAX_WASM_STACK_RANGE,
+                default: DEFAULT_MAX_WASM_STACK,
+            }),
+            max_call_depth: None,
+        }
+    }
+
+    fn supports_deterministic() -> bool {
+        true
+    }
+
+    fn supports_validation() -> bool {
+        true
+    }
+
+    fn supported_capabilities() -> Capabilities {
+        Capability::ALL.into_iter().collect()
+    }
+
+    fn precompiled_extensions() -> &'static [&'static str] {
+        &[precompiled::EXTENSION]
+    }
+
+    fn reports_guest_started() -> bool {
+        true
+    }
 }
 
 impl Sandbox for WasmtimeSandbox {
+    fn new(ctx: &impl RuntimeContext) -> Self {
+        Self::with_config(
+            ctx.stack_limits(),
+            ctx.coredump().is_some(),
+            ctx.memory_limit(),
+        )
+    }
+
     async fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         log::info!("setting up wasi");
 
-        let Entrypoint {
-            source,
-            func,
-            arg0: _,
-            name: _,
-        } = ctx.entrypoint();
+        let func = ctx.entrypoint().func;
+        let status = match self.load_entrypoint(ctx) {
+            Ok(Guest::Module(module)) => self.execute_module(ctx, module, &func, None).await,
+            Ok(Guest::Component(component)) => self.execute_component(ctx, component, func).await,
+            Err(err) => Err(err),
+        };
+        status.into_error_code().map_err(report_trap)
+    }
+
+    async fn validate(&self, ctx: &impl RuntimeContext) -> Result<Validation> {
+        let func = ctx.entrypoint().func;
+        match self.load_entrypoint(ctx) {
+            Ok(Guest::Module(module)) => self.validate_module(&module, &func),
+            Ok(Guest::Component(component)) => self.validate_component(&component, &func),
+            // e.g., the module is invalid, or requires a wasm proposal that is disabled
+            Err(err) => Ok(Validation {
+                errors: vec![format!("{err:#}")],
+                ..Default::default()
+            }),
+        }
+    }
 
-        let wasm_bytes = &source.as_bytes()?;
+    async fn terminate(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        // Stop serving new HTTP connections, and trap guests at their next epoch check
+        self.checkpoints.interrupt();
+        self.terminated.store(true, Ordering::SeqCst);
+        self.cancel.cancel();
+        self.engine.increment_epoch();
+        Ok(())
+    }
 
-        self.execute(ctx, wasm_bytes, func).await.into_error_code()
+    async fn checkpoint(&self, _ctx: &impl RuntimeContext, dir: &Path) -> Result<()> {
+        self.checkpoints.checkpoint(&self.engine, dir).await
+    }
+
+    async fn restore(&self, ctx: &impl RuntimeContext, dir: &Path) -> Result<i32> {
+        log::info!("restoring wasi from {dir:?}");
+
+        let func = ctx.entrypoint().func;
+        let status = match self.load_entrypoint(ctx) {
+            Ok(Guest::Module(module)) => self.execute_module(ctx, module, &func, Some(dir)).await,
+            Ok(Guest::Component(_)) => Err(anyhow::anyhow!("components can't be restored")),
+            Err(err) => Err(err),
+        };
+        status.into_error_code().map_err(report_trap)
     }
 }
 
 impl Compiler for WasmtimeCompiler {
     fn cache_key(&self) -> impl Hash {
-        self.0.precompile_compatibility_hash()
+        // The hash covers the Cranelift ISA flags and the wasm proposals, so modules
+        // compiled for different CPU or wasm features never share a cache key
+        (
+            WasmtimeShim::engine_version(),
+            self.0.precompile_compatibility_hash(),
+        )
     }
 
     async fn compile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
         let mut compiled_layers = Vec::<Option<Vec<u8>>>::with_capacity(layers.len());
 
         for layer in layers {
-            if self.0.detect_precompiled(&layer.layer).is_some() {
-                log::info!("Already precompiled");
-                compiled_layers.push(None);
-                continue;
-            }
-
-            let compiled_layer = match WasmBinaryType::from_bytes(&layer.layer) {
-                Some(WasmBinaryType::Module) => self.0.precompile_module(&layer.layer)?,
-                Some(WasmBinaryType::Component) => self.0.precompile_component(&layer.layer)?,
+            let kind = layer
+                .kind
+                .or_else(|| WasmLayerKind::from_bytes(&layer.layer));
+            let binary_type = match kind {
+                Some(WasmLayerKind::CoreModule) => WasmBinaryType::Module,
+                Some(WasmLayerKind::Component) => WasmBinaryType::Component,
+                Some(WasmLayerKind::Precompiled { .. }) => {
+                    log::info!("Already precompiled");
+                    compiled_layers.push(None);
+                    continue;
+                }
+                None if self.0.detect_precompiled(&layer.layer).is_some() => {
+                    log::info!("Already precompiled");
+                    compiled_layers.push(None);
+                    continue;
+                }
                 None => {
                     log::warn!("Unknown WASM binary type");
+                    compiled_layers.push(None);
                     continue;
                 }
             };
 
+            // Compile on a blocking thread, so that the layers of an image
+            // that the shim compiles concurrently are compiled in parallel
+            let engine = self.0.clone();
+            let wasm = layer.layer.clone();
+            let compiled_layer = tokio::task::spawn_blocking(move || match binary_type {
+                WasmBinaryType::Module => check_wasm_features(&engine, &wasm)
+                    .and_then(|()| engine.precompile_module(&wasm)),
+                WasmBinaryType::Component => engine.precompile_component(&wasm),
+            })
+            .await??;
+
             compiled_layers.push(Some(compiled_layer));
         }
 
         Ok(compiled_layers)
     }
+
+    fn compiles_per_layer(&self) -> bool {
+        true
+    }
 }
 
 impl WasmtimeSandbox {
     /// Execute a wasm module.
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
-    /// to execute a wasm module that uses wasi_preview1.
+    /// to execute a wasm module that uses wasi_preview1, restoring the state
+    /// of the checkpoint in `restore`, if any.
     async fn execute_module(
         &self,
         ctx: &impl RuntimeContext,
         module: Module,
         func: &String,
+        restore: Option<&Path>,
     ) -> Result<i32> {
         log::debug!("execute module");
 
-        let ctx_p1 = wasi_builder(ctx)?.build_p1();
+        let ctx_p1 = WasiPreview1Ctx {
+            wasi_ctx: wasi_builder(ctx)?.build_p1(),
+            limiter: ctx.memory_limit().map(MemoryLimiter::new),
+        };
         let mut store = Store::new(&self.engine, ctx_p1);
+        store.set_epoch_deadline(1);
+        limit_memory(&mut store, |ctx| &mut ctx.limiter);
         let mut module_linker = wasmtime::Linker::new(&self.engine);
 
         log::debug!("init linker");
-        wasi_preview1::add_to_linker_async(&mut module_linker, |wasi_ctx: &mut WasiP1Ctx| {
-            wasi_ctx
+        wasi_preview1::add_to_linker_async(&mut module_linker, |ctx: &mut WasiPreview1Ctx| {
+            &mut ctx.wasi_ctx
         })?;
 
         log::info!("instantiating instance");
         let instance: wasmtime::Instance =
             module_linker.instantiate_async(&mut store, &module).await?;
+        if let Some(dir) = restore {
+            checkpoint::restore(&mut store, instance, dir)?;
+        }
+        // the other directories of the guest are in its root
+        self.checkpoints
+            .serve(&mut store, instance, vec![PathBuf::from("/")]);
 
         log::debug!("getting start function");
-        let start_func = instance
-            .get_func(&mut store, func)
-            .context("module does not have a WASI start function")?;
+        let Some(start_func) = instance.get_func(&mut store, func) else {
+            bail!("module does not have an exported function {func:?}");
+        };
+        let Some(mut results) = core_results(&start_func.ty(&store)) else {
+            bail!(
+                "exported function {func:?} of the module can't be invoked: it must take no parameters and return numbers"
+            );
+        };
 
         log::info!("running start function {func:?}");
+        ctx.guest_started();
 
-        start_func
-            .call_async(&mut store, &[], &mut [])
-            .await
-            .into_error_code()
+        let res = start_func.call_async(&mut store, &[], &mut results).await;
+        if let (Err(err), Some(coredump)) = (&res, ctx.coredump()) {
+            let name = module.name().unwrap_or("main");
+            write_coredump(&mut store, err, &coredump, name);
+        }
+        res.into_error_code()
     }
 
     async fn execute_component_async(
@@ -244,6 +583,7 @@ impl WasmtimeSandbox {
         func: String,
     ) -> Result<i32> {
         log::info!("instantiating component");
+        self.checkpoints.running_component();
 
         let target = ComponentTarget::new(
             component.component_type().exports(&self.engine),
@@ -254,17 +594,24 @@ impl WasmtimeSandbox {
         let status = match target {
             ComponentTarget::HttpProxy => {
                 log::info!("Found HTTP proxy target");
-                let mut linker = component::Linker::new(&self.engine);
-                wasmtime_wasi::add_to_linker_async(&mut linker)?;
-                wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+                let linker = component_linker(&self.engine, true)?;
 
                 let pre = linker.instantiate_pre(&component)?;
                 log::info!("pre-instantiate_pre");
                 let instance = ProxyPre::new(pre)?;
 
                 log::info!("starting HTTP server");
+                ctx.guest_started();
                 let cancel = self.cancel.clone();
-                serve_conn(ctx, instance, cancel).await
+                // The server stops accepting connections on SIGINT or SIGTERM,
+                // and exits once the in-flight requests are handled
+                serve_conn(ctx, instance, cancel).await?;
+
+                // The server was stopped by SIGTERM rather than by a graceful SIGINT
+                if self.terminated.load(Ordering::SeqCst) {
+                    return Ok(128 + libc::SIGTERM);
+                }
+                Ok(())
             }
             ComponentTarget::Command => {
                 log::info!("Found command target");
@@ -272,6 +619,7 @@ impl WasmtimeSandbox {
                 let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
 
                 let command = Command::instantiate_async(&mut store, &component, &linker).await?;
+                ctx.guest_started();
 
                 command
                     .wasi_cli_run()
@@ -292,12 +640,34 @@ impl WasmtimeSandbox {
                 let instance = pre.instantiate_async(&mut store).await?;
 
                 log::info!("getting component exported function {func:?}");
-                let start_func = instance.get_func(&mut store, func).context(format!(
-                    "component does not have exported function {func:?}"
-                ))?;
+                let Some(start_func) = instance.get_func(&mut store, func) else {
+                    bail!("component does not have an exported function {func:?}");
+                };
+                let params = start_func.params(&store);
+                let params = match &params[..] {
+                    [] => vec![],
+                    // the canonical signature of a function taking the args, like `main`
+                    [component::Type::List(list)]
+                        if matches!(list.ty(), component::Type::String) =>
+                    {
+                        let args = ctx.args().iter().skip(1);
+                        let args = args.map(|arg| component::Val::String(arg.clone()));
+                        vec![component::Val::List(args.collect())]
+                    }
+                    _ => bail!(
+                        "exported function {func:?} of the component can't be invoked: it must take no parameters or a list<string> of the args"
+                    ),
+                };
+                // the results are overwritten by the call
+                let mut results =
+                    vec![component::Val::Bool(false); start_func.results(&store).len()];
 
                 log::debug!("running exported function {func:?} {start_func:?}");
-                start_func.call_async(&mut store, &[], &mut []).await
+                ctx.guest_started();
+                start_func
+                    .call_async(&mut store, &params, &mut results)
+                    .await?;
+                start_func.post_return_async(&mut store).await
             }
         };
 
@@ -341,39 +711,254 @@ impl WasmtimeSandbox {
         wait_for_signal().await
     }
 
-    async fn execute(
-        &self,
-        ctx: &impl RuntimeContext,
-        wasm_binary: &[u8],
-        func: String,
-    ) -> Result<i32> {
-        match WasmBinaryType::from_bytes(wasm_binary) {
-            Some(WasmBinaryType::Module) => {
+    /// Load the entrypoint of the container, which is either a wasm binary, or a file of the
+    /// rootfs precompiled by `wasmtime compile`, see [`crate::precompiled`].
+    fn load_entrypoint(&self, ctx: &impl RuntimeContext) -> Result<Guest> {
+        let source = ctx.entrypoint().source;
+        match &source {
+            Source::File(path) if ctx.is_precompiled_file() => {
+                match precompiled::load(&self.engine, path)? {
+                    PrecompiledFile::Module(module) => {
+                        log::info!("using precompiled file {path:?}");
+                        Ok(Guest::Module(module))
+                    }
+                    PrecompiledFile::Component(component) => {
+                        log::info!("using precompiled file {path:?}");
+                        Ok(Guest::Component(component))
+                    }
+                    PrecompiledFile::Wasm(wasm, kind) => self.load(&wasm, Some(kind)),
+                }
+            }
+            _ => {
+                let wasm_bytes = &source.as_bytes()?;
+                let kind = source.kind(wasm_bytes);
+                self.load(wasm_bytes, kind)
+            }
+        }
+    }
+
+    fn load(&self, wasm_binary: &[u8], kind: Option<WasmLayerKind>) -> Result<Guest> {
+        // Files, and artifacts that don't record what they were precompiled from,
+        // are checked to be precompiled by a compatible engine
+        let kind = kind.or_else(|| match self.engine.detect_precompiled(wasm_binary)? {
+            Precompiled::Module => Some(WasmLayerKind::CoreModule.precompiled()),
+            Precompiled::Component => Some(WasmLayerKind::Component.precompiled()),
+        });
+        match kind {
+            Some(WasmLayerKind::CoreModule) => {
                 log::debug!("loading wasm module");
+                check_wasm_features(&self.engine, wasm_binary)?;
                 let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(ctx, module, &func).await
+                Ok(Guest::Module(module))
             }
-            Some(WasmBinaryType::Component) => {
+            Some(WasmLayerKind::Component) => {
                 let component = Component::from_binary(&self.engine, wasm_binary)?;
-                self.execute_component(ctx, component, func).await
+                Ok(Guest::Component(component))
             }
-            None => match &self.engine.detect_precompiled(wasm_binary) {
-                Some(Precompiled::Module) => {
-                    log::info!("using precompiled module");
-                    let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(ctx, module, &func).await
-                }
-                Some(Precompiled::Component) => {
-                    log::info!("using precompiled component");
-                    let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_component(ctx, component, func).await
-                }
-                None => {
-                    bail!("invalid precompiled module")
+            Some(WasmLayerKind::Precompiled {
+                of: WasmBinaryType::Module,
+            }) => {
+                log::info!("using precompiled module");
+                let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
+                Ok(Guest::Module(module))
+            }
+            Some(WasmLayerKind::Precompiled {
+                of: WasmBinaryType::Component,
+            }) => {
+                log::info!("using precompiled component");
+                let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
+                Ok(Guest::Component(component))
+            }
+            None => {
+                bail!("invalid precompiled module")
+            }
+        }
+    }
+
+    /// Validate a wasm module against wasi_preview1, as [`Self::execute_module`] would run it.
+    fn validate_module(&self, module: &Module, func: &str) -> Result<Validation> {
+        let mut validation = Validation {
+            world: Some("wasi_snapshot_preview1".to_string()),
+            ..Default::default()
+        };
+
+        let mut linker = wasmtime::Linker::new(&self.engine);
+        wasi_preview1::add_to_linker_async(&mut linker, |ctx: &mut WasiPreview1Ctx| {
+            &mut ctx.wasi_ctx
+        })?;
+        // the store is only used to look the imports up, the module isn't instantiated
+        let ctx_p1 = WasiPreview1Ctx {
+            wasi_ctx: wasi_preview2::WasiCtxBuilder::new().build_p1(),
+            limiter: None,
+        };
+        let mut store = Store::new(&self.engine, ctx_p1);
+        for import in module.imports() {
+            if linker.get_by_import(&mut store, &import).is_none() {
+                let name = format!("{}::{}", import.module(), import.name());
+                validation.missing_imports.push(name);
+            }
+        }
+        // the imports that are found can still have the wrong type
+        if validation.missing_imports.is_empty() {
+            if let Err(err) = linker.instantiate_pre(module) {
+                validation.errors.push(format!("{err:#}"));
+            }
+        }
+
+        match module.get_export(func) {
+            Some(ExternType::Func(ty)) if core_results(&ty).is_some() => {}
+            Some(ExternType::Func(_)) => validation.errors.push(format!(
+                "exported function {func:?} of the module can't be invoked: it must take no parameters and return numbers"
+            )),
+            _ => validation
+                .errors
+                .push(format!("module does not have an exported function {func:?}")),
+        }
+        Ok(validation)
+    }
+
+    /// Validate a wasm component against the world it targets, as
+    /// [`Self::execute_component_async`] would run it.
+    fn validate_component(&self, component: &Component, func: &str) -> Result<Validation> {
+        let target = ComponentTarget::new(component.component_type().exports(&self.engine), func);
+        let mut validation = Validation {
+            world: target.world().map(str::to_string),
+            ..Default::default()
+        };
+
+        let mut linker =
+            component_linker(&self.engine, matches!(target, ComponentTarget::HttpProxy))?;
+        let pre = match missing_component_imports(&self.engine, &mut linker, component) {
+            Ok(missing) if missing.is_empty() => linker.instantiate_pre(component).ok(),
+            Ok(missing) => {
+                validation.missing_imports = missing;
+                None
+            }
+            Err(err) => {
+                validation.errors.push(format!("{err:#}"));
+                None
+            }
+        };
+
+        // the exports of the world are checked against the bindings of the host
+        let exports = match (target, pre) {
+            (ComponentTarget::HttpProxy, Some(pre)) => ProxyPre::new(pre).map(drop),
+            (ComponentTarget::Command, Some(pre)) => CommandPre::new(pre).map(drop),
+            (ComponentTarget::Core(func), _) => check_component_func(&self.engine, component, func),
+            (_, None) => Ok(()),
+        };
+        if let Err(err) = exports {
+            validation.errors.push(format!("{err:#}"));
+        }
+        Ok(validation)
+    }
+}
+
+// Returns the imports of `component` that `linker` doesn't provide. Instantiating it only fails
+// with the first of them, so each one it fails with is stubbed in `linker` to find the next.
+// Stubbing stops at the imports it can't stub, e.g., interfaces with resources, which the
+// instantiation then fails with.
+fn missing_component_imports(
+    engine: &wasmtime::Engine,
+    linker: &mut component::Linker<WasiPreview2Ctx>,
+    component: &Component,
+) -> Result<Vec<String>> {
+    let ty = component.component_type();
+    let imports: Vec<_> = ty.imports(engine).collect();
+    linker.allow_shadowing(true);
+
+    let mut missing = vec![];
+    loop {
+        let Err(err) = linker.instantiate_pre(component) else {
+            return Ok(missing);
+        };
+        let message = format!("{err:#}");
+        let import = imports.iter().find(|(name, _)| {
+            !missing.iter().any(|m| m == name) && message.contains(&format!("`{name}`"))
+        });
+        let Some((name, item)) = import else {
+            // e.g., a found import has the wrong type
+            return if missing.is_empty() {
+                Err(err)
+            } else {
+                Ok(missing)
+            };
+        };
+        missing.push(name.to_string());
+        if stub_import(engine, linker, name, item).is_err() {
+            return Ok(missing);
+        }
+    }
+}
+
+// Defines the function, or the functions of the instance, imported as `name` in `linker`, to
+// trap if they are called
+fn stub_import(
+    engine: &wasmtime::Engine,
+    linker: &mut component::Linker<WasiPreview2Ctx>,
+    name: &str,
+    item: &ComponentItem,
+) -> Result<()> {
+    match item {
+        ComponentItem::ComponentFunc(_) => {
+            linker
+                .root()
+                .func_new(name, |_, _, _| bail!("the import isn't provided"))?;
+        }
+        ComponentItem::ComponentInstance(instance) => {
+            let mut stub = linker.instance(name)?;
+            for (export, item) in instance.exports(engine) {
+                match item {
+                    ComponentItem::ComponentFunc(_) => {
+                        stub.func_new(export, |_, _, _| bail!("the import isn't provided"))?;
+                    }
+                    _ => bail!("can't stub export {export:?} of import {name:?}"),
                 }
-            },
+            }
         }
+        _ => bail!("can't stub import {name:?}"),
+    }
+    Ok(())
+}
+
+// Checks that the component exports `func` with parameters that the shim can pass, see
+// `WasmtimeSandbox::execute_component_async`
+fn check_component_func(
+    engine: &wasmtime::Engine,
+    component: &Component,
+    func: &str,
+) -> Result<()> {
+    let component_ty = component.component_type();
+    let export = component_ty.exports(engine).find(|(name, _)| *name == func);
+    let Some((_, ComponentItem::ComponentFunc(ty))) = export else {
+        bail!("component does not have an exported function {func:?}");
+    };
+    let params: Vec<_> = ty.params().collect();
+    match &params[..] {
+        [] => Ok(()),
+        [component::Type::List(list)] if matches!(list.ty(), component::Type::String) => Ok(()),
+        _ => bail!(
+            "exported function {func:?} of the component can't be invoked: it must take no parameters or a list<string> of the args"
+        ),
+    }
+}
+
+// Returns placeholder values for the results of a core function that takes no parameters,
+// or `None` if it can't be called without arguments or it returns references.
+fn core_results(ty: &wasmtime::FuncType) -> Option<Vec<wasmtime::Val>> {
+    if ty.params().next().is_some() {
+        return None;
     }
+    ty.results()
+        .map(|ty| match ty {
+            wasmtime::ValType::I32 => Some(wasmtime::Val::I32(0)),
+            wasmtime::ValType::I64 => Some(wasmtime::Val::I64(0)),
+            wasmtime::ValType::F32 => Some(wasmtime::Val::F32(0)),
+            wasmtime::ValType::F64 => Some(wasmtime::Val::F64(0)),
+            wasmtime::ValType::V128 => Some(wasmtime::Val::V128(0u128.into())),
+            wasmtime::ValType::Ref(_) => None,
+        })
+        .collect()
 }
 
 pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)> {
@@ -386,54 +971,156 @@ pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)>
         .collect()
 }
 
-fn store_for_context<T: wasi_preview2::WasiView>(
+fn store_for_context(
     engine: &wasmtime::Engine,
-    ctx: T,
-) -> Result<(Store<T>, component::Linker<T>)> {
-    let store = Store::new(engine, ctx);
+    ctx: WasiPreview2Ctx,
+) -> Result<(Store<WasiPreview2Ctx>, component::Linker<WasiPreview2Ctx>)> {
+    let mut store = Store::new(engine, ctx);
+    store.set_epoch_deadline(1);
+    limit_memory(&mut store, |ctx| &mut ctx.limiter);
 
     log::debug!("init linker");
-    let mut linker = component::Linker::new(engine);
-    wasi_preview2::add_to_linker_async(&mut linker)?;
+    let linker = component_linker(engine, false)?;
 
     Ok((store, linker))
 }
 
+// Returns a linker with the interfaces the host provides to components, and wasi:http if
+// the component is an `http` proxy
+fn component_linker(
+    engine: &wasmtime::Engine,
+    http: bool,
+) -> Result<component::Linker<WasiPreview2Ctx>> {
+    let mut linker = component::Linker::new(engine);
+    wasi_preview2::add_to_linker_async(&mut linker)?;
+    if http {
+        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+    }
+    logging::add_to_linker(&mut linker, |ctx: &mut WasiPreview2Ctx| &mut ctx.logger)?;
+    keyvalue::add_to_linker(&mut linker)?;
+    wasi_config::add_to_linker(&mut linker, |ctx: &mut WasiPreview2Ctx| &mut ctx.config)?;
+    Ok(linker)
+}
+
 fn wasi_builder(ctx: &impl RuntimeContext) -> Result<wasi_preview2::WasiCtxBuilder, anyhow::Error> {
     // TODO: make this more configurable (e.g. allow the user to specify the
     // preopened directories and their permissions)
     // https://github.com/containerd/runwasi/issues/413
     log::debug!("building WASI context");
 
+    let capabilities = ctx.capabilities();
     let file_perms = wasi_preview2::FilePerms::all();
     let dir_perms = wasi_preview2::DirPerms::all();
     let envs = envs_from_ctx(ctx);
 
+    let perms = |read_only: bool| {
+        if read_only {
+            (
+                wasi_preview2::DirPerms::READ,
+                wasi_preview2::FilePerms::READ,
+            )
+        } else {
+            (dir_perms, file_perms)
+        }
+    };
+    let root_read_only = ctx.root_read_only();
+    let (root_dir_perms, root_file_perms) = perms(root_read_only);
+
     let mut builder = wasi_preview2::WasiCtxBuilder::new();
     builder
         .args(ctx.args())
         .envs(&envs)
         .inherit_stdio()
-        .inherit_network()
-        .allow_tcp(true)
-        .allow_udp(true)
-        .allow_ip_name_lookup(true)
-        .preopened_dir("/", "/", dir_perms, file_perms)?;
+        .preopened_dir("/", "/", root_dir_perms, root_file_perms)?;
+    allow_network(
+        &mut builder,
+        capabilities,
+        ctx.net_allowlist(),
+        ctx.resolver(),
+        ctx.listeners(),
+    );
+
+    for preopen in ctx.preopens() {
+        let (dir_perms, file_perms) = perms(preopen.read_only);
+        let path = &preopen.path;
+        builder.preopened_dir(path, path.to_string_lossy(), dir_perms, file_perms)?;
+    }
+
+    // guests resolve their relative paths against the directory preopened as `.`, which is
+    // read-only if the innermost preopen it's in is, or the root if it's in none
+    let cwd = ctx.cwd();
+    if cwd != Path::new("/") {
+        let read_only = ctx
+            .preopens()
+            .iter()
+            .filter(|preopen| cwd.starts_with(&preopen.path))
+            .max_by_key(|preopen| preopen.path.components().count())
+            .map_or(root_read_only, |preopen| preopen.read_only);
+        let (dir_perms, file_perms) = perms(read_only);
+        builder.preopened_dir(cwd, ".", dir_perms, file_perms)?;
+    }
+
+    if let Some(deterministic) = ctx.deterministic() {
+        log::info!("running the guest deterministically with {deterministic:?}");
+        make_deterministic(&mut builder, &deterministic);
+    }
 
     log::debug!("WASI context built successfully");
     Ok(builder)
 }
 
+// Lets the guest use the sockets that `capabilities` grant, which it can't use otherwise, and
+// only connect them to the destinations of the `allowlist`, if there's one, resolving its host
+// names with the `resolver` of the container. The sockets are
+// created in the network namespace of the container, which the init process running the
+// engine joined before the executor. The `listeners` the shim bound are closed when the guest
+// binds their port, as wasi:sockets can't hand them over.
+fn allow_network(
+    builder: &mut wasi_preview2::WasiCtxBuilder,
+    capabilities: Capabilities,
+    allowlist: Option<NetAllowlist>,
+    resolver: Option<Arc<Resolver>>,
+    listeners: Arc<Listeners>,
+) {
+    let outbound = capabilities.contains(Capability::NetOutbound);
+    let inbound = capabilities.contains(Capability::NetInbound);
+    let allowlist = allowlist.map(Arc::new);
+    builder
+        .allow_tcp(outbound || inbound)
+        .allow_udp(outbound || inbound)
+        .allow_ip_name_lookup(outbound)
+        .socket_addr_check(move |addr, addr_use| {
+            let (allowed, allowlist) = match addr_use {
+                SocketAddrUse::TcpConnect
+                | SocketAddrUse::UdpConnect
+                | SocketAddrUse::UdpOutgoingDatagram => (outbound, allowlist.clone()),
+                SocketAddrUse::TcpBind => {
+                    if inbound {
+                        drop(listeners.take(addr.port()));
+                    }
+                    (inbound, None)
+                }
+                SocketAddrUse::UdpBind => (inbound, None),
+            };
+            let resolver = resolver.clone();
+            Box::pin(async move {
+                match allowlist {
+                    Some(allowlist) if allowed => allowlist.allows(addr, resolver.as_deref()).await,
+                    _ => allowed,
+                }
+            })
+        });
+}
+
 async fn wait_for_signal() -> Result<i32> {
     #[cfg(unix)]
     {
         use tokio::signal::unix::{SignalKind, signal};
+        // SIGTERM is handled by the executor, which calls `Sandbox::terminate`
         let mut sigquit = signal(SignalKind::quit())?;
-        let mut sigterm = signal(SignalKind::terminate())?;
 
         tokio::select! {
             _ = sigquit.recv() => { Ok(libc::SIGQUIT) }
-            _ = sigterm.recv() => { Ok(libc::SIGTERM) }
             _ = tokio::signal::ctrl_c() => { Ok(libc::SIGINT) }
         }
     }
@@ -444,7 +1131,124 @@ async fn wait_for_signal() -> Result<i32> {
     }
 }
 
-/// The pooling allocator is tailor made for the `wasi/http` use case. Check if we can use it.
+/// Returns the configuration shared by the precompilation and the runtime engines, with the
+/// engine settings of `shim_config`. Both need the same settings for the runtime engine to load
+/// the precompiled modules.
+fn engine_config(shim_config: &ShimConfig) -> Result<Config> {
+    let mut config = Config::new();
+
+    // Disable Wasmtime parallel compilation for the tests
+    // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
+    config.parallel_compilation(!cfg!(test));
+    config.wasm_component_model(true); // enable component linking
+    config.async_support(true); // must be on
+    config.epoch_interruption(true); // used to interrupt the guest on termination
+    // symbolicate the backtraces of traps with the DWARF info of the modules, when they have it
+    config.wasm_backtrace(true);
+    config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
+
+    if let Some(features) = shim_config.engine_value(CPU_FEATURES_KEY) {
+        set_cpu_features(&mut config, &features)
+            .with_context(|| format!("invalid `engine.{CPU_FEATURES_KEY}` value {features:?}"))?;
+    }
+    if let Some(features) = shim_config.engine_value(WASM_FEATURES_KEY) {
+        set_wasm_features(&mut config, &features)
+            .with_context(|| format!("invalid `engine.{WASM_FEATURES_KEY}` value {features:?}"))?;
+    }
+
+    Ok(config)
+}
+
+/// Sets the CPU features in `features`, in the [`CPU_FEATURES_KEY`] format, on `config`.
+pub(crate) fn set_cpu_features(config: &mut Config, features: &str) -> Result<()> {
+    for (name, enabled) in parse_features("CPU", features)? {
+        let enabled = if enabled { "true" } else { "false" };
+        // SAFETY: creating the engine fails on unknown flags, and on enabled
+        // features that the host CPU doesn't support
+        unsafe {
+            config.cranelift_flag_set(name, enabled);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the wasm proposals in `features`, in the [`WASM_FEATURES_KEY`] format, on `config`.
+pub(crate) fn set_wasm_features(config: &mut Config, features: &str) -> Result<()> {
+    for (name, enabled) in parse_features("wasm", features)? {
+        let Some((_, set)) = WASM_FEATURES.iter().find(|(feature, _)| *feature == name) else {
+            let names = WASM_FEATURES.map(|(name, _)| name).join(", ");
+            bail!("unknown wasm feature {name:?}, expected one of {names}");
+        };
+        set(config, enabled);
+    }
+    Ok(())
+}
+
+/// Returns the wasm features the engine enables, i.e., the component model and the proposals
+/// that `features`, in the [`WASM_FEATURES_KEY`] format, enables. The proposals it doesn't list
+/// keep the defaults of wasmtime, and aren't reported.
+pub(crate) fn enabled_wasm_features(features: &str) -> Vec<String> {
+    let mut enabled = vec!["component-model".to_string()];
+    // an invalid value fails the start of the shim, see `WasmtimeShim::check_config`
+    for (name, on) in parse_features("wasm", features).unwrap_or_default() {
+        enabled.retain(|feature| feature != name);
+        if on {
+            enabled.push(name.to_string());
+        }
+    }
+    enabled
+}
+
+// Parses the comma separated `features` of `kind`, prefixed with `+` to enable them or `-` to
+// disable them, into their names and whether they're enabled
+fn parse_features<'a>(kind: &str, features: &'a str) -> Result<Vec<(&'a str, bool)>> {
+    let mut parsed = vec![];
+    for feature in features.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let (name, enabled) = if let Some(name) = feature.strip_prefix('+') {
+            (name, true)
+        } else if let Some(name) = feature.strip_prefix('-') {
+            (name, false)
+        } else {
+            bail!("{kind} feature {feature:?} must start with `+` or `-`");
+        };
+        if name.is_empty() {
+            bail!("empty {kind} feature name in {feature:?}");
+        }
+        parsed.push((name, enabled));
+    }
+    Ok(parsed)
+}
+
+/// Checks that the core module `wasm` is valid for `engine`. When it requires a wasm proposal
+/// of [`WASM_FEATURES_KEY`] that is disabled, the error names the proposal instead of the
+/// section or instruction that failed to validate.
+pub(crate) fn check_wasm_features(engine: &wasmtime::Engine, wasm: &[u8]) -> Result<()> {
+    let Err(err) = Module::validate(engine, wasm) else {
+        return Ok(());
+    };
+    let required: Vec<_> = WASM_FEATURES
+        .iter()
+        .filter(|(_, set)| {
+            let Ok(mut config) = engine_config(ShimConfig::get()) else {
+                return false;
+            };
+            set(&mut config, true);
+            wasmtime::Engine::new(&config)
+                .is_ok_and(|engine| Module::validate(&engine, wasm).is_ok())
+        })
+        .map(|(name, _)| *name)
+        .collect();
+    match required.first() {
+        Some(name) => Err(err.context(format!(
+            "feature {} required by module but disabled, enable it with `engine.{WASM_FEATURES_KEY} = \"+{name}\"`",
+            required.join(" or ")
+        ))),
+        None => Err(err),
+    }
+}
+
+/// The pooling allocator is tailor made for the `wasi/http` use case. Check if we can use it
+/// when it's not explicitly enabled, see [`crate::pooling`].
 ///
 /// For more details refer to: <https://github.com/bytecodealliance/wasmtime/blob/v27.0.0/src/commands/serve.rs#L641>
 fn use_pooling_allocator_by_default() -> bool {
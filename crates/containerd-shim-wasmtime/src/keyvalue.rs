@@ -0,0 +1,526 @@
+//! The host side of `wasi:keyvalue/store` and `wasi:keyvalue/batch` for components, e.g., the
+//! ones of Spin.
+//!
+//! The stores the guest can open are declared with one [`KV_ANNOTATION_PREFIX`] annotation per
+//! store name. Opening any other store fails with `no-such-store`, so that the components
+//! importing `wasi:keyvalue` still run in containers without stores.
+//!
+//! The `dir:` stores keep each key in a file of a directory the guest has preopened, with the
+//! permissions of the preopen, and lock the directory so that the instances sharing a volume
+//! see the writes of each other whole. The `redis://` stores keep the keys in a database of
+//! Redis, see [`RedisClient`].
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result, bail};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use fd_lock::RwLock;
+use wasmtime::component::{Linker, Resource};
+
+use self::bindings::wasi::keyvalue::{batch, store};
+use self::store::{Error as StoreError, KeyResponse};
+use crate::instance::WasiPreview2Ctx;
+use crate::redis::RedisClient;
+
+/// Prefix of the annotations declaring the key-value stores of the guest, followed by the name
+/// of the store, e.g., `io.runwasi.kv.default=dir:/data/kv` for a store in the files of the
+/// mount `/data/kv`, or `io.runwasi.kv.cache=redis://redis:6379/0` for a store in a database of
+/// Redis.
+const KV_ANNOTATION_PREFIX: &str = "io.runwasi.kv.";
+
+// The file the `dir:` stores are locked with, which no key is stored in, as the names of the
+// files of the keys never start with a dot
+const LOCK_FILE: &str = ".lock";
+
+// only the interfaces of the world are added to the linkers, the world itself isn't used
+#[allow(dead_code)]
+mod bindings {
+    wasmtime::component::bindgen!({
+        inline: "
+            package wasi:keyvalue@0.2.0-draft2;
+
+            interface store {
+                variant error { no-such-store, access-denied, other(string) }
+
+                record key-response { keys: list<string>, cursor: option<string> }
+
+                open: func(identifier: string) -> result<bucket, error>;
+
+                resource bucket {
+                    get: func(key: string) -> result<option<list<u8>>, error>;
+                    set: func(key: string, value: list<u8>) -> result<_, error>;
+                    delete: func(key: string) -> result<_, error>;
+                    exists: func(key: string) -> result<bool, error>;
+                    list-keys: func(cursor: option<string>) -> result<key-response, error>;
+                }
+            }
+
+            interface batch {
+                use store.{bucket, error};
+
+                get-many: func(bucket: borrow<bucket>, keys: list<string>) -> result<list<option<tuple<string, list<u8>>>>, error>;
+                set-many: func(bucket: borrow<bucket>, key-values: list<tuple<string, list<u8>>>) -> result<_, error>;
+                delete-many: func(bucket: borrow<bucket>, keys: list<string>) -> result<_, error>;
+            }
+
+            world imports {
+                import store;
+                import batch;
+            }
+        ",
+        async: true,
+        trappable_imports: true,
+        with: {
+            "wasi:keyvalue/store/bucket": crate::keyvalue::Bucket,
+        },
+    });
+}
+
+/// Adds `wasi:keyvalue/store` and `wasi:keyvalue/batch` to `linker`.
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    fn ctx(ctx: &mut WasiPreview2Ctx) -> &mut WasiPreview2Ctx {
+        ctx
+    }
+    store::add_to_linker(linker, ctx)?;
+    batch::add_to_linker(linker, ctx)?;
+    Ok(())
+}
+
+/// The key-value stores of the guest, by name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyValue {
+    stores: HashMap<String, Arc<Backend>>,
+}
+
+impl KeyValue {
+    pub(crate) fn new(ctx: &impl RuntimeContext) -> Result<Self> {
+        let stores = ctx
+            .annotations_with_prefix(KV_ANNOTATION_PREFIX)
+            .into_iter()
+            .map(|(name, value)| {
+                let backend = Backend::new(ctx, value).with_context(|| {
+                    format!("invalid {KV_ANNOTATION_PREFIX}{name} annotation: {value:?}")
+                })?;
+                Ok((name.to_string(), Arc::new(backend)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { stores })
+    }
+}
+
+/// An open store of the guest.
+// public for the bindings to re-export it as the `bucket` resource
+pub struct Bucket {
+    backend: Arc<Backend>,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Dir(DirStore),
+    Redis(RedisClient),
+}
+
+impl Backend {
+    fn new(ctx: &impl RuntimeContext, value: &str) -> Result<Self> {
+        if value.starts_with("redis://") {
+            return Ok(Self::Redis(RedisClient::from_url(value)?));
+        }
+        let Some(dir) = value.strip_prefix("dir:") else {
+            bail!("expected `dir:<path>` or `redis://<host>`");
+        };
+        let dir = Path::new(dir);
+        if !dir.is_absolute() || dir.components().any(|c| c == Component::ParentDir) {
+            bail!("the path of the directory must be absolute, without `..`");
+        }
+        // the innermost preopen the directory is in
+        let preopen = ctx
+            .preopens()
+            .iter()
+            .filter(|preopen| dir.starts_with(&preopen.path))
+            .max_by_key(|preopen| preopen.path.components().count())
+            .context("the directory isn't in a mount of the container")?;
+        Ok(Self::Dir(DirStore {
+            dir: dir.to_path_buf(),
+            read_only: preopen.read_only,
+        }))
+    }
+
+    async fn get(&self, key: String) -> Result<Option<Vec<u8>>, StoreError> {
+        match self {
+            Self::Dir(store) => store.run(move |dir| dir.get(&key)).await,
+            Self::Redis(client) => client.get(&key).await.map_err(other),
+        }
+    }
+
+    async fn set(&self, key: String, value: Vec<u8>) -> Result<(), StoreError> {
+        match self {
+            Self::Dir(store) => store.run(move |dir| dir.set(&key, &value)).await,
+            Self::Redis(client) => client.set(&key, &value).await.map_err(other),
+        }
+    }
+
+    async fn delete(&self, key: String) -> Result<(), StoreError> {
+        match self {
+            Self::Dir(store) => store.run(move |dir| dir.delete(&key)).await,
+            Self::Redis(client) => client.delete(&key).await.map_err(other),
+        }
+    }
+
+    async fn exists(&self, key: String) -> Result<bool, StoreError> {
+        match self {
+            Self::Dir(store) => store.run(move |dir| dir.exists(&key)).await,
+            Self::Redis(client) => client.exists(&key).await.map_err(other),
+        }
+    }
+
+    async fn list_keys(&self, cursor: Option<String>) -> Result<KeyResponse, StoreError> {
+        let (keys, cursor) = match self {
+            // the keys of a directory are listed at once
+            Self::Dir(store) => (store.run(DirStore::keys).await?, None),
+            Self::Redis(client) => client.scan(cursor.as_deref()).await.map_err(other)?,
+        };
+        Ok(KeyResponse { keys, cursor })
+    }
+}
+
+fn other(err: anyhow::Error) -> StoreError {
+    log::debug!("wasi:keyvalue request failed: {err:#}");
+    StoreError::Other(format!("{err:#}"))
+}
+
+/// A store in the files of a directory.
+#[derive(Clone, Debug)]
+struct DirStore {
+    dir: PathBuf,
+    read_only: bool,
+}
+
+impl DirStore {
+    // Runs `op` on a thread that can block on the lock of the directory
+    async fn run<T, F>(&self, op: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> Result<T, StoreError> + Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || op(&store))
+            .await
+            .map_err(|err| other(err.into()))?
+    }
+
+    // Opens the lock file of the directory, or returns `None` if it doesn't exist in a
+    // read-only store, which no instance can write to then
+    fn lock_file(&self) -> Result<Option<RwLock<File>>, StoreError> {
+        let path = self.dir.join(LOCK_FILE);
+        let file = if self.read_only {
+            match File::open(&path) {
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                res => res,
+            }
+        } else {
+            std::fs::create_dir_all(&self.dir)
+                .and_then(|()| File::options().create(true).append(true).open(&path))
+        };
+        let file = file
+            .with_context(|| format!("failed to open the lock of {:?}", self.dir))
+            .map_err(other)?;
+        Ok(Some(RwLock::new(file)))
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StoreError> {
+        if key.is_empty() {
+            return Err(StoreError::Other("the key is empty".to_string()));
+        }
+        Ok(self.dir.join(encode_key(key)))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let path = self.path(key)?;
+        let lock = self.lock_file()?;
+        let _guard = lock.as_ref().map(RwLock::read).transpose().map_err(io)?;
+        match std::fs::read(path) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(io(err)),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), StoreError> {
+        if self.read_only {
+            return Err(StoreError::AccessDenied);
+        }
+        let path = self.path(key)?;
+        let mut lock = self.lock_file()?;
+        let _guard = lock.as_mut().map(RwLock::write).transpose().map_err(io)?;
+        // replaced whole, for the readers that don't lock the directory
+        let tmp = self.dir.join(format!(".tmp-{}", encode_key(key)));
+        std::fs::write(&tmp, value)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(io)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StoreError> {
+        if self.read_only {
+            return Err(StoreError::AccessDenied);
+        }
+        let path = self.path(key)?;
+        let mut lock = self.lock_file()?;
+        let _guard = lock.as_mut().map(RwLock::write).transpose().map_err(io)?;
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(io(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        let path = self.path(key)?;
+        let lock = self.lock_file()?;
+        let _guard = lock.as_ref().map(RwLock::read).transpose().map_err(io)?;
+        path.try_exists().map_err(io)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, StoreError> {
+        let lock = self.lock_file()?;
+        let _guard = lock.as_ref().map(RwLock::read).transpose().map_err(io)?;
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(io(err)),
+        };
+        let mut keys = vec![];
+        for entry in entries {
+            let name = entry.map_err(io)?.file_name();
+            // e.g., the lock, or the files written in place of the keys
+            if let Some(key) = name.to_str().and_then(decode_key) {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+fn io(err: std::io::Error) -> StoreError {
+    if err.kind() == ErrorKind::PermissionDenied {
+        return StoreError::AccessDenied;
+    }
+    other(err.into())
+}
+
+// The name of the file of `key`, which escapes the bytes other than the ASCII alphanumerics,
+// `-`, `_` and the dots that don't start the name
+fn encode_key(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for (i, byte) in key.bytes().enumerate() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(char::from(byte)),
+            b'.' if i > 0 => name.push('.'),
+            _ => name.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    name
+}
+
+// The key of the file `name`, or `None` if it's not the file of a key
+fn decode_key(name: &str) -> Option<String> {
+    if name.starts_with('.') {
+        return None;
+    }
+    let mut bytes = vec![];
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl WasiPreview2Ctx {
+    fn bucket(&self, bucket: &Resource<Bucket>) -> Result<Arc<Backend>> {
+        Ok(self.resource_table.get(bucket)?.backend.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl store::Host for WasiPreview2Ctx {
+    async fn open(&mut self, identifier: String) -> Result<Result<Resource<Bucket>, StoreError>> {
+        let Some(backend) = self.keyvalue.stores.get(&identifier) else {
+            log::debug!("the guest opened the undeclared key-value store {identifier:?}");
+            return Ok(Err(StoreError::NoSuchStore));
+        };
+        let bucket = Bucket {
+            backend: backend.clone(),
+        };
+        Ok(Ok(self.resource_table.push(bucket)?))
+    }
+}
+
+#[async_trait::async_trait]
+impl store::HostBucket for WasiPreview2Ctx {
+    async fn get(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Result<Option<Vec<u8>>, StoreError>> {
+        Ok(self.bucket(&bucket)?.get(key).await)
+    }
+
+    async fn set(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<Result<(), StoreError>> {
+        Ok(self.bucket(&bucket)?.set(key, value).await)
+    }
+
+    async fn delete(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Result<(), StoreError>> {
+        Ok(self.bucket(&bucket)?.delete(key).await)
+    }
+
+    async fn exists(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Result<bool, StoreError>> {
+        Ok(self.bucket(&bucket)?.exists(key).await)
+    }
+
+    async fn list_keys(
+        &mut self,
+        bucket: Resource<Bucket>,
+        cursor: Option<String>,
+    ) -> Result<Result<KeyResponse, StoreError>> {
+        Ok(self.bucket(&bucket)?.list_keys(cursor).await)
+    }
+
+    async fn drop(&mut self, bucket: Resource<Bucket>) -> Result<()> {
+        self.resource_table.delete(bucket)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl batch::Host for WasiPreview2Ctx {
+    async fn get_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<Result<Vec<Option<(String, Vec<u8>)>>, StoreError>> {
+        let backend = self.bucket(&bucket)?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            match backend.get(key.clone()).await {
+                Ok(value) => values.push(value.map(|value| (key, value))),
+                Err(err) => return Ok(Err(err)),
+            }
+        }
+        Ok(Ok(values))
+    }
+
+    async fn set_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key_values: Vec<(String, Vec<u8>)>,
+    ) -> Result<Result<(), StoreError>> {
+        let backend = self.bucket(&bucket)?;
+        for (key, value) in key_values {
+            if let Err(err) = backend.set(key, value).await {
+                return Ok(Err(err));
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    async fn delete_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<Result<(), StoreError>> {
+        let backend = self.bucket(&bucket)?;
+        for key in keys {
+            if let Err(err) = backend.delete(key).await {
+                return Ok(Err(err));
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir_store(dir: &Path, read_only: bool) -> Backend {
+        Backend::Dir(DirStore {
+            dir: dir.to_path_buf(),
+            read_only,
+        })
+    }
+
+    #[test]
+    fn test_encode_key() {
+        for key in ["counter", "user/1", ".hidden", "a.b", "üñí", "%41"] {
+            let name = encode_key(key);
+            assert!(!name.contains('/') && !name.starts_with('.'), "{name}");
+            assert_eq!(decode_key(&name).as_deref(), Some(key));
+        }
+        assert_eq!(encode_key("user/1"), "user%2F1");
+        assert_eq!(decode_key(LOCK_FILE), None);
+        assert_eq!(decode_key("%4"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dir_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kv");
+        // two instances sharing a volume
+        let (first, second) = (dir_store(&path, false), dir_store(&path, false));
+
+        assert_eq!(first.get("user/1".to_string()).await?, None);
+        assert!(first.list_keys(None).await?.keys.is_empty());
+
+        first.set("user/1".to_string(), b"alice".to_vec()).await?;
+        second.set("user/2".to_string(), b"bob".to_vec()).await?;
+        assert_eq!(
+            second.get("user/1".to_string()).await?,
+            Some(b"alice".to_vec())
+        );
+        assert!(first.exists("user/2".to_string()).await?);
+        let keys = first.list_keys(None).await?;
+        assert_eq!(keys.keys, ["user/1", "user/2"]);
+        assert_eq!(keys.cursor, None);
+
+        second.delete("user/1".to_string()).await?;
+        // deleting a missing key is fine
+        first.delete("user/1".to_string()).await?;
+        assert!(!first.exists("user/1".to_string()).await?);
+
+        let read_only = dir_store(&path, true);
+        assert_eq!(
+            read_only.get("user/2".to_string()).await?,
+            Some(b"bob".to_vec())
+        );
+        let err = read_only
+            .set("user/3".to_string(), vec![])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::AccessDenied), "{err:?}");
+
+        Ok(())
+    }
+}
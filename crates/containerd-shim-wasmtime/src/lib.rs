@@ -1,5 +1,15 @@
+mod checkpoint;
+mod deterministic;
 mod http_proxy;
 pub mod instance;
+mod keyvalue;
+mod limits;
+mod logging;
+mod pooling;
+mod precompiled;
+mod redis;
+mod trap;
+mod wasi_config;
 
 pub use instance::WasmtimeShim;
 
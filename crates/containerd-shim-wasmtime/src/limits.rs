@@ -0,0 +1,105 @@
+//! Limits of the memory of the guests, derived from the memory limit of their container.
+//!
+//! Without them, a guest can grow its linear memory until the kernel OOM-kills the whole
+//! instance. With them, growing the memory past the limit fails in the guest instead, which
+//! sees `memory.grow` return `-1` and usually traps with a more useful error.
+
+use anyhow::Result;
+use wasmtime::{ResourceLimiter, Store, StoreLimits, StoreLimitsBuilder};
+
+pub(crate) struct MemoryLimiter {
+    limit: usize,
+    limits: StoreLimits,
+}
+
+impl MemoryLimiter {
+    /// Returns a limiter for `limit` bytes, see `RuntimeContext::memory_limit`.
+    pub(crate) fn new(limit: u64) -> Self {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(limit)
+            // every element of a table takes at least a pointer
+            .table_elements(limit / size_of::<usize>())
+            .build();
+        Self { limit, limits }
+    }
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if !allowed {
+            log::warn!(
+                "failed to grow a linear memory of the guest from {current} to {desired} bytes: the limit is {} bytes",
+                self.limit
+            );
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = self.limits.table_growing(current, desired, maximum)?;
+        if !allowed {
+            log::warn!(
+                "failed to grow a table of the guest from {current} to {desired} elements: the memory limit is {} bytes",
+                self.limit
+            );
+        }
+        Ok(allowed)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+/// Limits the memory of the guest of `store` with the limiter `limiter` returns, if any.
+/// Stores without a limiter are not limited at all.
+pub(crate) fn limit_memory<T: 'static>(
+    store: &mut Store<T>,
+    limiter: fn(&mut T) -> &mut Option<MemoryLimiter>,
+) {
+    if limiter(store.data_mut()).is_none() {
+        return;
+    }
+    store.limiter(move |data| {
+        limiter(data)
+            .as_mut()
+            .expect("the limiter of the store was set")
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 64 * 1024;
+
+    #[test]
+    fn test_memory_limiter() -> Result<()> {
+        let mut limiter = MemoryLimiter::new(10 * PAGE_SIZE as u64);
+        assert!(limiter.memory_growing(0, 10 * PAGE_SIZE, None)?);
+        assert!(!limiter.memory_growing(10 * PAGE_SIZE, 11 * PAGE_SIZE, None)?);
+        assert!(limiter.table_growing(0, 10, None)?);
+        assert!(!limiter.table_growing(0, 10 * PAGE_SIZE, None)?);
+        Ok(())
+    }
+}
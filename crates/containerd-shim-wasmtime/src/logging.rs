@@ -0,0 +1,173 @@
+//! The host side of `wasi:logging/logging` for components.
+//!
+//! The records of the guest are logged by the shim at their level, with the target `guest`
+//! and their context as a key-value, so that they end up in the log of the shim with the
+//! instance and the pod they come from rather than in the stderr of the container.
+
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use self::bindings::wasi::logging::logging::{Host, Level as GuestLevel};
+
+/// Annotation with the minimum level of the records of the guest to log, e.g., `warn`.
+/// Defaults to `trace`, leaving the filtering to the level of the shim.
+const GUEST_LOG_LEVEL_ANNOTATION: &str = "io.runwasi.wasi-logging.level";
+
+// The target of the records of the guest
+const TARGET: &str = "guest";
+
+// only the interface of the world is added to the linkers, the world itself isn't used
+#[allow(dead_code)]
+mod bindings {
+    wasmtime::component::bindgen!({
+        inline: "
+            package wasi:logging@0.1.0-draft;
+
+            interface logging {
+                enum level { trace, debug, info, warn, error, critical }
+                log: func(level: level, context: string, message: string);
+            }
+
+            world imports {
+                import logging;
+            }
+        ",
+    });
+}
+
+pub(crate) use self::bindings::wasi::logging::logging::add_to_linker;
+
+/// Logs the records of a guest.
+#[derive(Clone, Copy)]
+pub(crate) struct GuestLogger {
+    min_level: LevelFilter,
+    logger: &'static dyn Log,
+}
+
+impl GuestLogger {
+    pub(crate) fn new(ctx: &impl RuntimeContext) -> Result<Self> {
+        let min_level = ctx
+            .annotation(GUEST_LOG_LEVEL_ANNOTATION)
+            .map(|level| {
+                LevelFilter::from_str(level).with_context(|| {
+                    format!("invalid {GUEST_LOG_LEVEL_ANNOTATION} annotation: {level:?}")
+                })
+            })
+            .transpose()?
+            .unwrap_or(LevelFilter::Trace);
+        Ok(Self {
+            min_level,
+            logger: log::logger(),
+        })
+    }
+}
+
+impl Host for GuestLogger {
+    fn log(&mut self, level: GuestLevel, context: String, message: String) {
+        let level = match level {
+            GuestLevel::Trace => Level::Trace,
+            GuestLevel::Debug => Level::Debug,
+            GuestLevel::Info => Level::Info,
+            GuestLevel::Warn => Level::Warn,
+            // the shim has no level above errors
+            GuestLevel::Error | GuestLevel::Critical => Level::Error,
+        };
+        if level > self.min_level {
+            return;
+        }
+
+        // the level of the shim, the `RUST_LOG` of its environment, is checked by its logger
+        let metadata = Metadata::builder().level(level).target(TARGET).build();
+        if !self.logger.enabled(&metadata) {
+            return;
+        }
+        let kvs = [("context", context.as_str())];
+        let kvs: &[_] = if context.is_empty() { &[] } else { &kvs };
+        self.logger.log(
+            &Record::builder()
+                .metadata(metadata)
+                .args(format_args!("{message}"))
+                .key_values(&kvs)
+                .build(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use containerd_shim_wasm::testing::modules::WASI_LOGGING;
+    use wasmtime::component::{Component, Linker};
+    use wasmtime::{Engine, Store};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Records(Mutex<Vec<(Level, Option<String>, String)>>);
+
+    impl Log for Records {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn log(&self, record: &Record) {
+            let context = record.key_values().get("context".into());
+            self.0.lock().unwrap().push((
+                record.level(),
+                context.map(|context| context.to_string()),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn run_logging(min_level: LevelFilter) -> Result<Vec<(Level, Option<String>, String)>> {
+        fn logger(logger: &mut GuestLogger) -> &mut GuestLogger {
+            logger
+        }
+
+        let engine = Engine::default();
+        let component = Component::new(&engine, WASI_LOGGING)?;
+        let mut linker = Linker::new(&engine);
+        add_to_linker(&mut linker, logger)?;
+
+        let records: &'static Records = Box::leak(Box::default());
+        let guest_logger = GuestLogger {
+            min_level,
+            logger: records,
+        };
+        let mut store = Store::new(&engine, guest_logger);
+        let instance = linker.instantiate(&mut store, &component)?;
+        instance
+            .get_typed_func::<(), ()>(&mut store, "run")?
+            .call(&mut store, ())?;
+
+        Ok(records.0.lock().unwrap().clone())
+    }
+
+    #[test]
+    fn test_guest_logger() -> Result<()> {
+        let context = || Some("wasi-logging".to_string());
+        assert_eq!(
+            run_logging(LevelFilter::Trace)?,
+            [
+                (Level::Info, context(), "an info record".to_string()),
+                (Level::Warn, context(), "a warn record".to_string()),
+                (Level::Error, context(), "an error record".to_string()),
+            ]
+        );
+        assert_eq!(
+            run_logging(LevelFilter::Warn)?,
+            [
+                (Level::Warn, context(), "a warn record".to_string()),
+                (Level::Error, context(), "an error record".to_string()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Configuration of the pooling instance allocator of wasmtime.
+//!
+//! The pool reserves the memories, tables and stacks of a number of instances when the engine
+//! is created, so that instantiating a guest, e.g., for every request of a `wasi/http` server,
+//! takes a slot of the pool instead of mapping and unmapping memory. The pool is sized with the
+//! `pooling_*` keys of the `[engine]` section of the config of the shim, and each memory of the
+//! pool is no larger than the memory limit of the container, which the guest can't exceed anyway.
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::shim::ShimConfig;
+use wasmtime::PoolingAllocationConfig;
+
+/// Engine setting to use the pooling allocator: `true` to always try it, `false` to
+/// allocate the instances on demand, or `auto`, the default, to use it when the host has the
+/// address space for it.
+pub(crate) const POOLING_KEY: &str = "pooling";
+
+/// Engine setting with the number of instances of the pool, which is also the number
+/// of their memories and stacks.
+pub(crate) const POOLING_TOTAL_INSTANCES_KEY: &str = "pooling_total_instances";
+
+/// Engine setting with the maximum number of 64 KiB pages of each memory of the pool.
+pub(crate) const POOLING_MEMORY_PAGES_KEY: &str = "pooling_memory_pages";
+
+/// Engine setting with the number of tables of the pool.
+pub(crate) const POOLING_TOTAL_TABLES_KEY: &str = "pooling_total_tables";
+
+/// Engine setting with the maximum number of elements of each table of the pool.
+pub(crate) const POOLING_TABLE_ELEMENTS_KEY: &str = "pooling_table_elements";
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// The size of the memories of the pool when it's not configured, which is the default of wasmtime
+const DEFAULT_MAX_MEMORY_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Default, PartialEq)]
+enum Mode {
+    #[default]
+    Auto,
+    Enabled,
+    Disabled,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Pooling {
+    mode: Mode,
+    total_instances: Option<u32>,
+    memory_pages: Option<u64>,
+    total_tables: Option<u32>,
+    table_elements: Option<usize>,
+}
+
+impl Pooling {
+    /// Returns the configuration of the pool in the `[engine]` section of `config`.
+    pub(crate) fn new(config: &ShimConfig) -> Result<Self> {
+        Self::parse(|key| config.engine_value(key))
+    }
+
+    // Parses the configuration from the engine settings `value` returns
+    fn parse(value: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        fn parse_value<T: std::str::FromStr>(
+            value: &impl Fn(&str) -> Option<String>,
+            key: &str,
+        ) -> Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            value(key)
+                .map(|value| {
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid `engine.{key}` value {value:?}"))
+                })
+                .transpose()
+        }
+
+        let mode = match value(POOLING_KEY).as_deref() {
+            None | Some("auto") => Mode::Auto,
+            Some("true") => Mode::Enabled,
+            Some("false") => Mode::Disabled,
+            Some(value) => anyhow::bail!(
+                "invalid `engine.{POOLING_KEY}` value {value:?}, expected `true`, `false` or `auto`"
+            ),
+        };
+        Ok(Self {
+            mode,
+            total_instances: parse_value(&value, POOLING_TOTAL_INSTANCES_KEY)?,
+            memory_pages: parse_value(&value, POOLING_MEMORY_PAGES_KEY)?,
+            total_tables: parse_value(&value, POOLING_TOTAL_TABLES_KEY)?,
+            table_elements: parse_value(&value, POOLING_TABLE_ELEMENTS_KEY)?,
+        })
+    }
+
+    /// Returns the configuration of the pool for a guest limited to `memory_limit` bytes, or
+    /// `None` if the instances are allocated on demand. `supported` tells whether the host has
+    /// the address space for the pool, and is only called in the `auto` mode.
+    pub(crate) fn config(
+        &self,
+        memory_limit: Option<u64>,
+        supported: impl FnOnce() -> bool,
+    ) -> Option<PoolingAllocationConfig> {
+        match self.mode {
+            Mode::Disabled => return None,
+            Mode::Auto if !supported() => return None,
+            Mode::Auto | Mode::Enabled => {}
+        }
+
+        let mut config = PoolingAllocationConfig::default();
+        if let Some(total) = self.total_instances {
+            config
+                .total_core_instances(total)
+                .total_component_instances(total)
+                .total_memories(total)
+                .total_stacks(total);
+        }
+        if let Some(total) = self.total_tables {
+            config.total_tables(total);
+        }
+        if let Some(elements) = self.table_elements {
+            config.table_elements(elements);
+        }
+        if let Some(size) = self.max_memory_size(memory_limit) {
+            config.max_memory_size(usize::try_from(size).unwrap_or(usize::MAX));
+        }
+        Some(config)
+    }
+
+    // The size of each memory of the pool, if it's not the default of wasmtime
+    fn max_memory_size(&self, memory_limit: Option<u64>) -> Option<u64> {
+        let configured = self
+            .memory_pages
+            .map(|pages| pages.saturating_mul(WASM_PAGE_SIZE));
+        match memory_limit {
+            // the memories can't grow past the limit, so there's no point in reserving more
+            Some(limit) => {
+                let size = configured.unwrap_or(DEFAULT_MAX_MEMORY_SIZE).min(limit);
+                Some(size.next_multiple_of(WASM_PAGE_SIZE))
+            }
+            None => configured,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn parse(values: &[(&str, &str)]) -> Result<Pooling> {
+        let values: HashMap<_, _> = values.iter().copied().collect();
+        Pooling::parse(|key| values.get(key).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn test_parse_pooling() -> Result<()> {
+        assert_eq!(parse(&[])?, Pooling::default());
+
+        let pooling = parse(&[
+            (POOLING_KEY, "true"),
+            (POOLING_TOTAL_INSTANCES_KEY, "100"),
+            (POOLING_MEMORY_PAGES_KEY, "160"),
+        ])?;
+        assert_eq!(pooling.mode, Mode::Enabled);
+        assert_eq!(pooling.total_instances, Some(100));
+        assert_eq!(pooling.memory_pages, Some(160));
+
+        assert!(parse(&[(POOLING_KEY, "yes")]).is_err());
+        let err = parse(&[(POOLING_TOTAL_INSTANCES_KEY, "many")]).unwrap_err();
+        assert!(
+            err.to_string().contains(POOLING_TOTAL_INSTANCES_KEY),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pooling_mode() -> Result<()> {
+        let auto = parse(&[])?;
+        assert!(auto.config(None, || true).is_some());
+        assert!(auto.config(None, || false).is_none());
+
+        let enabled = parse(&[(POOLING_KEY, "true")])?;
+        assert!(enabled.config(None, || false).is_some());
+
+        let disabled = parse(&[(POOLING_KEY, "false")])?;
+        assert!(disabled.config(None, || true).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pooling_memory_size() -> Result<()> {
+        let default = parse(&[])?;
+        assert_eq!(default.max_memory_size(None), None);
+        assert_eq!(
+            default.max_memory_size(Some(100 * 1024 * 1024)),
+            Some(100 * 1024 * 1024)
+        );
+
+        // the memories are capped by the memory limit, in whole pages
+        let pooling = parse(&[(POOLING_MEMORY_PAGES_KEY, "160")])?;
+        assert_eq!(pooling.max_memory_size(None), Some(160 * WASM_PAGE_SIZE));
+        assert_eq!(
+            pooling.max_memory_size(Some(100 * WASM_PAGE_SIZE + 1)),
+            Some(101 * WASM_PAGE_SIZE)
+        );
+        assert_eq!(
+            pooling.max_memory_size(Some(1 << 40)),
+            Some(160 * WASM_PAGE_SIZE)
+        );
+
+        Ok(())
+    }
+}
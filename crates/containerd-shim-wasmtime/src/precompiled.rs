@@ -0,0 +1,140 @@
+//! Precompiled files of the rootfs of the containers, e.g., `app.cwasm` from `wasmtime compile`.
+//!
+//! They are only run when the shim is started with `RUNWASI_ALLOW_PRECOMPILED_FILES=true`, as
+//! wasmtime loads the native code in them without validating the wasm they were compiled from.
+//! Loading them still checks that they were compiled by the same version of wasmtime, for the
+//! same target and with compatible settings. When they weren't, the wasm module next to the file,
+//! e.g., `app.wasm` for `app.cwasm`, is run instead if the rootfs has one.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::sandbox::context::{Source, WasmLayerKind};
+use object::{Object as _, ObjectSection as _};
+use wasmtime::component::Component;
+use wasmtime::{Engine, Module, Precompiled};
+
+/// The extension of the files precompiled by `wasmtime compile`.
+pub(crate) const EXTENSION: &str = "cwasm";
+
+// The section of the precompiled files where wasmtime records its version and settings
+const ENGINE_SECTION: &str = ".wasmtime.engine";
+
+// An empty core module
+const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+pub(crate) enum PrecompiledFile {
+    Module(Module),
+    Component(Component),
+    /// The wasm binary to run instead, and its kind.
+    Wasm(Vec<u8>, WasmLayerKind),
+}
+
+/// Loads the precompiled file at `path` for `engine`, or the wasm module next to it when
+/// `engine` can't run it.
+pub(crate) fn load(engine: &Engine, path: &Path) -> Result<PrecompiledFile> {
+    // read as is, as [`Source::as_bytes`] would convert a text file to wasm
+    let bytes = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+    let err = match deserialize(engine, &bytes) {
+        Ok(file) => return Ok(file),
+        Err(err) => err,
+    };
+
+    let fallback = path.with_extension("wasm");
+    if let Ok(wasm) = Source::File(fallback.clone()).as_bytes() {
+        let Some(kind) = WasmLayerKind::from_bytes(&wasm) else {
+            bail!(
+                "{fallback:?}, run instead of the precompiled file {path:?}, is not a wasm binary"
+            );
+        };
+        log::warn!("running {fallback:?} instead of the precompiled file {path:?}: {err:#}");
+        return Ok(PrecompiledFile::Wasm(wasm.into_owned(), kind));
+    }
+
+    let running = engine
+        .precompile_module(EMPTY_MODULE)
+        .ok()
+        .and_then(|empty| version(&empty));
+    Err(err).with_context(|| {
+        format!(
+            "{path:?} was precompiled by wasmtime {}, which can't be run by wasmtime {} of the shim, and there is no {fallback:?} to run instead",
+            version(&bytes).as_deref().unwrap_or("unknown"),
+            running.as_deref().unwrap_or("unknown"),
+        )
+    })
+}
+
+fn deserialize(engine: &Engine, bytes: &[u8]) -> Result<PrecompiledFile> {
+    // SAFETY: deserializing runs the native code of the file without validating it, which is
+    // only sound for the files wasmtime precompiled. The shim only runs the precompiled files of
+    // the rootfs when it's allowed to, for trusted images, and wasmtime checks that the version
+    // and the settings of the engine that compiled the file are compatible with `engine`.
+    match engine.detect_precompiled(bytes) {
+        Some(Precompiled::Module) => {
+            let module = unsafe { Module::deserialize(engine, bytes) }?;
+            Ok(PrecompiledFile::Module(module))
+        }
+        Some(Precompiled::Component) => {
+            let component = unsafe { Component::deserialize(engine, bytes) }?;
+            Ok(PrecompiledFile::Component(component))
+        }
+        None => bail!("not a file precompiled by wasmtime"),
+    }
+}
+
+// Returns the version of wasmtime that precompiled `bytes`, which starts the engine section
+// after a byte with the version of the section and a byte with the length of the version
+fn version(bytes: &[u8]) -> Option<String> {
+    let file = object::File::parse(bytes).ok()?;
+    let section = file.section_by_name(ENGINE_SECTION)?.data().ok()?;
+    let [_, len, rest @ ..] = section else {
+        return None;
+    };
+    let version = rest.get(..usize::from(*len))?;
+    String::from_utf8(version.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precompiled_version() -> Result<()> {
+        let engine = Engine::default();
+        let precompiled = engine.precompile_module(EMPTY_MODULE)?;
+
+        let compiled = version(&precompiled).context("no version")?;
+        assert!(
+            compiled.starts_with(|c: char| c.is_ascii_digit()),
+            "{compiled}"
+        );
+        assert_eq!(version(EMPTY_MODULE), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_falls_back_to_wasm() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("app.cwasm");
+        std::fs::write(&path, b"not precompiled")?;
+        let engine = Engine::default();
+
+        let Err(err) = load(&engine, &path) else {
+            bail!("loaded an invalid precompiled file");
+        };
+        assert!(format!("{err:#}").contains("app.wasm"), "{err:#}");
+
+        std::fs::write(path.with_extension("wasm"), EMPTY_MODULE)?;
+        assert!(matches!(
+            load(&engine, &path)?,
+            PrecompiledFile::Wasm(_, WasmLayerKind::CoreModule)
+        ));
+
+        // a compatible precompiled file is loaded as is
+        std::fs::write(&path, engine.precompile_module(EMPTY_MODULE)?)?;
+        assert!(matches!(load(&engine, &path)?, PrecompiledFile::Module(_)));
+
+        Ok(())
+    }
+}
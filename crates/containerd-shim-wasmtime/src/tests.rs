@@ -5,6 +5,9 @@ use containerd_shim_wasm::testing::{WasiTest, oci_helpers};
 use serial_test::serial;
 
 use crate::WasmtimeShim as WasiEngine;
+use crate::instance::{
+    check_wasm_features, enabled_wasm_features, set_cpu_features, set_wasm_features,
+};
 
 #[test]
 #[serial]
@@ -137,6 +140,195 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_invoke_annotation() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation("io.runwasi.invoke", "foo")
+        .with_wasm(CUSTOM_ENTRYPOINT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_missing_export() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_start_fn("missing")
+        .with_wasm(CUSTOM_ENTRYPOINT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_ne!(exit_code, 0);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_validate_only() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation("io.runwasi.validate-only", "true")
+        .with_wasm(HELLO_WORLD)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    // the guest isn't run
+    assert_eq!(exit_code, 0);
+    assert_eq!(
+        stdout,
+        "{\"valid\":true,\"engine\":\"wasmtime\",\"entrypoint\":\"_start\",\"world\":\"wasi_snapshot_preview1\",\"missing_imports\":[],\"errors\":[]}\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_validate_only_missing_imports() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation("io.runwasi.validate-only", "true")
+        .with_start_fn("missing")
+        .with_wasm(
+            r#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (import "env" "now" (func (result i64)))
+                (import "env" "sleep" (func (param i64)))
+                (func (export "_start")))"#,
+        )?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 1);
+    assert!(
+        stdout.contains("\"missing_imports\":[\"env::now\",\"env::sleep\"]"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("module does not have an exported function \\\"missing\\\""),
+        "{stdout}"
+    );
+
+    Ok(())
+}
+
+// Test that a guest stuck in a loop is interrupted once it used up its CPU time.
+#[test]
+#[serial]
+fn test_cpu_time_limit() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiEngine>::builder()?
+        .with_annotation("io.runwasi.cpu-time-limit", "1s")
+        .with_wasm(INFINITE_LOOP)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 152);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_echo_stdin() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(ECHO)?
+        .with_stdin("hello\n")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    // the module exits once it reads the end of stdin
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_echo_terminal() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(ECHO)?
+        .with_stdin("hello\n")?
+        .with_terminal()
+        .build()?
+        .resize_pty(120, 40)?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    // the terminal echoes the input, then the module does, with the newlines of a terminal
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello\r\nhello\r\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_deterministic() -> anyhow::Result<()> {
+    let run = |value: &str| -> anyhow::Result<String> {
+        let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+            .with_annotation("io.runwasi.deterministic", value)
+            .with_wasm(DETERMINISTIC)?
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))?;
+        assert_eq!(exit_code, 0);
+        Ok(stdout)
+    };
+
+    let stdout = run("seed:42")?;
+    assert_eq!(stdout, run("seed:42")?);
+    assert_ne!(stdout, run("seed:43")?);
+    // the clock was read once, a tick of 1ms after the epoch
+    assert!(stdout.ends_with("40420f0000000000\n"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_checkpoint_restore() -> anyhow::Result<()> {
+    let checkpoint = tempfile::tempdir()?;
+
+    let counter = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(COUNTER)?
+        .build()?;
+    counter.start()?;
+    wait_for_counts(&counter, 3)?;
+    counter.checkpoint(checkpoint.path())?;
+    let (exit_code, stdout, _) = counter.kill()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 137);
+    let last = *counts(&stdout).last().unwrap();
+
+    let restored = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(COUNTER)?
+        .with_checkpoint(checkpoint.path())
+        .build()?;
+    restored.start()?;
+    wait_for_counts(&restored, 1)?;
+    let (_, stdout, _) = restored.kill()?.wait(Duration::from_secs(10))?;
+
+    // the restored guest runs its entrypoint again, counting from the checkpoint rather than 1
+    let first = counts(&stdout)[0];
+    assert!(
+        (4..=last + 1).contains(&first),
+        "restored counting at {first}"
+    );
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_unreachable() -> anyhow::Result<()> {
@@ -165,6 +357,21 @@ fn test_exit_code() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_large_output_before_exit() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(LARGE_OUTPUT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "x".repeat(1024 * 1024));
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_seccomp() -> anyhow::Result<()> {
@@ -194,10 +401,30 @@ fn test_has_default_devices() -> anyhow::Result<()> {
     Ok(())
 }
 
+// The masked paths of the spec aren't writable even when the root is
+#[test]
+#[serial]
+fn test_masked_path_is_read_only() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(CREATE_FILE)?
+        .with_annotation("io.runwasi.allow", "fs-write")
+        .with_masked_path("/masked")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 1);
+    assert_eq!(
+        stdout.trim(),
+        "failed to create /masked/file: ReadOnlyFilesystem"
+    );
+
+    Ok(())
+}
+
 // Test that the shim can execute an named exported function
 // that is not the default _start function in a wasm component.
-// The current limitation is that there is no way to pass arguments
-// to the exported function.
+// Exported functions can take no parameters, or the args as a `list<string>`.
 // Issue that tracks this: https://github.com/containerd/runwasi/issues/414
 #[test]
 #[serial]
@@ -316,19 +543,134 @@ fn test_wasip2_component_http_proxy_force_shutdown() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Test that the address to serve HTTP on can be set with an annotation.
+#[test]
+#[serial]
+fn test_wasip2_component_http_proxy_listen_annotation() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiEngine>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .with_annotation("io.runwasi.http.listen", "127.0.0.1:8081")
+        .with_annotation("io.runwasi.http.max-in-flight", "1")
+        .build()?;
+
+    let srv = srv.start()?;
+    let response = http_get_url("http://127.0.0.1:8081", 1);
+
+    let response = response.expect("Server did not start in time");
+    assert!(response.status().is_success());
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 128 + libc::SIGTERM as u32);
+
+    Ok(())
+}
+
+#[test]
+fn test_cpu_features() -> anyhow::Result<()> {
+    let engine = |features: &str| -> anyhow::Result<wasmtime::Engine> {
+        let mut config = wasmtime::Config::new();
+        set_cpu_features(&mut config, features)?;
+        wasmtime::Engine::new(&config)
+    };
+
+    assert!(engine("").is_ok());
+    assert!(engine("has_sse3").is_err());
+    assert!(engine("+").is_err());
+    assert!(engine("+has_not_a_cpu_feature").is_err());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+
+        let hash = |engine: &wasmtime::Engine| {
+            let mut hasher = DefaultHasher::new();
+            engine.precompile_compatibility_hash().hash(&mut hasher);
+            hasher.finish()
+        };
+        let native = engine("")?;
+        let pinned = engine(" -has_sse3, -has_ssse3 ")?;
+        assert_ne!(hash(&native), hash(&pinned));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_wasm_features() -> anyhow::Result<()> {
+    let engine = |features: &str| -> anyhow::Result<wasmtime::Engine> {
+        let mut config = wasmtime::Config::new();
+        set_wasm_features(&mut config, features)?;
+        wasmtime::Engine::new(&config)
+    };
+
+    assert!(engine("").is_ok());
+    assert!(engine("memory64").is_err());
+    assert!(engine("+not-a-proposal").is_err());
+
+    let memory64 = wat::parse_str("(module (memory i64 1))")?;
+    let err = check_wasm_features(&engine("-memory64")?, &memory64).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("feature memory64 required by module but disabled"),
+        "{err:#}"
+    );
+    let enabled = engine("+memory64")?;
+    check_wasm_features(&enabled, &memory64)?;
+
+    use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+    let hash = |engine: &wasmtime::Engine| {
+        let mut hasher = DefaultHasher::new();
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        hasher.finish()
+    };
+    assert_ne!(hash(&engine("-memory64")?), hash(&enabled));
+
+    assert_eq!(enabled_wasm_features(""), ["component-model"]);
+    assert_eq!(
+        enabled_wasm_features("+memory64, -threads, +threads, -memory64"),
+        ["component-model", "threads"]
+    );
+
+    Ok(())
+}
+
+// The counts the counter module printed on `stdout`
+fn counts(stdout: &str) -> Vec<u32> {
+    stdout
+        .lines()
+        .filter_map(|line| line.parse().ok())
+        .collect()
+}
+
+// Waits for the counter module of `test` to print `n` counts
+fn wait_for_counts(test: &WasiTest<WasiEngine>, n: usize) -> anyhow::Result<()> {
+    for _ in 0..100 {
+        if counts(&test.read_stdout()?.unwrap_or_default()).len() >= n {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    anyhow::bail!("the counter didn't count to {n}")
+}
+
 fn http_get() -> reqwest::Result<reqwest::blocking::Response> {
     http_get_with_backoff_secs(1)
 }
 
-// Helper method to make a `GET` request
 fn http_get_with_backoff_secs(backoff: u64) -> reqwest::Result<reqwest::blocking::Response> {
+    http_get_url("http://127.0.0.1:8080", backoff)
+}
+
+// Helper method to make a `GET` request
+fn http_get_url(url: &str, backoff: u64) -> reqwest::Result<reqwest::blocking::Response> {
     const MAX_ATTEMPTS: u32 = 10;
     let backoff_duration: Duration = Duration::from_secs(backoff);
 
     let mut attempts = 0;
 
     loop {
-        match reqwest::blocking::get("http://127.0.0.1:8080") {
+        match reqwest::blocking::get(url) {
             Ok(resp) => break Ok(resp),
             Err(err) if attempts == MAX_ATTEMPTS => break Err(err),
             Err(_) => {
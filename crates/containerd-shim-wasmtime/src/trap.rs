@@ -0,0 +1,224 @@
+//! Reports of the traps of the guests.
+//!
+//! A trap is logged with its wasm backtrace, symbolicated with the DWARF info or the name
+//! section of the module when it has them, and with the offsets of the frames in the module
+//! otherwise. The error the executor reports gets a short form of it.
+//!
+//! Traps of core modules can also be written to a coredump, see
+//! [`COREDUMP_ANNOTATION`](containerd_shim_wasm::sandbox::context::COREDUMP_ANNOTATION).
+
+use std::fmt::{self, Display, Formatter, Write as _};
+
+use containerd_shim_wasm::sandbox::context::Coredump;
+use containerd_shim_wasm::sandbox::error::Error;
+use wasmtime::{AsContextMut, FrameInfo, Trap, WasmBacktrace, WasmCoreDump};
+
+// The number of innermost frames in the short form of the trap
+const SHORT_BACKTRACE_FRAMES: usize = 3;
+
+/// A frame of the backtrace of a trap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    /// The name of the function, from the DWARF info or the name section, if any
+    pub(crate) func: Option<String>,
+    pub(crate) func_index: u32,
+    /// The offset of the instruction in the module
+    pub(crate) offset: Option<usize>,
+    /// The source location of the instruction, from the DWARF info, if any
+    pub(crate) location: Option<String>,
+}
+
+impl Frame {
+    fn new(frame: &FrameInfo) -> Self {
+        let symbol = frame.symbols().first();
+        let func = symbol
+            .and_then(|symbol| symbol.name())
+            .or_else(|| frame.func_name())
+            .map(str::to_string);
+        let location = symbol.and_then(|symbol| {
+            let file = symbol.file()?;
+            Some(match symbol.line() {
+                Some(line) => format!("{file}:{line}"),
+                None => file.to_string(),
+            })
+        });
+        Self {
+            func,
+            func_index: frame.func_index(),
+            offset: frame.module_offset(),
+            location,
+        }
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.func {
+            Some(func) => write!(f, "{func}")?,
+            None => write!(f, "<wasm function {}>", self.func_index)?,
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " @ {offset:#x}")?;
+        }
+        if let Some(location) = &self.location {
+            write!(f, " ({location})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the trap of the guest that `err` is, and the frames of its backtrace, innermost first.
+pub(crate) fn trap_frames(err: &anyhow::Error) -> Option<(Trap, Vec<Frame>)> {
+    let trap = *err.downcast_ref::<Trap>()?;
+    let frames = err
+        .downcast_ref::<WasmBacktrace>()
+        .map(|backtrace| backtrace.frames().iter().map(Frame::new).collect())
+        .unwrap_or_default();
+    Some((trap, frames))
+}
+
+/// Logs `err` with its backtrace if it's a trap of the guest, and adds an [`Error::Runtime`]
+/// with the short form of the trap to it. Other errors are returned as is.
+pub(crate) fn report_trap(err: anyhow::Error) -> anyhow::Error {
+    let Some((trap, frames)) = trap_frames(&err) else {
+        return err;
+    };
+
+    let frames: Vec<_> = frames.iter().map(Frame::to_string).collect();
+    let backtrace = frames
+        .iter()
+        .enumerate()
+        .fold(String::new(), |mut backtrace, (i, frame)| {
+            let _ = write!(backtrace, "\n  {i}: {frame}");
+            backtrace
+        });
+    // wasmtime prefixes the description of the trap with `wasm trap: `
+    let trap = trap.to_string();
+    let trap = trap.strip_prefix("wasm trap: ").unwrap_or(&trap);
+    log::error!(trap:% = trap, frames:? = frames; "the guest trapped: {trap}{backtrace}");
+
+    let message = if frames.is_empty() {
+        format!("wasm trap: {trap}")
+    } else {
+        let short = frames[..frames.len().min(SHORT_BACKTRACE_FRAMES)].join(" <- ");
+        format!("wasm trap: {trap} in {short}")
+    };
+    err.context(Error::Runtime {
+        message,
+        trap: Some(trap.to_string()),
+    })
+}
+
+/// Writes the coredump of `err` to the path of `coredump`, if `err` is a trap with a coredump
+/// and the linear memories of the guest fit in its size limit. The failures are logged, as the
+/// trap is what the guest exits with.
+pub(crate) fn write_coredump(
+    mut store: impl AsContextMut,
+    err: &anyhow::Error,
+    coredump: &Coredump,
+    name: &str,
+) {
+    let Some(dump) = err.downcast_ref::<WasmCoreDump>() else {
+        return;
+    };
+
+    let size: u64 = dump
+        .memories()
+        .iter()
+        .map(|memory| memory.data_size(&store) as u64)
+        .sum();
+    if size > coredump.max_size {
+        log::warn!(
+            "not writing a coredump of the guest: its linear memories take {size} bytes, over the limit of {} bytes",
+            coredump.max_size
+        );
+        return;
+    }
+
+    let path = coredump.path();
+    match std::fs::write(&path, dump.serialize(&mut store, name)) {
+        Ok(()) => log::info!("wrote the coredump of the guest to {path:?}"),
+        Err(err) => log::warn!("failed to write the coredump of the guest to {path:?}: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim_wasm::testing::modules::TRAP_BACKTRACE;
+    use wasmtime::{Config, Engine, Instance, Module, Store};
+
+    use super::*;
+
+    fn call_start_in(store: &mut Store<()>, wasm: impl AsRef<[u8]>) -> anyhow::Error {
+        let module = Module::new(store.engine(), wasm).unwrap();
+        let instance = Instance::new(&mut *store, &module, &[]).unwrap();
+        let start = instance
+            .get_typed_func::<(), ()>(&mut *store, "_start")
+            .unwrap();
+        start.call(&mut *store, ()).unwrap_err()
+    }
+
+    fn call_start(wasm: impl AsRef<[u8]>) -> anyhow::Error {
+        call_start_in(&mut Store::new(&Engine::default(), ()), wasm)
+    }
+
+    #[test]
+    fn test_trap_frames() {
+        let err = call_start(TRAP_BACKTRACE);
+        let (trap, frames) = trap_frames(&err).unwrap();
+        assert_eq!(trap, Trap::UnreachableCodeReached);
+
+        let funcs: Vec<_> = frames.iter().filter_map(|f| f.func.as_deref()).collect();
+        assert_eq!(funcs[..3], ["inner", "middle", "outer"]);
+        assert!(frames.iter().all(|frame| frame.offset.is_some()));
+
+        let err = report_trap(err);
+        assert!(
+            err.to_string()
+                .starts_with("wasm trap: wasm `unreachable` instruction executed in inner @ 0x"),
+            "{err}"
+        );
+        assert!(err.downcast_ref::<Trap>().is_some());
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Runtime { trap: Some(trap), .. }) if trap == "wasm `unreachable` instruction executed"
+        ));
+    }
+
+    #[test]
+    fn test_trap_frames_without_names() {
+        let err = call_start(r#"(module (func (export "_start") unreachable))"#);
+        let (_, frames) = trap_frames(&err).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].func, None);
+        assert!(frames[0].to_string().starts_with("<wasm function 0> @ 0x"));
+
+        // errors that are not traps are left alone
+        let err = report_trap(anyhow::anyhow!("not a trap"));
+        assert_eq!(err.to_string(), "not a trap");
+    }
+
+    #[test]
+    fn test_write_coredump() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config.coredump_on_trap(true);
+        let mut store = Store::new(&Engine::new(&config)?, ());
+        let dir = std::env::temp_dir().join(format!("runwasi-coredump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("core.wasm");
+        let wasm = r#"(module (memory 1) (func (export "_start") unreachable))"#;
+
+        // the memory of the guest is over the limit
+        let err = call_start_in(&mut store, wasm);
+        let coredump = Coredump::new(path.to_string_lossy(), 1024);
+        write_coredump(&mut store, &err, &coredump, "main");
+        assert!(!path.exists());
+
+        let coredump = Coredump::new(path.to_string_lossy(), 64 * 1024);
+        write_coredump(&mut store, &err, &coredump, "main");
+        assert!(std::fs::read(&path)?.starts_with(b"\0asm"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
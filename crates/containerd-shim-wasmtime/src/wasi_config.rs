@@ -0,0 +1,60 @@
+//! The host side of `wasi:config/store` for components.
+//!
+//! The guest reads the [`WasiConfig`] of its container, merged from its annotations, env vars
+//! and config file. Keys that aren't in it read as `none`.
+
+use std::sync::Arc;
+
+use containerd_shim_wasm::sandbox::context::RuntimeContext;
+use containerd_shim_wasm::sandbox::wasi_config::WasiConfig;
+
+use self::bindings::wasi::config::store::{Error, Host};
+
+// only the interface of the world is added to the linkers, the world itself isn't used
+#[allow(dead_code)]
+mod bindings {
+    wasmtime::component::bindgen!({
+        inline: "
+            package wasi:config@0.2.0-draft;
+
+            interface store {
+                variant error { upstream(string), io(string) }
+
+                get: func(key: string) -> result<option<string>, error>;
+                get-all: func() -> result<list<tuple<string, string>>, error>;
+            }
+
+            world imports {
+                import store;
+            }
+        ",
+    });
+}
+
+pub(crate) use self::bindings::wasi::config::store::add_to_linker;
+
+/// The configuration a guest reads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GuestConfig(Arc<WasiConfig>);
+
+impl GuestConfig {
+    pub(crate) fn new(ctx: &impl RuntimeContext) -> Self {
+        Self(ctx.wasi_config())
+    }
+}
+
+impl Host for GuestConfig {
+    fn get(&mut self, key: String) -> Result<Option<String>, Error> {
+        self.0.get(&key).map_err(io)
+    }
+
+    fn get_all(&mut self) -> Result<Vec<(String, String)>, Error> {
+        self.0.get_all().map_err(io)
+    }
+}
+
+// The values that fail are the ones read from files
+fn io(err: anyhow::Error) -> Error {
+    log::debug!("failed to read the config of the guest: {err:#}");
+    Error::Io(format!("{err:#}"))
+}
@@ -31,7 +31,7 @@ pub trait AmbientRuntime: Future + Sealded {
         RUNTIME.block_on(self)
     }
 
-    #[allow(dead_code, async_fn_in_trait)] // used in tests and with the testing feature
+    #[allow(async_fn_in_trait)]
     async fn with_timeout(self, t: Duration) -> Option<Self::Output>
     where
         Self: Sized,
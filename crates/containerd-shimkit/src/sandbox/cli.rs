@@ -34,6 +34,10 @@
 //! - [`version!()`] - Returns the crate version from Cargo.toml
 //! - [`revision!()`] - Returns the Git revision hash, if available
 //!
+//! The engine of the instances is described by [`Instance::engine_version`], which the shim
+//! prints with `--version`, logs when it starts, adds to the resource of its traces, and
+//! returns as JSON in the `version` of the responses to `Connect`.
+//!
 //! ## Example usage:
 //!
 //! ```rust,no_run
@@ -97,9 +101,11 @@
 //! - `OTEL_SDK_DISABLED`: Disable OpenTelemetry SDK
 //!
 
+use std::fmt::{self, Display, Formatter};
 use std::path::PathBuf;
 
 use containerd_shim::{Config, parse, run};
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "opentelemetry")]
 use crate::sandbox::async_utils::AmbientRuntime as _;
@@ -150,6 +156,27 @@ macro_rules! shim_version {
     };
 }
 
+/// The engine that runs the instances of a shim, see [`Instance::engine_version`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EngineVersion {
+    /// The name of the engine, e.g., `wasmtime`
+    pub name: String,
+    /// The semver of the engine, e.g., `27.0.0`
+    pub version: String,
+    /// The wasm features the engine enables, e.g., `component-model`
+    pub features: Vec<String>,
+}
+
+impl Display for EngineVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)?;
+        if !self.features.is_empty() {
+            write!(f, " ({})", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 impl Default for Version {
     fn default() -> Self {
         Self {
@@ -232,6 +259,7 @@ where
         println!("  Runtime: {name}");
         println!("  Version: {}", version.version);
         println!("  Revision: {}", version.revision);
+        println!("  Engine: {}", I::engine_version());
         println!();
 
         std::process::exit(0);
@@ -249,10 +277,20 @@ where
     if otel_traces_enabled() {
         // opentelemetry uses tokio, so we need to initialize a runtime
         async {
-            let otlp_config = OtlpConfig::build_from_env().expect("Failed to build OtelConfig.");
-            let _guard = otlp_config
-                .init()
-                .expect("Failed to initialize OpenTelemetry.");
+            // traces are best-effort, the shim runs without them if they can't be exported
+            let _guard = OtlpConfig::build_from_env()
+                .and_then(|config| {
+                    config
+                        .with_shim(name, version.version)
+                        .with_engine(&I::engine_version())
+                        .init()
+                })
+                .inspect_err(|err| {
+                    log::warn!(
+                        "failed to initialize OpenTelemetry, traces won't be exported: {err:#}"
+                    )
+                })
+                .ok();
             tokio::task::block_in_place(move || {
                 shim_main_inner::<I>(name, config);
             });
@@ -48,6 +48,24 @@ pub enum Error {
     Libcontainer(#[from] libcontainer::error::LibcontainerError),
     #[error("{0}")]
     Containerd(String),
+    /// Content read from the content store doesn't match its digest
+    #[error("digest mismatch for {expected}: got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    /// An operation didn't complete in time, e.g., a call to containerd
+    #[error("{what} timed out after {timeout:?}")]
+    Timeout {
+        what: String,
+        timeout: std::time::Duration,
+    },
+    /// The signatures of an image don't satisfy the signature policy of the shim
+    #[error("signature verification of image {image} failed: {reason}")]
+    SignatureVerification { image: String, reason: String },
+    /// The image policy of the shim doesn't allow an image
+    #[error("image {image} is denied by the image policy: {reason}")]
+    ImageDenied { image: String, reason: String },
+    /// The shim isn't allowed to do an operation, e.g., to signal a process
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
 }
 
 pub type Result<T, E = Error> = ::std::result::Result<T, E>;
@@ -82,6 +100,24 @@ impl From<Error> for ttrpc::Error {
             Error::Any(ref s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, s))
             }
+            Error::DigestMismatch { .. } => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::DATA_LOSS, e.to_string()))
+            }
+            Error::Timeout { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::DEADLINE_EXCEEDED,
+                e.to_string(),
+            )),
+            Error::SignatureVerification { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::PERMISSION_DENIED,
+                e.to_string(),
+            )),
+            Error::ImageDenied { .. } => ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::PERMISSION_DENIED,
+                e.to_string(),
+            )),
+            Error::PermissionDenied(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::PERMISSION_DENIED, s))
+            }
             _ => ttrpc::Error::Others(e.to_string()),
         }
     }
@@ -141,6 +177,74 @@ mod tests {
             _ => panic!("unexpected error"),
         }
 
+        let e = Error::DigestMismatch {
+            expected: "sha256:1".to_string(),
+            actual: "sha256:2".to_string(),
+        };
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::DATA_LOSS);
+                assert_eq!(s.message, "digest mismatch for sha256:1: got sha256:2");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::Timeout {
+            what: "Content.Read".to_string(),
+            timeout: std::time::Duration::from_secs(30),
+        };
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::DEADLINE_EXCEEDED);
+                assert_eq!(s.message, "Content.Read timed out after 30s");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::SignatureVerification {
+            image: "example.com/app:v1".to_string(),
+            reason: "no signatures".to_string(),
+        };
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::PERMISSION_DENIED);
+                assert_eq!(
+                    s.message,
+                    "signature verification of image example.com/app:v1 failed: no signatures"
+                );
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::ImageDenied {
+            image: "example.com/app:v1".to_string(),
+            reason: "it matches the deny rule \"example.com/*\"".to_string(),
+        };
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::PERMISSION_DENIED);
+                assert_eq!(
+                    s.message,
+                    "image example.com/app:v1 is denied by the image policy: it matches the deny rule \"example.com/*\""
+                );
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::PermissionDenied("failed to signal pid 42: EPERM".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::PERMISSION_DENIED);
+                assert_eq!(s.message, "failed to signal pid 42: EPERM");
+            }
+            _ => panic!("unexpected error"),
+        }
+
         let e = Error::Shim(ShimError::InvalidArgument("invalid argument".to_string()));
         let t: ttrpc::Error = e.into();
         match t {
@@ -1,11 +1,15 @@
 //! Abstractions for running/managing a wasm/wasi instance.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
+use containerd_shim::Error as ShimError;
+use oci_spec::runtime::{LinuxResources, Process};
 use serde::{Deserialize, Serialize};
 
+use super::cli::EngineVersion;
 use super::error::Error;
+use super::stats::Stats;
 use crate::sandbox::shim::Config;
 
 /// Generic options builder for creating a wasm instance.
@@ -18,6 +22,10 @@ pub struct InstanceConfig {
     pub stdout: PathBuf,
     /// Optional stderr named pipe path.
     pub stderr: PathBuf,
+    /// Whether the process runs attached to a terminal.
+    /// The instance allocates the pty, and connects it to `stdin` and `stdout`.
+    #[serde(default)]
+    pub terminal: bool,
     /// Path to the OCI bundle directory.
     pub bundle: PathBuf,
     /// Namespace for containerd
@@ -26,6 +34,17 @@ pub struct InstanceConfig {
     pub containerd_address: String,
     /// containerd runtime options config
     pub config: Config,
+    /// Checkpoint directory to restore the instance from, if any
+    pub checkpoint: Option<PathBuf>,
+}
+
+/// A process running inside an instance, as reported by [`Instance::pids`].
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ProcessInfo {
+    /// PID of the process
+    pub pid: u32,
+    /// ID of the exec, if this is the main process of an exec'd process
+    pub exec_id: Option<String>,
 }
 
 /// Represents a WASI module(s).
@@ -39,6 +58,27 @@ pub trait Instance: 'static {
     where
         Self: Sized;
 
+    /// Recover an instance started by a previous shim process, whose init process `pid`
+    /// is still running. The recovered instance must support the same operations as an
+    /// instance returned by `start`.
+    /// The default implementation doesn't support recovery, and the instance is dropped.
+    async fn recover(_id: String, _cfg: &InstanceConfig, _pid: u32) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        async move { Err(ShimError::Unimplemented("recover is not supported".to_string()).into()) }
+    }
+
+    /// Returns the engine the instances run with, which the shim logs when it starts and reports
+    /// to its clients, see [`EngineVersion`].
+    /// The default implementation reports an engine without a name.
+    fn engine_version() -> EngineVersion
+    where
+        Self: Sized,
+    {
+        EngineVersion::default()
+    }
+
     /// Start the instance
     /// The returned value should be a unique ID (such as a PID) for the instance.
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
@@ -47,6 +87,16 @@ pub trait Instance: 'static {
     /// Send a signal to the instance
     async fn kill(&self, signal: u32) -> Result<(), Error>;
 
+    /// Send a signal to all the processes of the instance, e.g., to its exec'd processes too
+    /// This is called when containerd kills a task with `all` set.
+    /// The default implementation only signals the instance, like `kill`.
+    async fn kill_all(&self, signal: u32) -> Result<(), Error>
+    where
+        Self: Sync,
+    {
+        async move { self.kill(signal).await }
+    }
+
     /// Delete any reference to the instance
     /// This is called after the instance has exited.
     async fn delete(&self) -> Result<(), Error>;
@@ -54,4 +104,92 @@ pub trait Instance: 'static {
     /// Waits for the instance to finish and returns its exit code
     /// This is an async call.
     async fn wait(&self) -> (u32, DateTime<Utc>);
+
+    /// Pause the instance, freezing all of its processes
+    async fn pause(&self) -> Result<(), Error> {
+        // this async block is required to make the rewrite of trait_variant happy
+        async move { Err(ShimError::Unimplemented("pause is not supported".to_string()).into()) }
+    }
+
+    /// Resume a previously paused instance
+    async fn resume(&self) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("resume is not supported".to_string()).into()) }
+    }
+
+    /// Collect resource usage metrics for the instance, for the cgroup hierarchy it runs in
+    /// The default implementation rejects the request, in which case the task service
+    /// falls back to reading the cgroup of the instance's pid.
+    async fn stats(&self) -> Result<Stats, Error> {
+        async move { Err(ShimError::Unimplemented("stats is not supported".to_string()).into()) }
+    }
+
+    /// Update the resource limits of the running instance
+    async fn update(&self, _resources: &LinuxResources) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("update is not supported".to_string()).into()) }
+    }
+
+    /// Checkpoint the state of the running instance into the `path` directory.
+    /// The checkpoint can later be restored by creating a new instance with
+    /// [`InstanceConfig::checkpoint`] pointing to that directory.
+    /// Implementations that can't restore from a checkpoint should fail `new` when it is set.
+    async fn checkpoint(&self, _path: &Path) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("checkpoint is not supported".to_string()).into()) }
+    }
+
+    /// List the processes running inside the instance
+    /// The default implementation rejects the request, in which case the task service
+    /// reports only the pid of the instance.
+    async fn pids(&self) -> Result<Vec<ProcessInfo>, Error> {
+        async move { Err(ShimError::Unimplemented("pids is not supported".to_string()).into()) }
+    }
+
+    /// Wait for a process of the instance to be killed by the OOM killer.
+    /// This resolves once for every OOM kill, and must return an error once the instance
+    /// has exited and all its OOM kills have been reported, as the task service only
+    /// publishes the exit of the instance after that.
+    /// The default implementation doesn't report OOM kills.
+    async fn wait_oom(&self) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("wait_oom is not supported".to_string()).into()) }
+    }
+
+    /// Resize the terminal of the init process, or of the exec'd process `exec_id`, to
+    /// `width` columns and `height` rows.
+    /// The default implementation rejects the request.
+    async fn resize_pty(
+        &self,
+        _exec_id: Option<&str>,
+        _width: u32,
+        _height: u32,
+    ) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("resize_pty is not supported".to_string()).into()) }
+    }
+
+    /// Execute an additional process inside the running instance.
+    /// `process` is the OCI process spec sent by containerd, and `cfg` carries the stdio for the new process.
+    /// The returned value should be a unique ID (such as a PID) for the exec'd process.
+    /// The default implementation rejects the request.
+    async fn exec(
+        &self,
+        _exec_id: &str,
+        _process: &Process,
+        _cfg: &InstanceConfig,
+    ) -> Result<u32, Error> {
+        async move { Err(ShimError::Unimplemented("exec is not supported".to_string()).into()) }
+    }
+
+    /// Send a signal to an exec'd process
+    async fn kill_exec(&self, _exec_id: &str, _signal: u32) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("exec is not supported".to_string()).into()) }
+    }
+
+    /// Delete any reference to an exec'd process
+    /// This is called after the exec'd process has exited.
+    async fn delete_exec(&self, _exec_id: &str) -> Result<(), Error> {
+        async move { Err(ShimError::Unimplemented("exec is not supported".to_string()).into()) }
+    }
+
+    /// Waits for an exec'd process to finish and returns its exit code
+    async fn wait_exec(&self, _exec_id: &str) -> Result<(u32, DateTime<Utc>), Error> {
+        async move { Err(ShimError::Unimplemented("exec is not supported".to_string()).into()) }
+    }
 }
@@ -1,14 +1,15 @@
 //! Common utilities for the containerd shims.
 
 use std::fs::File;
-use std::io::{Error as IoError, ErrorKind, Result as IoResult};
-use std::path::PathBuf;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use super::{Error, InstanceConfig};
+use super::log_rotation::RotatingWriter;
+use super::{Error, InstanceConfig, OutputOptions};
 use crate::sys::DEFAULT_CONTAINER_ROOT_DIR;
-use crate::sys::stdio::open;
+use crate::sys::stdio::{self, open, open_read};
 
 #[derive(Serialize, Deserialize)]
 struct Options {
@@ -41,11 +42,12 @@ impl InstanceConfig {
         Ok(path)
     }
 
+    /// Opens the stdin for reading only, so that the process sees EOF once the client closes it.
     pub fn open_stdin(&self) -> IoResult<File> {
         if self.stdin.as_os_str().is_empty() {
             return Err(IoError::new(ErrorKind::NotFound, "File not found"));
         }
-        open(&self.stdin)
+        open_read(&self.stdin)
     }
 
     pub fn open_stdout(&self) -> IoResult<File> {
@@ -61,14 +63,46 @@ impl InstanceConfig {
         }
         open(&self.stderr)
     }
+
+    /// Opens the stdout for writing the output of the process with `options`.
+    ///
+    /// A regular file is rotated with the rotation of `options`, if any, while a named pipe is
+    /// left to its reader to rotate, and buffers the output while it has no reader.
+    pub fn open_stdout_with(&self, options: OutputOptions) -> IoResult<Box<dyn Write + Send>> {
+        open_output(&self.stdout, options)
+    }
+
+    /// Opens the stderr for writing, see [`InstanceConfig::open_stdout_with`].
+    pub fn open_stderr_with(&self, options: OutputOptions) -> IoResult<Box<dyn Write + Send>> {
+        open_output(&self.stderr, options)
+    }
+}
+
+fn open_output(path: &Path, options: OutputOptions) -> IoResult<Box<dyn Write + Send>> {
+    if path.as_os_str().is_empty() {
+        return Err(IoError::new(ErrorKind::NotFound, "File not found"));
+    }
+    match options.rotation {
+        Some(rotation) if is_regular_file(path) => {
+            Ok(Box::new(RotatingWriter::open(path, rotation)?))
+        }
+        _ => stdio::open_output(path, options.fifo_buffer),
+    }
+}
+
+fn is_regular_file(path: &Path) -> bool {
+    path.metadata().is_ok_and(|metadata| metadata.is_file())
 }
 
 #[cfg(unix)]
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::OpenOptionsExt as _;
+
     use tempfile::tempdir;
 
     use super::*;
+    use crate::sandbox::LogRotation;
 
     #[test]
     fn test_determine_rootdir_with_options_file() -> Result<(), Error> {
@@ -92,6 +126,68 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_open_stdin_reads_to_eof() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let stdin = dir.path().join("stdin");
+        nix::unistd::mkfifo(&stdin, nix::sys::stat::Mode::S_IRWXU).map_err(std::io::Error::from)?;
+        let cfg = InstanceConfig {
+            stdin: stdin.clone(),
+            ..Default::default()
+        };
+
+        // opening doesn't wait for the client
+        let mut reader = cfg.open_stdin()?;
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&stdin)?;
+        std::io::Write::write_all(&mut writer, b"hello")?;
+        drop(writer);
+
+        let mut input = String::new();
+        std::io::Read::read_to_string(&mut reader, &mut input)?;
+        assert_eq!(input, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_stdout_with() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let stdout = dir.path().join("stdout");
+        std::fs::write(&stdout, "0123456789\n")?;
+        let stderr = dir.path().join("stderr");
+        nix::unistd::mkfifo(&stderr, nix::sys::stat::Mode::S_IRWXU)
+            .map_err(std::io::Error::from)?;
+        let cfg = InstanceConfig {
+            stdout: stdout.clone(),
+            stderr: stderr.clone(),
+            ..Default::default()
+        };
+        let options = OutputOptions {
+            rotation: Some(LogRotation {
+                max_size: 16,
+                max_files: 1,
+            }),
+            ..Default::default()
+        };
+
+        cfg.open_stdout_with(options)?.write_all(b"abcdefgh\n")?;
+        assert_eq!(std::fs::read_to_string(&stdout)?, "abcdefgh\n");
+        assert!(dir.path().join("stdout.1").exists());
+
+        // named pipes are left to their reader
+        let mut reader = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::fcntl::OFlag::O_NONBLOCK.bits())
+            .open(&stderr)?;
+        cfg.open_stderr_with(options)?
+            .write_all(b"0123456789\nabcdefgh\n")?;
+        let mut output = String::new();
+        std::io::Read::read_to_string(&mut reader, &mut output)?;
+        assert_eq!(output, "0123456789\nabcdefgh\n");
+        assert!(!dir.path().join("stderr.1").exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_determine_rootdir_without_options_file() -> Result<(), Error> {
         let dir = tempdir()?;
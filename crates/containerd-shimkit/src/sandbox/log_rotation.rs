@@ -0,0 +1,163 @@
+//! Size-based rotation of the stdout and stderr files of the processes.
+//!
+//! containerd hands the shim named pipes for the stdio of most processes, whose reader owns the
+//! logs, but it can also hand it plain files, which nothing else rotates.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// How to rotate a stdio file: once it reaches `max_size` bytes, it's renamed to `<path>.1`,
+/// the previous `<path>.1` to `<path>.2`, and so on, keeping `max_files` rotated files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRotation {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+/// A writer to a file that it rotates with a [`LogRotation`].
+///
+/// Files are only rotated between lines, so that the line-oriented consumers of the logs, e.g.,
+/// of JSON records, never see a line split across files. A line longer than the maximum size
+/// gets a file of its own.
+pub(crate) struct RotatingWriter {
+    path: PathBuf,
+    file: File,
+    rotation: LogRotation,
+    // the size of the current file
+    size: u64,
+    // whether the next byte written starts a line
+    at_line_start: bool,
+}
+
+impl RotatingWriter {
+    pub(crate) fn open(path: impl AsRef<Path>, rotation: LogRotation) -> IoResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            rotation,
+            size,
+            at_line_start: true,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> IoResult<()> {
+        self.file.flush()?;
+        for index in (1..self.rotation.max_files).rev() {
+            match std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        // at most a line is written at a time, so that the file can be rotated before the next one
+        let line = buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(buf.len(), |i| i + 1);
+        if self.at_line_start && self.size > 0 && self.size + line as u64 > self.rotation.max_size {
+            self.rotate()?;
+        }
+
+        let n = self.file.write(&buf[..line])?;
+        self.size += n as u64;
+        if n > 0 {
+            self.at_line_start = buf[n - 1] == b'\n';
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_rotating_writer() -> IoResult<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        let rotation = LogRotation {
+            max_size: 16,
+            max_files: 2,
+        };
+        let read = |suffix: &str| {
+            let mut path = path.clone().into_os_string();
+            path.push(suffix);
+            std::fs::read_to_string(path).unwrap_or_default()
+        };
+
+        let mut writer = RotatingWriter::open(&path, rotation)?;
+        writer.write_all(b"{\"n\":1}\n{\"n\":2}\n")?;
+        assert_eq!(read(""), "{\"n\":1}\n{\"n\":2}\n");
+
+        // a line written in several parts isn't split across files
+        writer.write_all(b"{\"n\":")?;
+        writer.write_all(b"3}\n")?;
+        assert_eq!(read(""), "{\"n\":3}\n");
+        assert_eq!(read(".1"), "{\"n\":1}\n{\"n\":2}\n");
+
+        // lines longer than the maximum size get a file of their own
+        writer.write_all(b"{\"n\":4,\"long\":true}\n{\"n\":5}\n")?;
+        assert_eq!(read(""), "{\"n\":5}\n");
+        assert_eq!(read(".1"), "{\"n\":4,\"long\":true}\n");
+        assert_eq!(read(".2"), "{\"n\":3}\n");
+
+        // only `max_files` rotated files are kept
+        writer.write_all(b"{\"n\":6,\"long\":true}\n")?;
+        assert_eq!(read(""), "{\"n\":6,\"long\":true}\n");
+        assert_eq!(read(".1"), "{\"n\":5}\n");
+        assert_eq!(read(".2"), "{\"n\":4,\"long\":true}\n");
+        assert_eq!(read(".3"), "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotating_writer_appends() -> IoResult<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        std::fs::write(&path, "0123456789\n")?;
+        let rotation = LogRotation {
+            max_size: 16,
+            max_files: 1,
+        };
+
+        let mut writer = RotatingWriter::open(&path, rotation)?;
+        writer.write_all(b"abcdefgh\n")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "abcdefgh\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("stdout.1"))?,
+            "0123456789\n"
+        );
+
+        Ok(())
+    }
+}
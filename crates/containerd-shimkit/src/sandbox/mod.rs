@@ -56,14 +56,21 @@ pub mod cli;
 pub mod error;
 pub mod instance;
 pub mod shim;
+pub mod stats;
 pub mod sync;
 
 pub use error::{Error, Result};
-pub use instance::{Instance, InstanceConfig};
+pub use instance::{Instance, InstanceConfig, ProcessInfo};
+pub use log_rotation::LogRotation;
+pub use oci::{CONTAINER_TYPE_ANNOTATION, SANDBOX_ID_ANNOTATION, is_sandbox_container, sandbox_id};
+pub use output::{DropPolicy, FifoBuffer, OutputOptions};
 pub use shim::Config;
 pub(crate) use shim::Shim;
+pub use stats::Stats;
 
 pub(crate) mod instance_utils;
+pub(crate) mod log_rotation;
 pub(crate) mod oci;
+pub(crate) mod output;
 
 pub(crate) mod async_utils;
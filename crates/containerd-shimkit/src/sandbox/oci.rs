@@ -7,9 +7,35 @@ use std::os::unix::process::CommandExt;
 use std::process;
 
 use anyhow::Context;
+use oci_spec::runtime::Spec;
 
 use super::error::Result;
 
+/// Annotation with the id of the pod sandbox of a container, set by the CRI plugin of containerd.
+/// The containers of a pod are served by a single shim process, which exits once the last of them
+/// is deleted.
+pub const SANDBOX_ID_ANNOTATION: &str = "io.kubernetes.cri.sandbox-id";
+
+/// Returns the id of the pod sandbox of the container with `spec`, if it runs in a pod.
+pub fn sandbox_id(spec: &Spec) -> Option<&str> {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(SANDBOX_ID_ANNOTATION))
+        .map(String::as_str)
+}
+
+/// Annotation with the type of a container, set by the CRI plugin of containerd: `sandbox` for
+/// the pause container of a pod, which holds its namespaces, and `container` for the others.
+pub const CONTAINER_TYPE_ANNOTATION: &str = "io.kubernetes.cri.container-type";
+
+/// Returns whether the container with `spec` is the pause container of a pod.
+pub fn is_sandbox_container(spec: &Spec) -> bool {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(CONTAINER_TYPE_ANNOTATION))
+        .is_some_and(|ty| ty == "sandbox")
+}
+
 fn parse_env(envs: &[String]) -> HashMap<String, String> {
     // make NAME=VALUE to HashMap<NAME, VALUE>.
     envs.iter()
@@ -0,0 +1,137 @@
+//! Options for writing the output of the processes to their stdout and stderr.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::LogRotation;
+
+/// How to write the output of a process, see [`InstanceConfig::open_stdout_with`].
+///
+/// [`InstanceConfig::open_stdout_with`]: super::InstanceConfig::open_stdout_with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputOptions {
+    /// How to rotate the output if it's a regular file.
+    pub rotation: Option<LogRotation>,
+    /// How to buffer the output if it's a named pipe without a reader.
+    pub fifo_buffer: FifoBuffer,
+}
+
+/// The buffer of the output written to a named pipe while it has no reader, e.g., while the log
+/// collector of containerd restarts. The output is written to the named pipe once it has a
+/// reader again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FifoBuffer {
+    /// The maximum number of bytes buffered.
+    pub max_size: usize,
+    /// What's dropped once the buffer is full.
+    pub drop: DropPolicy,
+}
+
+impl Default for FifoBuffer {
+    fn default() -> Self {
+        Self {
+            max_size: 1 << 20,
+            drop: DropPolicy::Oldest,
+        }
+    }
+}
+
+/// The output to drop from a full [`FifoBuffer`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DropPolicy {
+    /// Drop the oldest output, to make room for the new one.
+    #[default]
+    Oldest,
+    /// Drop the new output.
+    Newest,
+}
+
+/// The output buffered by a [`FifoBuffer`].
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct Backlog {
+    buffer: FifoBuffer,
+    bytes: VecDeque<u8>,
+    dropped: usize,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+impl Backlog {
+    pub(crate) fn new(buffer: FifoBuffer) -> Self {
+        Self {
+            buffer,
+            bytes: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, buf: &[u8]) {
+        let max_size = self.buffer.max_size;
+        match self.buffer.drop {
+            DropPolicy::Oldest => {
+                self.bytes.extend(buf);
+                let excess = self.bytes.len().saturating_sub(max_size);
+                self.bytes.drain(..excess);
+                self.dropped += excess;
+            }
+            DropPolicy::Newest => {
+                let room = max_size.saturating_sub(self.bytes.len()).min(buf.len());
+                self.bytes.extend(&buf[..room]);
+                self.dropped += buf.len() - room;
+            }
+        }
+    }
+
+    /// The buffered output, to write before removing it with [`Backlog::consume`].
+    pub(crate) fn pending(&mut self) -> &[u8] {
+        self.bytes.make_contiguous()
+    }
+
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.bytes.drain(..n);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the number of bytes dropped since the last call.
+    pub(crate) fn take_dropped(&mut self) -> usize {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let oldest = FifoBuffer {
+            max_size: 4,
+            drop: DropPolicy::Oldest,
+        };
+        let mut backlog = Backlog::new(oldest);
+        backlog.push(b"012");
+        backlog.push(b"3456789");
+        assert_eq!(backlog.pending(), b"6789");
+        assert_eq!(backlog.take_dropped(), 6);
+        backlog.consume(2);
+        assert_eq!(backlog.pending(), b"89");
+        assert_eq!(backlog.take_dropped(), 0);
+
+        let newest = FifoBuffer {
+            max_size: 4,
+            drop: DropPolicy::Newest,
+        };
+        let mut backlog = Backlog::new(newest);
+        backlog.push(b"012");
+        backlog.push(b"3456789");
+        assert_eq!(backlog.pending(), b"0123");
+        assert_eq!(backlog.take_dropped(), 6);
+    }
+}
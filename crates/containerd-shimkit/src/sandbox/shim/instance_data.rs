@@ -1,17 +1,44 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
 use chrono::{DateTime, Utc};
+use futures::FutureExt as _;
+use oci_spec::runtime::{LinuxResources, Process};
 use tokio::sync::{OnceCell, RwLock};
 
 use crate::sandbox::shim::task_state::TaskState;
-use crate::sandbox::{Instance, InstanceConfig, Result};
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, Instance, InstanceConfig, Result};
+
+// The exit code of a process, and the time it exited at
+type ExitStatus = (u32, DateTime<Utc>);
 
 pub(super) struct InstanceData<T: Instance> {
     pub instance: T,
     pub config: InstanceConfig,
     pid: OnceCell<u32>,
     state: RwLock<TaskState>,
+    execs: RwLock<HashMap<String, Arc<ExecData>>>,
+    // set once the exit of the init process is published
+    exit_published: WaitableCell<()>,
+}
+
+/// Bookkeeping for a process exec'd into a running instance.
+pub(super) struct ExecData {
+    pub process: Process,
+    pub config: InstanceConfig,
+    pid: OnceCell<u32>,
+    state: RwLock<TaskState>,
 }
 
-impl<T: Instance> InstanceData<T> {
+impl ExecData {
+    pub fn pid(&self) -> Option<u32> {
+        self.pid.get().copied()
+    }
+}
+
+impl<T: Instance + Sync> InstanceData<T> {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub async fn new(
         id: impl AsRef<str> + std::fmt::Debug,
@@ -24,6 +51,27 @@ impl<T: Instance> InstanceData<T> {
             config,
             pid: OnceCell::default(),
             state: RwLock::new(TaskState::Created),
+            execs: RwLock::default(),
+            exit_published: WaitableCell::new(),
+        })
+    }
+
+    /// Recover an instance that was running under a previous shim process
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub async fn recover(
+        id: impl AsRef<str> + std::fmt::Debug,
+        config: InstanceConfig,
+        pid: u32,
+    ) -> Result<Self> {
+        let id = id.as_ref().to_string();
+        let instance = T::recover(id, &config, pid).await?;
+        Ok(Self {
+            instance,
+            config,
+            pid: OnceCell::new_with(Some(pid)),
+            state: RwLock::new(TaskState::Started),
+            execs: RwLock::default(),
+            exit_published: WaitableCell::new(),
         })
     }
 
@@ -53,11 +101,77 @@ impl<T: Instance> InstanceData<T> {
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
-    pub async fn kill(&self, signal: u32) -> Result<()> {
+    pub async fn kill(&self, signal: u32, all: bool) -> Result<()> {
         let mut s = self.state.write().await;
         s.kill()?;
 
-        self.instance.kill(signal).await
+        if all {
+            self.instance.kill_all(signal).await
+        } else {
+            self.instance.kill(signal).await
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn pause(&self) -> Result<()> {
+        let mut s = self.state.write().await;
+        s.pause()?;
+
+        let res = self.instance.pause().await;
+
+        if res.is_err() {
+            // Always `Ok(())` because we hold the lock since `s.pause()`
+            let _ = s.resume();
+        }
+
+        res
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn resume(&self) -> Result<()> {
+        let mut s = self.state.write().await;
+        s.resume()?;
+
+        let res = self.instance.resume().await;
+
+        if res.is_err() {
+            // Always `Ok(())` because we hold the lock since `s.resume()`
+            let _ = s.pause();
+        }
+
+        res
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, resources), level = "Debug")
+    )]
+    pub async fn update(&self, resources: &LinuxResources) -> Result<()> {
+        let s = self.state.read().await;
+        if !matches!(*s, TaskState::Started | TaskState::Paused) {
+            return Err(Error::FailedPrecondition(
+                "cannot update resources: instance is not running".to_string(),
+            ));
+        }
+
+        self.instance.update(resources).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn checkpoint(&self, path: &Path) -> Result<()> {
+        let s = self.state.read().await;
+        if !matches!(*s, TaskState::Started) {
+            return Err(Error::FailedPrecondition(
+                "cannot checkpoint: instance is not running".to_string(),
+            ));
+        }
+
+        self.instance.checkpoint(path).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn is_paused(&self) -> bool {
+        matches!(*self.state.read().await, TaskState::Paused)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
@@ -82,4 +196,136 @@ impl<T: Instance> InstanceData<T> {
         *s = TaskState::Exited;
         res
     }
+
+    /// Marks the exit of the init process as published.
+    pub fn set_exit_published(&self) {
+        let _ = self.exit_published.set(());
+    }
+
+    /// Resolves once the exit of the init process is published.
+    pub async fn exit_published(&self) {
+        self.exit_published.wait().await;
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, process, config), level = "Debug")
+    )]
+    pub async fn add_exec(
+        &self,
+        exec_id: impl AsRef<str> + std::fmt::Debug,
+        process: Process,
+        config: InstanceConfig,
+    ) -> Result<()> {
+        let exec_id = exec_id.as_ref().to_string();
+
+        // Hold the lock so that the instance can't transition while the exec is registered
+        let s = self.state.read().await;
+        if !matches!(*s, TaskState::Started) || self.instance.wait().now_or_never().is_some() {
+            return Err(Error::FailedPrecondition(format!(
+                "cannot exec {exec_id}: instance is not running"
+            )));
+        }
+
+        let mut execs = self.execs.write().await;
+        if execs.contains_key(&exec_id) {
+            return Err(Error::AlreadyExists(exec_id));
+        }
+
+        execs.insert(
+            exec_id,
+            Arc::new(ExecData {
+                process,
+                config,
+                pid: OnceCell::default(),
+                state: RwLock::new(TaskState::Created),
+            }),
+        );
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn get_exec(&self, exec_id: &str) -> Result<Arc<ExecData>> {
+        let exec = self.execs.read().await.get(exec_id).cloned();
+        exec.ok_or_else(|| Error::NotFound(exec_id.to_string()))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn start_exec(&self, exec_id: &str) -> Result<u32> {
+        let exec = self.get_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        s.start()?;
+
+        let res = self
+            .instance
+            .exec(exec_id, &exec.process, &exec.config)
+            .await;
+
+        // These state transitions are always `Ok(())` because
+        // we hold the lock since `s.start()`
+        let _ = match res {
+            Ok(pid) => {
+                let _ = exec.pid.set(pid);
+                s.started()
+            }
+            Err(_) => s.stop(),
+        };
+
+        res
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn kill_exec(&self, exec_id: &str, signal: u32) -> Result<()> {
+        let exec = self.get_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        s.kill()?;
+
+        self.instance.kill_exec(exec_id, signal).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn resize_pty(&self, exec_id: Option<&str>, width: u32, height: u32) -> Result<()> {
+        if let Some(exec_id) = exec_id {
+            self.get_exec(exec_id).await?;
+        }
+        self.instance.resize_pty(exec_id, width, height).await
+    }
+
+    /// Deletes the exec'd process, returning its pid and exit status (if it has exited).
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn delete_exec(&self, exec_id: &str) -> Result<(u32, Option<ExitStatus>)> {
+        let exec = self.get_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        s.delete()?;
+
+        // Grab the exit status before the instance forgets about the exec'd process
+        let exit = match exec.pid() {
+            Some(_) => self
+                .instance
+                .wait_exec(exec_id)
+                .now_or_never()
+                .and_then(Result::ok),
+            None => None,
+        };
+
+        if let Err(err) = self.instance.delete_exec(exec_id).await {
+            // Always `Ok(())` because we hold the lock since `s.delete()`
+            let _ = s.stop();
+            return Err(err);
+        }
+
+        self.execs.write().await.remove(exec_id);
+
+        Ok((exec.pid().unwrap_or_default(), exit))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn wait_exec(&self, exec_id: &str) -> Result<(u32, DateTime<Utc>)> {
+        let exec = self.get_exec(exec_id).await?;
+        let res = self.instance.wait_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        *s = TaskState::Exited;
+        Ok(res)
+    }
 }
@@ -1,24 +1,29 @@
 use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::ops::Not;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use anyhow::ensure;
 use containerd_shim::api::{
-    ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty,
-    KillRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
-    StatsRequest, StatsResponse, WaitRequest, WaitResponse,
+    CheckpointTaskRequest, ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse,
+    DeleteRequest, Empty, ExecProcessRequest, KillRequest, PauseRequest, PidsRequest, PidsResponse,
+    ResizePtyRequest, ResumeRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest,
+    StateResponse, StatsRequest, StatsResponse, UpdateTaskRequest, WaitRequest, WaitResponse,
 };
 use containerd_shim::error::Error as ShimError;
-use containerd_shim::protos::events::task::{TaskCreate, TaskDelete, TaskExit, TaskIO, TaskStart};
+use containerd_shim::protos::events::task::{
+    TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskExit, TaskIO, TaskOOM, TaskPaused,
+    TaskResumed, TaskStart,
+};
 use containerd_shim::protos::shim::shim_ttrpc::Task;
-use containerd_shim::protos::types::task::Status;
+use containerd_shim::protos::types::task::{ProcessInfo as TaskProcessInfo, Status};
 use containerd_shim::util::IntoOption;
 use containerd_shim::{DeleteResponse, TtrpcContext, TtrpcResult};
 use futures::FutureExt as _;
 use log::debug;
-use oci_spec::runtime::Spec;
+use oci_spec::runtime::{LinuxResources, Process, Spec};
 use prost::Message;
 use protobuf::well_known_types::any::Any;
 use serde::{Deserialize, Serialize};
@@ -28,10 +33,12 @@ use tracing_opentelemetry::OpenTelemetrySpanExt as _;
 
 #[cfg(feature = "opentelemetry")]
 use super::otel::extract_context;
+use super::runtime_options::options_toml;
 use crate::sandbox::async_utils::AmbientRuntime as _;
-use crate::sandbox::instance::{Instance, InstanceConfig};
+use crate::sandbox::instance::{Instance, InstanceConfig, ProcessInfo};
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::shim::recovery::{InstanceRecord, StateDir};
 use crate::sandbox::sync::WaitableCell;
 use crate::sandbox::{Error, Result, oci};
 use crate::sys::metrics::get_metrics;
@@ -39,20 +46,20 @@ use crate::sys::metrics::get_metrics;
 #[cfg(test)]
 mod tests;
 
-/// containerd runtime options
+// How long the deletion of a task waits for its exit to be published, as publishing it only
+// waits for the OOM kills of the instance to be reported
+const EXIT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Details of an exec'd process, as reported by the runc shim
 #[derive(Message, Clone, PartialEq)]
-struct Options {
-    #[prost(string)]
-    type_url: String,
+struct ProcessDetails {
     #[prost(string)]
-    config_path: String,
-    #[prost(string)]
-    config_body: String,
+    exec_id: String,
 }
 
 /// This is generated by decoding the `options` field of a `CreateTaskRequest` to get an `Options` struct,
 /// interpreting the `config_body` field as TOML,
-/// and deserializing it.
+/// and deserializing it. Options of another type than `runtimeoptions.v1.Options` are ignored.
 #[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
 pub struct Config {
     /// Enables systemd cgroup.
@@ -66,15 +73,12 @@ impl Config {
             return Ok(Default::default());
         };
 
-        ensure!(
-            opts.type_url == "runtimeoptions.v1.Options",
-            "Invalid options type {}",
-            opts.type_url
-        );
-
-        let opts = Options::decode(opts.value.as_slice())?;
+        let Some(body) = options_toml(opts)? else {
+            log::warn!("ignoring the runtime options of type {:?}", opts.type_url);
+            return Ok(Default::default());
+        };
 
-        let config = toml::from_str(opts.config_body.as_str())
+        let config = toml::from_str(body.as_str())
             .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
 
         Ok(config)
@@ -85,12 +89,18 @@ type LocalInstances<T> = RwLock<HashMap<String, Arc<InstanceData<T>>>>;
 
 /// Local implements the Task service for a containerd shim.
 /// It defers all task operations to the `Instance` implementation.
+///
+/// It serves all the tasks of the shim process, e.g., the containers of a pod, each with its
+/// own instance, and lets the shim exit once it has no tasks left.
 pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender> {
     pub(super) instances: LocalInstances<T>,
+    // the number of tasks being created, which aren't in `instances` yet
+    creating: AtomicUsize,
     events: E,
     exit: WaitableCell<()>,
     namespace: String,
     containerd_address: String,
+    state_dir: Option<StateDir>,
 }
 
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
@@ -110,10 +120,99 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         let containerd_address = containerd_address.as_ref().to_string();
         Self {
             instances,
+            creating: AtomicUsize::new(0),
             events,
             exit,
             namespace,
             containerd_address,
+            state_dir: None,
+        }
+    }
+
+    /// Persists the running instances in `dir`, so that they can be recovered with
+    /// [`Local::recover`] if the shim restarts.
+    pub fn with_state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(StateDir::new(dir));
+        self
+    }
+
+    /// Takes over the instances that were running under a previous shim process.
+    /// Instances whose init process is gone, or that can't be recovered, are forgotten.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    pub async fn recover(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        for record in state_dir.load() {
+            let InstanceRecord {
+                id, config, pid, ..
+            } = record.clone();
+
+            if !record.is_running() {
+                log::info!("instance {id} is not running anymore");
+                self.forget(&id);
+                continue;
+            }
+
+            let i = match InstanceData::recover(&id, config, pid).await {
+                Ok(i) => Arc::new(i),
+                Err(err) => {
+                    log::warn!("failed to recover instance {id}: {err}");
+                    self.forget(&id);
+                    continue;
+                }
+            };
+
+            log::info!("recovered instance {id} with pid {pid}");
+            self.instances.write().await.insert(id.clone(), i.clone());
+            self.publish_exit(id, i, pid);
+        }
+    }
+
+    /// Publishes the OOM kills and the exit of the init process of the instance
+    fn publish_exit(&self, id: String, i: Arc<InstanceData<T>>, pid: u32) {
+        let events = self.events.clone();
+
+        async move {
+            // Publish the OOM kills before the exit, so that the task is reported as OOM-killed
+            while i.instance.wait_oom().await.is_ok() {
+                events.send(TaskOOM {
+                    container_id: id.clone(),
+                    ..Default::default()
+                });
+            }
+
+            let (exit_code, timestamp) = i.wait().await;
+            events.send(TaskExit {
+                container_id: id.clone(),
+                exit_status: exit_code,
+                exited_at: Some(timestamp.to_timestamp()).into(),
+                pid,
+                id,
+                ..Default::default()
+            });
+            i.set_exit_published();
+        }
+        .spawn();
+    }
+
+    fn persist(&self, id: &str, config: &InstanceConfig, pid: u32) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+        let record = InstanceRecord::new(id, config.clone(), pid);
+        if let Err(err) = state_dir.save(&record) {
+            log::warn!("failed to persist instance {id}, it won't be recovered: {err}");
+        }
+    }
+
+    fn forget(&self, id: &str) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+        if let Err(err) = state_dir.remove(id) {
+            log::warn!("failed to remove the record of instance {id}: {err}");
         }
     }
 
@@ -128,9 +227,28 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         self.instances.read().await.contains_key(id)
     }
 
+    /// Whether the shim has no tasks, including the ones being created, so that it can exit.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn is_empty(&self) -> bool {
-        self.instances.read().await.is_empty()
+        // created tasks stop being counted while the lock is held, see `task_create`
+        let instances = self.instances.read().await;
+        instances.is_empty() && self.creating.load(Ordering::SeqCst) == 0
+    }
+}
+
+// Counts a task being created until it's dropped
+struct Creating<'a>(&'a AtomicUsize);
+
+impl<'a> Creating<'a> {
+    fn new(creating: &'a AtomicUsize) -> Self {
+        creating.fetch_add(1, Ordering::SeqCst);
+        Self(creating)
+    }
+}
+
+impl Drop for Creating<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -141,20 +259,20 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         let config = Config::get_from_options(req.options.as_ref())
             .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
 
-        if !req.checkpoint().is_empty() || !req.parent_checkpoint().is_empty() {
-            return Err(ShimError::Unimplemented("checkpoint is not supported".to_string()).into());
-        }
-
-        if req.terminal {
-            return Err(Error::InvalidArgument(
-                "terminal is not supported".to_string(),
-            ));
+        if !req.parent_checkpoint().is_empty() {
+            return Err(ShimError::Unimplemented(
+                "incremental checkpoints are not supported".to_string(),
+            )
+            .into());
         }
 
         if self.has_instance(&req.id).await {
             return Err(Error::AlreadyExists(req.id));
         }
 
+        // the other tasks of the shim may all be deleted before this one is created
+        let creating = Creating::new(&self.creating);
+
         let mut spec = Spec::load(Path::new(&req.bundle).join("config.json"))
             .map_err(|err| Error::InvalidArgument(format!("could not load runtime spec: {err}")))?;
 
@@ -192,16 +310,21 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             stdout: req.stdout.as_str().into(),
             stderr: req.stderr.as_str().into(),
             stdin: req.stdin.as_str().into(),
+            terminal: req.terminal,
             config,
+            checkpoint: req
+                .checkpoint()
+                .none_if(|&x| x.is_empty())
+                .map(PathBuf::from),
         };
 
         // Check if this is a cri container
         let instance = InstanceData::new(req.id(), cfg).await?;
 
-        self.instances
-            .write()
-            .await
-            .insert(req.id().to_string(), Arc::new(instance));
+        let mut instances = self.instances.write().await;
+        instances.insert(req.id().to_string(), Arc::new(instance));
+        drop(creating);
+        drop(instances);
 
         self.events.send(TaskCreate {
             container_id: req.id,
@@ -211,6 +334,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
                 stdin: req.stdin,
                 stdout: req.stdout,
                 stderr: req.stderr,
+                terminal: req.terminal,
                 ..Default::default()
             })
             .into(),
@@ -230,36 +354,89 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
-    async fn task_start(&self, req: StartRequest) -> Result<StartResponse> {
-        if req.exec_id().is_empty().not() {
-            return Err(ShimError::Unimplemented("exec is not supported".to_string()).into());
-        }
+    async fn task_exec(&self, req: ExecProcessRequest) -> Result<Empty> {
+        let spec = req
+            .spec
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("exec process spec is not set".to_string()))?;
+        let process: Process = serde_json::from_slice(&spec.value)
+            .map_err(|err| Error::InvalidArgument(format!("invalid exec process spec: {err}")))?;
 
         let i = self.get_instance(req.id()).await?;
-        let pid = i.start().await?;
 
-        self.events.send(TaskStart {
-            container_id: req.id().into(),
-            pid,
+        let cfg = InstanceConfig {
+            stdout: req.stdout.as_str().into(),
+            stderr: req.stderr.as_str().into(),
+            stdin: req.stdin.as_str().into(),
+            terminal: req.terminal,
+            ..i.config.clone()
+        };
+
+        i.add_exec(req.exec_id(), process, cfg).await?;
+
+        self.events.send(TaskExecAdded {
+            container_id: req.id,
+            exec_id: req.exec_id,
             ..Default::default()
         });
 
-        let events = self.events.clone();
+        debug!("exec added");
 
-        let id = req.id().to_string();
+        Ok(Empty::new())
+    }
 
-        async move {
-            let (exit_code, timestamp) = i.wait().await;
-            events.send(TaskExit {
-                container_id: id.clone(),
-                exit_status: exit_code,
-                exited_at: Some(timestamp.to_timestamp()).into(),
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_start(&self, req: StartRequest) -> Result<StartResponse> {
+        let i = self.get_instance(req.id()).await?;
+
+        if req.exec_id().is_empty().not() {
+            let exec_id = req.exec_id().to_string();
+            let pid = i.start_exec(&exec_id).await?;
+
+            self.events.send(TaskExecStarted {
+                container_id: req.id().into(),
+                exec_id: exec_id.clone(),
+                pid,
+                ..Default::default()
+            });
+
+            let events = self.events.clone();
+            let container_id = req.id().to_string();
+
+            async move {
+                let res = i.wait_exec(&exec_id).await;
+                match res {
+                    Ok((exit_code, timestamp)) => events.send(TaskExit {
+                        container_id,
+                        exit_status: exit_code,
+                        exited_at: Some(timestamp.to_timestamp()).into(),
+                        pid,
+                        id: exec_id,
+                        ..Default::default()
+                    }),
+                    Err(err) => log::error!("failed to wait for exec {exec_id}: {err}"),
+                }
+            }
+            .spawn();
+
+            debug!("exec started: {:?}", req);
+
+            return Ok(StartResponse {
                 pid,
-                id,
                 ..Default::default()
             });
         }
-        .spawn();
+
+        let pid = i.start().await?;
+
+        self.events.send(TaskStart {
+            container_id: req.id().into(),
+            pid,
+            ..Default::default()
+        });
+
+        self.persist(req.id(), &i.config, pid);
+        self.publish_exit(req.id().to_string(), i, pid);
 
         debug!("started: {:?}", req);
 
@@ -271,31 +448,150 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_kill(&self, req: KillRequest) -> Result<Empty> {
+        let i = self.get_instance(req.id()).await?;
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            i.kill_exec(req.exec_id(), req.signal()).await?;
+        } else {
+            i.kill(req.signal(), req.all()).await?;
+        }
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_resize_pty(&self, req: ResizePtyRequest) -> Result<Empty> {
+        let i = self.get_instance(req.id()).await?;
+        let exec_id = req.exec_id().none_if(|&x| x.is_empty());
+        i.resize_pty(exec_id, req.width, req.height).await?;
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_pause(&self, req: PauseRequest) -> Result<Empty> {
+        self.get_instance(req.id()).await?.pause().await?;
+
+        self.events.send(TaskPaused {
+            container_id: req.id,
+            ..Default::default()
+        });
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_resume(&self, req: ResumeRequest) -> Result<Empty> {
+        self.get_instance(req.id()).await?.resume().await?;
+
+        self.events.send(TaskResumed {
+            container_id: req.id,
+            ..Default::default()
+        });
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_update(&self, req: UpdateTaskRequest) -> Result<Empty> {
+        let resources = req
+            .resources
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("resources are not set".to_string()))?;
+        let resources: LinuxResources = serde_json::from_slice(&resources.value)
+            .map_err(|err| Error::InvalidArgument(format!("invalid resources: {err}")))?;
+
+        self.get_instance(req.id())
+            .await?
+            .update(&resources)
+            .await?;
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_checkpoint(&self, req: CheckpointTaskRequest) -> Result<Empty> {
+        if req.path().is_empty() {
+            return Err(Error::InvalidArgument(
+                "checkpoint path is not set".to_string(),
+            ));
         }
+
         self.get_instance(req.id())
             .await?
-            .kill(req.signal())
+            .checkpoint(Path::new(req.path()))
             .await?;
+
         Ok(Empty::new())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_pids(&self, req: PidsRequest) -> Result<PidsResponse> {
+        let i = self.get_instance(req.id()).await?;
+
+        let processes = match i.instance.pids().await {
+            Ok(processes) => processes,
+            Err(Error::Shim(ShimError::Unimplemented(_))) => i
+                .pid()
+                .map(|pid| ProcessInfo { pid, exec_id: None })
+                .into_iter()
+                .collect(),
+            Err(err) => return Err(err),
+        };
+
+        let processes = processes
+            .into_iter()
+            .map(|p| TaskProcessInfo {
+                pid: p.pid,
+                info: p
+                    .exec_id
+                    .map(|exec_id| Any {
+                        type_url: "containerd.runc.v1.ProcessDetails".to_string(),
+                        value: ProcessDetails { exec_id }.encode_to_vec(),
+                        ..Default::default()
+                    })
+                    .into(),
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(PidsResponse {
+            processes,
+            ..Default::default()
+        })
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+        let i = self.get_instance(req.id()).await?;
+
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            let (pid, exit) = i.delete_exec(req.exec_id()).await?;
+            let (exit_code, timestamp) = exit.unzip();
+            return Ok(DeleteResponse {
+                pid,
+                exit_status: exit_code.unwrap_or_default(),
+                exited_at: timestamp.map(ToTimestamp::to_timestamp).into(),
+                ..Default::default()
+            });
         }
 
-        let i = self.get_instance(req.id()).await?;
-
         i.delete().await?;
 
+        // containerd expects the exit of a started task to be published before its deletion,
+        // which the task publishing it may not have done yet
+        if i.pid().is_some()
+            && i.exit_published()
+                .with_timeout(EXIT_PUBLISH_TIMEOUT)
+                .await
+                .is_none()
+        {
+            log::warn!("the exit of instance {} wasn't published", req.id());
+        }
+
         let pid = i.pid().unwrap_or_default();
         let (exit_code, timestamp) = i.wait().now_or_never().unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
         self.instances.write().await.remove(req.id());
+        self.forget(req.id());
 
         self.events.send(TaskDelete {
             container_id: req.id().into(),
@@ -315,12 +611,12 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_wait(&self, req: WaitRequest) -> Result<WaitResponse> {
-        if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
-        }
-
         let i = self.get_instance(req.id()).await?;
-        let (exit_code, timestamp) = i.wait().await;
+        let (exit_code, timestamp) = if !req.exec_id().is_empty() {
+            i.wait_exec(req.exec_id()).await?
+        } else {
+            i.wait().await
+        };
 
         debug!("wait finishes");
         Ok(WaitResponse {
@@ -332,21 +628,53 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_state(&self, req: StateRequest) -> Result<StateResponse> {
+        let i = self.get_instance(req.id()).await?;
+
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            let exec = i.get_exec(req.exec_id()).await?;
+            let pid = exec.pid();
+            let exit = match pid {
+                Some(_) => i.instance.wait_exec(req.exec_id()).now_or_never(),
+                None => None,
+            };
+            let (exit_code, timestamp) = exit.transpose()?.unzip();
+            let timestamp = timestamp.map(ToTimestamp::to_timestamp);
+
+            let status = if pid.is_none() {
+                Status::CREATED
+            } else if exit_code.is_none() {
+                Status::RUNNING
+            } else {
+                Status::STOPPED
+            };
+
+            return Ok(StateResponse {
+                id: req.id().into(),
+                exec_id: req.exec_id().into(),
+                bundle: exec.config.bundle.to_string_lossy().to_string(),
+                stdin: exec.config.stdin.to_string_lossy().to_string(),
+                stdout: exec.config.stdout.to_string_lossy().to_string(),
+                stderr: exec.config.stderr.to_string_lossy().to_string(),
+                pid: pid.unwrap_or_default(),
+                exit_status: exit_code.unwrap_or_default(),
+                exited_at: timestamp.into(),
+                status: status.into(),
+                ..Default::default()
+            });
         }
 
-        let i = self.get_instance(req.id()).await?;
         let pid = i.pid();
         let (exit_code, timestamp) = i.wait().now_or_never().unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
         let status = if pid.is_none() {
             Status::CREATED
-        } else if exit_code.is_none() {
-            Status::RUNNING
-        } else {
+        } else if exit_code.is_some() {
             Status::STOPPED
+        } else if i.is_paused().await {
+            Status::PAUSED
+        } else {
+            Status::RUNNING
         };
 
         Ok(StateResponse {
@@ -362,6 +690,33 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         })
     }
 
+    /// Returns the pids of the shim and of the task, and the
+    /// [`EngineVersion`](crate::sandbox::cli::EngineVersion) of the shim as JSON in the
+    /// `version`, so that clients can tell which engine serves the task.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_connect(&self, req: ConnectRequest) -> Result<ConnectResponse> {
+        let i = self.get_instance(req.id()).await?;
+        let shim_pid = std::process::id();
+        let task_pid = i.pid().unwrap_or_default();
+        let version = serde_json::to_string(&T::engine_version())?;
+        Ok(ConnectResponse {
+            shim_pid,
+            task_pid,
+            version,
+            ..Default::default()
+        })
+    }
+
+    /// Lets the shim exit if it has no tasks left, e.g., once the last container of its pod is
+    /// deleted.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_shutdown(&self) -> Result<Empty> {
+        if self.is_empty().await {
+            let _ = self.exit.set(());
+        }
+        Ok(Empty::new())
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_stats(&self, req: StatsRequest) -> Result<StatsResponse> {
         let i = self.get_instance(req.id()).await?;
@@ -369,7 +724,11 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             .pid()
             .ok_or_else(|| Error::InvalidArgument("task is not running".to_string()))?;
 
-        let metrics = get_metrics(pid)?;
+        let metrics = match i.instance.stats().await {
+            Ok(stats) => stats.into_any()?,
+            Err(Error::Shim(ShimError::Unimplemented(_))) => get_metrics(pid)?,
+            Err(err) => return Err(err),
+        };
 
         Ok(StatsResponse {
             stats: Some(metrics).into(),
@@ -393,6 +752,16 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         Ok(self.task_create(req).block_on()?)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        debug!("exec: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_exec(req).block_on()?)
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
         debug!("start: {:?}", req);
@@ -413,6 +782,66 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         Ok(self.task_kill(req).block_on()?)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        debug!("resize_pty: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_resize_pty(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn pause(&self, _ctx: &TtrpcContext, req: PauseRequest) -> TtrpcResult<Empty> {
+        debug!("pause: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_pause(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn resume(&self, _ctx: &TtrpcContext, req: ResumeRequest) -> TtrpcResult<Empty> {
+        debug!("resume: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_resume(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn update(&self, _ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        debug!("update: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_update(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn checkpoint(&self, _ctx: &TtrpcContext, req: CheckpointTaskRequest) -> TtrpcResult<Empty> {
+        debug!("checkpoint: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_checkpoint(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        debug!("pids: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(self.task_pids(req).block_on()?)
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
         debug!("delete: {:?}", req);
@@ -470,14 +899,7 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        let i = self.get_instance(req.id()).block_on()?;
-        let shim_pid = std::process::id();
-        let task_pid = i.pid().unwrap_or_default();
-        Ok(ConnectResponse {
-            shim_pid,
-            task_pid,
-            ..Default::default()
-        })
+        Ok(self.task_connect(req).block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
@@ -497,10 +919,7 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        if self.is_empty().block_on() {
-            let _ = self.exit.set(());
-        }
-        Ok(Empty::new())
+        Ok(self.task_shutdown().block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
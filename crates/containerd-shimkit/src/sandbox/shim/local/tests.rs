@@ -1,10 +1,14 @@
 use std::fs::{File, create_dir};
+use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::Context;
 use chrono::{DateTime, Utc};
 use containerd_shim::api::Status;
 use containerd_shim::event::Event;
+use containerd_shim::protos::ttrpc;
+use futures::FutureExt as _;
+use oci_spec::runtime::ProcessBuilder;
 use protobuf::{MessageDyn, SpecialFields};
 use serde_json as json;
 use tempfile::tempdir;
@@ -12,7 +16,9 @@ use tokio::sync::mpsc::{UnboundedSender as Sender, unbounded_channel as channel}
 use tokio_async_drop::tokio_async_drop;
 
 use super::*;
+use crate::sandbox::cli::EngineVersion;
 use crate::sandbox::shim::events::EventSender;
+use crate::sandbox::shim::runtime_options::Options;
 use crate::sandbox::sync::WaitableCell;
 
 /// This is used for the tests and is a no-op instance implementation.
@@ -20,14 +26,37 @@ pub struct InstanceStub {
     /// Since we are faking the container, we need to keep track of the "exit" code/time
     /// We'll just mark it as exited when kill is called.
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    /// Exec'd processes "exit" with the signal number they are killed with.
+    execs: Mutex<HashMap<String, ExitCode>>,
+}
+
+type ExitCode = WaitableCell<(u32, DateTime<Utc>)>;
+
+impl InstanceStub {
+    fn exec_exit_code(&self, exec_id: &str) -> Result<WaitableCell<(u32, DateTime<Utc>)>, Error> {
+        let execs = self.execs.lock().unwrap();
+        let exit_code = execs.get(exec_id).cloned();
+        exit_code.ok_or_else(|| Error::NotFound(exec_id.to_string()))
+    }
 }
 
 impl Instance for InstanceStub {
     async fn new(_id: String, _cfg: &InstanceConfig) -> Result<Self, Error> {
         Ok(InstanceStub {
             exit_code: WaitableCell::new(),
+            execs: Mutex::default(),
         })
     }
+    async fn recover(id: String, cfg: &InstanceConfig, _pid: u32) -> Result<Self, Error> {
+        Self::new(id, cfg).await
+    }
+    fn engine_version() -> EngineVersion {
+        EngineVersion {
+            name: "stub".to_string(),
+            version: "1.0.0".to_string(),
+            features: vec!["component-model".to_string()],
+        }
+    }
     async fn start(&self) -> Result<u32, Error> {
         Ok(std::process::id())
     }
@@ -41,6 +70,34 @@ impl Instance for InstanceStub {
     async fn wait(&self) -> (u32, DateTime<Utc>) {
         *self.exit_code.wait().await
     }
+    async fn pause(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn resume(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn exec(
+        &self,
+        exec_id: &str,
+        _process: &Process,
+        _cfg: &InstanceConfig,
+    ) -> Result<u32, Error> {
+        let mut execs = self.execs.lock().unwrap();
+        execs.insert(exec_id.to_string(), WaitableCell::new());
+        Ok(std::process::id())
+    }
+    async fn kill_exec(&self, exec_id: &str, signal: u32) -> Result<(), Error> {
+        let _ = self.exec_exit_code(exec_id)?.set((signal, Utc::now()));
+        Ok(())
+    }
+    async fn delete_exec(&self, exec_id: &str) -> Result<(), Error> {
+        self.execs.lock().unwrap().remove(exec_id);
+        Ok(())
+    }
+    async fn wait_exec(&self, exec_id: &str) -> Result<(u32, DateTime<Utc>), Error> {
+        let exit_code = self.exec_exit_code(exec_id)?;
+        Ok(*exit_code.wait().await)
+    }
 }
 
 struct LocalWithDestructor<T: Instance + Send + Sync, E: EventSender> {
@@ -64,7 +121,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Drop for LocalWithDestructor<T,
         tokio_async_drop!({
             let instances = self.local.instances.write().await;
             for (_, instance) in instances.iter() {
-                let _ = instance.kill(9).await;
+                let _ = instance.kill(9, false).await;
                 let _ = instance.delete().await;
             }
         })
@@ -280,6 +337,22 @@ async fn test_cri_task() -> Result<()> {
         })
         .await?;
     assert_eq!(state.status(), Status::STOPPED);
+
+    // killing the exited instance is reported as not found, which clients treat as benign
+    let err = local
+        .task_kill(KillRequest {
+            id: "testinstance".to_string(),
+            signal: 9,
+            all: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    match ttrpc::Error::from(err) {
+        ttrpc::Error::RpcStatus(s) => assert_eq!(s.code(), ttrpc::Code::NOT_FOUND),
+        e => panic!("unexpected error: {e:?}"),
+    }
+
     local
         .task_delete(DeleteRequest {
             id: "testinstance".to_string(),
@@ -351,11 +424,67 @@ async fn test_cri_task() -> Result<()> {
     Ok(())
 }
 
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_shutdown_after_last_task() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal.clone(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    // the containers of a pod are served by the same shim
+    let temp = tempdir().unwrap();
+    for id in ["sandbox", "container"] {
+        let dir = temp.path().join(id);
+        create_dir(&dir)?;
+        create_bundle(&dir, Some(with_cri_sandbox(None, "pod".to_string())))?;
+        local
+            .task_create(CreateTaskRequest {
+                id: id.to_string(),
+                bundle: dir.to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+            .await?;
+    }
+
+    let delete = async |id: &str| {
+        local
+            .task_delete(DeleteRequest {
+                id: id.to_string(),
+                ..Default::default()
+            })
+            .await
+    };
+
+    delete("container").await?;
+    local.task_shutdown().await?;
+    assert!(exit_signal.wait().now_or_never().is_none());
+
+    // a task being created also keeps the shim running
+    let creating = Creating::new(&local.creating);
+    delete("sandbox").await?;
+    local.task_shutdown().await?;
+    assert!(exit_signal.wait().now_or_never().is_none());
+
+    drop(creating);
+    local.task_shutdown().await?;
+    assert!(exit_signal.wait().now_or_never().is_some());
+
+    Ok(())
+}
+
 // Use a multi threaded runtime because LocalWithDestructor needs
 // it to run its async drop.
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_task_lifecycle() -> Result<()> {
-    let (etx, _erx) = channel(); // TODO: check events
+    let (etx, mut erx) = channel();
     let exit_signal = WaitableCell::new();
     let local = Arc::new(Local::<InstanceStub, _>::new(
         etx,
@@ -412,6 +541,16 @@ async fn test_task_lifecycle() -> Result<()> {
 
     assert_eq!(state.status(), Status::CREATED);
 
+    let connected = local
+        .task_connect(ConnectRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(connected.shim_pid, std::process::id());
+    let engine: EngineVersion = json::from_str(&connected.version)?;
+    assert_eq!(engine, InstanceStub::engine_version());
+
     local
         .task_start(StartRequest {
             id: "test".to_string(),
@@ -458,7 +597,8 @@ async fn test_task_lifecycle() -> Result<()> {
         })
         .await?;
 
-    rx.recv()
+    let wait = rx
+        .recv()
         .with_timeout(Duration::from_secs(5))
         .await
         .flatten()
@@ -491,40 +631,722 @@ async fn test_task_lifecycle() -> Result<()> {
         e => return Err(e),
     }
 
+    // the exit is published before the deletion, with the time the instance exited at
+    let mut events = vec![];
+    while let Ok(event) = erx.try_recv() {
+        events.push(event);
+    }
+    let topics = events.iter().map(|(topic, _)| topic.as_str());
+    assert_eq!(
+        topics.collect::<Vec<_>>(),
+        [
+            "/tasks/create",
+            "/tasks/start",
+            "/tasks/exit",
+            "/tasks/delete"
+        ]
+    );
+    let (_, exit) = events.remove(2);
+    let exit = exit.downcast_box::<TaskExit>().unwrap();
+    assert_eq!(exit.pid, std::process::id());
+    assert_eq!(exit.exit_status, wait.exit_status);
+    assert_eq!(exit.exited_at, wait.exited_at);
+
     Ok(())
 }
 
-#[test]
-fn test_default_runtime_options() -> Result<()> {
-    let options: Option<&Any> = None;
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pause_resume() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
 
-    let config = Config::get_from_options(options).unwrap();
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // can't pause an instance that isn't running
+    match local
+        .task_pause(PauseRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_pause(PauseRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::PAUSED);
+
+    match local
+        .task_pause(PauseRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_resume(ResumeRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    // killing a paused instance is allowed
+    local
+        .task_pause(PauseRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::STOPPED);
 
-    assert_eq!(config.systemd_cgroup, false);
+    local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
 
     Ok(())
 }
 
-#[test]
-fn test_custom_runtime_options() -> Result<()> {
-    let options = Options {
-        type_url: "runtimeoptions.v1.Options".to_string(),
-        config_path: "".to_string(),
-        config_body: "SystemdCgroup = true\n".to_string(),
-    };
-    let req = CreateTaskRequest {
-        options: Some(Any {
-            type_url: options.type_url.clone(),
-            value: options.encode_to_vec(),
-            special_fields: SpecialFields::default(),
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_recover_after_restart() -> Result<()> {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let state_dir = dir.join("state");
+    create_bundle(dir, None)?;
+
+    // Don't wrap the first task service in a LocalWithDestructor, so that
+    // the instance keeps running after it's dropped, as if the shim crashed.
+    let (etx, _erx) = channel();
+    let local =
+        Local::<InstanceStub, _>::new(etx, WaitableCell::new(), "test_namespace", "/test/address")
+            .with_state_dir(&state_dir);
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
         })
-        .into(),
-        ..Default::default()
-    };
+        .await?;
 
-    let config = Config::get_from_options(req.options.as_ref()).unwrap();
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert!(state_dir.join("test.json").exists());
+    drop(local);
+
+    let (etx, _erx) = channel();
+    let local = Arc::new(
+        Local::<InstanceStub, _>::new(etx, WaitableCell::new(), "test_namespace", "/test/address")
+            .with_state_dir(&state_dir),
+    );
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local.recover().await;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+    assert_eq!(state.pid, std::process::id());
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+
+    local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert!(!state_dir.join("test.json").exists());
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pids_fallback() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // the instance isn't running yet, so there are no processes to report
+    let pids = local
+        .task_pids(PidsRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert!(pids.processes.is_empty());
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // the stub doesn't implement pids, so only the init process is reported
+    let pids = local
+        .task_pids(PidsRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(pids.processes.len(), 1);
+    assert_eq!(pids.processes[0].pid, std::process::id());
+    assert!(pids.processes[0].info.is_none());
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+
+    local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_update_resources() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let resources = LinuxResources::default();
+    let update_request = UpdateTaskRequest {
+        id: "test".to_string(),
+        resources: Some(Any {
+            type_url: "types.containerd.io/opencontainers/runtime-spec/1/LinuxResources"
+                .to_string(),
+            value: json::to_vec(&resources)?,
+            special_fields: SpecialFields::default(),
+        })
+        .into(),
+        ..Default::default()
+    };
+
+    match local
+        .task_update(UpdateTaskRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::InvalidArgument(_) => {}
+        e => return Err(e),
+    }
+
+    // can't update an instance that isn't running
+    match local.task_update(update_request.clone()).await.unwrap_err() {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // the stub instance doesn't implement update
+    match local.task_update(update_request).await.unwrap_err() {
+        Error::Shim(ShimError::Unimplemented(_)) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_resize_pty() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            terminal: true,
+            ..Default::default()
+        })
+        .await?;
+    assert!(local.get_instance("test").await?.config.terminal);
+
+    // the stub instance doesn't implement resize_pty
+    match local
+        .task_resize_pty(ResizePtyRequest {
+            id: "test".to_string(),
+            width: 80,
+            height: 24,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::Shim(ShimError::Unimplemented(_)) => {}
+        e => return Err(e),
+    }
+
+    match local
+        .task_resize_pty(ResizePtyRequest {
+            id: "test".to_string(),
+            exec_id: "missing".to_string(),
+            width: 80,
+            height: 24,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::NotFound(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+fn exec_request(id: &str, exec_id: &str) -> Result<ExecProcessRequest> {
+    let process = ProcessBuilder::default()
+        .args(vec!["hello.wasm".to_string()])
+        .cwd("/")
+        .build()?;
+    Ok(ExecProcessRequest {
+        id: id.to_string(),
+        exec_id: exec_id.to_string(),
+        spec: Some(Any {
+            type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process".to_string(),
+            value: json::to_vec(&process)?,
+            special_fields: SpecialFields::default(),
+        })
+        .into(),
+        ..Default::default()
+    })
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_exec_lifecycle() -> Result<()> {
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // exec before the init process has started is rejected
+    match local
+        .task_exec(exec_request("test", "exec0")?)
+        .await
+        .unwrap_err()
+    {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    local.task_exec(exec_request("test", "exec1")?).await?;
+    local.task_exec(exec_request("test", "exec2")?).await?;
+
+    match local
+        .task_exec(exec_request("test", "exec1")?)
+        .await
+        .unwrap_err()
+    {
+        Error::AlreadyExists(_) => {}
+        e => return Err(e),
+    }
+
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            exec_id: "exec1".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::CREATED);
+
+    for exec_id in ["exec1", "exec2"] {
+        local
+            .task_start(StartRequest {
+                id: "test".to_string(),
+                exec_id: exec_id.to_string(),
+                ..Default::default()
+            })
+            .await?;
+
+        let state = local
+            .task_state(StateRequest {
+                id: "test".to_string(),
+                exec_id: exec_id.to_string(),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(state.status(), Status::RUNNING);
+    }
+
+    let (tx, mut rx) = channel();
+    let ll = local.clone();
+    tokio::spawn(async move {
+        let resp = ll
+            .task_wait(WaitRequest {
+                id: "test".to_string(),
+                exec_id: "exec1".to_string(),
+                ..Default::default()
+            })
+            .await;
+        tx.send(resp).unwrap();
+    });
+
+    rx.try_recv().unwrap_err();
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            exec_id: "exec2".to_string(),
+            signal: 15,
+            ..Default::default()
+        })
+        .await?;
+
+    // killing exec2 doesn't affect exec1
+    rx.try_recv().unwrap_err();
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            exec_id: "exec1".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    let resp = rx
+        .recv()
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .flatten()
+        .unwrap()?;
+    assert_eq!(resp.exit_status, 9);
+
+    let resp = local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            exec_id: "exec2".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+    assert_eq!(resp.exit_status, 15);
+
+    let resp = local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            exec_id: "exec2".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(resp.exit_status, 15);
+
+    match local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            exec_id: "exec2".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::NotFound(_) => {}
+        e => return Err(e),
+    }
+
+    // the init process is unaffected by the execs
+    let state = local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()?;
+
+    // exec after the init process has exited is rejected
+    match local
+        .task_exec(exec_request("test", "exec3")?)
+        .await
+        .unwrap_err()
+    {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_default_runtime_options() -> Result<()> {
+    let options: Option<&Any> = None;
+
+    let config = Config::get_from_options(options).unwrap();
+
+    assert!(!config.systemd_cgroup);
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "SystemdCgroup = true\n".to_string(),
+    };
+    let req = CreateTaskRequest {
+        options: Some(Any {
+            type_url: options.type_url.clone(),
+            value: options.encode_to_vec(),
+            special_fields: SpecialFields::default(),
+        })
+        .into(),
+        ..Default::default()
+    };
+
+    let config = Config::get_from_options(req.options.as_ref()).unwrap();
+
+    assert!(config.systemd_cgroup);
+
+    Ok(())
+}
+
+#[test]
+fn test_unknown_runtime_options() -> Result<()> {
+    let options = Any {
+        type_url: "io.containerd.runc.v1.Options".to_string(),
+        value: b"\x08\x01".to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
 
-    assert_eq!(config.systemd_cgroup, true);
+    assert_eq!(config, Config::default());
 
     Ok(())
 }
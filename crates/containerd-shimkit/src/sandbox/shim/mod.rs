@@ -1,10 +1,13 @@
 //! The shim exposes the [Config] struct to configure the shim and [OtlpConfig] module to enable tracing if the `opentelemetry` feature is enabled.
 
 pub use local::Config;
+pub use runtime_options::{RUNTIME_OPTIONS_ENV, decode_runtime_options, runtime_options};
 
 mod events;
 mod instance_data;
 mod local;
+mod recovery;
+mod runtime_options;
 #[allow(clippy::module_inception)]
 mod shim;
 mod task_state;
@@ -25,11 +25,12 @@
 
 use std::collections::HashMap;
 use std::env;
+use std::time::Duration;
 
-use opentelemetry::Context;
 use opentelemetry::global::{self, set_text_map_propagator};
 use opentelemetry::propagation::Extractor;
 use opentelemetry::trace::TraceError;
+use opentelemetry::{Context, KeyValue};
 pub use opentelemetry_otlp::{
     OTEL_EXPORTER_OTLP_ENDPOINT, OTEL_EXPORTER_OTLP_PROTOCOL, OTEL_EXPORTER_OTLP_TRACES_ENDPOINT,
 };
@@ -37,23 +38,35 @@ use opentelemetry_otlp::{
     OTEL_EXPORTER_OTLP_PROTOCOL_DEFAULT, Protocol, SpanExporterBuilder, WithExportConfig,
 };
 use opentelemetry_sdk::propagation::TraceContextPropagator;
-use opentelemetry_sdk::{runtime, trace as sdktrace};
+use opentelemetry_sdk::resource::EnvResourceDetector;
+use opentelemetry_sdk::{Resource, runtime, trace as sdktrace};
 use tracing::span::{Attributes, Id};
 use tracing::{Span, Subscriber};
 use tracing_opentelemetry::{OpenTelemetrySpanExt as _, OtelData};
 use tracing_subscriber::layer::SubscriberExt as _;
 use tracing_subscriber::{EnvFilter, Layer, Registry};
 
+use crate::sandbox::cli::EngineVersion;
+
 const OTEL_EXPORTER_OTLP_PROTOCOL_HTTP_JSON: &str = "http/json";
 const OTEL_EXPORTER_OTLP_PROTOCOL_HTTP_PROTOBUF: &str = "http/protobuf";
 const OTEL_EXPORTER_OTLP_PROTOCOL_GRPC: &str = "grpc";
 const OTEL_EXPORTER_OTLP_TRACES_PROTOCOL: &str = "OTEL_EXPORTER_OTLP_TRACES_PROTOCOL";
 const OTEL_SDK_DISABLED: &str = "OTEL_SDK_DISABLED";
+const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+
+// Resource attribute with the name of the engine of the shim
+const ENGINE_ATTRIBUTE: &str = "runwasi.engine";
+// Resource attributes with the version and the comma separated wasm features of the engine
+const ENGINE_VERSION_ATTRIBUTE: &str = "runwasi.engine.version";
+const ENGINE_FEATURES_ATTRIBUTE: &str = "runwasi.engine.features";
 
 /// Configuration struct for OpenTelemetry setup.
 pub struct Config {
     traces_endpoint: String,
     traces_protocol: Protocol,
+    // resource attributes of the shim, which the ones from the environment override
+    attributes: Vec<KeyValue>,
 }
 
 /// Returns `true` if traces are enabled, `false` otherwise.
@@ -82,13 +95,54 @@ impl Config {
         Ok(Self {
             traces_endpoint,
             traces_protocol,
+            attributes: vec![],
         })
     }
 
+    /// Sets the resource attributes of the traces of the shim for the engine `name` with
+    /// `version`, and of the node it runs on.
+    /// `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES` take precedence over them.
+    pub fn with_shim(mut self, name: &str, version: &str) -> Self {
+        self.attributes = vec![
+            KeyValue::new("service.name", format!("containerd-shim-{name}-v1")),
+            KeyValue::new("service.version", version.to_string()),
+            KeyValue::new(ENGINE_ATTRIBUTE, name.to_string()),
+        ];
+        if let Some(node_name) = node_name() {
+            self.attributes.push(KeyValue::new("host.name", node_name));
+        }
+        self
+    }
+
+    /// Adds the version and the wasm features of the `engine` of the shim to the resource
+    /// attributes of its traces.
+    pub fn with_engine(mut self, engine: &EngineVersion) -> Self {
+        self.attributes.extend([
+            KeyValue::new(ENGINE_VERSION_ATTRIBUTE, engine.version.clone()),
+            KeyValue::new(ENGINE_FEATURES_ATTRIBUTE, engine.features.join(",")),
+        ]);
+        self
+    }
+
+    fn resource(&self) -> Resource {
+        let mut from_env =
+            Resource::from_detectors(Duration::ZERO, vec![Box::new(EnvResourceDetector::new())]);
+        let service_name = env::var(OTEL_SERVICE_NAME)
+            .ok()
+            .filter(|name| !name.is_empty());
+        if let Some(service_name) = service_name {
+            from_env = from_env.merge(&Resource::new([KeyValue::new(
+                "service.name",
+                service_name,
+            )]));
+        }
+        Resource::new(self.attributes.clone()).merge(&from_env)
+    }
+
     /// Initializes the tracer, sets up the telemetry and subscriber layers, and sets the global subscriber.
     ///
     /// Note: this function should be called only once and be called by the binary entry point.
-    pub fn init(&self) -> anyhow::Result<impl Drop> {
+    pub fn init(&self) -> anyhow::Result<impl Drop + use<>> {
         let tracer = self.init_tracer()?;
         let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
         set_text_map_propagator(TraceContextPropagator::new());
@@ -144,10 +198,12 @@ impl Config {
             Protocol::Grpc => self.init_tracer_grpc(),
         };
 
+        // the spans are exported in batches in the background, so that a collector that's down
+        // or slow only drops spans, rather than delaying the requests of containerd
         opentelemetry_otlp::new_pipeline()
             .tracing()
             .with_exporter(exporter)
-            .with_trace_config(sdktrace::config())
+            .with_trace_config(sdktrace::config().with_resource(self.resource()))
             .install_batch(runtime::Tokio)
     }
 }
@@ -186,6 +242,16 @@ fn traces_protocol_from_env() -> anyhow::Result<Protocol> {
     Ok(protocol)
 }
 
+/// The name of the node the shim runs on.
+fn node_name() -> Option<String> {
+    #[cfg(unix)]
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname").ok();
+    #[cfg(windows)]
+    let name = env::var("COMPUTERNAME").ok();
+    name.map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
 /// A layer that renames spans to include the target in the span name.
 struct SpanNamingLayer;
 
@@ -423,6 +489,81 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_resource() {
+        let attribute = |resource: &Resource, key: &'static str| {
+            resource
+                .get(opentelemetry::Key::new(key))
+                .map(|value| value.to_string())
+        };
+
+        with_vars(
+            [
+                (OTEL_EXPORTER_OTLP_ENDPOINT, Some("general_endpoint")),
+                ("OTEL_RESOURCE_ATTRIBUTES", None),
+                (OTEL_SERVICE_NAME, None),
+            ],
+            || {
+                let config = Config::build_from_env()
+                    .unwrap()
+                    .with_shim("wasmtime", "1.2.3")
+                    .with_engine(&EngineVersion {
+                        name: "wasmtime".to_string(),
+                        version: "27.0.0".to_string(),
+                        features: vec!["component-model".to_string(), "threads".to_string()],
+                    });
+                let resource = config.resource();
+                assert_eq!(
+                    attribute(&resource, "service.name").as_deref(),
+                    Some("containerd-shim-wasmtime-v1")
+                );
+                assert_eq!(
+                    attribute(&resource, "service.version").as_deref(),
+                    Some("1.2.3")
+                );
+                assert_eq!(
+                    attribute(&resource, ENGINE_ATTRIBUTE).as_deref(),
+                    Some("wasmtime")
+                );
+                assert_eq!(
+                    attribute(&resource, ENGINE_VERSION_ATTRIBUTE).as_deref(),
+                    Some("27.0.0")
+                );
+                assert_eq!(
+                    attribute(&resource, ENGINE_FEATURES_ATTRIBUTE).as_deref(),
+                    Some("component-model,threads")
+                );
+            },
+        );
+
+        with_vars(
+            [
+                (OTEL_EXPORTER_OTLP_ENDPOINT, Some("general_endpoint")),
+                (
+                    "OTEL_RESOURCE_ATTRIBUTES",
+                    Some("host.name=node-1,team=wasm"),
+                ),
+                (OTEL_SERVICE_NAME, Some("my-shim")),
+            ],
+            || {
+                let config = Config::build_from_env()
+                    .unwrap()
+                    .with_shim("wasmtime", "1.2.3");
+                let resource = config.resource();
+                assert_eq!(
+                    attribute(&resource, "service.name").as_deref(),
+                    Some("my-shim")
+                );
+                assert_eq!(attribute(&resource, "host.name").as_deref(), Some("node-1"));
+                assert_eq!(attribute(&resource, "team").as_deref(), Some("wasm"));
+                assert_eq!(
+                    attribute(&resource, ENGINE_ATTRIBUTE).as_deref(),
+                    Some("wasmtime")
+                );
+            },
+        );
+    }
+
     #[test]
     fn test_metadata_extractor() {
         let mut metadata = HashMap::new();
@@ -0,0 +1,136 @@
+//! Persists the running instances of the shim, so that a new shim process
+//! can take them over after the shim restarts (e.g., when it's upgraded).
+
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, write};
+use std::io::{ErrorKind, Result as IoResult};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sandbox::InstanceConfig;
+use crate::sys::process;
+
+/// The state needed to recover a running instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct InstanceRecord {
+    pub id: String,
+    pub config: InstanceConfig,
+    pub pid: u32,
+    /// Start time of the init process, to tell it apart from a process that reuses its pid
+    pub pid_start_time: Option<u64>,
+}
+
+impl InstanceRecord {
+    pub fn new(id: impl Into<String>, config: InstanceConfig, pid: u32) -> Self {
+        Self {
+            id: id.into(),
+            config,
+            pid,
+            pid_start_time: process::start_time(pid),
+        }
+    }
+
+    /// Checks that the recorded init process is still running
+    pub fn is_running(&self) -> bool {
+        self.pid_start_time.is_some() && process::start_time(self.pid) == self.pid_start_time
+    }
+}
+
+/// A directory with one record file per running instance
+#[derive(Debug, Clone)]
+pub(super) struct StateDir(PathBuf);
+
+impl StateDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+
+    pub fn save(&self, record: &InstanceRecord) -> IoResult<()> {
+        create_dir_all(&self.0)?;
+        // write to a temporary file first, so that a crash never leaves a partial record behind
+        let path = self.path(&record.id);
+        let tmp = path.with_extension("json.tmp");
+        write(&tmp, serde_json::to_vec(record)?)?;
+        rename(tmp, path)
+    }
+
+    pub fn remove(&self, id: &str) -> IoResult<()> {
+        match remove_file(self.path(id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads all the records, skipping the ones that can't be read.
+    pub fn load(&self) -> Vec<InstanceRecord> {
+        let entries = match read_dir(&self.0) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return vec![],
+            Err(err) => {
+                log::warn!("failed to read state directory {:?}: {err}", self.0);
+                return vec![];
+            }
+        };
+
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+                let record = read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|content| Ok(serde_json::from_str(&content)?));
+                match record {
+                    Ok(record) => Some(record),
+                    Err(err) => {
+                        log::warn!("ignoring invalid instance record {path:?}: {err}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.0.join(format!("{id}.json"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_state_dir() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let state = StateDir::new(dir.path().join("state"));
+        assert!(state.load().is_empty());
+
+        let record = InstanceRecord::new("test", InstanceConfig::default(), std::process::id());
+        assert!(record.is_running());
+        state.save(&record)?;
+        write(dir.path().join("state/invalid.json"), "{")?;
+
+        let records = state.load();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "test");
+        assert_eq!(records[0].pid, std::process::id());
+        assert_eq!(records[0].pid_start_time, record.pid_start_time);
+
+        state.remove("test")?;
+        state.remove("test")?;
+        assert!(state.load().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reused_pid() {
+        let mut record = InstanceRecord::new("test", InstanceConfig::default(), std::process::id());
+        record.pid_start_time = record.pid_start_time.map(|t| t + 1);
+        assert!(!record.is_running());
+    }
+}
@@ -0,0 +1,140 @@
+//! The runtime options of the shim, i.e., the `options` of its runtime in the config of
+//! containerd, or of the runtime class in CRI.
+//!
+//! containerd writes them as an `Any` to the stdin of `start`, and the shim passes their TOML
+//! to the shim process it spawns in [`RUNTIME_OPTIONS_ENV`], so that runtimes differing only
+//! in their options, e.g., `wasmtime` and `wasmtime-debug`, can share the binary of the shim.
+
+use std::io::{IsTerminal as _, Read as _};
+use std::sync::OnceLock;
+
+use anyhow::Context as _;
+use prost::Message;
+use protobuf::Message as _;
+use protobuf::well_known_types::any::Any;
+
+/// Environment variable with the TOML of the runtime options of the shim process, set by the
+/// `start` of the shim.
+pub const RUNTIME_OPTIONS_ENV: &str = "RUNWASI_RUNTIME_OPTIONS";
+
+/// The type URL of the options of the runtimes that aren't runc.
+pub(crate) const RUNTIME_OPTIONS_TYPE_URL: &str = "runtimeoptions.v1.Options";
+
+/// containerd runtime options
+#[derive(Message, Clone, PartialEq)]
+pub(crate) struct Options {
+    #[prost(string)]
+    pub(crate) type_url: String,
+    #[prost(string)]
+    pub(crate) config_path: String,
+    #[prost(string)]
+    pub(crate) config_body: String,
+}
+
+/// Returns the TOML of the runtime options of the shim, or `None` if it has none.
+///
+/// Options that aren't `runtimeoptions.v1.Options` are ignored, and the shim warns about them
+/// when it creates the tasks they are passed with.
+pub fn runtime_options() -> Option<&'static str> {
+    static OPTIONS: OnceLock<Option<String>> = OnceLock::new();
+
+    OPTIONS
+        .get_or_init(|| {
+            if let Ok(options) = std::env::var(RUNTIME_OPTIONS_ENV) {
+                return Some(options);
+            }
+            if !is_start() || std::io::stdin().is_terminal() {
+                return None;
+            }
+            let mut payload = vec![];
+            std::io::stdin().read_to_end(&mut payload).ok()?;
+            decode_runtime_options(&payload).ok().flatten()
+        })
+        .as_deref()
+}
+
+/// Decodes the `payload` of the runtime options containerd writes to the stdin of `start`,
+/// returning their TOML, or `None` if the payload is empty or of another type.
+pub fn decode_runtime_options(payload: &[u8]) -> anyhow::Result<Option<String>> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    let any = Any::parse_from_bytes(payload).context("invalid runtime options")?;
+    options_toml(&any)
+}
+
+/// Returns the TOML of the runtime options `any`, which is the file of their config path when
+/// CRI only sets that, or `None` if they are of another type.
+pub(crate) fn options_toml(any: &Any) -> anyhow::Result<Option<String>> {
+    if any.type_url != RUNTIME_OPTIONS_TYPE_URL {
+        return Ok(None);
+    }
+    let options = Options::decode(any.value.as_slice())?;
+    if options.config_body.is_empty() && !options.config_path.is_empty() {
+        let body = std::fs::read_to_string(&options.config_path)
+            .with_context(|| format!("failed to read {:?}", options.config_path))?;
+        return Ok(Some(body));
+    }
+    Ok(Some(options.config_body))
+}
+
+// Whether the shim was run by containerd to start the shim process
+fn is_start() -> bool {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    containerd_shim::parse(&args).is_ok_and(|flags| flags.action == "start")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    fn payload(type_url: &str, options: &Options) -> Vec<u8> {
+        let any = Any {
+            type_url: type_url.to_string(),
+            value: options.encode_to_vec(),
+            ..Default::default()
+        };
+        any.write_to_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_decode_runtime_options() -> anyhow::Result<()> {
+        let options = Options {
+            type_url: RUNTIME_OPTIONS_TYPE_URL.to_string(),
+            config_body: "SystemdCgroup = true\n\n[engine]\nwasm_features = \"+threads\"\n"
+                .to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_runtime_options(&payload(RUNTIME_OPTIONS_TYPE_URL, &options))?.as_deref(),
+            Some("SystemdCgroup = true\n\n[engine]\nwasm_features = \"+threads\"\n")
+        );
+
+        // the options of runc are for runc
+        assert_eq!(
+            decode_runtime_options(&payload("io.containerd.runc.v1.Options", &options))?,
+            None
+        );
+        assert_eq!(decode_runtime_options(&[])?, None);
+        assert!(decode_runtime_options(b"\xff\xff").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_runtime_options_config_path() -> anyhow::Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"[layers]\nstrict = true\n")?;
+        let options = Options {
+            type_url: RUNTIME_OPTIONS_TYPE_URL.to_string(),
+            config_path: file.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_runtime_options(&payload(RUNTIME_OPTIONS_TYPE_URL, &options))?.as_deref(),
+            Some("[layers]\nstrict = true\n")
+        );
+        Ok(())
+    }
+}
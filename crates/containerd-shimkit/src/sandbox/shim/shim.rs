@@ -12,13 +12,21 @@ use shim::Flags;
 
 use crate::sandbox::async_utils::AmbientRuntime as _;
 use crate::sandbox::instance::Instance;
+use crate::sandbox::oci;
 use crate::sandbox::shim::events::{RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::local::Local;
+use crate::sandbox::shim::runtime_options::{RUNTIME_OPTIONS_ENV, runtime_options};
 use crate::sandbox::sync::WaitableCell;
 
+/// Directory, relative to the bundle of the shim, where the running instances are persisted
+const STATE_DIR: &str = "runwasi-state";
+
 /// Shim implements the [containerd_shim::Shim] trait using `Local<T>` as the task service.
 ///
 /// It can be used as [`containerd_shim::synchronous::run<Shim<I>>()`] to start the shim.
+///
+/// The containers of a pod share the shim process started for the first of them, which serves
+/// their instances side by side until the last one is deleted.
 pub struct Shim<I: Instance + Sync + Send> {
     namespace: String,
     containerd_address: String,
@@ -65,13 +73,17 @@ where
         })?;
 
         let id = opts.id.clone();
-        let grouping = spec
-            .annotations()
-            .as_ref()
-            .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
-            .unwrap_or(&id);
+        let grouping = grouping(&spec, &id);
+
+        // the shim process gets the runtime options from its environment, as its stdin isn't
+        // the one of containerd
+        let vars = match runtime_options() {
+            Some(options) => vec![(RUNTIME_OPTIONS_ENV, options)],
+            None => vec![],
+        };
 
-        let (_child, address) = shim::spawn(opts, grouping, vec![])?;
+        // returns the address of the shim of the group if it's already running
+        let (_child, address) = shim::spawn(opts, grouping, vars)?;
 
         write_address(&address)?;
 
@@ -88,9 +100,22 @@ where
         tracing::instrument(skip(publisher), level = "Info")
     )]
     fn create_task_service(&self, publisher: RemotePublisher) -> Self::T {
+        log::info!("starting the shim with {}", I::engine_version());
         let events = RemoteEventSender::new(&self.namespace, publisher);
         let exit = self.exit.clone();
-        Local::<I>::new(events, exit, &self.namespace, &self.containerd_address)
+        let local = Local::<I>::new(events, exit, &self.namespace, &self.containerd_address);
+
+        // The shim runs in the bundle of the task it was started for,
+        // which outlives the tasks grouped with it.
+        let local = match current_dir() {
+            Ok(dir) => local.with_state_dir(dir.join(STATE_DIR)),
+            Err(err) => {
+                log::warn!("running instances won't be recovered after a restart: {err}");
+                local
+            }
+        };
+        local.recover().block_on();
+        local
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
@@ -102,3 +127,30 @@ where
         })
     }
 }
+
+/// The group of the shim process of the container `id` with `spec`: containers of the same
+/// group are served by the same shim process, containerd connecting to the one that's already
+/// running. It's the pod sandbox of the container for CRI, and the container itself otherwise.
+fn grouping<'a>(spec: &'a Spec, id: &'a str) -> &'a str {
+    oci::sandbox_id(spec).unwrap_or(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_grouping() {
+        let mut spec = Spec::default();
+        assert_eq!(grouping(&spec, "container"), "container");
+
+        let annotations = HashMap::from([(
+            oci::SANDBOX_ID_ANNOTATION.to_string(),
+            "sandbox".to_string(),
+        )]);
+        spec.set_annotations(Some(annotations));
+        assert_eq!(grouping(&spec, "container"), "sandbox");
+    }
+}
@@ -1,4 +1,4 @@
-use crate::sandbox::Error::FailedPrecondition;
+use crate::sandbox::Error::{FailedPrecondition, NotFound};
 use crate::sandbox::Result;
 
 #[derive(Debug, Clone, Copy)]
@@ -6,6 +6,7 @@ pub(super) enum TaskState {
     Created,
     Starting,
     Started,
+    Paused,
     Exited,
     Deleting,
 }
@@ -23,7 +24,9 @@ impl TaskState {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn kill(&mut self) -> Result<()> {
         *self = match self {
-            Self::Started => Ok(Self::Started),
+            Self::Started | Self::Paused => Ok(*self),
+            // Like runc, so that containerd treats killing an exited process as a no-op
+            Self::Exited => Err(NotFound("process already finished".to_string())),
             _ => state_transition_error(*self, "Killing"),
         }?;
         Ok(())
@@ -32,7 +35,8 @@ impl TaskState {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn delete(&mut self) -> Result<()> {
         *self = match self {
-            Self::Created | Self::Exited => Ok(Self::Deleting),
+            // A paused instance is thawed and killed by the instance before being deleted.
+            Self::Created | Self::Exited | Self::Paused => Ok(Self::Deleting),
             _ => state_transition_error(*self, Self::Deleting),
         }?;
         Ok(())
@@ -47,10 +51,28 @@ impl TaskState {
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn pause(&mut self) -> Result<()> {
+        *self = match self {
+            Self::Started => Ok(Self::Paused),
+            _ => state_transition_error(*self, Self::Paused),
+        }?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn resume(&mut self) -> Result<()> {
+        *self = match self {
+            Self::Paused => Ok(Self::Started),
+            _ => state_transition_error(*self, Self::Started),
+        }?;
+        Ok(())
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn stop(&mut self) -> Result<()> {
         *self = match self {
-            Self::Started | Self::Starting => Ok(Self::Exited),
+            Self::Started | Self::Starting | Self::Paused => Ok(Self::Exited),
             // This is for potential failure cases where we want delete to be able to be retried.
             Self::Deleting => Ok(Self::Exited),
             _ => state_transition_error(*self, Self::Exited),
@@ -0,0 +1,330 @@
+//! Resource usage of the instances, as reported by [`Instance::stats`].
+//!
+//! containerd expects the metrics of the cgroup hierarchy the instance runs in: the
+//! `io.containerd.cgroups.v1.Metrics` message for cgroup v1, and the
+//! `io.containerd.cgroups.v2.Metrics` message for cgroup v2, which [`v2`] defines.
+//!
+//! Instances can add their own data to the metrics with [`Stats::add_extension`]. containerd
+//! ignores it, and clients that know about it can decode it from [`EXTENSIONS_FIELD`].
+//!
+//! [`Instance::stats`]: super::Instance::stats
+
+use containerd_shim::protos::cgroups::metrics::Metrics as V1Metrics;
+use containerd_shim::util::convert_to_any;
+use prost::Message as _;
+use protobuf::Message as _;
+use protobuf::well_known_types::any::Any;
+
+/// The field of the metrics messages with their extensions, repeated `google.protobuf.Any`
+/// messages. It's past the fields containerd defines for both cgroup versions.
+pub const EXTENSIONS_FIELD: u32 = 1000;
+
+/// The metrics of an instance, for the cgroup hierarchy it runs in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stats {
+    V1(V1Metrics),
+    V2(Box<v2::Metrics>),
+}
+
+impl Stats {
+    /// Converts the metrics into the `Any` of a `StatsResponse`.
+    pub fn into_any(self) -> anyhow::Result<Any> {
+        match self {
+            Self::V1(metrics) => Ok(convert_to_any(Box::new(metrics))?),
+            Self::V2(metrics) => {
+                let mut any = Any::new();
+                any.type_url = v2::Metrics::TYPE_URL.to_string();
+                any.value = metrics.encode_to_vec();
+                Ok(any)
+            }
+        }
+    }
+
+    /// Adds an extension with the message `value` of type `type_url` to the metrics.
+    pub fn add_extension(&mut self, type_url: impl Into<String>, value: Vec<u8>) {
+        let type_url = type_url.into();
+        match self {
+            Self::V1(metrics) => {
+                let mut any = Any::new();
+                any.type_url = type_url;
+                any.value = value;
+                // writing to a vec only fails for messages over 2 GiB
+                let any = any.write_to_bytes().unwrap_or_default();
+                metrics
+                    .special_fields
+                    .mut_unknown_fields()
+                    .add_length_delimited(EXTENSIONS_FIELD, any);
+            }
+            Self::V2(metrics) => metrics.extensions.push(v2::Extension { type_url, value }),
+        }
+    }
+}
+
+impl From<V1Metrics> for Stats {
+    fn from(metrics: V1Metrics) -> Self {
+        Self::V1(metrics)
+    }
+}
+
+impl From<v2::Metrics> for Stats {
+    fn from(metrics: v2::Metrics) -> Self {
+        Self::V2(Box::new(metrics))
+    }
+}
+
+/// The `io.containerd.cgroups.v2` messages, from the `stats/metrics.proto` of
+/// `github.com/containerd/cgroups/cgroup2`.
+///
+/// The pressure stall information and the RDMA stats aren't reported, so they're left out.
+/// The extensions of the instance are in [`EXTENSIONS_FIELD`](super::EXTENSIONS_FIELD).
+pub mod v2 {
+    use prost::Message;
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct Metrics {
+        #[prost(message, optional, tag = "1")]
+        pub pids: Option<PidsStat>,
+        #[prost(message, optional, tag = "2")]
+        pub cpu: Option<CpuStat>,
+        #[prost(message, optional, tag = "4")]
+        pub memory: Option<MemoryStat>,
+        #[prost(message, optional, tag = "6")]
+        pub io: Option<IoStat>,
+        #[prost(message, repeated, tag = "7")]
+        pub hugetlb: Vec<HugeTlbStat>,
+        #[prost(message, optional, tag = "8")]
+        pub memory_events: Option<MemoryEvents>,
+        #[prost(message, repeated, tag = "1000")]
+        pub extensions: Vec<Extension>,
+    }
+
+    impl Metrics {
+        pub const TYPE_URL: &str = "io.containerd.cgroups.v2.Metrics";
+    }
+
+    /// An extension of the metrics, with the fields of `google.protobuf.Any`.
+    #[derive(Message, Clone, PartialEq)]
+    pub struct Extension {
+        #[prost(string, tag = "1")]
+        pub type_url: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub value: Vec<u8>,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct PidsStat {
+        #[prost(uint64, tag = "1")]
+        pub current: u64,
+        #[prost(uint64, tag = "2")]
+        pub limit: u64,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct CpuStat {
+        #[prost(uint64, tag = "1")]
+        pub usage_usec: u64,
+        #[prost(uint64, tag = "2")]
+        pub user_usec: u64,
+        #[prost(uint64, tag = "3")]
+        pub system_usec: u64,
+        #[prost(uint64, tag = "4")]
+        pub nr_periods: u64,
+        #[prost(uint64, tag = "5")]
+        pub nr_throttled: u64,
+        #[prost(uint64, tag = "6")]
+        pub throttled_usec: u64,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct MemoryStat {
+        #[prost(uint64, tag = "1")]
+        pub anon: u64,
+        #[prost(uint64, tag = "2")]
+        pub file: u64,
+        #[prost(uint64, tag = "3")]
+        pub kernel_stack: u64,
+        #[prost(uint64, tag = "4")]
+        pub slab: u64,
+        #[prost(uint64, tag = "5")]
+        pub sock: u64,
+        #[prost(uint64, tag = "6")]
+        pub shmem: u64,
+        #[prost(uint64, tag = "7")]
+        pub file_mapped: u64,
+        #[prost(uint64, tag = "8")]
+        pub file_dirty: u64,
+        #[prost(uint64, tag = "9")]
+        pub file_writeback: u64,
+        #[prost(uint64, tag = "10")]
+        pub anon_thp: u64,
+        #[prost(uint64, tag = "11")]
+        pub inactive_anon: u64,
+        #[prost(uint64, tag = "12")]
+        pub active_anon: u64,
+        #[prost(uint64, tag = "13")]
+        pub inactive_file: u64,
+        #[prost(uint64, tag = "14")]
+        pub active_file: u64,
+        #[prost(uint64, tag = "15")]
+        pub unevictable: u64,
+        #[prost(uint64, tag = "16")]
+        pub slab_reclaimable: u64,
+        #[prost(uint64, tag = "17")]
+        pub slab_unreclaimable: u64,
+        #[prost(uint64, tag = "18")]
+        pub pgfault: u64,
+        #[prost(uint64, tag = "19")]
+        pub pgmajfault: u64,
+        #[prost(uint64, tag = "20")]
+        pub workingset_refault: u64,
+        #[prost(uint64, tag = "21")]
+        pub workingset_activate: u64,
+        #[prost(uint64, tag = "22")]
+        pub workingset_nodereclaim: u64,
+        #[prost(uint64, tag = "23")]
+        pub pgrefill: u64,
+        #[prost(uint64, tag = "24")]
+        pub pgscan: u64,
+        #[prost(uint64, tag = "25")]
+        pub pgsteal: u64,
+        #[prost(uint64, tag = "26")]
+        pub pgactivate: u64,
+        #[prost(uint64, tag = "27")]
+        pub pgdeactivate: u64,
+        #[prost(uint64, tag = "28")]
+        pub pglazyfree: u64,
+        #[prost(uint64, tag = "29")]
+        pub pglazyfreed: u64,
+        #[prost(uint64, tag = "30")]
+        pub thp_fault_alloc: u64,
+        #[prost(uint64, tag = "31")]
+        pub thp_collapse_alloc: u64,
+        #[prost(uint64, tag = "32")]
+        pub usage: u64,
+        #[prost(uint64, tag = "33")]
+        pub usage_limit: u64,
+        #[prost(uint64, tag = "34")]
+        pub swap_usage: u64,
+        #[prost(uint64, tag = "35")]
+        pub swap_limit: u64,
+        #[prost(uint64, tag = "36")]
+        pub max_usage: u64,
+        #[prost(uint64, tag = "37")]
+        pub swap_max_usage: u64,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct MemoryEvents {
+        #[prost(uint64, tag = "1")]
+        pub low: u64,
+        #[prost(uint64, tag = "2")]
+        pub high: u64,
+        #[prost(uint64, tag = "3")]
+        pub max: u64,
+        #[prost(uint64, tag = "4")]
+        pub oom: u64,
+        #[prost(uint64, tag = "5")]
+        pub oom_kill: u64,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct IoStat {
+        #[prost(message, repeated, tag = "1")]
+        pub usage: Vec<IoEntry>,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct IoEntry {
+        #[prost(uint64, tag = "1")]
+        pub major: u64,
+        #[prost(uint64, tag = "2")]
+        pub minor: u64,
+        #[prost(uint64, tag = "3")]
+        pub rbytes: u64,
+        #[prost(uint64, tag = "4")]
+        pub wbytes: u64,
+        #[prost(uint64, tag = "5")]
+        pub rios: u64,
+        #[prost(uint64, tag = "6")]
+        pub wios: u64,
+    }
+
+    #[derive(Message, Clone, PartialEq)]
+    pub struct HugeTlbStat {
+        #[prost(uint64, tag = "1")]
+        pub current: u64,
+        #[prost(uint64, tag = "2")]
+        pub max: u64,
+        #[prost(string, tag = "3")]
+        pub pagesize: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message as _;
+
+    use super::*;
+
+    #[test]
+    fn test_v2_into_any() -> anyhow::Result<()> {
+        let metrics = v2::Metrics {
+            pids: Some(v2::PidsStat {
+                current: 3,
+                limit: 100,
+            }),
+            ..Default::default()
+        };
+
+        let any = Stats::from(metrics.clone()).into_any()?;
+        assert_eq!(any.type_url, "io.containerd.cgroups.v2.Metrics");
+        assert_eq!(v2::Metrics::decode(any.value.as_slice())?, metrics);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v1_into_any() -> anyhow::Result<()> {
+        let any = Stats::from(V1Metrics::new()).into_any()?;
+        assert_eq!(any.type_url, "io.containerd.cgroups.v1.Metrics");
+        Ok(())
+    }
+
+    // The extensions of a metrics message, skipping the fields of containerd
+    #[derive(prost::Message)]
+    struct Extensions {
+        #[prost(message, repeated, tag = "1000")]
+        extensions: Vec<v2::Extension>,
+    }
+
+    #[test]
+    fn test_add_extension() -> anyhow::Result<()> {
+        let extension = v2::Extension {
+            type_url: "example.com/Ext".into(),
+            value: vec![1, 2, 3],
+        };
+
+        let mut stats = Stats::from(V1Metrics::new());
+        stats.add_extension("example.com/Ext", vec![1, 2, 3]);
+        let any = stats.into_any()?;
+        let decoded = Extensions::decode(any.value.as_slice())?;
+        assert_eq!(decoded.extensions, [extension.clone()]);
+
+        let pids = v2::PidsStat {
+            current: 3,
+            limit: 100,
+        };
+        let mut stats = Stats::from(v2::Metrics {
+            pids: Some(pids.clone()),
+            ..Default::default()
+        });
+        stats.add_extension("example.com/Ext", vec![1, 2, 3]);
+        let any = stats.into_any()?;
+        let metrics = v2::Metrics::decode(any.value.as_slice())?;
+        assert_eq!(metrics.pids, Some(pids));
+        let decoded = Extensions::decode(any.value.as_slice())?;
+        assert_eq!(decoded.extensions, [extension]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Writers to named pipes that outlive their readers.
+//!
+//! The reader of the stdout and stderr of a process is the log collector of containerd, which
+//! may restart while the process runs. Writes to a named pipe without a reader fail with
+//! `EPIPE`, so rather than losing the rest of the output, the writer buffers it and reopens the
+//! named pipe in the background until a reader shows up again.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Result, Write};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+
+use crate::sandbox::output::{Backlog, FifoBuffer};
+
+// How often a named pipe without a reader is reopened
+const REOPEN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A writer to a named pipe that buffers the output while it has no reader.
+pub struct FifoWriter {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    // the named pipe, unless it has no reader
+    file: Option<File>,
+    backlog: Backlog,
+    // whether the writer is dropped, so that the named pipe isn't reopened anymore
+    closed: bool,
+}
+
+impl FifoWriter {
+    pub fn open(path: impl AsRef<Path>, buffer: FifoBuffer) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = match open_write(&path) {
+            Ok(file) => Some(file),
+            Err(err) if err.raw_os_error() == Some(Errno::ENXIO as i32) => None,
+            Err(err) => return Err(err),
+        };
+        let reopen = file.is_none();
+        let writer = Self {
+            shared: Arc::new(Shared {
+                path,
+                state: Mutex::new(State {
+                    file,
+                    backlog: Backlog::new(buffer),
+                    closed: false,
+                }),
+            }),
+        };
+        if reopen {
+            writer.reopen();
+        }
+        Ok(writer)
+    }
+
+    // Reopens the named pipe on a thread until it has a reader, and writes what was buffered
+    fn reopen(&self) {
+        let shared = self.shared.clone();
+        log::warn!(
+            "the named pipe {:?} has no reader, buffering the output",
+            shared.path
+        );
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(REOPEN_INTERVAL);
+                let mut state = shared.state.lock().unwrap();
+                if state.closed {
+                    if !state.backlog.is_empty() {
+                        log::warn!(
+                            "dropped {} bytes of output buffered for {:?}",
+                            state.backlog.len(),
+                            shared.path
+                        );
+                    }
+                    return;
+                }
+                let mut file = match open_write(&shared.path) {
+                    Ok(file) => file,
+                    Err(err) if err.raw_os_error() == Some(Errno::ENXIO as i32) => continue,
+                    Err(err) => {
+                        log::warn!("failed to reopen the named pipe {:?}: {err}", shared.path);
+                        continue;
+                    }
+                };
+                // the reader may go away again while writing what was buffered
+                match write_backlog(&mut file, &mut state.backlog) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::BrokenPipe => continue,
+                    Err(err) => {
+                        log::warn!("failed to write to the named pipe {:?}: {err}", shared.path);
+                        continue;
+                    }
+                }
+                let dropped = state.backlog.take_dropped();
+                if dropped > 0 {
+                    log::warn!(
+                        "dropped {dropped} bytes of output while {:?} had no reader",
+                        shared.path
+                    );
+                }
+                state.file = Some(file);
+                return;
+            }
+        });
+    }
+}
+
+impl Write for FifoWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(file) = &mut state.file else {
+            state.backlog.push(buf);
+            return Ok(buf.len());
+        };
+        match file.write(buf) {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                state.file = None;
+                state.backlog.push(buf);
+                drop(state);
+                self.reopen();
+                Ok(buf.len())
+            }
+            res => res,
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &mut self.shared.state.lock().unwrap().file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FifoWriter {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+    }
+}
+
+fn write_backlog(file: &mut File, backlog: &mut Backlog) -> Result<()> {
+    while !backlog.is_empty() {
+        let n = file.write(backlog.pending())?;
+        backlog.consume(n);
+    }
+    Ok(())
+}
+
+/// Returns whether `path` is a named pipe.
+pub fn is_fifo(path: impl AsRef<Path>) -> bool {
+    use std::os::unix::fs::FileTypeExt as _;
+    path.as_ref()
+        .metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+// Opens the named pipe at `path` for writing only, so that writing fails once it has no reader.
+// Opening fails with `ENXIO` rather than waiting when it has no reader.
+fn open_write(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    let flags = OFlag::from_bits_retain(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Instant;
+
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sandbox::output::DropPolicy;
+
+    fn open_read(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(path)
+    }
+
+    // Reads `len` bytes from the non-blocking `reader`, waiting for the writer to reopen the pipe
+    fn read_exact(reader: &mut File, len: usize) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut output = vec![];
+        let mut buf = [0; 64];
+        while output.len() < len && Instant::now() < deadline {
+            match reader.read(&mut buf) {
+                Ok(n) => output.extend(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+            // reading returns 0 rather than blocking until the pipe has a writer
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn test_fifo_writer_reopens() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        mkfifo(&path, Mode::S_IRWXU)?;
+        assert!(is_fifo(&path));
+
+        let mut reader = open_read(&path)?;
+        let mut writer = FifoWriter::open(&path, FifoBuffer::default())?;
+        writer.write_all(b"before\n")?;
+        assert_eq!(read_exact(&mut reader, 7)?, b"before\n");
+
+        // the reader restarts in the middle of the output
+        drop(reader);
+        writer.write_all(b"while\n")?;
+        writer.write_all(b"disconnected\n")?;
+        let mut reader = open_read(&path)?;
+        assert_eq!(read_exact(&mut reader, 19)?, b"while\ndisconnected\n");
+
+        writer.write_all(b"after\n")?;
+        assert_eq!(read_exact(&mut reader, 6)?, b"after\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fifo_writer_drops_oldest() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout");
+        mkfifo(&path, Mode::S_IRWXU)?;
+        let buffer = FifoBuffer {
+            max_size: 4,
+            drop: DropPolicy::Oldest,
+        };
+
+        // the named pipe has no reader yet
+        let mut writer = FifoWriter::open(&path, buffer)?;
+        writer.write_all(b"0123456789")?;
+        let mut reader = open_read(&path)?;
+        assert_eq!(read_exact(&mut reader, 4)?, b"6789");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs::read_to_string;
+
+/// Returns the start time of the process `pid` in clock ticks since boot,
+/// or `None` if there's no such process.
+/// Together with the pid, this identifies a process even if its pid gets reused.
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_start_time(&stat)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name can contain spaces and parentheses, so split after its last `)`.
+    // The start time is the 22nd field, the 20th one after the command name.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_start_time() {
+        let stat = "4242 (wasm (shim) x) S 1 4242 4242 0 -1 4194560 1 0 0 0 0 0 0 0 20 0 1 0 987654 1000 10 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("4242 (wasm) S 1"), None);
+    }
+
+    #[test]
+    fn test_start_time() {
+        assert!(start_time(std::process::id()).is_some());
+    }
+}
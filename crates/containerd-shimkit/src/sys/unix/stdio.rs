@@ -1,7 +1,38 @@
 use std::fs::{File, OpenOptions};
-use std::io::Result;
+use std::io::{Result, Write};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::OpenOptionsExt as _;
 use std::path::Path;
 
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+
+use super::fifo::{FifoWriter, is_fifo};
+use crate::sandbox::output::FifoBuffer;
+
 pub fn open(path: impl AsRef<Path>) -> Result<File> {
     OpenOptions::new().read(true).write(true).open(path)
 }
+
+/// Opens the named pipe at `path` for reading only, so that the reader sees EOF once the
+/// client closes its end, unlike with [`open`], which keeps a writer open.
+/// Opening doesn't wait for the client to open its end, but reading does.
+pub fn open_read(path: impl AsRef<Path>) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    let flags = OFlag::from_bits_retain(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK))?;
+    Ok(file)
+}
+
+/// Opens `path` for writing the output of a process. A named pipe is reopened whenever its
+/// reader goes away, with the output buffered in `buffer` meanwhile.
+pub fn open_output(path: impl AsRef<Path>, buffer: FifoBuffer) -> Result<Box<dyn Write + Send>> {
+    if is_fifo(&path) {
+        Ok(Box::new(FifoWriter::open(path, buffer)?))
+    } else {
+        Ok(Box::new(open(path)?))
+    }
+}
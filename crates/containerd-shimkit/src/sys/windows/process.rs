@@ -0,0 +1,5 @@
+/// Returns the start time of the process `pid`.
+/// This is not supported on Windows.
+pub fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
@@ -1,10 +1,12 @@
 use std::fs::{File, OpenOptions};
-use std::io::Result;
+use std::io::{Result, Write};
 use std::os::windows::fs::OpenOptionsExt as _;
 use std::path::Path;
 
 use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
 
+use crate::sandbox::output::FifoBuffer;
+
 pub fn open(path: impl AsRef<Path>) -> Result<File> {
     // Containerd always passes a named pipe for stdin, stdout, and stderr so we can check if it is a pipe and open with overlapped IO
     let mut options = OpenOptions::new();
@@ -14,3 +16,11 @@ pub fn open(path: impl AsRef<Path>) -> Result<File> {
     }
     options.open(path)
 }
+
+pub fn open_read(path: impl AsRef<Path>) -> Result<File> {
+    open(path)
+}
+
+pub fn open_output(path: impl AsRef<Path>, _buffer: FifoBuffer) -> Result<Box<dyn Write + Send>> {
+    Ok(Box::new(open(path)?))
+}
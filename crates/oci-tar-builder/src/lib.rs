@@ -62,16 +62,7 @@ impl OciConfig for WasmConfig {
     }
 }
 
-impl Default for Builder<WasmConfig> {
-    fn default() -> Self {
-        Self {
-            configs: Vec::new(),
-            layers: Vec::new(),
-        }
-    }
-}
-
-impl Default for Builder<ImageConfiguration> {
+impl<C: OciConfig> Default for Builder<C> {
     fn default() -> Self {
         Self {
             configs: Vec::new(),
@@ -0,0 +1,32 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Runs a container with the given runtime and checks that the shim publishes the events of its
+# task in order.
+
+RUNTIME=${1:?usage: $0 <runtime>}
+IMAGE="ghcr.io/containerd/runwasi/wasi-demo-app:latest"
+CONTAINER="testevents"
+EVENTS=$(mktemp)
+
+ctr events > "$EVENTS" &
+EVENTS_PID=$!
+trap 'kill $EVENTS_PID 2>/dev/null || true; rm -f "$EVENTS"' EXIT
+sleep 1
+
+ctr run --rm --runtime="io.containerd.${RUNTIME}.v1" "$IMAGE" "$CONTAINER" /wasi-demo-app.wasm echo 'hello'
+sleep 1
+
+# The lines are `<timestamp> <namespace> <topic> <event>`
+TOPICS=$(grep "\"container_id\":\"${CONTAINER}\"" "$EVENTS" \
+    | grep -oE '/tasks/(create|start|oom|exit|delete)' \
+    | tr '\n' ' ')
+
+EXPECTED="/tasks/create /tasks/start /tasks/exit /tasks/delete "
+if [ "$TOPICS" != "$EXPECTED" ]; then
+    echo "Unexpected task events: '$TOPICS', expected '$EXPECTED'"
+    cat "$EVENTS"
+    exit 1
+fi
+
+echo "All task events published in order!"